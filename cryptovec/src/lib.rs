@@ -24,6 +24,9 @@ use libc::c_void;
 #[cfg(not(windows))]
 use libc::size_t;
 
+#[cfg(feature = "pool")]
+mod pool;
+
 /// A buffer which zeroes its memory on `.clear()`, `.resize()` and
 /// reallocations, to avoid copying secrets around.
 #[derive(Debug)]
@@ -31,6 +34,9 @@ pub struct CryptoVec {
     p: *mut u8,
     size: usize,
     capacity: usize,
+    // Returned to the pool when dropped, see `from_slice_pooled`.
+    #[cfg_attr(not(feature = "pool"), allow(dead_code))]
+    pooled: bool,
 }
 
 impl Unpin for CryptoVec {}
@@ -143,6 +149,7 @@ impl Default for CryptoVec {
             p: std::ptr::NonNull::dangling().as_ptr(),
             size: 0,
             capacity: 0,
+            pooled: false,
         }
     }
 }
@@ -192,7 +199,12 @@ impl CryptoVec {
             let layout = std::alloc::Layout::from_size_align_unchecked(capacity, 1);
             let p = std::alloc::alloc_zeroed(layout);
             mlock(p, capacity);
-            CryptoVec { p, capacity, size }
+            CryptoVec {
+                p,
+                capacity,
+                size,
+                pooled: false,
+            }
         }
     }
 
@@ -207,6 +219,7 @@ impl CryptoVec {
                 p,
                 capacity,
                 size: 0,
+                pooled: false,
             }
         }
     }
@@ -414,6 +427,29 @@ impl CryptoVec {
         }
         v
     }
+
+    /// Like [`CryptoVec::from_slice`], but with the `pool` feature, the
+    /// buffer is taken from a process-wide pool of small buffers, and
+    /// returned to it, zeroed, when dropped. This saves an allocation
+    /// and its `mlock` for each short-lived buffer, such as the data
+    /// of a channel message.
+    ///
+    /// ```
+    /// let v = russh_cryptovec::CryptoVec::from_slice_pooled(b"test");
+    /// assert_eq!(&v[..], b"test");
+    /// ```
+    pub fn from_slice_pooled(s: &[u8]) -> CryptoVec {
+        #[cfg(feature = "pool")]
+        {
+            let mut v = pool::take(s.len());
+            v.extend(s);
+            v
+        }
+        #[cfg(not(feature = "pool"))]
+        {
+            CryptoVec::from_slice(s)
+        }
+    }
 }
 
 impl Drop for CryptoVec {
@@ -423,6 +459,10 @@ impl Drop for CryptoVec {
                 for i in 0..self.size {
                     std::ptr::write_volatile(self.p.add(i), 0)
                 }
+                #[cfg(feature = "pool")]
+                if self.pooled && pool::give(self.p, self.capacity) {
+                    return;
+                }
                 munlock(self.p, self.capacity);
                 let layout = std::alloc::Layout::from_size_align_unchecked(self.capacity, 1);
                 std::alloc::dealloc(self.p, layout);
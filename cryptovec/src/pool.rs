@@ -0,0 +1,86 @@
+//! A process-wide pool of small, locked buffers, from which
+//! [`CryptoVec::from_slice_pooled`] draws.
+//!
+//! Buffers are kept by capacity (always a power of two), up to
+//! [`MAX_CAPACITY`] bytes and [`MAX_BUFFERS`] buffers per capacity,
+//! the others are freed as usual. Kept buffers stay allocated and
+//! locked in memory, and are zeroed before being kept.
+use std::sync::Mutex;
+
+use super::CryptoVec;
+
+/// The largest capacity kept, enough for the data of a channel message
+/// with the default maximum packet sizes.
+pub const MAX_CAPACITY: usize = 1 << 16;
+
+/// The number of buffers kept for each capacity.
+pub const MAX_BUFFERS: usize = 64;
+
+const CLASSES: usize = MAX_CAPACITY.trailing_zeros() as usize + 1;
+
+// The addresses of the kept buffers, by log2 of their capacity.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+static POOL: [Mutex<Vec<usize>>; CLASSES] = [EMPTY; CLASSES];
+
+fn class(capacity: usize) -> Option<&'static Mutex<Vec<usize>>> {
+    if capacity > MAX_CAPACITY {
+        return None;
+    }
+    POOL.get(capacity.trailing_zeros() as usize)
+}
+
+/// An empty pooled buffer with room for `size` bytes.
+pub(crate) fn take(size: usize) -> CryptoVec {
+    let capacity = size.next_power_of_two();
+    let kept = class(capacity).and_then(|c| c.lock().ok()?.pop());
+    let mut v = match kept {
+        Some(p) => CryptoVec {
+            p: p as *mut u8,
+            size: 0,
+            capacity,
+            pooled: true,
+        },
+        None => CryptoVec::with_capacity(capacity),
+    };
+    v.pooled = true;
+    v
+}
+
+/// Keeps the zeroed buffer `p` of `capacity` bytes, or returns `false`
+/// if the pool is full for this capacity.
+pub(crate) fn give(p: *mut u8, capacity: usize) -> bool {
+    let mut kept = match class(capacity).map(|c| c.lock()) {
+        Some(Ok(kept)) => kept,
+        _ => return false,
+    };
+    if kept.len() >= MAX_BUFFERS {
+        return false;
+    }
+    kept.push(p as usize);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused_zeroed() {
+        // A capacity no other test uses, since the pool is shared.
+        let v = CryptoVec::from_slice_pooled(&[0xaa; 3000]);
+        let p = v.p;
+        drop(v);
+        let v = take(2500);
+        assert_eq!(v.p, p);
+        assert_eq!(v.capacity, 4096);
+        let bytes = unsafe { std::slice::from_raw_parts(v.p, v.capacity) };
+        assert!(bytes.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn large_buffers_are_freed() {
+        let v = CryptoVec::from_slice_pooled(&[1; MAX_CAPACITY + 1]);
+        assert!(!give(v.p, v.capacity));
+    }
+}
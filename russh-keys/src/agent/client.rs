@@ -3,14 +3,28 @@ use std::convert::TryFrom;
 use byteorder::{BigEndian, ByteOrder};
 use log::debug;
 use russh_cryptovec::CryptoVec;
+use ssh_encoding::Encode;
+use ssh_key::Certificate;
 use tokio;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use super::{msg, Constraint};
+use super::{msg, Constraint, Hop};
 use crate::encoding::{Encoding, Reader};
 use crate::key::{PublicKey, SignatureHash};
 use crate::{key, protocol, Error, PublicKeyBase64};
 
+/// An identity held by an SSH agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentIdentity {
+    /// A plain public key.
+    PublicKey { key: PublicKey, comment: String },
+    /// A certificate, loaded alongside its private key.
+    Certificate {
+        certificate: Certificate,
+        comment: String,
+    },
+}
+
 /// SSH agent client.
 pub struct AgentClient<S: AsyncRead + AsyncWrite> {
     stream: S,
@@ -58,7 +72,63 @@ impl AgentClient<tokio::net::UnixStream> {
     }
 }
 
-#[cfg(not(unix))]
+#[cfg(windows)]
+const ERROR_PIPE_BUSY: i32 = 231;
+
+/// How many times, 50ms apart, [`AgentClient::connect_named_pipe`]
+/// tries to open a pipe whose instances are all in use: 5s in total.
+#[cfg(windows)]
+const PIPE_BUSY_RETRIES: usize = 100;
+
+/// The pipe of the agent shipped with OpenSSH for Windows.
+#[cfg(windows)]
+const OPENSSH_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+#[cfg(windows)]
+impl AgentClient<tokio::net::windows::named_pipe::NamedPipeClient> {
+    /// Connect to an SSH agent listening on a named pipe, such as the
+    /// OpenSSH for Windows agent (`\\.\pipe\openssh-ssh-agent`).
+    ///
+    /// If the pipe stays busy for about 5 seconds, this returns the
+    /// `ERROR_PIPE_BUSY` error.
+    pub async fn connect_named_pipe<P: AsRef<std::ffi::OsStr>>(path: P) -> Result<Self, Error> {
+        let mut retries = PIPE_BUSY_RETRIES;
+        let stream = loop {
+            match tokio::net::windows::named_pipe::ClientOptions::new().open(path.as_ref()) {
+                Ok(stream) => break stream,
+                // All instances of the pipe are in use, try again.
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) && retries > 0 => retries -= 1,
+                Err(e) => return Err(e.into()),
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        };
+        Ok(AgentClient {
+            stream,
+            buf: CryptoVec::new(),
+        })
+    }
+
+    /// Connect to the agent whose pipe is named by `SSH_AUTH_SOCK`, or
+    /// to the OpenSSH for Windows agent if the variable isn't set.
+    pub async fn connect_env() -> Result<Self, Error> {
+        let (path, not_found) = if let Ok(var) = std::env::var("SSH_AUTH_SOCK") {
+            (var, Error::BadAuthSock)
+        } else {
+            (
+                OPENSSH_AGENT_PIPE.to_string(),
+                Error::EnvVar("SSH_AUTH_SOCK"),
+            )
+        };
+        match Self::connect_named_pipe(path).await {
+            Err(Error::IO(io_err)) if io_err.kind() == std::io::ErrorKind::NotFound => {
+                Err(not_found)
+            }
+            owise => owise,
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
 impl AgentClient<tokio::net::TcpStream> {
     /// Build a future that connects to an SSH agent via the provided
     /// stream (on Unix, usually a Unix-domain socket).
@@ -102,6 +172,26 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
         &mut self,
         key: &key::KeyPair,
         constraints: &[Constraint],
+    ) -> Result<(), Error> {
+        self.add_key(key, None, constraints).await
+    }
+
+    /// Like [`AgentClient::add_identity`], but the agent then lists
+    /// and signs with `certificate`, which must certify `key`.
+    pub async fn add_identity_with_certificate(
+        &mut self,
+        key: &key::KeyPair,
+        certificate: &Certificate,
+        constraints: &[Constraint],
+    ) -> Result<(), Error> {
+        self.add_key(key, Some(certificate), constraints).await
+    }
+
+    async fn add_key(
+        &mut self,
+        key: &key::KeyPair,
+        certificate: Option<&Certificate>,
+        constraints: &[Constraint],
     ) -> Result<(), Error> {
         // See IETF draft-miller-ssh-agent-13, section 3.2 for format.
         // https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent
@@ -112,48 +202,50 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
         } else {
             self.buf.push(msg::ADD_ID_CONSTRAINED)
         }
+        // Certified keys start with the certificate instead of the
+        // public key, the rest of the private key is unchanged.
+        let certificate = certificate.map(certificate_blob).transpose()?;
+        if let Some(ref blob) = certificate {
+            self.buf.extend_ssh_string(blob.reader(0).read_string()?);
+            self.buf.extend_ssh_string(blob);
+        }
         match *key {
             key::KeyPair::Ed25519(ref pair) => {
-                self.buf.extend_ssh_string(b"ssh-ed25519");
+                if certificate.is_none() {
+                    self.buf.extend_ssh_string(b"ssh-ed25519");
+                }
                 self.buf.extend_ssh_string(pair.verifying_key().as_bytes());
                 self.buf.push_u32_be(64);
                 self.buf.extend(pair.to_bytes().as_slice());
                 self.buf.extend(pair.verifying_key().as_bytes());
                 self.buf.extend_ssh_string(b"");
             }
-            #[allow(clippy::unwrap_used)] // key is known to be private
             key::KeyPair::RSA { ref key, .. } => {
-                self.buf.extend_ssh_string(b"ssh-rsa");
-                self.buf
-                    .extend_ssh(&protocol::RsaPrivateKey::try_from(key)?);
+                let key = protocol::RsaPrivateKey::try_from(key)?;
+                if certificate.is_none() {
+                    self.buf.extend_ssh_string(b"ssh-rsa");
+                    self.buf.extend_ssh(&key);
+                } else {
+                    self.buf.extend_ssh_mpint(&key.private_exponent);
+                    self.buf.extend_ssh_mpint(&key.coefficient);
+                    self.buf.extend_ssh_mpint(&key.prime1);
+                    self.buf.extend_ssh_mpint(&key.prime2);
+                    self.buf.extend_ssh_string(&key.comment);
+                }
             }
             key::KeyPair::EC { ref key } => {
-                self.buf.extend_ssh_string(key.algorithm().as_bytes());
-                self.buf.extend_ssh_string(key.ident().as_bytes());
-                self.buf
-                    .extend_ssh_string(&key.to_public_key().to_sec1_bytes());
+                if certificate.is_none() {
+                    self.buf.extend_ssh_string(key.algorithm().as_bytes());
+                    self.buf.extend_ssh_string(key.ident().as_bytes());
+                    self.buf
+                        .extend_ssh_string(&key.to_public_key().to_sec1_bytes());
+                }
                 self.buf.extend_ssh_mpint(&key.to_secret_bytes());
                 self.buf.extend_ssh_string(b""); // comment
             }
         }
-        if !constraints.is_empty() {
-            for cons in constraints {
-                match *cons {
-                    Constraint::KeyLifetime { seconds } => {
-                        self.buf.push(msg::CONSTRAIN_LIFETIME);
-                        self.buf.push_u32_be(seconds);
-                    }
-                    Constraint::Confirm => self.buf.push(msg::CONSTRAIN_CONFIRM),
-                    Constraint::Extensions {
-                        ref name,
-                        ref details,
-                    } => {
-                        self.buf.push(msg::CONSTRAIN_EXTENSION);
-                        self.buf.extend_ssh_string(name);
-                        self.buf.extend_ssh_string(details);
-                    }
-                }
-            }
+        for cons in constraints {
+            extend_constraint(&mut self.buf, cons);
         }
         let len = self.buf.len() - 4;
         BigEndian::write_u32(&mut self.buf[..], len as u32);
@@ -182,21 +274,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
         if !constraints.is_empty() {
             self.buf.push_u32_be(constraints.len() as u32);
             for cons in constraints {
-                match *cons {
-                    Constraint::KeyLifetime { seconds } => {
-                        self.buf.push(msg::CONSTRAIN_LIFETIME);
-                        self.buf.push_u32_be(seconds)
-                    }
-                    Constraint::Confirm => self.buf.push(msg::CONSTRAIN_CONFIRM),
-                    Constraint::Extensions {
-                        ref name,
-                        ref details,
-                    } => {
-                        self.buf.push(msg::CONSTRAIN_EXTENSION);
-                        self.buf.extend_ssh_string(name);
-                        self.buf.extend_ssh_string(details);
-                    }
-                }
+                extend_constraint(&mut self.buf, cons);
             }
         }
         let len = self.buf.len() - 4;
@@ -233,6 +311,37 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
     /// Ask the agent for a list of the currently registered secret
     /// keys.
     pub async fn request_identities(&mut self) -> Result<Vec<PublicKey>, Error> {
+        Ok(self
+            .request_identities_with_comments()
+            .await?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    /// Like [`AgentClient::request_identities`], with the comment the
+    /// agent stores with each key, usually the path it was loaded from.
+    /// Certificates are left out, see
+    /// [`AgentClient::request_identities_with_certificates`].
+    pub async fn request_identities_with_comments(
+        &mut self,
+    ) -> Result<Vec<(PublicKey, String)>, Error> {
+        Ok(self
+            .request_identities_with_certificates()
+            .await?
+            .into_iter()
+            .filter_map(|identity| match identity {
+                AgentIdentity::PublicKey { key, comment } => Some((key, comment)),
+                AgentIdentity::Certificate { .. } => None,
+            })
+            .collect())
+    }
+
+    /// Ask the agent for all its identities, including the
+    /// certificates loaded alongside private keys.
+    pub async fn request_identities_with_certificates(
+        &mut self,
+    ) -> Result<Vec<AgentIdentity>, Error> {
         self.buf.clear();
         self.buf.resize(4);
         self.buf.push(msg::REQUEST_IDENTITIES);
@@ -249,11 +358,19 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
             let n = r.read_u32()?;
             for _ in 0..n {
                 let key_blob = r.read_string()?;
-                let _comment = r.read_string()?;
-                keys.push(key::parse_public_key(
-                    key_blob,
-                    Some(SignatureHash::SHA2_512),
-                )?);
+                let comment = String::from_utf8_lossy(r.read_string()?).into_owned();
+                let key_type = key_blob.reader(0).read_string()?;
+                keys.push(if key_type.ends_with(CERT_SUFFIX) {
+                    AgentIdentity::Certificate {
+                        certificate: Certificate::from_bytes(key_blob)?,
+                        comment,
+                    }
+                } else {
+                    AgentIdentity::PublicKey {
+                        key: key::parse_public_key(key_blob, Some(SignatureHash::SHA2_512))?,
+                        comment,
+                    }
+                });
             }
         }
 
@@ -264,36 +381,54 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
     pub fn sign_request(
         mut self,
         public: &key::PublicKey,
-        mut data: CryptoVec,
+        data: CryptoVec,
     ) -> impl futures::Future<Output = (Self, Result<CryptoVec, Error>)> {
         debug!("sign_request: {:?}", data);
         let hash = self.prepare_sign_request(public, &data);
+        self.sign_prepared(hash, data)
+    }
 
-        async move {
-            if let Err(e) = hash {
-                return (self, Err(e));
-            }
+    /// Like [`AgentClient::sign_request`], but names the key by
+    /// `certificate`, for keys added with
+    /// [`AgentClient::add_identity_with_certificate`].
+    pub fn sign_request_with_certificate(
+        mut self,
+        certificate: &Certificate,
+        data: CryptoVec,
+    ) -> impl futures::Future<Output = (Self, Result<CryptoVec, Error>)> {
+        debug!("sign_request_with_certificate: {:?}", data);
+        let hash = self.prepare_certificate_sign_request(certificate, &data);
+        self.sign_prepared(hash, data)
+    }
 
-            let resp = self.read_response().await;
-            debug!("resp = {:?}", &self.buf[..]);
+    async fn sign_prepared(
+        mut self,
+        hash: Result<u32, Error>,
+        mut data: CryptoVec,
+    ) -> (Self, Result<CryptoVec, Error>) {
+        if let Err(e) = hash {
+            return (self, Err(e));
+        }
+
+        let resp = self.read_response().await;
+        debug!("resp = {:?}", &self.buf[..]);
+        if let Err(e) = resp {
+            return (self, Err(e));
+        }
+
+        #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
+        // length is checked, hash already checked
+        if !self.buf.is_empty() && self.buf[0] == msg::SIGN_RESPONSE {
+            let resp = self.write_signature(hash.unwrap(), &mut data);
             if let Err(e) = resp {
                 return (self, Err(e));
             }
-
-            #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
-            // length is checked, hash already checked
-            if !self.buf.is_empty() && self.buf[0] == msg::SIGN_RESPONSE {
-                let resp = self.write_signature(hash.unwrap(), &mut data);
-                if let Err(e) = resp {
-                    return (self, Err(e));
-                }
-                (self, Ok(data))
-            } else if self.buf.first() == Some(&msg::FAILURE) {
-                (self, Err(Error::AgentFailure))
-            } else {
-                debug!("self.buf = {:?}", &self.buf[..]);
-                (self, Ok(data))
-            }
+            (self, Ok(data))
+        } else if self.buf.first() == Some(&msg::FAILURE) {
+            (self, Err(Error::AgentFailure))
+        } else {
+            debug!("self.buf = {:?}", &self.buf[..]);
+            (self, Ok(data))
         }
     }
 
@@ -302,7 +437,6 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
         self.buf.resize(4);
         self.buf.push(msg::SIGN_REQUEST);
         key_blob(public, &mut self.buf)?;
-        self.buf.extend_ssh_string(data);
         debug!("public = {:?}", public);
         let hash = match public {
             PublicKey::RSA { hash, .. } => match hash {
@@ -312,6 +446,29 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
             },
             _ => 0,
         };
+        self.finish_sign_request(data, hash)
+    }
+
+    fn prepare_certificate_sign_request(
+        &mut self,
+        certificate: &Certificate,
+        data: &[u8],
+    ) -> Result<u32, Error> {
+        self.buf.clear();
+        self.buf.resize(4);
+        self.buf.push(msg::SIGN_REQUEST);
+        self.buf.extend_ssh_string(&certificate_blob(certificate)?);
+        // Like OpenSSH, RSA certificates are used with SHA-512.
+        let hash = if certificate.algorithm().is_rsa() {
+            4
+        } else {
+            0
+        };
+        self.finish_sign_request(data, hash)
+    }
+
+    fn finish_sign_request(&mut self, data: &[u8], hash: u32) -> Result<u32, Error> {
+        self.buf.extend_ssh_string(data);
         self.buf.push_u32_be(hash);
         let len = self.buf.len() - 4;
         BigEndian::write_u32(&mut self.buf[..], len as u32);
@@ -454,6 +611,31 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
         Ok(())
     }
 
+    /// Tell the agent which host the connection is authenticating to
+    /// (`session-bind@openssh.com`), so that it can check the
+    /// destination constraints of its keys. `signature` is the host's
+    /// signature of `session_id` from the key exchange, as an SSH
+    /// signature blob.
+    pub async fn bind_session(
+        &mut self,
+        host_key: &key::PublicKey,
+        session_id: &[u8],
+        signature: &[u8],
+        forwarding: bool,
+    ) -> Result<(), Error> {
+        self.buf.clear();
+        self.buf.resize(4);
+        self.buf.push(msg::EXTENSION);
+        self.buf.extend_ssh_string(msg::EXTENSION_SESSION_BIND);
+        self.buf.extend_ssh_string(&host_key.public_key_bytes());
+        self.buf.extend_ssh_string(session_id);
+        self.buf.extend_ssh_string(signature);
+        self.buf.push(forwarding as u8);
+        let len = self.buf.len() - 4;
+        BigEndian::write_u32(&mut self.buf[..], len as u32);
+        self.read_success().await
+    }
+
     /// Send a custom message to the agent.
     pub async fn extension(&mut self, typ: &[u8], ext: &[u8]) -> Result<(), Error> {
         self.buf.clear();
@@ -485,6 +667,64 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
     }
 }
 
+/// Suffix of the names of the OpenSSH certificate types.
+const CERT_SUFFIX: &[u8] = b"-cert-v01@openssh.com";
+
+fn extend_constraint(buf: &mut CryptoVec, constraint: &Constraint) {
+    match *constraint {
+        Constraint::KeyLifetime { seconds } => {
+            buf.push(msg::CONSTRAIN_LIFETIME);
+            buf.push_u32_be(seconds)
+        }
+        Constraint::Confirm => buf.push(msg::CONSTRAIN_CONFIRM),
+        Constraint::Extensions {
+            ref name,
+            ref details,
+        } => {
+            buf.push(msg::CONSTRAIN_EXTENSION);
+            buf.extend_ssh_string(name);
+            buf.extend_ssh_string(details);
+        }
+        Constraint::RestrictDestination { ref constraints } => {
+            // Each constraint and each of its hops is a string of its own.
+            let mut details = CryptoVec::new();
+            for c in constraints {
+                let mut constraint = CryptoVec::new();
+                constraint.extend_ssh_string(&hop_blob(&c.from));
+                constraint.extend_ssh_string(&hop_blob(&c.to));
+                constraint.extend_ssh_string(b""); // reserved
+                details.extend_ssh_string(&constraint);
+            }
+            buf.push(msg::CONSTRAIN_EXTENSION);
+            buf.extend_ssh_string(msg::CONSTRAIN_RESTRICT_DESTINATION);
+            buf.extend_ssh_string(&details);
+        }
+        Constraint::SkProvider { ref path } => {
+            buf.push(msg::CONSTRAIN_EXTENSION);
+            buf.extend_ssh_string(msg::CONSTRAIN_SK_PROVIDER);
+            buf.extend_ssh_string(path.as_bytes());
+        }
+    }
+}
+
+fn certificate_blob(certificate: &Certificate) -> Result<Vec<u8>, Error> {
+    let mut blob = Vec::new();
+    certificate.encode(&mut blob)?;
+    Ok(blob)
+}
+
+fn hop_blob(hop: &Hop) -> CryptoVec {
+    let mut buf = CryptoVec::new();
+    buf.extend_ssh_string(hop.user.as_bytes());
+    buf.extend_ssh_string(hop.hostname.as_bytes());
+    buf.extend_ssh_string(b""); // reserved
+    for (key, is_ca) in &hop.keys {
+        buf.extend_ssh_string(&key.public_key_bytes());
+        buf.push(*is_ca as u8);
+    }
+    buf
+}
+
 fn key_blob(public: &key::PublicKey, buf: &mut CryptoVec) -> Result<(), Error> {
     match *public {
         PublicKey::RSA { ref key, .. } => {
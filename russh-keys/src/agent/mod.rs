@@ -1,3 +1,5 @@
+use crate::key::PublicKey;
+
 /// Write clients for SSH agents.
 pub mod client;
 mod msg;
@@ -13,4 +15,36 @@ pub enum Constraint {
     Confirm,
     /// Custom constraints
     Extensions { name: Vec<u8>, details: Vec<u8> },
+    /// The key may only be used on the listed hops
+    /// (`restrict-destination-v00@openssh.com`). The agent then refuses
+    /// to sign on connections that weren't bound to a session with
+    /// [`AgentClient::bind_session`](client::AgentClient::bind_session).
+    RestrictDestination {
+        constraints: Vec<DestinationConstraint>,
+    },
+    /// Library implementing the security key holding the private key
+    /// (`sk-provider@openssh.com`).
+    SkProvider { path: String },
+}
+
+/// A hop the key may be used on, from one host to another.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DestinationConstraint {
+    /// The host authenticating. The default value means the host
+    /// running the agent.
+    pub from: Hop,
+    /// The host being authenticated to.
+    pub to: Hop,
+}
+
+/// One end of a [`DestinationConstraint`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Hop {
+    /// The user on that host, or an empty string for any user.
+    pub user: String,
+    /// The name of the host, or an empty string for the local host.
+    pub hostname: String,
+    /// The keys identifying the host, each tagged with whether it is
+    /// a certificate authority rather than a host key.
+    pub keys: Vec<(PublicKey, bool)>,
 }
@@ -21,3 +21,7 @@ pub const CONSTRAIN_LIFETIME: u8 = 1;
 pub const CONSTRAIN_CONFIRM: u8 = 2;
 // pub const CONSTRAIN_MAXSIGN: u8 = 3;
 pub const CONSTRAIN_EXTENSION: u8 = 255;
+
+pub const EXTENSION_SESSION_BIND: &[u8] = b"session-bind@openssh.com";
+pub const CONSTRAIN_RESTRICT_DESTINATION: &[u8] = b"restrict-destination-v00@openssh.com";
+pub const CONSTRAIN_SK_PROVIDER: &[u8] = b"sk-provider@openssh.com";
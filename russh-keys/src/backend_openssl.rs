@@ -1,9 +1,12 @@
 use std::convert::TryFrom;
 
 use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
+use openssl::md::{Md, MdRef};
 use openssl::pkey::{PKey, Private, Public};
-use openssl::rsa::Rsa;
+use openssl::pkey_ctx::{PkeyCtx, PkeyCtxRef};
+use openssl::rsa::{Padding, Rsa};
 
 use crate::key::{RsaCrtExtra, SignatureHash};
 use crate::{protocol, Error};
@@ -15,11 +18,23 @@ pub struct RsaPublic {
 }
 
 impl RsaPublic {
+    /// The size of the modulus, in bits.
+    pub fn bits(&self) -> usize {
+        self.key.n().num_bits() as usize
+    }
+
     pub fn verify_detached(&self, hash: &SignatureHash, msg: &[u8], sig: &[u8]) -> bool {
         openssl::sign::Verifier::new(message_digest_for(hash), &self.pkey)
             .and_then(|mut v| v.verify_oneshot(sig, msg))
             .unwrap_or(false)
     }
+
+    /// Verifies `sig` against the `digest` of a message, computed with `hash`.
+    pub fn verify_digest(&self, hash: &SignatureHash, digest: &[u8], sig: &[u8]) -> bool {
+        pkey_ctx(&self.pkey, hash, |ctx| ctx.verify_init())
+            .and_then(|mut ctx| ctx.verify(digest, sig))
+            .unwrap_or(false)
+    }
 }
 
 impl TryFrom<&protocol::RsaPublicKey<'_>> for RsaPublic {
@@ -124,6 +139,14 @@ impl RsaPrivate {
                 .sign_oneshot_to_vec(msg)?,
         )
     }
+
+    /// Signs the `digest` of a message, computed with `hash`.
+    pub fn sign_digest(&self, hash: &SignatureHash, digest: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut ctx = pkey_ctx(&self.pkey, hash, |ctx| ctx.sign_init())?;
+        let mut sig = Vec::new();
+        ctx.sign_to_vec(digest, &mut sig)?;
+        Ok(sig)
+    }
 }
 
 impl<'a> TryFrom<&RsaPrivate> for protocol::RsaPrivateKey<'a> {
@@ -196,6 +219,28 @@ fn message_digest_for(hash: &SignatureHash) -> MessageDigest {
     }
 }
 
+fn md_for(hash: &SignatureHash) -> &'static MdRef {
+    match hash {
+        SignatureHash::SHA2_256 => Md::sha256(),
+        SignatureHash::SHA2_512 => Md::sha512(),
+        SignatureHash::SHA1 => Md::sha1(),
+    }
+}
+
+/// A PKCS#1 v1.5 context on `pkey`, for signing or verifying digests
+/// made with `hash`.
+fn pkey_ctx<T>(
+    pkey: &PKey<T>,
+    hash: &SignatureHash,
+    init: impl FnOnce(&mut PkeyCtxRef<T>) -> Result<(), ErrorStack>,
+) -> Result<PkeyCtx<T>, ErrorStack> {
+    let mut ctx = PkeyCtx::new(pkey)?;
+    init(&mut ctx)?;
+    ctx.set_rsa_padding(Padding::PKCS1)?;
+    ctx.set_signature_md(md_for(hash))?;
+    Ok(ctx)
+}
+
 fn calc_dp_dq(d: &BigNumRef, p: &BigNumRef, q: &BigNumRef) -> Result<(BigNum, BigNum), Error> {
     let one = BigNum::from_u32(1)?;
     let p1 = p - one.as_ref();
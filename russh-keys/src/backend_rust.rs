@@ -12,9 +12,19 @@ pub struct RsaPublic {
 }
 
 impl RsaPublic {
+    /// The size of the modulus, in bits.
+    pub fn bits(&self) -> usize {
+        self.key.n().bits()
+    }
+
     pub fn verify_detached(&self, hash: &SignatureHash, msg: &[u8], sig: &[u8]) -> bool {
+        self.verify_digest(hash, &hash_msg(hash, msg), sig)
+    }
+
+    /// Verifies `sig` against the `digest` of a message, computed with `hash`.
+    pub fn verify_digest(&self, hash: &SignatureHash, digest: &[u8], sig: &[u8]) -> bool {
         self.key
-            .verify(signature_scheme_for_hash(hash), &hash_msg(hash, msg), sig)
+            .verify(signature_scheme_for_hash(hash), digest, sig)
             .is_ok()
     }
 }
@@ -99,9 +109,12 @@ impl RsaPrivate {
     }
 
     pub fn sign(&self, hash: &SignatureHash, msg: &[u8]) -> Result<Vec<u8>, Error> {
-        Ok(self
-            .key
-            .sign(signature_scheme_for_hash(hash), &hash_msg(hash, msg))?)
+        self.sign_digest(hash, &hash_msg(hash, msg))
+    }
+
+    /// Signs the `digest` of a message, computed with `hash`.
+    pub fn sign_digest(&self, hash: &SignatureHash, digest: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(self.key.sign(signature_scheme_for_hash(hash), digest)?)
     }
 }
 
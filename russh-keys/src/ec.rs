@@ -20,11 +20,18 @@ mod local_p521 {
         key: &SigningKey,
         rng: &mut impl CryptoRngCore,
         msg: &[u8],
+    ) -> Result<Signature> {
+        try_sign_prehash_with_rng(key, rng, &Sha512::digest(msg))
+    }
+
+    pub fn try_sign_prehash_with_rng(
+        key: &SigningKey,
+        rng: &mut impl CryptoRngCore,
+        prehash: &[u8],
     ) -> Result<Signature> {
         use ecdsa::hazmat::{bits2field, sign_prehashed};
         use elliptic_curve::Field;
-        let prehash = Sha512::digest(msg);
-        let z = bits2field::<NistP521>(&prehash)?;
+        let z = bits2field::<NistP521>(prehash)?;
         let k = p521::Scalar::random(rng);
         sign_prehashed(key.as_nonzero_scalar().as_ref(), k, &z).map(|sig| sig.0)
     }
@@ -59,6 +66,15 @@ impl PublicKey {
         }
     }
 
+    /// The size of the curve, in bits.
+    pub fn bits(&self) -> usize {
+        match self {
+            Self::P256(_) => 256,
+            Self::P384(_) => 384,
+            Self::P521(_) => 521,
+        }
+    }
+
     /// Returns the ECC public key algorithm name defined in RFC 5656 section 6.2, in the form of
     /// `"ecdsa-sha2-[identifier]"`.
     pub fn algorithm(&self) -> &'static str {
@@ -116,6 +132,27 @@ impl PublicKey {
         }
         .map_err(Error::from)
     }
+
+    /// Verifies the digest of a message, computed with the associated
+    /// digest algorithm, against signature `(r, s)`.
+    pub fn verify_prehash(&self, prehash: &[u8], r: &[u8], s: &[u8]) -> Result<(), Error> {
+        use ecdsa::signature::hazmat::PrehashVerifier;
+        match self {
+            Self::P256(key) => key.verify_prehash(
+                prehash,
+                &signature_from_scalar_bytes::<p256::NistP256>(r, s)?,
+            ),
+            Self::P384(key) => key.verify_prehash(
+                prehash,
+                &signature_from_scalar_bytes::<p384::NistP384>(r, s)?,
+            ),
+            Self::P521(key) => key.verify_prehash(
+                prehash,
+                &signature_from_scalar_bytes::<p521::NistP521>(r, s)?,
+            ),
+        }
+        .map_err(Error::from)
+    }
 }
 
 impl std::fmt::Debug for PublicKey {
@@ -157,6 +194,26 @@ impl PrivateKey {
         }
     }
 
+    /// Generates a random `PrivateKey` for the curve of the algorithm name.
+    pub fn generate(algorithm: &[u8]) -> Result<Self, Error> {
+        match algorithm {
+            crate::KEYTYPE_ECDSA_SHA2_NISTP256 => {
+                Ok(Self::P256(p256::ecdsa::SigningKey::random(&mut safe_rng())))
+            }
+            crate::KEYTYPE_ECDSA_SHA2_NISTP384 => {
+                Ok(Self::P384(p384::ecdsa::SigningKey::random(&mut safe_rng())))
+            }
+            crate::KEYTYPE_ECDSA_SHA2_NISTP521 => {
+                Ok(Self::P521(local_p521::SigningKey::random(&mut safe_rng())))
+            }
+            _ => Err(Error::UnsupportedKeyType {
+                key_type_string: String::from_utf8(algorithm.to_vec())
+                    .unwrap_or_else(|_| format!("{algorithm:?}")),
+                key_type_raw: algorithm.to_vec(),
+            }),
+        }
+    }
+
     /// Returns the elliptic curve domain parameter identifiers defined in RFC 5656 section 6.1.
     pub fn ident(&self) -> &'static str {
         match self {
@@ -209,6 +266,24 @@ impl PrivateKey {
             }
         })
     }
+
+    /// Sign the digest of a message, computed with the associated digest algorithm.
+    pub fn try_sign_prehash(&self, prehash: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        use ecdsa::signature::hazmat::RandomizedPrehashSigner;
+        Ok(match self {
+            Self::P256(key) => {
+                signature_to_scalar_bytes(key.sign_prehash_with_rng(&mut safe_rng(), prehash)?)
+            }
+            Self::P384(key) => {
+                signature_to_scalar_bytes(key.sign_prehash_with_rng(&mut safe_rng(), prehash)?)
+            }
+            Self::P521(key) => signature_to_scalar_bytes(local_p521::try_sign_prehash_with_rng(
+                key,
+                &mut safe_rng(),
+                prehash,
+            )?),
+        })
+    }
 }
 
 impl std::fmt::Debug for PrivateKey {
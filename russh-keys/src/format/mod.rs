@@ -84,7 +84,10 @@ pub fn decode_secret_key(secret: &str, password: Option<&str>) -> Result<key::Ke
         Some(Format::Openssh) => decode_openssh(&secret, password),
         Some(Format::Rsa) => decode_rsa(&secret),
         Some(Format::Pkcs5Encrypted(enc)) => decode_pkcs5(&secret, password, enc),
+        Some(Format::Pkcs8Encrypted) if password.is_none() => Err(Error::KeyIsEncrypted),
         Some(Format::Pkcs8Encrypted) | Some(Format::Pkcs8) => {
+            // Unencrypted keys don't need a password, ignore it.
+            let password = password.filter(|_| matches!(format, Some(Format::Pkcs8Encrypted)));
             let result = self::pkcs8::decode_pkcs8(&secret, password.map(|x| x.as_bytes()));
             #[cfg(feature = "legacy-ed25519-pkcs8-parser")]
             {
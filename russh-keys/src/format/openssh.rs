@@ -14,8 +14,12 @@ use crate::{ec, protocol, Error};
 pub fn decode_openssh(secret: &[u8], password: Option<&str>) -> Result<KeyPair, Error> {
     let pk = PrivateKey::from_bytes(secret)?;
     KeyPair::try_from(&match password {
-        Some(password) => pk.decrypt(password)?,
-        None => pk,
+        Some(password) if pk.is_encrypted() => pk.decrypt(password).map_err(|e| match e {
+            // The check integers differ.
+            ssh_key::Error::Crypto => Error::WrongPassphrase,
+            e => e.into(),
+        })?,
+        _ => pk,
     })
 }
 
@@ -24,11 +24,15 @@ pub fn decode_pkcs5(
                 #[allow(clippy::unwrap_used)] // AES parameters are static
                 let c = cbc::Decryptor::<Aes128>::new_from_slices(&md5.0, &iv[..]).unwrap();
                 let mut dec = secret.to_vec();
-                c.decrypt_padded_mut::<Pkcs7>(&mut dec)?.to_vec()
+                c.decrypt_padded_mut::<Pkcs7>(&mut dec)
+                    .map_err(|_| Error::WrongPassphrase)?
+                    .to_vec()
             }
             Encryption::Aes256Cbc(_) => unimplemented!(),
         };
-        super::decode_rsa(&sec)
+        // A wrong password only goes unnoticed until here if the
+        // padding happened to be valid.
+        super::decode_rsa(&sec).map_err(|_| Error::WrongPassphrase)
     } else {
         Err(Error::KeyIsEncrypted)
     }
@@ -10,7 +10,14 @@ pub fn decode_pkcs8(ciphertext: &[u8], password: Option<&[u8]>) -> Result<key::K
     let doc = SecretDocument::try_from(ciphertext)?;
     let doc = if let Some(password) = password {
         doc.decode_msg::<pkcs8::EncryptedPrivateKeyInfo>()?
-            .decrypt(password)?
+            .decrypt(password)
+            .map_err(|e| match e {
+                // pkcs5 0.7 reports invalid padding as `EncryptFailed`.
+                pkcs8::Error::EncryptedPrivateKey(
+                    pkcs5::Error::DecryptFailed | pkcs5::Error::EncryptFailed,
+                ) => Error::WrongPassphrase,
+                e => e.into(),
+            })?
     } else {
         doc
     };
@@ -16,7 +16,7 @@ use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
 
 pub use backend::{RsaPrivate, RsaPublic};
-use ed25519_dalek::{Signer, Verifier};
+use ed25519_dalek::{Signer as _, Verifier as _};
 use rand_core::OsRng;
 use russh_cryptovec::CryptoVec;
 use serde::{Deserialize, Serialize};
@@ -92,6 +92,17 @@ pub trait Verify {
     fn verify_server_auth(&self, buffer: &[u8], sig: &[u8]) -> bool;
 }
 
+/// The hash function of a key fingerprint, see [`PublicKey::fingerprint`].
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub enum HashAlg {
+    /// `SHA256:` followed by the unpadded base64 digest, as shown by
+    /// current OpenSSH versions.
+    Sha256,
+    /// `MD5:` followed by the hex digest in colon-separated pairs, as
+    /// shown by OpenSSH before 6.8.
+    Md5,
+}
+
 /// The hash function used for signing with RSA keys.
 #[derive(Eq, PartialEq, Clone, Copy, Debug, Hash, Serialize, Deserialize)]
 #[allow(non_camel_case_types)]
@@ -123,6 +134,15 @@ impl SignatureHash {
     }
 }
 
+/// The family of a key, whose sizes are comparable, see
+/// [`PublicKey::bit_length`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyFamily {
+    Rsa,
+    Ecdsa,
+    Ed25519,
+}
+
 /// Public key
 #[derive(Eq, Debug, Clone)]
 pub enum PublicKey {
@@ -178,6 +198,25 @@ impl PublicKey {
         })
     }
 
+    /// The family of this key.
+    pub fn family(&self) -> KeyFamily {
+        match *self {
+            PublicKey::Ed25519(_) => KeyFamily::Ed25519,
+            PublicKey::RSA { .. } => KeyFamily::Rsa,
+            PublicKey::EC { .. } => KeyFamily::Ecdsa,
+        }
+    }
+
+    /// The size of this key in bits: the size of the modulus for RSA
+    /// keys, and of the curve for the others.
+    pub fn bit_length(&self) -> usize {
+        match *self {
+            PublicKey::Ed25519(_) => 256,
+            PublicKey::RSA { ref key, .. } => key.bits(),
+            PublicKey::EC { ref key } => key.bits(),
+        }
+    }
+
     /// Algorithm name for that key.
     pub fn name(&self) -> &'static str {
         match *self {
@@ -202,14 +241,41 @@ impl PublicKey {
         }
     }
 
-    /// Compute the key fingerprint, hashed with sha2-256.
-    pub fn fingerprint(&self) -> String {
+    /// Verify a signature of a message given in several parts, see [`Verifier`].
+    pub fn verifier(&self) -> Verifier<'_> {
+        Verifier::new(self)
+    }
+
+    /// Compute the key fingerprint in OpenSSH's format, for instance
+    /// `SHA256:ldyiXa1JQakitNU5tErauu8DvWQ1dZ7aXu+rm7KQuog`. The hash
+    /// is computed over the key blob, see
+    /// [`PublicKeyBase64::public_key_bytes`](crate::PublicKeyBase64::public_key_bytes).
+    pub fn fingerprint(&self, alg: HashAlg) -> String {
         use super::PublicKeyBase64;
         let key = self.public_key_bytes();
+        match alg {
+            HashAlg::Sha256 => Self::fingerprint_sha256_raw(&key),
+            HashAlg::Md5 => {
+                let digest = md5::compute(&key);
+                let hex: Vec<String> = digest.0.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("MD5:{}", hex.join(":"))
+            }
+        }
+    }
+
+    /// Compute the `SHA256:` fingerprint of a key blob as received,
+    /// exactly as OpenSSH does, without parsing it. For keys parsed by
+    /// this crate, this is the same as
+    /// [`fingerprint`](Self::fingerprint), since blobs that wouldn't
+    /// serialize back to the same bytes are rejected.
+    pub fn fingerprint_sha256_raw(blob: &[u8]) -> String {
         use sha2::{Digest, Sha256};
         let mut hasher = Sha256::new();
-        hasher.update(&key[..]);
-        data_encoding::BASE64_NOPAD.encode(&hasher.finalize())
+        hasher.update(blob);
+        format!(
+            "SHA256:{}",
+            data_encoding::BASE64_NOPAD.encode(&hasher.finalize())
+        )
     }
 
     pub fn set_algorithm(&mut self, algorithm: SignatureHash) {
@@ -328,6 +394,14 @@ impl KeyPair {
         Some(KeyPair::RSA { key, hash })
     }
 
+    /// Generate an ECDSA key pair, on the curve of `name`, which is one
+    /// of [`ECDSA_SHA2_NISTP256`], [`ECDSA_SHA2_NISTP384`] and
+    /// [`ECDSA_SHA2_NISTP521`].
+    pub fn generate_ecdsa(name: Name) -> Option<Self> {
+        let key = ec::PrivateKey::generate(name.0.as_bytes()).ok()?;
+        Some(KeyPair::EC { key })
+    }
+
     /// Sign a slice using this algorithm.
     pub fn sign_detached(&self, to_sign: &[u8]) -> Result<Signature, Error> {
         match self {
@@ -346,6 +420,11 @@ impl KeyPair {
         }
     }
 
+    /// Sign a message given in several parts, see [`Signer`].
+    pub fn signer(&self) -> Signer<'_> {
+        Signer::new(self)
+    }
+
     #[doc(hidden)]
     /// This is used by the server to sign the initial DH kex
     /// message. Note: we are not signing the same kind of thing as in
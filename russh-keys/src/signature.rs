@@ -188,3 +188,178 @@ impl Clone for SignatureBytes {
         result
     }
 }
+
+/// The state of a message being signed or verified incrementally: a
+/// hash context, or the message itself for Ed25519, which signs the
+/// whole message rather than a digest of it.
+enum Hasher {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha384(sha2::Sha384),
+    Sha512(sha2::Sha512),
+    Message(Vec<u8>),
+}
+
+impl Hasher {
+    fn for_rsa(hash: &SignatureHash) -> Self {
+        use digest::Digest;
+        match hash {
+            SignatureHash::SHA2_256 => Hasher::Sha256(sha2::Sha256::new()),
+            SignatureHash::SHA2_512 => Hasher::Sha512(sha2::Sha512::new()),
+            SignatureHash::SHA1 => Hasher::Sha1(sha1::Sha1::new()),
+        }
+    }
+
+    fn for_ec(curve: &str) -> Self {
+        use digest::Digest;
+        match curve {
+            "nistp256" => Hasher::Sha256(sha2::Sha256::new()),
+            "nistp384" => Hasher::Sha384(sha2::Sha384::new()),
+            _ => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        use digest::Digest;
+        match self {
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha384(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+            Hasher::Message(m) => m.extend_from_slice(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        use digest::Digest;
+        match self {
+            Hasher::Sha1(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha384(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+            Hasher::Message(m) => m,
+        }
+    }
+}
+
+/// Signs a message given in several parts, without keeping it in
+/// memory, producing the same signature as
+/// [`KeyPair::sign_detached`](crate::key::KeyPair::sign_detached) on
+/// the concatenation of the parts.
+///
+/// Ed25519 signs the message itself rather than a digest, so its
+/// parts are still buffered until [`finish`](Self::finish). To sign
+/// large payloads with any key, see [`sshsig`](crate::sshsig).
+pub struct Signer<'a> {
+    key: &'a crate::key::KeyPair,
+    hasher: Hasher,
+}
+
+impl<'a> Signer<'a> {
+    pub fn new(key: &'a crate::key::KeyPair) -> Self {
+        use crate::key::KeyPair;
+        let hasher = match key {
+            KeyPair::Ed25519(_) => Hasher::Message(Vec::new()),
+            KeyPair::RSA { hash, .. } => Hasher::for_rsa(hash),
+            KeyPair::EC { key } => Hasher::for_ec(key.ident()),
+        };
+        Signer { key, hasher }
+    }
+
+    /// Adds `data` to the message.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data)
+    }
+
+    /// Signs the message.
+    pub fn finish(self) -> Result<Signature, Error> {
+        use crate::encoding::Encoding;
+        use crate::key::KeyPair;
+        let digest = self.hasher.finish();
+        match self.key {
+            KeyPair::Ed25519(_) => self.key.sign_detached(&digest),
+            KeyPair::RSA { key, hash } => Ok(Signature::RSA {
+                bytes: key.sign_digest(hash, &digest)?,
+                hash: *hash,
+            }),
+            KeyPair::EC { key } => {
+                let (r, s) = key.try_sign_prehash(&digest)?;
+                let mut signature = Vec::new();
+                signature.extend_ssh_mpint(&r);
+                signature.extend_ssh_mpint(&s);
+                Ok(Signature::ECDSA {
+                    algorithm: key.algorithm(),
+                    signature,
+                })
+            }
+        }
+    }
+}
+
+impl std::io::Write for Signer<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Verifies a signature of a message given in several parts, without
+/// keeping it in memory, like
+/// [`PublicKey::verify_detached`](crate::key::PublicKey::verify_detached)
+/// on the concatenation of the parts.
+///
+/// As with [`Signer`], the parts are buffered for Ed25519 keys.
+pub struct Verifier<'a> {
+    key: &'a crate::key::PublicKey,
+    hasher: Hasher,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(key: &'a crate::key::PublicKey) -> Self {
+        use crate::key::PublicKey;
+        let hasher = match key {
+            PublicKey::Ed25519(_) => Hasher::Message(Vec::new()),
+            PublicKey::RSA { hash, .. } => Hasher::for_rsa(hash),
+            PublicKey::EC { key } => Hasher::for_ec(key.ident()),
+        };
+        Verifier { key, hasher }
+    }
+
+    /// Adds `data` to the message.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data)
+    }
+
+    /// Verifies `sig` against the message.
+    pub fn finish(self, sig: &[u8]) -> bool {
+        use crate::encoding::Reader;
+        use crate::key::PublicKey;
+        let digest = self.hasher.finish();
+        match self.key {
+            PublicKey::Ed25519(_) => self.key.verify_detached(&digest, sig),
+            PublicKey::RSA { key, hash } => key.verify_digest(hash, &digest, sig),
+            PublicKey::EC { key } => {
+                let mut r = sig.reader(0);
+                match (r.read_mpint(), r.read_mpint()) {
+                    (Ok(r), Ok(s)) => key.verify_prehash(&digest, r, s).is_ok(),
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+impl std::io::Write for Verifier<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Signatures of files and other data in OpenSSH's `SSHSIG` format,
+//! as made and checked by `ssh-keygen -Y sign` and `ssh-keygen -Y
+//! verify`:
+//!
+//! ```text
+//! -----BEGIN SSH SIGNATURE-----
+//! U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAg...
+//! -----END SSH SIGNATURE-----
+//! ```
+//!
+//! The key signs a digest of the message, along with a namespace
+//! (`"file"` for ssh-keygen's default) telling what the signature is
+//! for, so that a signature made for one purpose can't be used for
+//! another. Messages are hashed as they are given to a [`Signer`] or
+//! a [`Verifier`], so they never need to be in memory at once.
+//!
+//! ```
+//! # fn main() -> Result<(), russh_keys::Error> {
+//! use russh_keys::key::KeyPair;
+//! use russh_keys::sshsig::{self, HashAlg, LineEnding, SshSig};
+//!
+//! let key = KeyPair::generate_ed25519().unwrap();
+//! let mut signer = sshsig::Signer::new(&key, "file", HashAlg::Sha512);
+//! signer.update(b"hello, ");
+//! signer.update(b"world\n");
+//! let armored = signer.finish()?.to_pem(LineEnding::LF)?;
+//!
+//! let signature = SshSig::from_pem(&armored)?;
+//! let mut verifier = sshsig::Verifier::new(&signature);
+//! verifier.update(b"hello, world\n");
+//! verifier.finish(&key.clone_public_key()?, "file")?;
+//! # Ok(())
+//! # }
+//! ```
+use std::convert::TryFrom;
+
+pub use ssh_key::{HashAlg, LineEnding, SshSig};
+
+use crate::encoding::{Encoding, Reader};
+use crate::key::{KeyPair, PublicKey, SignatureHash};
+use crate::{Error, PublicKeyBase64};
+
+const MAGIC_PREAMBLE: &[u8] = b"SSHSIG";
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    fn new(hash_alg: HashAlg) -> Self {
+        use digest::Digest;
+        match hash_alg {
+            HashAlg::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            _ => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        use digest::Digest;
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        use digest::Digest;
+        match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+/// The data actually signed by the key, for the digest `hash` of the
+/// message.
+fn signed_data(namespace: &str, reserved: &[u8], hash_alg: HashAlg, hash: &[u8]) -> Vec<u8> {
+    let mut data = MAGIC_PREAMBLE.to_vec();
+    data.extend_ssh_string(namespace.as_bytes());
+    data.extend_ssh_string(reserved);
+    data.extend_ssh_string(hash_alg.as_str().as_bytes());
+    data.extend_ssh_string(hash);
+    data
+}
+
+/// Signs a message given in several parts.
+pub struct Signer<'a> {
+    key: &'a KeyPair,
+    namespace: String,
+    hash_alg: HashAlg,
+    hasher: Hasher,
+}
+
+impl<'a> Signer<'a> {
+    /// Signs with `key`, for the purpose described by `namespace`.
+    /// RSA keys set to sign with SHA-1 sign with SHA-512 instead, like
+    /// ssh-keygen, since OpenSSH refuses SHA-1 here.
+    pub fn new(key: &'a KeyPair, namespace: &str, hash_alg: HashAlg) -> Self {
+        Signer {
+            key,
+            namespace: namespace.to_string(),
+            hash_alg,
+            hasher: Hasher::new(hash_alg),
+        }
+    }
+
+    /// Adds `data` to the message.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data)
+    }
+
+    /// Signs the message.
+    pub fn finish(self) -> Result<SshSig, Error> {
+        let rsa_sha512;
+        let key = match self.key {
+            KeyPair::RSA {
+                hash: SignatureHash::SHA1,
+                ..
+            } => {
+                rsa_sha512 = self.key.with_signature_hash(SignatureHash::SHA2_512);
+                rsa_sha512.as_ref().unwrap_or(self.key)
+            }
+            key => key,
+        };
+        let hash = self.hasher.finish();
+        let data = signed_data(&self.namespace, &[], self.hash_alg, &hash);
+        let signature = key.sign_detached(&data)?;
+        let public_key = key.clone_public_key()?.public_key_bytes();
+        Ok(SshSig::new(
+            ssh_encoding::Decode::decode(&mut public_key.reader(0))?,
+            self.namespace,
+            self.hash_alg,
+            ssh_key::Signature::new(ssh_key::Algorithm::new(key.name())?, signature.as_ref())?,
+        )?)
+    }
+}
+
+impl std::io::Write for Signer<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Verifies a signature of a message given in several parts.
+pub struct Verifier<'a> {
+    signature: &'a SshSig,
+    hasher: Hasher,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(signature: &'a SshSig) -> Self {
+        Verifier {
+            signature,
+            hasher: Hasher::new(signature.hash_alg()),
+        }
+    }
+
+    /// Adds `data` to the message.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data)
+    }
+
+    /// Checks that the signature of the message was made by `key`, for
+    /// the purpose described by `namespace`, and is valid. The key the
+    /// signature claims to be made by is
+    /// [`SshSig::public_key`](ssh_key::SshSig::public_key).
+    pub fn finish(self, key: &PublicKey, namespace: &str) -> Result<(), Error> {
+        let sig = self.signature;
+        let mut signer = PublicKey::try_from(sig.public_key())?;
+        if &signer != key || sig.namespace() != namespace {
+            return Err(Error::InvalidSignature);
+        }
+        // Keys are compared without their RSA hash, which is given by
+        // the signature. OpenSSH refuses SHA-1 here.
+        match sig.algorithm() {
+            ssh_key::Algorithm::Rsa {
+                hash: Some(ssh_key::HashAlg::Sha256),
+            } => signer.set_algorithm(SignatureHash::SHA2_256),
+            ssh_key::Algorithm::Rsa {
+                hash: Some(ssh_key::HashAlg::Sha512),
+            } => signer.set_algorithm(SignatureHash::SHA2_512),
+            ssh_key::Algorithm::Rsa { .. } => return Err(Error::InvalidSignature),
+            _ => {}
+        }
+        let hash = self.hasher.finish();
+        let data = signed_data(sig.namespace(), sig.reserved(), sig.hash_alg(), &hash);
+        if signer.verify_detached(&data, sig.signature_bytes()) {
+            Ok(())
+        } else {
+            Err(Error::InvalidSignature)
+        }
+    }
+}
+
+impl std::io::Write for Verifier<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+///
+/// Remote port forwarding driven by the client event stream instead of
+/// `Handler` callbacks: connections to <remote port> on the server are
+/// forwarded to <local host>:<local port>.
+///
+/// Run this example with:
+/// cargo run --example client_events -- -k <private key path> <host> <remote port> <local host> <local port>
+///
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Parser;
+use futures::StreamExt;
+use log::{info, warn};
+use russh::client::ClientEvent;
+use russh::keys::*;
+use russh::*;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let cli = Cli::parse();
+
+    let key_pair = load_secret_key(&cli.private_key, None)?;
+    let config = Arc::new(client::Config::default());
+    let mut session = client::connect(config, (cli.host, cli.port), Client {}).await?;
+    // Take the stream before authenticating, so that we see the banner.
+    let mut events = session.events();
+
+    let user = cli.username.unwrap_or("root".to_string());
+    if !session
+        .authenticate_publickey(user, Arc::new(key_pair))
+        .await?
+    {
+        anyhow::bail!("Authentication failed");
+    }
+
+    session.tcpip_forward("localhost", cli.remote_port).await?;
+    info!("Forwarding remote port {}", cli.remote_port);
+
+    while let Some(event) = events.next().await {
+        match event {
+            ClientEvent::Banner(banner) => println!("{banner}"),
+            ClientEvent::ForwardedTcpIp {
+                channel,
+                originator_address,
+                originator_port,
+                ..
+            } => {
+                info!("New connection from {originator_address}:{originator_port}");
+                let target = (cli.local_host.clone(), cli.local_port);
+                tokio::spawn(async move {
+                    let mut stream = channel.into_stream();
+                    match tokio::net::TcpStream::connect(target).await {
+                        Ok(mut socket) => {
+                            let _ = tokio::io::copy_bidirectional(&mut socket, &mut stream).await;
+                        }
+                        Err(e) => warn!("Could not connect to the local target: {e}"),
+                    }
+                });
+            }
+            ClientEvent::Disconnected(info) => {
+                info!("Disconnected: {info:?}");
+            }
+            event => info!("Event: {event:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// Only the host key check is needed, everything else goes to the
+/// event stream.
+struct Client {}
+
+#[async_trait]
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        _server_public_key: &key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+#[derive(clap::Parser)]
+pub struct Cli {
+    #[clap(index = 1)]
+    host: String,
+
+    #[clap(long, short, default_value_t = 22)]
+    port: u16,
+
+    #[clap(long, short)]
+    username: Option<String>,
+
+    #[clap(long, short = 'k')]
+    private_key: PathBuf,
+
+    #[clap(index = 2)]
+    remote_port: u32,
+
+    #[clap(index = 3)]
+    local_host: String,
+
+    #[clap(index = 4)]
+    local_port: u16,
+}
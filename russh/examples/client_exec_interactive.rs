@@ -2,7 +2,6 @@
 /// Run this example with:
 /// cargo run --example client_exec_interactive -- -k <private key path> <host> <command>
 ///
-use std::convert::TryFrom;
 use std::env;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -14,9 +13,9 @@ use clap::Parser;
 use log::info;
 use russh::keys::*;
 use russh::*;
-use termion::raw::IntoRawMode;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -42,15 +41,19 @@ async fn main() -> Result<()> {
     info!("Connected");
 
     let code = {
-        // We're using `termion` to put the terminal into raw mode, so that we can
-        // display the output of interactive applications correctly
-        let _raw_term = std::io::stdout().into_raw_mode()?;
+        // The modes have to be read before the terminal is put into raw mode
+        let modes = terminal_modes();
+        // We're using `crossterm` to put the terminal into raw mode (on Unix
+        // and Windows alike), so that we can display the output of
+        // interactive applications correctly
+        let _raw_term = RawMode::enable()?;
         ssh.call(
             &cli.command
                 .into_iter()
                 .map(|x| shell_escape::escape(x.into())) // arguments are escaped manually since the SSH protocol doesn't support quoting
                 .collect::<Vec<_>>()
                 .join(" "),
+            &modes,
         )
         .await?
     };
@@ -60,6 +63,53 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// The modes of the local terminal, passed on to the remote PTY.
+#[cfg(all(unix, feature = "tty"))]
+fn terminal_modes() -> TerminalModes {
+    TerminalModes::from_current_tty().unwrap_or_default()
+}
+
+/// Without the `tty` feature, let the server pick its defaults.
+#[cfg(not(all(unix, feature = "tty")))]
+fn terminal_modes() -> TerminalModes {
+    TerminalModes::new()
+}
+
+/// Leaves raw mode when dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        crossterm::terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+}
+
+/// Reads stdin on a dedicated thread: `tokio::io::stdin` would keep the
+/// runtime from shutting down until the user types something.
+fn spawn_stdin_reader() -> mpsc::Receiver<std::io::Result<Vec<u8>>> {
+    let (sender, receiver) = mpsc::channel(1);
+    std::thread::spawn(move || {
+        use std::io::Read;
+        let mut stdin = std::io::stdin();
+        let mut buf = vec![0; 1024];
+        loop {
+            let r = stdin.read(&mut buf).map(|n| buf[..n].to_vec());
+            let done = !matches!(r, Ok(ref data) if !data.is_empty());
+            if sender.blocking_send(r).is_err() || done {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
 struct Client {}
 
 // More SSH event handlers
@@ -131,45 +181,43 @@ impl Session {
         Ok(Self { session })
     }
 
-    async fn call(&mut self, command: &str) -> Result<u32> {
+    async fn call(&mut self, command: &str, modes: &TerminalModes) -> Result<u32> {
         let mut channel = self.session.channel_open_session().await?;
 
         // This example doesn't terminal resizing after the connection is established
-        let (w, h) = termion::terminal_size()?;
+        let (w, h) = crossterm::terminal::size()?;
 
         // Request an interactive PTY from the server
         channel
-            .request_pty(
+            .request_pty_with(
                 false,
                 &env::var("TERM").unwrap_or("xterm".into()),
-                w as u32,
-                h as u32,
-                0,
-                0,
-                &[], // ideally you want to pass the actual terminal modes here
+                TerminalSize::new(w as u32, h as u32),
+                modes,
             )
             .await?;
         channel.exec(true, command).await?;
 
         let code;
-        let mut stdin = tokio_fd::AsyncFd::try_from(0)?;
-        let mut stdout = tokio_fd::AsyncFd::try_from(1)?;
-        let mut buf = vec![0; 1024];
+        let mut stdin = spawn_stdin_reader();
+        let mut stdout = tokio::io::stdout();
         let mut stdin_closed = false;
 
         loop {
             // Handle one of the possible events:
             tokio::select! {
                 // There's terminal input available from the user
-                r = stdin.read(&mut buf), if !stdin_closed => {
+                r = stdin.recv(), if !stdin_closed => {
                     match r {
-                        Ok(0) => {
+                        Some(Ok(data)) if !data.is_empty() => {
+                            // Send it to the server
+                            channel.data(&data[..]).await?
+                        }
+                        Some(Err(e)) => return Err(e.into()),
+                        _ => {
                             stdin_closed = true;
                             channel.eof().await?;
-                        },
-                        // Send it to the server
-                        Ok(n) => channel.data(&buf[..n]).await?,
-                        Err(e) => return Err(e.into()),
+                        }
                     };
                 },
                 // There's an event available on the session channel
@@ -0,0 +1,227 @@
+//! Serves the Git repositories under a directory over SSH, for
+//! `git clone ssh://localhost:2222/repo.git`, by running
+//! `git-upload-pack` and `git-receive-pack` for the client's `exec`
+//! requests.
+//!
+//! The command is handled as bytes from end to end: on Unix, paths which
+//! aren't UTF-8 are passed to git exactly as the client sent them.
+//!
+//! ```sh
+//! cargo run --example git_server -- /srv/git
+//! ```
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{error, info};
+use russh::keys::*;
+use russh::server::{Msg, Server as _, Session};
+use russh::*;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+#[tokio::main]
+async fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let root = std::env::args().nth(1).unwrap_or_else(|| ".".into());
+    let config = russh::server::Config {
+        inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
+        auth_rejection_time: std::time::Duration::from_secs(3),
+        auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
+        keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
+        ..Default::default()
+    };
+    let mut sh = Server {
+        root: Arc::new(PathBuf::from(root)),
+        channels: HashMap::new(),
+    };
+    sh.run_on_address(Arc::new(config), ("0.0.0.0", 2222))
+        .await
+        .unwrap();
+}
+
+struct Server {
+    root: Arc<PathBuf>,
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl server::Server for Server {
+    type Handler = Self;
+    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
+        Server {
+            root: self.root.clone(),
+            channels: HashMap::new(),
+        }
+    }
+}
+
+/// Parses `git-upload-pack '/repo.git'` into the program and the
+/// repository path, unquoted as a shell would.
+fn parse_command(command: &[u8]) -> Option<(&'static str, Vec<u8>)> {
+    let space = command.iter().position(|&b| b == b' ')?;
+    let (program, arg) = command.split_at(space);
+    let program = match program {
+        b"git-upload-pack" => "git-upload-pack",
+        b"git-receive-pack" => "git-receive-pack",
+        _ => return None,
+    };
+    // Git quotes the path in single quotes, and a single quote in it
+    // as '\''.
+    let mut path = Vec::new();
+    let mut quoted = false;
+    let mut bytes = arg.get(1..)?.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'\'' => quoted = !quoted,
+            b'\\' if !quoted => path.push(*bytes.next()?),
+            _ => path.push(b),
+        }
+    }
+    (!quoted).then_some((program, path))
+}
+
+#[cfg(unix)]
+fn os_path(path: &[u8]) -> Option<&Path> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(Path::new(std::ffi::OsStr::from_bytes(path)))
+}
+
+#[cfg(not(unix))]
+fn os_path(path: &[u8]) -> Option<&Path> {
+    std::str::from_utf8(path).ok().map(Path::new)
+}
+
+/// The repository at `path` under `root`, without leaving it.
+fn repository(root: &Path, path: &[u8]) -> Option<PathBuf> {
+    let path = os_path(path)?;
+    let mut repository = root.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => repository.push(c),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(repository)
+}
+
+/// Runs `program` on the repository, with its standard streams wired
+/// to the channel, and sends its exit status.
+async fn run_git(
+    mut channel: Channel<Msg>,
+    handle: server::Handle,
+    program: &'static str,
+    repository: PathBuf,
+) -> std::io::Result<()> {
+    let id = channel.id();
+    let mut child = Command::new(program)
+        .arg(&repository)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("piped");
+    let mut stdout = child.stdout.take().expect("piped");
+    let mut stderr = child.stderr.take().expect("piped");
+
+    let mut out = channel.make_writer();
+    let mut err = channel.make_writer_ext(Some(1));
+    let input = async {
+        // Until the client's EOF, then close git's standard input.
+        tokio::io::copy(&mut channel.make_reader(), &mut stdin).await?;
+        stdin.shutdown().await
+    };
+    let output = async {
+        tokio::io::copy(&mut stdout, &mut out).await?;
+        out.flush().await
+    };
+    let errors = async {
+        tokio::io::copy(&mut stderr, &mut err).await?;
+        err.flush().await
+    };
+    let io = async {
+        let (status, ..) = tokio::try_join!(child.wait(), output, errors)?;
+        Ok::<_, std::io::Error>(status)
+    };
+    tokio::pin!(io, input);
+    // git may exit without waiting for the client's EOF, for instance
+    // once a fetch is complete.
+    let mut input_done = false;
+    let status = loop {
+        tokio::select! {
+            status = &mut io => break status?,
+            r = &mut input, if !input_done => {
+                input_done = true;
+                r?;
+            }
+        }
+    };
+
+    let code = status.code().unwrap_or(128);
+    info!("{} {:?} exited with {}", program, repository, code);
+    let _ = handle.exit_status_request(id, code as u32).await;
+    let _ = handle.eof(id).await;
+    let _ = handle.close(id).await;
+    Ok(())
+}
+
+#[async_trait]
+impl server::Handler for Server {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(
+        &mut self,
+        _: &str,
+        _: &key::PublicKey,
+    ) -> Result<server::Auth, Self::Error> {
+        Ok(server::Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let Some((program, path)) = parse_command(data) else {
+            info!("refused command {:?}", String::from_utf8_lossy(data));
+            session.channel_failure(channel);
+            return Ok(());
+        };
+        let (Some(repository), Some(ch)) = (
+            repository(&self.root, &path),
+            self.channels.remove(&channel),
+        ) else {
+            session.channel_failure(channel);
+            return Ok(());
+        };
+        session.channel_success(channel);
+
+        let handle = session.handle();
+        tokio::spawn(async move {
+            if let Err(e) = run_git(ch, handle.clone(), program, repository).await {
+                error!("{}: {}", program, e);
+                let _ = handle
+                    .extended_data(channel, 1, CryptoVec::from(format!("{}\n", e)))
+                    .await;
+                let _ = handle.exit_status_request(channel, 1).await;
+                let _ = handle.close(channel).await;
+            }
+        });
+        Ok(())
+    }
+}
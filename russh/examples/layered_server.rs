@@ -0,0 +1,161 @@
+//! A server whose handler is composed of layers: `AuthLayer` checks the
+//! passwords and stores the authenticated user in the session's
+//! extensions, and `App`, the business logic, reads it from there
+//! without knowing anything about authentication.
+//!
+//! ```sh
+//! cargo run --example layered_server
+//! ssh -p 2222 alice@localhost whoami   # password: wonderland
+//! ```
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use russh::server::{Auth, Msg, Server as _, Session};
+use russh::*;
+
+#[tokio::main]
+async fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let config = russh::server::Config {
+        inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
+        auth_rejection_time: std::time::Duration::from_secs(3),
+        auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
+        methods: MethodSet::PASSWORD,
+        keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
+        ..Default::default()
+    };
+    let mut users = HashMap::new();
+    users.insert("alice".to_string(), "wonderland".to_string());
+    let mut sh = Server {
+        users: Arc::new(users),
+    };
+    sh.run_on_address(Arc::new(config), ("0.0.0.0", 2222))
+        .await
+        .unwrap();
+}
+
+struct Server {
+    users: Arc<HashMap<String, String>>,
+}
+
+impl server::Server for Server {
+    type Handler = AuthLayer<App>;
+    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> AuthLayer<App> {
+        AuthLayer {
+            users: self.users.clone(),
+            user: None,
+            inner: App {
+                channels: HashMap::new(),
+            },
+        }
+    }
+}
+
+/// The authenticated user, stored in the session's extensions.
+#[derive(Clone, Debug)]
+struct User(String);
+
+/// Checks the passwords, and passes everything else to `inner`.
+struct AuthLayer<H> {
+    users: Arc<HashMap<String, String>>,
+    /// The user of the last successful password check.
+    user: Option<String>,
+    inner: H,
+}
+
+#[async_trait]
+impl<H: server::Handler<Error = anyhow::Error> + Send> server::Handler for AuthLayer<H> {
+    type Error = anyhow::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        if self.users.get(user).map(String::as_str) == Some(password) {
+            self.user = Some(user.to_string());
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
+    }
+
+    async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+        if let Some(user) = self.user.take() {
+            session.extensions_mut().insert(User(user));
+        }
+        self.inner.auth_succeeded(session).await
+    }
+
+    // The callbacks used by the inner layers.
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.inner.channel_open_session(channel, session).await
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.exec_request(channel, data, session).await
+    }
+}
+
+/// Runs `whoami` for the user found in the session's extensions.
+struct App {
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+#[async_trait]
+impl server::Handler for App {
+    type Error = anyhow::Error;
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let Some(channel) = self.channels.remove(&channel) else {
+            return Ok(());
+        };
+        if data != b"whoami" {
+            session.channel_failure(channel.id());
+            return Ok(());
+        }
+        session.channel_success(channel.id());
+
+        // Tasks outside of the callbacks read the extensions through a
+        // view, from the session's handle.
+        let handle = session.handle();
+        tokio::spawn(async move {
+            let user = handle.extensions().get::<User>().await;
+            let answer = match user {
+                Some(User(name)) => format!("{}\n", name),
+                None => "nobody\n".to_string(),
+            };
+            channel.data(answer.as_bytes()).await?;
+            let _ = handle.exit_status_request(channel.id(), 0).await;
+            channel.eof().await?;
+            channel.close().await
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+///
+/// A server authenticating users with keyboard-interactive through PAM.
+/// Every conversation round of the PAM stack is shown to the client,
+/// with prompts echoed or hidden as the stack asks.
+///
+/// Run this example with:
+/// cargo run --features pam --example pam_server -- [PAM service, default "sshd"]
+///
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use russh::server::auth::pam::PamAuthenticator;
+use russh::server::{Auth, Msg, Response, Server as _, Session};
+use russh::*;
+
+#[tokio::main]
+async fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .init();
+
+    let config = russh::server::Config {
+        methods: MethodSet::KEYBOARD_INTERACTIVE,
+        auth_rejection_time: std::time::Duration::from_secs(3),
+        auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
+        keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
+        ..Default::default()
+    };
+    let mut sh = Server {
+        service: std::env::args()
+            .nth(1)
+            .unwrap_or_else(|| "sshd".to_string()),
+    };
+    sh.run_on_address(Arc::new(config), ("0.0.0.0", 2222))
+        .await
+        .unwrap();
+}
+
+struct Server {
+    service: String,
+}
+
+impl server::Server for Server {
+    type Handler = Client;
+    fn new_client(&mut self, peer: Option<std::net::SocketAddr>) -> Client {
+        let mut pam = PamAuthenticator::new(self.service.clone());
+        if let Some(peer) = peer {
+            pam = pam.remote_host(peer.ip().to_string());
+        }
+        Client {
+            pam,
+            user: String::new(),
+        }
+    }
+}
+
+struct Client {
+    pam: PamAuthenticator,
+    user: String,
+}
+
+#[async_trait]
+impl server::Handler for Client {
+    type Error = anyhow::Error;
+
+    async fn auth_keyboard_interactive(
+        &mut self,
+        user: &str,
+        submethods: &str,
+        response: Option<Response<'async_trait>>,
+    ) -> Result<Auth, Self::Error> {
+        // Submethods are only a hint from the client, PAM decides
+        // which prompts to show.
+        log::debug!("keyboard-interactive for {user}, submethods {submethods:?}");
+        self.user = user.to_string();
+        Ok(self.pam.keyboard_interactive(user, response).await)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_success(channel);
+        session.data(
+            channel,
+            CryptoVec::from(format!("Hello {}, PAM let you in.\r\n", self.user)),
+        );
+        session.exit_status_request(channel, 0);
+        session.eof(channel);
+        session.close(channel);
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+///
+/// An echo server running each session on its own thread and runtime,
+/// or, on unix when given a user and group id, in a child process that
+/// drops its privileges before talking to the client. The parent only
+/// accepts connections.
+///
+/// Run this example with:
+/// cargo run --example privsep_server -- <host key file> [uid gid]
+///
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use russh::server::{Acceptor, Msg, PendingConnection, Session};
+use russh::*;
+use russh_keys::key;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [session, host_key, uid, gid] if session == "--session" => {
+            run_child(host_key, uid.parse()?, gid.parse()?)
+        }
+        [host_key] => run_parent(host_key, None),
+        [host_key, uid, gid] => run_parent(host_key, Some((uid.parse()?, gid.parse()?))),
+        _ => Err(anyhow!("usage: privsep_server <host key file> [uid gid]")),
+    }
+}
+
+fn config(host_key: &str) -> anyhow::Result<Arc<server::Config>> {
+    Ok(Arc::new(server::Config {
+        auth_rejection_time: std::time::Duration::from_secs(3),
+        auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
+        keys: vec![russh_keys::load_secret_key(host_key, None).context("host key")?],
+        ..Default::default()
+    }))
+}
+
+fn run_parent(host_key: &str, ids: Option<(u32, u32)>) -> anyhow::Result<()> {
+    let config = config(host_key)?;
+    tokio::runtime::Runtime::new()?.block_on(async move {
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", 2222)).await?;
+        let acceptor = Acceptor::new(listener, config);
+        loop {
+            let pending = acceptor.accept().await?;
+            log::info!("connection from {:?}", pending.peer_addr());
+            match ids {
+                Some((uid, gid)) => spawn_child(pending, host_key, uid, gid)?,
+                None => spawn_thread(pending),
+            }
+        }
+    })
+}
+
+/// Runs the session on a new thread, with its own single-threaded
+/// runtime: a panic or a stuck handler only affects this session.
+fn spawn_thread(pending: PendingConnection) {
+    std::thread::spawn(move || {
+        let result = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(anyhow::Error::from)
+            .and_then(|runtime| runtime.block_on(async { pending.run(Echo).await?.await }));
+        if let Err(e) = result {
+            log::error!("session failed: {:?}", e);
+        }
+    });
+}
+
+/// Runs the session in a child process, which inherits the socket as
+/// its standard input.
+#[cfg(unix)]
+fn spawn_child(
+    pending: PendingConnection,
+    host_key: &str,
+    uid: u32,
+    gid: u32,
+) -> anyhow::Result<()> {
+    use std::os::unix::io::OwnedFd;
+
+    let stream = OwnedFd::from(pending.into_std());
+    let mut child = tokio::process::Command::new(std::env::current_exe()?)
+        .args(["--session", host_key, &uid.to_string(), &gid.to_string()])
+        .stdin(std::process::Stdio::from(stream))
+        .spawn()?;
+    tokio::spawn(async move {
+        let status = child.wait().await;
+        log::info!("session process exited: {:?}", status);
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_child(_: PendingConnection, _: &str, _: u32, _: u32) -> anyhow::Result<()> {
+    Err(anyhow!("running sessions as another user requires unix"))
+}
+
+#[cfg(unix)]
+fn run_child(host_key: &str, uid: u32, gid: u32) -> anyhow::Result<()> {
+    use std::os::unix::io::FromRawFd;
+
+    // Read the host key while we still can.
+    let config = config(host_key)?;
+    // The group must be changed first: once the user has changed, we
+    // aren't allowed to anymore.
+    unsafe {
+        if libc::setgroups(0, std::ptr::null()) != 0
+            || libc::setgid(gid) != 0
+            || libc::setuid(uid) != 0
+        {
+            return Err(std::io::Error::last_os_error()).context("dropping privileges");
+        }
+    }
+    // Safety: the parent passes the connection as our standard input,
+    // and nothing else uses it.
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(0) };
+    let pending = PendingConnection::from_std(stream, config);
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async { pending.run(Echo).await?.await })
+}
+
+#[cfg(not(unix))]
+fn run_child(_: &str, _: u32, _: u32) -> anyhow::Result<()> {
+    Err(anyhow!("running sessions as another user requires unix"))
+}
+
+struct Echo;
+
+#[async_trait]
+impl server::Handler for Echo {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(
+        &mut self,
+        _: &str,
+        _: &key::PublicKey,
+    ) -> Result<server::Auth, Self::Error> {
+        Ok(server::Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.data(channel, CryptoVec::from_slice(data));
+        Ok(())
+    }
+}
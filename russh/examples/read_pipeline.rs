@@ -0,0 +1,133 @@
+//! Measures the throughput of a bulk upload over the loopback
+//! interface, with the server decrypting in its session task, and
+//! then with `read_pipeline_depth`, decrypting on a separate task.
+//!
+//! Build in release mode, on a machine with at least two cores:
+//!
+//! ```sh
+//! cargo run --release --example read_pipeline -- 2048  # MiB
+//! ```
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use russh::keys::*;
+use russh::*;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Warn)
+        .init();
+
+    let mib: u64 = std::env::args()
+        .nth(1)
+        .map(|s| s.parse())
+        .transpose()?
+        .unwrap_or(1024);
+    for depth in [None, Some(16)] {
+        let throughput = upload(mib << 20, depth).await?;
+        println!(
+            "read_pipeline_depth: {:?}\t{:.0} MiB/s",
+            depth,
+            throughput / f64::from(1 << 20)
+        );
+    }
+    Ok(())
+}
+
+/// Uploads `total` bytes to a server using `depth`, and returns the
+/// throughput in bytes per second.
+async fn upload(total: u64, depth: Option<usize>) -> anyhow::Result<f64> {
+    let config = Arc::new(server::Config {
+        keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
+        read_pipeline_depth: depth,
+        ..Default::default()
+    });
+    let socket = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    let (done, mut received) = unbounded_channel();
+    tokio::spawn(async move {
+        let (socket, _) = socket.accept().await?;
+        let server = Server { received: 0, done };
+        server::run_stream(config, socket, server).await?.await
+    });
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {}).await?;
+    let key = Arc::new(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+    if !session.authenticate_publickey("user", key).await? {
+        anyhow::bail!("authentication failed");
+    }
+    let channel = session.channel_open_session().await?;
+    let start = Instant::now();
+    channel.data(tokio::io::repeat(0).take(total)).await?;
+    channel.eof().await?;
+    let n = received.recv().await;
+    let elapsed = start.elapsed();
+    if n != Some(total) {
+        anyhow::bail!("the server received {:?} bytes", n);
+    }
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await?;
+    Ok(total as f64 / elapsed.as_secs_f64())
+}
+
+struct Client {}
+
+#[async_trait]
+impl client::Handler for Client {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(&mut self, _: &key::PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+/// Counts the bytes received on the channel, until its EOF.
+struct Server {
+    received: u64,
+    done: UnboundedSender<u64>,
+}
+
+#[async_trait]
+impl server::Handler for Server {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(
+        &mut self,
+        _: &str,
+        _: &key::PublicKey,
+    ) -> Result<server::Auth, Self::Error> {
+        Ok(server::Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _: Channel<server::Msg>,
+        _: &mut server::Session,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn data(
+        &mut self,
+        _: ChannelId,
+        data: &[u8],
+        _: &mut server::Session,
+    ) -> Result<(), Self::Error> {
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    async fn channel_eof(
+        &mut self,
+        _: ChannelId,
+        _: &mut server::Session,
+    ) -> Result<(), Self::Error> {
+        let _ = self.done.send(std::mem::take(&mut self.received));
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Measures the rate of small channel messages received by a server
+//! over the loopback interface, and the cost of the buffers they are
+//! received in, with and without the `cryptovec-pool` feature:
+//!
+//! ```sh
+//! cargo run --release --example small_messages -- 1000000 64  # messages, bytes
+//! cargo run --release --example small_messages --features cryptovec-pool -- 1000000 64
+//! ```
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use russh::keys::*;
+use russh::*;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Warn)
+        .init();
+
+    let mut args = std::env::args().skip(1).map(|s| s.parse::<usize>());
+    let messages = args.next().transpose()?.unwrap_or(1_000_000);
+    let size = args.next().transpose()?.unwrap_or(64);
+    println!(
+        "cryptovec-pool: {}",
+        if cfg!(feature = "cryptovec-pool") {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+
+    // The buffers alone, as the session allocates and a channel drops them.
+    let data = vec![0; size];
+    for (name, from_slice) in [
+        (
+            "from_slice",
+            CryptoVec::from_slice as fn(&[u8]) -> CryptoVec,
+        ),
+        ("from_slice_pooled", CryptoVec::from_slice_pooled),
+    ] {
+        let start = Instant::now();
+        for _ in 0..messages {
+            let buffer = from_slice(&data);
+            // Reading the buffer keeps the allocation from being optimized
+            // out, without `std::hint::black_box`, newer than our MSRV.
+            if let Some(first) = buffer.first() {
+                unsafe { std::ptr::read_volatile(first) };
+            }
+        }
+        println!(
+            "{}:\t{:.0} ns per buffer",
+            name,
+            start.elapsed().as_nanos() as f64 / messages as f64
+        );
+    }
+
+    let rate = send(messages, size).await?;
+    println!("channel messages:\t{:.0} per second", rate);
+    Ok(())
+}
+
+/// Sends `messages` messages of `size` bytes to a server reading them
+/// from its `Channel`, and returns the number of messages per second.
+async fn send(messages: usize, size: usize) -> anyhow::Result<f64> {
+    let config = Arc::new(server::Config {
+        keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
+        ..Default::default()
+    });
+    let socket = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    let (done, mut received) = unbounded_channel();
+    tokio::spawn(async move {
+        let (socket, _) = socket.accept().await?;
+        server::run_stream(config, socket, Server { done })
+            .await?
+            .await
+    });
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {}).await?;
+    let key = Arc::new(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+    if !session.authenticate_publickey("user", key).await? {
+        anyhow::bail!("authentication failed");
+    }
+    let channel = session.channel_open_session().await?;
+    let data = vec![0; size];
+    let start = Instant::now();
+    for _ in 0..messages {
+        channel.data(&data[..]).await?;
+    }
+    channel.eof().await?;
+    let n = received.recv().await;
+    let elapsed = start.elapsed();
+    if n != Some(messages) {
+        anyhow::bail!("the server received {:?} messages", n);
+    }
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await?;
+    Ok(messages as f64 / elapsed.as_secs_f64())
+}
+
+struct Client {}
+
+#[async_trait]
+impl client::Handler for Client {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(&mut self, _: &key::PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+/// Counts the messages received on the channel, until its EOF.
+struct Server {
+    done: UnboundedSender<usize>,
+}
+
+#[async_trait]
+impl server::Handler for Server {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(
+        &mut self,
+        _: &str,
+        _: &key::PublicKey,
+    ) -> Result<server::Auth, Self::Error> {
+        Ok(server::Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        mut channel: Channel<server::Msg>,
+        _: &mut server::Session,
+    ) -> Result<bool, Self::Error> {
+        let done = self.done.clone();
+        tokio::spawn(async move {
+            let mut received = 0;
+            while let Some(msg) = channel.wait().await {
+                match msg {
+                    ChannelMsg::Data { .. } => received += 1,
+                    ChannelMsg::Eof => break,
+                    _ => {}
+                }
+            }
+            let _ = done.send(received);
+        });
+        Ok(true)
+    }
+}
@@ -19,6 +19,7 @@ use bitflags::bitflags;
 use ssh_key::Certificate;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::Zeroizing;
 
 use crate::keys::{encoding, key};
 use crate::CryptoVec;
@@ -80,10 +81,14 @@ impl<R: AsyncRead + AsyncWrite + Unpin + Send + 'static> Signer
 pub enum Method {
     None,
     Password {
-        password: String,
+        /// Wiped from memory when the method is dropped.
+        password: Zeroizing<String>,
     },
     PublicKey {
         key: Arc<key::KeyPair>,
+        /// Signature algorithm forced by the caller, which
+        /// `server-sig-algs` doesn't override.
+        hash_alg: Option<key::SignatureHash>,
     },
     OpenSSHCertificate {
         key: Arc<key::KeyPair>,
@@ -131,6 +136,10 @@ pub struct AuthRequest {
     pub partial_success: bool,
     pub current: Option<CurrentRequest>,
     pub rejection_count: usize,
+    /// (server only) The methods `methods_user` may use.
+    pub allowed_methods: MethodSet,
+    /// (server only) The user `allowed_methods` was chosen for.
+    pub methods_user: Option<String>,
 }
 
 #[doc(hidden)]
@@ -30,6 +30,10 @@ const CERT_SK_ECDSA_SHA2_P256: &str = "sk-ecdsa-sha2-nistp256-cert-v01@openssh.c
 /// OpenSSH certificate for Ed25519 U2F/FIDO security key
 const CERT_SK_SSH_ED25519: &str = "sk-ssh-ed25519-cert-v01@openssh.com";
 
+/// Suffix of the certificate algorithm names, after the name of the
+/// algorithm of the certified key.
+pub(crate) const CERT_ALGO_SUFFIX: &str = "-cert-v01@openssh.com";
+
 /// None
 const NONE: &str = "none";
 
@@ -1,35 +1,244 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::Mutex;
 
-use crate::ChannelMsg;
+use crate::{ChannelId, ChannelMsg};
+
+/// Capacity of the queue to a [`super::Channel`] when no limit is
+/// configured. Messages that do not fit are kept in the overflow.
+const DEFAULT_CHANNEL_BUFFER_SIZE: usize = 1024;
+
+/// Why the session of a channel ended, if it ended with an error.
+/// Shared with the channel's readers and writers, which would
+/// otherwise only see their queues closing.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionError(Arc<std::sync::Mutex<Option<String>>>);
+
+impl SessionError {
+    fn set(&self, description: &str) {
+        let mut error = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if error.is_none() {
+            *error = Some(description.to_owned());
+        }
+    }
+
+    /// The error reads and writes fail with, if the session failed.
+    pub fn get(&self) -> Option<io::Error> {
+        let error = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        error.as_ref().map(|description| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("session failed: {}", description),
+            )
+        })
+    }
+}
+
+/// The label of a channel, shared between the session and the
+/// [`super::Channel`], since the peer may announce it after the
+/// channel is open.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChannelLabel(Arc<std::sync::Mutex<Option<String>>>);
+
+impl ChannelLabel {
+    pub fn set(&self, label: &str) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(label.to_owned());
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
 
 /// A handle to the [`super::Channel`]'s to be able to transmit messages
 /// to it and update it's `window_size`.
 #[derive(Debug)]
 pub struct ChannelRef {
-    pub(super) sender: UnboundedSender<ChannelMsg>,
+    pub(super) sender: Sender<ChannelMsg>,
     pub(super) window_size: Arc<Mutex<u32>>,
+    /// Set once the peer closed the channel, so that writers fail
+    /// instead of queuing data nobody will read.
+    pub(super) closed: Arc<AtomicBool>,
+    pub(super) session_error: SessionError,
+    pub(super) label: ChannelLabel,
+    /// Messages that did not fit in the queue yet, in order.
+    pub(super) overflow: VecDeque<ChannelMsg>,
+    /// Whether the session should stop reading from the socket while
+    /// the queue is full, instead of filling `overflow`.
+    pub(super) bounded: bool,
 }
 
 impl ChannelRef {
-    pub fn new(sender: UnboundedSender<ChannelMsg>) -> Self {
-        Self {
-            sender,
-            window_size: Default::default(),
-        }
+    /// Creates the queue between the session loop and a channel. With a
+    /// `buffer_size`, the session stops reading from the socket while
+    /// the queue is full. Without one, messages that do not fit are
+    /// kept aside without limit.
+    pub fn new(buffer_size: Option<usize>) -> (Self, Receiver<ChannelMsg>) {
+        let (sender, receiver) =
+            tokio::sync::mpsc::channel(buffer_size.unwrap_or(DEFAULT_CHANNEL_BUFFER_SIZE).max(1));
+        (
+            Self {
+                sender,
+                window_size: Default::default(),
+                closed: Default::default(),
+                session_error: Default::default(),
+                label: Default::default(),
+                overflow: VecDeque::new(),
+                bounded: buffer_size.is_some(),
+            },
+            receiver,
+        )
     }
 
     pub fn window_size(&self) -> &Arc<Mutex<u32>> {
         &self.window_size
     }
+
+    pub fn closed(&self) -> &Arc<AtomicBool> {
+        &self.closed
+    }
+
+    pub(crate) fn session_error(&self) -> &SessionError {
+        &self.session_error
+    }
+
+    pub(crate) fn label(&self) -> &ChannelLabel {
+        &self.label
+    }
+
+    /// The state shared with the writers of the channel, for a writer
+    /// made without the [`super::Channel`].
+    pub(crate) fn writer_parts(&self, max_packet_size: u32) -> WriterParts {
+        WriterParts {
+            window_size: self.window_size.clone(),
+            max_packet_size,
+            closed: self.closed.clone(),
+            session_error: self.session_error.clone(),
+        }
+    }
+
+    /// Marks the channel as closed by the peer.
+    pub(crate) fn set_closed(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+
+    /// Queues a message for the channel, without waiting. Messages are
+    /// delivered in order; this only fails if the channel was dropped.
+    pub fn send(&mut self, msg: ChannelMsg) -> Result<(), SendError<ChannelMsg>> {
+        if self.sender.is_closed() {
+            self.overflow.clear();
+            return Err(SendError(msg));
+        }
+        if !self.overflow.is_empty() {
+            self.overflow.push_back(msg);
+            return Ok(());
+        }
+        match self.sender.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(msg)) => {
+                self.overflow.push_back(msg);
+                Ok(())
+            }
+            Err(TrySendError::Closed(msg)) => Err(SendError(msg)),
+        }
+    }
+
+    /// Moves as many messages as possible from the overflow to the queue.
+    pub(crate) fn flush_overflow(&mut self) {
+        while let Some(msg) = self.overflow.pop_front() {
+            match self.sender.try_send(msg) {
+                Ok(()) => {}
+                Err(TrySendError::Full(msg)) => {
+                    self.overflow.push_front(msg);
+                    return;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    self.overflow.clear();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Whether messages are waiting for room in the queue, or (if the
+    /// queue is bounded) the queue is full.
+    fn is_congested(&self) -> bool {
+        !self.sender.is_closed()
+            && (!self.overflow.is_empty() || (self.bounded && self.sender.capacity() == 0))
+    }
+
+    /// Whether the session should stop reading from the socket until
+    /// the consumer of this channel catches up.
+    pub(crate) fn is_full(&self) -> bool {
+        self.bounded && self.is_congested()
+    }
+}
+
+impl Drop for ChannelRef {
+    fn drop(&mut self) {
+        // The session is gone, but the channel may still want to read
+        // what was received before.
+        if self.overflow.is_empty() || self.sender.is_closed() {
+            return;
+        }
+        let sender = self.sender.clone();
+        let overflow = std::mem::take(&mut self.overflow);
+        crate::runtime::spawn_if_running(async move {
+            for msg in overflow {
+                if sender.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
 }
 
-impl std::ops::Deref for ChannelRef {
-    type Target = UnboundedSender<ChannelMsg>;
+/// What a writer needs to send on a channel it doesn't own, see
+/// [`ChannelRef::writer_parts`].
+#[derive(Debug)]
+pub struct WriterParts {
+    pub(crate) window_size: Arc<Mutex<u32>>,
+    pub(crate) max_packet_size: u32,
+    pub(crate) closed: Arc<AtomicBool>,
+    pub(crate) session_error: SessionError,
+}
+
+/// Whether any channel needs the session to stop reading from the socket.
+pub(crate) fn channels_full(channels: &HashMap<ChannelId, ChannelRef>) -> bool {
+    channels.values().any(ChannelRef::is_full)
+}
+
+/// Resolves when a congested channel has room in its queue again (or
+/// was dropped), and never if no channel is congested.
+pub(crate) async fn wait_channel_capacity(channels: &HashMap<ChannelId, ChannelRef>) {
+    let waits: Vec<_> = channels
+        .values()
+        .filter(|c| c.is_congested())
+        .map(|c| Box::pin(c.sender.reserve()))
+        .collect();
+    if waits.is_empty() {
+        futures::future::pending::<()>().await;
+    } else {
+        let _ = futures::future::select_all(waits).await;
+    }
+}
+
+/// Records, in all the channels, that the session ended because of
+/// the error described by `description`.
+pub(crate) fn fail_channels(channels: &HashMap<ChannelId, ChannelRef>, description: &str) {
+    for channel in channels.values() {
+        channel.session_error.set(description);
+    }
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.sender
+/// Moves waiting messages to the channels' queues.
+pub(crate) fn flush_channels(channels: &mut HashMap<ChannelId, ChannelRef>) {
+    for channel in channels.values_mut() {
+        channel.flush_overflow();
     }
 }
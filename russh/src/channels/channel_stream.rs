@@ -1,19 +1,34 @@
+use std::future::Future;
 use std::io;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
 
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use super::io::{ChannelRx, ChannelTx};
 use super::{ChannelId, ChannelMsg};
+use crate::runtime::Sleep;
 
 /// AsyncRead/AsyncWrite wrapper for SSH Channels
+///
+/// By default, each write is sent right away, as one or more DATA
+/// messages. When writes are small, [`set_write_buffer_size`] lets
+/// the stream gather them into fewer, larger messages instead.
+///
+/// [`set_write_buffer_size`]: ChannelStream::set_write_buffer_size
 pub struct ChannelStream<S>
 where
     S: From<(ChannelId, ChannelMsg)> + 'static,
 {
     tx: ChannelTx<S>,
     rx: ChannelRx<'static, S>,
+    /// Small writes waiting to be sent together.
+    write_buffer: Vec<u8>,
+    write_buffer_size: usize,
+    flush_delay: Duration,
+    /// Sends `write_buffer` once `flush_delay` has elapsed.
+    flush_timer: Option<Sleep>,
 }
 
 impl<S> ChannelStream<S>
@@ -21,19 +36,88 @@ where
     S: From<(ChannelId, ChannelMsg)>,
 {
     pub(super) fn new(tx: ChannelTx<S>, rx: ChannelRx<'static, S>) -> Self {
-        Self { tx, rx }
+        Self {
+            tx,
+            rx,
+            write_buffer: Vec::new(),
+            write_buffer_size: 0,
+            flush_delay: Duration::from_millis(5),
+            flush_timer: None,
+        }
+    }
+
+    /// The largest DATA message the peer accepts on this channel.
+    /// This is a good write buffer size for bulk transfers.
+    pub fn max_packet_size(&self) -> u32 {
+        self.tx.max_packet_size()
+    }
+
+    /// Gathers writes smaller than `size` bytes, and sends them once
+    /// `size` bytes are buffered, the stream is flushed or shut down,
+    /// or the flush delay has elapsed (see
+    /// [`set_flush_delay`](Self::set_flush_delay)). Larger writes are
+    /// sent right away. `0`, the default, disables buffering.
+    ///
+    /// Like everything else on this stream, buffered data only moves
+    /// while the stream is polled: for a reply to be read, reading
+    /// from the stream is enough, but buffered data is lost if the
+    /// stream is dropped before being flushed.
+    ///
+    /// ```no_run
+    /// # async fn f(channel: russh::Channel<russh::client::Msg>) {
+    /// let mut stream = channel.into_stream();
+    /// stream.set_write_buffer_size(stream.max_packet_size() as usize);
+    /// # }
+    /// ```
+    pub fn set_write_buffer_size(&mut self, size: usize) {
+        self.write_buffer_size = size;
+    }
+
+    /// How long buffered writes may wait for more data before being
+    /// sent. Defaults to 5 milliseconds; zero sends each write right
+    /// away, merging it only with the writes the peer's window held
+    /// back.
+    pub fn set_flush_delay(&mut self, delay: Duration) {
+        self.flush_delay = delay;
+    }
+}
+
+impl<S> ChannelStream<S>
+where
+    S: From<(ChannelId, ChannelMsg)> + 'static + Send,
+{
+    /// Sends everything in the write buffer.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buffer.is_empty() {
+            let written = ready!(Pin::new(&mut self.tx).poll_write(cx, &self.write_buffer))?;
+            self.write_buffer.drain(..written);
+        }
+        self.flush_timer = None;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends the write buffer if the flush delay has elapsed. Errors
+    /// are left for the next write or flush to report.
+    fn poll_flush_timer(&mut self, cx: &mut Context<'_>) {
+        if let Some(timer) = self.flush_timer.as_mut() {
+            if Pin::new(timer).poll(cx).is_ready() {
+                let _ = self.poll_drain(cx);
+            }
+        }
     }
 }
 
 impl<S> AsyncRead for ChannelStream<S>
 where
-    S: From<(ChannelId, ChannelMsg)>,
+    S: From<(ChannelId, ChannelMsg)> + 'static + Send,
 {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        // Waiting for a reply is no reason to hold the request back.
+        self.poll_flush_timer(cx);
         Pin::new(&mut self.rx).poll_read(cx, buf)
     }
 }
@@ -47,10 +131,29 @@ where
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        Pin::new(&mut self.tx).poll_write(cx, buf)
+        let size = self.write_buffer_size;
+        if !self.write_buffer.is_empty() && (self.write_buffer.len() >= size || buf.len() >= size) {
+            ready!(self.poll_drain(cx))?;
+        }
+        if buf.len() >= size {
+            return Pin::new(&mut self.tx).poll_write(cx, buf);
+        }
+
+        let len = buf.len().min(size - self.write_buffer.len());
+        #[allow(clippy::indexing_slicing)] // length checked
+        self.write_buffer.extend_from_slice(&buf[..len]);
+        if self.write_buffer.len() >= size || self.flush_delay.is_zero() {
+            // The data is ours now, errors are reported by the next call.
+            let _ = self.poll_drain(cx);
+        } else if self.flush_timer.is_none() {
+            self.flush_timer = Some(crate::runtime::sleep(self.flush_delay));
+            self.poll_flush_timer(cx);
+        }
+        Poll::Ready(Ok(len))
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        ready!(self.poll_drain(cx))?;
         Pin::new(&mut self.tx).poll_flush(cx)
     }
 
@@ -58,6 +161,7 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
+        ready!(self.poll_drain(cx))?;
         Pin::new(&mut self.tx).poll_shutdown(cx)
     }
 }
@@ -1,6 +1,6 @@
 use std::io;
 use std::pin::Pin;
-use std::task::{ready, Context, Poll};
+use std::task::{Context, Poll};
 
 use tokio::io::AsyncRead;
 
@@ -35,56 +35,82 @@ impl<'i, S> AsyncRead for ChannelRx<'i, S>
 where
     S: From<(ChannelId, ChannelMsg)>,
 {
+    /// Reads as much data as is available, from as many messages
+    /// as needed to fill `buf`, so that the consumer sees contiguous
+    /// data rather than one message at a time.
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        let (msg, mut idx) = match self.buffer.take() {
-            Some(msg) => msg,
-            None => match ready!(self.channel.as_mut().receiver.poll_recv(cx)) {
-                Some(msg) => (msg, 0),
-                None => return Poll::Ready(Ok(())),
-            },
-        };
-
-        match (&msg, self.ext) {
-            (ChannelMsg::Data { data }, None) => {
-                let readable = buf.remaining().min(data.len() - idx);
+        let mut read_any = false;
+        loop {
+            // The peer has sent EOF: the read half is done, but the channel
+            // itself stays open so that we can still write to it. Our own
+            // EOF has no effect here.
+            if self.channel.as_mut().remote_eof_received || (read_any && buf.remaining() == 0) {
+                return Poll::Ready(Ok(()));
+            }
 
-                // Clamped to maximum `buf.remaining()` and `data.len() - idx` with `.min`
-                #[allow(clippy::indexing_slicing)]
-                buf.put_slice(&data[idx..idx + readable]);
-                idx += readable;
+            let (msg, mut idx) = match self.buffer.take() {
+                Some(msg) => msg,
+                None => match self.channel.as_mut().poll_recv(cx) {
+                    Poll::Ready(Some(msg)) => (msg, 0),
+                    _ if read_any => return Poll::Ready(Ok(())),
+                    Poll::Pending => return Poll::Pending,
+                    // The session is gone: this is only a clean end of file if
+                    // it ended without an error.
+                    Poll::Ready(None) => {
+                        return Poll::Ready(match self.channel.as_mut().session_error.get() {
+                            Some(e) => Err(e),
+                            None => Ok(()),
+                        })
+                    }
+                },
+            };
 
-                if idx != data.len() {
-                    self.buffer = Some((msg, idx));
-                }
-
-                Poll::Ready(Ok(()))
-            }
-            (ChannelMsg::ExtendedData { data, ext }, Some(target)) if *ext == target => {
-                let readable = buf.remaining().min(data.len() - idx);
+            match (&msg, self.ext) {
+                (ChannelMsg::Data { data }, None) => {
+                    let readable = buf.remaining().min(data.len() - idx);
 
-                // Clamped to maximum `buf.remaining()` and `data.len() - idx` with `.min`
-                #[allow(clippy::indexing_slicing)]
-                buf.put_slice(&data[idx..idx + readable]);
-                idx += readable;
+                    // Clamped to maximum `buf.remaining()` and `data.len() - idx` with `.min`
+                    #[allow(clippy::indexing_slicing)]
+                    buf.put_slice(&data[idx..idx + readable]);
+                    idx += readable;
+                    read_any = true;
 
-                if idx != data.len() {
-                    self.buffer = Some((msg, idx));
+                    if idx != data.len() {
+                        self.buffer = Some((msg, idx));
+                    }
                 }
+                (ChannelMsg::ExtendedData { data, ext }, Some(target)) if *ext == target => {
+                    let readable = buf.remaining().min(data.len() - idx);
 
-                Poll::Ready(Ok(()))
-            }
-            (ChannelMsg::Eof, _) => {
-                self.channel.as_mut().receiver.close();
+                    // Clamped to maximum `buf.remaining()` and `data.len() - idx` with `.min`
+                    #[allow(clippy::indexing_slicing)]
+                    buf.put_slice(&data[idx..idx + readable]);
+                    idx += readable;
+                    read_any = true;
 
-                Poll::Ready(Ok(()))
-            }
-            _ => {
-                cx.waker().wake_by_ref();
-                Poll::Pending
+                    if idx != data.len() {
+                        self.buffer = Some((msg, idx));
+                    }
+                }
+                // Recorded on the channel by `poll_recv`.
+                (ChannelMsg::Eof, _) => {}
+                // Reported by the next read if data was read already.
+                (ChannelMsg::IdleTimeout, _) if read_any => {
+                    self.buffer = Some((msg, 0));
+                    return Poll::Ready(Ok(()));
+                }
+                (ChannelMsg::IdleTimeout, _) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "channel idle timeout",
+                    )));
+                }
+                // Not for this reader.
+                _ => {}
             }
         }
     }
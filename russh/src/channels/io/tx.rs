@@ -1,5 +1,7 @@
+use std::future::Future;
 use std::io;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 
@@ -7,9 +9,11 @@ use futures::FutureExt;
 use tokio::io::AsyncWrite;
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::{self, OwnedPermit};
-use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::sync::{oneshot, Mutex, OwnedMutexGuard};
 
 use super::ChannelMsg;
+use crate::channels::{SessionError, SharedRateLimit};
+use crate::runtime::Sleep;
 use crate::{ChannelId, CryptoVec};
 
 type BoxedThreadsafeFuture<T> = Pin<Box<dyn Sync + Send + std::future::Future<Output = T>>>;
@@ -21,31 +25,89 @@ pub struct ChannelTx<S> {
     send_fut: Option<OwnedPermitFuture<S>>,
     id: ChannelId,
 
+    flush_fut: Option<BoxedThreadsafeFuture<Result<(), io::Error>>>,
+
     window_size_fut: Option<BoxedThreadsafeFuture<OwnedMutexGuard<u32>>>,
     window_size: Arc<Mutex<u32>>,
     max_packet_size: u32,
     ext: Option<u32>,
+
+    rate_limit: SharedRateLimit,
+    throttle: Option<Sleep>,
+    closed: Arc<AtomicBool>,
+    /// Shared with the channel and its other writers.
+    eof_sent: Arc<AtomicBool>,
+    /// Whether this writer is sending EOF.
+    shutdown: bool,
+    session_error: SessionError,
+}
+
+impl<S> ChannelTx<S> {
+    pub fn max_packet_size(&self) -> u32 {
+        self.max_packet_size
+    }
 }
 
 impl<S> ChannelTx<S>
 where
     S: From<(ChannelId, ChannelMsg)> + 'static + Send,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         sender: mpsc::Sender<S>,
         id: ChannelId,
         window_size: Arc<Mutex<u32>>,
         max_packet_size: u32,
         ext: Option<u32>,
+        rate_limit: SharedRateLimit,
+        closed: Arc<AtomicBool>,
+        eof_sent: Arc<AtomicBool>,
+        session_error: SessionError,
     ) -> Self {
         Self {
             sender,
             send_fut: None,
+            flush_fut: None,
             id,
             window_size,
             window_size_fut: None,
             max_packet_size,
             ext,
+            rate_limit,
+            throttle: None,
+            closed,
+            eof_sent,
+            shutdown: false,
+            session_error,
+        }
+    }
+
+    /// Waits until the rate limit allows the next write, and returns
+    /// the largest write allowed. This happens before taking the
+    /// window, so that other writers can use it in the meantime.
+    fn poll_throttle(&mut self, cx: &mut Context<'_>) -> Poll<usize> {
+        loop {
+            if let Some(throttle) = self.throttle.as_mut() {
+                ready!(Pin::new(throttle).poll(cx));
+                self.throttle = None;
+            }
+            let mut rate_limit = self.rate_limit.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(ref mut bucket) = *rate_limit else {
+                return Poll::Ready(usize::MAX);
+            };
+            let delay = bucket.delay();
+            if delay.is_zero() {
+                return Poll::Ready(bucket.burst());
+            }
+            drop(rate_limit);
+            self.throttle = Some(crate::runtime::sleep(delay));
+        }
+    }
+
+    fn consume(&self, written: usize) {
+        let mut rate_limit = self.rate_limit.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(ref mut bucket) = *rate_limit {
+            bucket.consume(written)
         }
     }
 
@@ -65,10 +127,8 @@ where
             cx.waker().wake_by_ref();
             return Poll::Pending;
         }
-        let mut data = CryptoVec::new_zeroed(writable);
         #[allow(clippy::indexing_slicing)] // Clamped to maximum `buf.len()` with `.min`
-        data.copy_from_slice(&buf[..writable]);
-        data.resize(writable);
+        let data = CryptoVec::from_slice_pooled(&buf[..writable]);
 
         *window_size -= writable as u32;
         drop(window_size);
@@ -91,6 +151,13 @@ where
         ))
     }
 
+    /// The error of the session if it failed, or a broken pipe.
+    fn session_closed(&self, description: &str) -> io::Error {
+        self.session_error
+            .get()
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, description.to_owned()))
+    }
+
     fn handle_write_result(
         &mut self,
         r: Result<(OwnedPermit<S>, ChannelMsg, usize), SendError<()>>,
@@ -101,7 +168,7 @@ where
                 permit.send((self.id, msg).into());
                 Ok(writable)
             }
-            Err(SendError(())) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "channel closed")),
+            Err(SendError(())) => Err(self.session_closed("channel closed")),
         }
     }
 }
@@ -119,27 +186,79 @@ where
         let send_fut = if let Some(x) = self.send_fut.as_mut() {
             x
         } else {
+            if self.closed.load(Ordering::Acquire) {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "channel closed by the peer",
+                )));
+            }
+            if self.eof_sent.load(Ordering::Acquire) {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "EOF already sent on the channel",
+                )));
+            }
+            let allowed = ready!(self.poll_throttle(cx));
+            let buf = buf.get(..allowed).unwrap_or(buf);
             let (msg, writable) = ready!(self.poll_mk_msg(cx, buf));
+            self.consume(writable);
             self.activate(msg, writable)
         };
         let r = ready!(send_fut.as_mut().poll_unpin(cx));
         Poll::Ready(self.handle_write_result(r))
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        Poll::Ready(Ok(()))
+    /// Waits until everything written so far has been written to the
+    /// socket by the session (or queued in the session, if the peer's
+    /// window is full).
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        if let Some(send_fut) = self.send_fut.as_mut() {
+            let r = ready!(send_fut.as_mut().poll_unpin(cx));
+            self.handle_write_result(r)?;
+        }
+        let sender = self.sender.clone();
+        let id = self.id;
+        let session_error = self.session_error.clone();
+        let flush_fut = self.flush_fut.get_or_insert_with(|| {
+            Box::pin(async move {
+                let session_closed = || {
+                    session_error.get().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::BrokenPipe, "session closed")
+                    })
+                };
+                let (done, flushed) = oneshot::channel();
+                let permit = sender.reserve_owned().await.map_err(|_| session_closed())?;
+                permit.send((id, ChannelMsg::Flush { done }).into());
+                flushed.await.map_err(|_| session_closed())
+            })
+        });
+        let r = ready!(flush_fut.as_mut().poll_unpin(cx));
+        self.flush_fut = None;
+        Poll::Ready(r)
     }
 
+    /// Sends EOF, unless it was sent already by another writer or
+    /// [`Channel::eof`](super::Channel::eof). This only closes the
+    /// write half: the channel's readers go on until the peer's EOF.
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
-        let send_fut = if let Some(x) = self.send_fut.as_mut() {
-            x
-        } else {
-            self.activate(ChannelMsg::Eof, 0)
-        };
-        let r = ready!(send_fut.as_mut().poll_unpin(cx)).map(|(p, _, _)| (p, ChannelMsg::Eof, 0));
-        Poll::Ready(self.handle_write_result(r).map(drop))
+        if !self.shutdown {
+            self.shutdown = true;
+            // A write left pending is abandoned.
+            self.send_fut = None;
+            if self.eof_sent.swap(true, Ordering::AcqRel) {
+                return Poll::Ready(Ok(()));
+            }
+            self.activate(ChannelMsg::Eof, 0);
+        }
+        match self.send_fut.as_mut() {
+            Some(send_fut) => {
+                let r = ready!(send_fut.as_mut().poll_unpin(cx));
+                Poll::Ready(self.handle_write_result(r).map(drop))
+            }
+            None => Poll::Ready(Ok(())),
+        }
     }
 }
@@ -1,19 +1,43 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::mpsc::{Sender, UnboundedReceiver};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{oneshot, Mutex};
 
-use crate::{ChannelId, ChannelOpenFailure, CryptoVec, Error, Pty, Sig};
+use crate::rate_limit::TokenBucket;
+use crate::runtime::Sleep;
+use crate::{
+    ChannelId, ChannelOpenFailure, CryptoVec, Error, Pty, RateLimit, Sig, TerminalModes,
+    TerminalSize,
+};
 
 pub mod io;
 
 mod channel_ref;
 pub use channel_ref::ChannelRef;
+pub(crate) use channel_ref::{
+    channels_full, fail_channels, flush_channels, wait_channel_capacity, ChannelLabel,
+    SessionError, WriterParts,
+};
 
 mod channel_stream;
 pub use channel_stream::ChannelStream;
 
+/// Which way channel data goes, see `Handler::on_channel_data`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Data received from the peer.
+    Inbound,
+    /// Data sent to the peer.
+    Outbound,
+}
+
 #[derive(Debug)]
 #[non_exhaustive]
 /// Possible messages that [Channel::wait] can receive.
@@ -32,6 +56,11 @@ pub enum ChannelMsg {
     },
     Eof,
     Close,
+    /// The peer won't read more data from the channel, although it may
+    /// still write. OpenSSH sends this as an `eow@openssh.com` request
+    /// when it can no longer write the channel's output, for instance
+    /// to a closed pipe. See [`Channel::end_of_write`].
+    EndOfWrite,
     /// (client only)
     RequestPty {
         want_reply: bool,
@@ -110,22 +139,146 @@ pub enum ChannelMsg {
     /// (server only)
     Failure,
     OpenFailure(ChannelOpenFailure),
+    /// A request of any type, with `payload` written verbatim after
+    /// the `want reply` field. See [`Channel::send_channel_request`].
+    Request {
+        request_type: String,
+        want_reply: bool,
+        payload: Vec<u8>,
+    },
+    /// Nothing was received within the channel's idle timeout, or its
+    /// deadline has passed. See [`Channel::set_idle_timeout`] and
+    /// [`Channel::deadline`]. The channel stays open.
+    IdleTimeout,
+    /// Sent by channel writers when flushed. The session answers once
+    /// everything sent before has been written to the socket.
+    #[doc(hidden)]
+    Flush {
+        done: oneshot::Sender<()>,
+    },
 }
 
+/// The rate limit of a channel, shared with its writers.
+pub(crate) type SharedRateLimit = Arc<std::sync::Mutex<Option<TokenBucket>>>;
+
 /// A handle to a session channel.
 ///
 /// Allows you to read and write from a channel without borrowing the session
 pub struct Channel<Send: From<(ChannelId, ChannelMsg)>> {
     pub(crate) id: ChannelId,
     pub(crate) sender: Sender<Send>,
-    pub(crate) receiver: UnboundedReceiver<ChannelMsg>,
+    pub(crate) receiver: Receiver<ChannelMsg>,
+    /// Messages received while waiting for the reply to a request.
+    pub(crate) pending: VecDeque<ChannelMsg>,
     pub(crate) max_packet_size: u32,
     pub(crate) window_size: Arc<Mutex<u32>>,
+    pub(crate) rate_limit: SharedRateLimit,
+    pub(crate) closed: Arc<AtomicBool>,
+    /// Set once we sent EOF, shared with the writers.
+    pub(crate) local_eof_sent: Arc<AtomicBool>,
+    /// Set once the peer's EOF was received.
+    pub(crate) remote_eof_received: bool,
+    pub(crate) session_error: SessionError,
+    pub(crate) label: ChannelLabel,
+    pub(crate) timeouts: Timeouts,
+}
+
+/// The idle timeout and deadline of a channel, checked while waiting
+/// for its messages.
+#[derive(Default)]
+pub(crate) struct Timeouts {
+    idle: Option<Duration>,
+    deadline: Option<Instant>,
+    /// Fires at the earliest of the two.
+    timer: Option<Sleep>,
+}
+
+impl Timeouts {
+    /// Restarts the timer, after a message was received.
+    fn reset(&mut self) {
+        let now = crate::runtime::now();
+        let at = match (self.idle, self.deadline) {
+            (Some(idle), Some(deadline)) => Some((now + idle).min(deadline)),
+            (Some(idle), None) => Some(now + idle),
+            (None, deadline) => deadline,
+        };
+        self.timer = at.map(|at| crate::runtime::sleep(at.saturating_duration_since(now)));
+    }
+
+    fn clear(&mut self) {
+        *self = Timeouts::default()
+    }
+
+    /// Returns `Ready` when the timer fires. A deadline only fires
+    /// once, the idle timeout is restarted.
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(timer) = self.timer.as_mut() else {
+            return Poll::Pending;
+        };
+        if Pin::new(timer).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        if self.deadline.map_or(false, |d| d <= crate::runtime::now()) {
+            self.deadline = None;
+        }
+        self.reset();
+        Poll::Ready(())
+    }
 }
 
 impl<T: From<(ChannelId, ChannelMsg)>> std::fmt::Debug for Channel<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Channel").field("id", &self.id).finish()
+        f.debug_struct("Channel")
+            .field("id", &self.id)
+            .field("label", &self.label.get())
+            .finish()
+    }
+}
+
+impl<S: From<(ChannelId, ChannelMsg)>> Channel<S> {
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<ChannelMsg>> {
+        let msg = match self.pending.pop_front() {
+            Some(msg) => Poll::Ready(Some(msg)),
+            None => self.receiver.poll_recv(cx),
+        };
+        match msg {
+            Poll::Ready(Some(ChannelMsg::Close)) | Poll::Ready(None) => self.timeouts.clear(),
+            Poll::Ready(Some(ChannelMsg::Eof)) => {
+                self.remote_eof_received = true;
+                self.timeouts.reset()
+            }
+            Poll::Ready(Some(_)) => self.timeouts.reset(),
+            Poll::Pending => {
+                if self.timeouts.poll(cx).is_ready() {
+                    return Poll::Ready(Some(ChannelMsg::IdleTimeout));
+                }
+            }
+        }
+        msg
+    }
+
+    /// Makes [`Channel::wait`] return [`ChannelMsg::IdleTimeout`] when
+    /// no message is received for `timeout`, and the readers of the
+    /// channel fail with [`TimedOut`](std::io::ErrorKind::TimedOut).
+    /// The timer starts now, restarts with every message received, and
+    /// after it fires. It stops once the channel is closed.
+    ///
+    /// The timer only runs while the channel is waited on or read.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.timeouts.idle = Some(timeout);
+        self.timeouts.reset();
+    }
+
+    /// Like [`Channel::set_idle_timeout`], but fires once, at
+    /// `deadline`, whether or not messages are received.
+    pub fn deadline(&mut self, deadline: Instant) {
+        self.timeouts.deadline = Some(deadline);
+        self.timeouts.reset();
+    }
+
+    /// Removes the idle timeout and the deadline.
+    pub fn clear_timeouts(&mut self) {
+        self.timeouts.clear()
     }
 }
 
@@ -135,22 +288,32 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         sender: Sender<S>,
         max_packet_size: u32,
         window_size: u32,
+        buffer_size: Option<usize>,
     ) -> (Self, ChannelRef) {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (mut channel_ref, receiver) = ChannelRef::new(buffer_size);
         let window_size = Arc::new(Mutex::new(window_size));
+        channel_ref.window_size = window_size.clone();
+        let closed = channel_ref.closed.clone();
+        let session_error = channel_ref.session_error.clone();
+        let label = channel_ref.label.clone();
 
         (
             Self {
                 id,
                 sender,
-                receiver: rx,
+                receiver,
+                pending: VecDeque::new(),
                 max_packet_size,
-                window_size: window_size.clone(),
-            },
-            ChannelRef {
-                sender: tx,
                 window_size,
+                rate_limit: Default::default(),
+                closed,
+                local_eof_sent: Default::default(),
+                remote_eof_received: false,
+                session_error,
+                label,
+                timeouts: Default::default(),
             },
+            channel_ref,
         )
     }
 
@@ -164,6 +327,36 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         self.id
     }
 
+    /// The label given to this channel when it was opened, with
+    /// `channel_open_session_labeled`, or announced by the peer. Unlike
+    /// the id, which is only meaningful on one side of one connection,
+    /// the label can be used to follow a connection across hops.
+    pub fn label(&self) -> Option<String> {
+        self.label.get()
+    }
+
+    /// Whether we sent EOF on this channel, with [`Channel::eof`] or by
+    /// shutting down one of its writers. The channel can't be written
+    /// to anymore, but the peer may still send data until its own EOF.
+    pub fn local_eof_sent(&self) -> bool {
+        self.local_eof_sent.load(Ordering::Acquire)
+    }
+
+    /// Whether the peer's EOF was received, by [`Channel::wait`] or one
+    /// of the readers. The channel may still be written to.
+    pub fn remote_eof_received(&self) -> bool {
+        self.remote_eof_received
+    }
+
+    /// Limits the rate at which data is sent on this channel to
+    /// `bytes_per_sec`, allowing bursts of `burst` bytes. This applies
+    /// to all the writers of the channel, including existing ones and
+    /// its stream. Throttled writers don't hold the channel window.
+    pub fn set_rate_limit(&self, bytes_per_sec: u32, burst: u32) {
+        let bucket = TokenBucket::new(RateLimit::new(bytes_per_sec, burst));
+        *self.rate_limit.lock().unwrap_or_else(|e| e.into_inner()) = Some(bucket);
+    }
+
     /// Request a pseudo-terminal with the given characteristics.
     #[allow(clippy::too_many_arguments)] // length checked
     pub async fn request_pty(
@@ -188,6 +381,27 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         .await
     }
 
+    /// Request a pseudo-terminal of type `term` (such as
+    /// `xterm-256color`), with a size and modes.
+    pub async fn request_pty_with(
+        &self,
+        want_reply: bool,
+        term: &str,
+        size: TerminalSize,
+        modes: &TerminalModes,
+    ) -> Result<(), Error> {
+        self.request_pty(
+            want_reply,
+            term,
+            size.col_width,
+            size.row_height,
+            size.pix_width,
+            size.pix_height,
+            modes.as_slice(),
+        )
+        .await
+    }
+
     /// Request a remote shell.
     pub async fn request_shell(&self, want_reply: bool) -> Result<(), Error> {
         self.send_msg(ChannelMsg::RequestShell { want_reply }).await
@@ -204,9 +418,13 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         .await
     }
 
-    /// Signal a remote process.
-    pub async fn signal(&self, signal: Sig) -> Result<(), Error> {
-        self.send_msg(ChannelMsg::Signal { signal }).await
+    /// Signal a remote process. Accepts a [`Sig`] or a signal name,
+    /// which may be a custom one.
+    pub async fn signal<A: Into<Sig>>(&self, signal: A) -> Result<(), Error> {
+        self.send_msg(ChannelMsg::Signal {
+            signal: signal.into(),
+        })
+        .await
     }
 
     /// Request the start of a subsystem with the given name.
@@ -222,6 +440,58 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         .await
     }
 
+    /// Request the start of a subsystem with the given name, and wait
+    /// for the server's answer: `true` if it accepted. Messages received
+    /// in the meantime are kept for [`Channel::wait`] and the readers,
+    /// so nothing sent by the subsystem is lost.
+    pub async fn request_subsystem_wait<A: Into<String>>(
+        &mut self,
+        name: A,
+    ) -> Result<bool, Error> {
+        self.request_subsystem(true, name).await?;
+        self.wait_reply().await
+    }
+
+    /// Sends a request of type `request_type`, with `payload` written
+    /// verbatim after the `want reply` field, for requests this crate
+    /// doesn't know about.
+    ///
+    /// If `want_reply` is true, waits for the peer's answer: `Some(true)`
+    /// if it accepted. As with
+    /// [`request_subsystem_wait`](Self::request_subsystem_wait), the
+    /// messages received in the meantime are kept. Otherwise, returns
+    /// `None` once the request is queued.
+    pub async fn send_channel_request<A: Into<String>>(
+        &mut self,
+        request_type: A,
+        want_reply: bool,
+        payload: &[u8],
+    ) -> Result<Option<bool>, Error> {
+        self.send_msg(ChannelMsg::Request {
+            request_type: request_type.into(),
+            want_reply,
+            payload: payload.to_vec(),
+        })
+        .await?;
+        if want_reply {
+            self.wait_reply().await.map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Waits for the success or failure answering a request.
+    async fn wait_reply(&mut self) -> Result<bool, Error> {
+        loop {
+            match self.receiver.recv().await {
+                Some(ChannelMsg::Success) => return Ok(true),
+                Some(ChannelMsg::Failure) => return Ok(false),
+                Some(msg) => self.pending.push_back(msg),
+                None => return Err(Error::ChannelClosed),
+            }
+        }
+    }
+
     /// Request X11 forwarding through an already opened X11
     /// channel. See
     /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-6.3.1)
@@ -304,15 +574,30 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
     ) -> Result<(), Error> {
         let mut tx = self.make_writer_ext(ext);
 
-        tokio::io::copy(&mut data, &mut tx).await?;
-
-        Ok(())
+        match tokio::io::copy(&mut data, &mut tx).await {
+            Ok(_) => Ok(()),
+            Err(_) if self.closed.load(Ordering::Acquire) => Err(Error::ChannelClosed),
+            Err(e) => Err(e.into()),
+        }
     }
 
+    /// Sends EOF: we won't write to the channel anymore. Only the
+    /// first call sends it. Reading goes on until the peer's EOF.
     pub async fn eof(&self) -> Result<(), Error> {
+        if self.local_eof_sent.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
         self.send_msg(ChannelMsg::Eof).await
     }
 
+    /// Tells the peer that we won't read more data from the channel,
+    /// in an `eow@openssh.com` request, as OpenSSH does when it can't
+    /// write the channel's output. Implementations other than OpenSSH
+    /// and russh may ignore it. We can still write to the channel.
+    pub async fn end_of_write(&self) -> Result<(), Error> {
+        self.send_msg(ChannelMsg::EndOfWrite).await
+    }
+
     /// Request that the channel be closed.
     pub async fn close(&self) -> Result<(), Error> {
         self.send_msg(ChannelMsg::Close).await
@@ -327,11 +612,24 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
 
     /// Awaits an incoming [`ChannelMsg`], this method returns [`None`] if the channel has been closed.
     pub async fn wait(&mut self) -> Option<ChannelMsg> {
-        self.receiver.recv().await
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
     }
 
     /// Consume the [`Channel`] to produce a bidirectionnal stream,
     /// sending and receiving [`ChannelMsg::Data`] as `AsyncRead` + `AsyncWrite`.
+    ///
+    /// The stream supports half-close: once the peer sends EOF, reads return
+    /// end-of-file but the stream remains writable until it is shut down
+    /// (which sends our own EOF) or the channel is closed.
+    ///
+    /// No task is spawned: data only moves while the stream is polled,
+    /// by the caller's reads and writes (which also return the errors),
+    /// so it can be driven from any `select!` and cancelled by dropping
+    /// it, which drops the channel.
+    ///
+    /// If the session fails, reads and writes return an error of kind
+    /// [`ConnectionAborted`](std::io::ErrorKind::ConnectionAborted)
+    /// describing the failure, instead of end-of-file or a broken pipe.
     pub fn into_stream(self) -> ChannelStream<S> {
         ChannelStream::new(
             io::ChannelTx::new(
@@ -340,6 +638,10 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
                 self.window_size.clone(),
                 self.max_packet_size,
                 None,
+                self.rate_limit.clone(),
+                self.closed.clone(),
+                self.local_eof_sent.clone(),
+                self.session_error.clone(),
             ),
             io::ChannelRx::new(self, None),
         )
@@ -372,6 +674,10 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
             self.window_size.clone(),
             self.max_packet_size,
             ext,
+            self.rate_limit.clone(),
+            self.closed.clone(),
+            self.local_eof_sent.clone(),
+            self.session_error.clone(),
         )
     }
 }
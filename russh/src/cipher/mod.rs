@@ -39,6 +39,7 @@ pub(crate) mod cbc;
 pub(crate) mod chacha20poly1305;
 pub(crate) mod clear;
 pub(crate) mod gcm;
+pub(crate) mod pipeline;
 
 use block::SshBlockCipher;
 use chacha20poly1305::SshChacha20Poly1305Cipher;
@@ -230,10 +231,13 @@ pub(crate) trait SealingKey {
     }
 }
 
+/// Reads and decrypts the next packet into `buffer`, refusing packets
+/// longer than `max_packet_size` before allocating them.
 pub(crate) async fn read<'a, R: AsyncRead + Unpin>(
     stream: &'a mut R,
     buffer: &'a mut SSHBuffer,
     cipher: &'a mut (dyn OpeningKey + Send),
+    max_packet_size: usize,
 ) -> Result<usize, Error> {
     if buffer.len == 0 {
         let mut len = vec![0; cipher.packet_length_to_read_for_block_length()];
@@ -246,7 +250,14 @@ pub(crate) async fn read<'a, R: AsyncRead + Unpin>(
             buffer.buffer.extend(&len);
             debug!("reading, seqn = {:?}", seqn);
             let len = cipher.decrypt_packet_length(seqn, &len);
-            buffer.len = BigEndian::read_u32(&len) as usize + cipher.tag_len();
+            let len = BigEndian::read_u32(&len) as usize;
+            if len > max_packet_size {
+                return Err(Error::PacketTooLarge {
+                    size: len,
+                    limit: max_packet_size,
+                });
+            }
+            buffer.len = len + cipher.tag_len();
             debug!("reading, clear len = {:?}", buffer.len);
         }
     }
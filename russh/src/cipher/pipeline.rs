@@ -0,0 +1,214 @@
+//! Decrypting inbound packets ahead of the session, on a separate task.
+//!
+//! The reader task owns the read half of the stream and the opening
+//! key, and sends the decrypted packets to the session, in order,
+//! through a bounded queue. New opening keys are only installed by the
+//! session, when it processes a NEWKEYS, so while a key exchange is in
+//! progress (see [`ReadBarrier`]) the reader stops after each packet,
+//! hands its key and sequence number over with it, and waits for the
+//! session to give them back, possibly changed, before reading on.
+use std::num::Wrapping;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use russh_cryptovec::CryptoVec;
+use tokio::io::AsyncRead;
+use tokio::sync::{mpsc, oneshot};
+
+use super::{clear, OpeningKey};
+use crate::runtime::Spawner;
+use crate::sshbuffer::SSHBuffer;
+use crate::Error;
+
+type Key = Box<dyn OpeningKey + Send>;
+
+/// The key and the sequence number of the next packet, given back to
+/// the reader task.
+type Resume = oneshot::Sender<(Key, Wrapping<u32>)>;
+
+struct Frame {
+    buffer: CryptoVec,
+    len: usize,
+    /// The sequence number following this packet's.
+    seqn: Wrapping<u32>,
+    /// The reader's key, if it waits for the session before reading on.
+    key: Option<(Key, Resume)>,
+}
+
+/// Where the session reads its packets from: the stream itself, or
+/// the queue of a reader task.
+pub(crate) enum PacketReader<R> {
+    Serial { stream: R, max_packet_size: usize },
+    Pipelined(Pipeline),
+}
+
+pub(crate) struct Pipeline {
+    frames: mpsc::Receiver<Result<Frame, Error>>,
+    /// The buffers of the packets processed by the session, for reuse
+    /// by the reader task.
+    recycle: mpsc::Sender<CryptoVec>,
+    /// Set while the reader task waits for its key.
+    waiting: Option<Resume>,
+    barrier: ReadBarrier,
+}
+
+/// Tells the reader task whether a key exchange is in progress, in
+/// which case it waits for the session after each packet.
+#[derive(Clone)]
+pub(crate) struct ReadBarrier(Option<Arc<AtomicBool>>);
+
+impl ReadBarrier {
+    /// Must be called before writing anything the peer could answer
+    /// with a NEWKEYS, i.e. before each write to the stream.
+    pub fn set(&self, rekeying: bool) {
+        if let Some(ref b) = self.0 {
+            b.store(rekeying, Ordering::SeqCst)
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.0.as_ref().map_or(true, |b| b.load(Ordering::SeqCst))
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> PacketReader<R> {
+    /// Reads from `stream` directly with `depth == None`, or starts a
+    /// reader task decrypting up to `depth` packets ahead. Packets
+    /// longer than `max_packet_size` are refused.
+    pub fn new(
+        stream: R,
+        max_packet_size: usize,
+        depth: Option<usize>,
+        spawner: Option<&Spawner>,
+    ) -> Self {
+        let Some(depth) = depth else {
+            return PacketReader::Serial {
+                stream,
+                max_packet_size,
+            };
+        };
+        let depth = depth.max(1);
+        let (frames_tx, frames) = mpsc::channel(depth);
+        let (recycle, recycle_rx) = mpsc::channel(depth + 1);
+        // The session starts in a key exchange, and hands the first key
+        // over on its first read.
+        let barrier = ReadBarrier(Some(Arc::new(AtomicBool::new(true))));
+        let (resume, resumed) = oneshot::channel();
+        crate::runtime::spawn(
+            spawner,
+            read_ahead(
+                stream,
+                max_packet_size,
+                frames_tx,
+                recycle_rx,
+                resumed,
+                barrier.clone(),
+            ),
+        );
+        PacketReader::Pipelined(Pipeline {
+            frames,
+            recycle,
+            waiting: Some(resume),
+            barrier,
+        })
+    }
+}
+
+impl<R: AsyncRead + Unpin> PacketReader<R> {
+    pub fn barrier(&self) -> ReadBarrier {
+        match self {
+            PacketReader::Serial { .. } => ReadBarrier(None),
+            PacketReader::Pipelined(p) => p.barrier.clone(),
+        }
+    }
+
+    /// Reads the next packet into `buffer`, like [`super::read`].
+    ///
+    /// When pipelined, `cipher` only holds the actual key while the
+    /// reader task waits for it, and a placeholder otherwise.
+    pub async fn read(&mut self, buffer: &mut SSHBuffer, cipher: &mut Key) -> Result<usize, Error> {
+        let p = match self {
+            PacketReader::Serial {
+                stream,
+                max_packet_size,
+            } => return super::read(stream, buffer, &mut **cipher, *max_packet_size).await,
+            PacketReader::Pipelined(p) => p,
+        };
+        if let Some(resume) = p.waiting.take() {
+            let key = std::mem::replace(cipher, Box::new(clear::Key));
+            resume
+                .send((key, buffer.seqn))
+                .map_err(|_| Error::Disconnect)?;
+        }
+        let frame = p.frames.recv().await.ok_or(Error::Disconnect)??;
+        let buf = std::mem::replace(&mut buffer.buffer, frame.buffer);
+        let _ = p.recycle.try_send(buf);
+        buffer.seqn = frame.seqn;
+        if let Some((key, resume)) = frame.key {
+            *cipher = key;
+            p.waiting = Some(resume);
+        }
+        Ok(frame.len)
+    }
+}
+
+async fn read_ahead<R: AsyncRead + Unpin>(
+    mut stream: R,
+    max_packet_size: usize,
+    frames: mpsc::Sender<Result<Frame, Error>>,
+    mut recycle: mpsc::Receiver<CryptoVec>,
+    resumed: oneshot::Receiver<(Key, Wrapping<u32>)>,
+    barrier: ReadBarrier,
+) {
+    let Ok((mut key, seqn)) = resumed.await else {
+        return;
+    };
+    let mut buffer = SSHBuffer::new();
+    buffer.seqn = seqn;
+    loop {
+        buffer.buffer = recycle.try_recv().unwrap_or_default();
+        buffer.buffer.clear();
+        let r = tokio::select! {
+            r = super::read(&mut stream, &mut buffer, &mut *key, max_packet_size) => r,
+            // The session is over.
+            () = frames.closed() => return,
+        };
+        let len = match r {
+            Ok(len) => len,
+            Err(e) => {
+                let _ = frames.send(Err(e)).await;
+                return;
+            }
+        };
+        let packet = std::mem::take(&mut buffer.buffer);
+        if !barrier.is_set() {
+            let frame = Frame {
+                buffer: packet,
+                len,
+                seqn: buffer.seqn,
+                key: None,
+            };
+            if frames.send(Ok(frame)).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        let (resume, resumed) = oneshot::channel();
+        let frame = Frame {
+            buffer: packet,
+            len,
+            seqn: buffer.seqn,
+            key: Some((key, resume)),
+        };
+        if frames.send(Ok(frame)).await.is_err() {
+            return;
+        }
+        match resumed.await {
+            Ok((k, seqn)) => {
+                key = k;
+                buffer.seqn = seqn;
+            }
+            Err(_) => return,
+        }
+    }
+}
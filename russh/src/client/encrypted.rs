@@ -18,16 +18,20 @@ use std::num::Wrapping;
 
 use log::{debug, error, info, trace, warn};
 
-use crate::client::{Handler, Msg, Prompt, Reply, Session};
+use crate::client::forward::LocalForward;
+use crate::client::timings::Step;
+use crate::client::{ClientEvent, Handler, Msg, Prompt, Reply, Session};
 use crate::key::PubKey;
 use crate::keys::encoding::{Encoding, Reader};
-use crate::keys::key::parse_public_key;
+use crate::keys::key::{self, parse_public_key};
 use crate::negotiation::{Named, Select};
 use crate::parsing::{ChannelOpenConfirmation, ChannelType, OpenChannelMessage};
-use crate::session::{Encrypted, EncryptedState, GlobalRequestResponse, Kex, KexInit};
+use crate::session::{
+    forwarded_port, Encrypted, EncryptedState, GlobalRequestResponse, Kex, KexInit,
+};
 use crate::{
     auth, msg, negotiation, strict_kex_violation, Channel, ChannelId, ChannelMsg,
-    ChannelOpenFailure, ChannelParams, CryptoVec, Sig,
+    ChannelOpenFailure, ChannelParams, CryptoVec, Direction, Sig,
 };
 
 thread_local! {
@@ -61,7 +65,9 @@ impl Session {
                         exchange,
                         negotiation::Client::read_kex(
                             buf,
-                            &self.common.config.as_ref().preferred,
+                            self.legacy_preferred
+                                .as_ref()
+                                .unwrap_or(&self.common.config.preferred),
                             None,
                         )?,
                         &enc.session_id,
@@ -73,15 +79,21 @@ impl Session {
                 if let Some(kexinit) = kexinit {
                     if let Some(ref algo) = kexinit.algo {
                         if self.common.strict_kex && !algo.strict_kex {
-                            return Err(strict_kex_violation(msg::KEXINIT, 0).into());
+                            let e = strict_kex_violation(msg::KEXINIT, 0);
+                            return Err(self.common.error_disconnect.record(e).into());
                         }
                     }
 
-                    let dhdone = kexinit.client_parse(
-                        self.common.config.as_ref(),
-                        &mut *self.common.cipher.local_to_remote,
-                        buf,
-                        &mut self.common.write_buffer,
+                    let dhdone = self.common.error_disconnect.check(
+                        kexinit.client_parse(
+                            self.common.config.as_ref(),
+                            self.legacy_preferred
+                                .as_ref()
+                                .unwrap_or(&self.common.config.preferred),
+                            &mut *self.common.cipher.local_to_remote,
+                            buf,
+                            &mut self.common.write_buffer,
+                        ),
                     )?;
 
                     if !enc.kex.skip_exchange() {
@@ -104,7 +116,15 @@ impl Session {
                         Ok(())
                     } else if buf.first() == Some(&msg::KEX_ECDH_REPLY) {
                         // We've sent ECDH_INIT, waiting for ECDH_REPLY
-                        let kex = kexdhdone.server_key_check(true, client, buf).await?;
+                        let kex = kexdhdone
+                            .server_key_check(
+                                true,
+                                client,
+                                &self.common.config,
+                                buf,
+                                &mut self.common.error_disconnect,
+                            )
+                            .await?;
                         enc.rekey = Some(Kex::Keys(kex));
                         self.common
                             .cipher
@@ -115,56 +135,56 @@ impl Session {
                         Ok(())
                     } else {
                         error!("Wrong packet received");
-                        Err(crate::Error::Inconsistent.into())
+                        let e = crate::Error::Inconsistent;
+                        Err(self.common.error_disconnect.record(e).into())
                     };
                 }
                 Some(Kex::Keys(newkeys)) => {
                     if buf.first() != Some(&msg::NEWKEYS) {
-                        return Err(crate::Error::Kex.into());
+                        let e = crate::Error::Kex;
+                        return Err(self.common.error_disconnect.record(e).into());
                     }
                     self.common.write_buffer.bytes = 0;
-                    enc.last_rekey = std::time::Instant::now();
+                    enc.last_rekey = crate::runtime::now();
 
-                    // Ok, NEWKEYS received, now encrypted.
+                    // Ok, NEWKEYS received, now encrypted. What was held
+                    // back during the exchange goes out with the new keys.
                     enc.flush_all_pending();
-                    let mut pending = std::mem::take(&mut self.pending_reads);
-                    for p in pending.drain(..) {
-                        self.process_packet(client, &p).await?;
-                    }
-                    self.pending_reads = pending;
-                    self.pending_len = 0;
                     self.common.newkeys(newkeys);
                     self.flush()?;
 
                     if self.common.strict_kex {
                         *seqn = Wrapping(0);
                     }
+                    self.send_event(ClientEvent::Rekeyed);
 
                     return Ok(());
                 }
-                Some(Kex::Init(k)) => {
-                    enc.rekey = Some(Kex::Init(k));
-                    self.pending_len += buf.len() as u32;
-                    if self.pending_len > 2 * self.target_window_size {
-                        return Err(crate::Error::Pending.into());
-                    }
-                    self.pending_reads.push(CryptoVec::from_slice(buf));
-                    return Ok(());
-                }
+                // The server sent this before our KEXINIT reached it
+                // (RFC 4253, section 7.1), handle it as usual.
                 rek => enc.rekey = rek,
             }
         }
-        self.process_packet(client, buf).await
+        // `seqn` was incremented after reading this packet.
+        self.process_packet(client, (*seqn - Wrapping(1)).0, buf)
+            .await
     }
 
     async fn process_packet<H: Handler>(
         &mut self,
         client: &mut H,
+        packet_seqn: u32,
         buf: &[u8],
     ) -> Result<(), H::Error> {
         // If we've successfully read a packet.
         trace!("process_packet buf = {:?} bytes", buf.len());
         trace!("buf = {:?}", buf);
+        #[cfg(feature = "danger-raw-packets")]
+        if let Some(ref hook) = self.common.config.raw_packet_hook {
+            if hook.consume(buf) {
+                return Ok(());
+            }
+        }
         let mut is_authenticated = false;
         if let Some(ref mut enc) = self.common.encrypted {
             match enc.state {
@@ -176,11 +196,16 @@ impl Session {
                         buf.first(),
                         msg::SERVICE_ACCEPT
                     );
-                    if buf.first() == Some(&msg::SERVICE_ACCEPT) {
+                    if buf.first() == Some(&msg::SERVICE_ACCEPT) && *accepted {
+                        return Ok(self.unexpected("SERVICE_ACCEPT")?);
+                    } else if buf.first() == Some(&msg::SERVICE_ACCEPT) {
                         let mut r = buf.reader(1);
                         if r.read_string().map_err(crate::Error::from)? == b"ssh-userauth" {
                             *accepted = true;
-                            if let Some(ref meth) = self.common.auth_method {
+                            if let Some(ref mut meth) = self.common.auth_method {
+                                if let Some(ref algs) = self.server_sig_algs {
+                                    choose_rsa_hash(meth, algs);
+                                }
                                 let auth_request = match meth {
                                     crate::auth::Method::KeyboardInteractive { submethods } => {
                                         auth::AuthRequest {
@@ -192,6 +217,8 @@ impl Session {
                                                 },
                                             ),
                                             rejection_count: 0,
+                                            allowed_methods: auth::MethodSet::all(),
+                                            methods_user: None,
                                         }
                                     }
                                     _ => auth::AuthRequest {
@@ -199,6 +226,8 @@ impl Session {
                                         partial_success: false,
                                         current: None,
                                         rejection_count: 0,
+                                        allowed_methods: auth::MethodSet::all(),
+                                        methods_user: None,
                                     },
                                 };
                                 let len = enc.write.len();
@@ -213,9 +242,14 @@ impl Session {
                         }
                     } else if buf.first() == Some(&msg::EXT_INFO) {
                         return self.handle_ext_info(client, buf);
-                    } else {
+                    } else if buf.first().map_or(false, |&t| !msg::is_known(t)) {
                         debug!("unknown message: {:?}", buf);
-                        return Err(crate::Error::Inconsistent.into());
+                        self.common.unimplemented(packet_seqn);
+                        return Ok(());
+                    } else {
+                        debug!("unexpected message: {:?}", buf);
+                        let e = crate::Error::Inconsistent;
+                        return Err(self.common.error_disconnect.record(e).into());
                     }
                 }
                 EncryptedState::WaitingAuthRequest(ref mut auth_request) => {
@@ -226,12 +260,16 @@ impl Session {
                             .map_err(|_| crate::Error::SendError)?;
                         enc.state = EncryptedState::InitCompression;
                         enc.server_compression.init_decompress(&mut enc.decompress);
+                        // Drops the credentials.
+                        self.common.auth_method = None;
+                        self.common.established_at = Some(crate::runtime::now());
+                        self.record_timing(Step::AuthSuccess);
                         return Ok(());
                     } else if buf.first() == Some(&msg::USERAUTH_BANNER) {
                         let mut r = buf.reader(1);
                         let banner = r.read_string().map_err(crate::Error::from)?;
                         return if let Ok(banner) = std::str::from_utf8(banner) {
-                            client.auth_banner(banner, self).await
+                            handler_call!(self, client.auth_banner(banner, self))
                         } else {
                             Ok(())
                         };
@@ -245,15 +283,24 @@ impl Session {
                             std::str::from_utf8(remaining_methods)
                         );
                         auth_request.methods = auth::MethodSet::empty();
+                        let mut method_names = Vec::new();
                         for method in remaining_methods.split(|&c| c == b',') {
                             if let Some(m) = auth::MethodSet::from_bytes(method) {
                                 auth_request.methods |= m
                             }
+                            if !method.is_empty() {
+                                method_names.push(String::from_utf8_lossy(method).into_owned());
+                            }
                         }
+                        auth_request.partial_success = r.read_byte().map_or(false, |b| b != 0);
+                        debug!("partial success: {:?}", auth_request.partial_success);
                         let no_more_methods = auth_request.methods.is_empty();
                         self.common.auth_method = None;
                         self.sender
-                            .send(Reply::AuthFailure)
+                            .send(Reply::AuthFailure {
+                                remaining_methods: method_names,
+                                partial_success: auth_request.partial_success,
+                            })
                             .map_err(|_| crate::Error::SendError)?;
 
                         // If no other authentication method is allowed by the server, give up.
@@ -373,9 +420,16 @@ impl Session {
                         }
                     } else if buf.first() == Some(&msg::EXT_INFO) {
                         return self.handle_ext_info(client, buf);
-                    } else {
+                    } else if buf.first() == Some(&msg::SERVICE_ACCEPT) {
+                        return Ok(self.unexpected("SERVICE_ACCEPT")?);
+                    } else if buf.first().map_or(false, |&t| !msg::is_known(t)) {
                         debug!("unknown message: {:?}", buf);
-                        return Err(crate::Error::Inconsistent.into());
+                        self.common.unimplemented(packet_seqn);
+                        return Ok(());
+                    } else {
+                        debug!("unexpected message: {:?}", buf);
+                        let e = crate::Error::Inconsistent;
+                        return Err(self.common.error_disconnect.record(e).into());
                     }
                 }
                 EncryptedState::InitCompression => unreachable!(),
@@ -383,20 +437,48 @@ impl Session {
             }
         }
         if is_authenticated {
-            self.client_read_authenticated(client, buf).await
+            self.client_read_authenticated(client, packet_seqn, buf)
+                .await
         } else {
             Ok(())
         }
     }
 
+    /// Ignores an unexpected `packet` if the configuration is lenient,
+    /// and fails otherwise.
+    fn unexpected(&mut self, packet: &str) -> Result<(), crate::Error> {
+        if self.common.config.lenient {
+            warn!("Ignoring unexpected {} from the server", packet);
+            Ok(())
+        } else {
+            error!("Unexpected {} from the server", packet);
+            let e = crate::Error::Inconsistent;
+            Err(self.common.error_disconnect.record(e))
+        }
+    }
+
     fn handle_ext_info<H: Handler>(&mut self, _client: &mut H, buf: &[u8]) -> Result<(), H::Error> {
         debug!("Received EXT_INFO: {:?}", buf);
+        let mut r = buf.reader(1);
+        let n = r.read_u32().map_err(crate::Error::from)?;
+        for _ in 0..n {
+            let name = r.read_string().map_err(crate::Error::from)?;
+            let value = r.read_string().map_err(crate::Error::from)?;
+            if name == b"server-sig-algs" {
+                let algs = String::from_utf8_lossy(value);
+                debug!("server-sig-algs: {:?}", algs);
+                self.server_sig_algs = Some(algs.split(',').map(String::from).collect());
+            } else if name == b"ping@openssh.com" && value == b"0" {
+                self.server_ping = true;
+            }
+        }
         Ok(())
     }
 
     async fn client_read_authenticated<H: Handler>(
         &mut self,
         client: &mut H,
+        packet_seqn: u32,
         buf: &[u8],
     ) -> Result<(), H::Error> {
         match buf.first() {
@@ -407,17 +489,18 @@ impl Session {
                 let local_id = ChannelId(msg.recipient_channel);
 
                 if let Some(ref mut enc) = self.common.encrypted {
-                    if let Some(parameters) = enc.channels.get_mut(&local_id) {
-                        parameters.confirm(&msg);
-                    } else {
+                    if !enc.confirm_channel(&msg) {
                         // We've not requested this channel, close connection.
-                        return Err(crate::Error::Inconsistent.into());
+                        let e = crate::Error::Inconsistent;
+                        return Err(self.common.error_disconnect.record(e).into());
                     }
                 } else {
-                    return Err(crate::Error::Inconsistent.into());
+                    let e = crate::Error::Inconsistent;
+                    return Err(self.common.error_disconnect.record(e).into());
                 };
 
-                if let Some(channel) = self.channels.get(&local_id) {
+                self.record_timing(Step::FirstChannelOpen);
+                if let Some(channel) = self.channels.get_mut(&local_id) {
                     channel
                         .send(ChannelMsg::Open {
                             id: local_id,
@@ -429,14 +512,15 @@ impl Session {
                     error!("no channel for id {local_id:?}");
                 }
 
-                client
-                    .channel_open_confirmation(
+                handler_call!(
+                    self,
+                    client.channel_open_confirmation(
                         local_id,
                         msg.maximum_packet_size,
                         msg.initial_window_size,
                         self,
                     )
-                    .await
+                )
             }
             Some(&msg::CHANNEL_CLOSE) => {
                 debug!("channel_close");
@@ -445,19 +529,21 @@ impl Session {
                 if let Some(ref mut enc) = self.common.encrypted {
                     // The CHANNEL_CLOSE message must be sent to the server at this point or the session
                     // will not be released.
-                    enc.close(channel_num);
+                    enc.peer_closed(channel_num);
                 }
-                self.channels.remove(&channel_num);
-                client.channel_close(channel_num, self).await
+                if let Some(channel) = self.channels.remove(&channel_num) {
+                    channel.set_closed();
+                }
+                handler_call!(self, client.channel_close(channel_num, self))
             }
             Some(&msg::CHANNEL_EOF) => {
                 debug!("channel_eof");
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32().map_err(crate::Error::from)?);
-                if let Some(chan) = self.channels.get(&channel_num) {
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
                     let _ = chan.send(ChannelMsg::Eof);
                 }
-                client.channel_eof(channel_num, self).await
+                handler_call!(self, client.channel_eof(channel_num, self))
             }
             Some(&msg::CHANNEL_OPEN_FAILURE) => {
                 debug!("channel_open_failure");
@@ -474,15 +560,16 @@ impl Session {
                     enc.channels.remove(&channel_num);
                 }
 
-                if let Some(sender) = self.channels.remove(&channel_num) {
+                if let Some(mut sender) = self.channels.remove(&channel_num) {
                     let _ = sender.send(ChannelMsg::OpenFailure(reason_code));
                 }
 
                 let _ = self.sender.send(Reply::ChannelOpenFailure);
 
-                client
-                    .channel_open_failure(channel_num, reason_code, descr, language, self)
-                    .await
+                handler_call!(
+                    self,
+                    client.channel_open_failure(channel_num, reason_code, descr, language, self)
+                )
             }
             Some(&msg::CHANNEL_DATA) => {
                 trace!("channel_data");
@@ -500,13 +587,14 @@ impl Session {
                     }
                 }
 
-                if let Some(chan) = self.channels.get(&channel_num) {
+                client.on_channel_data(Direction::Inbound, channel_num, data);
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
                     let _ = chan.send(ChannelMsg::Data {
-                        data: CryptoVec::from_slice(data),
+                        data: CryptoVec::from_slice_pooled(data),
                     });
                 }
 
-                client.data(channel_num, data, self).await
+                handler_call!(self, client.data(channel_num, data, self))
             }
             Some(&msg::CHANNEL_EXTENDED_DATA) => {
                 debug!("channel_extended_data");
@@ -525,16 +613,23 @@ impl Session {
                     }
                 }
 
-                if let Some(chan) = self.channels.get(&channel_num) {
+                client.on_channel_extended_data(
+                    Direction::Inbound,
+                    channel_num,
+                    extended_code,
+                    data,
+                );
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
                     let _ = chan.send(ChannelMsg::ExtendedData {
                         ext: extended_code,
-                        data: CryptoVec::from_slice(data),
+                        data: CryptoVec::from_slice_pooled(data),
                     });
                 }
 
-                client
-                    .extended_data(channel_num, extended_code, data, self)
-                    .await
+                handler_call!(
+                    self,
+                    client.extended_data(channel_num, extended_code, data, self)
+                )
             }
             Some(&msg::CHANNEL_REQUEST) => {
                 let mut r = buf.reader(1);
@@ -549,23 +644,23 @@ impl Session {
                     b"xon-xoff" => {
                         r.read_byte().map_err(crate::Error::from)?; // should be 0.
                         let client_can_do = r.read_byte().map_err(crate::Error::from)? != 0;
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::XonXoff { client_can_do });
                         }
-                        client.xon_xoff(channel_num, client_can_do, self).await
+                        handler_call!(self, client.xon_xoff(channel_num, client_can_do, self))
                     }
                     b"exit-status" => {
                         r.read_byte().map_err(crate::Error::from)?; // should be 0.
                         let exit_status = r.read_u32().map_err(crate::Error::from)?;
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::ExitStatus { exit_status });
                         }
-                        client.exit_status(channel_num, exit_status, self).await
+                        handler_call!(self, client.exit_status(channel_num, exit_status, self))
                     }
                     b"exit-signal" => {
                         r.read_byte().map_err(crate::Error::from)?; // should be 0.
                         let signal_name =
-                            Sig::from_name(r.read_string().map_err(crate::Error::from)?)?;
+                            Sig::from_name(r.read_string().map_err(crate::Error::from)?);
                         let core_dumped = r.read_byte().map_err(crate::Error::from)? != 0;
                         let error_message =
                             std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
@@ -573,7 +668,7 @@ impl Session {
                         let lang_tag =
                             std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
                                 .map_err(crate::Error::from)?;
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::ExitSignal {
                                 signal_name: signal_name.clone(),
                                 core_dumped,
@@ -581,8 +676,9 @@ impl Session {
                                 lang_tag: lang_tag.to_string(),
                             });
                         }
-                        client
-                            .exit_signal(
+                        handler_call!(
+                            self,
+                            client.exit_signal(
                                 channel_num,
                                 signal_name,
                                 core_dumped,
@@ -590,7 +686,7 @@ impl Session {
                                 lang_tag,
                                 self,
                             )
-                            .await
+                        )
                     }
                     b"keepalive@openssh.com" => {
                         let wants_reply = r.read_byte().map_err(crate::Error::from)?;
@@ -601,25 +697,51 @@ impl Session {
                                     std::str::from_utf8(req),
                                 );
                                 self.common.wants_reply = false;
-                                push_packet!(enc.write, {
-                                    enc.write.push(msg::CHANNEL_SUCCESS);
-                                    enc.write.push_u32_be(channel_num.0)
-                                });
+                                if let Some(channel) = enc.channels.get(&channel_num) {
+                                    push_packet!(enc.write, {
+                                        enc.write.push(msg::CHANNEL_SUCCESS);
+                                        enc.write.push_u32_be(channel.recipient_channel)
+                                    });
+                                }
                             }
                         } else {
                             warn!("Received keepalive without reply request!");
                         }
                         Ok(())
                     }
+                    b"eow@openssh.com" => {
+                        r.read_byte().map_err(crate::Error::from)?; // should be 0.
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
+                            let _ = chan.send(ChannelMsg::EndOfWrite);
+                        }
+                        handler_call!(self, client.end_of_write(channel_num, self))
+                    }
+                    b"label@russh.rs" => {
+                        let wants_reply = r.read_byte().map_err(crate::Error::from)? != 0;
+                        let label =
+                            String::from_utf8_lossy(r.read_string().map_err(crate::Error::from)?)
+                                .into_owned();
+                        debug!("channel {:?} labeled {:?}", channel_num, label);
+                        if let Some(ref mut enc) = self.common.encrypted {
+                            enc.label_channel(channel_num, &label, false);
+                            if wants_reply {
+                                enc.channel_msg(channel_num, msg::CHANNEL_SUCCESS, |_| ());
+                            }
+                        }
+                        if let Some(chan) = self.channels.get(&channel_num) {
+                            chan.label().set(&label);
+                        }
+                        self.send_event(ClientEvent::ChannelLabeled {
+                            id: channel_num,
+                            label,
+                        });
+                        Ok(())
+                    }
                     _ => {
-                        let wants_reply = r.read_byte().map_err(crate::Error::from)?;
-                        if wants_reply == 1 {
-                            if let Some(ref mut enc) = self.common.encrypted {
-                                self.common.wants_reply = false;
-                                push_packet!(enc.write, {
-                                    enc.write.push(msg::CHANNEL_FAILURE);
-                                    enc.write.push_u32_be(channel_num.0)
-                                })
+                        let wants_reply = r.read_byte().map_err(crate::Error::from)? != 0;
+                        if let Some(ref mut enc) = self.common.encrypted {
+                            if let Some(channel) = enc.channels.get_mut(&channel_num) {
+                                channel.wants_reply = wants_reply;
                             }
                         }
                         info!(
@@ -627,7 +749,12 @@ impl Session {
                             std::str::from_utf8(req),
                             wants_reply
                         );
-                        Ok(())
+                        let req = std::str::from_utf8(req).map_err(crate::Error::from)?;
+                        let data = buf.get(r.position..).unwrap_or(&[]);
+                        handler_call!(
+                            self,
+                            client.channel_request(channel_num, req, wants_reply, data, self)
+                        )
                     }
                 }
             }
@@ -650,12 +777,12 @@ impl Session {
                 if let Some(ref mut enc) = self.common.encrypted {
                     new_size -= enc.flush_pending(channel_num) as u32;
                 }
-                if let Some(chan) = self.channels.get(&channel_num) {
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
                     *chan.window_size().lock().await = new_size;
 
                     let _ = chan.send(ChannelMsg::WindowAdjusted { new_size });
                 }
-                client.window_adjusted(channel_num, new_size, self).await
+                handler_call!(self, client.window_adjusted(channel_num, new_size, self))
             }
             Some(&msg::GLOBAL_REQUEST) => {
                 let mut r = buf.reader(1);
@@ -697,7 +824,10 @@ impl Session {
                                 }
                             }
                         }
-                        return client.openssh_ext_host_keys_announced(keys, self).await;
+                        return handler_call!(
+                            self,
+                            client.openssh_ext_host_keys_announced(keys, self)
+                        );
                     } else {
                         warn!(
                             "Unhandled global request: {:?} {:?}",
@@ -705,7 +835,13 @@ impl Session {
                             wants_reply
                         );
                         self.common.wants_reply = false;
-                        push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+                        if wants_reply == 1 {
+                            push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE));
+                        }
+                        self.send_event(ClientEvent::GlobalRequest {
+                            name: String::from_utf8_lossy(req).into_owned(),
+                            want_reply: wants_reply == 1,
+                        });
                     }
                 }
                 self.common.received_data = false;
@@ -714,18 +850,34 @@ impl Session {
             Some(&msg::CHANNEL_SUCCESS) => {
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32().map_err(crate::Error::from)?);
-                if let Some(chan) = self.channels.get(&channel_num) {
+                let solicited = self
+                    .common
+                    .encrypted
+                    .as_mut()
+                    .map_or(false, |enc| enc.channel_reply(channel_num));
+                if !solicited {
+                    return Ok(self.unexpected("CHANNEL_SUCCESS")?);
+                }
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
                     let _ = chan.send(ChannelMsg::Success);
                 }
-                client.channel_success(channel_num, self).await
+                handler_call!(self, client.channel_success(channel_num, self))
             }
             Some(&msg::CHANNEL_FAILURE) => {
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32().map_err(crate::Error::from)?);
-                if let Some(chan) = self.channels.get(&channel_num) {
+                let solicited = self
+                    .common
+                    .encrypted
+                    .as_mut()
+                    .map_or(false, |enc| enc.channel_reply(channel_num));
+                if !solicited {
+                    return Ok(self.unexpected("CHANNEL_FAILURE")?);
+                }
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
                     let _ = chan.send(ChannelMsg::Failure);
                 }
-                client.channel_failure(channel_num, self).await
+                handler_call!(self, client.channel_failure(channel_num, self))
             }
             Some(&msg::CHANNEL_OPEN) => {
                 let mut r = buf.reader(1);
@@ -741,10 +893,18 @@ impl Session {
                         recipient_maximum_packet_size: msg.recipient_maximum_packet_size,
                         sender_maximum_packet_size: self.common.config.maximum_packet_size,
                         confirmed: true,
+                        kind: msg.typ.name(),
+                        created_at: crate::runtime::now(),
+                        initial_window_size: msg.recipient_window_size,
+                        bytes_sent: 0,
+                        bytes_received: 0,
                         wants_reply: false,
+                        pending_replies: 0,
                         pending_data: std::collections::VecDeque::new(),
-                        pending_eof: false,
+                        pending_messages: std::collections::VecDeque::new(),
                         pending_close: false,
+                        label: None,
+                        data_rate: None,
                     };
 
                     let confirm = || {
@@ -761,12 +921,13 @@ impl Session {
                     match &msg.typ {
                         ChannelType::Session => {
                             confirm();
-                            client.server_channel_open_session(id, self).await?
+                            handler_call!(self, client.server_channel_open_session(id, self))?
                         }
                         ChannelType::DirectTcpip(d) => {
                             confirm();
-                            client
-                                .server_channel_open_direct_tcpip(
+                            handler_call!(
+                                self,
+                                client.server_channel_open_direct_tcpip(
                                     id,
                                     &d.host_to_connect,
                                     d.port_to_connect,
@@ -774,7 +935,7 @@ impl Session {
                                     d.originator_port,
                                     self,
                                 )
-                                .await?
+                            )?
                         }
                         ChannelType::X11 {
                             originator_address,
@@ -782,20 +943,31 @@ impl Session {
                         } => {
                             confirm();
                             let channel = self.accept_server_initiated_channel(id, &msg);
-                            client
-                                .server_channel_open_x11(
+                            handler_call!(
+                                self,
+                                client.server_channel_open_x11(
                                     channel,
                                     originator_address,
                                     *originator_port,
                                     self,
                                 )
-                                .await?
+                            )?
                         }
                         ChannelType::ForwardedTcpIp(d) => {
                             confirm();
                             let channel = self.accept_server_initiated_channel(id, &msg);
-                            client
-                                .server_channel_open_forwarded_tcpip(
+                            let key = (d.host_to_connect.clone(), d.port_to_connect);
+                            if let Some(forward) = self.local_forwards.get(&key) {
+                                forward.pipe(
+                                    channel,
+                                    self.inbound_channel_sender.clone(),
+                                    self.common.config.spawner.as_ref(),
+                                );
+                                return Ok(());
+                            }
+                            handler_call!(
+                                self,
+                                client.server_channel_open_forwarded_tcpip(
                                     channel,
                                     &d.host_to_connect,
                                     d.port_to_connect,
@@ -803,11 +975,11 @@ impl Session {
                                     d.originator_port,
                                     self,
                                 )
-                                .await?
+                            )?
                         }
                         ChannelType::AgentForward => {
                             confirm();
-                            client.server_channel_open_agent_forward(id, self).await?
+                            handler_call!(self, client.server_channel_open_agent_forward(id, self))?
                         }
                         ChannelType::Unknown { typ } => {
                             if client.server_channel_handle_unknown(id, typ) {
@@ -825,29 +997,41 @@ impl Session {
             }
             Some(&msg::REQUEST_SUCCESS) => {
                 trace!("Global Request Success");
-                match self.open_global_requests.pop_front() {
+                match self.global_requests.pop(
+                    &mut self.common.encrypted,
+                    self.common.config.max_pending_global_requests,
+                ) {
                     Some(GlobalRequestResponse::Keepalive) => {
                         // ignore keepalives
                     }
+                    Some(GlobalRequestResponse::KeepaliveReply(return_channel)) => {
+                        let _ = return_channel.send(());
+                    }
                     Some(GlobalRequestResponse::TcpIpForward(return_channel)) => {
-                        let result = if buf.len() == 1 {
-                            // If a specific port was requested, the reply has no data
-                            Some(0)
-                        } else {
-                            let mut r = buf.reader(1);
-                            match r.read_u32() {
-                                Ok(port) => Some(port),
-                                Err(e) => {
-                                    error!("Error parsing port for TcpIpForward request: {e:?}");
-                                    None
-                                }
-                            }
-                        };
-                        let _ = return_channel.send(result);
+                        let _ = return_channel.send(forwarded_port(buf));
+                    }
+                    Some(GlobalRequestResponse::LocalTcpIpForward {
+                        reply_channel,
+                        address,
+                        port,
+                        target,
+                    }) => {
+                        let result = forwarded_port(buf);
+                        if let Some(bound) = result {
+                            let port = if port == 0 { bound } else { port };
+                            self.local_forwards
+                                .insert((address, port), LocalForward::new(target));
+                        }
+                        if let Some(reply_channel) = reply_channel {
+                            let _ = reply_channel.send(result);
+                        }
                     }
                     Some(GlobalRequestResponse::CancelTcpIpForward(return_channel)) => {
                         let _ = return_channel.send(true);
                     }
+                    Some(GlobalRequestResponse::Raw(return_channel)) => {
+                        let _ = return_channel.send(buf.get(1..).map(<[u8]>::to_vec));
+                    }
                     None => {
                         error!("Received global request failure for unknown request!")
                     }
@@ -856,24 +1040,32 @@ impl Session {
             }
             Some(&msg::REQUEST_FAILURE) => {
                 trace!("global request failure");
-                match self.open_global_requests.pop_front() {
-                    Some(GlobalRequestResponse::Keepalive) => {
-                        // ignore keepalives
-                    }
-                    Some(GlobalRequestResponse::TcpIpForward(return_channel)) => {
-                        let _ = return_channel.send(None);
-                    }
-                    Some(GlobalRequestResponse::CancelTcpIpForward(return_channel)) => {
-                        let _ = return_channel.send(false);
-                    }
+                match self.global_requests.pop(
+                    &mut self.common.encrypted,
+                    self.common.config.max_pending_global_requests,
+                ) {
+                    Some(request) => request.fail(),
                     None => {
                         error!("Received global request failure for unknown request!")
                     }
                 }
                 Ok(())
             }
+            Some(&msg::SERVICE_ACCEPT) => Ok(self.unexpected("SERVICE_ACCEPT")?),
+            // Some servers repeat it. Authentication is over either way,
+            // so it is ignored even if the configuration isn't lenient.
+            Some(&msg::USERAUTH_SUCCESS) => {
+                warn!("Ignoring repeated USERAUTH_SUCCESS from the server");
+                Ok(())
+            }
+            // RFC 8308 only allows a second EXT_INFO just before
+            // USERAUTH_SUCCESS, but a later one is harmless.
+            Some(&msg::EXT_INFO) => self.handle_ext_info(client, buf),
             m => {
                 debug!("unknown message received: {:?}", m);
+                if m.map_or(false, |&t| !msg::is_known(t)) {
+                    self.common.unimplemented(packet_seqn);
+                }
                 Ok(())
             }
         }
@@ -889,6 +1081,7 @@ impl Session {
             self.inbound_channel_sender.clone(),
             msg.recipient_maximum_packet_size,
             msg.recipient_window_size,
+            self.common.config.channel_buffer_size,
         );
 
         self.channels.insert(id, channel_ref);
@@ -896,7 +1089,11 @@ impl Session {
         channel
     }
 
-    pub(crate) fn write_auth_request_if_needed(&mut self, user: &str, meth: auth::Method) -> bool {
+    pub(crate) fn write_auth_request_if_needed(
+        &mut self,
+        user: &str,
+        mut meth: auth::Method,
+    ) -> bool {
         let mut is_waiting = false;
         if let Some(ref mut enc) = self.common.encrypted {
             is_waiting = match enc.state {
@@ -923,6 +1120,9 @@ impl Session {
                 is_waiting
             );
             if is_waiting {
+                if let Some(ref algs) = self.server_sig_algs {
+                    choose_rsa_hash(&mut meth, algs);
+                }
                 enc.write_auth_request(user, &meth);
             }
         }
@@ -933,6 +1133,50 @@ impl Session {
     }
 }
 
+/// Makes RSA keys sign with a hash the server accepts, preferring the
+/// key's own, then SHA-512, SHA-256 and finally SHA-1.
+fn choose_rsa_hash(method: &mut auth::Method, server_sig_algs: &[String]) {
+    let current = match method {
+        auth::Method::PublicKey {
+            hash_alg: Some(_), ..
+        } => return,
+        auth::Method::PublicKey { key, .. } => match **key {
+            key::KeyPair::RSA { hash, .. } => hash,
+            _ => return,
+        },
+        auth::Method::FuturePublicKey {
+            key: key::PublicKey::RSA { hash, .. },
+        } => *hash,
+        _ => return,
+    };
+    let accepted = |hash: key::SignatureHash| server_sig_algs.iter().any(|a| a == hash.name().0);
+    let Some(hash) = [
+        current,
+        key::SignatureHash::SHA2_512,
+        key::SignatureHash::SHA2_256,
+        key::SignatureHash::SHA1,
+    ]
+    .iter()
+    .copied()
+    .find(|h| accepted(*h)) else {
+        debug!("the server doesn't accept RSA signatures");
+        return;
+    };
+    if hash == current {
+        return;
+    }
+    debug!("using {} for the RSA key", hash.name().0);
+    match method {
+        auth::Method::PublicKey { key, .. } => {
+            if let Some(k) = key.with_signature_hash(hash) {
+                *key = std::sync::Arc::new(k)
+            }
+        }
+        auth::Method::FuturePublicKey { key } => key.set_algorithm(hash),
+        _ => {}
+    }
+}
+
 impl Encrypted {
     fn write_auth_request(&mut self, user: &str, auth_method: &auth::Method) -> bool {
         // The server is waiting for our USERAUTH_REQUEST.
@@ -954,7 +1198,7 @@ impl Encrypted {
                     self.write.extend_ssh_string(password.as_bytes());
                     true
                 }
-                auth::Method::PublicKey { ref key } => {
+                auth::Method::PublicKey { ref key, .. } => {
                     self.write.extend_ssh_string(user.as_bytes());
                     self.write.extend_ssh_string(b"ssh-connection");
                     self.write.extend_ssh_string(b"publickey");
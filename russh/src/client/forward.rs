@@ -0,0 +1,73 @@
+use log::debug;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+
+use super::Msg;
+use crate::runtime::Spawner;
+use crate::{Channel, ChannelMsg};
+
+/// Where the connections to a remote port forwarding go, see
+/// [`Handle::tcpip_forward_to`](super::Handle::tcpip_forward_to).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ForwardTarget {
+    /// To [`Handler::server_channel_open_forwarded_tcpip`](super::Handler::server_channel_open_forwarded_tcpip).
+    Handler,
+    /// To a TCP connection to this local address, such as
+    /// `"127.0.0.1:8080"`, piped by the session.
+    Local(String),
+}
+
+/// A remote port forwarding whose connections are piped to a local
+/// address.
+#[derive(Debug)]
+pub(crate) struct LocalForward {
+    target: String,
+    /// Dropping the sender stops the piping tasks.
+    _stop: watch::Sender<()>,
+    stopped: watch::Receiver<()>,
+}
+
+impl LocalForward {
+    pub(crate) fn new(target: String) -> Self {
+        let (_stop, stopped) = watch::channel(());
+        LocalForward {
+            target,
+            _stop,
+            stopped,
+        }
+    }
+
+    /// Connects `channel` to the target, and pipes data both ways until
+    /// either side is done or the forward is dropped. The channel is
+    /// then closed through `sender`.
+    pub(crate) fn pipe(
+        &self,
+        channel: Channel<Msg>,
+        sender: Sender<Msg>,
+        spawner: Option<&Spawner>,
+    ) {
+        let target = self.target.clone();
+        let mut stopped = self.stopped.clone();
+        crate::runtime::spawn(spawner, async move {
+            let id = channel.id();
+            tokio::select! {
+                r = pipe_to(channel, &target) => {
+                    if let Err(e) = r {
+                        debug!("forwarding to {}: {}", target, e);
+                    }
+                }
+                _ = stopped.changed() => {}
+            }
+            let _ = sender.send(Msg::Channel(id, ChannelMsg::Close)).await;
+        });
+    }
+}
+
+async fn pipe_to(channel: Channel<Msg>, target: &str) -> std::io::Result<()> {
+    let mut socket = TcpStream::connect(target).await?;
+    let mut stream = channel.into_stream();
+    tokio::io::copy_bidirectional(&mut stream, &mut socket).await?;
+    Ok(())
+}
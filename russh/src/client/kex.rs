@@ -4,14 +4,16 @@ use crate::cipher::SealingKey;
 use crate::client::Config;
 use crate::kex::KEXES;
 use crate::negotiation;
-use crate::negotiation::Select;
+use crate::negotiation::{Preferred, Select};
 use crate::session::{KexDhDone, KexInit};
 use crate::sshbuffer::SSHBuffer;
+use crate::CryptoVec;
 
 impl KexInit {
     pub fn client_parse(
         mut self,
         config: &Config,
+        preferred: &Preferred,
         cipher: &mut dyn SealingKey,
         buf: &[u8],
         write_buffer: &mut SSHBuffer,
@@ -21,36 +23,45 @@ impl KexInit {
             // read algorithms from packet.
             debug!("extending {:?}", &self.exchange.server_kex_init[..]);
             self.exchange.server_kex_init.extend(buf);
-            negotiation::Client::read_kex(buf, &config.preferred, None)?
+            negotiation::Client::read_kex(buf, preferred, None)?
         };
         debug!("algo = {:?}", algo);
         debug!("write = {:?}", &write_buffer.buffer[..]);
         if !self.sent {
-            self.client_write(config, cipher, write_buffer)?
+            self.client_write(config, preferred, cipher, write_buffer)?
         }
 
-        // This function is called from the public API.
-        //
-        // In order to simplify the public API, we reuse the
-        // self.exchange.client_kex buffer to send an extra packet,
-        // then truncate that buffer. Without that, we would need an
-        // extra buffer.
-        let i0 = self.exchange.client_kex_init.len();
-        debug!("i0 = {:?}", i0);
+        let kex = match self.guess.take() {
+            Some((name, kex)) if algo.guess_matches && name == algo.kex => {
+                debug!("the guessed kex packet was right");
+                kex
+            }
+            _ => {
+                // This function is called from the public API.
+                //
+                // In order to simplify the public API, we reuse the
+                // self.exchange.client_kex buffer to send an extra packet,
+                // then truncate that buffer. Without that, we would need an
+                // extra buffer.
+                let i0 = self.exchange.client_kex_init.len();
+                debug!("i0 = {:?}", i0);
 
-        let mut kex = KEXES
-            .get(&algo.kex)
-            .ok_or(crate::Error::UnknownAlgo)?
-            .make();
+                let mut kex = KEXES
+                    .get(&algo.kex)
+                    .ok_or(crate::Error::UnknownAlgo)?
+                    .make();
 
-        kex.client_dh(
-            &mut self.exchange.client_ephemeral,
-            &mut self.exchange.client_kex_init,
-        )?;
+                kex.client_dh(
+                    &mut self.exchange.client_ephemeral,
+                    &mut self.exchange.client_kex_init,
+                )?;
 
-        #[allow(clippy::indexing_slicing)] // length checked
-        cipher.write(&self.exchange.client_kex_init[i0..], write_buffer);
-        self.exchange.client_kex_init.resize(i0);
+                #[allow(clippy::indexing_slicing)] // length checked
+                cipher.write(&self.exchange.client_kex_init[i0..], write_buffer);
+                self.exchange.client_kex_init.resize(i0);
+                kex
+            }
+        };
 
         debug!("moving to kexdhdone, exchange = {:?}", self.exchange);
         Ok(KexDhDone {
@@ -65,13 +76,36 @@ impl KexInit {
     pub fn client_write(
         &mut self,
         config: &Config,
+        preferred: &Preferred,
         cipher: &mut dyn SealingKey,
         write_buffer: &mut SSHBuffer,
     ) -> Result<(), crate::Error> {
         self.exchange.client_kex_init.clear();
-        negotiation::write_kex(&config.preferred, &mut self.exchange.client_kex_init, None)?;
+        // Only guess before knowing the server's algorithms.
+        let guess = if config.first_kex_packet_follows && self.algo.is_none() {
+            preferred
+                .kex
+                .first()
+                .and_then(|name| Some((*name, KEXES.get(name)?.make())))
+                .filter(|(_, kex)| !kex.skip_exchange())
+        } else {
+            None
+        };
+        negotiation::write_kex(
+            preferred,
+            &mut self.exchange.client_kex_init,
+            None,
+            guess.is_some(),
+        )?;
         self.sent = true;
         cipher.write(&self.exchange.client_kex_init, write_buffer);
+        if let Some((name, mut kex)) = guess {
+            debug!("guessing kex algorithm {:?}", name);
+            let mut packet = CryptoVec::new();
+            kex.client_dh(&mut self.exchange.client_ephemeral, &mut packet)?;
+            cipher.write(&packet, write_buffer);
+            self.guess = Some((name, kex));
+        }
         Ok(())
     }
 }
@@ -0,0 +1,485 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::Future;
+use log::{error, info};
+
+use super::{Handler, Msg, Session};
+use crate::keys::key::{self, PublicKey};
+use crate::keys::KnownHostsWriter;
+use crate::{Channel, ChannelId, ChannelOpenFailure, Direction, Sig};
+
+/// A host key that isn't in the known_hosts file yet, as shown to
+/// [`HostKeyPolicy::Ask`].
+#[derive(Debug, Clone)]
+pub struct UnknownHostKey {
+    pub host: String,
+    pub port: u16,
+    /// The SHA-256 fingerprint of `key`, as shown by OpenSSH (`SHA256:...`).
+    pub fingerprint: String,
+    pub key: PublicKey,
+}
+
+type AskFn = dyn Fn(UnknownHostKey) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync;
+
+/// What to do with a host key, following OpenSSH's
+/// `StrictHostKeyChecking`. Whatever the policy, a key that differs
+/// from the one recorded for the host is an error.
+#[derive(Clone)]
+pub enum HostKeyPolicy {
+    /// Only accept hosts that are already known.
+    Strict,
+    /// Accept unknown hosts and record their key.
+    AcceptNew,
+    /// Accept unknown hosts without recording them.
+    Off,
+    /// Ask the callback about unknown hosts, and record their key if
+    /// it returns `true`. See [`HostKeyPolicy::ask`].
+    Ask(Arc<AskFn>),
+}
+
+impl HostKeyPolicy {
+    /// Builds [`HostKeyPolicy::Ask`] from an async callback.
+    pub fn ask<F, Fut>(f: F) -> Self
+    where
+        F: Fn(UnknownHostKey) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        HostKeyPolicy::Ask(Arc::new(move |unknown| Box::pin(f(unknown))))
+    }
+}
+
+impl fmt::Debug for HostKeyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostKeyPolicy::Strict => f.write_str("Strict"),
+            HostKeyPolicy::AcceptNew => f.write_str("AcceptNew"),
+            HostKeyPolicy::Off => f.write_str("Off"),
+            HostKeyPolicy::Ask(_) => f.write_str("Ask(..)"),
+        }
+    }
+}
+
+/// A [`Handler`] checking the server key against a known_hosts file
+/// according to a [`HostKeyPolicy`], and passing every other callback
+/// to the wrapped handler (whose own `check_server_key` and
+/// `check_server_key_blob` are not called).
+///
+/// ```no_run
+/// # async fn connect<H: russh::client::Handler + 'static>(handler: H) -> Result<(), H::Error> {
+/// use russh::client::{self, HostKeyPolicy, KnownHostsHandler};
+///
+/// let handler = KnownHostsHandler::new(handler, "example.com", 22, HostKeyPolicy::AcceptNew);
+/// let session = client::connect(Default::default(), ("example.com", 22), handler).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct KnownHostsHandler<H> {
+    inner: H,
+    host: String,
+    port: u16,
+    policy: HostKeyPolicy,
+    path: Option<PathBuf>,
+    hash_known_hosts: bool,
+}
+
+impl<H: Handler> KnownHostsHandler<H> {
+    /// `host` and `port` are the ones the known_hosts entries are
+    /// looked up (and recorded) for, usually the address given to
+    /// [`super::connect`].
+    pub fn new<S: Into<String>>(inner: H, host: S, port: u16, policy: HostKeyPolicy) -> Self {
+        KnownHostsHandler {
+            inner,
+            host: host.into(),
+            port,
+            policy,
+            path: None,
+            hash_known_hosts: false,
+        }
+    }
+
+    /// Uses `path` instead of the user's known_hosts file.
+    pub fn known_hosts_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Hash the host names of the entries learnt from now on, like
+    /// OpenSSH's `HashKnownHosts` option (defaults to `false`).
+    pub fn hash_known_hosts(mut self, hash_known_hosts: bool) -> Self {
+        self.hash_known_hosts = hash_known_hosts;
+        self
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    fn check(&self, key: &PublicKey) -> Result<bool, crate::keys::Error> {
+        match self.path {
+            Some(ref path) => crate::keys::check_known_hosts_path(&self.host, self.port, key, path),
+            None => crate::keys::check_known_hosts(&self.host, self.port, key),
+        }
+    }
+
+    fn learn(&self, key: &PublicKey) -> Result<(), crate::keys::Error> {
+        info!(
+            "Adding {} key {} for {}:{} to known_hosts",
+            key.name(),
+            key.fingerprint(key::HashAlg::Sha256),
+            self.host,
+            self.port
+        );
+        let writer = match self.path {
+            Some(ref path) => KnownHostsWriter::new(path),
+            None => KnownHostsWriter::for_user()?,
+        };
+        writer
+            .hash_hostnames(self.hash_known_hosts)
+            .learn(&self.host, self.port, key)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<H: Handler> Handler for KnownHostsHandler<H> {
+    type Error = H::Error;
+
+    async fn auth_banner(
+        &mut self,
+        banner: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.auth_banner(banner, session).await
+    }
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        match self.check(server_public_key) {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(crate::keys::Error::KeyChanged { line }) => {
+                error!(
+                    "HOST KEY MISMATCH for {}:{}: the {} key {} differs from the one recorded \
+                     at line {} of {}. Someone could be eavesdropping on you (man-in-the-middle \
+                     attack), or the host key has just been changed.",
+                    self.host,
+                    self.port,
+                    server_public_key.name(),
+                    server_public_key.fingerprint(key::HashAlg::Sha256),
+                    line,
+                    self.path
+                        .as_ref()
+                        .map(|p| p.display().to_string())
+                        .unwrap_or_else(|| "the known_hosts file".to_string()),
+                );
+                return Err(crate::Error::from(crate::keys::Error::KeyChanged { line }).into());
+            }
+            Err(e) => return Err(crate::Error::from(e).into()),
+        }
+        let accept = match self.policy {
+            HostKeyPolicy::Strict => {
+                error!(
+                    "No {} host key is known for {}:{}",
+                    server_public_key.name(),
+                    self.host,
+                    self.port
+                );
+                false
+            }
+            HostKeyPolicy::Off => true,
+            HostKeyPolicy::AcceptNew => {
+                self.learn(server_public_key).map_err(crate::Error::from)?;
+                true
+            }
+            HostKeyPolicy::Ask(ref ask) => {
+                let accept = ask(UnknownHostKey {
+                    host: self.host.clone(),
+                    port: self.port,
+                    fingerprint: server_public_key.fingerprint(key::HashAlg::Sha256),
+                    key: server_public_key.clone(),
+                })
+                .await;
+                if accept {
+                    self.learn(server_public_key).map_err(crate::Error::from)?;
+                }
+                accept
+            }
+        };
+        Ok(accept)
+    }
+
+    async fn channel_open_confirmation(
+        &mut self,
+        id: ChannelId,
+        max_packet_size: u32,
+        window_size: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .channel_open_confirmation(id, max_packet_size, window_size, session)
+            .await
+    }
+
+    async fn channel_success(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.channel_success(channel, session).await
+    }
+
+    async fn channel_failure(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.channel_failure(channel, session).await
+    }
+
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.channel_close(channel, session).await
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.channel_eof(channel, session).await
+    }
+
+    async fn end_of_write(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.end_of_write(channel, session).await
+    }
+
+    async fn channel_open_failure(
+        &mut self,
+        channel: ChannelId,
+        reason: ChannelOpenFailure,
+        description: &str,
+        language: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .channel_open_failure(channel, reason, description, language, session)
+            .await
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        connected_address: &str,
+        connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .server_channel_open_forwarded_tcpip(
+                channel,
+                connected_address,
+                connected_port,
+                originator_address,
+                originator_port,
+                session,
+            )
+            .await
+    }
+
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .server_channel_open_agent_forward(channel, session)
+            .await
+    }
+
+    fn server_channel_handle_unknown(&self, channel: ChannelId, channel_type: &[u8]) -> bool {
+        self.inner
+            .server_channel_handle_unknown(channel, channel_type)
+    }
+
+    async fn server_channel_open_session(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .server_channel_open_session(channel, session)
+            .await
+    }
+
+    async fn server_channel_open_direct_tcpip(
+        &mut self,
+        channel: ChannelId,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        originator_address: &str,
+        originator_port: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .server_channel_open_direct_tcpip(
+                channel,
+                host_to_connect,
+                port_to_connect,
+                originator_address,
+                originator_port,
+                session,
+            )
+            .await
+    }
+
+    async fn server_channel_open_x11(
+        &mut self,
+        channel: Channel<Msg>,
+        originator_address: &str,
+        originator_port: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .server_channel_open_x11(channel, originator_address, originator_port, session)
+            .await
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.data(channel, data, session).await
+    }
+
+    async fn extended_data(
+        &mut self,
+        channel: ChannelId,
+        ext: u32,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.extended_data(channel, ext, data, session).await
+    }
+
+    fn on_channel_data(&mut self, direction: Direction, channel: ChannelId, data: &[u8]) {
+        self.inner.on_channel_data(direction, channel, data)
+    }
+
+    fn on_channel_extended_data(
+        &mut self,
+        direction: Direction,
+        channel: ChannelId,
+        ext: u32,
+        data: &[u8],
+    ) {
+        self.inner
+            .on_channel_extended_data(direction, channel, ext, data)
+    }
+
+    async fn xon_xoff(
+        &mut self,
+        channel: ChannelId,
+        client_can_do: bool,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.xon_xoff(channel, client_can_do, session).await
+    }
+
+    async fn exit_status(
+        &mut self,
+        channel: ChannelId,
+        exit_status: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.exit_status(channel, exit_status, session).await
+    }
+
+    async fn exit_signal(
+        &mut self,
+        channel: ChannelId,
+        signal_name: Sig,
+        core_dumped: bool,
+        error_message: &str,
+        lang_tag: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .exit_signal(
+                channel,
+                signal_name,
+                core_dumped,
+                error_message,
+                lang_tag,
+                session,
+            )
+            .await
+    }
+
+    async fn channel_request(
+        &mut self,
+        channel: ChannelId,
+        request_type: &str,
+        want_reply: bool,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .channel_request(channel, request_type, want_reply, data, session)
+            .await
+    }
+
+    async fn window_adjusted(
+        &mut self,
+        channel: ChannelId,
+        new_size: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.window_adjusted(channel, new_size, session).await
+    }
+
+    fn adjust_window(&mut self, channel: ChannelId, window: u32) -> u32 {
+        self.inner.adjust_window(channel, window)
+    }
+
+    async fn openssh_ext_host_keys_announced(
+        &mut self,
+        keys: Vec<key::PublicKey>,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .openssh_ext_host_keys_announced(keys, session)
+            .await
+    }
+
+    async fn disconnected(
+        &mut self,
+        reason: super::DisconnectReason<Self::Error>,
+    ) -> Result<(), Self::Error> {
+        self.inner.disconnected(reason).await
+    }
+}
@@ -39,12 +39,13 @@ use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
 use std::num::Wrapping;
 use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::task::{Context, Poll};
-use futures::Future;
-use log::{debug, error, info, trace};
+use futures::{Future, Stream};
+use log::{debug, error, info, trace, warn};
 use ssh_key::Certificate;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::{TcpStream, ToSocketAddrs};
@@ -53,26 +54,45 @@ use tokio::sync::mpsc::{
     channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
 };
 use tokio::sync::{oneshot, Mutex};
+use zeroize::Zeroizing;
 
-use crate::channels::{Channel, ChannelMsg, ChannelRef};
-use crate::cipher::{self, clear, CipherPair, OpeningKey};
+use crate::channels::{
+    channels_full, fail_channels, flush_channels, wait_channel_capacity, Channel, ChannelLabel,
+    ChannelMsg, ChannelRef, Direction, SessionError,
+};
+use crate::cipher::pipeline::PacketReader;
+use crate::cipher::{clear, CipherPair, OpeningKey};
 use crate::key::PubKey;
+use crate::keys::agent::client::AgentClient;
 use crate::keys::encoding::Reader;
 use crate::keys::key::{self, parse_public_key, PublicKey, SignatureHash};
+use crate::rate_limit::TokenBucket;
 use crate::session::{
-    CommonSession, EncryptedState, Exchange, GlobalRequestResponse, Kex, KexDhDone, KexInit,
-    NewKeys,
+    CommonSession, EncryptedState, ErrorDisconnect, Exchange, GlobalRequests, Kex, KexDhDone,
+    KexInit, NewKeys,
 };
 use crate::ssh_read::SshRead;
 use crate::sshbuffer::{SSHBuffer, SshId};
 use crate::{
-    auth, msg, negotiation, strict_kex_violation, ChannelId, ChannelOpenFailure, CryptoVec,
-    Disconnect, Limits, Sig,
+    auth, msg, negotiation, strict_kex_violation, ChannelId, ChannelInfo, ChannelOpenFailure,
+    CryptoVec, Disconnect, Extensions, ExtensionsView, Limits, RateLimit, Sig, WriteBufferPolicy,
 };
 
 mod encrypted;
+mod forward;
 mod kex;
+mod known_hosts;
+mod pool;
+mod proxy;
 mod session;
+mod timings;
+
+pub use forward::ForwardTarget;
+pub use known_hosts::{HostKeyPolicy, KnownHostsHandler, UnknownHostKey};
+pub use pool::{ConnectionLease, ConnectionPool, PoolConfig, PoolEvent, PoolKey, PooledChannel};
+pub use proxy::{connect_via_proxy, Proxy, ProxyAuth, ProxyError, Socks5Error};
+pub use timings::HandshakeTimings;
+use timings::{SharedTimings, Step};
 
 /// Actual client session's state.
 ///
@@ -85,11 +105,32 @@ pub struct Session {
     sender: UnboundedSender<Reply>,
     channels: HashMap<ChannelId, ChannelRef>,
     target_window_size: u32,
-    pending_reads: Vec<CryptoVec>,
-    pending_len: u32,
     inbound_channel_sender: Sender<Msg>,
     inbound_channel_receiver: Receiver<Msg>,
-    open_global_requests: VecDeque<GlobalRequestResponse>,
+    global_requests: GlobalRequests,
+    /// Remote port forwardings piped to a local address, by address
+    /// and port.
+    local_forwards: HashMap<(String, u32), forward::LocalForward>,
+    event_sender: SharedEventSender,
+    timings: SharedTimings,
+    extensions: Extensions,
+    /// The signature algorithms the server accepts for public key
+    /// authentication, if it sent `server-sig-algs` (RFC 8308).
+    server_sig_algs: Option<Vec<String>>,
+    /// Whether the server announced `ping@openssh.com`.
+    server_ping: bool,
+    /// All the identification strings the server sent, see
+    /// [`Config::use_last_server_id`].
+    remote_sshids: Vec<Vec<u8>>,
+    /// The algorithms offered to the server when
+    /// [`Config::auto_legacy_compat`] adds some to those of the config.
+    legacy_preferred: Option<negotiation::Preferred>,
+    /// The PINGs waiting for their PONG: data, and when they were sent.
+    pings: VecDeque<(
+        Vec<u8>,
+        std::time::Instant,
+        oneshot::Sender<Option<std::time::Duration>>,
+    )>,
 }
 
 const STRICT_KEX_MSG_ORDER: &[u8] = &[msg::KEXINIT, msg::KEX_ECDH_REPLY, msg::NEWKEYS];
@@ -104,7 +145,10 @@ impl Drop for Session {
 #[allow(clippy::large_enum_variant)]
 enum Reply {
     AuthSuccess,
-    AuthFailure,
+    AuthFailure {
+        remaining_methods: Vec<String>,
+        partial_success: bool,
+    },
     ChannelOpenFailure,
     SignRequest {
         key: key::PublicKey,
@@ -124,7 +168,7 @@ pub enum Msg {
         method: auth::Method,
     },
     AuthInfoResponse {
-        responses: Vec<String>,
+        responses: Zeroizing<Vec<String>>,
     },
     Signed {
         data: CryptoVec,
@@ -153,6 +197,7 @@ pub enum Msg {
         reply_channel: Option<oneshot::Sender<Option<u32>>>,
         address: String,
         port: u32,
+        target: ForwardTarget,
     },
     CancelTcpIpForward {
         /// Provide a channel for the reply result to request a reply from the server
@@ -160,6 +205,26 @@ pub enum Msg {
         address: String,
         port: u32,
     },
+    Keepalive {
+        reply_channel: oneshot::Sender<()>,
+    },
+    Ping {
+        data: Vec<u8>,
+        reply_channel: oneshot::Sender<Option<std::time::Duration>>,
+    },
+    GlobalRequest {
+        name: String,
+        payload: Vec<u8>,
+        reply_channel: Option<oneshot::Sender<Option<Vec<u8>>>>,
+    },
+    ListChannels {
+        reply_channel: oneshot::Sender<Vec<ChannelInfo>>,
+    },
+    ForceClose {
+        id: ChannelId,
+        reason: String,
+        reply_channel: oneshot::Sender<bool>,
+    },
     Close {
         id: ChannelId,
     },
@@ -168,6 +233,14 @@ pub enum Msg {
         description: String,
         language_tag: String,
     },
+    #[cfg(feature = "danger-raw-packets")]
+    RawPacket {
+        payload: Vec<u8>,
+    },
+    Flush {
+        reply_channel: oneshot::Sender<()>,
+    },
+    ReadExtensions(crate::extensions::ReadExtensions),
     Channel(ChannelId, ChannelMsg),
 }
 
@@ -188,13 +261,80 @@ pub enum KeyboardInteractiveAuthResponse {
     },
 }
 
+/// The key accepted by the server, as reported by the methods of
+/// [`Handle`] that try several keys in turn.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyIdentity {
+    pub public_key: PublicKey,
+    /// The SHA-256 fingerprint of the key, as shown by `ssh-keygen -l`.
+    pub fingerprint: String,
+    /// The comment the agent stores with the key, if it came from an
+    /// agent and has one.
+    pub comment: Option<String>,
+    /// The file the key was loaded from, if it came from a file.
+    pub path: Option<std::path::PathBuf>,
+}
+
+impl KeyIdentity {
+    fn new(
+        public_key: PublicKey,
+        comment: Option<String>,
+        path: Option<std::path::PathBuf>,
+    ) -> Self {
+        KeyIdentity {
+            fingerprint: public_key.fingerprint(key::HashAlg::Sha256),
+            public_key,
+            comment: comment.filter(|c| !c.is_empty()),
+            path,
+        }
+    }
+}
+
+/// The server's answer to an authentication attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult {
+    Success,
+    Failure {
+        /// The methods that can continue the authentication, as sent
+        /// by the server, including those russh doesn't implement.
+        remaining_methods: Vec<String>,
+        /// Whether the attempt was accepted but more authentication is
+        /// required, for instance with a second factor.
+        partial_success: bool,
+    },
+}
+
+impl AuthResult {
+    pub fn success(&self) -> bool {
+        matches!(self, AuthResult::Success)
+    }
+
+    /// Whether the server may still accept another public key after
+    /// this answer.
+    fn accepts_more_publickeys(&self) -> bool {
+        match self {
+            AuthResult::Success => false,
+            AuthResult::Failure {
+                remaining_methods, ..
+            } => remaining_methods.iter().any(|m| m == "publickey"),
+        }
+    }
+
+    fn disconnected() -> Self {
+        AuthResult::Failure {
+            remaining_methods: Vec::new(),
+            partial_success: false,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Prompt {
     pub prompt: String,
     pub echo: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RemoteDisconnectInfo {
     pub reason_code: crate::Disconnect,
     pub message: String,
@@ -207,12 +347,81 @@ pub enum DisconnectReason<E: From<crate::Error> + Send> {
     Error(E),
 }
 
+/// An event from the server, delivered by [`Handle::events`].
+///
+/// The default implementations of the corresponding [`Handler`] methods
+/// forward these events to the stream, so overriding a method means the
+/// handler consumes its events. Events that need an immediate answer
+/// (such as [`Handler::check_server_key`]) are never sent to the stream.
+/// Events nobody takes the stream for are dropped, and the channels of
+/// dropped [`ClientEvent::ForwardedTcpIp`] and [`ClientEvent::X11`]
+/// events are closed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ClientEvent {
+    /// An authentication banner, see [`Handler::auth_banner`].
+    Banner(String),
+    /// A global request russh doesn't handle. It has already been
+    /// answered with a failure.
+    GlobalRequest { name: String, want_reply: bool },
+    /// A new remote port forwarding connection, see
+    /// [`Handler::server_channel_open_forwarded_tcpip`].
+    ForwardedTcpIp {
+        channel: Channel<Msg>,
+        connected_address: String,
+        connected_port: u32,
+        originator_address: String,
+        originator_port: u32,
+    },
+    /// A new X11 channel, see [`Handler::server_channel_open_x11`].
+    X11 {
+        channel: Channel<Msg>,
+        originator_address: String,
+        originator_port: u32,
+    },
+    /// The server labeled one of the channels, see
+    /// [`Channel::label`] and [`Config::send_channel_labels`].
+    ChannelLabeled { id: ChannelId, label: String },
+    /// The server announced its host keys, see
+    /// [`Handler::openssh_ext_host_keys_announced`].
+    HostKeysAnnounced(Vec<PublicKey>),
+    /// A key re-exchange has completed.
+    Rekeyed,
+    /// The server sent several identification strings, all given here,
+    /// and the last one was used, see [`Config::use_last_server_id`].
+    MultipleServerIds(Vec<Vec<u8>>),
+    /// A step of the handshake was reached, see
+    /// [`Handle::handshake_timings`].
+    Handshake(HandshakeTimings),
+    /// A [`Handler`] callback, whose name is given, ran for longer
+    /// than [`Config::handler_timeout`]. The session then ends with
+    /// [`Error::HandlerTimeout`](crate::Error::HandlerTimeout).
+    HandlerTimeout(&'static str),
+    /// The session has ended, with the server's disconnect message if
+    /// there was one. This is always the last event.
+    Disconnected(Option<RemoteDisconnectInfo>),
+}
+
+/// The sender of [`Handle::events`], shared by the session and its
+/// handle. It is only set once the stream has been taken, so that events
+/// don't pile up when nobody reads them.
+type SharedEventSender = Arc<std::sync::Mutex<Option<UnboundedSender<ClientEvent>>>>;
+
 /// Handle to a session, used to send messages to a client outside of
 /// the request/response cycle.
 pub struct Handle<H: Handler> {
     sender: Sender<Msg>,
     receiver: UnboundedReceiver<Reply>,
-    join: tokio::task::JoinHandle<Result<(), H::Error>>,
+    /// The events sent before [`connect`] returned, and what
+    /// [`Handle::events`] needs to receive the later ones.
+    events: Option<(
+        UnboundedReceiver<ClientEvent>,
+        UnboundedSender<ClientEvent>,
+        SharedEventSender,
+    )>,
+    join: crate::runtime::Task<Result<(), H::Error>>,
+    channel_buffer_size: Option<usize>,
+    timings: SharedTimings,
 }
 
 impl<H: Handler> Drop for Handle<H> {
@@ -222,16 +431,68 @@ impl<H: Handler> Drop for Handle<H> {
 }
 
 impl<H: Handler> Handle<H> {
+    /// Returns the stream of [`ClientEvent`]s for this session, as an
+    /// alternative to implementing the corresponding [`Handler`] methods.
+    ///
+    /// The stream is unbounded so that a slow consumer never stalls the
+    /// session. It starts with the events of the handshake up to the
+    /// return of [`connect`], events sent later and before it is taken
+    /// are dropped. It can only be taken once, later calls return an
+    /// empty stream. The stream ends when the session ends.
+    pub fn events(&mut self) -> impl Stream<Item = ClientEvent> + Send + Unpin + 'static {
+        let mut events = self.events.take().map(|(receiver, sender, shared)| {
+            *shared.lock().unwrap_or_else(|e| e.into_inner()) = Some(sender);
+            receiver
+        });
+        futures::stream::poll_fn(move |cx| match events {
+            Some(ref mut events) => events.poll_recv(cx),
+            None => Poll::Ready(None),
+        })
+    }
+
     pub fn is_closed(&self) -> bool {
         self.sender.is_closed()
     }
 
+    /// Waits until the session has ended, without consuming the handle
+    /// like awaiting it does. This suits sessions that only forward
+    /// ports and never open a channel, like `ssh -N`: set
+    /// [`Config::keepalive_interval`] so that the server sees the
+    /// connection as active and a dead connection is noticed, and keep
+    /// using the handle while waiting.
+    pub async fn wait_closed(&self) {
+        self.sender.closed().await
+    }
+
+    /// Returns when each step of the handshake happened so far. The
+    /// same timings are sent as [`ClientEvent::Handshake`] when a step
+    /// is reached.
+    pub fn handshake_timings(&self) -> HandshakeTimings {
+        *self.timings.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A view of the [`Extensions`] stored by the
+    /// handler callbacks, which can be cloned into other tasks.
+    pub fn extensions(&self) -> ExtensionsView {
+        ExtensionsView::client(self.sender.clone())
+    }
+
     /// Perform no authentication. This is useful for testing, but should not be
     /// used in most other circumstances.
     pub async fn authenticate_none<U: Into<String>>(
         &mut self,
         user: U,
     ) -> Result<bool, crate::Error> {
+        Ok(self.authenticate_none_ex(user).await?.success())
+    }
+
+    /// Like [`Handle::authenticate_none`], but tells why the attempt
+    /// failed. Servers often reject this method with the list of the
+    /// methods they accept.
+    pub async fn authenticate_none_ex<U: Into<String>>(
+        &mut self,
+        user: U,
+    ) -> Result<AuthResult, crate::Error> {
         let user = user.into();
         self.sender
             .send(Msg::Authenticate {
@@ -244,17 +505,38 @@ impl<H: Handler> Handle<H> {
     }
 
     /// Perform password-based SSH authentication.
+    ///
+    /// The password is kept until the server answers, then wiped from
+    /// memory, as are the packets it was written to. A `&str` is copied
+    /// first, and the caller's copy isn't wiped: to avoid leaving one
+    /// behind, pass an owned `String`, for instance taken out of a
+    /// [`Zeroizing<String>`](zeroize::Zeroizing) with
+    /// `std::mem::take(&mut *password)`.
     pub async fn authenticate_password<U: Into<String>, P: Into<String>>(
         &mut self,
         user: U,
         password: P,
     ) -> Result<bool, crate::Error> {
+        Ok(self
+            .authenticate_password_ex(user, password)
+            .await?
+            .success())
+    }
+
+    /// Like [`Handle::authenticate_password`], but tells whether the
+    /// password was accepted as one step of a multi-factor
+    /// authentication, and which methods remain.
+    pub async fn authenticate_password_ex<U: Into<String>, P: Into<String>>(
+        &mut self,
+        user: U,
+        password: P,
+    ) -> Result<AuthResult, crate::Error> {
         let user = user.into();
         self.sender
             .send(Msg::Authenticate {
                 user,
                 method: auth::Method::Password {
-                    password: password.into(),
+                    password: Zeroizing::new(password.into()),
                 },
             })
             .await
@@ -291,12 +573,16 @@ impl<H: Handler> Handle<H> {
     ///
     /// * `responses` - The responses to each prompt. The number of responses must match the number
     /// of prompts. If a prompt has an empty string, then the response should be an empty string.
+    ///
+    /// The responses are wiped from memory once they have been sent.
     pub async fn authenticate_keyboard_interactive_respond(
         &mut self,
         responses: Vec<String>,
     ) -> Result<KeyboardInteractiveAuthResponse, crate::Error> {
         self.sender
-            .send(Msg::AuthInfoResponse { responses })
+            .send(Msg::AuthInfoResponse {
+                responses: Zeroizing::new(responses),
+            })
             .await
             .map_err(|_| crate::Error::SendError)?;
         self.wait_recv_keyboard_interactive_reply().await
@@ -308,7 +594,9 @@ impl<H: Handler> Handle<H> {
         loop {
             match self.receiver.recv().await {
                 Some(Reply::AuthSuccess) => return Ok(KeyboardInteractiveAuthResponse::Success),
-                Some(Reply::AuthFailure) => return Ok(KeyboardInteractiveAuthResponse::Failure),
+                Some(Reply::AuthFailure { .. }) => {
+                    return Ok(KeyboardInteractiveAuthResponse::Failure)
+                }
                 Some(Reply::AuthInfoRequest {
                     name,
                     instructions,
@@ -325,28 +613,119 @@ impl<H: Handler> Handle<H> {
         }
     }
 
-    async fn wait_recv_reply(&mut self) -> Result<bool, crate::Error> {
+    async fn wait_recv_reply(&mut self) -> Result<AuthResult, crate::Error> {
         loop {
             match self.receiver.recv().await {
-                Some(Reply::AuthSuccess) => return Ok(true),
-                Some(Reply::AuthFailure) => return Ok(false),
-                None => return Ok(false),
+                Some(Reply::AuthSuccess) => return Ok(AuthResult::Success),
+                Some(Reply::AuthFailure {
+                    remaining_methods,
+                    partial_success,
+                }) => {
+                    return Ok(AuthResult::Failure {
+                        remaining_methods,
+                        partial_success,
+                    })
+                }
+                None => return Ok(AuthResult::disconnected()),
                 _ => {}
             }
         }
     }
 
     /// Perform public key-based SSH authentication.
+    ///
+    /// The session drops its reference to `key` once the server has
+    /// answered. The private key is wiped from memory when the last
+    /// reference to it is dropped.
     pub async fn authenticate_publickey<U: Into<String>>(
         &mut self,
         user: U,
         key: Arc<key::KeyPair>,
     ) -> Result<bool, crate::Error> {
+        Ok(self.authenticate_publickey_ex(user, key).await?.success())
+    }
+
+    /// Like [`Handle::authenticate_publickey`], but tells whether the
+    /// key was accepted as one step of a multi-factor authentication,
+    /// and which methods remain.
+    pub async fn authenticate_publickey_ex<U: Into<String>>(
+        &mut self,
+        user: U,
+        key: Arc<key::KeyPair>,
+    ) -> Result<AuthResult, crate::Error> {
+        let user = user.into();
+        self.sender
+            .send(Msg::Authenticate {
+                user,
+                method: auth::Method::PublicKey {
+                    key,
+                    hash_alg: None,
+                },
+            })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+        self.wait_recv_reply().await
+    }
+
+    /// Loads the private key at `path`, decrypting it with `passphrase`
+    /// if it is encrypted, and authenticates with it like
+    /// [`Handle::authenticate_publickey`].
+    ///
+    /// Fails with [`russh_keys::Error::KeyIsEncrypted`] if the key needs
+    /// a passphrase and none is given, and with
+    /// [`russh_keys::Error::WrongPassphrase`] if `passphrase` doesn't
+    /// decrypt it.
+    pub async fn authenticate_publickey_from_file<U: Into<String>, P: AsRef<std::path::Path>>(
+        &mut self,
+        user: U,
+        path: P,
+        passphrase: Option<&str>,
+    ) -> Result<bool, crate::Error> {
+        let key = russh_keys::load_secret_key(path, passphrase)?;
+        self.authenticate_publickey(user, Arc::new(key)).await
+    }
+
+    /// Perform public key-based SSH authentication, signing with
+    /// `hash_alg` instead of the algorithm picked from the server's
+    /// `server-sig-algs` extension. This is useful to work around
+    /// servers that advertise algorithms they don't accept.
+    ///
+    /// Only RSA keys have a choice of signature algorithm, other keys
+    /// return [`russh_keys::Error::UnsupportedKeyType`].
+    pub async fn authenticate_publickey_with_hash_alg<U: Into<String>>(
+        &mut self,
+        user: U,
+        key: Arc<key::KeyPair>,
+        hash_alg: key::SignatureHash,
+    ) -> Result<bool, crate::Error> {
+        Ok(self
+            .authenticate_publickey_with_hash_alg_ex(user, key, hash_alg)
+            .await?
+            .success())
+    }
+
+    /// Like [`Handle::authenticate_publickey_with_hash_alg`], but
+    /// returns the server's full answer.
+    pub async fn authenticate_publickey_with_hash_alg_ex<U: Into<String>>(
+        &mut self,
+        user: U,
+        key: Arc<key::KeyPair>,
+        hash_alg: key::SignatureHash,
+    ) -> Result<AuthResult, crate::Error> {
+        let Some(key) = key.with_signature_hash(hash_alg) else {
+            return Err(crate::Error::Keys(russh_keys::Error::UnsupportedKeyType {
+                key_type_string: key.name().to_string(),
+                key_type_raw: key.name().as_bytes().to_vec(),
+            }));
+        };
         let user = user.into();
         self.sender
             .send(Msg::Authenticate {
                 user,
-                method: auth::Method::PublicKey { key },
+                method: auth::Method::PublicKey {
+                    key: Arc::new(key),
+                    hash_alg: Some(hash_alg),
+                },
             })
             .await
             .map_err(|_| crate::Error::SendError)?;
@@ -360,6 +739,20 @@ impl<H: Handler> Handle<H> {
         key: Arc<key::KeyPair>,
         cert: Certificate,
     ) -> Result<bool, crate::Error> {
+        Ok(self
+            .authenticate_openssh_cert_ex(user, key, cert)
+            .await?
+            .success())
+    }
+
+    /// Like [`Handle::authenticate_openssh_cert`], but returns the
+    /// server's full answer.
+    pub async fn authenticate_openssh_cert_ex<U: Into<String>>(
+        &mut self,
+        user: U,
+        key: Arc<key::KeyPair>,
+        cert: Certificate,
+    ) -> Result<AuthResult, crate::Error> {
         let user = user.into();
         self.sender
             .send(Msg::Authenticate {
@@ -379,9 +772,182 @@ impl<H: Handler> Handle<H> {
         &mut self,
         user: U,
         key: key::PublicKey,
-        mut future: S,
+        future: S,
     ) -> (S, Result<bool, S::Error>) {
+        let (future, result) = self.authenticate_signer(user.into(), key, future).await;
+        (future, result.map(|r| r.success()))
+    }
+
+    /// Like [`Handle::authenticate_future`], but returns the server's
+    /// full answer.
+    pub async fn authenticate_future_ex<U: Into<String>, S: auth::Signer>(
+        &mut self,
+        user: U,
+        key: key::PublicKey,
+        future: S,
+    ) -> (S, Result<AuthResult, S::Error>) {
+        self.authenticate_signer(user.into(), key, future).await
+    }
+
+    /// Authenticate with each identity of an SSH agent in turn, like
+    /// OpenSSH does, until one is accepted. This stops early once the
+    /// server no longer accepts the "publickey" method, for instance
+    /// because there were too many attempts.
+    ///
+    /// RSA keys are used with the best hash the server announced in
+    /// `server-sig-algs`.
+    pub async fn authenticate_agent<U: Into<String>, R>(
+        &mut self,
+        user: U,
+        agent: AgentClient<R>,
+    ) -> (AgentClient<R>, Result<bool, auth::AgentAuthError>)
+    where
+        R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (agent, result) = self.authenticate_agent_ex(user, agent).await;
+        (agent, result.map(|identity| identity.is_some()))
+    }
+
+    /// Like [`Handle::authenticate_agent`], but tells which identity
+    /// was accepted, if any.
+    pub async fn authenticate_agent_ex<U: Into<String>, R>(
+        &mut self,
+        user: U,
+        mut agent: AgentClient<R>,
+    ) -> (
+        AgentClient<R>,
+        Result<Option<KeyIdentity>, auth::AgentAuthError>,
+    )
+    where
+        R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let user = user.into();
+        let identities = match agent.request_identities_with_comments().await {
+            Ok(identities) => identities,
+            Err(e) => return (agent, Err(e.into())),
+        };
+        for (key, comment) in identities {
+            debug!("trying agent key {}", key.fingerprint(key::HashAlg::Sha256));
+            let identity = KeyIdentity::new(key.clone(), Some(comment), None);
+            let (a, result) = self.authenticate_signer(user.clone(), key, agent).await;
+            agent = a;
+            match result {
+                Ok(AuthResult::Success) => return (agent, Ok(Some(identity))),
+                Ok(result) => {
+                    if !result.accepts_more_publickeys() {
+                        debug!("the server doesn't accept public keys anymore");
+                        break;
+                    }
+                }
+                Err(e) => return (agent, Err(e)),
+            }
+        }
+        (agent, Ok(None))
+    }
+
+    /// Authenticates like OpenSSH does by default: with the identities
+    /// of the agent named by `SSH_AUTH_SOCK`, if it is set, and then
+    /// with the keys in `~/.ssh/id_ed25519`, `~/.ssh/id_ecdsa` and
+    /// `~/.ssh/id_rsa`, until one is accepted.
+    ///
+    /// Key files that don't exist, can't be read or need a passphrase
+    /// are skipped, as are keys already offered through the agent. Like
+    /// [`Handle::authenticate_agent`], this stops once the server no
+    /// longer accepts the "publickey" method, for instance because
+    /// there were too many attempts, and RSA keys are used with the
+    /// best hash the server announced in `server-sig-algs`.
+    pub async fn authenticate_default_identities<U: Into<String>>(
+        &mut self,
+        user: U,
+    ) -> Result<bool, crate::Error> {
+        Ok(self
+            .authenticate_default_identities_ex(user)
+            .await?
+            .is_some())
+    }
+
+    /// Like [`Handle::authenticate_default_identities`], but tells which
+    /// identity was accepted, if any.
+    pub async fn authenticate_default_identities_ex<U: Into<String>>(
+        &mut self,
+        user: U,
+    ) -> Result<Option<KeyIdentity>, crate::Error> {
+        let user = user.into();
+        let mut tried = Vec::new();
+        if std::env::var_os("SSH_AUTH_SOCK").is_some() {
+            match AgentClient::connect_env().await {
+                Ok(mut agent) => {
+                    let identities = agent
+                        .request_identities_with_comments()
+                        .await
+                        .unwrap_or_else(|e| {
+                            debug!("could not list the agent's identities: {}", e);
+                            Vec::new()
+                        });
+                    for (key, comment) in identities {
+                        debug!("trying agent key {}", key.fingerprint(key::HashAlg::Sha256));
+                        tried.push(key.clone());
+                        let identity = KeyIdentity::new(key.clone(), Some(comment), None);
+                        let (a, result) = self.authenticate_signer(user.clone(), key, agent).await;
+                        agent = a;
+                        match result {
+                            Ok(AuthResult::Success) => return Ok(Some(identity)),
+                            Ok(result) => {
+                                if !result.accepts_more_publickeys() {
+                                    return Ok(None);
+                                }
+                            }
+                            Err(auth::AgentAuthError::Send(_)) => {
+                                return Err(crate::Error::SendError)
+                            }
+                            // The agent may refuse to sign with some keys.
+                            Err(e) => debug!("agent signature failed: {}", e),
+                        }
+                    }
+                }
+                Err(e) => debug!("could not connect to the agent: {}", e),
+            }
+        }
+        for path in russh_keys::default_identity_paths()? {
+            let key = match russh_keys::load_secret_key(&path, None) {
+                Ok(key) => key,
+                Err(russh_keys::Error::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                    continue
+                }
+                Err(e) => {
+                    debug!("skipping {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let public_key = key.clone_public_key()?;
+            if tried.contains(&public_key) {
+                continue;
+            }
+            debug!("trying key {:?}", path);
+            match self
+                .authenticate_publickey_ex(user.clone(), Arc::new(key))
+                .await?
+            {
+                AuthResult::Success => {
+                    return Ok(Some(KeyIdentity::new(public_key, None, Some(path))))
+                }
+                result => {
+                    if !result.accepts_more_publickeys() {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Authenticates with `future`.
+    async fn authenticate_signer<S: auth::Signer>(
+        &mut self,
+        user: String,
+        key: key::PublicKey,
+        mut future: S,
+    ) -> (S, Result<AuthResult, S::Error>) {
         if self
             .sender
             .send(Msg::Authenticate {
@@ -396,8 +962,19 @@ impl<H: Handler> Handle<H> {
         loop {
             let reply = self.receiver.recv().await;
             match reply {
-                Some(Reply::AuthSuccess) => return (future, Ok(true)),
-                Some(Reply::AuthFailure) => return (future, Ok(false)),
+                Some(Reply::AuthSuccess) => return (future, Ok(AuthResult::Success)),
+                Some(Reply::AuthFailure {
+                    remaining_methods,
+                    partial_success,
+                }) => {
+                    return (
+                        future,
+                        Ok(AuthResult::Failure {
+                            remaining_methods,
+                            partial_success,
+                        }),
+                    )
+                }
                 Some(Reply::SignRequest { key, data }) => {
                     let (f, data) = future.auth_publickey_sign(&key, data).await;
                     future = f;
@@ -409,7 +986,7 @@ impl<H: Handler> Handle<H> {
                         return (future, Err((crate::SendError {}).into()));
                     }
                 }
-                None => return (future, Ok(false)),
+                None => return (future, Ok(AuthResult::disconnected())),
                 _ => {}
             }
         }
@@ -418,8 +995,11 @@ impl<H: Handler> Handle<H> {
     /// Wait for confirmation that a channel is open
     async fn wait_channel_confirmation(
         &self,
-        mut receiver: UnboundedReceiver<ChannelMsg>,
+        mut receiver: Receiver<ChannelMsg>,
         window_size_ref: Arc<Mutex<u32>>,
+        closed_ref: Arc<AtomicBool>,
+        session_error: SessionError,
+        label: ChannelLabel,
     ) -> Result<Channel<Msg>, crate::Error> {
         loop {
             match receiver.recv().await {
@@ -434,8 +1014,16 @@ impl<H: Handler> Handle<H> {
                         id,
                         sender: self.sender.clone(),
                         receiver,
+                        pending: VecDeque::new(),
                         max_packet_size,
                         window_size: window_size_ref,
+                        rate_limit: Default::default(),
+                        closed: closed_ref,
+                        local_eof_sent: Default::default(),
+                        remote_eof_received: false,
+                        session_error,
+                        label,
+                        timeouts: Default::default(),
                     });
                 }
                 Some(ChannelMsg::OpenFailure(reason)) => {
@@ -457,15 +1045,39 @@ impl<H: Handler> Handle<H> {
     /// usable when it's confirmed by the server, as indicated by the
     /// `confirmed` field of the corresponding `Channel`.
     pub async fn channel_open_session(&self) -> Result<Channel<Msg>, crate::Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
+        self.open_session(None).await
+    }
+
+    /// Request a session channel like [`Handle::channel_open_session`],
+    /// with a label that can be read back from [`Channel::label`] and
+    /// the channel listings. Labels let channels be matched across the
+    /// hops of a chain of connections, where their ids differ.
+    ///
+    /// With [`Config::send_channel_labels`], the label is also sent to
+    /// the server, so that a russh server sees it on its side of the
+    /// channel.
+    pub async fn channel_open_session_labeled<L: Into<String>>(
+        &self,
+        label: L,
+    ) -> Result<Channel<Msg>, crate::Error> {
+        self.open_session(Some(label.into())).await
+    }
+
+    async fn open_session(&self, label: Option<String>) -> Result<Channel<Msg>, crate::Error> {
+        let (channel_ref, receiver) = ChannelRef::new(self.channel_buffer_size);
+        if let Some(label) = label {
+            channel_ref.label().set(&label);
+        }
         let window_size_ref = channel_ref.window_size().clone();
+        let closed_ref = channel_ref.closed().clone();
+        let session_error = channel_ref.session_error().clone();
+        let label = channel_ref.label().clone();
 
         self.sender
             .send(Msg::ChannelOpenSession { channel_ref })
             .await
             .map_err(|_| crate::Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
+        self.wait_channel_confirmation(receiver, window_size_ref, closed_ref, session_error, label)
             .await
     }
 
@@ -475,9 +1087,11 @@ impl<H: Handler> Handle<H> {
         originator_address: A,
         originator_port: u32,
     ) -> Result<Channel<Msg>, crate::Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
+        let (channel_ref, receiver) = ChannelRef::new(self.channel_buffer_size);
         let window_size_ref = channel_ref.window_size().clone();
+        let closed_ref = channel_ref.closed().clone();
+        let session_error = channel_ref.session_error().clone();
+        let label = channel_ref.label().clone();
 
         self.sender
             .send(Msg::ChannelOpenX11 {
@@ -487,7 +1101,7 @@ impl<H: Handler> Handle<H> {
             })
             .await
             .map_err(|_| crate::Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
+        self.wait_channel_confirmation(receiver, window_size_ref, closed_ref, session_error, label)
             .await
     }
 
@@ -506,9 +1120,11 @@ impl<H: Handler> Handle<H> {
         originator_address: B,
         originator_port: u32,
     ) -> Result<Channel<Msg>, crate::Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
+        let (channel_ref, receiver) = ChannelRef::new(self.channel_buffer_size);
         let window_size_ref = channel_ref.window_size().clone();
+        let closed_ref = channel_ref.closed().clone();
+        let session_error = channel_ref.session_error().clone();
+        let label = channel_ref.label().clone();
 
         self.sender
             .send(Msg::ChannelOpenDirectTcpIp {
@@ -520,7 +1136,7 @@ impl<H: Handler> Handle<H> {
             })
             .await
             .map_err(|_| crate::Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
+        self.wait_channel_confirmation(receiver, window_size_ref, closed_ref, session_error, label)
             .await
     }
 
@@ -528,9 +1144,11 @@ impl<H: Handler> Handle<H> {
         &self,
         socket_path: S,
     ) -> Result<Channel<Msg>, crate::Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
+        let (channel_ref, receiver) = ChannelRef::new(self.channel_buffer_size);
         let window_size_ref = channel_ref.window_size().clone();
+        let closed_ref = channel_ref.closed().clone();
+        let session_error = channel_ref.session_error().clone();
+        let label = channel_ref.label().clone();
 
         self.sender
             .send(Msg::ChannelOpenDirectStreamLocal {
@@ -539,7 +1157,7 @@ impl<H: Handler> Handle<H> {
             })
             .await
             .map_err(|_| crate::Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
+        self.wait_channel_confirmation(receiver, window_size_ref, closed_ref, session_error, label)
             .await
     }
 
@@ -550,6 +1168,25 @@ impl<H: Handler> Handle<H> {
         &mut self,
         address: A,
         port: u32,
+    ) -> Result<u32, crate::Error> {
+        self.tcpip_forward_to(address, port, ForwardTarget::Handler)
+            .await
+    }
+
+    /// Like [`tcpip_forward`](Self::tcpip_forward), but the
+    /// connections to the forwarded port go to `target`.
+    ///
+    /// With [`ForwardTarget::Local`], each connection is connected to
+    /// the local address and piped by the session, without calling
+    /// [`Handler::server_channel_open_forwarded_tcpip`], which still
+    /// gets the connections to other forwards.
+    /// [`cancel_tcpip_forward`](Self::cancel_tcpip_forward) closes the
+    /// connections in progress, as does the end of the session.
+    pub async fn tcpip_forward_to<A: Into<String>>(
+        &mut self,
+        address: A,
+        port: u32,
+        target: ForwardTarget,
     ) -> Result<u32, crate::Error> {
         let (reply_send, reply_recv) = oneshot::channel();
         self.sender
@@ -557,6 +1194,7 @@ impl<H: Handler> Handle<H> {
                 reply_channel: Some(reply_send),
                 address: address.into(),
                 port,
+                target,
             })
             .await
             .map_err(|_| crate::Error::SendError)?;
@@ -596,6 +1234,166 @@ impl<H: Handler> Handle<H> {
         }
     }
 
+    /// Sends a keepalive request, and waits for the server's answer,
+    /// to check that the connection is still alive.
+    pub async fn keepalive(&self) -> Result<(), crate::Error> {
+        let (reply_channel, reply) = oneshot::channel();
+        self.sender
+            .send(Msg::Keepalive { reply_channel })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+        reply.await.map_err(|_| crate::Error::Disconnect)
+    }
+
+    /// Sends a PING carrying `data`, from OpenSSH's `ping@openssh.com`
+    /// extension, and returns the time until the matching PONG. Unlike
+    /// [`keepalive`](Self::keepalive), the server answers it at the
+    /// transport level, so this measures the round trip time itself.
+    ///
+    /// Fails with [`PingUnsupported`](crate::Error::PingUnsupported)
+    /// if the server didn't announce the extension, as OpenSSH 9.8 and
+    /// later do.
+    pub async fn ping(&self, data: &[u8]) -> Result<std::time::Duration, crate::Error> {
+        let (reply_channel, reply) = oneshot::channel();
+        self.sender
+            .send(Msg::Ping {
+                data: data.to_vec(),
+                reply_channel,
+            })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+        match reply.await {
+            Ok(Some(rtt)) => Ok(rtt),
+            Ok(None) => Err(crate::Error::PingUnsupported),
+            Err(_) => Err(crate::Error::Disconnect),
+        }
+    }
+
+    /// Waits until everything sent through this handle or the channels
+    /// before this call has been written to the socket, for instance
+    /// before a long pause.
+    ///
+    /// The session writes after handling the messages it has received:
+    /// when several are queued, they are taken together and written in
+    /// one batch, and this returns once the batch holding the messages
+    /// sent before it has been written. It also waits for a key
+    /// re-exchange in progress. Data waiting for the peer's window to
+    /// open isn't written yet, and isn't waited for.
+    pub async fn flush(&self) -> Result<(), crate::Error> {
+        let (reply_channel, reply) = oneshot::channel();
+        self.sender
+            .send(Msg::Flush { reply_channel })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+        reply.await.map_err(|_| crate::Error::Disconnect)
+    }
+
+    /// Sends a global request named `name`, with `payload` written
+    /// verbatim after the `want reply` field. See
+    /// [`Session::send_global_request`].
+    ///
+    /// If `want_reply` is true, waits for the server's answer and
+    /// returns the data of its success reply, or
+    /// [`RequestDenied`](crate::Error::RequestDenied) if it refused the
+    /// request. Otherwise, returns `None` once the request is queued.
+    pub async fn send_global_request<N: Into<String>>(
+        &self,
+        name: N,
+        want_reply: bool,
+        payload: &[u8],
+    ) -> Result<Option<Vec<u8>>, crate::Error> {
+        let (reply_channel, reply) = if want_reply {
+            let (reply_channel, reply) = oneshot::channel();
+            (Some(reply_channel), Some(reply))
+        } else {
+            (None, None)
+        };
+        self.sender
+            .send(Msg::GlobalRequest {
+                name: name.into(),
+                payload: payload.to_vec(),
+                reply_channel,
+            })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+        let Some(reply) = reply else {
+            return Ok(None);
+        };
+        match reply.await {
+            Ok(Some(data)) => Ok(Some(data)),
+            Ok(None) => Err(crate::Error::RequestDenied),
+            Err(_) => Err(crate::Error::Disconnect),
+        }
+    }
+
+    /// Tells the server that this connection won't open more session
+    /// channels, with OpenSSH's `no-more-sessions@openssh.com` request.
+    /// russh and OpenSSH servers then refuse them, which keeps a
+    /// connection used for forwarding from being reused to run
+    /// commands. Other servers may ignore the request.
+    pub async fn no_more_sessions(&self) -> Result<(), crate::Error> {
+        self.send_global_request("no-more-sessions@openssh.com", false, &[])
+            .await
+            .map(|_| ())
+    }
+
+    /// Lists the channels currently open on this session, ordered by
+    /// id. The list is empty once the session is closed.
+    pub async fn list_channels(&self) -> Vec<ChannelInfo> {
+        let (reply_channel, reply) = oneshot::channel();
+        if self
+            .sender
+            .send(Msg::ListChannels { reply_channel })
+            .await
+            .is_err()
+        {
+            return Vec::new();
+        }
+        reply.await.unwrap_or_default()
+    }
+
+    /// Closes channel `id` without waiting for its pending data, for
+    /// instance to stop a misbehaving tunnel without ending the session.
+    /// SSH has no room for `reason`, which is only logged.
+    ///
+    /// Returns `true` once the server has closed the channel too, or
+    /// `false` if it hasn't done so within ten seconds.
+    pub async fn force_close(&self, id: ChannelId, reason: &str) -> Result<bool, crate::Error> {
+        let (reply_channel, reply) = oneshot::channel();
+        self.sender
+            .send(Msg::ForceClose {
+                id,
+                reason: reason.into(),
+                reply_channel,
+            })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+        match crate::runtime::timeout(crate::session::FORCE_CLOSE_TIMEOUT, reply).await {
+            Ok(Ok(true)) => Ok(true),
+            Ok(Ok(false)) => Err(crate::Error::WrongChannel),
+            Ok(Err(_)) | Err(_) => Ok(false),
+        }
+    }
+
+    /// Sends `payload`, a message type followed by its contents, as a
+    /// single packet. The packet is queued behind the ones already
+    /// waiting, and held back during key re-exchanges like any other.
+    ///
+    /// Nothing else is checked: the session doesn't know what was
+    /// sent, and will not expect any answer. Empty payloads and key
+    /// exchange messages are refused with
+    /// [`Error::RawPacketRejected`](crate::Error::RawPacketRejected).
+    #[cfg(feature = "danger-raw-packets")]
+    pub async fn send_raw_packet(&self, payload: &[u8]) -> Result<(), crate::Error> {
+        crate::raw::check_payload(payload)?;
+        self.sender
+            .send(Msg::RawPacket {
+                payload: payload.to_vec(),
+            })
+            .await
+            .map_err(|_| crate::Error::SendError)
+    }
+
     /// Sends a disconnect message.
     pub async fn disconnect(
         &self,
@@ -627,6 +1425,24 @@ impl<H: Handler> Handle<H> {
                 _ => unreachable!(),
             })
     }
+
+    /// Reply to a request the server sent on a channel with
+    /// `want_reply` set, accepting it.
+    pub async fn channel_success(&self, id: ChannelId) -> Result<(), crate::Error> {
+        self.sender
+            .send(Msg::Channel(id, ChannelMsg::Success))
+            .await
+            .map_err(|_| crate::Error::SendError)
+    }
+
+    /// Reply to a request the server sent on a channel with
+    /// `want_reply` set, refusing it.
+    pub async fn channel_failure(&self, id: ChannelId) -> Result<(), crate::Error> {
+        self.sender
+            .send(Msg::Channel(id, ChannelMsg::Failure))
+            .await
+            .map_err(|_| crate::Error::SendError)
+    }
 }
 
 impl<H: Handler> Future for Handle<H> {
@@ -635,7 +1451,7 @@ impl<H: Handler> Future for Handle<H> {
         match Future::poll(Pin::new(&mut self.join), cx) {
             Poll::Ready(r) => Poll::Ready(match r {
                 Ok(Ok(x)) => Ok(x),
-                Err(e) => Err(crate::Error::from(e).into()),
+                Err(e) => Err(e.into()),
                 Ok(Err(e)) => Err(e),
             }),
             Poll::Pending => Poll::Pending,
@@ -655,10 +1471,12 @@ pub async fn connect<H: Handler + Send + 'static, A: ToSocketAddrs>(
     addrs: A,
     handler: H,
 ) -> Result<Handle<H>, H::Error> {
+    let mut timings = HandshakeTimings::new(crate::runtime::now());
     let socket = TcpStream::connect(addrs)
         .await
         .map_err(crate::Error::from)?;
-    connect_stream(config, socket, handler).await
+    timings.record(Step::TcpConnected);
+    connect_stream_timed(config, socket, handler, timings).await
 }
 
 /// Connect a stream to a server. This stream must implement
@@ -666,14 +1484,33 @@ pub async fn connect<H: Handler + Send + 'static, A: ToSocketAddrs>(
 /// and [`Send`]. Typically, you may prefer to use [`connect`], which uses a
 /// [`tokio::net::TcpStream`] and then calls this function under the hood.
 pub async fn connect_stream<H, R>(
+    config: Arc<Config>,
+    stream: R,
+    handler: H,
+) -> Result<Handle<H>, H::Error>
+where
+    H: Handler + Send + 'static,
+    R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut timings = HandshakeTimings::new(crate::runtime::now());
+    timings.record(Step::TcpConnected);
+    connect_stream_timed(config, stream, handler, timings).await
+}
+
+/// [`connect_stream`], with the timings of the connection so far.
+pub(crate) async fn connect_stream_timed<H, R>(
     config: Arc<Config>,
     mut stream: R,
     handler: H,
+    mut timings: HandshakeTimings,
 ) -> Result<Handle<H>, H::Error>
 where
     H: Handler + Send + 'static,
     R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
+    if config.disable_rekey {
+        warn!("key re-exchange is disabled (disable_rekey), keys will not be renewed");
+    }
     // Writing SSH id.
     let mut write_buffer = SSHBuffer::new();
     write_buffer.send_ssh_id(&config.as_ref().client_id);
@@ -681,12 +1518,26 @@ where
         .write_all(&write_buffer.buffer)
         .await
         .map_err(crate::Error::from)?;
+    stream.flush().await.map_err(crate::Error::from)?;
 
     // Reading SSH id and allocating a session if correct.
     let mut stream = SshRead::new(stream);
-    let sshid = stream.read_ssh_id().await?;
+    let mut sshids = vec![stream.read_ssh_id().await?.to_vec()];
+    if config.use_last_server_id {
+        while stream.next_is_ssh_id().await? {
+            sshids.push(stream.read_ssh_id().await?.to_vec());
+        }
+    }
+    #[allow(clippy::indexing_slicing)] // length checked
+    let sshid = &sshids[sshids.len() - 1][..];
+    timings.record(Step::VersionExchanged);
+    let timings = Arc::new(std::sync::Mutex::new(timings));
     let (handle_sender, session_receiver) = channel(10);
     let (session_sender, handle_receiver) = unbounded_channel();
+    let (event_sender, event_receiver) = unbounded_channel();
+    let shared_event_sender = Arc::new(std::sync::Mutex::new(Some(event_sender)));
+    let channel_buffer_size = config.channel_buffer_size;
+    let spawner = config.spawner.clone();
     if config.maximum_packet_size > 65535 {
         error!(
             "Maximum packet size ({:?}) should not larger than a TCP packet (65535)",
@@ -706,41 +1557,82 @@ where
                 remote_to_local: Box::new(clear::Key),
             },
             encrypted: None,
+            limits: config.limits.clone(),
+            rate_limit: config.rate_limit.map(TokenBucket::new),
+            error_disconnect: Default::default(),
+            flush_waiters: Vec::new(),
+            established_at: None,
             config,
             wants_reply: false,
             disconnected: false,
             buffer: CryptoVec::new(),
             strict_kex: false,
+            skipped_kex_packets: 0,
             alive_timeouts: 0,
             received_data: false,
             remote_sshid: sshid.into(),
         },
         session_receiver,
         session_sender,
+        shared_event_sender.clone(),
+        timings.clone(),
     );
+    session.send_event(ClientEvent::Handshake(
+        *timings.lock().unwrap_or_else(|e| e.into_inner()),
+    ));
+    if sshids.len() > 1 {
+        warn!(
+            "the server sent several identification strings, using the last one: {:?}",
+            sshids
+                .iter()
+                .map(|id| String::from_utf8_lossy(id))
+                .collect::<Vec<_>>()
+        );
+        session.send_event(ClientEvent::MultipleServerIds(sshids.clone()));
+    }
     session.read_ssh_id(sshid)?;
+    session.remote_sshids = sshids;
     let (encrypted_signal, encrypted_recv) = tokio::sync::oneshot::channel();
-    let join = tokio::spawn(session.run(stream, handler, Some(encrypted_signal)));
+    let join = crate::runtime::spawn_task(
+        spawner.as_ref(),
+        session.run(stream, handler, Some(encrypted_signal)),
+    );
 
     if encrypted_recv.await.is_err() {
-        join.await.map_err(crate::Error::Join)??;
+        join.await??;
         return Err(H::Error::from(crate::Error::Disconnect));
     }
 
+    // Stop queuing events until the handle's stream is taken.
+    let event_sender = shared_event_sender
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
     Ok(Handle {
         sender: handle_sender,
         receiver: handle_receiver,
+        events: event_sender.map(|sender| (event_receiver, sender, shared_event_sender)),
         join,
+        channel_buffer_size,
+        timings,
     })
 }
 
 async fn start_reading<R: AsyncRead + Unpin>(
-    mut stream_read: R,
+    mut stream_read: PacketReader<R>,
     mut buffer: SSHBuffer,
     mut cipher: Box<dyn OpeningKey + Send>,
-) -> Result<(usize, R, SSHBuffer, Box<dyn OpeningKey + Send>), crate::Error> {
+) -> Result<
+    (
+        usize,
+        PacketReader<R>,
+        SSHBuffer,
+        Box<dyn OpeningKey + Send>,
+    ),
+    crate::Error,
+> {
     buffer.buffer.clear();
-    let n = cipher::read(&mut stream_read, &mut buffer, &mut *cipher).await?;
+    let n = stream_read.read(&mut buffer, &mut cipher).await?;
     Ok((n, stream_read, buffer, cipher))
 }
 
@@ -750,9 +1642,12 @@ impl Session {
         common: CommonSession<Arc<Config>>,
         receiver: Receiver<Msg>,
         sender: UnboundedSender<Reply>,
+        event_sender: SharedEventSender,
+        timings: SharedTimings,
     ) -> Self {
         let (inbound_channel_sender, inbound_channel_receiver) = channel(10);
         Self {
+            legacy_preferred: None,
             common,
             receiver,
             sender,
@@ -760,13 +1655,55 @@ impl Session {
             inbound_channel_sender,
             inbound_channel_receiver,
             channels: HashMap::new(),
-            pending_reads: Vec::new(),
-            pending_len: 0,
-            open_global_requests: VecDeque::new(),
+            global_requests: Default::default(),
+            local_forwards: HashMap::new(),
+            event_sender,
+            timings,
+            extensions: Extensions::new(),
+            server_sig_algs: None,
+            server_ping: false,
+            remote_sshids: Vec::new(),
+            pings: VecDeque::new(),
+        }
+    }
+
+    /// Forwards an event to [`Handle::events`], if anyone is listening.
+    pub(crate) fn send_event(&self, event: ClientEvent) {
+        let _ = self.try_send_event(event);
+    }
+
+    /// Forwards an event to [`Handle::events`], or gives it back if
+    /// nobody is listening.
+    pub(crate) fn try_send_event(&self, event: ClientEvent) -> Result<(), ClientEvent> {
+        let mut sender = self.event_sender.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(ref events) = *sender else {
+            return Err(event);
+        };
+        if let Err(e) = events.send(event) {
+            // The stream was dropped.
+            *sender = None;
+            return Err(e.0);
+        }
+        Ok(())
+    }
+
+    /// Records that the handshake reached `step`, the first time only.
+    pub(crate) fn record_timing(&self, step: Step) {
+        let mut timings = self.timings.lock().unwrap_or_else(|e| e.into_inner());
+        if timings.record(step) {
+            self.send_event(ClientEvent::Handshake(*timings));
         }
     }
 
-    async fn run<H: Handler + Send, R: AsyncRead + AsyncWrite + Unpin + Send>(
+    /// Records that the handler callback `callback` ran for longer
+    /// than [`Config::handler_timeout`]. The client reports it with a
+    /// [`ClientEvent::HandlerTimeout`] rather than to the handler.
+    pub(crate) fn handler_timed_out<H>(&self, _: &mut H, callback: &'static str) {
+        error!("handler callback {} timed out", callback);
+        self.send_event(ClientEvent::HandlerTimeout(callback));
+    }
+
+    async fn run<H: Handler + Send, R: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
         mut self,
         stream: SshRead<R>,
         mut handler: H,
@@ -782,9 +1719,16 @@ impl Session {
             )
             .await;
         trace!("disconnected");
+        if let Err(ref e) = result {
+            fail_channels(&self.channels, &format!("{:?}", e));
+        }
         self.receiver.close();
         self.inbound_channel_receiver.close();
+        if result.is_err() {
+            self.write_error_disconnect(&mut stream_write).await;
+        }
         stream_write.shutdown().await.map_err(crate::Error::from)?;
+        self.send_event(ClientEvent::Disconnected(result.as_ref().ok().cloned()));
         match result {
             Ok(v) => {
                 handler
@@ -800,7 +1744,19 @@ impl Session {
         }
     }
 
-    async fn run_inner<H: Handler + Send, R: AsyncRead + AsyncWrite + Unpin + Send>(
+    /// Sends the DISCONNECT matching the protocol error that ended
+    /// the session, if any.
+    async fn write_error_disconnect<W: AsyncWrite + Unpin>(&mut self, stream_write: &mut W) {
+        if self.common.disconnect_after_error() && self.flush().is_ok() {
+            let _ = stream_write
+                .write_all(&self.common.write_buffer.buffer)
+                .await;
+            let _ = stream_write.flush().await;
+            self.common.write_buffer.buffer.clear();
+        }
+    }
+
+    async fn run_inner<H: Handler + Send, R: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
         &mut self,
         stream_read: SshRead<ReadHalf<R>>,
         stream_write: &mut WriteHalf<R>,
@@ -828,13 +1784,20 @@ impl Session {
         std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
 
         let keepalive_timer =
-            crate::future_or_pending(self.common.config.keepalive_interval, tokio::time::sleep);
+            crate::future_or_pending(self.common.config.keepalive_interval, crate::runtime::sleep);
         pin!(keepalive_timer);
 
         let inactivity_timer =
-            crate::future_or_pending(self.common.config.inactivity_timeout, tokio::time::sleep);
+            crate::future_or_pending(self.common.config.inactivity_timeout, crate::runtime::sleep);
         pin!(inactivity_timer);
 
+        let stream_read = PacketReader::new(
+            stream_read,
+            self.common.config.maximum_inbound_packet_size,
+            self.common.config.read_pipeline_depth,
+            self.common.config.spawner.as_ref(),
+        );
+        let read_barrier = stream_read.barrier();
         let reading = start_reading(stream_read, buffer, opening_cipher);
         pin!(reading);
 
@@ -843,10 +1806,10 @@ impl Session {
             self.common.received_data = false;
             let mut sent_keepalive = false;
             tokio::select! {
-                r = &mut reading => {
+                r = &mut reading, if !channels_full(&self.channels) => {
                     let (stream_read, mut buffer, mut opening_cipher) = match r {
                         Ok((_, stream_read, buffer, opening_cipher)) => (stream_read, buffer, opening_cipher),
-                        Err(e) => return Err(e.into())
+                        Err(e) => return Err(self.common.error_disconnect.record(e).into())
                     };
 
                     std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
@@ -895,9 +1858,14 @@ impl Session {
                     debug!("timeout");
                     return Err(crate::Error::InactivityTimeout.into());
                 }
-                msg = self.receiver.recv(), if !self.is_rekeying() => {
+                () = wait_channel_capacity(&self.channels) => {
+                    flush_channels(&mut self.channels);
+                }
+                // The channel data held back is flushed below.
+                () = std::future::ready(()), if self.common.has_flushable_data() => {}
+                msg = self.receiver.recv(), if self.can_handle_messages() => {
                     match msg {
-                        Some(msg) => self.handle_msg(msg)?,
+                        Some(msg) => self.handle_msg(handler, msg)?,
                         None => {
                             self.common.disconnected = true;
                             break
@@ -905,23 +1873,23 @@ impl Session {
                     };
 
                     // eagerly take all outgoing messages so writes are batched
-                    while !self.is_rekeying() {
+                    while self.can_handle_messages() {
                         match self.receiver.try_recv() {
-                            Ok(next) => self.handle_msg(next)?,
+                            Ok(next) => self.handle_msg(handler, next)?,
                             Err(_) => break
                         }
                     }
                 }
-                msg = self.inbound_channel_receiver.recv(), if !self.is_rekeying() => {
+                msg = self.inbound_channel_receiver.recv(), if self.can_handle_messages() => {
                     match msg {
-                        Some(msg) => self.handle_msg(msg)?,
+                        Some(msg) => self.handle_msg(handler, msg)?,
                         None => (),
                     }
 
                     // eagerly take all outgoing messages so writes are batched
-                    while !self.is_rekeying() {
+                    while self.can_handle_messages() {
                         match self.inbound_channel_receiver.try_recv() {
-                            Ok(next) => self.handle_msg(next)?,
+                            Ok(next) => self.handle_msg(handler, next)?,
                             Err(_) => break
                         }
                     }
@@ -929,11 +1897,16 @@ impl Session {
             };
 
             self.flush()?;
+            read_barrier.set(self.is_rekeying());
             if !self.common.write_buffer.buffer.is_empty() {
                 trace!(
                     "writing to stream: {:?} bytes",
                     self.common.write_buffer.buffer.len()
                 );
+                if let Some(ref mut rate_limit) = self.common.rate_limit {
+                    rate_limit.wait().await;
+                    rate_limit.consume(self.common.write_buffer.buffer.len());
+                }
                 stream_write
                     .write_all(&self.common.write_buffer.buffer)
                     .await
@@ -941,6 +1914,8 @@ impl Session {
                 stream_write.flush().await.map_err(crate::Error::from)?;
             }
             self.common.write_buffer.buffer.clear();
+            self.common.flushed();
+            self.check_write_buffer()?;
             if let Some(ref mut enc) = self.common.encrypted {
                 if let EncryptedState::InitCompression = enc.state {
                     enc.client_compression.init_compress(&mut enc.compress);
@@ -961,7 +1936,7 @@ impl Session {
                     keepalive_timer.as_mut().as_pin_mut(),
                     self.common.config.keepalive_interval,
                 ) {
-                    sleep.as_mut().reset(tokio::time::Instant::now() + d);
+                    sleep.reset(d);
                 }
             }
             if !sent_keepalive {
@@ -969,7 +1944,7 @@ impl Session {
                     inactivity_timer.as_mut().as_pin_mut(),
                     self.common.config.inactivity_timeout,
                 ) {
-                    sleep.as_mut().reset(tokio::time::Instant::now() + d);
+                    sleep.reset(d);
                 }
             }
         }
@@ -999,15 +1974,22 @@ impl Session {
         })
     }
 
-    fn handle_msg(&mut self, msg: Msg) -> Result<(), crate::Error> {
+    fn handle_msg<H: Handler>(&mut self, handler: &mut H, msg: Msg) -> Result<(), crate::Error> {
         match msg {
             Msg::Authenticate { user, method } => {
+                self.record_timing(Step::AuthStart);
                 self.write_auth_request_if_needed(&user, method);
             }
             Msg::Signed { .. } => {}
             Msg::AuthInfoResponse { .. } => {}
             Msg::ChannelOpenSession { channel_ref } => {
                 let id = self.channel_open_session()?;
+                if let Some(label) = channel_ref.label().get() {
+                    let announce = self.common.config.send_channel_labels;
+                    if let Some(ref mut enc) = self.common.encrypted {
+                        enc.label_channel(id, &label, announce);
+                    }
+                }
                 self.channels.insert(id, channel_ref);
             }
             Msg::ChannelOpenX11 {
@@ -1044,22 +2026,48 @@ impl Session {
                 reply_channel,
                 address,
                 port,
-            } => self.tcpip_forward(reply_channel, &address, port),
+                target,
+            } => self.tcpip_forward_to(reply_channel, address, port, target),
             Msg::CancelTcpIpForward {
                 reply_channel,
                 address,
                 port,
             } => self.cancel_tcpip_forward(reply_channel, &address, port),
+            Msg::Keepalive { reply_channel } => self.keepalive_with_reply(reply_channel),
+            Msg::Ping {
+                data,
+                reply_channel,
+            } => self.ping(data, reply_channel),
+            Msg::GlobalRequest {
+                name,
+                payload,
+                reply_channel,
+            } => self.global_request(&name, &payload, reply_channel),
+            Msg::ListChannels { reply_channel } => {
+                let _ = reply_channel.send(self.channels_info());
+            }
+            Msg::ForceClose {
+                id,
+                reason,
+                reply_channel,
+            } => self.force_close(id, &reason, reply_channel),
             Msg::Disconnect {
                 reason,
                 description,
                 language_tag,
             } => self.disconnect(reason, &description, &language_tag),
-            Msg::Channel(id, ChannelMsg::Data { data }) => self.data(id, data),
+            #[cfg(feature = "danger-raw-packets")]
+            Msg::RawPacket { payload } => self.common.send_raw_packet(&payload),
+            Msg::Channel(id, ChannelMsg::Data { data }) => {
+                handler.on_channel_data(Direction::Outbound, id, &data);
+                self.data(id, data)
+            }
             Msg::Channel(id, ChannelMsg::Eof) => {
                 self.eof(id);
             }
+            Msg::Channel(id, ChannelMsg::EndOfWrite) => self.end_of_write(id),
             Msg::Channel(id, ChannelMsg::ExtendedData { data, ext }) => {
+                handler.on_channel_extended_data(Direction::Outbound, id, ext, &data);
                 self.extended_data(id, ext, data);
             }
             Msg::Channel(
@@ -1134,7 +2142,20 @@ impl Session {
             Msg::Channel(id, ChannelMsg::AgentForward { want_reply }) => {
                 self.agent_forward(id, want_reply)
             }
+            Msg::Channel(
+                id,
+                ChannelMsg::Request {
+                    request_type,
+                    want_reply,
+                    payload,
+                },
+            ) => self.channel_request(id, &request_type, want_reply, &payload),
             Msg::Channel(id, ChannelMsg::Close) => self.close(id),
+            Msg::Channel(id, ChannelMsg::Success) => self.channel_success(id),
+            Msg::Channel(id, ChannelMsg::Failure) => self.channel_failure(id),
+            Msg::Channel(_, ChannelMsg::Flush { done }) => self.common.flush_waiters.push(done),
+            Msg::Flush { reply_channel } => self.common.flush_waiters.push(reply_channel),
+            Msg::ReadExtensions(read) => read.run(&self.extensions),
             msg => {
                 // should be unreachable, since the receiver only gets
                 // messages from methods implemented within russh
@@ -1152,7 +2173,37 @@ impl Session {
         }
     }
 
+    /// Whether messages from the handle and the channels can be handled
+    /// now. During a key re-exchange, what they send is held back until
+    /// the new keys are in use, within the write buffer's limits.
+    fn can_handle_messages(&self) -> bool {
+        self.common.encrypted.is_some() && !self.is_write_buffer_full()
+    }
+
+    /// Whether new outgoing messages should wait until the pending
+    /// data has been sent.
+    fn is_write_buffer_full(&self) -> bool {
+        self.common.config.write_buffer_policy == WriteBufferPolicy::Backpressure
+            && self.common.pending_write_len() > self.common.config.write_buffer_high_water_mark
+    }
+
+    fn check_write_buffer(&self) -> Result<(), crate::Error> {
+        if self.common.config.write_buffer_policy == WriteBufferPolicy::Disconnect
+            && self.common.pending_write_len() > self.common.config.write_buffer_high_water_mark
+        {
+            debug!(
+                "write buffer overflow: {} bytes pending",
+                self.common.pending_write_len()
+            );
+            return Err(crate::Error::WriteBufferOverflow);
+        }
+        Ok(())
+    }
+
     fn read_ssh_id(&mut self, sshid: &[u8]) -> Result<(), crate::Error> {
+        if self.common.config.auto_legacy_compat && negotiation::is_legacy_peer(sshid) {
+            self.enable_legacy_compat(sshid);
+        }
         // self.read_buffer.bytes += sshid.bytes_read + 2;
         let mut exchange = Exchange::new();
         exchange.server_id.extend(sshid);
@@ -1165,10 +2216,14 @@ impl Session {
             algo: None,
             sent: false,
             session_id: None,
+            guess: None,
         };
         self.common.write_buffer.buffer.clear();
         kexinit.client_write(
             self.common.config.as_ref(),
+            self.legacy_preferred
+                .as_ref()
+                .unwrap_or(&self.common.config.preferred),
             &mut *self.common.cipher.local_to_remote,
             &mut self.common.write_buffer,
         )?;
@@ -1176,21 +2231,44 @@ impl Session {
         Ok(())
     }
 
+    /// Adds the legacy algorithms to the ones offered to the server
+    /// identified by `sshid`.
+    fn enable_legacy_compat(&mut self, sshid: &[u8]) {
+        let sshid = String::from_utf8_lossy(sshid);
+        #[cfg(feature = "legacy-algorithms")]
+        {
+            warn!(
+                "server {:?} is outdated, enabling weak legacy algorithms (auto_legacy_compat)",
+                sshid
+            );
+            self.legacy_preferred = Some(self.common.config.preferred.with_legacy());
+        }
+        #[cfg(not(feature = "legacy-algorithms"))]
+        warn!(
+            "server {:?} is outdated, but auto_legacy_compat needs the legacy-algorithms feature",
+            sshid
+        );
+    }
+
     /// Flush the temporary cleartext buffer into the encryption
     /// buffer. This does *not* flush to the socket.
     fn flush(&mut self) -> Result<(), crate::Error> {
         if let Some(ref mut enc) = self.common.encrypted {
             if enc.flush(
-                &self.common.config.as_ref().limits,
+                &self.common.limits,
                 &mut *self.common.cipher.local_to_remote,
                 &mut self.common.write_buffer,
-            )? {
+            )? && !self.common.config.disable_rekey
+            {
                 info!("Re-exchanging keys");
                 if enc.rekey.is_none() {
                     if let Some(exchange) = enc.exchange.take() {
                         let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id);
                         kexinit.client_write(
                             self.common.config.as_ref(),
+                            self.legacy_preferred
+                                .as_ref()
+                                .unwrap_or(&self.common.config.preferred),
                             &mut *self.common.cipher.local_to_remote,
                             &mut self.common.write_buffer,
                         )?;
@@ -1203,8 +2281,8 @@ impl Session {
     }
 
     /// Send a `ChannelMsg` from the background handler to the client.
-    pub fn send_channel_msg(&self, channel: ChannelId, msg: ChannelMsg) -> bool {
-        if let Some(chan) = self.channels.get(&channel) {
+    pub fn send_channel_msg(&mut self, channel: ChannelId, msg: ChannelMsg) -> bool {
+        if let Some(chan) = self.channels.get_mut(&channel) {
             chan.send(msg).unwrap_or(());
             true
         } else {
@@ -1222,20 +2300,27 @@ impl KexDhDone {
         mut self,
         rekey: bool,
         handler: &mut H,
+        config: &Config,
         buf: &[u8],
+        error_disconnect: &mut ErrorDisconnect,
     ) -> Result<NewKeys, H::Error> {
         let mut reader = buf.reader(1);
-        let pubkey = reader.read_string().map_err(crate::Error::from)?; // server public key.
+        let pubkey_blob = reader.read_string().map_err(crate::Error::from)?; // server public key.
         let pubkey = parse_public_key(
-            pubkey,
+            pubkey_blob,
             SignatureHash::from_rsa_hostkey_algo(self.names.key.0.as_bytes()),
         )
         .map_err(crate::Error::from)?;
         debug!("server_public_Key: {:?}", pubkey);
+        error_disconnect.check(crate::key::check_key_bits(
+            &pubkey,
+            &config.minimum_key_bits,
+            &config.maximum_key_bits,
+        ))?;
         if !rekey {
-            let check = handler.check_server_key(&pubkey).await?;
+            let check = handler.check_server_key_blob(pubkey_blob, &pubkey).await?;
             if !check {
-                return Err(crate::Error::UnknownKey.into());
+                return Err(error_disconnect.record(crate::Error::UnknownKey).into());
             }
         }
         HASH_BUFFER.with(|buffer| {
@@ -1268,7 +2353,7 @@ impl KexDhDone {
                 debug!("signature: {:?}", signature);
                 if !pubkey.verify_server_auth(hash.as_ref(), signature) {
                     debug!("wrong server sig");
-                    return Err(crate::Error::WrongServerSig.into());
+                    return Err(error_disconnect.record(crate::Error::WrongServerSig).into());
                 }
                 hash
             };
@@ -1289,14 +2374,33 @@ async fn reply<H: Handler>(
     if let Some(message_type) = buf.first() {
         if session.common.strict_kex && session.common.encrypted.is_none() {
             let seqno = seqn.0 - 1; // was incremented after read()
-            if let Some(expected) = STRICT_KEX_MSG_ORDER.get(seqno as usize) {
+            let index = seqno - session.common.skipped_kex_packets;
+            if let Some(expected) = STRICT_KEX_MSG_ORDER.get(index as usize) {
                 if message_type != expected {
-                    return Err(strict_kex_violation(*message_type, seqno as usize).into());
+                    let e = strict_kex_violation(*message_type, seqno as usize);
+                    return Err(session.common.error_disconnect.record(e).into());
                 }
             }
         }
 
-        if [msg::IGNORE, msg::UNIMPLEMENTED, msg::DEBUG].contains(message_type) {
+        if *message_type == msg::UNIMPLEMENTED {
+            if let Ok(seqn) = buf.reader(1).read_u32() {
+                session.global_requests.peer_unimplemented(
+                    &mut session.common.encrypted,
+                    session.common.config.max_pending_global_requests,
+                    seqn,
+                );
+            }
+            return Ok(());
+        }
+        if [msg::IGNORE, msg::DEBUG].contains(message_type) {
+            return Ok(());
+        }
+        if *message_type == msg::PING {
+            return Ok(session.common.pong(buf)?);
+        }
+        if *message_type == msg::PONG {
+            session.pong(buf);
             return Ok(());
         }
     }
@@ -1307,16 +2411,23 @@ async fn reply<H: Handler>(
                 || buf.first() == Some(&msg::KEXINIT)
                 || session.common.encrypted.is_none()
             {
-                let done = kexinit.client_parse(
-                    session.common.config.as_ref(),
-                    &mut *session.common.cipher.local_to_remote,
-                    buf,
-                    &mut session.common.write_buffer,
+                let done = session.common.error_disconnect.check(
+                    kexinit.client_parse(
+                        session.common.config.as_ref(),
+                        session
+                            .legacy_preferred
+                            .as_ref()
+                            .unwrap_or(&session.common.config.preferred),
+                        &mut *session.common.cipher.local_to_remote,
+                        buf,
+                        &mut session.common.write_buffer,
+                    ),
                 )?;
 
                 // seqno has already been incremented after read()
                 if done.names.strict_kex && seqn.0 != 1 {
-                    return Err(strict_kex_violation(msg::KEXINIT, seqn.0 as usize - 1).into());
+                    let e = strict_kex_violation(msg::KEXINIT, seqn.0 as usize - 1);
+                    return Err(session.common.error_disconnect.record(e).into());
                 }
 
                 if done.kex.skip_exchange() {
@@ -1327,6 +2438,7 @@ async fn reply<H: Handler>(
 
                     if let Some(sender) = sender.take() {
                         sender.send(()).unwrap_or(());
+                        session.record_timing(Step::FirstKexComplete);
                     }
                 } else {
                     session.common.kex = Some(Kex::DhDone(done));
@@ -1338,11 +2450,20 @@ async fn reply<H: Handler>(
         Some(Kex::DhDone(mut kexdhdone)) => {
             if kexdhdone.names.ignore_guessed {
                 kexdhdone.names.ignore_guessed = false;
+                session.common.skipped_kex_packets += 1;
                 session.common.kex = Some(Kex::DhDone(kexdhdone));
                 Ok(())
             } else if buf.first() == Some(&msg::KEX_ECDH_REPLY) {
                 // We've sent ECDH_INIT, waiting for ECDH_REPLY
-                let kex = kexdhdone.server_key_check(false, handler, buf).await?;
+                let kex = kexdhdone
+                    .server_key_check(
+                        false,
+                        handler,
+                        &session.common.config,
+                        buf,
+                        &mut session.common.error_disconnect,
+                    )
+                    .await?;
                 session.common.strict_kex = session.common.strict_kex || kex.names.strict_kex;
                 session.common.kex = Some(Kex::Keys(kex));
                 session
@@ -1355,16 +2476,22 @@ async fn reply<H: Handler>(
                 Ok(())
             } else {
                 error!("Wrong packet received");
-                Err(crate::Error::Inconsistent.into())
+                let e = crate::Error::Inconsistent;
+                Err(session.common.error_disconnect.record(e).into())
             }
         }
         Some(Kex::Keys(newkeys)) => {
             debug!("newkeys received");
             if buf.first() != Some(&msg::NEWKEYS) {
-                return Err(crate::Error::Kex.into());
+                return Err(session
+                    .common
+                    .error_disconnect
+                    .record(crate::Error::Kex)
+                    .into());
             }
             if let Some(sender) = sender.take() {
                 sender.send(()).unwrap_or(());
+                session.record_timing(Step::FirstKexComplete);
             }
             session
                 .common
@@ -1395,17 +2522,28 @@ fn initial_encrypted_state(session: &Session) -> EncryptedState {
 }
 
 /// The configuration of clients.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     /// The client ID string sent at the beginning of the protocol.
     pub client_id: SshId,
     /// The bytes and time limits before key re-exchange.
     pub limits: Limits,
+    /// Whether to never start a key re-exchange, whatever `limits`
+    /// say, for servers that fail when asked to rekey, such as some
+    /// embedded devices. Key exchanges started by the server are still
+    /// answered.
+    ///
+    /// **This weakens long sessions**: the same keys keep encrypting
+    /// past the limits of RFC 4253, section 9. A warning is logged when
+    /// connecting with this set.
+    pub disable_rekey: bool,
     /// The initial size of a channel (used for flow control).
     pub window_size: u32,
     /// The maximal size of a single packet.
     pub maximum_packet_size: u32,
-    /// Lists of preferred algorithms.
+    /// Lists of preferred algorithms. See [`negotiation::Preferred::MODERN`],
+    /// [`negotiation::Preferred::COMPATIBLE`] and [`negotiation::Preferred::DEFAULT`]
+    /// for presets.
     pub preferred: negotiation::Preferred,
     /// Time after which the connection is garbage-collected.
     pub inactivity_timeout: Option<std::time::Duration>,
@@ -1413,8 +2551,105 @@ pub struct Config {
     pub keepalive_interval: Option<std::time::Duration>,
     /// If this many keepalives have been sent without reply, close the connection.
     pub keepalive_max: usize,
+    /// How many global requests (forwardings, keepalives...) may wait
+    /// for the server's reply at once. Later ones are sent as the replies
+    /// come in, in order. The default doesn't limit them.
+    pub max_pending_global_requests: usize,
     /// Whether to expect and wait for an authentication call.
     pub anonymous: bool,
+    /// Maximal number of messages queued for each [`Channel`] that
+    /// has not read them yet. While a queue is full, the session stops
+    /// reading from the socket, so that a slow consumer slows down the
+    /// server instead of using more memory. With `None`, messages are
+    /// queued without limit.
+    ///
+    /// Every [`Channel`] must then be read (or dropped), otherwise the
+    /// session eventually stalls. A full window of data takes
+    /// `window_size / maximum_packet_size` messages.
+    pub channel_buffer_size: Option<usize>,
+    /// Maximal number of bytes waiting to be sent to the server, including
+    /// channel data held back by flow control, before
+    /// `write_buffer_policy` applies.
+    pub write_buffer_high_water_mark: usize,
+    /// What to do when `write_buffer_high_water_mark` is exceeded.
+    pub write_buffer_policy: WriteBufferPolicy,
+    /// Caps the rate at which the session writes to the server, for
+    /// all channels and protocol messages together. See also
+    /// [`Channel::set_rate_limit`].
+    pub rate_limit: Option<RateLimit>,
+    /// Whether to send the first key exchange packet right after
+    /// KEXINIT, guessing that the server prefers the same key exchange
+    /// and host key algorithms as the first ones of `preferred`. A right
+    /// guess saves a round trip during the handshake, a wrong one is
+    /// discarded by the server (RFC 4253, section 7.1).
+    pub first_kex_packet_follows: bool,
+    /// Maximal time a [`Handler`] callback may run. If a callback
+    /// takes longer, the session ends with
+    /// [`Error::HandlerTimeout`](crate::Error::HandlerTimeout) and a
+    /// [`ClientEvent::HandlerTimeout`] is sent. `None` waits forever.
+    pub handler_timeout: Option<std::time::Duration>,
+    /// Called with every inbound packet before the session processes
+    /// it, and allowed to consume it. See [`crate::RawPacketHook`].
+    #[cfg(feature = "danger-raw-packets")]
+    pub raw_packet_hook: Option<crate::RawPacketHook>,
+    /// Runs the session's tasks, instead of the runtime selected by the
+    /// `runtime-*` features. See [`crate::runtime`].
+    pub spawner: Option<crate::runtime::Spawner>,
+    /// Maximal length of the packets accepted from the server, checked
+    /// before reading them. Longer packets end the session with
+    /// [`Error::PacketTooLarge`](crate::Error::PacketTooLarge). RFC 4253
+    /// requires accepting packets of at least 35000 bytes.
+    pub maximum_inbound_packet_size: usize,
+    /// Decrypts the packets from the server on a separate task, up to
+    /// this many packets ahead of the session, so that decryption and
+    /// the processing of the previous packets run in parallel. This
+    /// helps bulk transfers on fast links, where decryption alone can
+    /// keep a core busy. Key exchanges still read one packet at a time.
+    /// `None`, the default, decrypts in the session's task.
+    pub read_pipeline_depth: Option<usize>,
+    /// Whether to add the algorithms of `Preferred::LEGACY` to
+    /// `preferred` when the server identifies as an implementation
+    /// known to need them, such as OpenSSH before 7.2 or Dropbear
+    /// before 2020.79.
+    ///
+    /// **This weakens the connection to these servers**, see
+    /// `Preferred::LEGACY`. A warning
+    /// is logged when it happens. Without the `legacy-algorithms`
+    /// feature, this only logs the warning.
+    pub auto_legacy_compat: bool,
+    /// Whether to log and ignore harmless protocol violations seen from
+    /// some servers, such as Mikrotik RouterOS, instead of ending the
+    /// session with [`Error::Inconsistent`](crate::Error::Inconsistent):
+    /// a SERVICE_ACCEPT after the first one, and a CHANNEL_SUCCESS or
+    /// CHANNEL_FAILURE answering no request sent with `want_reply`.
+    pub lenient: bool,
+    /// Whether to tell the server the labels given to channels with
+    /// [`Handle::channel_open_session_labeled`], in a `label@russh.rs`
+    /// channel request. Other implementations ignore it, but the
+    /// request still shows in their logs.
+    pub send_channel_labels: bool,
+    /// The smallest host keys accepted, in bits (see
+    /// [`PublicKey::bit_length`]), by key family. The session fails
+    /// with [`Error::KeyTooWeak`](crate::Error::KeyTooWeak) before
+    /// [`Handler::check_server_key`] sees smaller keys. Requires RSA
+    /// keys of 1024 bits by default.
+    pub minimum_key_bits: HashMap<key::KeyFamily, usize>,
+    /// The largest host keys accepted, failing with
+    /// [`Error::KeyTooLarge`](crate::Error::KeyTooLarge), to bound the
+    /// cost of verifying their signatures. RSA keys are limited to
+    /// 16384 bits by default.
+    pub maximum_key_bits: HashMap<key::KeyFamily, usize>,
+    /// Whether to read all the `SSH-2.0-` identification lines the
+    /// server sends before its first packet, and use the last one, for
+    /// SSH-aware firewalls that send their own before passing the
+    /// server's on. Without it, the first one is used, and the key
+    /// exchange with these fails. All of them are kept, see
+    /// [`Session::remote_sshids`], and [`ClientEvent::MultipleServerIds`]
+    /// is sent when there are several.
+    ///
+    /// This waits for the server's first packet before sending ours,
+    /// which servers sending it first, such as OpenSSH, don't mind.
+    pub use_last_server_id: bool,
 }
 
 impl Default for Config {
@@ -1426,13 +2661,32 @@ impl Default for Config {
                 env!("CARGO_PKG_VERSION")
             )),
             limits: Limits::default(),
+            disable_rekey: false,
             window_size: 2097152,
             maximum_packet_size: 32768,
             preferred: Default::default(),
             inactivity_timeout: None,
             keepalive_interval: None,
             keepalive_max: 3,
+            max_pending_global_requests: usize::MAX,
             anonymous: false,
+            channel_buffer_size: None,
+            write_buffer_high_water_mark: 8 << 20,
+            write_buffer_policy: WriteBufferPolicy::default(),
+            rate_limit: None,
+            first_kex_packet_follows: false,
+            handler_timeout: None,
+            #[cfg(feature = "danger-raw-packets")]
+            raw_packet_hook: None,
+            spawner: None,
+            maximum_inbound_packet_size: 256 << 10,
+            read_pipeline_depth: None,
+            auto_legacy_compat: false,
+            lenient: true,
+            send_channel_labels: false,
+            minimum_key_bits: crate::key::default_minimum_key_bits(),
+            maximum_key_bits: crate::key::default_maximum_key_bits(),
+            use_last_server_id: false,
         }
     }
 }
@@ -1440,6 +2694,13 @@ impl Default for Config {
 /// A client handler. Note that messages can be received from the
 /// server at any time during a session.
 ///
+/// The handler given to [`connect`] is kept for the whole connection,
+/// and every callback gets that same instance through `&mut self`.
+/// Callbacks run one at a time, and the session doesn't process
+/// further messages while one is running, so state kept in the
+/// handler needs no locking. To share state with the code holding the
+/// [`Handle`], put it behind an `Arc<Mutex<_>>` in both.
+///
 /// Note: this is an `async_trait`. Click `[source]` on the right to see actual async function definitions.
 
 #[async_trait]
@@ -1456,12 +2717,18 @@ pub trait Handler: Sized + Send {
         banner: &str,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.send_event(ClientEvent::Banner(banner.to_string()));
         Ok(())
     }
 
     /// Called to check the server's public key. This is a very important
     /// step to help prevent man-in-the-middle attacks. The default
     /// implementation rejects all keys.
+    ///
+    /// To show the key to a user, `server_public_key.fingerprint(HashAlg::Sha256)`
+    /// gives the same `SHA256:...` string as OpenSSH, and
+    /// [`PublicKeyBase64::public_key_bytes`](crate::keys::PublicKeyBase64::public_key_bytes)
+    /// the key blob.
     #[allow(unused_variables)]
     async fn check_server_key(
         &mut self,
@@ -1470,6 +2737,21 @@ pub trait Handler: Sized + Send {
         Ok(false)
     }
 
+    /// Called to check the server's public key with its blob, exactly
+    /// as the server sent it, for pinning host keys by comparing bytes
+    /// instead of parsed keys.
+    /// [`PublicKey::fingerprint_sha256_raw`](key::PublicKey::fingerprint_sha256_raw)
+    /// gives the blob's fingerprint. The default implementation calls
+    /// [`Handler::check_server_key`].
+    #[allow(unused_variables)]
+    async fn check_server_key_blob(
+        &mut self,
+        server_key_blob: &[u8],
+        server_public_key: &key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        self.check_server_key(server_public_key).await
+    }
+
     /// Called when the server confirmed our request to open a
     /// channel. A channel can only be written to after receiving this
     /// message (this library panics otherwise).
@@ -1524,6 +2806,17 @@ pub trait Handler: Sized + Send {
         Ok(())
     }
 
+    /// Called when the server won't read more data from a channel, see
+    /// [`ChannelMsg::EndOfWrite`].
+    #[allow(unused_variables)]
+    async fn end_of_write(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Called when the server rejected our request to open a channel.
     #[allow(unused_variables)]
     async fn channel_open_failure(
@@ -1537,7 +2830,9 @@ pub trait Handler: Sized + Send {
         Ok(())
     }
 
-    /// Called when the server opens a channel for a new remote port forwarding connection
+    /// Called when the server opens a channel for a new remote port
+    /// forwarding connection. The default sends it to [`Handle::events`],
+    /// and closes it if nobody takes the events.
     #[allow(unused_variables)]
     async fn server_channel_open_forwarded_tcpip(
         &mut self,
@@ -1548,6 +2843,18 @@ pub trait Handler: Sized + Send {
         originator_port: u32,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        let id = channel.id();
+        let event = ClientEvent::ForwardedTcpIp {
+            channel,
+            connected_address: connected_address.to_string(),
+            connected_port,
+            originator_address: originator_address.to_string(),
+            originator_port,
+        };
+        if session.try_send_event(event).is_err() {
+            debug!("no one takes forwarded connection {:?}, closing it", id);
+            session.close(id);
+        }
         Ok(())
     }
 
@@ -1593,7 +2900,8 @@ pub trait Handler: Sized + Send {
         Ok(())
     }
 
-    /// Called when the server opens an X11 channel.
+    /// Called when the server opens an X11 channel. The default sends it
+    /// to [`Handle::events`], and closes it if nobody takes the events.
     #[allow(unused_variables)]
     async fn server_channel_open_x11(
         &mut self,
@@ -1602,6 +2910,16 @@ pub trait Handler: Sized + Send {
         originator_port: u32,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        let id = channel.id();
+        let event = ClientEvent::X11 {
+            channel,
+            originator_address: originator_address.to_string(),
+            originator_port,
+        };
+        if session.try_send_event(event).is_err() {
+            debug!("no one takes X11 channel {:?}, closing it", id);
+            session.close(id);
+        }
         Ok(())
     }
 
@@ -1634,6 +2952,28 @@ pub trait Handler: Sized + Send {
         Ok(())
     }
 
+    /// Observes the data of every channel, received or sent, for
+    /// instance to record sessions. It is called before
+    /// [`Handler::data`] for received data, and when sending the data
+    /// given to a [`Handle`] or [`Channel`](crate::Channel). The data this handler
+    /// sends itself with [`Session::data`] isn't reported.
+    #[allow(unused_variables)]
+    #[inline]
+    fn on_channel_data(&mut self, direction: Direction, channel: ChannelId, data: &[u8]) {}
+
+    /// Like [`Handler::on_channel_data`], for extended data such as
+    /// standard error.
+    #[allow(unused_variables)]
+    #[inline]
+    fn on_channel_extended_data(
+        &mut self,
+        direction: Direction,
+        channel: ChannelId,
+        ext: u32,
+        data: &[u8],
+    ) {
+    }
+
     /// The server informs this client of whether the client may
     /// perform control-S/control-Q flow control. See
     /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-6.8).
@@ -1672,6 +3012,26 @@ pub trait Handler: Sized + Send {
         Ok(())
     }
 
+    /// Called when the server sends a channel request that russh does
+    /// not handle itself, for instance on a channel opened by the
+    /// server. `data` is the request-specific payload following the
+    /// `want_reply` byte. If `want_reply` is set, the server expects
+    /// an answer through [`Session::channel_success`] or
+    /// [`Session::channel_failure`]; the default implementation
+    /// refuses the request.
+    #[allow(unused_variables)]
+    async fn channel_request(
+        &mut self,
+        channel: ChannelId,
+        request_type: &str,
+        want_reply: bool,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_failure(channel);
+        Ok(())
+    }
+
     /// Called when the network window is adjusted, meaning that we
     /// can send more bytes. This is useful if this client wants to
     /// send huge amounts of data, for instance if we have called
@@ -1702,6 +3062,7 @@ pub trait Handler: Sized + Send {
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         debug!("openssh_ext_hostkeys_announced: {:?}", keys);
+        session.send_event(ClientEvent::HostKeysAnnounced(keys));
         Ok(())
     }
 
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use futures::Stream;
+use log::debug;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::{Handle, Handler, Msg};
+use crate::{Channel, Disconnect};
+
+type DialFuture<H> = Pin<Box<dyn Future<Output = Result<Handle<H>, <H as Handler>::Error>> + Send>>;
+type Dial<H> = Box<dyn Fn(PoolKey) -> DialFuture<H> + Send + Sync>;
+
+/// Identifies the connections a [`ConnectionPool`] may share: only
+/// connections to the same server, authenticated as the same user
+/// with the same identity, are reused.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    /// Fingerprint of the key (or any other description of the
+    /// credentials) used to authenticate.
+    pub identity: String,
+}
+
+impl PoolKey {
+    pub fn new<H: Into<String>, U: Into<String>, I: Into<String>>(
+        host: H,
+        port: u16,
+        user: U,
+        identity: I,
+    ) -> Self {
+        PoolKey {
+            host: host.into(),
+            port,
+            user: user.into(),
+            identity: identity.into(),
+        }
+    }
+}
+
+/// Configuration of a [`ConnectionPool`].
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Maximal number of leases (usually one per channel) on each
+    /// connection. OpenSSH servers accept 10 sessions per connection
+    /// by default (`MaxSessions`).
+    pub max_channels_per_connection: usize,
+    /// Time after which a connection without leases is closed.
+    pub idle_timeout: Duration,
+    /// Before reusing a connection without leases, check that the
+    /// server still answers a keepalive within this time. Connections
+    /// failing the check are closed, and another one is dialed.
+    pub health_check_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_channels_per_connection: 10,
+            idle_timeout: Duration::from_secs(60),
+            health_check_timeout: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// What happened to the connections of a [`ConnectionPool`], see
+/// [`ConnectionPool::events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PoolEvent {
+    /// A new connection was dialed.
+    Connected(PoolKey),
+    /// A connection left the pool, because it was idle for too long,
+    /// failed its health check, or its session ended.
+    Disconnected(PoolKey),
+}
+
+struct PooledConnection<H: Handler> {
+    id: u64,
+    handle: Arc<Handle<H>>,
+    leases: usize,
+    /// Incremented each time the connection becomes idle, so that the
+    /// idle timer of an earlier idle period doesn't close it.
+    idle_generation: u64,
+}
+
+struct PoolState<H: Handler> {
+    connections: HashMap<PoolKey, Vec<PooledConnection<H>>>,
+    /// Held while dialing, so that concurrent requests for the same
+    /// key wait for that connection instead of dialing their own.
+    dialing: HashMap<PoolKey, Arc<tokio::sync::Mutex<()>>>,
+    next_id: u64,
+}
+
+struct PoolInner<H: Handler> {
+    config: PoolConfig,
+    dial: Dial<H>,
+    state: Mutex<PoolState<H>>,
+    event_sender: UnboundedSender<PoolEvent>,
+    events: Mutex<Option<UnboundedReceiver<PoolEvent>>>,
+}
+
+/// Shares authenticated connections between requests to the same
+/// server, in the manner of OpenSSH's `ControlMaster`, to avoid paying
+/// for a handshake on each request.
+///
+/// Connections are dialed by the function given to
+/// [`ConnectionPool::new`], which must return an authenticated
+/// [`Handle`]. Concurrent requests for a key without a connection wait
+/// for a single dial. Each [`ConnectionLease`] holds a slot on its
+/// connection until dropped.
+///
+/// If a session ends, the requests using it get errors from their
+/// channels, and the next request for its key dials again.
+pub struct ConnectionPool<H: Handler> {
+    inner: Arc<PoolInner<H>>,
+}
+
+impl<H: Handler> Clone for ConnectionPool<H> {
+    fn clone(&self) -> Self {
+        ConnectionPool {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<H: Handler + Send + 'static> ConnectionPool<H> {
+    pub fn new<F, Fut>(config: PoolConfig, dial: F) -> Self
+    where
+        F: Fn(PoolKey) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Handle<H>, H::Error>> + Send + 'static,
+    {
+        let (event_sender, events) = unbounded_channel();
+        ConnectionPool {
+            inner: Arc::new(PoolInner {
+                config,
+                dial: Box::new(move |key| Box::pin(dial(key))),
+                state: Mutex::new(PoolState {
+                    connections: HashMap::new(),
+                    dialing: HashMap::new(),
+                    next_id: 0,
+                }),
+                event_sender,
+                events: Mutex::new(Some(events)),
+            }),
+        }
+    }
+
+    /// Returns the stream of [`PoolEvent`]s. As with
+    /// [`Handle::events`], it is unbounded and can only be taken once.
+    /// Events are buffered until it is taken.
+    pub fn events(&self) -> impl Stream<Item = PoolEvent> + Send + Unpin + 'static {
+        let mut events = self.inner.lock_events().take();
+        futures::stream::poll_fn(move |cx| match events {
+            Some(ref mut events) => events.poll_recv(cx),
+            None => std::task::Poll::Ready(None),
+        })
+    }
+
+    /// Leases a connection for `key`, reusing one with a free slot or
+    /// dialing a new one.
+    pub async fn lease(&self, key: &PoolKey) -> Result<ConnectionLease<H>, H::Error> {
+        loop {
+            let dialing = match self.inner.take_slot(key) {
+                Ok((lease, was_idle)) => {
+                    if was_idle
+                        && !lease
+                            .check_health(self.inner.config.health_check_timeout)
+                            .await
+                    {
+                        debug!("pooled connection to {:?} failed its health check", key);
+                        self.inner.remove(key, lease.id);
+                        continue;
+                    }
+                    return Ok(lease);
+                }
+                Err(dialing) => dialing,
+            };
+            let _dialing = dialing.lock().await;
+            // Another request may have dialed while we were waiting.
+            if let Ok((lease, _)) = self.inner.take_slot(key) {
+                return Ok(lease);
+            }
+            let handle = (self.inner.dial)(key.clone()).await?;
+            return Ok(self.inner.insert(key, handle));
+        }
+    }
+
+    /// Opens a session channel on a leased connection.
+    pub async fn channel_open_session(&self, key: &PoolKey) -> Result<PooledChannel<H>, H::Error> {
+        let lease = self.lease(key).await?;
+        let channel = lease.channel_open_session().await?;
+        Ok(PooledChannel { channel, lease })
+    }
+}
+
+impl<H: Handler + Send + 'static> PoolInner<H> {
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, PoolState<H>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_events(&self) -> std::sync::MutexGuard<'_, Option<UnboundedReceiver<PoolEvent>>> {
+        self.events.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes a slot on a live connection for `key`, also returning
+    /// whether that connection was idle. Without one, returns the lock
+    /// to hold while dialing.
+    fn take_slot(
+        self: &Arc<Self>,
+        key: &PoolKey,
+    ) -> Result<(ConnectionLease<H>, bool), Arc<tokio::sync::Mutex<()>>> {
+        let mut state = self.lock_state();
+        if let Some(connections) = state.connections.get_mut(key) {
+            let before = connections.len();
+            connections.retain(|c| !c.handle.is_closed());
+            for _ in connections.len()..before {
+                let _ = self.event_sender.send(PoolEvent::Disconnected(key.clone()));
+            }
+            let max = self.config.max_channels_per_connection.max(1);
+            if let Some(connection) = connections.iter_mut().find(|c| c.leases < max) {
+                let was_idle = connection.leases == 0;
+                connection.leases += 1;
+                return Ok((self.lease(key, connection), was_idle));
+            }
+        }
+        Err(state.dialing.entry(key.clone()).or_default().clone())
+    }
+
+    fn insert(self: &Arc<Self>, key: &PoolKey, handle: Handle<H>) -> ConnectionLease<H> {
+        let _ = self.event_sender.send(PoolEvent::Connected(key.clone()));
+        let mut state = self.lock_state();
+        let id = state.next_id;
+        state.next_id += 1;
+        let connections = state.connections.entry(key.clone()).or_default();
+        connections.push(PooledConnection {
+            id,
+            handle: Arc::new(handle),
+            leases: 1,
+            idle_generation: 0,
+        });
+        #[allow(clippy::unwrap_used)] // just pushed
+        let connection = connections.last().unwrap();
+        self.lease(key, connection)
+    }
+
+    fn lease(
+        self: &Arc<Self>,
+        key: &PoolKey,
+        connection: &PooledConnection<H>,
+    ) -> ConnectionLease<H> {
+        ConnectionLease {
+            pool: Arc::downgrade(self),
+            key: key.clone(),
+            id: connection.id,
+            handle: connection.handle.clone(),
+        }
+    }
+
+    /// Gives back a slot, and starts the idle timer if this was the
+    /// last one.
+    fn release(self: &Arc<Self>, key: &PoolKey, id: u64) {
+        let mut state = self.lock_state();
+        let Some(connection) = state
+            .connections
+            .get_mut(key)
+            .and_then(|c| c.iter_mut().find(|c| c.id == id))
+        else {
+            return;
+        };
+        connection.leases = connection.leases.saturating_sub(1);
+        if connection.leases > 0 {
+            return;
+        }
+        connection.idle_generation += 1;
+        let generation = connection.idle_generation;
+        let pool = Arc::downgrade(self);
+        let key = key.clone();
+        let idle_timeout = self.config.idle_timeout;
+        crate::runtime::spawn_if_running(async move {
+            crate::runtime::sleep(idle_timeout).await;
+            if let Some(pool) = pool.upgrade() {
+                pool.close_if_idle(&key, id, generation);
+            }
+        });
+    }
+
+    fn close_if_idle(&self, key: &PoolKey, id: u64, generation: u64) {
+        let still_idle = self
+            .lock_state()
+            .connections
+            .get(key)
+            .and_then(|c| c.iter().find(|c| c.id == id))
+            .map_or(false, |c| c.leases == 0 && c.idle_generation == generation);
+        if still_idle {
+            debug!("closing idle pooled connection to {:?}", key);
+            self.remove(key, id);
+        }
+    }
+
+    /// Removes a connection from the pool, and disconnects it.
+    fn remove(&self, key: &PoolKey, id: u64) {
+        let removed = {
+            let mut state = self.lock_state();
+            let Some(connections) = state.connections.get_mut(key) else {
+                return;
+            };
+            let Some(i) = connections.iter().position(|c| c.id == id) else {
+                return;
+            };
+            let removed = connections.swap_remove(i);
+            if connections.is_empty() {
+                state.connections.remove(key);
+            }
+            removed
+        };
+        let _ = self.event_sender.send(PoolEvent::Disconnected(key.clone()));
+        crate::runtime::spawn_if_running(async move {
+            let _ = removed
+                .handle
+                .disconnect(Disconnect::ByApplication, "", "en")
+                .await;
+        });
+    }
+}
+
+/// A slot on a pooled connection, giving access to its [`Handle`].
+/// The slot is given back to the pool when the lease is dropped, so
+/// the lease must be kept as long as the channels opened with it.
+pub struct ConnectionLease<H: Handler + Send + 'static> {
+    pool: Weak<PoolInner<H>>,
+    key: PoolKey,
+    id: u64,
+    handle: Arc<Handle<H>>,
+}
+
+impl<H: Handler + Send + 'static> ConnectionLease<H> {
+    pub fn key(&self) -> &PoolKey {
+        &self.key
+    }
+
+    async fn check_health(&self, timeout: Option<Duration>) -> bool {
+        let Some(timeout) = timeout else {
+            return !self.handle.is_closed();
+        };
+        matches!(
+            crate::runtime::timeout(timeout, self.handle.keepalive()).await,
+            Ok(Ok(()))
+        )
+    }
+}
+
+impl<H: Handler + Send + 'static> Deref for ConnectionLease<H> {
+    type Target = Handle<H>;
+    fn deref(&self) -> &Handle<H> {
+        &self.handle
+    }
+}
+
+impl<H: Handler + Send + 'static> Drop for ConnectionLease<H> {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.upgrade() {
+            pool.release(&self.key, self.id)
+        }
+    }
+}
+
+/// A session channel opened by [`ConnectionPool::channel_open_session`],
+/// holding its [`ConnectionLease`].
+pub struct PooledChannel<H: Handler + Send + 'static> {
+    channel: Channel<Msg>,
+    lease: ConnectionLease<H>,
+}
+
+impl<H: Handler + Send + 'static> PooledChannel<H> {
+    /// Splits the channel from its lease, for instance to turn the
+    /// channel into a stream. The lease must be kept alive as long as
+    /// the channel is used.
+    pub fn into_parts(self) -> (Channel<Msg>, ConnectionLease<H>) {
+        (self.channel, self.lease)
+    }
+}
+
+impl<H: Handler + Send + 'static> Deref for PooledChannel<H> {
+    type Target = Channel<Msg>;
+    fn deref(&self) -> &Channel<Msg> {
+        &self.channel
+    }
+}
+
+impl<H: Handler + Send + 'static> DerefMut for PooledChannel<H> {
+    fn deref_mut(&mut self) -> &mut Channel<Msg> {
+        &mut self.channel
+    }
+}
@@ -0,0 +1,288 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::timings::Step;
+use super::{connect_stream_timed, Config, Handle, Handler, HandshakeTimings};
+
+/// Credentials sent to a proxy.
+#[derive(Debug, Clone)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// A proxy to tunnel the SSH connection through, see [`connect_via_proxy`].
+#[derive(Debug, Clone)]
+pub enum Proxy {
+    /// An HTTP proxy, used with the `CONNECT` method. `auth` is sent
+    /// with the `Basic` scheme.
+    Http {
+        host: String,
+        port: u16,
+        auth: Option<ProxyAuth>,
+    },
+    /// A SOCKS5 proxy ([RFC1928](https://tools.ietf.org/html/rfc1928)),
+    /// with username/password authentication
+    /// ([RFC1929](https://tools.ietf.org/html/rfc1929)) if `auth` is set.
+    Socks5 {
+        host: String,
+        port: u16,
+        auth: Option<ProxyAuth>,
+    },
+}
+
+/// Error codes of a SOCKS5 reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks5Error {
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+    Other(u8),
+}
+
+impl From<u8> for Socks5Error {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => Socks5Error::GeneralFailure,
+            2 => Socks5Error::NotAllowed,
+            3 => Socks5Error::NetworkUnreachable,
+            4 => Socks5Error::HostUnreachable,
+            5 => Socks5Error::ConnectionRefused,
+            6 => Socks5Error::TtlExpired,
+            7 => Socks5Error::CommandNotSupported,
+            8 => Socks5Error::AddressTypeNotSupported,
+            code => Socks5Error::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    /// The HTTP proxy replied with status 407, either because no
+    /// credentials were given or because they were wrong.
+    #[error("Proxy authentication required")]
+    AuthenticationRequired,
+
+    /// The HTTP proxy refused to connect, with this status.
+    #[error("HTTP proxy replied with status {0}")]
+    HttpStatus(u16),
+
+    /// The SOCKS5 proxy accepts none of the offered authentication methods.
+    #[error("No acceptable SOCKS5 authentication method")]
+    NoAcceptableAuthMethod,
+
+    /// The SOCKS5 proxy rejected the credentials.
+    #[error("SOCKS5 authentication failed")]
+    AuthenticationFailed,
+
+    /// The SOCKS5 proxy couldn't connect.
+    #[error("SOCKS5 proxy error: {0:?}")]
+    Socks5(Socks5Error),
+
+    /// The host name, user name or password is too long for SOCKS5.
+    #[error("Field too long for SOCKS5")]
+    TooLong,
+
+    #[error("Invalid reply from the proxy")]
+    InvalidReply,
+}
+
+/// Maximal size of the HTTP reply headers.
+const MAX_HTTP_REPLY: usize = 8192;
+
+impl Proxy {
+    /// Opens a TCP connection to `host:port` through the proxy. `host`
+    /// is resolved by the proxy, unless it is an IP address.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, crate::Error> {
+        let (proxy_host, proxy_port) = match self {
+            Proxy::Http { host, port, .. } | Proxy::Socks5 { host, port, .. } => (host, *port),
+        };
+        let mut stream = TcpStream::connect((proxy_host.as_str(), proxy_port)).await?;
+        match self {
+            Proxy::Http { auth, .. } => {
+                http_connect(&mut stream, host, port, auth.as_ref()).await?
+            }
+            Proxy::Socks5 { auth, .. } => {
+                socks5_connect(&mut stream, host, port, auth.as_ref()).await?
+            }
+        }
+        Ok(stream)
+    }
+}
+
+/// Connects to the server at `host:port` through `proxy`, and then
+/// starts the session as [`connect_stream`] does. The proxy resolves
+/// `host`, so pass an IP address to resolve it locally instead.
+///
+/// ```no_run
+/// # async fn connect<H: russh::client::Handler + 'static>(handler: H) -> Result<(), H::Error> {
+/// use russh::client::{self, Proxy};
+///
+/// let proxy = Proxy::Http {
+///     host: "proxy.example.com".to_string(),
+///     port: 3128,
+///     auth: None,
+/// };
+/// let session =
+///     client::connect_via_proxy(Default::default(), &proxy, "example.com", 22, handler).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn connect_via_proxy<H: Handler + Send + 'static>(
+    config: Arc<Config>,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+    handler: H,
+) -> Result<Handle<H>, H::Error> {
+    let mut timings = HandshakeTimings::new(crate::runtime::now());
+    let stream = proxy.connect(host, port).await?;
+    timings.record(Step::TcpConnected);
+    connect_stream_timed(config, stream, handler, timings).await
+}
+
+async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    auth: Option<&ProxyAuth>,
+) -> Result<(), crate::Error> {
+    let authority = if host.parse::<std::net::Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(auth) = auth {
+        let credentials = format!("{}:{}", auth.username, auth.password);
+        request.push_str("Proxy-Authorization: Basic ");
+        request.push_str(&data_encoding::BASE64.encode(credentials.as_bytes()));
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read one byte at a time, so that nothing after the headers
+    // (the server's SSH id) is consumed.
+    let mut reply = Vec::new();
+    while !reply.ends_with(b"\r\n\r\n") {
+        if reply.len() >= MAX_HTTP_REPLY {
+            return Err(ProxyError::InvalidReply.into());
+        }
+        reply.push(stream.read_u8().await?);
+    }
+    // "HTTP/1.1 200 Connection established"
+    let status = std::str::from_utf8(&reply)
+        .ok()
+        .and_then(|reply| reply.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(ProxyError::InvalidReply)?;
+    debug!("HTTP proxy replied with status {}", status);
+    match status {
+        200..=299 => Ok(()),
+        407 => Err(ProxyError::AuthenticationRequired.into()),
+        status => Err(ProxyError::HttpStatus(status).into()),
+    }
+}
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_NO_AUTH: u8 = 0;
+const SOCKS5_USER_PASSWORD: u8 = 2;
+const SOCKS5_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS5_CONNECT: u8 = 1;
+const SOCKS5_IPV4: u8 = 1;
+const SOCKS5_DOMAIN: u8 = 3;
+const SOCKS5_IPV6: u8 = 4;
+
+async fn socks5_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    auth: Option<&ProxyAuth>,
+) -> Result<(), crate::Error> {
+    if auth.is_some() {
+        stream
+            .write_all(&[SOCKS5_VERSION, 2, SOCKS5_NO_AUTH, SOCKS5_USER_PASSWORD])
+            .await?;
+    } else {
+        stream
+            .write_all(&[SOCKS5_VERSION, 1, SOCKS5_NO_AUTH])
+            .await?;
+    }
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    match (reply, auth) {
+        ([SOCKS5_VERSION, SOCKS5_NO_AUTH], _) => {}
+        ([SOCKS5_VERSION, SOCKS5_USER_PASSWORD], Some(auth)) => {
+            let mut request = vec![1];
+            push_socks5_string(&mut request, auth.username.as_bytes())?;
+            push_socks5_string(&mut request, auth.password.as_bytes())?;
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            let [_, status] = reply;
+            if status != 0 {
+                return Err(ProxyError::AuthenticationFailed.into());
+            }
+        }
+        ([SOCKS5_VERSION, SOCKS5_NO_ACCEPTABLE_METHOD], _) => {
+            return Err(ProxyError::NoAcceptableAuthMethod.into())
+        }
+        _ => return Err(ProxyError::InvalidReply.into()),
+    }
+
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS5_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS5_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            request.push(SOCKS5_DOMAIN);
+            push_socks5_string(&mut request, host.as_bytes())?;
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // Version, reply code, reserved, address type.
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    let [version, code, _, address_type] = reply;
+    if version != SOCKS5_VERSION {
+        return Err(ProxyError::InvalidReply.into());
+    }
+    if code != 0 {
+        return Err(ProxyError::Socks5(code.into()).into());
+    }
+    // Skip the bound address and port.
+    let address_len = match address_type {
+        SOCKS5_IPV4 => 4,
+        SOCKS5_IPV6 => 16,
+        SOCKS5_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(ProxyError::InvalidReply.into()),
+    };
+    let mut bound = vec![0; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn push_socks5_string(buf: &mut Vec<u8>, s: &[u8]) -> Result<(), ProxyError> {
+    let len = u8::try_from(s.len()).map_err(|_| ProxyError::TooLong)?;
+    buf.push(len);
+    buf.extend_from_slice(s);
+    Ok(())
+}
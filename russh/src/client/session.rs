@@ -1,10 +1,12 @@
-use log::error;
+use std::future::Future;
+
+use log::{debug, error, info};
 use tokio::sync::oneshot;
 
-use crate::client::Session;
-use crate::keys::encoding::Encoding;
+use crate::client::{ForwardTarget, Session};
+use crate::keys::encoding::{Encoding, Reader};
 use crate::session::EncryptedState;
-use crate::{msg, ChannelId, CryptoVec, Disconnect, Pty, Sig};
+use crate::{msg, ChannelId, CryptoVec, Disconnect, Extensions, Limits, Pty, Sig};
 
 impl Session {
     fn channel_open_generic<F>(
@@ -19,6 +21,7 @@ impl Session {
             match enc.state {
                 EncryptedState::Authenticated => {
                     let sender_channel = enc.new_channel(
+                        kind,
                         self.common.config.window_size,
                         self.common.config.maximum_packet_size,
                     );
@@ -103,29 +106,21 @@ impl Session {
         terminal_modes: &[(Pty, u32)],
     ) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"pty-req");
-                    enc.write.push(want_reply as u8);
-
-                    enc.write.extend_ssh_string(term.as_bytes());
-                    enc.write.push_u32_be(col_width);
-                    enc.write.push_u32_be(row_height);
-                    enc.write.push_u32_be(pix_width);
-                    enc.write.push_u32_be(pix_height);
-
-                    enc.write.push_u32_be((1 + 5 * terminal_modes.len()) as u32);
-                    for &(code, value) in terminal_modes {
-                        enc.write.push(code as u8);
-                        enc.write.push_u32_be(value)
-                    }
-                    // 0 code (to terminate the list)
-                    enc.write.push(0);
-                });
-            }
+            enc.channel_request(channel, b"pty-req", want_reply, |w| {
+                w.extend_ssh_string(term.as_bytes());
+                w.push_u32_be(col_width);
+                w.push_u32_be(row_height);
+                w.push_u32_be(pix_width);
+                w.push_u32_be(pix_height);
+
+                w.push_u32_be((1 + 5 * terminal_modes.len()) as u32);
+                for &(code, value) in terminal_modes {
+                    w.push(code as u8);
+                    w.push_u32_be(value)
+                }
+                // 0 code (to terminate the list)
+                w.push(0);
+            });
         }
     }
 
@@ -139,21 +134,12 @@ impl Session {
         x11_screen_number: u32,
     ) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"x11-req");
-                    enc.write.push(want_reply as u8);
-                    enc.write.push(single_connection as u8);
-                    enc.write
-                        .extend_ssh_string(x11_authentication_protocol.as_bytes());
-                    enc.write
-                        .extend_ssh_string(x11_authentication_cookie.as_bytes());
-                    enc.write.push_u32_be(x11_screen_number);
-                });
-            }
+            enc.channel_request(channel, b"x11-req", want_reply, |w| {
+                w.push(single_connection as u8);
+                w.extend_ssh_string(x11_authentication_protocol.as_bytes());
+                w.extend_ssh_string(x11_authentication_cookie.as_bytes());
+                w.push_u32_be(x11_screen_number);
+            });
         }
     }
 
@@ -165,45 +151,25 @@ impl Session {
         variable_value: &str,
     ) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"env");
-                    enc.write.push(want_reply as u8);
-                    enc.write.extend_ssh_string(variable_name.as_bytes());
-                    enc.write.extend_ssh_string(variable_value.as_bytes());
-                });
-            }
+            enc.channel_request(channel, b"env", want_reply, |w| {
+                w.extend_ssh_string(variable_name.as_bytes());
+                w.extend_ssh_string(variable_value.as_bytes());
+            });
         }
     }
 
     pub fn request_shell(&mut self, want_reply: bool, channel: ChannelId) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"shell");
-                    enc.write.push(want_reply as u8);
-                });
-            }
+            enc.channel_request(channel, b"shell", want_reply, |_| {});
         }
     }
 
     pub fn exec(&mut self, channel: ChannelId, want_reply: bool, command: &[u8]) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"exec");
-                    enc.write.push(want_reply as u8);
-                    enc.write.extend_ssh_string(command);
-                });
+            let sent = enc.channel_request(channel, b"exec", want_reply, |w| {
+                w.extend_ssh_string(command);
+            });
+            if sent {
                 return;
             }
         }
@@ -212,30 +178,19 @@ impl Session {
 
     pub fn signal(&mut self, channel: ChannelId, signal: Sig) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"signal");
-                    enc.write.push(0);
-                    enc.write.extend_ssh_string(signal.name().as_bytes());
-                });
-            }
+            enc.channel_msg(channel, msg::CHANNEL_REQUEST, |w| {
+                w.extend_ssh_string(b"signal");
+                w.push(0);
+                w.extend_ssh_string(signal.name().as_bytes());
+            });
         }
     }
 
     pub fn request_subsystem(&mut self, want_reply: bool, channel: ChannelId, name: &str) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"subsystem");
-                    enc.write.push(want_reply as u8);
-                    enc.write.extend_ssh_string(name.as_bytes());
-                });
-            }
+            enc.channel_request(channel, b"subsystem", want_reply, |w| {
+                w.extend_ssh_string(name.as_bytes());
+            });
         }
     }
 
@@ -248,19 +203,14 @@ impl Session {
         pix_height: u32,
     ) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"window-change");
-                    enc.write.push(0); // this packet never wants reply
-                    enc.write.push_u32_be(col_width);
-                    enc.write.push_u32_be(row_height);
-                    enc.write.push_u32_be(pix_width);
-                    enc.write.push_u32_be(pix_height);
-                });
-            }
+            enc.channel_msg(channel, msg::CHANNEL_REQUEST, |w| {
+                w.extend_ssh_string(b"window-change");
+                w.push(0); // this packet never wants reply
+                w.push_u32_be(col_width);
+                w.push_u32_be(row_height);
+                w.push_u32_be(pix_width);
+                w.push_u32_be(pix_height);
+            });
         }
     }
 
@@ -274,20 +224,67 @@ impl Session {
         address: &str,
         port: u32,
     ) {
-        if let Some(ref mut enc) = self.common.encrypted {
-            let want_reply = reply_channel.is_some();
-            if let Some(reply_channel) = reply_channel {
-                self.open_global_requests.push_back(
-                    crate::session::GlobalRequestResponse::TcpIpForward(reply_channel),
-                );
+        self.tcpip_forward_to(reply_channel, address.into(), port, ForwardTarget::Handler)
+    }
+
+    /// Like [`tcpip_forward`](Self::tcpip_forward), but asks for the
+    /// server's reply, and returns a future resolving to the forwarded
+    /// port, which the server chooses if `port` is 0.
+    ///
+    /// The request is only sent once the handler returns, so the
+    /// future must be awaited elsewhere, for instance in a spawned task.
+    pub fn tcpip_forward_with_reply(
+        &mut self,
+        address: &str,
+        port: u32,
+    ) -> impl Future<Output = Result<u32, crate::Error>> + Send + 'static {
+        let (reply_channel, reply) = oneshot::channel();
+        self.tcpip_forward(Some(reply_channel), address, port);
+        async move {
+            match reply.await {
+                Ok(Some(bound)) if port == 0 => Ok(bound),
+                Ok(Some(_)) => Ok(port),
+                Ok(None) => Err(crate::Error::RequestDenied),
+                Err(_) => Err(crate::Error::Disconnect),
             }
-            push_packet!(enc.write, {
-                enc.write.push(msg::GLOBAL_REQUEST);
-                enc.write.extend_ssh_string(b"tcpip-forward");
-                enc.write.push(want_reply as u8);
-                enc.write.extend_ssh_string(address.as_bytes());
-                enc.write.push_u32_be(port);
-            });
+        }
+    }
+
+    pub(crate) fn tcpip_forward_to(
+        &mut self,
+        reply_channel: Option<oneshot::Sender<Option<u32>>>,
+        address: String,
+        port: u32,
+        target: ForwardTarget,
+    ) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            let mut packet = CryptoVec::new();
+            // Local forwards are set up when the server accepts them,
+            // which needs a reply.
+            let response = match target {
+                ForwardTarget::Local(target) => {
+                    Some(crate::session::GlobalRequestResponse::LocalTcpIpForward {
+                        reply_channel,
+                        address: address.clone(),
+                        port,
+                        target,
+                    })
+                }
+                ForwardTarget::Handler => {
+                    reply_channel.map(crate::session::GlobalRequestResponse::TcpIpForward)
+                }
+            };
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(b"tcpip-forward");
+            packet.push(response.is_some() as u8);
+            packet.extend_ssh_string(address.as_bytes());
+            packet.push_u32_be(port);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                response,
+            );
         }
     }
 
@@ -301,32 +298,169 @@ impl Session {
         address: &str,
         port: u32,
     ) {
+        // Stops piping the connections in progress, if any.
+        self.local_forwards.remove(&(address.to_string(), port));
         if let Some(ref mut enc) = self.common.encrypted {
-            let want_reply = reply_channel.is_some();
-            if let Some(reply_channel) = reply_channel {
-                self.open_global_requests.push_back(
-                    crate::session::GlobalRequestResponse::CancelTcpIpForward(reply_channel),
-                );
+            let mut packet = CryptoVec::new();
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(b"cancel-tcpip-forward");
+            packet.push(reply_channel.is_some() as u8);
+            packet.extend_ssh_string(address.as_bytes());
+            packet.push_u32_be(port);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                reply_channel.map(crate::session::GlobalRequestResponse::CancelTcpIpForward),
+            );
+        }
+    }
+
+    /// Like [`cancel_tcpip_forward`](Self::cancel_tcpip_forward), but
+    /// asks for the server's reply, and returns a future resolving once
+    /// it arrives. As with
+    /// [`tcpip_forward_with_reply`](Self::tcpip_forward_with_reply),
+    /// the future must be awaited outside of the handler.
+    pub fn cancel_tcpip_forward_with_reply(
+        &mut self,
+        address: &str,
+        port: u32,
+    ) -> impl Future<Output = Result<(), crate::Error>> + Send + 'static {
+        let (reply_channel, reply) = oneshot::channel();
+        self.cancel_tcpip_forward(Some(reply_channel), address, port);
+        async move {
+            match reply.await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(crate::Error::RequestDenied),
+                Err(_) => Err(crate::Error::Disconnect),
             }
-            push_packet!(enc.write, {
-                enc.write.push(msg::GLOBAL_REQUEST);
-                enc.write.extend_ssh_string(b"cancel-tcpip-forward");
-                enc.write.push(want_reply as u8);
-                enc.write.extend_ssh_string(address.as_bytes());
-                enc.write.push_u32_be(port);
-            });
+        }
+    }
+
+    /// Sends a global request named `name`, with `payload` written
+    /// verbatim after the `want reply` field, for requests this crate
+    /// doesn't know about.
+    ///
+    /// If `want_reply` is true, returns a future resolving to the data
+    /// of the server's success reply, or to [`crate::Error::RequestDenied`] if
+    /// the server refused the request. As with the other requests, it
+    /// is only sent once the handler returns, so the future must be
+    /// awaited outside of the handler.
+    pub fn send_global_request(
+        &mut self,
+        name: &str,
+        want_reply: bool,
+        payload: &[u8],
+    ) -> Option<impl Future<Output = Result<Vec<u8>, crate::Error>> + Send + 'static> {
+        let (reply_channel, reply) = if want_reply {
+            let (reply_channel, reply) = oneshot::channel();
+            (Some(reply_channel), Some(reply))
+        } else {
+            (None, None)
+        };
+        self.global_request(name, payload, reply_channel);
+        reply.map(|reply| async move {
+            match reply.await {
+                Ok(Some(data)) => Ok(data),
+                Ok(None) => Err(crate::Error::RequestDenied),
+                Err(_) => Err(crate::Error::Disconnect),
+            }
+        })
+    }
+
+    /// Sends a raw global request, asking for a reply if
+    /// `reply_channel` is given.
+    pub(crate) fn global_request(
+        &mut self,
+        name: &str,
+        payload: &[u8],
+        reply_channel: Option<oneshot::Sender<Option<Vec<u8>>>>,
+    ) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            let mut packet = CryptoVec::new();
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(name.as_bytes());
+            packet.push(reply_channel.is_some() as u8);
+            packet.extend(payload);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                reply_channel.map(crate::session::GlobalRequestResponse::Raw),
+            );
         }
     }
 
     pub fn send_keepalive(&mut self, want_reply: bool) {
-        self.open_global_requests
-            .push_back(crate::session::GlobalRequestResponse::Keepalive);
+        if let Some(ref mut enc) = self.common.encrypted {
+            let mut packet = CryptoVec::new();
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(b"keepalive@openssh.com");
+            packet.push(want_reply as u8);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                want_reply.then_some(crate::session::GlobalRequestResponse::Keepalive),
+            );
+        }
+    }
+
+    /// Sends a PING, and answers `reply_channel` with the round trip
+    /// time once the server's PONG arrives, or `None` right away if the
+    /// server doesn't support them.
+    pub(crate) fn ping(
+        &mut self,
+        data: Vec<u8>,
+        reply_channel: oneshot::Sender<Option<std::time::Duration>>,
+    ) {
+        if !self.server_ping {
+            let _ = reply_channel.send(None);
+            return;
+        }
         if let Some(ref mut enc) = self.common.encrypted {
             push_packet!(enc.write, {
-                enc.write.push(msg::GLOBAL_REQUEST);
-                enc.write.extend_ssh_string(b"keepalive@openssh.com");
-                enc.write.push(want_reply as u8);
+                enc.write.push(msg::PING);
+                enc.write.extend_ssh_string(&data);
             });
+            self.pings
+                .push_back((data, crate::runtime::now(), reply_channel));
+        }
+    }
+
+    /// Answers the oldest PING sent with the same data as this PONG.
+    pub(crate) fn pong(&mut self, buf: &[u8]) {
+        let Ok(data) = buf.reader(1).read_string() else {
+            debug!("invalid PONG");
+            return;
+        };
+        match self.pings.iter().position(|(d, _, _)| d == data) {
+            Some(i) => {
+                if let Some((_, sent, reply_channel)) = self.pings.remove(i) {
+                    let rtt = crate::runtime::now().saturating_duration_since(sent);
+                    let _ = reply_channel.send(Some(rtt));
+                }
+            }
+            None => debug!("unsolicited PONG"),
+        }
+    }
+
+    /// Sends a keepalive, and notifies `reply_channel` when the server
+    /// answers it.
+    pub(crate) fn keepalive_with_reply(&mut self, reply_channel: oneshot::Sender<()>) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            let mut packet = CryptoVec::new();
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(b"keepalive@openssh.com");
+            packet.push(1);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                Some(crate::session::GlobalRequestResponse::KeepaliveReply(
+                    reply_channel,
+                )),
+            );
         }
     }
 
@@ -346,6 +480,14 @@ impl Session {
         }
     }
 
+    /// Tells the server that we won't read more data from `channel`,
+    /// see [`Channel::end_of_write`](crate::Channel::end_of_write).
+    pub fn end_of_write(&mut self, channel: ChannelId) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.end_of_write(channel)
+        }
+    }
+
     pub fn close(&mut self, channel: ChannelId) {
         if let Some(ref mut enc) = self.common.encrypted {
             enc.close(channel)
@@ -364,13 +506,58 @@ impl Session {
 
     pub fn agent_forward(&mut self, channel: ChannelId, want_reply: bool) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"auth-agent-req@openssh.com");
-                    enc.write.push(want_reply as u8);
-                });
+            enc.channel_request(channel, b"auth-agent-req@openssh.com", want_reply, |_| {});
+        }
+    }
+
+    /// Sends a request of type `request_type` on `channel`, with
+    /// `payload` written verbatim after the `want reply` field. If
+    /// `want_reply` is set, the server's answer is delivered to the
+    /// channel as [`ChannelMsg::Success`](crate::ChannelMsg::Success)
+    /// or [`ChannelMsg::Failure`](crate::ChannelMsg::Failure).
+    pub fn channel_request(
+        &mut self,
+        channel: ChannelId,
+        request_type: &str,
+        want_reply: bool,
+        payload: &[u8],
+    ) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.channel_request(channel, request_type.as_bytes(), want_reply, |w| {
+                w.extend(payload);
+            });
+        }
+    }
+
+    /// Accept a request the server sent on `channel`, if it asked
+    /// for a reply.
+    pub fn channel_success(&mut self, channel: ChannelId) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if let Some(channel) = enc.channels.get_mut(&channel) {
+                if channel.wants_reply {
+                    channel.wants_reply = false;
+                    debug!("channel_success {:?}", channel);
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_SUCCESS);
+                        enc.write.push_u32_be(channel.recipient_channel);
+                    })
+                }
+            }
+        }
+    }
+
+    /// Refuse a request the server sent on `channel`, if it asked
+    /// for a reply.
+    pub fn channel_failure(&mut self, channel: ChannelId) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if let Some(channel) = enc.channels.get_mut(&channel) {
+                if channel.wants_reply {
+                    channel.wants_reply = false;
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_FAILURE);
+                        enc.write.push_u32_be(channel.recipient_channel);
+                    })
+                }
             }
         }
     }
@@ -379,6 +566,31 @@ impl Session {
         self.common.disconnect(reason, description, language_tag);
     }
 
+    /// See [`Handle::force_close`](super::Handle::force_close).
+    pub(crate) fn force_close(
+        &mut self,
+        id: ChannelId,
+        reason: &str,
+        reply_channel: oneshot::Sender<bool>,
+    ) {
+        info!("closing channel {:?}: {}", id, reason);
+        match self.common.encrypted {
+            Some(ref mut enc) => enc.force_close(id, reply_channel),
+            None => {
+                let _ = reply_channel.send(false);
+            }
+        }
+    }
+
+    /// Lists the channels currently open on this session, ordered by id.
+    pub fn channels_info(&self) -> Vec<crate::ChannelInfo> {
+        if let Some(ref enc) = self.common.encrypted {
+            enc.channels_info()
+        } else {
+            Vec::new()
+        }
+    }
+
     pub fn has_pending_data(&self, channel: ChannelId) -> bool {
         if let Some(ref enc) = self.common.encrypted {
             enc.has_pending_data(channel)
@@ -407,4 +619,54 @@ impl Session {
     pub fn remote_sshid(&self) -> &[u8] {
         &self.common.remote_sshid
     }
+
+    /// Returns all the SSH IDs the server sent, in order. There is more
+    /// than one only with [`Config::use_last_server_id`](super::Config::use_last_server_id), and
+    /// [`remote_sshid`](Self::remote_sshid) is then the last one.
+    pub fn remote_sshids(&self) -> &[Vec<u8>] {
+        &self.remote_sshids
+    }
+
+    /// When authentication succeeded, or `None` if the session is not
+    /// authenticated yet.
+    pub fn established_at(&self) -> Option<std::time::Instant> {
+        self.common.established_at
+    }
+
+    /// How long ago authentication succeeded, see [`Session::established_at`].
+    pub fn age(&self) -> Option<std::time::Duration> {
+        self.common
+            .established_at
+            .map(|t| crate::runtime::now().saturating_duration_since(t))
+    }
+
+    /// The rekey thresholds currently used by this session, initially
+    /// taken from the configuration.
+    pub fn limits(&self) -> &Limits {
+        &self.common.limits
+    }
+
+    /// Change the rekey thresholds for the rest of this session, for
+    /// instance to rekey more often once a bulk transfer is over.
+    ///
+    /// The byte counters are not reset, so if the new limits are
+    /// already exceeded, a key exchange starts on the next flush.
+    /// Returns [`crate::Error::InvalidLimits`] if a byte limit is zero or above
+    /// 1GB, or if the time limit is zero.
+    pub fn set_limits(&mut self, limits: Limits) -> Result<(), crate::Error> {
+        limits.check()?;
+        self.common.limits = limits;
+        Ok(())
+    }
+
+    /// Application data attached to this session, available from
+    /// every [`crate::client::Handler`] callback.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// See [`Session::extensions`].
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
 }
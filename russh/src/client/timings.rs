@@ -0,0 +1,82 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// When each step of a client's handshake happened, to find out where
+/// the time goes when connecting. See [`Handle::handshake_timings`].
+///
+/// The steps not reached yet are `None`. Use
+/// [`HandshakeTimings::elapsed`] for the durations since the start, or
+/// subtract the instants for the durations between steps.
+///
+/// [`Handle::handshake_timings`]: super::Handle::handshake_timings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTimings {
+    /// When [`connect`](super::connect) started resolving the address,
+    /// or when the connected stream was passed to
+    /// [`connect_stream`](super::connect_stream).
+    pub started: Instant,
+    /// When the TCP connection, or the proxy tunnel, was established.
+    pub tcp_connected: Option<Instant>,
+    /// When the server's identification string was received.
+    pub version_exchanged: Option<Instant>,
+    /// When the first key exchange completed.
+    pub first_kex_complete: Option<Instant>,
+    /// When the first authentication request was made.
+    pub auth_start: Option<Instant>,
+    /// When the server accepted the authentication.
+    pub auth_success: Option<Instant>,
+    /// When the server confirmed the first channel opened by the
+    /// client.
+    pub first_channel_open: Option<Instant>,
+}
+
+/// A step of [`HandshakeTimings`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Step {
+    TcpConnected,
+    VersionExchanged,
+    FirstKexComplete,
+    AuthStart,
+    AuthSuccess,
+    FirstChannelOpen,
+}
+
+impl HandshakeTimings {
+    pub(crate) fn new(started: Instant) -> Self {
+        HandshakeTimings {
+            started,
+            tcp_connected: None,
+            version_exchanged: None,
+            first_kex_complete: None,
+            auth_start: None,
+            auth_success: None,
+            first_channel_open: None,
+        }
+    }
+
+    /// The time from the start to `at`, one of the steps.
+    pub fn elapsed(&self, at: Option<Instant>) -> Option<Duration> {
+        at.map(|at| at.saturating_duration_since(self.started))
+    }
+
+    /// Records that `step` happened now, unless it happened before.
+    /// Returns whether it was recorded.
+    pub(crate) fn record(&mut self, step: Step) -> bool {
+        let at = match step {
+            Step::TcpConnected => &mut self.tcp_connected,
+            Step::VersionExchanged => &mut self.version_exchanged,
+            Step::FirstKexComplete => &mut self.first_kex_complete,
+            Step::AuthStart => &mut self.auth_start,
+            Step::AuthSuccess => &mut self.auth_success,
+            Step::FirstChannelOpen => &mut self.first_channel_open,
+        };
+        if at.is_some() {
+            return false;
+        }
+        *at = Some(crate::runtime::now());
+        true
+    }
+}
+
+/// The timings of a session, shared with its [`Handle`](super::Handle).
+pub(crate) type SharedTimings = Arc<Mutex<HandshakeTimings>>;
@@ -0,0 +1,179 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+/// Application data attached to a session, with at most one value per
+/// type. This is available from every handler callback through
+/// `Session::extensions` and `Session::extensions_mut`, and lives as
+/// long as the session.
+///
+/// Wrap values in a newtype to store several values of the same
+/// underlying type. Outside of the callbacks, the session's handle
+/// reads them through an [`ExtensionsView`].
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores a value, returning the previous value of that type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Returns the value of type `T`, inserting the result of `f` first
+    /// if there is none.
+    pub fn get_or_insert_with<T: Send + Sync + 'static, F: FnOnce() -> T>(
+        &mut self,
+        f: F,
+    ) -> &mut T {
+        let value = self
+            .map
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()));
+        // The entry for `TypeId::of::<T>()` always holds a `T`.
+        #[allow(clippy::unwrap_used)]
+        value.downcast_mut().unwrap()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// A read of a session's [`Extensions`], run by its event loop.
+pub struct ReadExtensions(Box<dyn FnOnce(&Extensions) + Send + Sync>);
+
+impl ReadExtensions {
+    pub(crate) fn run(self, extensions: &Extensions) {
+        (self.0)(extensions)
+    }
+}
+
+impl std::fmt::Debug for ReadExtensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReadExtensions").finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone)]
+enum ViewSender {
+    Client(Sender<crate::client::Msg>),
+    Server(Sender<crate::server::Msg>),
+}
+
+/// A read-only view of the [`Extensions`] of a session, from its
+/// handle, for the tasks spawned by the handler.
+///
+/// The reads are run by the session's event loop, in order with the
+/// handler callbacks: they see everything stored by the callbacks that
+/// returned before. They return `None` once the session is closed.
+#[derive(Clone)]
+pub struct ExtensionsView {
+    sender: ViewSender,
+}
+
+impl std::fmt::Debug for ExtensionsView {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtensionsView").finish_non_exhaustive()
+    }
+}
+
+impl ExtensionsView {
+    pub(crate) fn client(sender: Sender<crate::client::Msg>) -> Self {
+        ExtensionsView {
+            sender: ViewSender::Client(sender),
+        }
+    }
+
+    pub(crate) fn server(sender: Sender<crate::server::Msg>) -> Self {
+        ExtensionsView {
+            sender: ViewSender::Server(sender),
+        }
+    }
+
+    /// Runs `f` on the extensions, and returns its result.
+    pub async fn with<R, F>(&self, f: F) -> Option<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Extensions) -> R + Send + Sync + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let read = ReadExtensions(Box::new(move |extensions| {
+            let _ = tx.send(f(extensions));
+        }));
+        let sent = match &self.sender {
+            ViewSender::Client(sender) => sender
+                .send(crate::client::Msg::ReadExtensions(read))
+                .await
+                .is_ok(),
+            ViewSender::Server(sender) => sender
+                .send(crate::server::Msg::ReadExtensions(read))
+                .await
+                .is_ok(),
+        };
+        if !sent {
+            return None;
+        }
+        rx.await.ok()
+    }
+
+    /// A copy of the value of type `T`.
+    pub async fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.with(|extensions| extensions.get::<T>().cloned())
+            .await
+            .flatten()
+    }
+
+    pub async fn contains<T: Send + Sync + 'static>(&self) -> Option<bool> {
+        self.with(|extensions| extensions.contains::<T>()).await
+    }
+}
@@ -0,0 +1,489 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{Channel, ChannelId, ChannelMsg, ChannelStream};
+
+const END_OF_MESSAGE: &[u8] = b"]]>]]>";
+const END_OF_CHUNKS: &[u8] = b"\n##\n";
+/// Largest chunk size allowed by RFC 6242.
+const MAX_CHUNK_SIZE: u64 = 4294967295;
+/// Size of the write buffer above which [`Sink::poll_ready`] writes it
+/// out before accepting another message.
+const WRITE_HIGH_WATER: usize = 1 << 16;
+
+/// How messages are delimited, see [RFC 6242, section
+/// 4](https://tools.ietf.org/html/rfc6242#section-4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingMode {
+    /// Messages end with `]]>]]>`. This is used for the hello
+    /// exchange, and afterwards if either side only supports NETCONF
+    /// 1.0.
+    EndOfMessage,
+    /// Messages are sent as a sequence of length-prefixed chunks.
+    Chunked,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FramingError {
+    #[error("Invalid chunk header")]
+    InvalidChunkHeader,
+    #[error("Chunked messages must have at least one chunk")]
+    EmptyMessage,
+    #[error("Message too large")]
+    MessageTooLarge,
+    #[error("Connection closed in the middle of a message")]
+    UnexpectedEof,
+    #[error(transparent)]
+    IO(#[from] io::Error),
+}
+
+enum ChunkHeader {
+    Chunk(usize),
+    End,
+}
+
+/// Splits the bytes of a channel into messages, and joins messages
+/// back into bytes, using the framing of NETCONF over SSH ([RFC
+/// 6242](https://tools.ietf.org/html/rfc6242)).
+///
+/// The framer starts in [`FramingMode::EndOfMessage`], as required for
+/// the hello messages. Once both sides have advertised the
+/// `urn:ietf:params:netconf:base:1.1` capability, switch to
+/// [`FramingMode::Chunked`] with [`ChunkedFramer::set_mode`]: bytes
+/// received after the last message returned by the stream are decoded
+/// in the new mode.
+///
+/// After an error, the framer has lost track of the message boundaries,
+/// and the stream ends.
+pub struct ChunkedFramer<T> {
+    io: T,
+    mode: FramingMode,
+    max_chunk_size: u32,
+    max_message_size: Option<usize>,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    /// Chunks of the message being decoded.
+    message: BytesMut,
+    in_message: bool,
+    chunk_remaining: usize,
+    /// Length of `read_buf` already searched for `]]>]]>`.
+    searched: usize,
+    eof: bool,
+}
+
+impl<S> ChunkedFramer<ChannelStream<S>>
+where
+    S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static,
+{
+    /// Frames the data of `channel`, typically after a successful
+    /// `netconf` subsystem request. This works on both client and
+    /// server channels.
+    pub fn new(channel: Channel<S>) -> Self {
+        Self::from_stream(channel.into_stream())
+    }
+}
+
+impl<T> ChunkedFramer<T> {
+    /// Frames any byte stream.
+    pub fn from_stream(io: T) -> Self {
+        ChunkedFramer {
+            io,
+            mode: FramingMode::EndOfMessage,
+            max_chunk_size: u32::MAX,
+            max_message_size: None,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            message: BytesMut::new(),
+            in_message: false,
+            chunk_remaining: 0,
+            searched: 0,
+            eof: false,
+        }
+    }
+
+    /// Largest chunk sent in [`FramingMode::Chunked`], longer messages
+    /// are split. Defaults to the largest size allowed by the RFC.
+    pub fn max_chunk_size(mut self, size: u32) -> Self {
+        self.max_chunk_size = size.max(1);
+        self
+    }
+
+    /// Fail with [`FramingError::MessageTooLarge`] when receiving a
+    /// message larger than `size`, instead of buffering it. Unlimited
+    /// by default.
+    pub fn max_message_size(mut self, size: Option<usize>) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    pub fn mode(&self) -> FramingMode {
+        self.mode
+    }
+
+    /// Switches the framing of the next messages in both directions.
+    pub fn set_mode(&mut self, mode: FramingMode) {
+        self.mode = mode;
+        self.searched = 0;
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// The underlying stream. Bytes received but not decoded yet are
+    /// lost.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Ends the stream, after which it only returns `None`.
+    fn terminate(&mut self) {
+        self.eof = true;
+        self.in_message = false;
+        self.chunk_remaining = 0;
+        self.read_buf.clear();
+        self.message.clear();
+    }
+
+    fn decode(&mut self) -> Result<Option<Bytes>, FramingError> {
+        match self.mode {
+            FramingMode::EndOfMessage => self.decode_end_of_message(),
+            FramingMode::Chunked => self.decode_chunked(),
+        }
+    }
+
+    fn decode_end_of_message(&mut self) -> Result<Option<Bytes>, FramingError> {
+        // The delimiter may have started at the end of the last search.
+        let start = self.searched.saturating_sub(END_OF_MESSAGE.len() - 1);
+        let found = self.read_buf.get(start..).and_then(|buf| {
+            buf.windows(END_OF_MESSAGE.len())
+                .position(|w| w == END_OF_MESSAGE)
+        });
+        let Some(i) = found else {
+            self.searched = self.read_buf.len();
+            if let Some(max) = self.max_message_size {
+                if self.searched.saturating_sub(END_OF_MESSAGE.len() - 1) > max {
+                    return Err(FramingError::MessageTooLarge);
+                }
+            }
+            return Ok(None);
+        };
+        let mut message = self.read_buf.split_to(start + i);
+        self.read_buf.advance(END_OF_MESSAGE.len());
+        self.searched = 0;
+        // Most servers send a newline after the delimiter, which would
+        // otherwise end up before the XML declaration of the next
+        // message.
+        let whitespace = message
+            .iter()
+            .take_while(|b| b.is_ascii_whitespace())
+            .count();
+        message.advance(whitespace);
+        Ok(Some(message.freeze()))
+    }
+
+    fn decode_chunked(&mut self) -> Result<Option<Bytes>, FramingError> {
+        loop {
+            if self.chunk_remaining > 0 {
+                let n = self.chunk_remaining.min(self.read_buf.len());
+                if n == 0 {
+                    return Ok(None);
+                }
+                self.message.extend_from_slice(&self.read_buf.split_to(n));
+                self.chunk_remaining -= n;
+                continue;
+            }
+            if !self.in_message {
+                // Skip whitespace between messages, such as the newline
+                // sent after the `]]>]]>` of a hello, but keep the line
+                // feed starting the next chunk header.
+                let whitespace = self
+                    .read_buf
+                    .iter()
+                    .take_while(|b| b.is_ascii_whitespace())
+                    .count();
+                if let Some(lf) = self
+                    .read_buf
+                    .get(..whitespace)
+                    .and_then(|w| w.iter().rposition(|&b| b == b'\n'))
+                {
+                    self.read_buf.advance(lf);
+                }
+            }
+            let Some((len, header)) = parse_chunk_header(&self.read_buf)? else {
+                return Ok(None);
+            };
+            self.read_buf.advance(len);
+            match header {
+                ChunkHeader::End => {
+                    if !self.in_message {
+                        return Err(FramingError::EmptyMessage);
+                    }
+                    self.in_message = false;
+                    return Ok(Some(self.message.split().freeze()));
+                }
+                ChunkHeader::Chunk(size) => {
+                    if let Some(max) = self.max_message_size {
+                        if self.message.len().saturating_add(size) > max {
+                            return Err(FramingError::MessageTooLarge);
+                        }
+                    }
+                    self.in_message = true;
+                    self.chunk_remaining = size;
+                }
+            }
+        }
+    }
+
+    fn encode(&mut self, message: &[u8]) -> Result<(), FramingError> {
+        match self.mode {
+            FramingMode::EndOfMessage => {
+                self.write_buf.extend_from_slice(message);
+                self.write_buf.extend_from_slice(END_OF_MESSAGE);
+            }
+            FramingMode::Chunked => {
+                if message.is_empty() {
+                    return Err(FramingError::EmptyMessage);
+                }
+                for chunk in message.chunks(self.max_chunk_size as usize) {
+                    self.write_buf
+                        .extend_from_slice(format!("\n#{}\n", chunk.len()).as_bytes());
+                    self.write_buf.extend_from_slice(chunk);
+                }
+                self.write_buf.extend_from_slice(END_OF_CHUNKS);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the `LF HASH chunk-size LF` or `LF HASH HASH LF` at the
+/// start of `buf`, returning its length, or `None` if it is incomplete.
+fn parse_chunk_header(buf: &[u8]) -> Result<Option<(usize, ChunkHeader)>, FramingError> {
+    let rest = match buf {
+        [] | [b'\n'] => return Ok(None),
+        [b'\n', b'#', rest @ ..] => rest,
+        _ => return Err(FramingError::InvalidChunkHeader),
+    };
+    match rest {
+        [] | [b'#'] => Ok(None),
+        [b'#', b'\n', ..] => Ok(Some((END_OF_CHUNKS.len(), ChunkHeader::End))),
+        // Leading zeros are not allowed.
+        [b'1'..=b'9', ..] => {
+            let mut size = 0u64;
+            for (i, &b) in rest.iter().enumerate() {
+                match b {
+                    b'0'..=b'9' => {
+                        size = size * 10 + u64::from(b - b'0');
+                        if size > MAX_CHUNK_SIZE {
+                            return Err(FramingError::InvalidChunkHeader);
+                        }
+                    }
+                    b'\n' => return Ok(Some((i + 3, ChunkHeader::Chunk(size as usize)))),
+                    _ => return Err(FramingError::InvalidChunkHeader),
+                }
+            }
+            Ok(None)
+        }
+        _ => Err(FramingError::InvalidChunkHeader),
+    }
+}
+
+impl<T: AsyncWrite + Unpin> ChunkedFramer<T> {
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> Stream for ChunkedFramer<T> {
+    type Item = Result<Bytes, FramingError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.decode() {
+                Ok(Some(message)) => return Poll::Ready(Some(Ok(message))),
+                Ok(None) => {}
+                Err(e) => {
+                    this.terminate();
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+            if this.eof {
+                let clean = !this.in_message
+                    && this.chunk_remaining == 0
+                    && this.read_buf.iter().all(|b| b.is_ascii_whitespace());
+                this.terminate();
+                return Poll::Ready(if clean {
+                    None
+                } else {
+                    Some(Err(FramingError::UnexpectedEof))
+                });
+            }
+            let mut buf = [0; 8192];
+            let mut buf = ReadBuf::new(&mut buf);
+            if let Err(e) = ready!(Pin::new(&mut this.io).poll_read(cx, &mut buf)) {
+                this.terminate();
+                return Poll::Ready(Some(Err(e.into())));
+            }
+            if buf.filled().is_empty() {
+                this.eof = true;
+            } else {
+                this.read_buf.extend_from_slice(buf.filled());
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Sink<Bytes> for ChunkedFramer<T> {
+    type Error = FramingError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.write_buf.len() >= WRITE_HIGH_WATER {
+            ready!(this.poll_write_buf(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        self.get_mut().encode(&item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx).map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, clippy::panic)]
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    /// Decodes `input`, delivered a few bytes at a time.
+    async fn decode(input: &'static [u8], mode: FramingMode) -> Vec<Result<Bytes, FramingError>> {
+        let (mut tx, rx) = tokio::io::duplex(7);
+        tokio::spawn(async move { tx.write_all(input).await.unwrap() });
+        let mut framer = ChunkedFramer::from_stream(rx);
+        framer.set_mode(mode);
+        framer.collect().await
+    }
+
+    #[tokio::test]
+    async fn test_hello_then_chunked() {
+        let input = b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+<hello xmlns=\"urn:ietf:params:xml:ns:netconf:base:1.0\">\n\
+<capabilities>\n\
+<capability>urn:ietf:params:netconf:base:1.1</capability>\n\
+</capabilities>\n\
+<session-id>4</session-id>\n\
+</hello>\n\
+]]>]]>\n\
+\n#50\n<rpc-reply message-id=\"101\" xmlns=\"urn:ietf:params\n#31\n:xml:ns:netconf:base:1.0\"><ok/>\n#12\n</rpc-reply>\n##\n";
+        let (mut tx, rx) = tokio::io::duplex(7);
+        tokio::spawn(async move { tx.write_all(input).await.unwrap() });
+        let mut framer = ChunkedFramer::from_stream(rx);
+
+        let hello = framer.next().await.unwrap().unwrap();
+        assert!(hello.starts_with(b"<?xml"));
+        assert!(hello.ends_with(b"</hello>\n"));
+
+        framer.set_mode(FramingMode::Chunked);
+        let reply = framer.next().await.unwrap().unwrap();
+        assert_eq!(
+            &reply[..],
+            &b"<rpc-reply message-id=\"101\" xmlns=\"urn:ietf:params:xml:ns:netconf:base:1.0\"><ok/></rpc-reply>"[..]
+        );
+        assert!(framer.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rfc_example() {
+        // RFC 6242, section 4.2.
+        let messages = decode(
+            b"\n#4\n<rpc\n#18\n message-id=\"102\"\n\n#79\n     xmlns=\"urn:ietf:params:xml:ns:netconf:base:1.0\">\n  <close-session/>\n</rpc>\n##\n",
+            FramingMode::Chunked,
+        )
+        .await;
+        let [Ok(message)] = messages.as_slice() else {
+            panic!("{:?}", messages);
+        };
+        assert_eq!(
+            &message[..],
+            &b"<rpc message-id=\"102\"\n     xmlns=\"urn:ietf:params:xml:ns:netconf:base:1.0\">\n  <close-session/>\n</rpc>"[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_chunks() {
+        for input in [
+            &b"\n#0\nx\n##\n"[..],
+            b"\n#04\nabcd\n##\n",
+            b"\n#4294967296\n",
+            b"\n#4x\nabcd\n##\n",
+            b"#4\nabcd\n##\n",
+            b"\n#4\nabcd#\n",
+            b"\n#4\nabcd\n#",
+            b"\n#4\nabcdef",
+        ] {
+            let messages = decode(input, FramingMode::Chunked).await;
+            assert!(
+                matches!(
+                    messages.as_slice(),
+                    [Err(FramingError::InvalidChunkHeader)] | [Err(FramingError::UnexpectedEof)]
+                ),
+                "{:?}: {:?}",
+                input,
+                messages
+            );
+        }
+        let messages = decode(b"\n##\n", FramingMode::Chunked).await;
+        assert!(matches!(
+            messages.as_slice(),
+            [Err(FramingError::EmptyMessage)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_encode() {
+        let mut framer = ChunkedFramer::from_stream(Vec::new()).max_chunk_size(4);
+        framer.send(Bytes::from_static(b"<hello/>")).await.unwrap();
+        framer.set_mode(FramingMode::Chunked);
+        framer.send(Bytes::from_static(b"<rpc/>")).await.unwrap();
+        assert!(matches!(
+            framer.send(Bytes::new()).await,
+            Err(FramingError::EmptyMessage)
+        ));
+        assert_eq!(
+            framer.into_inner(),
+            b"<hello/>]]>]]>\n#4\n<rpc\n#2\n/>\n##\n"
+        );
+    }
+}
@@ -4,7 +4,9 @@ use curve25519_dalek::montgomery::MontgomeryPoint;
 use curve25519_dalek::scalar::Scalar;
 use log::debug;
 
-use super::{compute_keys, KexAlgorithm, KexType};
+use super::{
+    check_x25519_peer_value, check_x25519_shared_secret, compute_keys, KexAlgorithm, KexType,
+};
 use crate::keys::encoding::Encoding;
 use crate::mac::{self};
 use crate::session::Exchange;
@@ -56,18 +58,12 @@ impl KexAlgorithm for Curve25519Kex {
             #[allow(clippy::indexing_slicing)] // length checked
             let pubkey_len = BigEndian::read_u32(&payload[1..]) as usize;
 
-            if pubkey_len != 32 {
-                return Err(crate::Error::Kex);
-            }
-
             if payload.len() < 5 + pubkey_len {
                 return Err(crate::Error::Inconsistent);
             }
 
-            let mut pubkey = MontgomeryPoint([0; 32]);
             #[allow(clippy::indexing_slicing)] // length checked
-            pubkey.0.clone_from_slice(&payload[5..5 + 32]);
-            pubkey
+            MontgomeryPoint(check_x25519_peer_value(&payload[5..5 + pubkey_len])?)
         };
 
         let server_secret = Scalar::from_bytes_mod_order(rand::random::<[u8; 32]>());
@@ -77,6 +73,7 @@ impl KexAlgorithm for Curve25519Kex {
         exchange.server_ephemeral.clear();
         exchange.server_ephemeral.extend(&server_pubkey.0);
         let shared = server_secret * client_pubkey;
+        check_x25519_shared_secret(&shared.0)?;
         self.shared_secret = Some(shared);
         Ok(())
     }
@@ -103,9 +100,9 @@ impl KexAlgorithm for Curve25519Kex {
 
     fn compute_shared_secret(&mut self, remote_pubkey_: &[u8]) -> Result<(), crate::Error> {
         let local_secret = self.local_secret.take().ok_or(crate::Error::KexInit)?;
-        let mut remote_pubkey = MontgomeryPoint([0; 32]);
-        remote_pubkey.0.clone_from_slice(remote_pubkey_);
+        let remote_pubkey = MontgomeryPoint(check_x25519_peer_value(remote_pubkey_)?);
         let shared = local_secret * remote_pubkey;
+        check_x25519_shared_secret(&shared.0)?;
         self.shared_secret = Some(shared);
         Ok(())
     }
@@ -117,21 +117,11 @@ impl DH {
         self.shared_secret.clone()
     }
 
-    pub fn validate_shared_secret(&self, shared_secret: &BigUint) -> bool {
-        let one = BigUint::from(1u8);
-        let prime_minus_one = &self.prime_num - &one;
-
-        shared_secret > &one && shared_secret < &prime_minus_one
-    }
-
     pub fn decode_public_key(buffer: &[u8]) -> BigUint {
         BigUint::from_bytes_be(buffer)
     }
 
-    pub fn validate_public_key(&self, public_key: &BigUint) -> bool {
-        let one = BigUint::from(1u8);
-        let prime_minus_one = &self.prime_num - &one;
-
-        public_key > &one && public_key < &prime_minus_one
+    pub fn prime(&self) -> &BigUint {
+        &self.prime_num
     }
 }
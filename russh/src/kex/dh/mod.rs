@@ -10,7 +10,7 @@ use sha1::Sha1;
 use sha2::{Sha256, Sha512};
 
 use self::groups::{DhGroup, DH_GROUP1, DH_GROUP14, DH_GROUP16};
-use super::{compute_keys, KexAlgorithm, KexType};
+use super::{check_dh_peer_value, check_dh_result, compute_keys, KexAlgorithm, KexType};
 use crate::keys::encoding::Encoding;
 use crate::session::Exchange;
 use crate::{cipher, mac, msg, CryptoVec};
@@ -45,6 +45,9 @@ impl KexType for DhGroup16Sha512KexType {
     }
 }
 
+#[cfg(test)]
+pub(crate) const DH_GROUP14_PRIME: &[u8] = DH_GROUP14.prime;
+
 #[doc(hidden)]
 pub struct DhGroupKex<D: Digest> {
     dh: DH,
@@ -112,11 +115,12 @@ impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
 
         debug!("client_pubkey: {:?}", client_pubkey);
 
+        let decoded_client_pubkey = DH::decode_public_key(client_pubkey);
+        check_dh_peer_value(&decoded_client_pubkey, self.dh.prime())?;
+
         self.dh.generate_private_key(true);
         let server_pubkey = &self.dh.generate_public_key();
-        if !self.dh.validate_public_key(server_pubkey) {
-            return Err(crate::Error::Inconsistent);
-        }
+        check_dh_result(server_pubkey, self.dh.prime())?;
 
         let encoded_server_pubkey = biguint_to_mpint(server_pubkey);
 
@@ -124,15 +128,8 @@ impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
         exchange.server_ephemeral.clear();
         exchange.server_ephemeral.extend(&encoded_server_pubkey);
 
-        let decoded_client_pubkey = DH::decode_public_key(client_pubkey);
-        if !self.dh.validate_public_key(&decoded_client_pubkey) {
-            return Err(crate::Error::Inconsistent);
-        }
-
         let shared = self.dh.compute_shared_secret(decoded_client_pubkey);
-        if !self.dh.validate_shared_secret(&shared) {
-            return Err(crate::Error::Inconsistent);
-        }
+        check_dh_result(&shared, self.dh.prime())?;
         self.shared_secret = Some(biguint_to_mpint(&shared));
         Ok(())
     }
@@ -145,10 +142,7 @@ impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
     ) -> Result<(), crate::Error> {
         self.dh.generate_private_key(false);
         let client_pubkey = &self.dh.generate_public_key();
-
-        if !self.dh.validate_public_key(client_pubkey) {
-            return Err(crate::Error::Inconsistent);
-        }
+        check_dh_result(client_pubkey, self.dh.prime())?;
 
         // fill exchange.
         let encoded_pubkey = biguint_to_mpint(client_pubkey);
@@ -163,15 +157,10 @@ impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
 
     fn compute_shared_secret(&mut self, remote_pubkey_: &[u8]) -> Result<(), crate::Error> {
         let remote_pubkey = DH::decode_public_key(remote_pubkey_);
-
-        if !self.dh.validate_public_key(&remote_pubkey) {
-            return Err(crate::Error::Inconsistent);
-        }
+        check_dh_peer_value(&remote_pubkey, self.dh.prime())?;
 
         let shared = self.dh.compute_shared_secret(remote_pubkey);
-        if !self.dh.validate_shared_secret(&shared) {
-            return Err(crate::Error::Inconsistent);
-        }
+        check_dh_result(&shared, self.dh.prime())?;
         self.shared_secret = Some(biguint_to_mpint(&shared));
         Ok(())
     }
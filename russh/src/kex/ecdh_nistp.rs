@@ -97,7 +97,7 @@ where
 
             #[allow(clippy::indexing_slicing)] // length checked
             elliptic_curve::PublicKey::<C>::from_sec1_bytes(&payload[5..(5 + pubkey_len)])
-                .map_err(|_| crate::Error::Inconsistent)?
+                .map_err(|_| crate::Error::InvalidPeerValue)?
         };
 
         let server_secret =
@@ -138,7 +138,7 @@ where
     fn compute_shared_secret(&mut self, remote_pubkey_: &[u8]) -> Result<(), crate::Error> {
         let local_secret = self.local_secret.take().ok_or(crate::Error::KexInit)?;
         let pubkey = elliptic_curve::PublicKey::<C>::from_sec1_bytes(remote_pubkey_)
-            .map_err(|_| crate::Error::KexInit)?;
+            .map_err(|_| crate::Error::InvalidPeerValue)?;
         self.shared_secret = Some(local_secret.diffie_hellman(&pubkey));
         Ok(())
     }
@@ -30,13 +30,14 @@ use dh::{
 };
 use digest::Digest;
 use ecdh_nistp::{EcdhNistP256KexType, EcdhNistP384KexType, EcdhNistP521KexType};
+use num_bigint::BigUint;
 use once_cell::sync::Lazy;
 
 use crate::cipher::CIPHERS;
 use crate::keys::encoding::Encoding;
 use crate::mac::{self, MACS};
 use crate::session::Exchange;
-use crate::{cipher, CryptoVec};
+use crate::{cipher, CryptoVec, Error};
 
 pub(crate) trait KexType {
     fn make(&self) -> Box<dyn KexAlgorithm + Send>;
@@ -163,6 +164,43 @@ pub(crate) static KEXES: Lazy<HashMap<&'static Name, &(dyn KexType + Send + Sync
         h
     });
 
+// The checks of the values received from the peer and of the results,
+// shared by all the key exchanges.
+
+/// Checks a Diffie-Hellman public value received from the peer:
+/// `1 < value < prime - 1`, see
+/// [RFC4253](https://tools.ietf.org/html/rfc4253#section-8).
+pub(crate) fn check_dh_peer_value(value: &BigUint, prime: &BigUint) -> Result<(), Error> {
+    let one = BigUint::from(1u8);
+    if value <= &one || value >= &(prime - &one) {
+        return Err(Error::InvalidPeerValue);
+    }
+    Ok(())
+}
+
+/// Checks a Diffie-Hellman value computed locally, our public value
+/// or the shared secret, with the same bounds as the peer's values.
+pub(crate) fn check_dh_result(value: &BigUint, prime: &BigUint) -> Result<(), Error> {
+    check_dh_peer_value(value, prime).map_err(|_| Error::KexMath)
+}
+
+/// Checks the length of a curve25519 public value received from the
+/// peer.
+pub(crate) fn check_x25519_peer_value(value: &[u8]) -> Result<[u8; 32], Error> {
+    <[u8; 32]>::try_from(value).map_err(|_| Error::InvalidPeerValue)
+}
+
+/// Rejects the all-zero curve25519 shared secret, which a peer gets
+/// by sending a point of small order, see
+/// [RFC7748](https://tools.ietf.org/html/rfc7748#section-6.1).
+pub(crate) fn check_x25519_shared_secret(shared: &[u8; 32]) -> Result<(), Error> {
+    // Constant time.
+    if shared.iter().fold(0, |acc, b| acc | b) == 0 {
+        return Err(Error::KexMath);
+    }
+    Ok(())
+}
+
 thread_local! {
     static KEY_BUF: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
     static NONCE_BUF: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
@@ -283,3 +321,90 @@ pub(crate) fn compute_keys<D: Digest>(
         })
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg;
+
+    /// A KEX_ECDH_INIT (or KEXDH_INIT) message carrying `value`.
+    fn init_payload(value: &[u8]) -> Vec<u8> {
+        let mut payload = vec![msg::KEX_ECDH_INIT];
+        payload.extend_ssh_string(value);
+        payload
+    }
+
+    /// Feeds `value` to the server, as the client's public value, and
+    /// to the client, as the server's.
+    fn exchange(name: &Name, value: &[u8]) -> (Result<(), Error>, Result<(), Error>) {
+        #[allow(clippy::unwrap_used)]
+        let kex = KEXES.get(name).unwrap();
+        let mut server = kex.make();
+        let server = server.server_dh(&mut Exchange::new(), &init_payload(value));
+        let mut client = kex.make();
+        let (mut ephemeral, mut buf) = (CryptoVec::new(), CryptoVec::new());
+        let client = client
+            .client_dh(&mut ephemeral, &mut buf)
+            .and_then(|()| client.compute_shared_secret(value));
+        (server, client)
+    }
+
+    #[test]
+    fn test_dh_peer_values() {
+        let prime = BigUint::from_bytes_be(dh::DH_GROUP14_PRIME);
+        let one = BigUint::from(1u8);
+        for value in [
+            BigUint::from(0u8),
+            one.clone(),
+            &prime - &one,
+            prime.clone(),
+            &prime + &one,
+        ] {
+            let (server, client) = exchange(&DH_G14_SHA256, &value.to_bytes_be());
+            assert!(
+                matches!(server, Err(Error::InvalidPeerValue)),
+                "{:?}",
+                value
+            );
+            assert!(
+                matches!(client, Err(Error::InvalidPeerValue)),
+                "{:?}",
+                value
+            );
+        }
+
+        let (server, client) = exchange(&DH_G14_SHA256, &[2]);
+        assert!(server.is_ok());
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    fn test_x25519_peer_values() {
+        // The all-zero point gives an all-zero shared secret.
+        let (server, client) = exchange(&CURVE25519, &[0; 32]);
+        assert!(matches!(server, Err(Error::KexMath)));
+        assert!(matches!(client, Err(Error::KexMath)));
+
+        for value in [&[9; 31][..], &[9; 33][..], &[][..]] {
+            let (server, client) = exchange(&CURVE25519, value);
+            assert!(matches!(server, Err(Error::InvalidPeerValue)));
+            assert!(matches!(client, Err(Error::InvalidPeerValue)));
+        }
+
+        // The base point.
+        let mut base = [0; 32];
+        base[0] = 9;
+        let (server, client) = exchange(&CURVE25519, &base);
+        assert!(server.is_ok());
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    fn test_ecdh_peer_values() {
+        for value in [&[0][..], &[4; 65][..], &[][..]] {
+            let (server, client) = exchange(&ECDH_SHA2_NISTP256, value);
+            assert!(matches!(server, Err(Error::InvalidPeerValue)));
+            assert!(matches!(client, Err(Error::InvalidPeerValue)));
+        }
+    }
+}
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
+use std::collections::HashMap;
+
 use crate::keys::encoding::*;
 use crate::keys::key::*;
 use crate::keys::{ec, protocol};
@@ -75,3 +77,45 @@ pub(crate) fn write_ec_public_key(buf: &mut CryptoVec, key: &ec::PublicKey) {
     buf.extend_ssh_string(ident);
     buf.extend_ssh_string(&q);
 }
+
+/// The default lower bounds on the size of the peer's keys: RSA keys
+/// need at least 1024 bits, as in OpenSSH.
+pub(crate) fn default_minimum_key_bits() -> HashMap<KeyFamily, usize> {
+    HashMap::from([(KeyFamily::Rsa, 1024)])
+}
+
+/// The default upper bounds on the size of the peer's keys: RSA keys
+/// have at most 16384 bits, as in OpenSSH, since verifying signatures
+/// with larger keys is expensive.
+pub(crate) fn default_maximum_key_bits() -> HashMap<KeyFamily, usize> {
+    HashMap::from([(KeyFamily::Rsa, 16384)])
+}
+
+/// Checks the size of the peer's `key` against the bounds of its
+/// family, if any.
+pub(crate) fn check_key_bits(
+    key: &PublicKey,
+    minimum: &HashMap<KeyFamily, usize>,
+    maximum: &HashMap<KeyFamily, usize>,
+) -> Result<(), crate::Error> {
+    let bits = key.bit_length();
+    if let Some(&minimum) = minimum.get(&key.family()) {
+        if bits < minimum {
+            return Err(crate::Error::KeyTooWeak {
+                key_type: key.name().to_string(),
+                bits,
+                minimum,
+            });
+        }
+    }
+    if let Some(&maximum) = maximum.get(&key.family()) {
+        if bits > maximum {
+            return Err(crate::Error::KeyTooLarge {
+                key_type: key.name().to_string(),
+                bits,
+                maximum,
+            });
+        }
+    }
+    Ok(())
+}
@@ -123,9 +123,11 @@ mod sshbuffer;
 
 pub use negotiation::Preferred;
 
+mod extensions;
 mod pty;
 
-pub use pty::Pty;
+pub use extensions::{Extensions, ExtensionsView};
+pub use pty::{Pty, TerminalModes, TerminalSize};
 pub use sshbuffer::SshId;
 
 macro_rules! push_packet {
@@ -143,8 +145,54 @@ macro_rules! push_packet {
     }};
 }
 
+/// Awaits a handler callback, failing with [`Error::HandlerTimeout`]
+/// if it runs for longer than the configured `handler_timeout`. Given
+/// a session instead of an explicit timeout, the timeout is read from
+/// the session's configuration and the stalled callback is reported to
+/// the session. The explicit timeout is for server callbacks running
+/// without the session, and the stalled callback is reported to
+/// [`server::Handler::handler_timed_out`].
+macro_rules! handler_call {
+    ( timeout = $timeout:expr, $handler:ident . $callback:ident ( $($arg:expr),* $(,)? ) ) => {{
+        match crate::session::with_timeout($timeout, $handler.$callback($($arg),*)).await {
+            Ok(result) => result,
+            Err(_) => {
+                log::error!("handler callback {} timed out", stringify!($callback));
+                crate::server::Handler::handler_timed_out($handler, stringify!($callback));
+                Err(crate::Error::HandlerTimeout(stringify!($callback)).into())
+            }
+        }
+    }};
+    ( $session:ident, $handler:ident . $callback:ident ( $($arg:expr),* $(,)? ) ) => {{
+        let timeout = $session.common.config.handler_timeout;
+        match crate::session::with_timeout(timeout, $handler.$callback($($arg),*)).await {
+            Ok(result) => result,
+            Err(_) => {
+                $session.handler_timed_out($handler, stringify!($callback));
+                Err(crate::Error::HandlerTimeout(stringify!($callback)).into())
+            }
+        }
+    }};
+}
+
 mod channels;
-pub use channels::{Channel, ChannelMsg, ChannelStream};
+pub use channels::{Channel, ChannelMsg, ChannelStream, Direction};
+
+mod rate_limit;
+pub use rate_limit::{RateLimit, RateStats};
+
+#[cfg(feature = "danger-raw-packets")]
+mod raw;
+#[cfg(feature = "danger-raw-packets")]
+pub use raw::RawPacketHook;
+
+/// Message framing on top of channels.
+pub mod framing;
+
+#[cfg(feature = "recording")]
+pub mod recording;
+
+pub mod runtime;
 
 mod parsing;
 mod session;
@@ -197,6 +245,15 @@ pub enum Error {
     #[error("Key exchange failed")]
     Kex,
 
+    /// A value computed during key exchange is invalid, such as an
+    /// all-zero shared secret.
+    #[error("Invalid key exchange result")]
+    KexMath,
+
+    /// A public value sent by the peer during key exchange is invalid.
+    #[error("Invalid key exchange value from the peer")]
+    InvalidPeerValue,
+
     /// Invalid packet authentication code.
     #[error("Wrong packet authentication code")]
     PacketAuth,
@@ -217,6 +274,24 @@ pub enum Error {
     #[error("Unknown server key")]
     UnknownKey,
 
+    /// The peer's key is smaller than allowed by the
+    /// `minimum_key_bits` of the configuration.
+    #[error("{key_type} key of {bits} bits is too weak, at least {minimum} bits are required")]
+    KeyTooWeak {
+        key_type: String,
+        bits: usize,
+        minimum: usize,
+    },
+
+    /// The peer's key is larger than allowed by the
+    /// `maximum_key_bits` of the configuration.
+    #[error("{key_type} key of {bits} bits is too large, at most {maximum} bits are accepted")]
+    KeyTooLarge {
+        key_type: String,
+        bits: usize,
+        maximum: usize,
+    },
+
     /// The server provided a wrong signature.
     #[error("Wrong server signature")]
     WrongServerSig,
@@ -225,6 +300,10 @@ pub enum Error {
     #[error("Channel not open")]
     WrongChannel,
 
+    /// The peer closed the channel before all the data was sent.
+    #[error("Channel closed")]
+    ChannelClosed,
+
     /// Server refused to open a channel.
     #[error("Failed to open channel ({0:?})")]
     ChannelOpenFailure(ChannelOpenFailure),
@@ -258,6 +337,11 @@ pub enum Error {
     #[error("Inactivity timeout")]
     InactivityTimeout,
 
+    /// The session lasted for the server's
+    /// [`max_session_duration`](server::Config::max_session_duration).
+    #[error("Maximum session duration reached")]
+    SessionDurationExceeded,
+
     /// Missing authentication method.
     #[error("No authentication method")]
     NoAuthMethod,
@@ -274,9 +358,46 @@ pub enum Error {
     #[error("The request was rejected by the other party")]
     RequestDenied,
 
+    /// The server didn't announce the `ping@openssh.com` extension.
+    #[error("The server doesn't support ping@openssh.com")]
+    PingUnsupported,
+
+    /// The rekey limits are out of the allowed bounds.
+    #[error("Invalid rekey limits")]
+    InvalidLimits,
+
+    /// Too much data is waiting to be sent to the peer.
+    #[error("Write buffer overflow")]
+    WriteBufferOverflow,
+
+    /// The peer sent a packet longer than the configured
+    /// `maximum_inbound_packet_size`. Contains the packet length read
+    /// from the wire, and the limit.
+    #[error("Packet too large ({size} bytes, the limit is {limit})")]
+    PacketTooLarge { size: usize, limit: usize },
+
+    /// [`server::Handler::check_client_id`] rejected the client.
+    #[error("Client identification rejected")]
+    ClientIdRejected,
+
+    /// A handler callback took longer than the configured
+    /// `handler_timeout`. Contains the name of the callback.
+    #[error("Handler callback {0} timed out")]
+    HandlerTimeout(&'static str),
+
+    /// A packet given to `Handle::send_raw_packet` was empty, or was
+    /// a key exchange message. Contains the message type.
+    #[cfg(feature = "danger-raw-packets")]
+    #[error("Message type {0} cannot be sent as a raw packet")]
+    RawPacketRejected(u8),
+
     #[error(transparent)]
     Keys(#[from] russh_keys::Error),
 
+    /// The proxy couldn't open a connection to the server.
+    #[error(transparent)]
+    Proxy(#[from] client::ProxyError),
+
     #[error(transparent)]
     IO(#[from] std::io::Error),
 
@@ -308,6 +429,42 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// The reason sent to the peer in a DISCONNECT message when a
+    /// session ends because of this error, or `None` if the error
+    /// isn't a protocol error the peer should hear about.
+    pub fn disconnect_reason(&self) -> Option<Disconnect> {
+        match self {
+            Error::KexInit
+            | Error::Kex
+            | Error::KexMath
+            | Error::InvalidPeerValue
+            | Error::UnknownAlgo
+            | Error::NoCommonKexAlgo
+            | Error::NoCommonKeyAlgo
+            | Error::NoCommonCipher
+            | Error::NoCommonCompression
+            | Error::NoCommonMac
+            | Error::WrongServerSig => Some(Disconnect::KeyExchangeFailed),
+            Error::UnknownKey
+            | Error::KeyChanged { .. }
+            | Error::KeyTooWeak { .. }
+            | Error::KeyTooLarge { .. } => Some(Disconnect::HostKeyNotVerifiable),
+            Error::PacketAuth | Error::DecryptionError => Some(Disconnect::MACError),
+            Error::SessionDurationExceeded => Some(Disconnect::ByApplication),
+            #[cfg(feature = "flate2")]
+            Error::Compress(_) | Error::Decompress(_) => Some(Disconnect::CompressionError),
+            Error::Inconsistent
+            | Error::StrictKeyExchangeViolation { .. }
+            | Error::WrongChannel
+            | Error::IndexOutOfBounds
+            | Error::PacketTooLarge { .. }
+            | Error::NotAuthenticated => Some(Disconnect::ProtocolError),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) fn strict_kex_violation(message_type: u8, sequence_number: usize) -> crate::Error {
     debug!(
         "strict kex violated at sequence no. {:?}, message type: {:?}",
@@ -343,6 +500,35 @@ impl Limits {
             rekey_time_limit: time_limit,
         }
     }
+
+    /// Check that these limits can be used on a session: the byte limits
+    /// must be non-zero and at most 1GB (to avoid nonce reuse), and the
+    /// time limit must be non-zero.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        let bytes = 1..=(1 << 30);
+        if bytes.contains(&self.rekey_write_limit)
+            && bytes.contains(&self.rekey_read_limit)
+            && !self.rekey_time_limit.is_zero()
+        {
+            Ok(())
+        } else {
+            Err(Error::InvalidLimits)
+        }
+    }
+}
+
+/// What a session does when the data waiting to be sent to the peer
+/// exceeds the configured high-water mark, which happens when the
+/// peer reads slower than the application writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteBufferPolicy {
+    /// Stop processing messages from the session handle and channels
+    /// until the pending data drains, so that `data` and similar calls
+    /// wait instead of queuing more.
+    #[default]
+    Backpressure,
+    /// Close the connection with [`Error::WriteBufferOverflow`].
+    Disconnect,
 }
 
 impl Default for Limits {
@@ -362,7 +548,7 @@ pub use auth::{AgentAuthError, MethodSet, Signer};
 /// A reason for disconnection.
 #[allow(missing_docs)] // This should be relatively self-explanatory.
 #[allow(clippy::manual_non_exhaustive)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Disconnect {
     HostNotAllowedToConnect = 1,
     ProtocolError = 2,
@@ -411,9 +597,14 @@ impl TryFrom<u32> for Disconnect {
 /// plan to use custom signals, read [the
 /// RFC](https://tools.ietf.org/html/rfc4254#section-6.10) to
 /// understand the encoding.
+///
+/// The named variants are the signals listed in RFC 4254. Any other
+/// name (`WINCH`, BSD's `INFO`, or vendor-specific `name@domain`
+/// signals) is carried as [`Sig::Custom`], both when sending and when
+/// receiving, so that parsing a signal name never fails.
 #[allow(missing_docs)]
 // This should be relatively self-explanatory.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Sig {
     ABRT,
     ALRM,
@@ -427,6 +618,7 @@ pub enum Sig {
     SEGV,
     TERM,
     USR1,
+    USR2,
     Custom(String),
 }
 
@@ -445,28 +637,117 @@ impl Sig {
             Sig::SEGV => "SEGV",
             Sig::TERM => "TERM",
             Sig::USR1 => "USR1",
+            Sig::USR2 => "USR2",
             Sig::Custom(ref c) => c,
         }
     }
-    fn from_name(name: &[u8]) -> Result<Sig, Error> {
+
+    fn from_name(name: &[u8]) -> Sig {
+        Sig::from(&*String::from_utf8_lossy(name))
+    }
+}
+
+impl From<&str> for Sig {
+    fn from(name: &str) -> Self {
         match name {
-            b"ABRT" => Ok(Sig::ABRT),
-            b"ALRM" => Ok(Sig::ALRM),
-            b"FPE" => Ok(Sig::FPE),
-            b"HUP" => Ok(Sig::HUP),
-            b"ILL" => Ok(Sig::ILL),
-            b"INT" => Ok(Sig::INT),
-            b"KILL" => Ok(Sig::KILL),
-            b"PIPE" => Ok(Sig::PIPE),
-            b"QUIT" => Ok(Sig::QUIT),
-            b"SEGV" => Ok(Sig::SEGV),
-            b"TERM" => Ok(Sig::TERM),
-            b"USR1" => Ok(Sig::USR1),
-            x => Ok(Sig::Custom(std::str::from_utf8(x)?.to_string())),
+            "ABRT" => Sig::ABRT,
+            "ALRM" => Sig::ALRM,
+            "FPE" => Sig::FPE,
+            "HUP" => Sig::HUP,
+            "ILL" => Sig::ILL,
+            "INT" => Sig::INT,
+            "KILL" => Sig::KILL,
+            "PIPE" => Sig::PIPE,
+            "QUIT" => Sig::QUIT,
+            "SEGV" => Sig::SEGV,
+            "TERM" => Sig::TERM,
+            "USR1" => Sig::USR1,
+            "USR2" => Sig::USR2,
+            x => Sig::Custom(x.to_string()),
         }
     }
 }
 
+impl From<String> for Sig {
+    fn from(name: String) -> Self {
+        Sig::from(name.as_str())
+    }
+}
+
+impl std::fmt::Display for Sig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(unix)]
+impl Sig {
+    /// Converts a unix signal number, such as the one reported by
+    /// [`std::os::unix::process::ExitStatusExt::signal`], to the
+    /// name used on the wire. Signals outside of RFC 4254 become
+    /// [`Sig::Custom`]; returns `None` for numbers this platform does
+    /// not know.
+    pub fn from_unix(signo: i32) -> Option<Sig> {
+        UNIX_SIGNALS
+            .iter()
+            .find(|(_, n)| *n == signo)
+            .map(|(name, _)| Sig::from(*name))
+    }
+
+    /// Converts this signal to the unix signal number of the current
+    /// platform, for instance to deliver a signal received from the
+    /// client to a child process. Returns `None` for custom signals
+    /// that have no local equivalent.
+    pub fn to_unix(&self) -> Option<i32> {
+        let name = self.name();
+        UNIX_SIGNALS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, signo)| *signo)
+    }
+}
+
+#[cfg(unix)]
+static UNIX_SIGNALS: &[(&str, i32)] = &[
+    ("ABRT", libc::SIGABRT),
+    ("ALRM", libc::SIGALRM),
+    ("FPE", libc::SIGFPE),
+    ("HUP", libc::SIGHUP),
+    ("ILL", libc::SIGILL),
+    ("INT", libc::SIGINT),
+    ("KILL", libc::SIGKILL),
+    ("PIPE", libc::SIGPIPE),
+    ("QUIT", libc::SIGQUIT),
+    ("SEGV", libc::SIGSEGV),
+    ("TERM", libc::SIGTERM),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("BUS", libc::SIGBUS),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("PROF", libc::SIGPROF),
+    ("STOP", libc::SIGSTOP),
+    ("SYS", libc::SIGSYS),
+    ("TRAP", libc::SIGTRAP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("VTALRM", libc::SIGVTALRM),
+    ("WINCH", libc::SIGWINCH),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    ("INFO", libc::SIGINFO),
+];
+
 /// Reason for not being able to open a channel.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[allow(missing_docs)]
@@ -517,21 +798,79 @@ pub(crate) struct ChannelParams {
     sender_maximum_packet_size: u32,
     /// Has the other side confirmed the channel?
     pub confirmed: bool,
+    /// The channel type, as named in CHANNEL_OPEN.
+    kind: String,
+    created_at: std::time::Instant,
+    /// The window the peer announced when opening or confirming the
+    /// channel.
+    initial_window_size: u32,
+    bytes_sent: u64,
+    bytes_received: u64,
     wants_reply: bool,
+    /// Number of requests we sent on this channel with `want reply`
+    /// set, and not answered yet.
+    pending_replies: usize,
     pending_data: std::collections::VecDeque<(CryptoVec, Option<u32>, usize)>,
-    pending_eof: bool,
+    /// Messages and data written before the channel was confirmed,
+    /// or after a message that had to wait, in the order they were
+    /// written. They are sent after `pending_data`.
+    pending_messages: std::collections::VecDeque<PendingMessage>,
     pending_close: bool,
+    /// The label given locally or announced by the peer, see
+    /// [`Channel::label`].
+    label: Option<String>,
+    /// Paces the data sent, see [`server::Config::per_channel_rate`].
+    data_rate: Option<rate_limit::TokenBucket>,
+}
+
+/// An entry of [`ChannelParams::pending_messages`].
+#[derive(Debug)]
+enum PendingMessage {
+    /// Data, with its extended data type if any.
+    Data(CryptoVec, Option<u32>),
+    /// A message type and the payload after the recipient channel.
+    Message(u8, CryptoVec),
 }
 
 impl ChannelParams {
     pub fn confirm(&mut self, c: &ChannelOpenConfirmation) {
         self.recipient_channel = c.sender_channel; // "sender" is the sender of the confirmation
         self.recipient_window_size = c.initial_window_size;
+        self.initial_window_size = c.initial_window_size;
         self.recipient_maximum_packet_size = c.maximum_packet_size;
         self.confirmed = true;
     }
 }
 
+/// A snapshot of an open channel, as returned by
+/// [`server::Session::channels_info`], [`client::Session::channels_info`]
+/// and the `list_channels` methods of the handles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+    pub id: ChannelId,
+    /// The channel type, such as `"session"` or `"direct-tcpip"`.
+    pub kind: String,
+    /// When the channel was opened, or requested if we opened it.
+    pub created_at: std::time::Instant,
+    /// Channel data sent to the remote side, in bytes.
+    pub bytes_sent: u64,
+    /// Channel data received from the remote side, in bytes.
+    pub bytes_received: u64,
+    /// The window the remote side announced when the channel was opened.
+    pub initial_window_size: u32,
+    /// How many bytes the remote side currently accepts on this channel.
+    pub window_size: u32,
+    /// The largest packet the remote side accepts on this channel.
+    pub max_packet_size: u32,
+    /// Whether some data is held back, waiting for the window to grow.
+    pub has_pending_data: bool,
+    /// The label of the channel, see [`Channel::label`].
+    pub label: Option<String>,
+    /// The pacing of the data sent, if limited by
+    /// [`server::Config::per_channel_rate`].
+    pub rate: Option<RateStats>,
+}
+
 pub(crate) fn future_or_pending<F: futures::Future, T>(
     val: Option<T>,
     f: impl FnOnce(T) -> F,
@@ -0,0 +1,382 @@
+//! UMAC ([RFC 4418](https://tools.ietf.org/html/rfc4418)), as used by
+//! OpenSSH's `umac-64@openssh.com` and `umac-128@openssh.com`: the
+//! 16-byte key is an AES-128 key, the nonce is the packet's sequence
+//! number on 8 bytes, and the sequence number isn't hashed.
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use generic_array::GenericArray;
+use subtle::ConstantTimeEq;
+
+use super::{Mac, MacAlgorithm};
+
+/// UMAC with a `TAG_LEN`-byte tag, 8 or 16, in encrypt-then-MAC mode
+/// if `ETM`.
+pub struct UmacAlgorithm<const TAG_LEN: usize, const ETM: bool>;
+
+impl<const TAG_LEN: usize, const ETM: bool> MacAlgorithm for UmacAlgorithm<TAG_LEN, ETM> {
+    fn key_len(&self) -> usize {
+        16
+    }
+
+    fn make_mac(&self, key: &[u8]) -> Box<dyn Mac + Send> {
+        Box::new(Umac {
+            etm: ETM,
+            ..Umac::new(key, TAG_LEN)
+        })
+    }
+}
+
+/// The size of the L1 key, and of the message chunks hashed by NH.
+const L1_KEY_LEN: usize = 1024;
+/// `prime(36)`.
+const P36: u64 = (1 << 36) - 5;
+/// `prime(64)`.
+const P64: u64 = 0u64.wrapping_sub(59);
+/// `prime(128)`.
+const P128: u128 = 0u128.wrapping_sub(159);
+
+/// The keys of one of the `iters` independent hashes making up UHASH.
+struct Iteration {
+    /// The L1 key words, for the whole L1 key of this iteration.
+    l1: Vec<u32>,
+    /// `k64` and `k128`.
+    l2: (u64, u128),
+    l3_1: [u64; 8],
+    l3_2: u32,
+}
+
+pub struct Umac {
+    tag_len: usize,
+    etm: bool,
+    /// Encrypts the nonces into pads.
+    pdf: Aes128,
+    iterations: Vec<Iteration>,
+}
+
+impl Umac {
+    #[allow(clippy::indexing_slicing)] // the key lengths are fixed
+    pub fn new(key: &[u8], tag_len: usize) -> Self {
+        let aes = Aes128::new(GenericArray::from_slice(key));
+        let iters = tag_len / 4;
+        let l1 = kdf(&aes, 1, L1_KEY_LEN + (iters - 1) * 16);
+        let l2 = kdf(&aes, 2, iters * 24);
+        let l3_1 = kdf(&aes, 3, iters * 64);
+        let l3_2 = kdf(&aes, 4, iters * 4);
+        let iterations = (0..iters)
+            .map(|i| {
+                let l1 = &l1[i * 16..i * 16 + L1_KEY_LEN];
+                let l2 = &l2[i * 24..(i + 1) * 24];
+                let l3_1 = &l3_1[i * 64..(i + 1) * 64];
+                let mut k3 = [0; 8];
+                for (k, b) in k3.iter_mut().zip(l3_1.chunks(8)) {
+                    *k = BigEndian::read_u64(b) % P36;
+                }
+                Iteration {
+                    l1: l1.chunks(4).map(BigEndian::read_u32).collect(),
+                    l2: (
+                        BigEndian::read_u64(&l2[..8]) & 0x01ff_ffff_01ff_ffff,
+                        BigEndian::read_u128(&l2[8..]) & 0x01ff_ffff_01ff_ffff_01ff_ffff_01ff_ffff,
+                    ),
+                    l3_1: k3,
+                    l3_2: BigEndian::read_u32(&l3_2[i * 4..]),
+                }
+            })
+            .collect();
+        let pdf = Aes128::new(GenericArray::from_slice(&kdf(&aes, 0, 16)));
+        Umac {
+            tag_len,
+            etm: false,
+            pdf,
+            iterations,
+        }
+    }
+
+    /// The tag of `message` under `nonce`, an 8-byte nonce here.
+    #[allow(clippy::indexing_slicing)] // the pad is 16 bytes long
+    pub fn tag(&self, nonce: u64, message: &[u8], output: &mut [u8]) {
+        // PDF: UMAC-64 uses one half of the encrypted nonce, selected
+        // by the nonce's last bit.
+        let index = if self.tag_len == 8 { nonce & 1 } else { 0 };
+        let mut pad = GenericArray::default();
+        BigEndian::write_u64(&mut pad[..8], nonce ^ index);
+        self.pdf.encrypt_block(&mut pad);
+        let offset = index as usize * self.tag_len;
+        for (i, (out, it)) in output.chunks_mut(4).zip(&self.iterations).enumerate() {
+            let pad = BigEndian::read_u32(&pad[offset + i * 4..]);
+            BigEndian::write_u32(out, it.uhash(message) ^ pad);
+        }
+    }
+}
+
+impl Iteration {
+    /// One iteration of UHASH, giving 4 bytes of the tag.
+    fn uhash(&self, message: &[u8]) -> u32 {
+        let l1 = self.l1_hash(message);
+        let b = match l1[..] {
+            [a] if message.len() <= L1_KEY_LEN => u128::from(a),
+            _ => self.l2_hash(&l1),
+        };
+        self.l3_hash(b)
+    }
+
+    #[allow(clippy::indexing_slicing)] // chunks are at most 1024 bytes long
+    fn l1_hash(&self, message: &[u8]) -> Vec<u64> {
+        let mut y = Vec::with_capacity(message.len() / L1_KEY_LEN + 1);
+        let mut chunks = message.chunks(L1_KEY_LEN).peekable();
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_some() {
+                y.push(nh(&self.l1, chunk).wrapping_add(8 * L1_KEY_LEN as u64));
+                continue;
+            }
+            // The last chunk, zero-padded to a multiple of 32 bytes.
+            let mut padded = [0; L1_KEY_LEN];
+            padded[..chunk.len()].copy_from_slice(chunk);
+            let len = (chunk.len() + 31) / 32 * 32;
+            y.push(nh(&self.l1, &padded[..len]).wrapping_add(8 * chunk.len() as u64));
+        }
+        if y.is_empty() {
+            // NH of a single chunk of 32 zero bytes, of length 0.
+            y.push(nh(&self.l1, &[0; 32]));
+        }
+        y
+    }
+
+    fn l2_hash(&self, l1: &[u64]) -> u128 {
+        let (k64, k128) = self.l2;
+        // POLY64 on the first 2^17 bytes, that is 2^14 words.
+        let (m1, m2) = l1.split_at(l1.len().min(1 << 14));
+        let y = m1.iter().fold(1, |y, &m| poly64(k64, y, m));
+        if m2.is_empty() {
+            return u128::from(y);
+        }
+        // POLY128 on the rest, padded with 0x80 and zeros to 16 bytes.
+        let mut y = poly128(k128, 1, u128::from(y));
+        let mut words = m2.chunks_exact(2);
+        for w in &mut words {
+            if let [w0, w1] = *w {
+                y = poly128(k128, y, (u128::from(w0) << 64) | u128::from(w1));
+            }
+        }
+        let last = match words.remainder() {
+            [w] => (u128::from(*w) << 64) | (0x80 << 56),
+            _ => 0x80 << 120,
+        };
+        poly128(k128, y, last)
+    }
+
+    fn l3_hash(&self, m: u128) -> u32 {
+        let y = self.l3_1.iter().enumerate().fold(0, |y, (i, k)| {
+            y + ((m >> (112 - 16 * i)) as u64 & 0xffff) * k
+        });
+        (y % P36) as u32 ^ self.l3_2
+    }
+}
+
+/// `KDF(K, index, numbytes)`.
+#[allow(clippy::indexing_slicing)] // blocks are 16 bytes long
+fn kdf(aes: &Aes128, index: u64, len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len + 16);
+    let mut i = 1;
+    while out.len() < len {
+        let mut block = GenericArray::default();
+        BigEndian::write_u64(&mut block[..8], index);
+        BigEndian::write_u64(&mut block[8..], i);
+        aes.encrypt_block(&mut block);
+        out.extend_from_slice(&block);
+        i += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+/// NH of `message`, whose length is a multiple of 32 bytes, at most
+/// the key's. The message words are read in little-endian order.
+#[allow(clippy::indexing_slicing)] // chunks of 32 bytes and 8 words
+fn nh(key: &[u32], message: &[u8]) -> u64 {
+    let mut y = 0u64;
+    for (m, k) in message.chunks_exact(32).zip(key.chunks_exact(8)) {
+        for j in 0..4 {
+            let a = LittleEndian::read_u32(&m[4 * j..]).wrapping_add(k[j]);
+            let b = LittleEndian::read_u32(&m[4 * (j + 4)..]).wrapping_add(k[j + 4]);
+            y = y.wrapping_add(u64::from(a) * u64::from(b));
+        }
+    }
+    y
+}
+
+/// One step of `POLY(64, 2^64 - 2^32, k, M)`.
+fn poly64(k: u64, y: u64, m: u64) -> u64 {
+    let step =
+        |y: u64, m: u64| ((u128::from(k) * u128::from(y) + u128::from(m)) % u128::from(P64)) as u64;
+    if m >= 0xffff_ffff_0000_0000 {
+        step(step(y, P64 - 1), m - 59)
+    } else {
+        step(y, m)
+    }
+}
+
+/// One step of `POLY(128, 2^128 - 2^96, k, M)`.
+fn poly128(k: u128, y: u128, m: u128) -> u128 {
+    let step = |y: u128, m: u128| {
+        let (hi, lo) = mul_wide(k, y);
+        let (lo, carry) = lo.overflowing_add(m);
+        reduce128(hi + u128::from(carry), lo)
+    };
+    if m >= 0xffff_ffff_0000_0000_0000_0000_0000_0000 {
+        step(step(y, P128 - 1), m - 159)
+    } else {
+        step(y, m)
+    }
+}
+
+/// The 256-bit product of `a` and `b`, as its high and low halves.
+fn mul_wide(a: u128, b: u128) -> (u128, u128) {
+    let (a1, a0) = (a >> 64, a & u128::from(u64::MAX));
+    let (b1, b0) = (b >> 64, b & u128::from(u64::MAX));
+    let lo = a0 * b0;
+    let (mid, mid_carry) = (a1 * b0).overflowing_add(a0 * b1);
+    let (lo, lo_carry) = lo.overflowing_add(mid << 64);
+    let hi = a1 * b1 + (mid >> 64) + (u128::from(mid_carry) << 64) + u128::from(lo_carry);
+    (hi, lo)
+}
+
+/// `hi * 2^128 + lo` modulo `prime(128)`, using `2^128 = 159`.
+fn reduce128(mut hi: u128, mut lo: u128) -> u128 {
+    while hi != 0 {
+        let (h, l) = mul_wide(hi, 159);
+        let (l, carry) = lo.overflowing_add(l);
+        lo = l;
+        hi = h + u128::from(carry);
+    }
+    if lo >= P128 {
+        lo - P128
+    } else {
+        lo
+    }
+}
+
+impl Mac for Umac {
+    fn mac_len(&self) -> usize {
+        self.tag_len
+    }
+
+    fn is_etm(&self) -> bool {
+        self.etm
+    }
+
+    fn compute(&self, sequence_number: u32, payload: &[u8], output: &mut [u8]) {
+        self.tag(u64::from(sequence_number), payload, output)
+    }
+
+    fn verify(&self, sequence_number: u32, payload: &[u8], mac: &[u8]) -> bool {
+        let mut tag = [0; 16];
+        #[allow(clippy::indexing_slicing)] // tag_len is 8 or 16
+        let tag = &mut tag[..self.tag_len];
+        self.compute(sequence_number, payload, tag);
+        tag.ct_eq(mac).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"abcdefghijklmnop";
+    const NONCE: u64 = 0x6263_6465_6667_6869; // "bcdefghi"
+
+    fn tags(message: &[u8], nonce: u64) -> (String, String) {
+        let mut t64 = [0; 8];
+        Umac::new(KEY, 8).tag(nonce, message, &mut t64);
+        let mut t128 = [0; 16];
+        Umac::new(KEY, 16).tag(nonce, message, &mut t128);
+        (
+            data_encoding::HEXLOWER.encode(&t64),
+            data_encoding::HEXLOWER.encode(&t128),
+        )
+    }
+
+    /// The test vectors of RFC 4418, appendix, with the corrected
+    /// 2^25 bytes vector (erratum 2434), and UMAC-128 tags.
+    #[test]
+    fn test_vectors() {
+        let a = vec![b'a'; 1 << 25];
+        let abc = b"abc".repeat(500);
+        for (message, t64, t128) in [
+            (
+                &a[..0],
+                "6e155fad26900be1",
+                "32fedb100c79ad58f07ff7643cc60465",
+            ),
+            (
+                &a[..3],
+                "44b5cb542f220104",
+                "185e4fe905cba7bd85e4c2dc3d117d8d",
+            ),
+            (
+                &a[..1 << 10],
+                "26bf2f5d60118bd9",
+                "7a54abe04af82d60fb298c3cbd195bcb",
+            ),
+            (
+                &a[..1 << 15],
+                "27f8ef643b0d118d",
+                "7b136bd911e4b734286ef2be501f2c3c",
+            ),
+            (
+                &a[..1 << 20],
+                "a4477e87e9f55853",
+                "f8acfa3ac31cfeea047f7b115b03bef5",
+            ),
+            (
+                &a[..],
+                "faca46f856e9b45f",
+                "a621c2457c0012e64f3fdae9e7e1870c",
+            ),
+            (
+                &abc[..3],
+                "d4d7b9f6bd4fbfcf",
+                "883c3d4b97a61976ffcf232308cba5a5",
+            ),
+            (
+                &abc[..],
+                "d4cf26ddefd5c01a",
+                "8824a260c53c66a36c9260a62cb83aa1",
+            ),
+        ] {
+            assert_eq!(
+                tags(message, NONCE),
+                (t64.to_string(), t128.to_string()),
+                "message of {} bytes",
+                message.len()
+            );
+        }
+    }
+
+    /// SSH uses the sequence number as the nonce: UMAC-64 uses either
+    /// half of the same pad depending on its parity.
+    #[test]
+    fn test_sequence_numbers() {
+        let abc = b"abc".repeat(500);
+        assert_eq!(
+            tags(&abc, 0),
+            (
+                "eb6dd5fc1d89c4ed".to_string(),
+                "eb6dd5fc1d89c4edbf7dc1600ea9ee78".to_string()
+            )
+        );
+        assert_eq!(
+            tags(&abc, 1),
+            (
+                "260de4ae4afd0679".to_string(),
+                "d9fe434bff9c35c8da9b70866646ceb8".to_string()
+            )
+        );
+        let mac = UmacAlgorithm::<8, false>.make_mac(KEY);
+        let mut tag = [0; 8];
+        mac.compute(0xdead_beef, &[b'a'; 1024], &mut tag);
+        assert_eq!(data_encoding::HEXLOWER.encode(&tag), "bb6c19f3f1652a35");
+        assert!(mac.verify(0xdead_beef, &[b'a'; 1024], &tag));
+        assert!(!mac.verify(0xdead_beee, &[b'a'; 1024], &tag));
+    }
+}
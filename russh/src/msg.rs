@@ -62,6 +62,32 @@ pub const CHANNEL_REQUEST: u8 = 98;
 pub const CHANNEL_SUCCESS: u8 = 99;
 pub const CHANNEL_FAILURE: u8 = 100;
 
+// https://github.com/openssh/openssh-portable/blob/master/PROTOCOL,
+// "ping@openssh.com"
+pub const PING: u8 = 192;
+pub const PONG: u8 = 193;
+
+/// Whether russh knows message number `t`, even if it doesn't expect
+/// it at this point of the protocol. Peers sending unknown messages
+/// get an UNIMPLEMENTED reply.
+pub fn is_known(t: u8) -> bool {
+    matches!(
+        t,
+        DISCONNECT..=EXT_INFO
+            | KEXINIT
+            | NEWKEYS
+            // Key exchange method specific.
+            | 30..=49
+            | USERAUTH_REQUEST..=USERAUTH_BANNER
+            | USERAUTH_INFO_REQUEST
+            | USERAUTH_INFO_RESPONSE
+            | GLOBAL_REQUEST..=REQUEST_FAILURE
+            | CHANNEL_OPEN..=CHANNEL_FAILURE
+            | PING
+            | PONG
+    )
+}
+
 pub const SSH_OPEN_ADMINISTRATIVELY_PROHIBITED: u8 = 1;
 #[allow(dead_code)]
 pub const SSH_OPEN_CONNECT_FAILED: u8 = 2;
@@ -36,10 +36,18 @@ pub struct Names {
     pub server_compression: compression::Compression,
     pub client_compression: compression::Compression,
     pub ignore_guessed: bool,
+    /// Whether both sides prefer the same kex and host key
+    /// algorithms, i.e. whether a guessed first kex packet is right.
+    pub guess_matches: bool,
     pub strict_kex: bool,
 }
 
 /// Lists of preferred algorithms. This is normally hard-coded into implementations.
+///
+/// The presets borrow static lists, which cost nothing to copy. Lists
+/// built at run time are owned by the config, which all its sessions
+/// share through an `Arc`, so they are not copied for each connection
+/// either.
 #[derive(Debug, Clone)]
 pub struct Preferred {
     /// Preferred key exchange algorithms.
@@ -86,11 +94,15 @@ const CIPHER_ORDER: &[cipher::Name] = &[
     cipher::AES_128_CTR,
 ];
 
-const HMAC_ORDER: &[mac::Name] = &[
+const MAC_ORDER: &[mac::Name] = &[
     mac::HMAC_SHA512_ETM,
     mac::HMAC_SHA256_ETM,
+    mac::UMAC_128_ETM,
+    mac::UMAC_64_ETM,
     mac::HMAC_SHA512,
     mac::HMAC_SHA256,
+    mac::UMAC_128,
+    mac::UMAC_64,
     mac::HMAC_SHA1_ETM,
     mac::HMAC_SHA1,
 ];
@@ -109,12 +121,13 @@ impl Preferred {
         key: Cow::Borrowed(&[
             key::ED25519,
             key::ECDSA_SHA2_NISTP256,
+            key::ECDSA_SHA2_NISTP384,
             key::ECDSA_SHA2_NISTP521,
             key::RSA_SHA2_256,
             key::RSA_SHA2_512,
         ]),
         cipher: Cow::Borrowed(CIPHER_ORDER),
-        mac: Cow::Borrowed(HMAC_ORDER),
+        mac: Cow::Borrowed(MAC_ORDER),
         compression: Cow::Borrowed(COMPRESSION_ORDER),
     };
 
@@ -122,7 +135,134 @@ impl Preferred {
         kex: Cow::Borrowed(SAFE_KEX_ORDER),
         key: Preferred::DEFAULT.key,
         cipher: Cow::Borrowed(CIPHER_ORDER),
-        mac: Cow::Borrowed(HMAC_ORDER),
+        mac: Cow::Borrowed(MAC_ORDER),
+        compression: Cow::Borrowed(COMPRESSION_ORDER),
+    };
+}
+
+const MODERN_KEX_ORDER: &[kex::Name] = &[
+    kex::CURVE25519,
+    kex::CURVE25519_PRE_RFC_8731,
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+];
+
+const COMPATIBLE_KEX_ORDER: &[kex::Name] = &[
+    kex::CURVE25519,
+    kex::CURVE25519_PRE_RFC_8731,
+    kex::ECDH_SHA2_NISTP256,
+    kex::ECDH_SHA2_NISTP384,
+    kex::ECDH_SHA2_NISTP521,
+    kex::DH_G16_SHA512,
+    kex::DH_G14_SHA256,
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+];
+
+#[cfg(feature = "legacy-algorithms")]
+const LEGACY_KEX_ORDER: &[kex::Name] = &[
+    kex::CURVE25519,
+    kex::CURVE25519_PRE_RFC_8731,
+    kex::ECDH_SHA2_NISTP256,
+    kex::ECDH_SHA2_NISTP384,
+    kex::ECDH_SHA2_NISTP521,
+    kex::DH_G16_SHA512,
+    kex::DH_G14_SHA256,
+    kex::DH_G14_SHA1,
+    kex::DH_G1_SHA1,
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+];
+
+#[cfg(feature = "legacy-algorithms")]
+const LEGACY_CIPHER_ORDER: &[cipher::Name] = &[
+    cipher::CHACHA20_POLY1305,
+    cipher::AES_256_GCM,
+    cipher::AES_256_CTR,
+    cipher::AES_192_CTR,
+    cipher::AES_128_CTR,
+    cipher::AES_256_CBC,
+    cipher::AES_192_CBC,
+    cipher::AES_128_CBC,
+    cipher::TRIPLE_DES_CBC,
+];
+
+impl Preferred {
+    /// Only the most recent, best-reviewed algorithms: curve25519 key
+    /// exchange, Ed25519 keys and AEAD ciphers (ChaCha20-Poly1305 and
+    /// AES-256-GCM).
+    ///
+    /// This is the most secure choice, but it will fail to negotiate
+    /// with peers that lack these algorithms, which includes older
+    /// OpenSSH versions, many network devices and peers using RSA keys.
+    pub const MODERN: Preferred = Preferred {
+        kex: Cow::Borrowed(MODERN_KEX_ORDER),
+        key: Cow::Borrowed(&[key::ED25519]),
+        cipher: Cow::Borrowed(&[cipher::CHACHA20_POLY1305, cipher::AES_256_GCM]),
+        mac: Cow::Borrowed(&[mac::HMAC_SHA512_ETM, mac::HMAC_SHA256_ETM]),
+        compression: Cow::Borrowed(COMPRESSION_ORDER),
+    };
+
+    /// [`Preferred::MODERN`], plus widely-deployed algorithms that are
+    /// still considered safe: NIST ECDH and SHA-2 Diffie-Hellman key
+    /// exchange, ECDSA and RSA (SHA-2 signatures only) keys, AES-CTR
+    /// ciphers and SHA-2 HMACs.
+    ///
+    /// The modern algorithms are still preferred when both sides
+    /// support them. This should interoperate with any peer from the
+    /// last decade.
+    pub const COMPATIBLE: Preferred = Preferred {
+        kex: Cow::Borrowed(COMPATIBLE_KEX_ORDER),
+        key: Cow::Borrowed(&[
+            key::ED25519,
+            key::ECDSA_SHA2_NISTP256,
+            key::ECDSA_SHA2_NISTP384,
+            key::ECDSA_SHA2_NISTP521,
+            key::RSA_SHA2_256,
+            key::RSA_SHA2_512,
+        ]),
+        cipher: Cow::Borrowed(CIPHER_ORDER),
+        mac: Cow::Borrowed(&[
+            mac::HMAC_SHA512_ETM,
+            mac::HMAC_SHA256_ETM,
+            mac::HMAC_SHA512,
+            mac::HMAC_SHA256,
+        ]),
+        compression: Cow::Borrowed(COMPRESSION_ORDER),
+    };
+
+    /// [`Preferred::COMPATIBLE`], plus SHA-1 based key exchange
+    /// (`diffie-hellman-group14-sha1`, `diffie-hellman-group1-sha1`),
+    /// `ssh-rsa` (SHA-1) signatures, CBC mode ciphers including 3DES,
+    /// and SHA-1 HMACs.
+    ///
+    /// **These algorithms are weak**: SHA-1 is vulnerable to collision
+    /// attacks, the 1024-bit group1 modulus is within reach of
+    /// well-funded attackers, and CBC mode has a history of plaintext
+    /// recovery attacks in SSH. Only use this preset to talk to devices
+    /// that can't be upgraded, and preferably on trusted networks.
+    ///
+    /// Requires the `legacy-algorithms` feature.
+    #[cfg(feature = "legacy-algorithms")]
+    pub const LEGACY: Preferred = Preferred {
+        kex: Cow::Borrowed(LEGACY_KEX_ORDER),
+        key: Cow::Borrowed(&[
+            key::ED25519,
+            key::ECDSA_SHA2_NISTP256,
+            key::ECDSA_SHA2_NISTP384,
+            key::ECDSA_SHA2_NISTP521,
+            key::RSA_SHA2_256,
+            key::RSA_SHA2_512,
+            key::SSH_RSA,
+        ]),
+        cipher: Cow::Borrowed(LEGACY_CIPHER_ORDER),
+        mac: Cow::Borrowed(MAC_ORDER),
         compression: Cow::Borrowed(COMPRESSION_ORDER),
     };
 }
@@ -133,6 +273,80 @@ impl Default for Preferred {
     }
 }
 
+#[cfg(feature = "legacy-algorithms")]
+impl Preferred {
+    /// These algorithms, followed by those of [`Preferred::LEGACY`]
+    /// that are missing.
+    pub(crate) fn with_legacy(&self) -> Preferred {
+        fn widen<T: Clone + PartialEq>(a: &[T], b: &[T]) -> Cow<'static, [T]> {
+            let mut all = a.to_vec();
+            all.extend(b.iter().filter(|x| !a.contains(x)).cloned());
+            Cow::Owned(all)
+        }
+        Preferred {
+            kex: widen(&self.kex, &Preferred::LEGACY.kex),
+            key: widen(&self.key, &Preferred::LEGACY.key),
+            cipher: widen(&self.cipher, &Preferred::LEGACY.cipher),
+            mac: widen(&self.mac, &Preferred::LEGACY.mac),
+            compression: self.compression.clone(),
+        }
+    }
+}
+
+/// Whether `remote_id` is the identification string of an
+/// implementation too old to negotiate anything but the algorithms of
+/// [`Preferred::LEGACY`]: OpenSSH before 7.2 and Dropbear before
+/// 2020.79 only sign with `ssh-rsa` (SHA-1) host keys, and lack SHA-2
+/// Diffie-Hellman key exchange.
+pub(crate) fn is_legacy_peer(remote_id: &[u8]) -> bool {
+    let Ok(remote_id) = from_utf8(remote_id) else {
+        return false;
+    };
+    let software = remote_id
+        .split_once('-')
+        .and_then(|(_, rest)| rest.split_once('-'))
+        .map_or("", |(_, software)| software);
+    let version = |prefix: &str| -> Option<(u32, u32)> {
+        let version = software.strip_prefix(prefix)?;
+        let mut numbers = version
+            .split(|c: char| !c.is_ascii_digit())
+            .map(|n| n.parse().ok());
+        Some((numbers.next()??, numbers.next().flatten().unwrap_or(0)))
+    };
+    if let Some(v) = version("OpenSSH_") {
+        v < (7, 2)
+    } else if let Some(v) = version("dropbear_") {
+        // Versions were numbered 0.x before 2011.
+        v < (2020, 79)
+    } else {
+        false
+    }
+}
+
+#[test]
+fn test_is_legacy_peer() {
+    for id in [
+        "SSH-2.0-OpenSSH_4.3",
+        "SSH-2.0-OpenSSH_5.3p1 Debian-3ubuntu7",
+        "SSH-2.0-OpenSSH_7.1",
+        "SSH-2.0-dropbear_0.52",
+        "SSH-2.0-dropbear_2019.78",
+    ] {
+        assert!(is_legacy_peer(id.as_bytes()), "{}", id);
+    }
+    for id in [
+        "SSH-2.0-OpenSSH_7.2",
+        "SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13",
+        "SSH-2.0-dropbear_2020.79",
+        "SSH-2.0-dropbear",
+        "SSH-2.0-russh_0.44.0",
+        "SSH-2.0-OpenSSH",
+        "garbage",
+    ] {
+        assert!(!is_legacy_peer(id.as_bytes()), "{}", id);
+    }
+}
+
 /// Named algorithms.
 pub trait Named {
     /// The name of this algorithm.
@@ -298,6 +512,7 @@ pub(crate) trait Select {
             server_compression,
             // Ignore the next packet if (1) it follows and (2) it's not the correct guess.
             ignore_guessed: follows && !(kex_both_first && key_both_first),
+            guess_matches: kex_both_first && key_both_first,
             strict_kex: strict_kex_requested && strict_kex_provided,
         })
     }
@@ -348,6 +563,7 @@ pub fn write_kex(
     prefs: &Preferred,
     buf: &mut CryptoVec,
     server_config: Option<&Config>,
+    first_kex_packet_follows: bool,
 ) -> Result<(), Error> {
     // buf.clear();
     buf.push(msg::KEXINIT);
@@ -394,7 +610,7 @@ pub fn write_kex(
     buf.write_empty_list(); // languages client to server
     buf.write_empty_list(); // languagesserver to client
 
-    buf.push(0); // doesn't follow
+    buf.push(first_kex_packet_follows as u8);
     buf.extend(&[0, 0, 0, 0]); // reserved
     Ok(())
 }
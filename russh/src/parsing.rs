@@ -97,6 +97,20 @@ pub enum ChannelType {
     },
 }
 
+impl ChannelType {
+    /// The channel type, as named in CHANNEL_OPEN.
+    pub fn name(&self) -> String {
+        match self {
+            ChannelType::Session => "session".into(),
+            ChannelType::X11 { .. } => "x11".into(),
+            ChannelType::DirectTcpip(_) => "direct-tcpip".into(),
+            ChannelType::ForwardedTcpIp(_) => "forwarded-tcpip".into(),
+            ChannelType::AgentForward => "auth-agent@openssh.com".into(),
+            ChannelType::Unknown { typ } => String::from_utf8_lossy(typ).into_owned(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TcpChannelInfo {
     pub host_to_connect: String,
@@ -132,3 +132,387 @@ impl Pty {
         }
     }
 }
+
+/// The size of a pseudo-terminal, in characters and in pixels. The
+/// pixel dimensions may be left to zero.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub col_width: u32,
+    pub row_height: u32,
+    pub pix_width: u32,
+    pub pix_height: u32,
+}
+
+impl TerminalSize {
+    /// A terminal of `col_width` columns and `row_height` rows.
+    pub fn new(col_width: u32, row_height: u32) -> Self {
+        TerminalSize {
+            col_width,
+            row_height,
+            pix_width: 0,
+            pix_height: 0,
+        }
+    }
+}
+
+/// The terminal modes sent with a pseudo-terminal request, as an
+/// ordered list of opcodes and values (RFC 4254, section 8).
+///
+/// Modes that aren't set are left to the server's defaults, which
+/// vary: for an interactive session, send the local terminal's modes
+/// with [`TerminalModes::from_current_tty`], or set at least the ones
+/// a program depends on.
+///
+/// ```
+/// use russh::TerminalModes;
+///
+/// let modes = TerminalModes::new()
+///     .icanon(true)
+///     .echo(true)
+///     .isig(true)
+///     .veof(4) // ^D
+///     .speeds(38400, 38400);
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TerminalModes(Vec<(Pty, u32)>);
+
+impl TerminalModes {
+    /// The value disabling a special character.
+    pub const DISABLED: u8 = 255;
+
+    /// No modes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `opcode` to `value`, replacing its previous value.
+    /// [`Pty::TTY_OP_END`] is ignored, it is added when the modes are
+    /// sent.
+    pub fn set(mut self, opcode: Pty, value: u32) -> Self {
+        if opcode == Pty::TTY_OP_END {
+            return self;
+        }
+        match self
+            .0
+            .binary_search_by_key(&(opcode as u8), |(p, _)| *p as u8)
+        {
+            #[allow(clippy::indexing_slicing)] // returned by the search
+            Ok(i) => self.0[i].1 = value,
+            Err(i) => self.0.insert(i, (opcode, value)),
+        }
+        self
+    }
+
+    /// The value of `opcode`, if set.
+    pub fn get(&self, opcode: Pty) -> Option<u32> {
+        self.0.iter().find(|(p, _)| *p == opcode).map(|(_, v)| *v)
+    }
+
+    /// The modes, ordered by opcode.
+    pub fn as_slice(&self) -> &[(Pty, u32)] {
+        &self.0
+    }
+
+    fn flag(self, opcode: Pty, on: bool) -> Self {
+        self.set(opcode, on as u32)
+    }
+
+    /// Echo input characters.
+    pub fn echo(self, on: bool) -> Self {
+        self.flag(Pty::ECHO, on)
+    }
+
+    /// Canonical mode: input is edited and sent line by line.
+    pub fn icanon(self, on: bool) -> Self {
+        self.flag(Pty::ICANON, on)
+    }
+
+    /// Generate signals from the INTR, QUIT and SUSP characters.
+    pub fn isig(self, on: bool) -> Self {
+        self.flag(Pty::ISIG, on)
+    }
+
+    /// Extended input processing, such as the LNEXT character.
+    pub fn iexten(self, on: bool) -> Self {
+        self.flag(Pty::IEXTEN, on)
+    }
+
+    /// Output flow control with the START and STOP characters.
+    pub fn ixon(self, on: bool) -> Self {
+        self.flag(Pty::IXON, on)
+    }
+
+    /// Translate carriage returns to newlines on input.
+    pub fn icrnl(self, on: bool) -> Self {
+        self.flag(Pty::ICRNL, on)
+    }
+
+    /// Input is UTF-8, for character erasure in canonical mode.
+    pub fn iutf8(self, on: bool) -> Self {
+        self.flag(Pty::IUTF8, on)
+    }
+
+    /// Output processing, needed by `onlcr`.
+    pub fn opost(self, on: bool) -> Self {
+        self.flag(Pty::OPOST, on)
+    }
+
+    /// Translate newlines to CR-LF on output.
+    pub fn onlcr(self, on: bool) -> Self {
+        self.flag(Pty::ONLCR, on)
+    }
+
+    /// Eight-bit characters.
+    pub fn cs8(self, on: bool) -> Self {
+        self.flag(Pty::CS8, on)
+    }
+
+    /// The input and output speeds, in bits per second.
+    pub fn speeds(self, input: u32, output: u32) -> Self {
+        self.set(Pty::TTY_OP_ISPEED, input)
+            .set(Pty::TTY_OP_OSPEED, output)
+    }
+
+    /// The interrupt character, usually ^C.
+    pub fn vintr(self, c: u8) -> Self {
+        self.set(Pty::VINTR, c.into())
+    }
+
+    /// The quit character, usually ^\.
+    pub fn vquit(self, c: u8) -> Self {
+        self.set(Pty::VQUIT, c.into())
+    }
+
+    /// The erase character, usually DEL or ^H.
+    pub fn verase(self, c: u8) -> Self {
+        self.set(Pty::VERASE, c.into())
+    }
+
+    /// The character erasing the current line, usually ^U.
+    pub fn vkill(self, c: u8) -> Self {
+        self.set(Pty::VKILL, c.into())
+    }
+
+    /// The end-of-file character, usually ^D.
+    pub fn veof(self, c: u8) -> Self {
+        self.set(Pty::VEOF, c.into())
+    }
+
+    /// The suspend character, usually ^Z.
+    pub fn vsusp(self, c: u8) -> Self {
+        self.set(Pty::VSUSP, c.into())
+    }
+}
+
+impl AsRef<[(Pty, u32)]> for TerminalModes {
+    fn as_ref(&self) -> &[(Pty, u32)] {
+        &self.0
+    }
+}
+
+#[cfg(all(unix, feature = "tty"))]
+impl TerminalModes {
+    /// The modes of the terminal on the standard input, as OpenSSH
+    /// sends them. Call this before putting the local terminal in raw
+    /// mode: the remote pseudo-terminal then does the line editing and
+    /// signal generation, and the local one passes keystrokes through.
+    ///
+    /// Fails if the standard input is not a terminal.
+    pub fn from_current_tty() -> std::io::Result<Self> {
+        let mut tio = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: `tcgetattr` initializes `tio` when it succeeds.
+        let tio = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, tio.as_mut_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            tio.assume_init()
+        };
+        Ok(tty::modes(&tio))
+    }
+}
+
+#[cfg(all(unix, feature = "tty"))]
+mod tty {
+    use super::{Pty, TerminalModes};
+
+    const CHARS: &[(Pty, usize)] = &[
+        (Pty::VINTR, libc::VINTR),
+        (Pty::VQUIT, libc::VQUIT),
+        (Pty::VERASE, libc::VERASE),
+        (Pty::VKILL, libc::VKILL),
+        (Pty::VEOF, libc::VEOF),
+        (Pty::VEOL, libc::VEOL),
+        (Pty::VEOL2, libc::VEOL2),
+        (Pty::VSTART, libc::VSTART),
+        (Pty::VSTOP, libc::VSTOP),
+        (Pty::VSUSP, libc::VSUSP),
+        (Pty::VREPRINT, libc::VREPRINT),
+        (Pty::VWERASE, libc::VWERASE),
+        (Pty::VLNEXT, libc::VLNEXT),
+        (Pty::VDISCARD, libc::VDISCARD),
+        #[cfg(any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "dragonfly"
+        ))]
+        (Pty::VDSUSP, libc::VDSUSP),
+        #[cfg(any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "dragonfly"
+        ))]
+        (Pty::VSTATUS, libc::VSTATUS),
+    ];
+
+    const IFLAGS: &[(Pty, libc::tcflag_t)] = &[
+        (Pty::IGNPAR, libc::IGNPAR),
+        (Pty::PARMRK, libc::PARMRK),
+        (Pty::INPCK, libc::INPCK),
+        (Pty::ISTRIP, libc::ISTRIP),
+        (Pty::INLCR, libc::INLCR),
+        (Pty::IGNCR, libc::IGNCR),
+        (Pty::ICRNL, libc::ICRNL),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (Pty::IUCLC, libc::IUCLC),
+        (Pty::IXON, libc::IXON),
+        (Pty::IXANY, libc::IXANY),
+        (Pty::IXOFF, libc::IXOFF),
+        (Pty::IMAXBEL, libc::IMAXBEL),
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+        (Pty::IUTF8, libc::IUTF8),
+    ];
+
+    const LFLAGS: &[(Pty, libc::tcflag_t)] = &[
+        (Pty::ISIG, libc::ISIG),
+        (Pty::ICANON, libc::ICANON),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (Pty::XCASE, libc::XCASE),
+        (Pty::ECHO, libc::ECHO),
+        (Pty::ECHOE, libc::ECHOE),
+        (Pty::ECHOK, libc::ECHOK),
+        (Pty::ECHONL, libc::ECHONL),
+        (Pty::NOFLSH, libc::NOFLSH),
+        (Pty::TOSTOP, libc::TOSTOP),
+        (Pty::IEXTEN, libc::IEXTEN),
+        (Pty::ECHOCTL, libc::ECHOCTL),
+        (Pty::ECHOKE, libc::ECHOKE),
+        (Pty::PENDIN, libc::PENDIN),
+    ];
+
+    const OFLAGS: &[(Pty, libc::tcflag_t)] = &[
+        (Pty::OPOST, libc::OPOST),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (Pty::OLCUC, libc::OLCUC),
+        (Pty::ONLCR, libc::ONLCR),
+        (Pty::OCRNL, libc::OCRNL),
+        (Pty::ONOCR, libc::ONOCR),
+        (Pty::ONLRET, libc::ONLRET),
+    ];
+
+    pub(super) fn modes(tio: &libc::termios) -> TerminalModes {
+        let mut modes = TerminalModes::new();
+        // SAFETY: `tio` is a valid termios.
+        let (ispeed, ospeed) = unsafe { (libc::cfgetispeed(tio), libc::cfgetospeed(tio)) };
+        modes = modes.speeds(baud(ispeed), baud(ospeed));
+        for &(opcode, i) in CHARS {
+            if let Some(&c) = tio.c_cc.get(i) {
+                let c = if c == libc::_POSIX_VDISABLE {
+                    TerminalModes::DISABLED
+                } else {
+                    c
+                };
+                modes = modes.set(opcode, c.into());
+            }
+        }
+        for (flags, table) in [
+            (tio.c_iflag, IFLAGS),
+            (tio.c_lflag, LFLAGS),
+            (tio.c_oflag, OFLAGS),
+        ] {
+            for &(opcode, bit) in table {
+                modes = modes.set(opcode, (flags & bit != 0) as u32);
+            }
+        }
+        let cflag = tio.c_cflag;
+        modes
+            .set(Pty::CS7, (cflag & libc::CSIZE == libc::CS7) as u32)
+            .set(Pty::CS8, (cflag & libc::CSIZE == libc::CS8) as u32)
+            .set(Pty::PARENB, (cflag & libc::PARENB != 0) as u32)
+            .set(Pty::PARODD, (cflag & libc::PARODD != 0) as u32)
+    }
+
+    /// The speed in bits per second: Linux encodes speeds as `B*`
+    /// constants, the BSDs directly.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn baud(speed: libc::speed_t) -> u32 {
+        const SPEEDS: &[(libc::speed_t, u32)] = &[
+            (libc::B0, 0),
+            (libc::B50, 50),
+            (libc::B75, 75),
+            (libc::B110, 110),
+            (libc::B134, 134),
+            (libc::B150, 150),
+            (libc::B200, 200),
+            (libc::B300, 300),
+            (libc::B600, 600),
+            (libc::B1200, 1200),
+            (libc::B1800, 1800),
+            (libc::B2400, 2400),
+            (libc::B4800, 4800),
+            (libc::B9600, 9600),
+            (libc::B19200, 19200),
+            (libc::B38400, 38400),
+            (libc::B57600, 57600),
+            (libc::B115200, 115200),
+            (libc::B230400, 230400),
+            (libc::B460800, 460800),
+            (libc::B921600, 921600),
+        ];
+        SPEEDS
+            .iter()
+            .find(|(s, _)| *s == speed)
+            .map_or(9600, |(_, baud)| *baud)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn baud(speed: libc::speed_t) -> u32 {
+        speed as u32
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        #[allow(clippy::indexing_slicing)] // VEOF and VINTR are within NCCS
+        fn termios_to_modes() {
+            // SAFETY: termios is plain data, all-zero is a valid value.
+            let mut tio: libc::termios = unsafe { std::mem::zeroed() };
+            tio.c_lflag = libc::ICANON | libc::ECHO;
+            tio.c_cflag = libc::CS8;
+            tio.c_cc[libc::VEOF] = 4;
+            tio.c_cc[libc::VINTR] = libc::_POSIX_VDISABLE;
+            // SAFETY: `tio` is a valid termios.
+            unsafe {
+                libc::cfsetispeed(&mut tio, libc::B38400);
+                libc::cfsetospeed(&mut tio, libc::B38400);
+            }
+            let modes = modes(&tio);
+            assert_eq!(modes.get(Pty::ICANON), Some(1));
+            assert_eq!(modes.get(Pty::ECHO), Some(1));
+            assert_eq!(modes.get(Pty::ISIG), Some(0));
+            assert_eq!(modes.get(Pty::CS8), Some(1));
+            assert_eq!(modes.get(Pty::PARENB), Some(0));
+            assert_eq!(modes.get(Pty::VEOF), Some(4));
+            assert_eq!(modes.get(Pty::VINTR), Some(255));
+            assert_eq!(modes.get(Pty::TTY_OP_ISPEED), Some(38400));
+            assert_eq!(modes.get(Pty::TTY_OP_OSPEED), Some(38400));
+        }
+    }
+}
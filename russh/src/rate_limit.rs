@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// A bandwidth cap, see [`Channel::set_rate_limit`](crate::Channel::set_rate_limit),
+/// the `rate_limit` field of the client and server configurations, and
+/// the [`per_channel_rate`](crate::server::Config::per_channel_rate) of
+/// servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained rate, in bytes per second.
+    pub bytes_per_sec: u32,
+    /// Number of bytes that can be sent at once after an idle period.
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u32, burst: u32) -> Self {
+        RateLimit {
+            bytes_per_sec,
+            burst,
+        }
+    }
+}
+
+/// Token bucket enforcing a [`RateLimit`]. Writers wait until the
+/// balance is non-negative, then write and [`consume`](Self::consume)
+/// what they wrote, which may bring the balance below zero, so a
+/// single write never needs to be split to fit the bucket.
+///
+/// The channel data paced by [`per_channel_rate`](crate::server::Config::per_channel_rate)
+/// never takes the balance below zero instead: what does not fit waits
+/// with the data the peer's window has no room for, until
+/// [`available`](Self::available) allows it.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+    bytes_sent: u64,
+    window_start: Instant,
+    window_bytes: u64,
+    last_rate: u64,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        // A zero burst would never let anything through.
+        let burst = f64::from(limit.burst.max(1));
+        let now = Instant::now();
+        TokenBucket {
+            limit,
+            rate: f64::from(limit.bytes_per_sec.max(1)),
+            burst,
+            tokens: burst,
+            last: now,
+            bytes_sent: 0,
+            window_start: now,
+            window_bytes: 0,
+            last_rate: 0,
+        }
+    }
+
+    /// Largest write allowed at once.
+    pub fn burst(&self) -> usize {
+        self.burst as usize
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Time to wait before the next write.
+    pub fn delay(&mut self) -> Duration {
+        self.refill();
+        if self.tokens >= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+        self.bytes_sent += bytes as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.last_rate = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+        self.window_bytes += bytes as u64;
+    }
+
+    /// Sleeps until the next write is allowed.
+    pub async fn wait(&mut self) {
+        let delay = self.delay();
+        if !delay.is_zero() {
+            crate::runtime::sleep(delay).await
+        }
+    }
+
+    /// Number of bytes that can be written now without going below zero.
+    pub fn available(&self) -> usize {
+        let elapsed = self.last.elapsed().as_secs_f64();
+        (self.tokens + elapsed * self.rate).min(self.burst).max(0.) as usize
+    }
+
+    /// Time to wait until `bytes` are [`available`](Self::available),
+    /// or the whole burst if `bytes` is larger.
+    pub fn delay_for(&mut self, bytes: usize) -> Duration {
+        self.refill();
+        let missing = (bytes as f64).min(self.burst) - self.tokens;
+        if missing <= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    pub fn stats(&self) -> RateStats {
+        let elapsed = self.window_start.elapsed();
+        let bytes_per_sec = if elapsed >= Duration::from_secs(1) {
+            (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64
+        } else {
+            self.last_rate
+        };
+        RateStats {
+            limit: self.limit,
+            bytes_sent: self.bytes_sent,
+            bytes_per_sec,
+            available: self.available() as u64,
+        }
+    }
+}
+
+/// Statistics of a [`RateLimit`], see [`ChannelInfo::rate`](crate::ChannelInfo::rate)
+/// and [`Handle::rate_limit_stats`](crate::server::Handle::rate_limit_stats).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateStats {
+    /// The limit in force.
+    pub limit: RateLimit,
+    /// Bytes sent since the limit applies.
+    pub bytes_sent: u64,
+    /// Rate of the bytes sent during the last second or so, in bytes
+    /// per second.
+    pub bytes_per_sec: u64,
+    /// Bytes that can be sent right away.
+    pub available: u64,
+}
@@ -0,0 +1,56 @@
+//! Raw packet access, for conformance testing and experimental
+//! protocol extensions.
+//!
+//! Nothing here is checked against the protocol state machine: a
+//! hand-crafted packet is sent exactly as given, and a consumed
+//! inbound packet is never seen by the session. Getting either wrong
+//! will confuse the peer, or russh itself.
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{msg, Error};
+
+type HookFn = dyn Fn(u8, &[u8]) -> bool + Send + Sync;
+
+/// Inspects inbound packets before the session dispatches them.
+///
+/// The hook is called with the message type and the rest of the
+/// payload, after decryption and decompression. If it returns `true`,
+/// the packet is consumed and the session ignores it; otherwise it is
+/// processed as usual. Key exchange packets are never passed to the
+/// hook.
+#[derive(Clone)]
+pub struct RawPacketHook(Arc<HookFn>);
+
+impl RawPacketHook {
+    pub fn new<F: Fn(u8, &[u8]) -> bool + Send + Sync + 'static>(f: F) -> Self {
+        RawPacketHook(Arc::new(f))
+    }
+
+    /// Offers `buf`, a whole packet payload, to the hook.
+    pub(crate) fn consume(&self, buf: &[u8]) -> bool {
+        match buf.split_first() {
+            Some((msg_type, payload)) => (self.0)(*msg_type, payload),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for RawPacketHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RawPacketHook")
+    }
+}
+
+/// Checks that `payload` may be sent as a raw packet. Key exchange
+/// messages are refused, since the session would not know a key
+/// exchange is in progress.
+pub(crate) fn check_payload(payload: &[u8]) -> Result<(), Error> {
+    match payload.first() {
+        None => Err(Error::RawPacketRejected(0)),
+        Some(&t) if t == msg::KEXINIT || t == msg::NEWKEYS || (30..=49).contains(&t) => {
+            Err(Error::RawPacketRejected(t))
+        }
+        Some(_) => Ok(()),
+    }
+}
@@ -0,0 +1,303 @@
+//! Recording the output of terminal sessions, with its timing, in the
+//! [asciicast v2](https://docs.asciinema.org/manual/asciicast/v2/) or
+//! `ttyrec` format, to be played back with `asciinema play` or
+//! `ttyplay`.
+//!
+//! A [`SessionRecorder`] is fed from the handler's `on_channel_data`
+//! hook, and told about the terminal's size changes:
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use std::io::BufWriter;
+//!
+//! use russh::recording::{RecordingFormat, SessionRecorder};
+//! use russh::server::{Handler, Session};
+//! use russh::{ChannelId, Direction, TerminalSize};
+//!
+//! struct Server {
+//!     recorder: SessionRecorder<BufWriter<File>>,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl Handler for Server {
+//!     type Error = russh::Error;
+//!
+//!     fn on_channel_data(&mut self, direction: Direction, channel: ChannelId, data: &[u8]) {
+//!         if let Err(e) = self.recorder.on_channel_data(direction, channel, data) {
+//!             log::error!("recording failed: {}", e);
+//!         }
+//!     }
+//!
+//!     async fn window_change_request(
+//!         &mut self,
+//!         channel: ChannelId,
+//!         col_width: u32,
+//!         row_height: u32,
+//!         _: u32,
+//!         _: u32,
+//!         session: &mut Session,
+//!     ) -> Result<(), Self::Error> {
+//!         self.recorder
+//!             .resize(TerminalSize::new(col_width, row_height))?;
+//!         session.channel_success(channel);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let file = BufWriter::new(File::create("session.cast")?);
+//! let recorder =
+//!     SessionRecorder::new(file, RecordingFormat::Asciicast, TerminalSize::new(80, 24))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Recording writes synchronously from the session's task, so a slow
+//! writer slows the session down: give it a buffered writer.
+use std::io::{self, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{ChannelId, Direction, TerminalSize};
+
+/// The format of a recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingFormat {
+    /// asciicast v2: a JSON header, then one JSON event per line, with
+    /// the time since the start of the recording. Resizes are `"r"`
+    /// events.
+    Asciicast,
+    /// ttyrec: frames of raw output, each with its time. ttyrec has no
+    /// resize events, so resizes are recorded as the xterm escape
+    /// sequence resizing the terminal.
+    Ttyrec,
+}
+
+/// Records the output of a terminal session to a writer, see the
+/// [module documentation](self).
+pub struct SessionRecorder<W: Write> {
+    writer: W,
+    format: RecordingFormat,
+    start: Instant,
+    direction: Direction,
+    channel: Option<ChannelId>,
+    /// The end of the output recorded last, if it cuts a UTF-8
+    /// character in two, since asciicast events are text.
+    partial: Vec<u8>,
+}
+
+impl<W: Write> SessionRecorder<W> {
+    /// Starts a recording of a terminal of `size`, writing the header
+    /// of asciicast recordings.
+    ///
+    /// By default, the data sent on any channel is recorded, which is
+    /// the output of the terminal for a server. See
+    /// [`direction`](Self::direction) and [`channel`](Self::channel).
+    pub fn new(mut writer: W, format: RecordingFormat, size: TerminalSize) -> io::Result<Self> {
+        if format == RecordingFormat::Asciicast {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or(0);
+            writeln!(
+                writer,
+                "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}}}",
+                size.col_width, size.row_height, timestamp
+            )?;
+        }
+        Ok(SessionRecorder {
+            writer,
+            format,
+            start: crate::runtime::now(),
+            direction: Direction::Outbound,
+            channel: None,
+            partial: Vec::new(),
+        })
+    }
+
+    /// Records the data going in `direction` instead: inbound for a
+    /// client recording the output of the server's terminal.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Records the data of `channel` only.
+    pub fn channel(mut self, channel: ChannelId) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Records `data` if it is output of the recorded channel, to be
+    /// called from the handler's `on_channel_data` hook.
+    pub fn on_channel_data(
+        &mut self,
+        direction: Direction,
+        channel: ChannelId,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if direction != self.direction || self.channel.map_or(false, |c| c != channel) {
+            return Ok(());
+        }
+        self.output(data)
+    }
+
+    /// Records `data` as output of the terminal.
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.format {
+            RecordingFormat::Asciicast => {
+                self.partial.extend_from_slice(data);
+                let valid = match std::str::from_utf8(&self.partial) {
+                    Ok(s) => s.len(),
+                    // Keep an incomplete character for the next output.
+                    Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                    Err(_) => self.partial.len(),
+                };
+                if valid == 0 {
+                    return Ok(());
+                }
+                let rest = self.partial.split_off(valid);
+                let text = String::from_utf8_lossy(&self.partial).into_owned();
+                self.partial = rest;
+                self.event("o", &text)
+            }
+            RecordingFormat::Ttyrec => self.frame(data),
+        }
+    }
+
+    /// Records that the terminal was resized to `size`.
+    pub fn resize(&mut self, size: TerminalSize) -> io::Result<()> {
+        match self.format {
+            RecordingFormat::Asciicast => {
+                self.event("r", &format!("{}x{}", size.col_width, size.row_height))
+            }
+            RecordingFormat::Ttyrec => {
+                self.frame(format!("\x1b[8;{};{}t", size.row_height, size.col_width).as_bytes())
+            }
+        }
+    }
+
+    /// Writes the buffered recording out.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Ends the recording, returning the writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        if !self.partial.is_empty() {
+            let text = String::from_utf8_lossy(&self.partial).into_owned();
+            self.event("o", &text)?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        let time = crate::runtime::now()
+            .saturating_duration_since(self.start)
+            .as_secs_f64();
+        write!(self.writer, "[{:.6}, \"{}\", \"", time, code)?;
+        for c in data.chars() {
+            match c {
+                '"' => self.writer.write_all(b"\\\"")?,
+                '\\' => self.writer.write_all(b"\\\\")?,
+                '\n' => self.writer.write_all(b"\\n")?,
+                '\r' => self.writer.write_all(b"\\r")?,
+                '\t' => self.writer.write_all(b"\\t")?,
+                c if (c as u32) < 0x20 || c == '\x7f' => {
+                    write!(self.writer, "\\u{:04x}", c as u32)?
+                }
+                c => write!(self.writer, "{}", c)?,
+            }
+        }
+        self.writer.write_all(b"\"]\n")
+    }
+
+    fn frame(&mut self, data: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.writer
+            .write_all(&(now.as_secs() as u32).to_le_bytes())?;
+        self.writer.write_all(&now.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, clippy::indexing_slicing)]
+    use std::convert::TryInto;
+
+    use super::*;
+
+    #[test]
+    fn test_asciicast() {
+        let mut recorder = SessionRecorder::new(
+            Vec::new(),
+            RecordingFormat::Asciicast,
+            TerminalSize::new(80, 24),
+        )
+        .unwrap()
+        .channel(ChannelId(2));
+        recorder
+            .on_channel_data(
+                Direction::Outbound,
+                ChannelId(2),
+                b"$ ls\r\n\"a\"\t\x1b[0m\xc3",
+            )
+            .unwrap();
+        // Neither input nor other channels are recorded.
+        recorder
+            .on_channel_data(Direction::Inbound, ChannelId(2), b"ls\r")
+            .unwrap();
+        recorder
+            .on_channel_data(Direction::Outbound, ChannelId(3), b"other")
+            .unwrap();
+        recorder.resize(TerminalSize::new(100, 30)).unwrap();
+        // The end of the character cut in two.
+        recorder
+            .on_channel_data(Direction::Outbound, ChannelId(2), b"\xa9\xff")
+            .unwrap();
+        let recording = String::from_utf8(recorder.into_inner().unwrap()).unwrap();
+
+        let lines: Vec<_> = recording.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", recording);
+        assert!(lines[0].starts_with("{\"version\": 2, \"width\": 80, \"height\": 24, "));
+        let event = |line: &str| line.split_once(", ").unwrap().1.to_string();
+        assert_eq!(
+            event(lines[1]),
+            "\"o\", \"$ ls\\r\\n\\\"a\\\"\\t\\u001b[0m\"]"
+        );
+        assert_eq!(event(lines[2]), "\"r\", \"100x30\"]");
+        assert_eq!(event(lines[3]), "\"o\", \"\u{e9}\u{fffd}\"]");
+    }
+
+    #[test]
+    fn test_ttyrec() {
+        let mut recorder = SessionRecorder::new(
+            Vec::new(),
+            RecordingFormat::Ttyrec,
+            TerminalSize::new(80, 24),
+        )
+        .unwrap()
+        .direction(Direction::Inbound);
+        recorder
+            .on_channel_data(Direction::Inbound, ChannelId(0), b"hello")
+            .unwrap();
+        recorder
+            .on_channel_data(Direction::Outbound, ChannelId(0), b"input")
+            .unwrap();
+        recorder.resize(TerminalSize::new(100, 30)).unwrap();
+        let recording = recorder.into_inner().unwrap();
+
+        let mut frames = Vec::new();
+        let mut rest = &recording[..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            frames.push(&rest[12..12 + len]);
+            rest = &rest[12 + len..];
+        }
+        assert_eq!(frames, [&b"hello"[..], &b"\x1b[8;30;100t"[..]]);
+    }
+}
@@ -0,0 +1,304 @@
+//! The little russh needs from an async runtime: spawning tasks and
+//! waiting for timers.
+//!
+//! Sessions run on Tokio with the `runtime-tokio` feature (the
+//! default), or on async-std with `runtime-async-std`. With both,
+//! Tokio is used from within a Tokio runtime, and async-std elsewhere.
+//! Tokio's channels and I/O traits are used either way, since they
+//! don't need a Tokio runtime: [`Compat`] adapts the streams
+//! implementing the `futures-io` traits instead, such as async-std's
+//! `TcpStream`. The helpers connecting sockets themselves, such as
+//! [`client::connect`](crate::client::connect), still need Tokio.
+//!
+//! Other executors can run the session tasks through a [`Spawner`],
+//! set in the `spawner` field of the client and server
+//! configurations.
+//!
+//! On Tokio, the timeouts, keepalives and timestamps of the sessions
+//! all follow Tokio's clock: tests can stop it with
+//! `tokio::time::pause` and move it on with `tokio::time::advance`
+//! instead of waiting.
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, Either};
+use tokio::io::ReadBuf;
+use tokio::sync::oneshot;
+
+type SpawnFn = dyn Fn(BoxFuture<'static, ()>) + Send + Sync;
+
+/// Runs the tasks started by a session, such as the session itself,
+/// on a custom executor.
+///
+/// ```ignore
+/// let config = russh::client::Config {
+///     spawner: Some(Spawner::new(|task| smol::spawn(task).detach())),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct Spawner(Arc<SpawnFn>);
+
+impl Spawner {
+    pub fn new<F: Fn(BoxFuture<'static, ()>) + Send + Sync + 'static>(f: F) -> Self {
+        Spawner(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for Spawner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Spawner")
+    }
+}
+
+/// What russh needs from a runtime.
+trait Runtime {
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+}
+
+#[cfg(any(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+struct Tokio;
+
+#[cfg(any(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+impl Runtime for Tokio {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(feature = "runtime-async-std")]
+struct AsyncStd;
+
+#[cfg(feature = "runtime-async-std")]
+impl Runtime for AsyncStd {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        async_std::task::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+/// Whether to use Tokio rather than async-std. Without any runtime
+/// feature, Tokio is used.
+#[cfg(any(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+fn use_tokio() -> bool {
+    cfg!(not(feature = "runtime-async-std")) || tokio::runtime::Handle::try_current().is_ok()
+}
+
+#[allow(unreachable_code)]
+fn current() -> &'static dyn Runtime {
+    #[cfg(any(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+    if use_tokio() {
+        return &Tokio;
+    }
+    #[cfg(feature = "runtime-async-std")]
+    return &AsyncStd;
+    #[cfg(not(feature = "runtime-async-std"))]
+    &Tokio
+}
+
+/// Starts `future` in the background, on `spawner` if there is one.
+pub(crate) fn spawn<F>(spawner: Option<&Spawner>, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    match spawner {
+        Some(spawner) => (spawner.0)(Box::pin(future)),
+        None => current().spawn(Box::pin(future)),
+    }
+}
+
+/// Like [`spawn`] without a spawner, but does nothing outside of a
+/// Tokio runtime when Tokio would be used, as in destructors running
+/// after the runtime is gone.
+pub(crate) fn spawn_if_running<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(any(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+    if use_tokio() && tokio::runtime::Handle::try_current().is_err() {
+        return;
+    }
+    spawn(None, future)
+}
+
+/// Like [`spawn`], but the result of `future` can be awaited.
+pub(crate) fn spawn_task<F>(spawner: Option<&Spawner>, future: F) -> Task<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(any(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+    if spawner.is_none() && use_tokio() {
+        return Task::Tokio(tokio::spawn(future));
+    }
+    let (sender, receiver) = oneshot::channel();
+    spawn(spawner, async move {
+        let _ = sender.send(future.await);
+    });
+    Task::Spawned(receiver)
+}
+
+/// A task started by [`spawn_task`].
+pub(crate) enum Task<T> {
+    #[cfg(any(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+    Tokio(tokio::task::JoinHandle<T>),
+    Spawned(oneshot::Receiver<T>),
+}
+
+impl<T> Future for Task<T> {
+    type Output = Result<T, crate::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.get_mut() {
+            #[cfg(any(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+            Task::Tokio(join) => Pin::new(join).poll(cx).map_err(crate::Error::Join),
+            // The task was dropped by its executor.
+            Task::Spawned(receiver) => Pin::new(receiver)
+                .poll(cx)
+                .map_err(|_| crate::Error::Disconnect),
+        }
+    }
+}
+
+/// A timer, which can be restarted.
+pub(crate) struct Sleep(Pin<Box<dyn Future<Output = ()> + Send + Sync>>);
+
+impl Sleep {
+    /// Restarts the timer, to complete after `duration` from now.
+    pub fn reset(&mut self, duration: Duration) {
+        *self = sleep(duration)
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    Sleep(current().sleep(duration))
+}
+
+/// The current time, from Tokio's clock so that tests pausing it
+/// (`tokio::time::pause`) control all the timing of the sessions. It
+/// is the system's monotonic clock otherwise.
+pub(crate) fn now() -> std::time::Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+/// The error returned by [`timeout`].
+#[derive(Debug)]
+pub(crate) struct Elapsed;
+
+/// Awaits `future`, giving up after `duration`.
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    futures::pin_mut!(future);
+    match futures::future::select(future, sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+/// Adapts streams between the Tokio I/O traits and the `futures-io`
+/// ones, in both directions: sessions can be run on a stream from
+/// async-std, and a [`ChannelStream`](crate::ChannelStream) can be used
+/// where `futures-io` traits are expected.
+#[derive(Debug)]
+pub struct Compat<T>(T);
+
+impl<T> Compat<T> {
+    pub fn new(inner: T) -> Self {
+        Compat(inner)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.0
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: futures::io::AsyncRead + Unpin> tokio::io::AsyncRead for Compat<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = ready!(Pin::new(&mut self.0).poll_read(cx, buf.initialize_unfilled()))?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: futures::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for Compat<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+impl<T: tokio::io::AsyncRead + Unpin> futures::io::AsyncRead for Compat<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(buf);
+        ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl<T: tokio::io::AsyncWrite + Unpin> futures::io::AsyncWrite for Compat<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,104 @@
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{run_stream_with_peer_addr, Config, Handler, RunningSession};
+
+/// Accepts connections without starting their sessions, for servers
+/// that run each session somewhere else: on another thread or
+/// runtime, or in a child process with fewer privileges.
+///
+/// Unlike [`Server::run_on_socket`](super::Server::run_on_socket),
+/// nothing is sent to the client before [`PendingConnection::run`],
+/// so the whole SSH protocol, including the version exchange and key
+/// exchange, runs wherever the session ends up.
+#[derive(Debug)]
+pub struct Acceptor {
+    listener: TcpListener,
+    config: Arc<Config>,
+}
+
+impl Acceptor {
+    pub fn new(listener: TcpListener, config: Arc<Config>) -> Self {
+        Acceptor { listener, config }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for the next connection.
+    pub async fn accept(&self) -> std::io::Result<PendingConnection> {
+        let (stream, peer_addr) = self.listener.accept().await?;
+        Ok(PendingConnection {
+            stream: stream.into_std()?,
+            peer_addr: Some(peer_addr),
+            config: self.config.clone(),
+        })
+    }
+}
+
+/// A connection accepted by an [`Acceptor`], whose session hasn't
+/// started yet.
+///
+/// The socket is detached from the runtime it was accepted on, so this
+/// can be moved to any thread and run on another runtime. On unix, the
+/// file descriptor can also be passed to another process (see
+/// [`PendingConnection::into_std`]), which rebuilds the connection with
+/// [`PendingConnection::from_std`] and its own [`Config`], after
+/// dropping privileges for instance.
+#[derive(Debug)]
+pub struct PendingConnection {
+    stream: std::net::TcpStream,
+    peer_addr: Option<SocketAddr>,
+    config: Arc<Config>,
+}
+
+impl PendingConnection {
+    /// Wraps a connected socket, for instance one inherited from a
+    /// parent process.
+    pub fn from_std(stream: std::net::TcpStream, config: Arc<Config>) -> Self {
+        PendingConnection {
+            peer_addr: stream.peer_addr().ok(),
+            stream,
+            config,
+        }
+    }
+
+    /// The socket, and nothing else: the configuration (with its host
+    /// keys) stays behind.
+    pub fn into_std(self) -> std::net::TcpStream {
+        self.stream
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    /// Starts the session with `handler` on the current runtime, as
+    /// [`run_stream_with_peer_addr`] does.
+    pub async fn run<H: Handler + Send + 'static>(
+        self,
+        handler: H,
+    ) -> Result<RunningSession<H>, H::Error> {
+        self.stream
+            .set_nonblocking(true)
+            .map_err(crate::Error::from)?;
+        let stream = TcpStream::from_std(self.stream).map_err(crate::Error::from)?;
+        run_stream_with_peer_addr(self.config, stream, self.peer_addr, handler).await
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for PendingConnection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
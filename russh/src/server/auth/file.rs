@@ -0,0 +1,681 @@
+//! Public key and certificate authentication the way `sshd` does it,
+//! from OpenSSH `authorized_keys` and `AuthorizedPrincipalsFile` files.
+//!
+//! Plain keys are accepted if they appear in one of the user's
+//! `authorized_keys` files. Certificates are accepted if they are
+//! signed by one of the trusted user CA keys (with principals checked
+//! against the authorized principals file, or against the user name if
+//! there is none), or by a `cert-authority` key of the user's
+//! `authorized_keys` files.
+//!
+//! Key options (`command=`, `no-pty`...) aren't enforced here, except
+//! `from=`. The options of the matching line are exposed in
+//! [`AuthInfo::key_options`](crate::server::AuthInfo::key_options) once
+//! [`FileAuth::auth_succeeded`] has been called, which also restricts
+//! the session's [`ForwardingPolicy`] with `no-port-forwarding`,
+//! `permitopen=` and `permitlisten=`.
+//!
+//! ```no_run
+//! use russh::server::auth::FileAuth;
+//! use russh::server::{Auth, Handler, Session};
+//! use russh_keys::key;
+//!
+//! struct Client {
+//!     auth: FileAuth,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl Handler for Client {
+//!     type Error = russh::Error;
+//!
+//!     async fn auth_publickey(
+//!         &mut self,
+//!         user: &str,
+//!         public_key: &key::PublicKey,
+//!     ) -> Result<Auth, Self::Error> {
+//!         Ok(self.auth.auth_publickey(user, public_key))
+//!     }
+//!
+//!     async fn auth_openssh_certificate(
+//!         &mut self,
+//!         user: &str,
+//!         certificate: &ssh_key::Certificate,
+//!     ) -> Result<Auth, Self::Error> {
+//!         Ok(self.auth.auth_openssh_certificate(user, certificate))
+//!     }
+//!
+//!     async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+//!         self.auth.auth_succeeded(session);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let auth = FileAuth::new(["/home/%u/.ssh/authorized_keys"]);
+//! ```
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use russh_keys::key;
+use russh_keys::PublicKeyBase64;
+use ssh_key::certificate::CertType;
+use ssh_key::{Certificate, HashAlg};
+
+use crate::server::{Auth, ForwardPattern, ForwardingPolicy, Session};
+
+const REJECT: Auth = Auth::Reject {
+    proceed_with_methods: None,
+};
+
+/// The options of the `authorized_keys` line (or principals line, or
+/// certificate) a client authenticated with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyOptions {
+    /// `command=`, or the `force-command` option of a certificate: the
+    /// command to run instead of whatever the client requested.
+    pub command: Option<String>,
+    /// `environment=` variables, as `NAME=value`.
+    pub environment: Vec<String>,
+    /// `permitopen=` destinations (`host:port`). Empty if unrestricted.
+    pub permit_open: Vec<String>,
+    /// `permitlisten=` addresses (`[host:]port`). Empty if unrestricted.
+    pub permit_listen: Vec<String>,
+    pub no_port_forwarding: bool,
+    pub no_agent_forwarding: bool,
+    pub no_x11_forwarding: bool,
+    pub no_pty: bool,
+    pub no_user_rc: bool,
+}
+
+impl KeyOptions {
+    /// The options of a certificate: permissions come from its
+    /// extensions and are denied by default.
+    fn from_certificate(cert: &Certificate) -> Self {
+        let ext = cert.extensions();
+        KeyOptions {
+            no_port_forwarding: !ext.contains_key("permit-port-forwarding"),
+            no_agent_forwarding: !ext.contains_key("permit-agent-forwarding"),
+            no_x11_forwarding: !ext.contains_key("permit-X11-forwarding"),
+            no_pty: !ext.contains_key("permit-pty"),
+            no_user_rc: !ext.contains_key("permit-user-rc"),
+            ..KeyOptions::default()
+        }
+    }
+
+    /// Adds the restrictions of `other`. Fails if both force a
+    /// different command.
+    fn merge(&mut self, other: KeyOptions) -> Option<()> {
+        match (&self.command, other.command) {
+            (Some(a), Some(b)) if *a != b => return None,
+            (_, Some(b)) => self.command = Some(b),
+            _ => {}
+        }
+        self.environment.extend(other.environment);
+        self.permit_open.extend(other.permit_open);
+        self.permit_listen.extend(other.permit_listen);
+        self.no_port_forwarding |= other.no_port_forwarding;
+        self.no_agent_forwarding |= other.no_agent_forwarding;
+        self.no_x11_forwarding |= other.no_x11_forwarding;
+        self.no_pty |= other.no_pty;
+        self.no_user_rc |= other.no_user_rc;
+        Some(())
+    }
+}
+
+/// Options of a line, including the ones only used for matching.
+#[derive(Debug, Default)]
+struct LineOptions {
+    options: KeyOptions,
+    from: Option<String>,
+    principals: Option<String>,
+    cert_authority: bool,
+}
+
+/// Checks public keys and certificates against OpenSSH files, see the
+/// [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct FileAuth {
+    authorized_keys: Vec<String>,
+    authorized_principals: Option<String>,
+    trusted_user_ca_keys: Vec<ssh_key::PublicKey>,
+    peer_addr: Option<IpAddr>,
+    options: Option<KeyOptions>,
+}
+
+impl FileAuth {
+    /// Looks up keys in the `authorized_keys` files at `paths`, where
+    /// `%u` is replaced by the user name (and `%%` by `%`), for
+    /// instance `/home/%u/.ssh/authorized_keys`.
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(paths: I) -> Self {
+        FileAuth {
+            authorized_keys: paths.into_iter().map(Into::into).collect(),
+            authorized_principals: None,
+            trusted_user_ca_keys: Vec::new(),
+            peer_addr: None,
+            options: None,
+        }
+    }
+
+    /// The client address, checked against `from=` options and the
+    /// `source-address` of certificates. Lines with a `from=` option
+    /// never match without it.
+    pub fn peer_addr(mut self, peer_addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = peer_addr.map(|a| match a.ip() {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+            ip => ip,
+        });
+        self
+    }
+
+    /// CA keys trusted to sign user certificates for any user, as
+    /// `TrustedUserCAKeys` in `sshd_config`.
+    pub fn trusted_user_ca_keys<I: IntoIterator<Item = ssh_key::PublicKey>>(
+        mut self,
+        keys: I,
+    ) -> Self {
+        self.trusted_user_ca_keys = keys.into_iter().collect();
+        self
+    }
+
+    /// Principals allowed for certificates signed by the trusted user
+    /// CA keys, one per line (possibly preceded by options), as
+    /// `AuthorizedPrincipalsFile` in `sshd_config`. The path is
+    /// expanded like the `authorized_keys` paths. Without it, the
+    /// certificate must list the user name as a principal.
+    pub fn authorized_principals_file<S: Into<String>>(mut self, path: S) -> Self {
+        self.authorized_principals = Some(path.into());
+        self
+    }
+
+    /// The options of the last successful check.
+    pub fn options(&self) -> Option<&KeyOptions> {
+        self.options.as_ref()
+    }
+
+    /// Call this from
+    /// [`Handler::auth_succeeded`](crate::server::Handler::auth_succeeded)
+    /// to record the options of the last successful check in the
+    /// session's [`AuthInfo`](crate::server::AuthInfo), and restrict
+    /// its forwarding policy with them.
+    pub fn auth_succeeded(&mut self, session: &mut Session) {
+        let options = self.options.take();
+        if let Some(ref options) = options {
+            session
+                .forwarding_policy
+                .restrict(ForwardingPolicy::from_key_options(options));
+        }
+        if let Some(auth_info) = session.auth_info.as_mut() {
+            auth_info.key_options = options;
+        }
+    }
+
+    /// Call this from
+    /// [`Handler::auth_publickey`](crate::server::Handler::auth_publickey).
+    /// Accepts keys listed (without `cert-authority`) in one of the
+    /// user's `authorized_keys` files.
+    pub fn auth_publickey(&mut self, user: &str, public_key: &key::PublicKey) -> Auth {
+        self.options = None;
+        let Ok(public_key) = ssh_key::PublicKey::from_bytes(&public_key.public_key_bytes()) else {
+            return REJECT;
+        };
+        for (line, key) in self.authorized_keys(user) {
+            if !line.cert_authority
+                && key.key_data() == public_key.key_data()
+                && self.check_from(&line)
+            {
+                self.options = Some(line.options);
+                return Auth::Accept;
+            }
+        }
+        debug!("no authorized_keys line for this key");
+        REJECT
+    }
+
+    /// Call this from
+    /// [`Handler::auth_openssh_certificate`](crate::server::Handler::auth_openssh_certificate).
+    /// Accepts valid user certificates signed by a trusted user CA key,
+    /// or by a `cert-authority` key of the user's `authorized_keys`
+    /// files, for one of the allowed principals.
+    pub fn auth_openssh_certificate(&mut self, user: &str, certificate: &Certificate) -> Auth {
+        self.options = None;
+        let Some(mut options) = self.check_certificate(certificate) else {
+            return REJECT;
+        };
+        let principals = certificate.valid_principals();
+        // Empty principals would mean "anyone".
+        if principals.is_empty() {
+            debug!("certificate has no principals");
+            return REJECT;
+        }
+
+        if self
+            .trusted_user_ca_keys
+            .iter()
+            .any(|ca| is_signed_by(certificate, ca))
+        {
+            let line_options = match &self.authorized_principals {
+                Some(path) => self.authorized_principals(path, user, principals),
+                None if principals.iter().any(|p| p == user) => Some(KeyOptions::default()),
+                None => None,
+            };
+            if let Some(line_options) = line_options {
+                if options.merge(line_options).is_some() {
+                    self.options = Some(options);
+                    return Auth::Accept;
+                }
+            }
+        }
+
+        for (line, key) in self.authorized_keys(user) {
+            if !line.cert_authority || !is_signed_by(certificate, &key) || !self.check_from(&line) {
+                continue;
+            }
+            let allowed = match &line.principals {
+                Some(allowed) => principals
+                    .iter()
+                    .any(|p| allowed.split(',').any(|a| a == p)),
+                None => principals.iter().any(|p| p == user),
+            };
+            if allowed && options.merge(line.options).is_some() {
+                self.options = Some(options);
+                return Auth::Accept;
+            }
+        }
+        debug!("certificate not authorized for {:?}", user);
+        REJECT
+    }
+
+    /// Checks the type and critical options of a certificate, and
+    /// returns the options it grants.
+    fn check_certificate(&self, certificate: &Certificate) -> Option<KeyOptions> {
+        if certificate.cert_type() != CertType::User {
+            debug!("not a user certificate");
+            return None;
+        }
+        let mut options = KeyOptions::from_certificate(certificate);
+        for (name, value) in certificate.critical_options().iter() {
+            match name.as_str() {
+                "force-command" => options.command = Some(value.clone()),
+                "source-address" => {
+                    let allowed = self.peer_addr.map_or(false, |ip| {
+                        value
+                            .split(',')
+                            .any(|cidr| match_cidr(cidr, ip) == Some(true))
+                    });
+                    if !allowed {
+                        debug!("source address not allowed by the certificate");
+                        return None;
+                    }
+                }
+                name => {
+                    debug!("unknown critical option {:?}", name);
+                    return None;
+                }
+            }
+        }
+        Some(options)
+    }
+
+    fn check_from(&self, line: &LineOptions) -> bool {
+        let Some(ref from) = line.from else {
+            return true;
+        };
+        let allowed = self
+            .peer_addr
+            .map_or(false, |ip| match_pattern_list(from, ip));
+        if !allowed {
+            debug!("peer address not allowed by from={:?}", from);
+        }
+        allowed
+    }
+
+    /// Parsed lines of all the `authorized_keys` files of `user`.
+    fn authorized_keys(&self, user: &str) -> Vec<(LineOptions, ssh_key::PublicKey)> {
+        let mut keys = Vec::new();
+        for path in &self.authorized_keys {
+            let Some(file) = read_file(path, user) else {
+                continue;
+            };
+            keys.extend(file.lines().filter_map(parse_authorized_key));
+        }
+        keys
+    }
+
+    /// Options of the first line of the principals file of `user`
+    /// naming one of `principals`.
+    fn authorized_principals(
+        &self,
+        path: &str,
+        user: &str,
+        principals: &[String],
+    ) -> Option<KeyOptions> {
+        let file = read_file(path, user)?;
+        for line in file.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // The principal is the last word, anything before it is options.
+            let (options, principal) = match line.rfind(|c| c == ' ' || c == '\t') {
+                Some(i) => (line.get(..i), line.get(i..).unwrap_or("").trim_start()),
+                None => (None, line),
+            };
+            if !principals.iter().any(|p| p == principal) {
+                continue;
+            }
+            let options = match options {
+                Some(options) => parse_options(options.trim_end())?,
+                None => LineOptions::default(),
+            };
+            if self.check_from(&options) {
+                return Some(options.options);
+            }
+        }
+        None
+    }
+}
+
+/// Whether `certificate` is signed by `ca` and valid now.
+fn is_signed_by(certificate: &Certificate, ca: &ssh_key::PublicKey) -> bool {
+    if certificate.signature_key() != ca.key_data() {
+        return false;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let fingerprint = ca.fingerprint(HashAlg::Sha256);
+    match certificate.validate_at(now, std::iter::once(&fingerprint)) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("invalid certificate: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Reads the file at the expansion of `template`.
+fn read_file(template: &str, user: &str) -> Option<String> {
+    // The user name becomes part of a path.
+    if user.is_empty() || user.contains('/') || user == "." || user == ".." {
+        return None;
+    }
+    let path = expand_path(template, user);
+    match std::fs::read_to_string(&path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            debug!("could not read {:?}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+fn expand_path(template: &str, user: &str) -> String {
+    let mut path = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') => path.push_str(user),
+            Some('%') => path.push('%'),
+            Some(c) => {
+                path.push('%');
+                path.push(c)
+            }
+            None => path.push('%'),
+        }
+    }
+    path
+}
+
+/// Parses a line of `authorized_keys`: optional options, then the key
+/// type, the base64 key and an optional comment.
+fn parse_authorized_key(line: &str) -> Option<(LineOptions, ssh_key::PublicKey)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    if let Ok(key) = ssh_key::PublicKey::from_openssh(line) {
+        return Some((LineOptions::default(), key));
+    }
+    let (options, key) = split_options(line)?;
+    let options = parse_options(options)?;
+    match ssh_key::PublicKey::from_openssh(key.trim_start()) {
+        Ok(key) => Some((options, key)),
+        Err(e) => {
+            debug!("invalid authorized_keys line: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Splits a line at the first whitespace outside quotes.
+fn split_options(line: &str) -> Option<(&str, &str)> {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ' ' | '\t' if !quoted => return Some((line.get(..i)?, line.get(i..)?)),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses comma-separated options. Unknown or malformed options make
+/// the whole line invalid, as in `sshd`.
+fn parse_options(s: &str) -> Option<LineOptions> {
+    let mut line = LineOptions::default();
+    for option in split_unquoted(s)? {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(unquote(value)?)),
+            None => (option, None),
+        };
+        let options = &mut line.options;
+        match (name.to_ascii_lowercase().as_str(), value) {
+            ("command", Some(v)) => options.command = Some(v),
+            ("environment", Some(v)) if v.contains('=') => options.environment.push(v),
+            ("permitopen", Some(v)) if ForwardPattern::open(&v).is_some() => {
+                options.permit_open.push(v)
+            }
+            ("permitlisten", Some(v)) if ForwardPattern::listen(&v).is_some() => {
+                options.permit_listen.push(v)
+            }
+            ("from", Some(v)) => line.from = Some(v),
+            ("principals", Some(v)) => line.principals = Some(v),
+            ("cert-authority", None) => line.cert_authority = true,
+            ("no-port-forwarding", None) => options.no_port_forwarding = true,
+            ("no-agent-forwarding", None) => options.no_agent_forwarding = true,
+            ("no-x11-forwarding", None) => options.no_x11_forwarding = true,
+            ("no-pty", None) => options.no_pty = true,
+            ("no-user-rc", None) => options.no_user_rc = true,
+            ("restrict", None) => {
+                options.no_port_forwarding = true;
+                options.no_agent_forwarding = true;
+                options.no_x11_forwarding = true;
+                options.no_pty = true;
+                options.no_user_rc = true;
+            }
+            ("port-forwarding", None) => options.no_port_forwarding = false,
+            ("agent-forwarding", None) => options.no_agent_forwarding = false,
+            ("x11-forwarding", None) => options.no_x11_forwarding = false,
+            ("pty", None) => options.no_pty = false,
+            ("user-rc", None) => options.no_user_rc = false,
+            (name, _) => {
+                debug!("unsupported authorized_keys option {:?}", name);
+                return None;
+            }
+        }
+    }
+    Some(line)
+}
+
+/// Splits at commas outside quotes.
+fn split_unquoted(s: &str) -> Option<Vec<&str>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(s.get(start..i)?);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return None;
+    }
+    parts.push(s.get(start..)?);
+    Some(parts)
+}
+
+/// Removes the quotes around an option value, and the backslashes
+/// before inner quotes.
+fn unquote(value: &str) -> Option<String> {
+    let value = value.strip_prefix('"')?.strip_suffix('"')?;
+    Some(value.replace("\\\"", "\""))
+}
+
+/// Matches `ip` against a `from=` list of patterns: addresses with
+/// `*` and `?` wildcards, or CIDR blocks, possibly negated with `!`.
+/// Host names are not resolved. A matching negated pattern always
+/// rejects.
+fn match_pattern_list(list: &str, ip: IpAddr) -> bool {
+    match_address(list.split(','), ip)
+}
+
+/// Matches `ip` against patterns as in a `from=` list.
+pub(crate) fn match_address<'a, I: IntoIterator<Item = &'a str>>(patterns: I, ip: IpAddr) -> bool {
+    let ip_str = ip.to_string();
+    let mut matched = false;
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let m = match_cidr(pattern, ip).unwrap_or_else(|| match_wildcard(pattern, &ip_str));
+        if m && negated {
+            return false;
+        }
+        matched |= m;
+    }
+    matched
+}
+
+/// Matches `ip` against an address or a CIDR block, or returns `None`
+/// if `pattern` is neither.
+fn match_cidr(pattern: &str, ip: IpAddr) -> Option<bool> {
+    let (addr, len) = match pattern.split_once('/') {
+        Some((addr, len)) => (addr.parse::<IpAddr>().ok()?, Some(len.parse::<u32>().ok()?)),
+        None => (pattern.parse::<IpAddr>().ok()?, None),
+    };
+    Some(match (addr, ip) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let len = len.unwrap_or(32);
+            if len > 32 {
+                return None;
+            }
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            u32::from(a) & mask == u32::from(b) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let len = len.unwrap_or(128);
+            if len > 128 {
+                return None;
+            }
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            u128::from(a) & mask == u128::from(b) & mask
+        }
+        _ => false,
+    })
+}
+
+pub(crate) fn match_wildcard(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    // Positions to resume from after the last `*`.
+    let (mut p, mut i) = (0, 0);
+    let mut star = None;
+    while i < s.len() {
+        match (pattern.get(p), s.get(i)) {
+            (Some('*'), _) => {
+                star = Some((p, i));
+                p += 1;
+            }
+            (Some('?'), _) => {
+                p += 1;
+                i += 1;
+            }
+            (Some(a), Some(b)) if a == b => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((sp, si)) => {
+                    star = Some((sp, si + 1));
+                    p = sp + 1;
+                    i = si + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern
+        .get(p..)
+        .map_or(false, |rest| rest.iter().all(|&c| c == '*'))
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn test_from_patterns() {
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        assert!(match_pattern_list("192.168.1.0/24", ip));
+        assert!(match_pattern_list("10.0.0.1,192.168.1.*", ip));
+        assert!(match_pattern_list("192.168.1.2?", ip));
+        assert!(!match_pattern_list("!192.168.1.20,192.168.*", ip));
+        assert!(!match_pattern_list("10.0.0.0/8", ip));
+        assert!(!match_pattern_list("::1", ip));
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(match_pattern_list("2001:db8::/32", ip));
+        assert!(!match_pattern_list("2001:db9::/32", ip));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let (options, key) = split_options(
+            r#"command="echo \"a, b\"",from="10.0.0.0/8",restrict,pty,permitopen="localhost:80" ssh-ed25519 AAAA"#,
+        )
+        .unwrap();
+        assert_eq!(key, " ssh-ed25519 AAAA");
+        let line = parse_options(options).unwrap();
+        assert_eq!(line.options.command.as_deref(), Some(r#"echo "a, b""#));
+        assert_eq!(line.from.as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(line.options.permit_open, ["localhost:80"]);
+        assert!(line.options.no_port_forwarding);
+        assert!(!line.options.no_pty);
+        assert!(parse_options("no-such-option").is_none());
+        assert!(parse_options(r#"command="unterminated"#).is_none());
+        assert!(parse_options(r#"permitopen="localhost""#).is_none());
+        assert!(parse_options(r#"permitlisten="[::1]:*",permitopen="[::1]:22""#).is_some());
+        assert_eq!(
+            expand_path("/home/%u/.ssh/%%u", "alice"),
+            "/home/alice/.ssh/%u"
+        );
+    }
+}
@@ -0,0 +1,8 @@
+//! Ready-made authentication backends for [`super::Handler`].
+
+mod file;
+#[cfg(all(unix, feature = "pam"))]
+pub mod pam;
+
+pub(crate) use file::{match_address, match_wildcard};
+pub use file::{FileAuth, KeyOptions};
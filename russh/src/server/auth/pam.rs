@@ -0,0 +1,581 @@
+//! Keyboard-interactive authentication against the system PAM stack.
+//!
+//! Each PAM conversation round becomes one `SSH_MSG_USERAUTH_INFO_REQUEST`:
+//! informational and error messages are sent as instructions, and
+//! prompts keep their echo flag. Once the stack has run, the outcome of
+//! `pam_authenticate` and `pam_acct_mgmt` (and optionally
+//! `pam_open_session`) decides between [`Auth::Accept`] and
+//! [`Auth::Reject`].
+//!
+//! PAM calls block, so the whole transaction runs on
+//! [`tokio::task::spawn_blocking`].
+//!
+//! ```no_run
+//! use russh::server::auth::pam::PamAuthenticator;
+//! use russh::server::{Auth, Handler, Response};
+//!
+//! struct Client {
+//!     pam: PamAuthenticator,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl Handler for Client {
+//!     type Error = russh::Error;
+//!
+//!     async fn auth_keyboard_interactive(
+//!         &mut self,
+//!         user: &str,
+//!         _submethods: &str,
+//!         response: Option<Response<'async_trait>>,
+//!     ) -> Result<Auth, Self::Error> {
+//!         Ok(self.pam.keyboard_interactive(user, response).await)
+//!     }
+//! }
+//! ```
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc as std_mpsc;
+
+use log::debug;
+use pam_sys::raw::{
+    pam_acct_mgmt, pam_authenticate, pam_close_session, pam_end, pam_open_session, pam_set_item,
+    pam_setcred, pam_start, pam_strerror,
+};
+use pam_sys::{
+    PamConversation, PamFlag, PamHandle, PamItemType, PamMessage, PamMessageStyle, PamResponse,
+    PamReturnCode,
+};
+use tokio::sync::mpsc;
+use zeroize::Zeroizing;
+
+use crate::server::{Auth, Response};
+
+const PAM_SUCCESS: c_int = PamReturnCode::SUCCESS as c_int;
+const PAM_BUF_ERR: c_int = PamReturnCode::BUF_ERR as c_int;
+const PAM_CONV_ERR: c_int = PamReturnCode::CONV_ERR as c_int;
+
+const PAM_PROMPT_ECHO_OFF: c_int = PamMessageStyle::PROMPT_ECHO_OFF as c_int;
+const PAM_PROMPT_ECHO_ON: c_int = PamMessageStyle::PROMPT_ECHO_ON as c_int;
+const PAM_ERROR_MSG: c_int = PamMessageStyle::ERROR_MSG as c_int;
+const PAM_TEXT_INFO: c_int = PamMessageStyle::TEXT_INFO as c_int;
+
+/// The answers to one conversation round, wiped once sent to PAM.
+type Answers = Vec<Zeroizing<String>>;
+
+/// What the PAM thread reports back to the session.
+enum Event {
+    /// A conversation round with at least one prompt. The PAM thread
+    /// waits for exactly one answer per prompt.
+    Prompts {
+        instructions: String,
+        prompts: Vec<(String, bool)>,
+    },
+    /// The end of the transaction.
+    Done(Result<Transaction, String>),
+}
+
+/// State of the conversation function, owned by the PAM thread (and
+/// then by [`Transaction`]).
+struct Conversation {
+    events: mpsc::UnboundedSender<Event>,
+    answers: std_mpsc::Receiver<Answers>,
+    /// Messages received since the last prompt.
+    info: String,
+}
+
+/// A PAM handle, ended when dropped. Ending it may close a PAM
+/// session, which blocks, so this happens on a blocking thread when
+/// dropped from a runtime.
+struct Transaction {
+    handle: *mut PamHandle,
+    // Both are referenced by `handle` and must outlive it.
+    refs: Option<(Box<PamConversation>, Box<Conversation>)>,
+    status: c_int,
+    session_open: bool,
+}
+
+// The handle is only used by one thread at a time.
+unsafe impl Send for Transaction {}
+
+impl Transaction {
+    fn strerror(&self, status: c_int) -> String {
+        let msg = unsafe { pam_strerror(self.handle, status) };
+        if msg.is_null() {
+            format!("PAM error {}", status)
+        } else {
+            unsafe { CStr::from_ptr(msg) }
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let end = End {
+            handle: self.handle,
+            _refs: self.refs.take(),
+            status: self.status,
+            session_open: self.session_open,
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || end.run());
+            }
+            Err(_) => end.run(),
+        }
+    }
+}
+
+/// The end of a [`Transaction`], sent to a blocking thread.
+struct End {
+    handle: *mut PamHandle,
+    _refs: Option<(Box<PamConversation>, Box<Conversation>)>,
+    status: c_int,
+    session_open: bool,
+}
+
+unsafe impl Send for End {}
+
+impl End {
+    fn run(self) {
+        unsafe {
+            if self.session_open {
+                pam_close_session(self.handle, 0);
+                pam_setcred(self.handle, PamFlag::DELETE_CRED as c_int);
+            }
+            pam_end(self.handle, self.status);
+        }
+        // `_refs` is dropped after `pam_end`.
+    }
+}
+
+/// The conversation function given to PAM. Informational messages are
+/// collected until the next prompt; a batch containing prompts is sent
+/// to the session and answered from the client's response.
+extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *mut PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    // Unwinding into PAM is undefined behavior.
+    catch_unwind(AssertUnwindSafe(|| unsafe {
+        converse(num_msg, msg, resp, appdata_ptr)
+    }))
+    .unwrap_or(PAM_CONV_ERR)
+}
+
+unsafe fn converse(
+    num_msg: c_int,
+    msg: *mut *mut PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || msg.is_null() || resp.is_null() || appdata_ptr.is_null() {
+        return PAM_CONV_ERR;
+    }
+    let state = &mut *(appdata_ptr as *mut Conversation);
+    let num_msg = num_msg as usize;
+
+    let mut styles = Vec::with_capacity(num_msg);
+    let mut prompts = Vec::new();
+    for i in 0..num_msg {
+        let m = *msg.add(i);
+        if m.is_null() {
+            return PAM_CONV_ERR;
+        }
+        let text = if (*m).msg.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr((*m).msg).to_string_lossy().into_owned()
+        };
+        match (*m).msg_style {
+            PAM_PROMPT_ECHO_OFF => prompts.push((text, false)),
+            PAM_PROMPT_ECHO_ON => prompts.push((text, true)),
+            PAM_ERROR_MSG | PAM_TEXT_INFO => {
+                state.info.push_str(&text);
+                if !text.ends_with('\n') {
+                    state.info.push('\n');
+                }
+            }
+            _ => return PAM_CONV_ERR,
+        }
+        styles.push((*m).msg_style);
+    }
+
+    let mut answers = Vec::new().into_iter();
+    if !prompts.is_empty() {
+        let n = prompts.len();
+        let event = Event::Prompts {
+            instructions: std::mem::take(&mut state.info),
+            prompts,
+        };
+        if state.events.send(event).is_err() {
+            return PAM_CONV_ERR;
+        }
+        match state.answers.recv() {
+            Ok(a) if a.len() == n => answers = a.into_iter(),
+            _ => return PAM_CONV_ERR,
+        }
+    }
+
+    // PAM frees the responses with free(3).
+    let replies = libc::calloc(num_msg, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+    if replies.is_null() {
+        return PAM_BUF_ERR;
+    }
+    for (i, style) in styles.into_iter().enumerate() {
+        if style == PAM_PROMPT_ECHO_OFF || style == PAM_PROMPT_ECHO_ON {
+            let answer = answers.next().unwrap_or_default();
+            (*replies.add(i)).resp = malloc_string(answer.as_bytes());
+        }
+    }
+    *resp = replies;
+    PAM_SUCCESS
+}
+
+/// Copies `s` to a C string allocated with malloc(3), without leaving
+/// another copy to wipe. A string containing NUL becomes empty.
+unsafe fn malloc_string(s: &[u8]) -> *mut c_char {
+    let s = if s.contains(&0) { &[][..] } else { s };
+    let p = libc::malloc(s.len() + 1) as *mut c_char;
+    if !p.is_null() {
+        std::ptr::copy_nonoverlapping(s.as_ptr() as *const c_char, p, s.len());
+        *p.add(s.len()) = 0;
+    }
+    p
+}
+
+/// Runs a whole PAM transaction; meant for a blocking thread.
+fn run_transaction(
+    service: CString,
+    user: CString,
+    remote_host: Option<CString>,
+    open_session: bool,
+    events: mpsc::UnboundedSender<Event>,
+    answers: std_mpsc::Receiver<Answers>,
+) {
+    let mut conversation = Box::new(Conversation {
+        events: events.clone(),
+        answers,
+        info: String::new(),
+    });
+    let conv = Box::new(PamConversation {
+        conv: Some(self::conversation),
+        data_ptr: &mut *conversation as *mut Conversation as *mut c_void,
+    });
+    let mut handle: *const PamHandle = std::ptr::null();
+    let status = unsafe { pam_start(service.as_ptr(), user.as_ptr(), &*conv, &mut handle) };
+    let handle = handle as *mut PamHandle;
+    if handle.is_null() {
+        let _ = events.send(Event::Done(Err(format!("pam_start failed ({})", status))));
+        return;
+    }
+    let mut transaction = Transaction {
+        handle,
+        refs: Some((conv, conversation)),
+        status,
+        session_open: false,
+    };
+    if status == PAM_SUCCESS {
+        transaction.status = unsafe { authenticate(&mut transaction, remote_host, open_session) };
+    }
+    let result = if transaction.status == PAM_SUCCESS {
+        Ok(transaction)
+    } else {
+        Err(transaction.strerror(transaction.status))
+    };
+    let _ = events.send(Event::Done(result));
+}
+
+unsafe fn authenticate(
+    transaction: &mut Transaction,
+    remote_host: Option<CString>,
+    open_session: bool,
+) -> c_int {
+    let handle = transaction.handle;
+    if let Some(ref remote_host) = remote_host {
+        let status = pam_set_item(
+            handle,
+            PamItemType::RHOST as c_int,
+            remote_host.as_ptr() as *const c_void,
+        );
+        if status != PAM_SUCCESS {
+            return status;
+        }
+    }
+    let status = pam_authenticate(handle, 0);
+    if status != PAM_SUCCESS {
+        return status;
+    }
+    let status = pam_acct_mgmt(handle, 0);
+    if status != PAM_SUCCESS || !open_session {
+        return status;
+    }
+    let status = pam_setcred(handle, PamFlag::ESTABLISH_CRED as c_int);
+    if status != PAM_SUCCESS {
+        return status;
+    }
+    let status = pam_open_session(handle, 0);
+    if status != PAM_SUCCESS {
+        pam_setcred(handle, PamFlag::DELETE_CRED as c_int);
+        return status;
+    }
+    transaction.session_open = true;
+    PAM_SUCCESS
+}
+
+/// The session's end of a running transaction.
+struct Pending {
+    events: mpsc::UnboundedReceiver<Event>,
+    answers: std_mpsc::Sender<Answers>,
+}
+
+/// Drives keyboard-interactive authentication through PAM, one
+/// instance per connection (usually a field of the
+/// [`Handler`](crate::server::Handler)).
+///
+/// A successful transaction is kept until this is dropped, so that a
+/// PAM session opened with [`PamAuthenticator::open_session`] lasts as
+/// long as the connection.
+pub struct PamAuthenticator {
+    service: String,
+    remote_host: Option<String>,
+    open_session: bool,
+    pending: Option<Pending>,
+    transaction: Option<Transaction>,
+}
+
+impl std::fmt::Debug for PamAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PamAuthenticator")
+            .field("service", &self.service)
+            .field("authenticated", &self.is_authenticated())
+            .finish()
+    }
+}
+
+impl PamAuthenticator {
+    /// Uses the PAM configuration of `service` (`/etc/pam.d/<service>`).
+    pub fn new<S: Into<String>>(service: S) -> Self {
+        PamAuthenticator {
+            service: service.into(),
+            remote_host: None,
+            open_session: false,
+            pending: None,
+            transaction: None,
+        }
+    }
+
+    /// Sets `PAM_RHOST`, for modules that log or filter on the client
+    /// address.
+    pub fn remote_host<S: Into<String>>(mut self, remote_host: S) -> Self {
+        self.remote_host = Some(remote_host.into());
+        self
+    }
+
+    /// Also establishes credentials and opens a PAM session after a
+    /// successful authentication. The session is closed when this is
+    /// dropped. Opening a session usually requires root.
+    pub fn open_session(mut self, open_session: bool) -> Self {
+        self.open_session = open_session;
+        self
+    }
+
+    /// Whether a PAM transaction has succeeded.
+    pub fn is_authenticated(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Call this from
+    /// [`Handler::auth_keyboard_interactive`](crate::server::Handler::auth_keyboard_interactive).
+    ///
+    /// Without a `response`, this starts a new transaction for `user`
+    /// (abandoning a running one). Otherwise, the response answers the
+    /// prompts of the last [`Auth::Partial`]. PAM errors, including a
+    /// wrong number of answers, result in [`Auth::Reject`].
+    pub async fn keyboard_interactive(
+        &mut self,
+        user: &str,
+        response: Option<Response<'_>>,
+    ) -> Auth {
+        match response {
+            None => {
+                if !self.start(user) {
+                    return reject();
+                }
+            }
+            Some(response) => {
+                let answers = response
+                    .map(|r| Zeroizing::new(String::from_utf8_lossy(r).into_owned()))
+                    .collect();
+                match self.pending {
+                    Some(ref pending) if pending.answers.send(answers).is_ok() => {}
+                    _ => {
+                        self.pending = None;
+                        return reject();
+                    }
+                }
+            }
+        }
+        self.next_step().await
+    }
+
+    fn start(&mut self, user: &str) -> bool {
+        self.pending = None;
+        let (service, user) = match (CString::new(&*self.service), CString::new(user)) {
+            (Ok(service), Ok(user)) => (service, user),
+            _ => return false,
+        };
+        let remote_host = self
+            .remote_host
+            .as_deref()
+            .and_then(|h| CString::new(h).ok());
+        let open_session = self.open_session;
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (answers, answers_rx) = std_mpsc::channel();
+        tokio::task::spawn_blocking(move || {
+            run_transaction(
+                service,
+                user,
+                remote_host,
+                open_session,
+                events_tx,
+                answers_rx,
+            )
+        });
+        self.pending = Some(Pending { events, answers });
+        true
+    }
+
+    async fn next_step(&mut self) -> Auth {
+        let event = match self.pending {
+            Some(ref mut pending) => pending.events.recv().await,
+            None => None,
+        };
+        match event {
+            Some(Event::Prompts {
+                instructions,
+                prompts,
+            }) => Auth::Partial {
+                name: Cow::Borrowed(""),
+                instructions: Cow::Owned(instructions),
+                prompts: Cow::Owned(
+                    prompts
+                        .into_iter()
+                        .map(|(prompt, echo)| (Cow::Owned(prompt), echo))
+                        .collect(),
+                ),
+            },
+            Some(Event::Done(Ok(transaction))) => {
+                self.pending = None;
+                self.transaction = Some(transaction);
+                Auth::Accept
+            }
+            Some(Event::Done(Err(e))) => {
+                debug!("PAM authentication failed: {}", e);
+                self.pending = None;
+                reject()
+            }
+            None => {
+                self.pending = None;
+                reject()
+            }
+        }
+    }
+}
+
+fn reject() -> Auth {
+    Auth::Reject {
+        proceed_with_methods: None,
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used, clippy::panic, clippy::indexing_slicing)]
+fn test_conversation_rounds() {
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let (answers, answers_rx) = std_mpsc::channel();
+    let mut state = Conversation {
+        events: events_tx,
+        answers: answers_rx,
+        info: String::new(),
+    };
+    let texts = [
+        CString::new("Welcome").unwrap(),
+        CString::new("Login: ").unwrap(),
+        CString::new("Password: ").unwrap(),
+    ];
+    let messages = [
+        PamMessage {
+            msg_style: PAM_TEXT_INFO,
+            msg: texts[0].as_ptr(),
+        },
+        PamMessage {
+            msg_style: PAM_PROMPT_ECHO_ON,
+            msg: texts[1].as_ptr(),
+        },
+        PamMessage {
+            msg_style: PAM_PROMPT_ECHO_OFF,
+            msg: texts[2].as_ptr(),
+        },
+    ];
+    let mut pointers: Vec<*mut PamMessage> =
+        messages.iter().map(|m| m as *const _ as *mut _).collect();
+
+    answers
+        .send(vec![
+            Zeroizing::new("user".to_string()),
+            Zeroizing::new("secret".to_string()),
+        ])
+        .unwrap();
+    let mut replies = std::ptr::null_mut();
+    let status = conversation(
+        3,
+        pointers.as_mut_ptr(),
+        &mut replies,
+        &mut state as *mut Conversation as *mut c_void,
+    );
+    assert_eq!(status, PAM_SUCCESS);
+    match events.try_recv() {
+        Ok(Event::Prompts {
+            instructions,
+            prompts,
+        }) => {
+            assert_eq!(instructions, "Welcome\n");
+            assert_eq!(
+                prompts,
+                [
+                    ("Login: ".to_string(), true),
+                    ("Password: ".to_string(), false)
+                ]
+            );
+        }
+        _ => panic!("expected prompts"),
+    }
+    unsafe {
+        assert!((*replies).resp.is_null());
+        assert_eq!(CStr::from_ptr((*replies.add(1)).resp).to_bytes(), b"user");
+        assert_eq!(CStr::from_ptr((*replies.add(2)).resp).to_bytes(), b"secret");
+        for i in 0..3 {
+            libc::free((*replies.add(i)).resp as *mut c_void);
+        }
+        libc::free(replies as *mut c_void);
+    }
+
+    // A round with the wrong number of answers fails.
+    answers.send(vec![]).unwrap();
+    let mut replies = std::ptr::null_mut();
+    let status = unsafe {
+        conversation(
+            1,
+            pointers.as_mut_ptr().add(2),
+            &mut replies,
+            &mut state as *mut Conversation as *mut c_void,
+        )
+    };
+    assert_eq!(status, PAM_CONV_ERR);
+}
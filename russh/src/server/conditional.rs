@@ -0,0 +1,348 @@
+//! Settings depending on the client's address and user name, like the
+//! `Match` blocks of `sshd_config`, see [`ConditionalConfig`].
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::auth::{match_address, match_wildcard};
+use super::{ForwardingPolicy, RuntimeConfig};
+use crate::auth::MethodSet;
+
+/// Tells whether a user belongs to the groups of a [`MatchCriteria`].
+pub type GroupsCallback = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// The connections a [`MatchRule`] applies to. Every criterion given
+/// must match: the default criteria match every connection.
+#[derive(Clone, Default)]
+pub struct MatchCriteria {
+    /// The client's address must match these patterns, as in `Match
+    /// Address`: addresses with `*` and `?` wildcards, or CIDR blocks
+    /// such as `10.0.0.0/8` or `2001:db8::/32`, possibly negated with
+    /// `!`. IPv4 clients of an IPv6 socket match as IPv4 addresses.
+    /// Rules with addresses never match sessions whose peer address is
+    /// unknown, see [`run_stream_with_peer_addr`](super::run_stream_with_peer_addr).
+    pub source_cidrs: Vec<String>,
+    /// The user name must match these patterns, as in `Match User`:
+    /// names with `*` and `?` wildcards, possibly negated with `!`.
+    pub users: Vec<String>,
+    /// Called with the user name, tells whether the user belongs to
+    /// the groups the rule is about, as in `Match Group`.
+    pub groups_callback: Option<GroupsCallback>,
+}
+
+impl fmt::Debug for MatchCriteria {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MatchCriteria")
+            .field("source_cidrs", &self.source_cidrs)
+            .field("users", &self.users)
+            .field("groups_callback", &self.groups_callback.is_some())
+            .finish()
+    }
+}
+
+impl MatchCriteria {
+    /// Whether a connection from `addr` matches, for `user` if known.
+    /// Rules on the user don't match until the user is known.
+    pub fn matches(&self, addr: Option<IpAddr>, user: Option<&str>) -> bool {
+        if !self.source_cidrs.is_empty() {
+            let addr = match addr {
+                Some(IpAddr::V6(v6)) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+                Some(addr) => addr,
+                None => return false,
+            };
+            if !match_address(self.source_cidrs.iter().map(|p| p.as_str()), addr) {
+                return false;
+            }
+        }
+        if self.users.is_empty() && self.groups_callback.is_none() {
+            return true;
+        }
+        let Some(user) = user else {
+            return false;
+        };
+        (self.users.is_empty() || match_user(&self.users, user))
+            && self.groups_callback.as_ref().map_or(true, |f| f(user))
+    }
+}
+
+/// Matches `user` against a list of patterns: a matching negated
+/// pattern always rejects.
+fn match_user(patterns: &[String], user: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+        let m = match_wildcard(pattern, user);
+        if m && negated {
+            return false;
+        }
+        matched |= m;
+    }
+    matched
+}
+
+/// The settings a [`MatchRule`] changes. Fields left to `None` keep the
+/// value of the [`RuntimeConfig`] and of the [`Config`](super::Config).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigOverrides {
+    /// See [`RuntimeConfig::methods`]. The methods allowed to a user
+    /// are still chosen by [`Handler::auth_methods`](super::Handler::auth_methods),
+    /// which gets these ones.
+    pub methods: Option<MethodSet>,
+    /// See [`RuntimeConfig::auth_banner`]. The banner is sent before
+    /// the client names the user, so only rules on the address can
+    /// change it.
+    pub auth_banner: Option<String>,
+    /// See [`RuntimeConfig::auth_rejection_time`].
+    pub auth_rejection_time: Option<Duration>,
+    /// See [`RuntimeConfig::inactivity_timeout`].
+    pub inactivity_timeout: Option<Duration>,
+    /// See [`RuntimeConfig::keepalive_interval`].
+    pub keepalive_interval: Option<Duration>,
+    /// See [`RuntimeConfig::keepalive_max`].
+    pub keepalive_max: Option<usize>,
+    /// Replaces [`Config::forwarding_policy`](super::Config::forwarding_policy).
+    pub forwarding_policy: Option<ForwardingPolicy>,
+}
+
+impl ConfigOverrides {
+    /// `runtime`, with the values set here.
+    pub fn apply(&self, runtime: &RuntimeConfig) -> RuntimeConfig {
+        RuntimeConfig {
+            methods: self.methods.unwrap_or(runtime.methods),
+            auth_banner: self
+                .auth_banner
+                .clone()
+                .or_else(|| runtime.auth_banner.clone()),
+            auth_rejection_time: self
+                .auth_rejection_time
+                .unwrap_or(runtime.auth_rejection_time),
+            auth_rejection_time_initial: runtime.auth_rejection_time_initial,
+            inactivity_timeout: self.inactivity_timeout.or(runtime.inactivity_timeout),
+            keepalive_interval: self.keepalive_interval.or(runtime.keepalive_interval),
+            keepalive_max: self.keepalive_max.unwrap_or(runtime.keepalive_max),
+        }
+    }
+
+    /// Sets the values not set yet to those of `other`.
+    fn fill(&mut self, other: &ConfigOverrides) {
+        self.methods = self.methods.or(other.methods);
+        if self.auth_banner.is_none() {
+            self.auth_banner = other.auth_banner.clone();
+        }
+        self.auth_rejection_time = self.auth_rejection_time.or(other.auth_rejection_time);
+        self.inactivity_timeout = self.inactivity_timeout.or(other.inactivity_timeout);
+        self.keepalive_interval = self.keepalive_interval.or(other.keepalive_interval);
+        self.keepalive_max = self.keepalive_max.or(other.keepalive_max);
+        if self.forwarding_policy.is_none() {
+            self.forwarding_policy = other.forwarding_policy.clone();
+        }
+    }
+}
+
+/// A named rule of a [`ConditionalConfig`].
+#[derive(Debug, Clone)]
+pub struct MatchRule {
+    /// Identifies the rule in [`AuthInfo::matched_rules`](super::AuthInfo::matched_rules)
+    /// and [`Session::matched_rules`](super::Session::matched_rules).
+    pub name: String,
+    pub criteria: MatchCriteria,
+    pub overrides: ConfigOverrides,
+}
+
+/// Settings depending on the client's address and user name, the way
+/// `sshd` applies the `Match` blocks of its configuration, see
+/// [`Config::conditional`](super::Config::conditional).
+///
+/// The rules are evaluated in order when the connection is accepted,
+/// and again when the client first names the user it authenticates
+/// as. All the matching rules apply, and as in `sshd`, the first rule
+/// setting a value wins.
+///
+/// ```
+/// use russh::server::{ConditionalConfig, ConfigOverrides, MatchCriteria};
+/// use russh::MethodSet;
+///
+/// let conditional = ConditionalConfig::new()
+///     .rule(
+///         "admins",
+///         MatchCriteria {
+///             users: vec!["root".into(), "admin-*".into()],
+///             ..Default::default()
+///         },
+///         ConfigOverrides {
+///             methods: Some(MethodSet::PUBLICKEY),
+///             ..Default::default()
+///         },
+///     )
+///     .rule(
+///         "internal",
+///         MatchCriteria {
+///             source_cidrs: vec!["10.0.0.0/8".into(), "fd00::/8".into()],
+///             ..Default::default()
+///         },
+///         ConfigOverrides {
+///             methods: Some(MethodSet::PUBLICKEY | MethodSet::PASSWORD),
+///             ..Default::default()
+///         },
+///     );
+/// let matched = conditional.evaluate(Some("10.1.2.3".parse().unwrap()), Some("admin-bob"));
+/// assert_eq!(matched.rules, ["admins", "internal"]);
+/// assert_eq!(matched.overrides.methods, Some(MethodSet::PUBLICKEY));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ConditionalConfig {
+    pub rules: Vec<MatchRule>,
+}
+
+impl ConditionalConfig {
+    /// A configuration without rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule after the others.
+    pub fn rule<N: Into<String>>(
+        mut self,
+        name: N,
+        criteria: MatchCriteria,
+        overrides: ConfigOverrides,
+    ) -> Self {
+        self.rules.push(MatchRule {
+            name: name.into(),
+            criteria,
+            overrides,
+        });
+        self
+    }
+
+    /// The rules matching a connection from `addr`, for `user` if
+    /// known, and the settings they change.
+    pub fn evaluate(&self, addr: Option<IpAddr>, user: Option<&str>) -> MatchResult {
+        let mut result = MatchResult::default();
+        for rule in self.rules.iter() {
+            if rule.criteria.matches(addr, user) {
+                result.rules.push(rule.name.clone());
+                result.overrides.fill(&rule.overrides);
+            }
+        }
+        result
+    }
+}
+
+/// The outcome of [`ConditionalConfig::evaluate`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatchResult {
+    /// The names of the matching rules, in order.
+    pub rules: Vec<String>,
+    /// The settings of the matching rules, the first one winning.
+    pub overrides: ConfigOverrides,
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    fn addresses(patterns: &[&str]) -> MatchCriteria {
+        MatchCriteria {
+            source_cidrs: patterns.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_match_ipv4() {
+        let criteria = addresses(&["10.0.0.0/8", "192.168.1.7", "!10.66.0.0/16"]);
+        assert!(criteria.matches(ip("10.1.2.3"), None));
+        assert!(criteria.matches(ip("192.168.1.7"), Some("alice")));
+        assert!(!criteria.matches(ip("192.168.1.8"), None));
+        assert!(!criteria.matches(ip("10.66.1.1"), None));
+        assert!(!criteria.matches(ip("11.0.0.1"), None));
+        assert!(!criteria.matches(None, None));
+        // IPv4 clients of a dual-stack socket.
+        assert!(criteria.matches(ip("::ffff:10.1.2.3"), None));
+        assert!(!criteria.matches(ip("::ffff:10.66.1.1"), None));
+        assert!(addresses(&["0.0.0.0/0"]).matches(ip("203.0.113.9"), None));
+        assert!(!addresses(&["0.0.0.0/0"]).matches(ip("2001:db8::1"), None));
+    }
+
+    #[test]
+    fn test_match_ipv6() {
+        let criteria = addresses(&["2001:db8::/32", "::1"]);
+        assert!(criteria.matches(ip("2001:db8:1::5"), None));
+        assert!(criteria.matches(ip("::1"), None));
+        assert!(!criteria.matches(ip("2001:db9::1"), None));
+        assert!(!criteria.matches(ip("10.0.0.1"), None));
+        assert!(addresses(&["fe80::/10"]).matches(ip("fe80::1:2"), None));
+        assert!(!addresses(&["fe80::/10"]).matches(ip("fec0::1"), None));
+        assert!(addresses(&["::/0"]).matches(ip("2001:db8::1"), None));
+    }
+
+    #[test]
+    fn test_match_users() {
+        let criteria = MatchCriteria {
+            users: vec!["admin-*".into(), "root".into(), "!admin-guest".into()],
+            ..Default::default()
+        };
+        // Not before the user is known.
+        assert!(!criteria.matches(ip("10.0.0.1"), None));
+        assert!(criteria.matches(ip("10.0.0.1"), Some("admin-bob")));
+        assert!(criteria.matches(None, Some("root")));
+        assert!(!criteria.matches(None, Some("admin-guest")));
+        assert!(!criteria.matches(None, Some("alice")));
+
+        let criteria = MatchCriteria {
+            source_cidrs: vec!["10.0.0.0/8".into()],
+            groups_callback: Some(Arc::new(|user| user.starts_with("ops-"))),
+            ..Default::default()
+        };
+        assert!(criteria.matches(ip("10.0.0.1"), Some("ops-carol")));
+        assert!(!criteria.matches(ip("10.0.0.1"), Some("dave")));
+        assert!(!criteria.matches(ip("172.16.0.1"), Some("ops-carol")));
+    }
+
+    #[test]
+    fn test_first_value_wins() {
+        let config = ConditionalConfig::new()
+            .rule(
+                "slow",
+                addresses(&["192.0.2.0/24"]),
+                ConfigOverrides {
+                    keepalive_interval: Some(Duration::from_secs(5)),
+                    ..Default::default()
+                },
+            )
+            .rule(
+                "all",
+                MatchCriteria::default(),
+                ConfigOverrides {
+                    keepalive_interval: Some(Duration::from_secs(60)),
+                    keepalive_max: Some(1),
+                    ..Default::default()
+                },
+            );
+        let matched = config.evaluate(ip("192.0.2.1"), None);
+        assert_eq!(matched.rules, ["slow", "all"]);
+        assert_eq!(
+            matched.overrides.keepalive_interval,
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(matched.overrides.keepalive_max, Some(1));
+
+        let matched = config.evaluate(ip("198.51.100.1"), None);
+        assert_eq!(matched.rules, ["all"]);
+        let runtime = RuntimeConfig::from(&super::super::Config::default());
+        let runtime = matched.overrides.apply(&runtime);
+        assert_eq!(runtime.keepalive_interval, Some(Duration::from_secs(60)));
+        assert_eq!(runtime.keepalive_max, 1);
+        assert_eq!(runtime.methods, MethodSet::all());
+    }
+}
@@ -14,7 +14,8 @@
 //
 use std::cell::RefCell;
 
-use auth::*;
+use crate::auth::*;
+use crate::cert::CERT_ALGO_SUFFIX;
 use byteorder::{BigEndian, ByteOrder};
 use log::{debug, error, info, trace, warn};
 use negotiation::Select;
@@ -28,6 +29,8 @@ use crate::keys::key;
 use crate::keys::key::Verify;
 use crate::msg::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED;
 use crate::parsing::{ChannelOpenConfirmation, ChannelType, OpenChannelMessage};
+use crate::rate_limit::TokenBucket;
+use crate::session::forwarded_port;
 
 impl Session {
     /// Returns false iff a request was rejected.
@@ -52,12 +55,12 @@ impl Session {
             debug!("Received rekeying request");
             // If we're not currently rekeying, but `buf` is a rekey request
             if let Some(Kex::Init(kexinit)) = enc.rekey.take() {
-                enc.rekey = Some(kexinit.server_parse(
+                enc.rekey = Some(self.common.error_disconnect.check(kexinit.server_parse(
                     self.common.config.as_ref(),
                     &mut *self.common.cipher.local_to_remote,
                     buf,
                     &mut self.common.write_buffer,
-                )?);
+                ))?);
             } else if let Some(exchange) = enc.exchange.take() {
                 let kexinit = KexInit::received_rekey(
                     exchange,
@@ -68,12 +71,12 @@ impl Session {
                     )?,
                     &enc.session_id,
                 );
-                enc.rekey = Some(kexinit.server_parse(
+                enc.rekey = Some(self.common.error_disconnect.check(kexinit.server_parse(
                     self.common.config.as_ref(),
                     &mut *self.common.cipher.local_to_remote,
                     buf,
                     &mut self.common.write_buffer,
-                )?);
+                ))?);
             }
             if let Some(Kex::Dh(KexDh { ref names, .. })) = enc.rekey {
                 self.common.strict_kex = self.common.strict_kex || names.strict_kex;
@@ -84,12 +87,12 @@ impl Session {
 
         match enc.rekey.take() {
             Some(Kex::Dh(kexdh)) => {
-                enc.rekey = Some(kexdh.parse(
+                enc.rekey = Some(self.common.error_disconnect.check(kexdh.parse(
                     self.common.config.as_ref(),
                     &mut *self.common.cipher.local_to_remote,
                     buf,
                     &mut self.common.write_buffer,
-                )?);
+                ))?);
                 if let Some(Kex::Keys(_)) = enc.rekey {
                     // just sent NEWKEYS
                     self.common.maybe_reset_seqn();
@@ -99,19 +102,14 @@ impl Session {
             }
             Some(Kex::Keys(newkeys)) => {
                 if buf.first() != Some(&msg::NEWKEYS) {
-                    return Err(Error::Kex.into());
+                    return Err(self.common.error_disconnect.record(Error::Kex).into());
                 }
                 self.common.write_buffer.bytes = 0;
-                enc.last_rekey = std::time::Instant::now();
+                enc.last_rekey = crate::runtime::now();
 
-                // Ok, NEWKEYS received, now encrypted.
+                // Ok, NEWKEYS received, now encrypted. What was held
+                // back during the exchange goes out with the new keys.
                 enc.flush_all_pending();
-                let mut pending = std::mem::take(&mut self.pending_reads);
-                for p in pending.drain(..) {
-                    self.process_packet(handler, &p).await?;
-                }
-                self.pending_reads = pending;
-                self.pending_len = 0;
                 self.common.newkeys(newkeys);
                 if self.common.strict_kex {
                     *seqn = Wrapping(0);
@@ -122,41 +120,59 @@ impl Session {
             Some(Kex::Init(k)) => {
                 if let Some(ref algo) = k.algo {
                     if self.common.strict_kex && !algo.strict_kex {
-                        return Err(strict_kex_violation(msg::KEXINIT, 0).into());
+                        let e = strict_kex_violation(msg::KEXINIT, 0);
+                        return Err(self.common.error_disconnect.record(e).into());
                     }
                 }
 
+                // The client sent this before our KEXINIT reached it
+                // (RFC 4253, section 7.1), handle it as usual.
                 enc.rekey = Some(Kex::Init(k));
-
-                self.pending_len += buf.len() as u32;
-                if self.pending_len > 2 * self.target_window_size {
-                    return Err(Error::Pending.into());
-                }
-                self.pending_reads.push(CryptoVec::from_slice(buf));
-                return Ok(());
             }
             rek => {
                 trace!("rek = {:?}", rek);
                 enc.rekey = rek
             }
         }
-        self.process_packet(handler, buf).await
+        // `seqn` was incremented after reading this packet.
+        self.process_packet(handler, (*seqn - Wrapping(1)).0, buf)
+            .await
     }
 
     async fn process_packet<H: Handler + Send>(
         &mut self,
         handler: &mut H,
+        packet_seqn: u32,
         buf: &[u8],
     ) -> Result<(), H::Error> {
-        let rejection_wait_until =
-            tokio::time::Instant::now() + self.common.config.auth_rejection_time;
+        #[cfg(feature = "danger-raw-packets")]
+        if let Some(ref hook) = self.common.config.raw_packet_hook {
+            if hook.consume(buf) {
+                return Ok(());
+            }
+        }
+        if buf.first() == Some(&msg::USERAUTH_REQUEST) {
+            let waiting = matches!(
+                self.common.encrypted.as_ref().map(|enc| &enc.state),
+                Some(EncryptedState::WaitingAuthRequest(_))
+            );
+            if let Some(user) = buf
+                .reader(1)
+                .read_string()
+                .ok()
+                .and_then(|user| std::str::from_utf8(user).ok())
+                .filter(|_| waiting)
+            {
+                self.match_user(user);
+            }
+        }
+        let runtime = self.runtime_config();
+        let rejection_wait_until = tokio::time::Instant::now() + runtime.auth_rejection_time;
         let initial_none_rejection_wait_until = if self.common.auth_attempts == 0 {
             tokio::time::Instant::now()
-                + self
-                    .common
-                    .config
+                + runtime
                     .auth_rejection_time_initial
-                    .unwrap_or(self.common.config.auth_rejection_time)
+                    .unwrap_or(runtime.auth_rejection_time)
         } else {
             rejection_wait_until
         };
@@ -173,8 +189,8 @@ impl Session {
                 debug!("request: {:?}", std::str::from_utf8(request));
                 if request == b"ssh-userauth" {
                     let auth_request = server_accept_service(
-                        self.common.config.as_ref().auth_banner,
-                        self.common.config.as_ref().methods,
+                        runtime.auth_banner.as_deref(),
+                        runtime.methods,
                         &mut enc.write,
                     );
                     *accepted = true;
@@ -186,17 +202,25 @@ impl Session {
                 if buf.first() == Some(&msg::USERAUTH_REQUEST) =>
             {
                 enc.server_read_auth_request(
+                    self.common.config.as_ref(),
+                    runtime.methods,
                     rejection_wait_until,
                     initial_none_rejection_wait_until,
                     handler,
                     buf,
                     &mut self.common.auth_user,
+                    &mut self.auth_info,
                 )
                 .await?;
                 self.common.auth_attempts += 1;
                 if let EncryptedState::InitCompression = enc.state {
+                    if let Some(ref mut auth_info) = self.auth_info {
+                        auth_info.matched_rules = self.matched.rules.clone();
+                    }
+                    debug!("authenticated: {:?}", self.auth_info);
+                    self.common.established_at = Some(crate::runtime::now());
                     enc.client_compression.init_decompress(&mut enc.decompress);
-                    handler.auth_succeeded(self).await?;
+                    handler_call!(self, handler.auth_succeeded(self))?;
                 }
                 Ok(())
             }
@@ -206,6 +230,7 @@ impl Session {
                 let resp = read_userauth_info_response(
                     rejection_wait_until,
                     handler,
+                    self.common.config.handler_timeout,
                     &mut enc.write,
                     auth,
                     &self.common.auth_user,
@@ -213,9 +238,19 @@ impl Session {
                 )
                 .await?;
                 if resp {
+                    self.auth_info = Some(AuthInfo {
+                        user: self.common.auth_user.clone(),
+                        method: MethodSet::KEYBOARD_INTERACTIVE,
+                        public_key: None,
+                        certificate: None,
+                        key_options: None,
+                        matched_rules: self.matched.rules.clone(),
+                    });
+                    debug!("authenticated: {:?}", self.auth_info);
+                    self.common.established_at = Some(crate::runtime::now());
                     enc.state = EncryptedState::InitCompression;
                     enc.client_compression.init_decompress(&mut enc.decompress);
-                    handler.auth_succeeded(self).await
+                    handler_call!(self, handler.auth_succeeded(self))
                 } else {
                     Ok(())
                 }
@@ -223,10 +258,20 @@ impl Session {
             EncryptedState::InitCompression => {
                 enc.server_compression.init_compress(&mut enc.compress);
                 enc.state = EncryptedState::Authenticated;
-                self.server_read_authenticated(handler, buf).await
+                self.server_read_authenticated(handler, packet_seqn, buf)
+                    .await
+            }
+            EncryptedState::Authenticated => {
+                self.server_read_authenticated(handler, packet_seqn, buf)
+                    .await
+            }
+            _ => {
+                if buf.first().map_or(false, |&t| !msg::is_known(t)) {
+                    debug!("unknown message: {:?}", buf);
+                    self.common.unimplemented(packet_seqn);
+                }
+                Ok(())
             }
-            EncryptedState::Authenticated => self.server_read_authenticated(handler, buf).await,
-            _ => Ok(()),
         }
     }
 }
@@ -254,18 +299,24 @@ fn server_accept_service(
         partial_success: false, // not used immediately anway.
         current: None,
         rejection_count: 0,
+        allowed_methods: methods,
+        methods_user: None,
     }
 }
 
 impl Encrypted {
     /// Returns false iff the request was rejected.
+    #[allow(clippy::too_many_arguments)]
     async fn server_read_auth_request<H: Handler + Send>(
         &mut self,
+        config: &Config,
+        methods: MethodSet,
         mut until: Instant,
         initial_auth_until: Instant,
         handler: &mut H,
         buf: &[u8],
         auth_user: &mut String,
+        auth_info: &mut Option<AuthInfo>,
     ) -> Result<(), H::Error> {
         // https://tools.ietf.org/html/rfc4252#section-5
         let mut r = buf.reader(1);
@@ -281,6 +332,31 @@ impl Encrypted {
         );
 
         if service_name == b"ssh-connection" {
+            let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+                a
+            } else {
+                unreachable!()
+            };
+            if auth_request.methods_user.as_deref() != Some(user) {
+                let allowed = handler_call!(
+                    timeout = config.handler_timeout,
+                    handler.auth_methods(user, methods)
+                )?;
+                debug!("methods allowed for {:?}: {:?}", user, allowed);
+                auth_request.allowed_methods = allowed;
+                auth_request.methods = allowed;
+                auth_request.methods_user = Some(user.to_string());
+            }
+            match MethodSet::from_bytes(method) {
+                Some(m) if !auth_request.allowed_methods.contains(m) => {
+                    debug!("{:?} may not use {:?}", user, std::str::from_utf8(method));
+                    auth_user.clear();
+                    reject_auth_request(until, &mut self.write, auth_request).await;
+                    return Ok(());
+                }
+                _ => {}
+            }
+
             if method == b"password" {
                 let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state
                 {
@@ -293,27 +369,32 @@ impl Encrypted {
                 r.read_byte().map_err(crate::Error::from)?;
                 let password = r.read_string().map_err(crate::Error::from)?;
                 let password = std::str::from_utf8(password).map_err(crate::Error::from)?;
-                let auth = handler.auth_password(user, password).await?;
+                let auth = handler_call!(
+                    timeout = config.handler_timeout,
+                    handler.auth_password(user, password)
+                )?;
                 if let Auth::Accept = auth {
+                    *auth_info = Some(AuthInfo {
+                        user: user.to_string(),
+                        method: MethodSet::PASSWORD,
+                        public_key: None,
+                        certificate: None,
+                        key_options: None,
+                        matched_rules: Vec::new(),
+                    });
                     server_auth_request_success(&mut self.write);
                     self.state = EncryptedState::InitCompression;
                 } else {
                     auth_user.clear();
-                    if let Auth::Reject {
-                        proceed_with_methods: Some(proceed_with_methods),
-                    } = auth
-                    {
-                        auth_request.methods = proceed_with_methods;
-                    } else {
-                        auth_request.methods -= MethodSet::PASSWORD;
-                    }
-                    auth_request.partial_success = false;
+                    update_auth_request(auth_request, auth, MethodSet::PASSWORD);
                     reject_auth_request(until, &mut self.write, auth_request).await;
                 }
                 Ok(())
             } else if method == b"publickey" {
-                self.server_read_auth_request_pk(until, handler, buf, auth_user, user, r)
-                    .await
+                self.server_read_auth_request_pk(
+                    config, until, handler, buf, auth_user, auth_info, user, r,
+                )
+                .await
             } else if method == b"none" {
                 let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state
                 {
@@ -326,21 +407,22 @@ impl Encrypted {
                     until = initial_auth_until
                 }
 
-                let auth = handler.auth_none(user).await?;
+                let auth =
+                    handler_call!(timeout = config.handler_timeout, handler.auth_none(user))?;
                 if let Auth::Accept = auth {
+                    *auth_info = Some(AuthInfo {
+                        user: user.to_string(),
+                        method: MethodSet::NONE,
+                        public_key: None,
+                        certificate: None,
+                        key_options: None,
+                        matched_rules: Vec::new(),
+                    });
                     server_auth_request_success(&mut self.write);
                     self.state = EncryptedState::InitCompression;
                 } else {
                     auth_user.clear();
-                    if let Auth::Reject {
-                        proceed_with_methods: Some(proceed_with_methods),
-                    } = auth
-                    {
-                        auth_request.methods = proceed_with_methods;
-                    } else {
-                        auth_request.methods -= MethodSet::NONE;
-                    }
-                    auth_request.partial_success = false;
+                    update_auth_request(auth_request, auth, MethodSet::NONE);
                     reject_auth_request(until, &mut self.write, auth_request).await;
                 }
                 Ok(())
@@ -360,10 +442,19 @@ impl Encrypted {
                 auth_request.current = Some(CurrentRequest::KeyboardInteractive {
                     submethods: submethods.to_string(),
                 });
-                let auth = handler
-                    .auth_keyboard_interactive(user, submethods, None)
-                    .await?;
+                let auth = handler_call!(
+                    timeout = config.handler_timeout,
+                    handler.auth_keyboard_interactive(user, submethods, None)
+                )?;
                 if reply_userauth_info_response(until, auth_request, &mut self.write, auth).await? {
+                    *auth_info = Some(AuthInfo {
+                        user: user.to_string(),
+                        method: MethodSet::KEYBOARD_INTERACTIVE,
+                        public_key: None,
+                        certificate: None,
+                        key_options: None,
+                        matched_rules: Vec::new(),
+                    });
                     self.state = EncryptedState::InitCompression
                 }
                 Ok(())
@@ -390,12 +481,15 @@ thread_local! {
 }
 
 impl Encrypted {
+    #[allow(clippy::too_many_arguments)]
     async fn server_read_auth_request_pk<H: Handler + Send>(
         &mut self,
+        config: &Config,
         until: Instant,
         handler: &mut H,
         buf: &[u8],
         auth_user: &mut String,
+        auth_info: &mut Option<AuthInfo>,
         user: &str,
         mut r: Position<'_>,
     ) -> Result<(), H::Error> {
@@ -408,9 +502,66 @@ impl Encrypted {
         let pubkey_algo = r.read_string().map_err(crate::Error::from)?;
         let pubkey_key = r.read_string().map_err(crate::Error::from)?;
         debug!("algo: {:?}, key: {:?}", pubkey_algo, pubkey_key);
-        match key::PublicKey::parse(pubkey_algo, pubkey_key) {
+        // With a certificate, the signature is made by the certified
+        // key, with the algorithm named without the certificate suffix.
+        let (key_algo, key_blob, certificate) =
+            match pubkey_algo.strip_suffix(CERT_ALGO_SUFFIX.as_bytes()) {
+                Some(key_algo) => {
+                    let cert = ssh_key::Certificate::from_bytes(pubkey_key).ok();
+                    let blob = cert.as_ref().and_then(|cert| {
+                        ssh_key::PublicKey::from(cert.public_key().clone())
+                            .to_bytes()
+                            .ok()
+                    });
+                    match (cert, blob) {
+                        (Some(cert), Some(blob)) => (key_algo, Cow::Owned(blob), Some(cert)),
+                        _ => {
+                            debug!("invalid certificate");
+                            reject_auth_request(until, &mut self.write, auth_request).await;
+                            return Ok(());
+                        }
+                    }
+                }
+                None => (pubkey_algo, Cow::Borrowed(pubkey_key), None),
+            };
+        // `parse` checks that the algorithm is valid for the key type,
+        // we also need it to be one we advertised in server-sig-algs.
+        let algo_accepted = config
+            .preferred
+            .key
+            .iter()
+            .any(|n| n.as_ref().as_bytes() == key_algo);
+        match key::PublicKey::parse(key_algo, &key_blob) {
+            Ok(_) if !algo_accepted => {
+                debug!(
+                    "public key algorithm not accepted: {:?}",
+                    std::str::from_utf8(pubkey_algo)
+                );
+                reject_auth_request(until, &mut self.write, auth_request).await;
+                Ok(())
+            }
             Ok(mut pubkey) => {
                 debug!("is_real = {:?}", is_real);
+                // Refuse weak keys, and keys too expensive to verify,
+                // before the handler or the signature check.
+                if let Err(e) = crate::key::check_key_bits(
+                    &pubkey,
+                    &config.minimum_key_bits,
+                    &config.maximum_key_bits,
+                ) {
+                    debug!("{}", e);
+                    reject_auth_request(until, &mut self.write, auth_request).await;
+                    handler_call!(
+                        timeout = config.handler_timeout,
+                        handler.auth_publickey_size_rejected(user, &pubkey, &e)
+                    )?;
+                    return Ok(());
+                }
+                // For RSA keys, the algorithm in the request (not the key
+                // blob) determines the signature hash.
+                if let Some(hash) = key::SignatureHash::from_rsa_hostkey_algo(key_algo) {
+                    pubkey.set_algorithm(hash);
+                }
 
                 if is_real != 0 {
                     let pos0 = r.position;
@@ -426,21 +577,25 @@ impl Encrypted {
                     debug!("signature = {:?}", signature);
                     let mut s = signature.reader(0);
                     let algo_ = s.read_string().map_err(crate::Error::from)?;
-                    if let Some(hash) = key::SignatureHash::from_rsa_hostkey_algo(algo_) {
-                        pubkey.set_algorithm(hash);
-                    }
                     debug!("algo_: {:?}", algo_);
                     let sig = s.read_string().map_err(crate::Error::from)?;
                     #[allow(clippy::indexing_slicing)] // length checked
                     let init = &buf[0..pos0];
 
-                    let is_valid = if sent_pk_ok && user == auth_user {
+                    let is_valid = if algo_ != key_algo {
+                        // RFC 8332: the signature must use the algorithm named in the request.
+                        debug!("signature algorithm doesn't match the request");
+                        false
+                    } else if sent_pk_ok && user == auth_user {
                         true
                     } else if auth_user.is_empty() {
                         auth_user.clear();
                         auth_user.push_str(user);
-                        let auth = handler.auth_publickey_offered(user, &pubkey).await?;
-                        auth == Auth::Accept
+                        let auth = handler_call!(
+                            timeout = config.handler_timeout,
+                            handler.auth_publickey_offered(user, &pubkey)
+                        )?;
+                        matches!(auth, Auth::Accept | Auth::PartialSuccess { .. })
                     } else {
                         false
                     };
@@ -456,19 +611,30 @@ impl Encrypted {
                             pubkey.verify_client_auth(&buf, sig)
                         }) {
                             debug!("signature verified");
-                            let auth = handler.auth_publickey(user, &pubkey).await?;
+                            let auth = match certificate {
+                                Some(ref cert) => handler_call!(
+                                    timeout = config.handler_timeout,
+                                    handler.auth_openssh_certificate(user, cert)
+                                )?,
+                                None => handler_call!(
+                                    timeout = config.handler_timeout,
+                                    handler.auth_publickey(user, &pubkey)
+                                )?,
+                            };
 
                             if auth == Auth::Accept {
+                                *auth_info = Some(AuthInfo {
+                                    user: user.to_string(),
+                                    method: MethodSet::PUBLICKEY,
+                                    public_key: Some(pubkey),
+                                    certificate,
+                                    key_options: None,
+                                    matched_rules: Vec::new(),
+                                });
                                 server_auth_request_success(&mut self.write);
                                 self.state = EncryptedState::InitCompression;
                             } else {
-                                if let Auth::Reject {
-                                    proceed_with_methods: Some(proceed_with_methods),
-                                } = auth
-                                {
-                                    auth_request.methods = proceed_with_methods;
-                                }
-                                auth_request.partial_success = false;
+                                update_auth_request(auth_request, auth, MethodSet::empty());
                                 auth_user.clear();
                                 reject_auth_request(until, &mut self.write, auth_request).await;
                             }
@@ -483,9 +649,12 @@ impl Encrypted {
                 } else {
                     auth_user.clear();
                     auth_user.push_str(user);
-                    let auth = handler.auth_publickey_offered(user, &pubkey).await?;
+                    let auth = handler_call!(
+                        timeout = config.handler_timeout,
+                        handler.auth_publickey_offered(user, &pubkey)
+                    )?;
                     match auth {
-                        Auth::Accept => {
+                        Auth::Accept | Auth::PartialSuccess { .. } => {
                             let mut public_key = CryptoVec::new();
                             public_key.extend(pubkey_key);
 
@@ -505,13 +674,7 @@ impl Encrypted {
                             });
                         }
                         auth => {
-                            if let Auth::Reject {
-                                proceed_with_methods: Some(proceed_with_methods),
-                            } = auth
-                            {
-                                auth_request.methods = proceed_with_methods;
-                            }
-                            auth_request.partial_success = false;
+                            update_auth_request(auth_request, auth, MethodSet::empty());
                             auth_user.clear();
                             reject_auth_request(until, &mut self.write, auth_request).await;
                         }
@@ -528,6 +691,24 @@ impl Encrypted {
     }
 }
 
+/// Prepares the next `USERAUTH_FAILURE` after the handler answered
+/// `auth`: advertised methods lose `tried` unless the handler chose
+/// them, and `partial_success` is set for [`Auth::PartialSuccess`].
+fn update_auth_request(auth_request: &mut AuthRequest, auth: Auth, tried: MethodSet) {
+    match auth {
+        Auth::PartialSuccess {
+            proceed_with_methods,
+        } => {
+            auth_request.methods = proceed_with_methods;
+            auth_request.partial_success = true;
+        }
+        Auth::Reject {
+            proceed_with_methods: Some(proceed_with_methods),
+        } => auth_request.methods = proceed_with_methods,
+        _ => auth_request.methods -= tried,
+    }
+}
+
 async fn reject_auth_request(
     until: Instant,
     write: &mut CryptoVec,
@@ -539,10 +720,12 @@ async fn reject_auth_request(
         write.extend_list(auth_request.methods.into_iter());
         write.push(auth_request.partial_success as u8);
     });
+    // `partial_success` only applies to the request we just answered.
+    auth_request.partial_success = false;
     auth_request.current = None;
     auth_request.rejection_count += 1;
     debug!("packet pushed");
-    tokio::time::sleep_until(until).await
+    crate::runtime::sleep(until.saturating_duration_since(Instant::now())).await
 }
 
 fn server_auth_request_success(buffer: &mut CryptoVec) {
@@ -554,6 +737,7 @@ fn server_auth_request_success(buffer: &mut CryptoVec) {
 async fn read_userauth_info_response<H: Handler + Send>(
     until: Instant,
     handler: &mut H,
+    handler_timeout: Option<std::time::Duration>,
     write: &mut CryptoVec,
     auth_request: &mut AuthRequest,
     user: &str,
@@ -563,9 +747,10 @@ async fn read_userauth_info_response<H: Handler + Send>(
         let mut r = b.reader(1);
         let n = r.read_u32().map_err(crate::Error::from)?;
         let response = Response { pos: r, n };
-        let auth = handler
-            .auth_keyboard_interactive(user, submethods, Some(response))
-            .await?;
+        let auth = handler_call!(
+            timeout = handler_timeout,
+            handler.auth_keyboard_interactive(user, submethods, Some(response))
+        )?;
         let resp = reply_userauth_info_response(until, auth_request, write, auth)
             .await
             .map_err(H::Error::from)?;
@@ -587,13 +772,8 @@ async fn reply_userauth_info_response(
             server_auth_request_success(write);
             Ok(true)
         }
-        Auth::Reject {
-            proceed_with_methods,
-        } => {
-            if let Some(proceed_with_methods) = proceed_with_methods {
-                auth_request.methods = proceed_with_methods;
-            }
-            auth_request.partial_success = false;
+        auth @ (Auth::Reject { .. } | Auth::PartialSuccess { .. }) => {
+            update_auth_request(auth_request, auth, MethodSet::empty());
             reject_auth_request(until, write, auth_request).await;
             Ok(false)
         }
@@ -623,6 +803,7 @@ impl Session {
     async fn server_read_authenticated<H: Handler + Send>(
         &mut self,
         handler: &mut H,
+        packet_seqn: u32,
         buf: &[u8],
     ) -> Result<(), H::Error> {
         #[allow(clippy::indexing_slicing)] // length checked
@@ -641,20 +822,22 @@ impl Session {
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32().map_err(crate::Error::from)?);
                 if let Some(ref mut enc) = self.common.encrypted {
-                    enc.channels.remove(&channel_num);
+                    enc.peer_closed(channel_num);
+                }
+                if let Some(channel) = self.channels.remove(&channel_num) {
+                    channel.set_closed();
                 }
-                self.channels.remove(&channel_num);
                 debug!("handler.channel_close {:?}", channel_num);
-                handler.channel_close(channel_num, self).await
+                handler_call!(self, handler.channel_close(channel_num, self))
             }
             Some(&msg::CHANNEL_EOF) => {
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32().map_err(crate::Error::from)?);
-                if let Some(chan) = self.channels.get(&channel_num) {
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
                     chan.send(ChannelMsg::Eof).unwrap_or(())
                 }
                 debug!("handler.channel_eof {:?}", channel_num);
-                handler.channel_eof(channel_num, self).await
+                handler_call!(self, handler.channel_eof(channel_num, self))
             }
             Some(&msg::CHANNEL_EXTENDED_DATA) | Some(&msg::CHANNEL_DATA) => {
                 let mut r = buf.reader(1);
@@ -679,22 +862,24 @@ impl Session {
                 }
                 self.flush()?;
                 if let Some(ext) = ext {
-                    if let Some(chan) = self.channels.get(&channel_num) {
+                    handler.on_channel_extended_data(Direction::Inbound, channel_num, ext, data);
+                    if let Some(chan) = self.channels.get_mut(&channel_num) {
                         chan.send(ChannelMsg::ExtendedData {
                             ext,
-                            data: CryptoVec::from_slice(data),
+                            data: CryptoVec::from_slice_pooled(data),
                         })
                         .unwrap_or(())
                     }
-                    handler.extended_data(channel_num, ext, data, self).await
+                    handler_call!(self, handler.extended_data(channel_num, ext, data, self))
                 } else {
-                    if let Some(chan) = self.channels.get(&channel_num) {
+                    handler.on_channel_data(Direction::Inbound, channel_num, data);
+                    if let Some(chan) = self.channels.get_mut(&channel_num) {
                         chan.send(ChannelMsg::Data {
-                            data: CryptoVec::from_slice(data),
+                            data: CryptoVec::from_slice_pooled(data),
                         })
                         .unwrap_or(())
                     }
-                    handler.data(channel_num, data, self).await
+                    handler_call!(self, handler.data(channel_num, data, self))
                 }
             }
 
@@ -714,14 +899,14 @@ impl Session {
                 if let Some(ref mut enc) = self.common.encrypted {
                     enc.flush_pending(channel_num);
                 }
-                if let Some(chan) = self.channels.get(&channel_num) {
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
                     *chan.window_size().lock().await = new_size;
 
                     chan.send(ChannelMsg::WindowAdjusted { new_size })
                         .unwrap_or(())
                 }
                 debug!("handler.window_adjusted {:?}", channel_num);
-                handler.window_adjusted(channel_num, new_size, self).await
+                handler_call!(self, handler.window_adjusted(channel_num, new_size, self))
             }
 
             Some(&msg::CHANNEL_OPEN_CONFIRMATION) => {
@@ -731,17 +916,20 @@ impl Session {
                 let local_id = ChannelId(msg.recipient_channel);
 
                 if let Some(ref mut enc) = self.common.encrypted {
-                    if let Some(parameters) = enc.channels.get_mut(&local_id) {
-                        parameters.confirm(&msg);
-                    } else {
+                    if !enc.confirm_channel(&msg) {
                         // We've not requested this channel, close connection.
-                        return Err(Error::Inconsistent.into());
+                        let e = Error::Inconsistent;
+                        return Err(self.common.error_disconnect.record(e).into());
                     }
                 } else {
-                    return Err(Error::Inconsistent.into());
+                    return Err(self
+                        .common
+                        .error_disconnect
+                        .record(Error::Inconsistent)
+                        .into());
                 };
 
-                if let Some(channel) = self.channels.get(&local_id) {
+                if let Some(channel) = self.channels.get_mut(&local_id) {
                     channel
                         .send(ChannelMsg::Open {
                             id: local_id,
@@ -752,14 +940,15 @@ impl Session {
                 } else {
                     error!("no channel for id {:?}", local_id);
                 }
-                handler
-                    .channel_open_confirmation(
+                handler_call!(
+                    self,
+                    handler.channel_open_confirmation(
                         local_id,
                         msg.maximum_packet_size,
                         msg.initial_window_size,
                         self,
                     )
-                    .await
+                )
             }
 
             Some(&msg::CHANNEL_REQUEST) => {
@@ -808,7 +997,7 @@ impl Session {
                             }
                         }
 
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::RequestPty {
                                 want_reply: true,
                                 term: term.into(),
@@ -822,18 +1011,20 @@ impl Session {
 
                         debug!("handler.pty_request {:?}", channel_num);
                         #[allow(clippy::indexing_slicing)] // `modes` length checked
-                        handler
-                            .pty_request(
+                        let modes = &modes[0..i];
+                        handler_call!(
+                            self,
+                            handler.pty_request(
                                 channel_num,
                                 term,
                                 col_width,
                                 row_height,
                                 pix_width,
                                 pix_height,
-                                &modes[0..i],
+                                modes,
                                 self,
                             )
-                            .await
+                        )
                     }
                     b"x11-req" => {
                         let single_connection = r.read_byte().map_err(crate::Error::from)? != 0;
@@ -845,7 +1036,7 @@ impl Session {
                                 .map_err(crate::Error::from)?;
                         let x11_screen_number = r.read_u32().map_err(crate::Error::from)?;
 
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::RequestX11 {
                                 want_reply: true,
                                 single_connection,
@@ -855,8 +1046,9 @@ impl Session {
                             });
                         }
                         debug!("handler.x11_request {:?}", channel_num);
-                        handler
-                            .x11_request(
+                        handler_call!(
+                            self,
+                            handler.x11_request(
                                 channel_num,
                                 single_connection,
                                 x11_auth_protocol,
@@ -864,7 +1056,7 @@ impl Session {
                                 x11_screen_number,
                                 self,
                             )
-                            .await
+                        )
                     }
                     b"env" => {
                         let env_variable =
@@ -874,7 +1066,7 @@ impl Session {
                             std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
                                 .map_err(crate::Error::from)?;
 
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::SetEnv {
                                 want_reply: true,
                                 variable_name: env_variable.into(),
@@ -883,55 +1075,57 @@ impl Session {
                         }
 
                         debug!("handler.env_request {:?}", channel_num);
-                        handler
-                            .env_request(channel_num, env_variable, env_value, self)
-                            .await
+                        handler_call!(
+                            self,
+                            handler.env_request(channel_num, env_variable, env_value, self)
+                        )
                     }
                     b"shell" => {
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::RequestShell { want_reply: true });
                         }
                         debug!("handler.shell_request {:?}", channel_num);
-                        handler.shell_request(channel_num, self).await
+                        handler_call!(self, handler.shell_request(channel_num, self))
                     }
                     b"auth-agent-req@openssh.com" => {
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::AgentForward { want_reply: true });
                         }
                         debug!("handler.agent_request {:?}", channel_num);
 
-                        let response = handler.agent_request(channel_num, self).await?;
+                        let response =
+                            handler_call!(self, handler.agent_request(channel_num, self))?;
                         if response {
-                            self.request_success()
+                            self.channel_success(channel_num)
                         } else {
-                            self.request_failure()
+                            self.channel_failure(channel_num)
                         }
                         Ok(())
                     }
                     b"exec" => {
                         let req = r.read_string().map_err(crate::Error::from)?;
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::Exec {
                                 want_reply: true,
                                 command: req.into(),
                             });
                         }
                         debug!("handler.exec_request {:?}", channel_num);
-                        handler.exec_request(channel_num, req, self).await
+                        handler_call!(self, handler.exec_request(channel_num, req, self))
                     }
                     b"subsystem" => {
                         let name =
                             std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
                                 .map_err(crate::Error::from)?;
 
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::RequestSubsystem {
                                 want_reply: true,
                                 name: name.into(),
                             });
                         }
                         debug!("handler.subsystem_request {:?}", channel_num);
-                        handler.subsystem_request(channel_num, name, self).await
+                        handler_call!(self, handler.subsystem_request(channel_num, name, self))
                     }
                     b"window-change" => {
                         let col_width = r.read_u32().map_err(crate::Error::from)?;
@@ -939,7 +1133,7 @@ impl Session {
                         let pix_width = r.read_u32().map_err(crate::Error::from)?;
                         let pix_height = r.read_u32().map_err(crate::Error::from)?;
 
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             let _ = chan.send(ChannelMsg::WindowChange {
                                 col_width,
                                 row_height,
@@ -949,8 +1143,9 @@ impl Session {
                         }
 
                         debug!("handler.window_change {:?}", channel_num);
-                        handler
-                            .window_change_request(
+                        handler_call!(
+                            self,
+                            handler.window_change_request(
                                 channel_num,
                                 col_width,
                                 row_height,
@@ -958,18 +1153,39 @@ impl Session {
                                 pix_height,
                                 self,
                             )
-                            .await
+                        )
                     }
                     b"signal" => {
-                        let signal = Sig::from_name(r.read_string().map_err(crate::Error::from)?)?;
-                        if let Some(chan) = self.channels.get(&channel_num) {
+                        let signal = Sig::from_name(r.read_string().map_err(crate::Error::from)?);
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
                             chan.send(ChannelMsg::Signal {
                                 signal: signal.clone(),
                             })
                             .unwrap_or(())
                         }
                         debug!("handler.signal {:?} {:?}", channel_num, signal);
-                        handler.signal(channel_num, signal, self).await
+                        handler_call!(self, handler.signal(channel_num, signal, self))
+                    }
+                    b"label@russh.rs" => {
+                        let label =
+                            String::from_utf8_lossy(r.read_string().map_err(crate::Error::from)?)
+                                .into_owned();
+                        debug!("channel {:?} labeled {:?}", channel_num, label);
+                        if let Some(ref mut enc) = self.common.encrypted {
+                            enc.label_channel(channel_num, &label, false);
+                        }
+                        if let Some(chan) = self.channels.get(&channel_num) {
+                            chan.label().set(&label);
+                        }
+                        self.channel_success(channel_num);
+                        Ok(())
+                    }
+                    b"eow@openssh.com" => {
+                        if let Some(chan) = self.channels.get_mut(&channel_num) {
+                            chan.send(ChannelMsg::EndOfWrite).unwrap_or(())
+                        }
+                        debug!("handler.end_of_write {:?}", channel_num);
+                        handler_call!(self, handler.end_of_write(channel_num, self))
                     }
                     x => {
                         warn!("unknown channel request {}", String::from_utf8_lossy(x));
@@ -988,16 +1204,31 @@ impl Session {
                             std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
                                 .map_err(crate::Error::from)?;
                         let port = r.read_u32().map_err(crate::Error::from)?;
+                        if !self.forwarding_policy.allows_listen(address, port) {
+                            info!("tcpip-forward {:?} {:?} denied by policy", address, port);
+                            if self.common.wants_reply {
+                                self.request_failure()
+                            }
+                            let denied = DeniedForwarding::Listen {
+                                address: address.to_string(),
+                                port,
+                            };
+                            return handler_call!(self, handler.forwarding_denied(&denied, self));
+                        }
                         debug!("handler.tcpip_forward {:?} {:?}", address, port);
                         let mut returned_port = port;
-                        let result = handler
-                            .tcpip_forward(address, &mut returned_port, self)
-                            .await?;
+                        let result = handler_call!(
+                            self,
+                            handler.tcpip_forward(address, &mut returned_port, self)
+                        )?;
+                        if !self.common.wants_reply {
+                            return Ok(());
+                        }
                         if let Some(ref mut enc) = self.common.encrypted {
                             if result {
                                 push_packet!(enc.write, {
                                     enc.write.push(msg::REQUEST_SUCCESS);
-                                    if self.common.wants_reply && port == 0 && returned_port != 0 {
+                                    if port == 0 && returned_port != 0 {
                                         enc.write.push_u32_be(returned_port);
                                     }
                                 })
@@ -1013,26 +1244,50 @@ impl Session {
                                 .map_err(crate::Error::from)?;
                         let port = r.read_u32().map_err(crate::Error::from)?;
                         debug!("handler.cancel_tcpip_forward {:?} {:?}", address, port);
-                        let result = handler.cancel_tcpip_forward(address, port, self).await?;
-                        if let Some(ref mut enc) = self.common.encrypted {
-                            if result {
-                                push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
-                            } else {
-                                push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
-                            }
+                        let result =
+                            handler_call!(self, handler.cancel_tcpip_forward(address, port, self))?;
+                        if result {
+                            self.request_success()
+                        } else if self.common.wants_reply {
+                            self.request_failure()
+                        }
+                        Ok(())
+                    }
+                    b"no-more-sessions@openssh.com" => {
+                        debug!("handler.no_more_sessions");
+                        self.no_more_sessions = true;
+                        handler_call!(self, handler.no_more_sessions(self))?;
+                        if self.common.wants_reply {
+                            self.request_success()
                         }
                         Ok(())
                     }
                     _ => {
-                        if let Some(ref mut enc) = self.common.encrypted {
-                            push_packet!(enc.write, {
-                                enc.write.push(msg::REQUEST_FAILURE);
-                            });
+                        if self.common.wants_reply {
+                            self.request_failure()
                         }
                         Ok(())
                     }
                 }
             }
+            Some(&msg::CHANNEL_SUCCESS) => {
+                let mut r = buf.reader(1);
+                let channel_num = ChannelId(r.read_u32().map_err(crate::Error::from)?);
+                debug!("channel_success {:?}", channel_num);
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
+                    let _ = chan.send(ChannelMsg::Success);
+                }
+                Ok(())
+            }
+            Some(&msg::CHANNEL_FAILURE) => {
+                let mut r = buf.reader(1);
+                let channel_num = ChannelId(r.read_u32().map_err(crate::Error::from)?);
+                debug!("channel_failure {:?}", channel_num);
+                if let Some(chan) = self.channels.get_mut(&channel_num) {
+                    let _ = chan.send(ChannelMsg::Failure);
+                }
+                Ok(())
+            }
             Some(&msg::CHANNEL_OPEN_FAILURE) => {
                 debug!("channel_open_failure");
                 let mut buf_pos = buf.reader(1);
@@ -1054,7 +1309,7 @@ impl Session {
                     enc.channels.remove(&channel_num);
                 }
 
-                if let Some(channel_sender) = self.channels.remove(&channel_num) {
+                if let Some(mut channel_sender) = self.channels.remove(&channel_num) {
                     channel_sender
                         .send(ChannelMsg::OpenFailure(reason))
                         .map_err(|_| crate::Error::SendError)?;
@@ -1064,29 +1319,30 @@ impl Session {
             }
             Some(&msg::REQUEST_SUCCESS) => {
                 trace!("Global Request Success");
-                match self.open_global_requests.pop_front() {
-                    Some(GlobalRequestResponse::Keepalive) => {
+                match self.global_requests.pop(
+                    &mut self.common.encrypted,
+                    self.common.config.max_pending_global_requests,
+                ) {
+                    Some(
+                        GlobalRequestResponse::Keepalive | GlobalRequestResponse::KeepaliveReply(_),
+                    ) => {
                         // ignore keepalives
                     }
                     Some(GlobalRequestResponse::TcpIpForward(return_channel)) => {
-                        let result = if buf.len() == 1 {
-                            // If a specific port was requested, the reply has no data
-                            Some(0)
-                        } else {
-                            let mut r = buf.reader(1);
-                            match r.read_u32() {
-                                Ok(port) => Some(port),
-                                Err(e) => {
-                                    error!("Error parsing port for TcpIpForward request: {e:?}");
-                                    None
-                                }
-                            }
-                        };
-                        let _ = return_channel.send(result);
+                        let _ = return_channel.send(forwarded_port(buf));
+                    }
+                    Some(GlobalRequestResponse::LocalTcpIpForward { reply_channel, .. }) => {
+                        // Only sent by clients.
+                        if let Some(reply_channel) = reply_channel {
+                            let _ = reply_channel.send(forwarded_port(buf));
+                        }
                     }
                     Some(GlobalRequestResponse::CancelTcpIpForward(return_channel)) => {
                         let _ = return_channel.send(true);
                     }
+                    Some(GlobalRequestResponse::Raw(return_channel)) => {
+                        let _ = return_channel.send(buf.get(1..).map(<[u8]>::to_vec));
+                    }
                     None => {
                         error!("Received global request failure for unknown request!")
                     }
@@ -1095,16 +1351,11 @@ impl Session {
             }
             Some(&msg::REQUEST_FAILURE) => {
                 trace!("global request failure");
-                match self.open_global_requests.pop_front() {
-                    Some(GlobalRequestResponse::Keepalive) => {
-                        // ignore keepalives
-                    }
-                    Some(GlobalRequestResponse::TcpIpForward(return_channel)) => {
-                        let _ = return_channel.send(None);
-                    }
-                    Some(GlobalRequestResponse::CancelTcpIpForward(return_channel)) => {
-                        let _ = return_channel.send(false);
-                    }
+                match self.global_requests.pop(
+                    &mut self.common.encrypted,
+                    self.common.config.max_pending_global_requests,
+                ) {
+                    Some(request) => request.fail(),
                     None => {
                         error!("Received global request failure for unknown request!")
                     }
@@ -1113,6 +1364,9 @@ impl Session {
             }
             m => {
                 debug!("unknown message received: {:?}", m);
+                if m.map_or(false, |&t| !msg::is_known(t)) {
+                    self.common.unimplemented(packet_seqn);
+                }
                 Ok(())
             }
         }
@@ -1142,10 +1396,18 @@ impl Session {
             recipient_maximum_packet_size: msg.recipient_maximum_packet_size,
             sender_maximum_packet_size: self.common.config.maximum_packet_size,
             confirmed: true,
+            kind: msg.typ.name(),
+            created_at: crate::runtime::now(),
+            initial_window_size: msg.recipient_window_size,
+            bytes_sent: 0,
+            bytes_received: 0,
             wants_reply: false,
+            pending_replies: 0,
             pending_data: std::collections::VecDeque::new(),
-            pending_eof: false,
+            pending_messages: std::collections::VecDeque::new(),
             pending_close: false,
+            label: None,
+            data_rate: self.common.config.per_channel_rate.map(TokenBucket::new),
         };
 
         let (channel, reference) = Channel::new(
@@ -1153,11 +1415,25 @@ impl Session {
             self.sender.sender.clone(),
             channel_params.recipient_maximum_packet_size,
             channel_params.recipient_window_size,
+            self.common.config.channel_buffer_size,
         );
 
         match &msg.typ {
+            ChannelType::Session
+                if self.no_more_sessions && self.common.config.enforce_no_more_sessions =>
+            {
+                info!("session channel refused after no-more-sessions@openssh.com");
+                if let Some(ref mut enc) = self.common.encrypted {
+                    msg.fail(
+                        &mut enc.write,
+                        SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                        b"No more sessions",
+                    );
+                }
+                Ok(false)
+            }
             ChannelType::Session => {
-                let mut result = handler.channel_open_session(channel, self).await;
+                let mut result = handler_call!(self, handler.channel_open_session(channel, self));
                 if let Ok(allowed) = &mut result {
                     self.channels.insert(sender_channel, reference);
                     self.finalize_channel_open(&msg, channel_params, *allowed);
@@ -1168,18 +1444,45 @@ impl Session {
                 originator_address,
                 originator_port,
             } => {
-                let mut result = handler
-                    .channel_open_x11(channel, originator_address, *originator_port, self)
-                    .await;
+                let mut result = handler_call!(
+                    self,
+                    handler.channel_open_x11(channel, originator_address, *originator_port, self)
+                );
                 if let Ok(allowed) = &mut result {
                     self.channels.insert(sender_channel, reference);
                     self.finalize_channel_open(&msg, channel_params, *allowed);
                 }
                 result
             }
+            ChannelType::DirectTcpip(d)
+                if !self
+                    .forwarding_policy
+                    .allows_open(&d.host_to_connect, d.port_to_connect) =>
+            {
+                info!(
+                    "direct-tcpip to {:?} {:?} denied by policy",
+                    d.host_to_connect, d.port_to_connect
+                );
+                if let Some(ref mut enc) = self.common.encrypted {
+                    msg.fail(
+                        &mut enc.write,
+                        SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                        b"Forwarding not permitted",
+                    );
+                }
+                let denied = DeniedForwarding::Open {
+                    host_to_connect: d.host_to_connect.clone(),
+                    port_to_connect: d.port_to_connect,
+                    originator_address: d.originator_address.clone(),
+                    originator_port: d.originator_port,
+                };
+                handler_call!(self, handler.forwarding_denied(&denied, self))?;
+                Ok(false)
+            }
             ChannelType::DirectTcpip(d) => {
-                let mut result = handler
-                    .channel_open_direct_tcpip(
+                let mut result = handler_call!(
+                    self,
+                    handler.channel_open_direct_tcpip(
                         channel,
                         &d.host_to_connect,
                         d.port_to_connect,
@@ -1187,7 +1490,7 @@ impl Session {
                         d.originator_port,
                         self,
                     )
-                    .await;
+                );
                 if let Ok(allowed) = &mut result {
                     self.channels.insert(sender_channel, reference);
                     self.finalize_channel_open(&msg, channel_params, *allowed);
@@ -1195,8 +1498,9 @@ impl Session {
                 result
             }
             ChannelType::ForwardedTcpIp(d) => {
-                let mut result = handler
-                    .channel_open_forwarded_tcpip(
+                let mut result = handler_call!(
+                    self,
+                    handler.channel_open_forwarded_tcpip(
                         channel,
                         &d.host_to_connect,
                         d.port_to_connect,
@@ -1204,7 +1508,7 @@ impl Session {
                         d.originator_port,
                         self,
                     )
-                    .await;
+                );
                 if let Ok(allowed) = &mut result {
                     self.channels.insert(sender_channel, reference);
                     self.finalize_channel_open(&msg, channel_params, *allowed);
@@ -1256,3 +1560,59 @@ impl Session {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, clippy::indexing_slicing)]
+    use super::*;
+
+    fn failure_packet(write: &CryptoVec) -> (Vec<String>, bool) {
+        let mut r = write.reader(4);
+        assert_eq!(r.read_byte().unwrap(), msg::USERAUTH_FAILURE);
+        let methods = std::str::from_utf8(r.read_string().unwrap())
+            .unwrap()
+            .split(',')
+            .map(String::from)
+            .collect();
+        (methods, r.read_byte().unwrap() != 0)
+    }
+
+    #[tokio::test]
+    async fn test_partial_success_flag() {
+        let mut auth_request = AuthRequest {
+            methods: MethodSet::PUBLICKEY | MethodSet::PASSWORD,
+            partial_success: false,
+            current: None,
+            rejection_count: 0,
+            allowed_methods: MethodSet::all(),
+            methods_user: None,
+        };
+
+        // The first factor succeeds.
+        let mut write = CryptoVec::new();
+        update_auth_request(
+            &mut auth_request,
+            Auth::PartialSuccess {
+                proceed_with_methods: MethodSet::PASSWORD,
+            },
+            MethodSet::PUBLICKEY,
+        );
+        reject_auth_request(Instant::now(), &mut write, &mut auth_request).await;
+        assert_eq!(failure_packet(&write), (vec!["password".to_string()], true));
+
+        // The second one fails.
+        let mut write = CryptoVec::new();
+        update_auth_request(
+            &mut auth_request,
+            Auth::Reject {
+                proceed_with_methods: None,
+            },
+            MethodSet::empty(),
+        );
+        reject_auth_request(Instant::now(), &mut write, &mut auth_request).await;
+        assert_eq!(
+            failure_packet(&write),
+            (vec!["password".to_string()], false)
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Restrictions on port forwarding, see [`ForwardingPolicy`].
+use log::warn;
+
+use super::auth::{match_wildcard, KeyOptions};
+
+/// A `host:port` pattern of a [`ForwardingPolicy`]. `*` stands for any
+/// host or any port, and IPv6 addresses are written in brackets, as in
+/// `[::1]:8080`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardPattern {
+    /// The host, without brackets. `*` matches any host.
+    pub host: String,
+    /// The port, or `None` for any port.
+    pub port: Option<u16>,
+}
+
+impl ForwardPattern {
+    /// Parses a `permitopen` pattern: `host:port`.
+    pub fn open(s: &str) -> Option<Self> {
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']')?;
+            (host, rest.strip_prefix(':')?)
+        } else {
+            s.split_once(':')?
+        };
+        let port = match port {
+            "*" => None,
+            port => match port.parse() {
+                Ok(0) | Err(_) => return None,
+                Ok(port) => Some(port),
+            },
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(ForwardPattern {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Parses a `permitlisten` pattern: `[host:]port`, where the host
+    /// may contain the `*` and `?` wildcards. A port alone allows any
+    /// host.
+    pub fn listen(s: &str) -> Option<Self> {
+        if s.contains(':') {
+            Self::open(s)
+        } else {
+            Self::open(&format!("*:{}", s))
+        }
+    }
+
+    fn matches_open(&self, host: &str, port: u32) -> bool {
+        self.port.map_or(true, |p| u32::from(p) == port) && (self.host == "*" || self.host == host)
+    }
+
+    fn matches_listen(&self, host: &str, port: u32) -> bool {
+        self.port.map_or(true, |p| u32::from(p) == port)
+            && match_wildcard(&self.host, &host.to_lowercase())
+    }
+}
+
+/// Which forwardings a session allows, the way `sshd` applies
+/// `PermitOpen`/`PermitListen` and the `permitopen=`/`permitlisten=`
+/// key options.
+///
+/// The session checks every `direct-tcpip` channel and `tcpip-forward`
+/// request against its policy before calling the
+/// [`Handler`](super::Handler). Denied requests are refused with
+/// `SSH_OPEN_ADMINISTRATIVELY_PROHIBITED` or `SSH_MSG_REQUEST_FAILURE`,
+/// and reported to
+/// [`Handler::forwarding_denied`](super::Handler::forwarding_denied).
+/// As in `sshd`, no name lookup is done: `localhost`, `127.0.0.1` and
+/// `::1` are different hosts.
+///
+/// Each call to [`ForwardingPolicy::permit_open`] or
+/// [`ForwardingPolicy::permit_listen`] adds a list of patterns, and a
+/// request must match one of the patterns of every list. This is how
+/// the options of a key restrict the server's configuration further.
+/// The default policy allows everything.
+///
+/// ```
+/// use russh::server::{ForwardPattern, ForwardingPolicy};
+///
+/// let policy = ForwardingPolicy::new()
+///     .permit_open(ForwardPattern::open("db.internal:5432"))
+///     .permit_listen(ForwardPattern::listen("localhost:*"));
+/// assert!(policy.allows_open("db.internal", 5432));
+/// assert!(!policy.allows_open("10.0.0.5", 5432));
+/// assert!(policy.allows_listen("localhost", 8080));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardingPolicy {
+    open: Vec<Vec<ForwardPattern>>,
+    listen: Vec<Vec<ForwardPattern>>,
+}
+
+impl ForwardingPolicy {
+    /// A policy allowing every forwarding.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy refusing every forwarding.
+    pub fn deny_all() -> Self {
+        Self::new()
+            .permit_open(std::iter::empty())
+            .permit_listen(std::iter::empty())
+    }
+
+    /// The options of a key: `no-port-forwarding` (or a certificate
+    /// without `permit-port-forwarding`) refuses everything, and the
+    /// `permitopen=` and `permitlisten=` patterns restrict forwarding
+    /// to them.
+    pub fn from_key_options(options: &KeyOptions) -> Self {
+        if options.no_port_forwarding {
+            return Self::deny_all();
+        }
+        let mut policy = Self::new();
+        if !options.permit_open.is_empty() {
+            policy = policy.permit_open(
+                options
+                    .permit_open
+                    .iter()
+                    .filter_map(|p| parsed(p, ForwardPattern::open(p))),
+            );
+        }
+        if !options.permit_listen.is_empty() {
+            policy = policy.permit_listen(
+                options
+                    .permit_listen
+                    .iter()
+                    .filter_map(|p| parsed(p, ForwardPattern::listen(p))),
+            );
+        }
+        policy
+    }
+
+    /// Only allows the `direct-tcpip` destinations matching one of
+    /// `patterns`, on top of the previous restrictions. An empty list
+    /// refuses all of them.
+    pub fn permit_open<I: IntoIterator<Item = ForwardPattern>>(mut self, patterns: I) -> Self {
+        self.open.push(patterns.into_iter().collect());
+        self
+    }
+
+    /// Only allows the `tcpip-forward` addresses matching one of
+    /// `patterns`, on top of the previous restrictions. An empty list
+    /// refuses all of them.
+    pub fn permit_listen<I: IntoIterator<Item = ForwardPattern>>(mut self, patterns: I) -> Self {
+        self.listen.push(patterns.into_iter().collect());
+        self
+    }
+
+    /// Adds the restrictions of `other` to this policy.
+    pub fn restrict(&mut self, other: ForwardingPolicy) {
+        self.open.extend(other.open);
+        self.listen.extend(other.listen);
+    }
+
+    /// Whether a `direct-tcpip` channel to `host:port` is allowed. The
+    /// host must be the same string as in the pattern.
+    pub fn allows_open(&self, host: &str, port: u32) -> bool {
+        self.open
+            .iter()
+            .all(|patterns| patterns.iter().any(|p| p.matches_open(host, port)))
+    }
+
+    /// Whether listening on `host:port` is allowed. The host is
+    /// lowercased, then matched against the wildcards of the pattern.
+    pub fn allows_listen(&self, host: &str, port: u32) -> bool {
+        self.listen
+            .iter()
+            .all(|patterns| patterns.iter().any(|p| p.matches_listen(host, port)))
+    }
+}
+
+fn parsed(s: &str, pattern: Option<ForwardPattern>) -> Option<ForwardPattern> {
+    if pattern.is_none() {
+        warn!("ignoring invalid forwarding pattern {:?}", s);
+    }
+    pattern
+}
+
+/// A forwarding request refused by the [`ForwardingPolicy`] of the
+/// session, see
+/// [`Handler::forwarding_denied`](super::Handler::forwarding_denied).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeniedForwarding {
+    /// A `direct-tcpip` channel.
+    Open {
+        host_to_connect: String,
+        port_to_connect: u32,
+        originator_address: String,
+        originator_port: u32,
+    },
+    /// A `tcpip-forward` request.
+    Listen { address: String, port: u32 },
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    fn open(patterns: &[&str]) -> ForwardingPolicy {
+        ForwardingPolicy::new()
+            .permit_open(patterns.iter().map(|p| ForwardPattern::open(p).unwrap()))
+    }
+
+    fn listen(patterns: &[&str]) -> ForwardingPolicy {
+        ForwardingPolicy::new()
+            .permit_listen(patterns.iter().map(|p| ForwardPattern::listen(p).unwrap()))
+    }
+
+    #[test]
+    fn test_parse_patterns() {
+        let pattern = |host: &str, port| {
+            Some(ForwardPattern {
+                host: host.to_string(),
+                port,
+            })
+        };
+        assert_eq!(ForwardPattern::open("host:22"), pattern("host", Some(22)));
+        assert_eq!(ForwardPattern::open("*:*"), pattern("*", None));
+        assert_eq!(ForwardPattern::open("[::1]:22"), pattern("::1", Some(22)));
+        assert_eq!(
+            ForwardPattern::open("[fe80::1]:*"),
+            pattern("fe80::1", None)
+        );
+        assert_eq!(ForwardPattern::listen("8080"), pattern("*", Some(8080)));
+        assert_eq!(ForwardPattern::listen("*"), pattern("*", None));
+        assert_eq!(ForwardPattern::listen("[::1]:80"), pattern("::1", Some(80)));
+        for invalid in [
+            "host",
+            "host:",
+            "host:0",
+            "host:65536",
+            ":22",
+            "::1:22",
+            "[::1]",
+            "[::1]22",
+        ] {
+            assert_eq!(ForwardPattern::open(invalid), None, "{:?}", invalid);
+        }
+        assert_eq!(ForwardPattern::listen("[::1]"), None);
+        assert_eq!(ForwardPattern::listen("port"), None);
+    }
+
+    #[test]
+    fn test_open_wildcard_ports() {
+        let policy = open(&["db:5432", "web:*"]);
+        assert!(policy.allows_open("db", 5432));
+        assert!(!policy.allows_open("db", 5433));
+        assert!(policy.allows_open("web", 80));
+        assert!(policy.allows_open("web", 65535));
+        assert!(open(&["*:22"]).allows_open("anything", 22));
+        assert!(!open(&["*:22"]).allows_open("anything", 23));
+        assert!(open(&["*:*"]).allows_open("anything", 1));
+    }
+
+    #[test]
+    fn test_open_hosts_are_literal() {
+        let policy = open(&["localhost:80", "[::1]:443"]);
+        assert!(policy.allows_open("localhost", 80));
+        assert!(!policy.allows_open("127.0.0.1", 80));
+        assert!(!policy.allows_open("::1", 80));
+        assert!(!policy.allows_open("LOCALHOST", 80));
+        assert!(policy.allows_open("::1", 443));
+        assert!(!policy.allows_open("0:0:0:0:0:0:0:1", 443));
+        assert!(!policy.allows_open("[::1]", 443));
+        // Only a whole `*` is a wildcard.
+        assert!(!open(&["*.internal:80"]).allows_open("db.internal", 80));
+    }
+
+    #[test]
+    fn test_listen_patterns() {
+        let policy = listen(&["localhost:8080", "*.example.com:*", "[::1]:9000"]);
+        assert!(policy.allows_listen("localhost", 8080));
+        assert!(policy.allows_listen("LocalHost", 8080));
+        assert!(!policy.allows_listen("127.0.0.1", 8080));
+        assert!(!policy.allows_listen("localhost", 8081));
+        assert!(policy.allows_listen("a.example.com", 1));
+        assert!(!policy.allows_listen("example.com", 1));
+        assert!(policy.allows_listen("::1", 9000));
+        assert!(!policy.allows_listen("::1", 9001));
+
+        let policy = listen(&["2222"]);
+        assert!(policy.allows_listen("localhost", 2222));
+        assert!(policy.allows_listen("0.0.0.0", 2222));
+        assert!(policy.allows_listen("", 2222));
+        assert!(!policy.allows_listen("localhost", 0));
+        assert!(listen(&["*"]).allows_listen("0.0.0.0", 0));
+        assert!(listen(&["127.0.0.?:*"]).allows_listen("127.0.0.1", 80));
+    }
+
+    #[test]
+    fn test_restrictions_add_up() {
+        let mut policy = open(&["a:1", "b:2"]).permit_listen(std::iter::empty());
+        assert!(!policy.allows_listen("localhost", 80));
+        policy.restrict(open(&["b:*", "c:3"]));
+        assert!(!policy.allows_open("a", 1));
+        assert!(policy.allows_open("b", 2));
+        assert!(!policy.allows_open("c", 3));
+        assert!(ForwardingPolicy::new().allows_open("anything", 1));
+        assert!(!ForwardingPolicy::deny_all().allows_open("anything", 1));
+    }
+
+    #[test]
+    fn test_from_key_options() {
+        let options = KeyOptions {
+            permit_open: vec!["localhost:80".into()],
+            permit_listen: vec!["9000".into()],
+            ..KeyOptions::default()
+        };
+        let policy = ForwardingPolicy::from_key_options(&options);
+        assert!(policy.allows_open("localhost", 80));
+        assert!(!policy.allows_open("localhost", 81));
+        assert!(policy.allows_listen("0.0.0.0", 9000));
+        assert!(!policy.allows_listen("0.0.0.0", 9001));
+
+        let policy = ForwardingPolicy::from_key_options(&KeyOptions::default());
+        assert_eq!(policy, ForwardingPolicy::new());
+
+        let options = KeyOptions {
+            no_port_forwarding: true,
+            ..options
+        };
+        let policy = ForwardingPolicy::from_key_options(&options);
+        assert!(!policy.allows_open("localhost", 80));
+        assert!(!policy.allows_listen("0.0.0.0", 9000));
+    }
+}
@@ -65,6 +65,7 @@ impl KexInit {
             &config.preferred,
             &mut self.exchange.server_kex_init,
             Some(config),
+            false,
         )?;
         debug!("server kex init: {:?}", &self.exchange.server_kex_init[..]);
         self.sent = true;
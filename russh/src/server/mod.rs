@@ -29,7 +29,7 @@
 //! * Serving `ratatui` based TUI app to clients: [per-client](https://github.com/warp-tech/russh/blob/main/russh/examples/ratatui_app.rs), [shared](https://github.com/warp-tech/russh/blob/main/russh/examples/ratatui_shared_app.rs)
 
 use std;
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
 use std::num::Wrapping;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -41,19 +41,32 @@ use log::{debug, error};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, ToSocketAddrs};
 use tokio::pin;
-use tokio::task::JoinHandle;
 
+use crate::cipher::pipeline::PacketReader;
 use crate::cipher::{clear, CipherPair, OpeningKey};
+use crate::keys::encoding::Reader;
 use crate::keys::key;
+use crate::rate_limit::TokenBucket;
 use crate::session::*;
 use crate::ssh_read::*;
 use crate::sshbuffer::*;
 use crate::*;
 
+mod acceptor;
+pub use self::acceptor::{Acceptor, PendingConnection};
+pub mod auth;
 mod kex;
 mod session;
 pub use self::session::*;
+mod conditional;
+pub use self::conditional::{
+    ConditionalConfig, ConfigOverrides, GroupsCallback, MatchCriteria, MatchResult, MatchRule,
+};
 mod encrypted;
+mod forwarding;
+pub use self::forwarding::{DeniedForwarding, ForwardPattern, ForwardingPolicy};
+mod runtime;
+pub use self::runtime::{RuntimeConfig, ServerHandle};
 
 #[derive(Debug)]
 /// Configuration of a server.
@@ -61,7 +74,7 @@ pub struct Config {
     /// The server ID string sent at the beginning of the protocol.
     pub server_id: SshId,
     /// Authentication methods proposed to the client.
-    pub methods: auth::MethodSet,
+    pub methods: crate::auth::MethodSet,
     /// The authentication banner, usually a warning message shown to the client.
     pub auth_banner: Option<&'static str>,
     /// Authentication rejections must happen in constant time for
@@ -80,16 +93,197 @@ pub struct Config {
     pub maximum_packet_size: u32,
     /// Internal event buffer size
     pub event_buffer_size: usize,
-    /// Lists of preferred algorithms.
+    /// Maximal number of messages queued for each [`Channel`] that
+    /// has not read them yet. While a queue is full, the session stops
+    /// reading from the socket, so that a slow consumer slows down the
+    /// client instead of using more memory. With `None`, messages are
+    /// queued without limit.
+    ///
+    /// Every [`Channel`] must then be read (or dropped), even if data
+    /// is processed through [`Handler`] callbacks, otherwise the session
+    /// eventually stalls. A full window of data takes
+    /// `window_size / maximum_packet_size` messages.
+    pub channel_buffer_size: Option<usize>,
+    /// Lists of preferred algorithms. See [`Preferred::MODERN`],
+    /// [`Preferred::COMPATIBLE`] and [`Preferred::DEFAULT`] for presets.
     pub preferred: Preferred,
     /// Maximal number of allowed authentication attempts.
     pub max_auth_attempts: usize,
+    /// The smallest client keys accepted for public key
+    /// authentication, in bits (see [`key::PublicKey::bit_length`]), by
+    /// key family. Smaller keys are rejected before the [`Handler`]
+    /// sees them, and reported to [`Handler::auth_publickey_size_rejected`].
+    /// Requires RSA keys of 1024 bits by default; DSA keys are never
+    /// accepted.
+    pub minimum_key_bits: HashMap<key::KeyFamily, usize>,
+    /// The largest client keys accepted for public key authentication,
+    /// to bound the cost of verifying their signatures. RSA keys are
+    /// limited to 16384 bits by default.
+    pub maximum_key_bits: HashMap<key::KeyFamily, usize>,
     /// Time after which the connection is garbage-collected.
     pub inactivity_timeout: Option<std::time::Duration>,
+    /// Ends sessions this long after the connection was accepted,
+    /// active or not, telling the client why in a DISCONNECT message.
+    /// The session then fails with
+    /// [`Error::SessionDurationExceeded`](crate::Error::SessionDurationExceeded).
+    /// `None`, the default, doesn't limit the duration of sessions.
+    pub max_session_duration: Option<std::time::Duration>,
     /// If nothing is received from the client for this amount of time, send a keepalive message.
     pub keepalive_interval: Option<std::time::Duration>,
     /// If this many keepalives have been sent without reply, close the connection.
     pub keepalive_max: usize,
+    /// How many global requests (forwardings, keepalives...) may wait
+    /// for the client's reply at once. Later ones are sent as the replies
+    /// come in, in order. The default doesn't limit them.
+    pub max_pending_global_requests: usize,
+    /// Whether to announce OpenSSH's `ping@openssh.com` extension to
+    /// the clients supporting RFC 8308, letting them measure the round
+    /// trip time, or send chaff to hide keystroke timings. PINGs are
+    /// answered either way. Defaults to `true`.
+    pub advertise_ping: bool,
+    /// Maximal time to wait for the client to close its side of the
+    /// connection once the session is over. After that, the connection
+    /// is dropped. `None` waits forever.
+    pub shutdown_timeout: Option<std::time::Duration>,
+    /// Maximal number of bytes waiting to be sent to the client, including
+    /// channel data held back by flow control, before
+    /// `write_buffer_policy` applies.
+    pub write_buffer_high_water_mark: usize,
+    /// What to do when `write_buffer_high_water_mark` is exceeded.
+    pub write_buffer_policy: WriteBufferPolicy,
+    /// Caps the rate at which each session writes to the client, for
+    /// all channels and protocol messages together. The current rate is
+    /// given by [`Handle::rate_limit_stats`]. See also
+    /// [`per_channel_rate`](Self::per_channel_rate).
+    pub rate_limit: Option<RateLimit>,
+    /// Caps the rate of the channel data sent on each channel, for
+    /// [`Handle::data`], [`Session::data`], [`Channel`]
+    /// writers and their extended data alike.
+    ///
+    /// Unlike [`rate_limit`](Self::rate_limit), the data over the limit
+    /// waits in the session, along with the data the client's window
+    /// has no room for, so the session keeps answering in the
+    /// meantime. The data waiting counts towards
+    /// [`write_buffer_high_water_mark`](Self::write_buffer_high_water_mark),
+    /// and [`Channel`] writers stop once the window is
+    /// full. The current rates are in [`ChannelInfo::rate`](crate::ChannelInfo::rate).
+    ///
+    /// [`Channel::set_rate_limit`](crate::Channel::set_rate_limit) adds
+    /// a limit of its own to the writers of one channel, which wait
+    /// before writing instead.
+    pub per_channel_rate: Option<RateLimit>,
+    /// Where sessions read the [`RuntimeConfig`] parameters from. With
+    /// `None`, each session uses the values of this `Config`. See
+    /// [`Config::server_handle`].
+    pub runtime: Option<ServerHandle>,
+    /// Maximal time a [`Handler`] callback may run. While a callback
+    /// runs, the session processes nothing else, not even keepalives.
+    /// If a callback takes longer, [`Handler::handler_timed_out`] is
+    /// called and the session ends with
+    /// [`Error::HandlerTimeout`](crate::Error::HandlerTimeout), naming
+    /// the callback. `None` waits forever.
+    ///
+    /// Slow work belongs in a separate task, answering through a
+    /// [`Handle`] so that the callback returns right away:
+    ///
+    /// ```
+    /// use russh::server::{self, Session};
+    /// use russh::{ChannelId, CryptoVec};
+    ///
+    /// struct Handler;
+    ///
+    /// #[async_trait::async_trait]
+    /// impl server::Handler for Handler {
+    ///     type Error = russh::Error;
+    ///
+    ///     async fn exec_request(
+    ///         &mut self,
+    ///         channel: ChannelId,
+    ///         data: &[u8],
+    ///         session: &mut Session,
+    ///     ) -> Result<(), Self::Error> {
+    ///         let command = data.to_vec();
+    ///         let handle = session.handle();
+    ///         session.channel_success(channel);
+    ///         tokio::spawn(async move {
+    ///             let output = run_query(&command).await;
+    ///             let _ = handle.data(channel, CryptoVec::from(output)).await;
+    ///             let _ = handle.close(channel).await;
+    ///         });
+    ///         Ok(())
+    ///     }
+    /// }
+    /// # async fn run_query(_: &[u8]) -> Vec<u8> { Vec::new() }
+    ///
+    /// let config = server::Config {
+    ///     handler_timeout: Some(std::time::Duration::from_secs(5)),
+    ///     ..Default::default()
+    /// };
+    /// ```
+    pub handler_timeout: Option<std::time::Duration>,
+    /// Called with every inbound packet before the session processes
+    /// it, and allowed to consume it. See [`crate::RawPacketHook`].
+    #[cfg(feature = "danger-raw-packets")]
+    pub raw_packet_hook: Option<crate::RawPacketHook>,
+    /// Runs the session's tasks, instead of the runtime selected by the
+    /// `runtime-*` features. See [`crate::runtime`].
+    pub spawner: Option<crate::runtime::Spawner>,
+    /// Maximal length of the packets accepted from the client, checked
+    /// before reading them. Longer packets end the session with
+    /// [`Error::PacketTooLarge`](crate::Error::PacketTooLarge). RFC 4253
+    /// requires accepting packets of at least 35000 bytes.
+    pub maximum_inbound_packet_size: usize,
+    /// Decrypts the packets from the client on a separate task, up to
+    /// this many packets ahead of the session, so that decryption and
+    /// the processing of the previous packets run in parallel. This
+    /// helps bulk transfers on fast links, where decryption alone can
+    /// keep a core busy. Key exchanges still read one packet at a time.
+    /// `None`, the default, decrypts in the session's task.
+    pub read_pipeline_depth: Option<usize>,
+    /// The forwardings allowed to every session, enforced before the
+    /// [`Handler`] sees the requests. Sessions can restrict or replace
+    /// it, see [`Session::forwarding_policy_mut`].
+    pub forwarding_policy: ForwardingPolicy,
+    /// Settings depending on the client's address and user name, on
+    /// top of the [`RuntimeConfig`] and of
+    /// [`forwarding_policy`](Self::forwarding_policy). See
+    /// [`Session::runtime_config`] for the settings in effect.
+    pub conditional: ConditionalConfig,
+    /// Whether to tell the client the labels given to channels with
+    /// [`Handle::channel_open_session_labeled`], in a `label@russh.rs`
+    /// channel request.
+    pub send_channel_labels: bool,
+    /// Whether to refuse the session channels opened after the client
+    /// sent `no-more-sessions@openssh.com`, as OpenSSH does. When
+    /// `false`, the request is only reported to
+    /// [`Handler::no_more_sessions`] and [`Session::no_more_sessions`].
+    pub enforce_no_more_sessions: bool,
+}
+
+impl Config {
+    /// Returns the handle changing the [`RuntimeConfig`] of the
+    /// sessions using this configuration, creating it from the current
+    /// values of this `Config` if needed. The `Config` fields
+    /// corresponding to `RuntimeConfig` are ignored from then on.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// let mut config = russh::server::Config::default();
+    /// let handle = config.server_handle();
+    /// // Start the server with `Arc::new(config)`, then later:
+    /// handle.update_config(|c| russh::server::RuntimeConfig {
+    ///     keepalive_interval: Some(Duration::from_secs(30)),
+    ///     ..c.clone()
+    /// });
+    /// ```
+    pub fn server_handle(&mut self) -> ServerHandle {
+        if let Some(ref handle) = self.runtime {
+            return handle.clone();
+        }
+        let handle = ServerHandle::new(RuntimeConfig::from(&*self));
+        self.runtime = Some(handle.clone());
+        handle
+    }
 }
 
 impl Default for Config {
@@ -100,7 +294,7 @@ impl Default for Config {
                 env!("CARGO_PKG_NAME"),
                 env!("CARGO_PKG_VERSION")
             )),
-            methods: auth::MethodSet::all(),
+            methods: crate::auth::MethodSet::all(),
             auth_banner: None,
             auth_rejection_time: std::time::Duration::from_secs(1),
             auth_rejection_time_initial: None,
@@ -108,12 +302,34 @@ impl Default for Config {
             window_size: 2097152,
             maximum_packet_size: 32768,
             event_buffer_size: 10,
+            channel_buffer_size: None,
             limits: Limits::default(),
             preferred: Default::default(),
             max_auth_attempts: 10,
+            minimum_key_bits: crate::key::default_minimum_key_bits(),
+            maximum_key_bits: crate::key::default_maximum_key_bits(),
             inactivity_timeout: Some(std::time::Duration::from_secs(600)),
+            max_session_duration: None,
             keepalive_interval: None,
             keepalive_max: 3,
+            max_pending_global_requests: usize::MAX,
+            advertise_ping: true,
+            shutdown_timeout: Some(std::time::Duration::from_secs(10)),
+            write_buffer_high_water_mark: 8 << 20,
+            write_buffer_policy: WriteBufferPolicy::default(),
+            rate_limit: None,
+            per_channel_rate: None,
+            runtime: None,
+            handler_timeout: None,
+            #[cfg(feature = "danger-raw-packets")]
+            raw_packet_hook: None,
+            spawner: None,
+            maximum_inbound_packet_size: 256 << 10,
+            read_pipeline_depth: None,
+            forwarding_policy: ForwardingPolicy::new(),
+            conditional: ConditionalConfig::new(),
+            send_channel_labels: false,
+            enforce_no_more_sessions: true,
         }
     }
 }
@@ -150,6 +366,16 @@ pub enum Auth {
     /// Accept the authentication request.
     Accept,
 
+    /// Accept this method as one step of a multi-factor
+    /// authentication, and ask the client to continue with one of
+    /// `proceed_with_methods`. The client is told with the
+    /// `partial_success` flag of `USERAUTH_FAILURE`.
+    ///
+    /// The handler lives for the whole connection, so it is up to it
+    /// to remember which factors succeeded and to eventually return
+    /// [`Auth::Accept`].
+    PartialSuccess { proceed_with_methods: MethodSet },
+
     /// Method was not accepted, but no other check was performed.
     UnsupportedMethod,
 
@@ -167,13 +393,83 @@ pub enum Auth {
     },
 }
 
+/// The outcome of a successful authentication, see [`Session::auth_info`].
+///
+/// Secrets (passwords, keyboard-interactive responses) are never recorded.
+#[derive(Debug, Clone)]
+pub struct AuthInfo {
+    /// The name of the authenticated user.
+    pub user: String,
+    /// The method that succeeded (a single flag).
+    pub method: MethodSet,
+    /// The public key the client authenticated with, if `method` is
+    /// [`MethodSet::PUBLICKEY`]. For certificates, this is the
+    /// certified key.
+    pub public_key: Option<key::PublicKey>,
+    /// The certificate the client authenticated with, if any.
+    pub certificate: Option<ssh_key::Certificate>,
+    /// The options of the matching `authorized_keys` line, recorded by
+    /// [`auth::FileAuth::auth_succeeded`].
+    pub key_options: Option<auth::KeyOptions>,
+    /// The rules of [`Config::conditional`] that applied to the session.
+    pub matched_rules: Vec<String>,
+}
+
+impl AuthInfo {
+    /// The SHA-256 fingerprint of the public key (`SHA256:...`), if any.
+    pub fn fingerprint(&self) -> Option<String> {
+        self.public_key
+            .as_ref()
+            .map(|k| k.fingerprint(key::HashAlg::Sha256))
+    }
+}
+
 /// Server handler. Each client will have their own handler.
 ///
+/// The handler returned by [`Server::new_client`] (or given to
+/// [`run_stream`]) is kept for the whole connection, and every
+/// callback gets that same instance through `&mut self`. Callbacks
+/// run one at a time, and the session doesn't process further
+/// messages while one is running, so state kept in the handler needs
+/// no locking, but long-running work should be moved to a spawned
+/// task (using [`Session::handle`] to talk to the session).
+///
+/// State shared between connections belongs in the [`Server`], cloned
+/// into each new handler, typically as an `Arc<Mutex<_>>` (see the
+/// `echoserver` example).
+///
 /// Note: this is an `async_trait`. Click `[source]` on the right to see actual async function definitions.
 #[async_trait]
 pub trait Handler: Sized {
     type Error: From<crate::Error> + Send;
 
+    /// Called with the client's identification string, such as
+    /// `SSH-2.0-OpenSSH_9.6` (without the line ending), before the key
+    /// exchange. Returning `false` disconnects the client, for instance
+    /// to deny known-vulnerable versions. The default accepts every
+    /// client.
+    #[allow(unused_variables)]
+    fn check_client_id(&self, id: &[u8]) -> bool {
+        true
+    }
+
+    /// Choose the authentication methods `user` may use, when the
+    /// client first sends a request for that user. These are the
+    /// methods listed in rejections (so the client knows what to try),
+    /// and requests with other methods are rejected without calling
+    /// the corresponding `auth_*` method. `methods` is
+    /// `config.methods` (or [`RuntimeConfig::methods`]), as changed by
+    /// the rules of [`Config::conditional`] matching the client's
+    /// address and `user`, which is the default.
+    #[allow(unused_variables)]
+    async fn auth_methods(
+        &mut self,
+        user: &str,
+        methods: MethodSet,
+    ) -> Result<MethodSet, Self::Error> {
+        Ok(methods)
+    }
+
     /// Check authentication using the "none" method. Russh makes
     /// sure rejection happens in time `config.auth_rejection_time`,
     /// except if this method takes more than that.
@@ -228,6 +524,25 @@ pub trait Handler: Sized {
         })
     }
 
+    /// Check authentication using an OpenSSH certificate, with the
+    /// "publickey" method. This method is called after the signature
+    /// has been verified against the certified key, but the certificate
+    /// itself (its CA, validity period, type and principals) must be
+    /// checked here, for instance with [`auth::FileAuth`].
+    /// Russh guarantees that rejection happens in constant time
+    /// `config.auth_rejection_time`, except if this method takes more
+    /// time than that.
+    #[allow(unused_variables)]
+    async fn auth_openssh_certificate(
+        &mut self,
+        user: &str,
+        certificate: &ssh_key::Certificate,
+    ) -> Result<Auth, Self::Error> {
+        Ok(Auth::Reject {
+            proceed_with_methods: None,
+        })
+    }
+
     /// Check authentication using the "keyboard-interactive"
     /// method. Russh makes sure rejection happens in time
     /// `config.auth_rejection_time`, except if this method takes more
@@ -270,6 +585,28 @@ pub trait Handler: Sized {
         Ok(())
     }
 
+    /// Called when the client sends `eow@openssh.com` on a channel,
+    /// meaning it won't write to the channel's stdin anymore although
+    /// it still reads its output. OpenSSH sends it instead of EOF, for
+    /// instance when the output pipe of `ssh` is closed.
+    #[allow(unused_variables)]
+    async fn end_of_write(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called when the client sends `no-more-sessions@openssh.com`,
+    /// promising not to open any more session channels. Unless
+    /// [`Config::enforce_no_more_sessions`] is `false`, the later
+    /// session channel openings are refused before reaching the handler.
+    #[allow(unused_variables)]
+    async fn no_more_sessions(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Called when a new session channel is created.
     /// Return value indicates whether the channel request should be granted.
     #[allow(unused_variables)]
@@ -365,6 +702,28 @@ pub trait Handler: Sized {
         Ok(())
     }
 
+    /// Observes the data of every channel, received or sent, for
+    /// instance to record sessions. It is called before
+    /// [`Handler::data`] for received data, and when sending the data
+    /// given to a [`Handle`] or [`Channel`]. The data this handler
+    /// sends itself with [`Session::data`] isn't reported.
+    #[allow(unused_variables)]
+    #[inline]
+    fn on_channel_data(&mut self, direction: Direction, channel: ChannelId, data: &[u8]) {}
+
+    /// Like [`Handler::on_channel_data`], for extended data such as
+    /// standard error.
+    #[allow(unused_variables)]
+    #[inline]
+    fn on_channel_extended_data(
+        &mut self,
+        direction: Direction,
+        channel: ChannelId,
+        code: u32,
+        data: &[u8],
+    ) {
+    }
+
     /// Called when the network window is adjusted, meaning that we
     /// can send more bytes.
     #[allow(unused_variables)]
@@ -384,8 +743,19 @@ pub trait Handler: Sized {
         current
     }
 
+    /// Called when the callback `callback` of this handler ran for
+    /// longer than [`Config::handler_timeout`] and was cancelled. The
+    /// session then ends with
+    /// [`Error::HandlerTimeout`](crate::Error::HandlerTimeout).
+    #[allow(unused_variables)]
+    fn handler_timed_out(&mut self, callback: &'static str) {}
+
     /// The client requests a pseudo-terminal with the given
     /// specifications.
+    ///
+    /// By default, the request is refused. A terminal is only useful
+    /// with a shell, so handlers implementing
+    /// [`Handler::shell_request`] usually implement this too.
     #[allow(unused_variables, clippy::too_many_arguments)]
     async fn pty_request(
         &mut self,
@@ -398,10 +768,13 @@ pub trait Handler: Sized {
         modes: &[(Pty, u32)],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.channel_failure(channel);
         Ok(())
     }
 
     /// The client requests an X11 connection.
+    ///
+    /// By default, the request is refused.
     #[allow(unused_variables)]
     async fn x11_request(
         &mut self,
@@ -412,12 +785,16 @@ pub trait Handler: Sized {
         x11_screen_number: u32,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.channel_failure(channel);
         Ok(())
     }
 
     /// The client wants to set the given environment variable. Check
     /// these carefully, as it is dangerous to allow any variable
     /// environment to be set.
+    ///
+    /// By default, the variable is refused, as `sshd` does for the
+    /// variables not listed in `AcceptEnv`.
     #[allow(unused_variables)]
     async fn env_request(
         &mut self,
@@ -426,21 +803,29 @@ pub trait Handler: Sized {
         variable_value: &str,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.channel_failure(channel);
         Ok(())
     }
 
     /// The client requests a shell.
+    ///
+    /// By default, the request is refused, so that a client such as
+    /// `ssh` reports the failure instead of waiting for output.
     #[allow(unused_variables)]
     async fn shell_request(
         &mut self,
         channel: ChannelId,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.channel_failure(channel);
         Ok(())
     }
 
     /// The client sends a command to execute, to be passed to a
-    /// shell. Make sure to check the command before doing so.
+    /// shell. Make sure to check the command before doing so. `data` is
+    /// the command exactly as sent, which isn't necessarily UTF-8.
+    ///
+    /// By default, the request is refused.
     #[allow(unused_variables)]
     async fn exec_request(
         &mut self,
@@ -448,11 +833,14 @@ pub trait Handler: Sized {
         data: &[u8],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.channel_failure(channel);
         Ok(())
     }
 
     /// The client asks to start the subsystem with the given name
     /// (such as sftp).
+    ///
+    /// By default, the request is refused.
     #[allow(unused_variables)]
     async fn subsystem_request(
         &mut self,
@@ -460,10 +848,14 @@ pub trait Handler: Sized {
         name: &str,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.channel_failure(channel);
         Ok(())
     }
 
     /// The client's pseudo-terminal window size has changed.
+    ///
+    /// By default, the change is acknowledged if the client asks for
+    /// a reply.
     #[allow(unused_variables)]
     async fn window_change_request(
         &mut self,
@@ -474,6 +866,7 @@ pub trait Handler: Sized {
         pix_height: u32,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.channel_success(channel);
         Ok(())
     }
 
@@ -489,6 +882,9 @@ pub trait Handler: Sized {
 
     /// The client is sending a signal (usually to pass to the
     /// currently running process).
+    ///
+    /// By default, the signal is refused if the client asks for a
+    /// reply.
     #[allow(unused_variables)]
     async fn signal(
         &mut self,
@@ -496,6 +892,7 @@ pub trait Handler: Sized {
         signal: Sig,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.channel_failure(channel);
         Ok(())
     }
 
@@ -523,6 +920,36 @@ pub trait Handler: Sized {
     ) -> Result<bool, Self::Error> {
         Ok(false)
     }
+
+    /// Called when a public key was refused because of its size, see
+    /// [`Config::minimum_key_bits`] and [`Config::maximum_key_bits`],
+    /// instead of [`Handler::auth_publickey_offered`] or
+    /// [`Handler::auth_publickey`]. `reason` is
+    /// [`Error::KeyTooWeak`](crate::Error::KeyTooWeak) or
+    /// [`Error::KeyTooLarge`](crate::Error::KeyTooLarge). The client
+    /// has been answered already: this is only for auditing.
+    #[allow(unused_variables)]
+    async fn auth_publickey_size_rejected(
+        &mut self,
+        user: &str,
+        public_key: &key::PublicKey,
+        reason: &crate::Error,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called when the session refused a forwarding because of its
+    /// [`ForwardingPolicy`], instead of [`Handler::channel_open_direct_tcpip`]
+    /// or [`Handler::tcpip_forward`]. The client has been answered
+    /// already: this is only for auditing.
+    #[allow(unused_variables)]
+    async fn forwarding_denied(
+        &mut self,
+        denied: &DeniedForwarding,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -530,7 +957,8 @@ pub trait Handler: Sized {
 pub trait Server {
     /// The type of handlers.
     type Handler: Handler + Send + 'static;
-    /// Called when a new client connects.
+    /// Called when a new client connects. The returned handler is
+    /// used for the whole connection.
     fn new_client(&mut self, peer_addr: Option<std::net::SocketAddr>) -> Self::Handler;
     /// Called when an active connection fails.
     fn handle_session_error(&mut self, _error: <Self::Handler as Handler>::Error) {}
@@ -555,12 +983,12 @@ pub trait Server {
             tokio::select! {
                 accept_result = socket.accept() => {
                     match accept_result {
-                        Ok((socket, _)) => {
+                        Ok((socket, peer_addr)) => {
                             let config = config.clone();
-                            let  handler = self.new_client(socket.peer_addr().ok());
+                            let  handler = self.new_client(Some(peer_addr));
                             let error_tx = error_tx.clone();
                             tokio::spawn(async move {
-                                let session = match run_stream(config, socket,  handler).await {
+                                let session = match run_stream_with_peer_addr(config, socket, Some(peer_addr), handler).await {
                                     Ok(s) => s,
                                     Err(e) => {
                                         debug!("Connection setup failed");
@@ -609,12 +1037,20 @@ thread_local! {
 }
 
 async fn start_reading<R: AsyncRead + Unpin>(
-    mut stream_read: R,
+    mut stream_read: PacketReader<R>,
     mut buffer: SSHBuffer,
     mut cipher: Box<dyn OpeningKey + Send>,
-) -> Result<(usize, R, SSHBuffer, Box<dyn OpeningKey + Send>), Error> {
+) -> Result<
+    (
+        usize,
+        PacketReader<R>,
+        SSHBuffer,
+        Box<dyn OpeningKey + Send>,
+    ),
+    Error,
+> {
     buffer.buffer.clear();
-    let n = cipher::read(&mut stream_read, &mut buffer, &mut *cipher).await?;
+    let n = stream_read.read(&mut buffer, &mut cipher).await?;
     Ok((n, stream_read, buffer, cipher))
 }
 
@@ -623,7 +1059,7 @@ async fn start_reading<R: AsyncRead + Unpin>(
 /// Implements [Future] and needs to be awaited to allow the session to run.
 pub struct RunningSession<H: Handler> {
     handle: Handle,
-    join: JoinHandle<Result<(), H::Error>>,
+    join: crate::runtime::Task<Result<(), H::Error>>,
 }
 
 impl<H: Handler> RunningSession<H> {
@@ -639,7 +1075,7 @@ impl<H: Handler> Future for RunningSession<H> {
         match Future::poll(Pin::new(&mut self.join), cx) {
             Poll::Ready(r) => Poll::Ready(match r {
                 Ok(Ok(x)) => Ok(x),
-                Err(e) => Err(crate::Error::from(e).into()),
+                Err(e) => Err(e.into()),
                 Ok(Err(e)) => Err(e),
             }),
             Poll::Pending => Poll::Pending,
@@ -649,8 +1085,23 @@ impl<H: Handler> Future for RunningSession<H> {
 
 /// Run a single connection to completion.
 pub async fn run_stream<H, R>(
+    config: Arc<Config>,
+    stream: R,
+    handler: H,
+) -> Result<RunningSession<H>, H::Error>
+where
+    H: Handler + Send + 'static,
+    R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    run_stream_with_peer_addr(config, stream, None, handler).await
+}
+
+/// Like [`run_stream`], for a client connecting from `peer_addr`, which
+/// the rules of [`Config::conditional`] can depend on.
+pub async fn run_stream_with_peer_addr<H, R>(
     config: Arc<Config>,
     mut stream: R,
+    peer_addr: Option<std::net::SocketAddr>,
     handler: H,
 ) -> Result<RunningSession<H>, H::Error>
 where
@@ -664,33 +1115,80 @@ where
         .write_all(&write_buffer.buffer[..])
         .await
         .map_err(crate::Error::from)?;
+    stream.flush().await.map_err(crate::Error::from)?;
 
     // Reading SSH id and allocating a session.
     let mut stream = SshRead::new(stream);
     let (sender, receiver) = tokio::sync::mpsc::channel(config.event_buffer_size);
-    let common = read_ssh_id(config, &mut stream).await?;
-    let handle = server::session::Handle { sender };
+    let runtime = config
+        .runtime
+        .clone()
+        .unwrap_or_else(|| ServerHandle::new(RuntimeConfig::from(config.as_ref())));
+    let matched = config.conditional.evaluate(peer_addr.map(|a| a.ip()), None);
+    if !matched.rules.is_empty() {
+        debug!("rules matching {:?}: {:?}", peer_addr, matched.rules);
+    }
+    let inactivity_timeout = matched
+        .overrides
+        .inactivity_timeout
+        .or(runtime.load().inactivity_timeout);
+    let mut common = read_ssh_id(config, inactivity_timeout, &mut stream).await?;
+    if !handler.check_client_id(&common.remote_sshid) {
+        debug!(
+            "client id rejected: {:?}",
+            String::from_utf8_lossy(&common.remote_sshid)
+        );
+        // Don't send our KEXINIT to a rejected client.
+        common.write_buffer.buffer.clear();
+        common.disconnect(
+            Disconnect::ProtocolVersionNotSupported,
+            "Client version not allowed",
+            "en",
+        );
+        let _ = stream.r.write_all(&common.write_buffer.buffer).await;
+        let _ = stream.r.shutdown().await;
+        return Err(crate::Error::ClientIdRejected.into());
+    }
+    let handle = server::session::Handle {
+        sender,
+        channel_buffer_size: common.config.channel_buffer_size,
+    };
     let session = Session {
         target_window_size: common.config.window_size,
+        forwarding_policy: matched
+            .overrides
+            .forwarding_policy
+            .clone()
+            .unwrap_or_else(|| common.config.forwarding_policy.clone()),
+        no_more_sessions: false,
         common,
         receiver,
         sender: handle.clone(),
-        pending_reads: Vec::new(),
-        pending_len: 0,
         channels: HashMap::new(),
-        open_global_requests: VecDeque::new(),
+        global_requests: Default::default(),
+        auth_info: None,
+        extensions: Extensions::new(),
+        runtime,
+        peer_addr,
+        matched,
+        match_user: None,
+        match_changed: false,
     };
-    let join = tokio::spawn(session.run(stream, handler));
+    let spawner = session.common.config.spawner.clone();
+    let join = crate::runtime::spawn_task(spawner.as_ref(), session.run(stream, handler));
 
     Ok(RunningSession { handle, join })
 }
 
 async fn read_ssh_id<R: AsyncRead + Unpin>(
     config: Arc<Config>,
+    inactivity_timeout: Option<std::time::Duration>,
     read: &mut SshRead<R>,
 ) -> Result<CommonSession<Arc<Config>>, Error> {
-    let sshid = if let Some(t) = config.inactivity_timeout {
-        tokio::time::timeout(t, read.read_ssh_id()).await??
+    let sshid = if let Some(t) = inactivity_timeout {
+        crate::runtime::timeout(t, read.read_ssh_id())
+            .await
+            .map_err(|_| Error::InactivityTimeout)??
     } else {
         read.read_ssh_id().await?
     };
@@ -705,6 +1203,7 @@ async fn read_ssh_id<R: AsyncRead + Unpin>(
         algo: None,
         sent: false,
         session_id: None,
+        guess: None,
     };
     let mut cipher = CipherPair {
         local_to_remote: Box::new(clear::Key),
@@ -724,11 +1223,17 @@ async fn read_ssh_id<R: AsyncRead + Unpin>(
         auth_attempts: 0,
         cipher,
         encrypted: None,
+        limits: config.limits.clone(),
+        rate_limit: config.rate_limit.map(TokenBucket::new),
+        error_disconnect: Default::default(),
+        flush_waiters: Vec::new(),
+        established_at: None,
         config,
         wants_reply: false,
         disconnected: false,
         buffer: CryptoVec::new(),
         strict_kex: false,
+        skipped_kex_packets: 0,
         alive_timeouts: 0,
         received_data: false,
         remote_sshid: sshid.into(),
@@ -746,14 +1251,33 @@ async fn reply<H: Handler + Send>(
     if let Some(message_type) = buf.first() {
         if session.common.strict_kex && session.common.encrypted.is_none() {
             let seqno = seqn.0 - 1; // was incremented after read()
-            if let Some(expected) = STRICT_KEX_MSG_ORDER.get(seqno as usize) {
+            let index = seqno - session.common.skipped_kex_packets;
+            if let Some(expected) = STRICT_KEX_MSG_ORDER.get(index as usize) {
                 if message_type != expected {
-                    return Err(strict_kex_violation(*message_type, seqno as usize).into());
+                    let e = strict_kex_violation(*message_type, seqno as usize);
+                    return Err(session.common.error_disconnect.record(e).into());
                 }
             }
         }
 
-        if [msg::IGNORE, msg::UNIMPLEMENTED, msg::DEBUG].contains(message_type) {
+        if *message_type == msg::UNIMPLEMENTED {
+            if let Ok(seqn) = buf.reader(1).read_u32() {
+                session.global_requests.peer_unimplemented(
+                    &mut session.common.encrypted,
+                    session.common.config.max_pending_global_requests,
+                    seqn,
+                );
+            }
+            return Ok(());
+        }
+        if [msg::IGNORE, msg::DEBUG].contains(message_type) {
+            return Ok(());
+        }
+        if *message_type == msg::PING {
+            return Ok(session.common.pong(buf)?);
+        }
+        if *message_type == msg::PONG {
+            debug!("unsolicited PONG");
             return Ok(());
         }
     }
@@ -763,18 +1287,20 @@ async fn reply<H: Handler + Send>(
         match session.common.kex.take() {
             Some(Kex::Init(kexinit)) => {
                 if kexinit.algo.is_some() || buf.first() == Some(&msg::KEXINIT) {
-                    session.common.kex = Some(kexinit.server_parse(
-                        session.common.config.as_ref(),
-                        &mut *session.common.cipher.local_to_remote,
-                        buf,
-                        &mut session.common.write_buffer,
-                    )?);
+                    session.common.kex =
+                        Some(session.common.error_disconnect.check(kexinit.server_parse(
+                            session.common.config.as_ref(),
+                            &mut *session.common.cipher.local_to_remote,
+                            buf,
+                            &mut session.common.write_buffer,
+                        ))?);
                     if let Some(Kex::Dh(KexDh { ref names, .. })) = session.common.kex {
                         session.common.strict_kex = names.strict_kex;
                     }
                     // seqno has already been incremented after read()
                     if session.common.strict_kex && seqn.0 != 1 {
-                        return Err(strict_kex_violation(msg::KEXINIT, seqn.0 as usize - 1).into());
+                        let e = strict_kex_violation(msg::KEXINIT, seqn.0 as usize - 1);
+                        return Err(session.common.error_disconnect.record(e).into());
                     }
                     return Ok(());
                 } else {
@@ -785,12 +1311,15 @@ async fn reply<H: Handler + Send>(
                 }
             }
             Some(Kex::Dh(kexdh)) => {
-                session.common.kex = Some(kexdh.parse(
+                if kexdh.names.ignore_guessed {
+                    session.common.skipped_kex_packets += 1;
+                }
+                session.common.kex = Some(session.common.error_disconnect.check(kexdh.parse(
                     session.common.config.as_ref(),
                     &mut *session.common.cipher.local_to_remote,
                     buf,
                     &mut session.common.write_buffer,
-                )?);
+                ))?);
                 if let Some(Kex::Keys(_)) = session.common.kex {
                     // just sent NEWKEYS
                     session.common.maybe_reset_seqn();
@@ -799,7 +1328,7 @@ async fn reply<H: Handler + Send>(
             }
             Some(Kex::Keys(newkeys)) => {
                 if buf.first() != Some(&msg::NEWKEYS) {
-                    return Err(Error::Kex.into());
+                    return Err(session.common.error_disconnect.record(Error::Kex).into());
                 }
                 // Ok, NEWKEYS received, now encrypted.
                 session.common.encrypted(
@@ -809,6 +1338,9 @@ async fn reply<H: Handler + Send>(
                     },
                     newkeys,
                 );
+                if let Some(ref mut enc) = session.common.encrypted {
+                    enc.per_channel_rate = session.common.config.per_channel_rate;
+                }
                 session.maybe_send_ext_info();
                 if session.common.strict_kex {
                     *seqn = Wrapping(0);
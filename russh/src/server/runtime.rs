@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use tokio::sync::watch;
+
+use super::Config;
+use crate::auth::MethodSet;
+
+/// The server parameters that can change while sessions are running,
+/// see [`ServerHandle::update_config`]. Sessions read them when they
+/// need them, so a change applies to existing sessions too.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    /// Authentication methods proposed to the client.
+    pub methods: MethodSet,
+    /// The authentication banner, usually a warning message shown to the client.
+    pub auth_banner: Option<String>,
+    /// See [`Config::auth_rejection_time`].
+    pub auth_rejection_time: Duration,
+    /// See [`Config::auth_rejection_time_initial`].
+    pub auth_rejection_time_initial: Option<Duration>,
+    /// Time after which the connection is garbage-collected.
+    pub inactivity_timeout: Option<Duration>,
+    /// If nothing is received from the client for this amount of time, send a keepalive message.
+    pub keepalive_interval: Option<Duration>,
+    /// If this many keepalives have been sent without reply, close the connection.
+    pub keepalive_max: usize,
+}
+
+impl From<&Config> for RuntimeConfig {
+    fn from(config: &Config) -> Self {
+        RuntimeConfig {
+            methods: config.methods,
+            auth_banner: config.auth_banner.map(String::from),
+            auth_rejection_time: config.auth_rejection_time,
+            auth_rejection_time_initial: config.auth_rejection_time_initial,
+            inactivity_timeout: config.inactivity_timeout,
+            keepalive_interval: config.keepalive_interval,
+            keepalive_max: config.keepalive_max,
+        }
+    }
+}
+
+/// Changes the [`RuntimeConfig`] of all the sessions of a server
+/// without restarting it. Get one with [`Config::server_handle`].
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    config: Arc<ArcSwap<RuntimeConfig>>,
+    changed: Arc<watch::Sender<()>>,
+}
+
+impl ServerHandle {
+    pub fn new(config: RuntimeConfig) -> Self {
+        ServerHandle {
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            changed: Arc::new(watch::channel(()).0),
+        }
+    }
+
+    /// The parameters currently in effect.
+    pub fn config(&self) -> Arc<RuntimeConfig> {
+        self.config.load_full()
+    }
+
+    /// Replaces the parameters with `f` applied to the current ones.
+    /// `f` may be called more than once if other updates happen at the
+    /// same time. Running sessions re-arm their keepalive and
+    /// inactivity timers with the new values.
+    pub fn update_config<F: FnMut(&RuntimeConfig) -> RuntimeConfig>(&self, mut f: F) {
+        self.config.rcu(|current| f(current));
+        let _ = self.changed.send(());
+    }
+
+    pub(crate) fn load(&self) -> arc_swap::Guard<Arc<RuntimeConfig>> {
+        self.config.load()
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+}
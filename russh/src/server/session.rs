@@ -1,16 +1,22 @@
 use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
-use log::debug;
+use log::{debug, error, info};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::sync::mpsc::{unbounded_channel, Receiver, Sender, UnboundedReceiver};
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::{oneshot, Mutex};
 
 use super::*;
-use crate::channels::{Channel, ChannelMsg, ChannelRef};
+use crate::channels::io::ChannelTx;
+use crate::channels::{
+    channels_full, fail_channels, flush_channels, wait_channel_capacity, Channel, ChannelLabel,
+    ChannelMsg, ChannelRef, SessionError, WriterParts,
+};
 use crate::kex::EXTENSION_SUPPORT_AS_CLIENT;
 use crate::keys::encoding::{Encoding, Reader};
-use crate::msg;
+use crate::{msg, ExtensionsView, RateStats};
 
 /// A connected server session. This type is unique to a client.
 pub struct Session {
@@ -18,10 +24,21 @@ pub struct Session {
     pub(crate) sender: Handle,
     pub(crate) receiver: Receiver<Msg>,
     pub(crate) target_window_size: u32,
-    pub(crate) pending_reads: Vec<CryptoVec>,
-    pub(crate) pending_len: u32,
     pub(crate) channels: HashMap<ChannelId, ChannelRef>,
-    pub(crate) open_global_requests: VecDeque<GlobalRequestResponse>,
+    pub(crate) global_requests: GlobalRequests,
+    pub(crate) auth_info: Option<AuthInfo>,
+    pub(crate) extensions: Extensions,
+    pub(crate) forwarding_policy: ForwardingPolicy,
+    pub(crate) no_more_sessions: bool,
+    pub(crate) runtime: ServerHandle,
+    pub(crate) peer_addr: Option<std::net::SocketAddr>,
+    /// The rules of [`Config::conditional`] matching the session.
+    pub(crate) matched: MatchResult,
+    /// The user the rules were last evaluated for.
+    pub(crate) match_user: Option<String>,
+    /// Whether the rules were evaluated again, and the timers need to
+    /// be re-armed.
+    pub(crate) match_changed: bool,
 }
 #[derive(Debug)]
 pub enum Msg {
@@ -59,11 +76,34 @@ pub enum Msg {
         address: String,
         port: u32,
     },
+    ListChannels {
+        reply_channel: oneshot::Sender<Vec<crate::ChannelInfo>>,
+    },
+    RateLimitStats {
+        reply_channel: oneshot::Sender<Option<RateStats>>,
+    },
+    ChannelWriter {
+        id: ChannelId,
+        reply_channel: oneshot::Sender<Option<WriterParts>>,
+    },
+    ForceClose {
+        id: ChannelId,
+        reason: String,
+        reply_channel: oneshot::Sender<bool>,
+    },
     Disconnect {
         reason: crate::Disconnect,
         description: String,
         language_tag: String,
     },
+    #[cfg(feature = "danger-raw-packets")]
+    RawPacket {
+        payload: Vec<u8>,
+    },
+    Flush {
+        reply_channel: oneshot::Sender<()>,
+    },
+    ReadExtensions(crate::extensions::ReadExtensions),
     Channel(ChannelId, ChannelMsg),
 }
 
@@ -78,10 +118,17 @@ impl From<(ChannelId, ChannelMsg)> for Msg {
 /// the request/response cycle.
 pub struct Handle {
     pub(crate) sender: Sender<Msg>,
+    pub(crate) channel_buffer_size: Option<usize>,
 }
 
 impl Handle {
     /// Send data to the session referenced by this handler.
+    ///
+    /// `data` is held in memory until the client's window lets it
+    /// through, and `CryptoVec`s are locked in memory, which may count
+    /// against `RLIMIT_MEMLOCK`. Large payloads are better sent with
+    /// [`Handle::data_stream`] or through [`Handle::writer`], which
+    /// only keep a few packets in flight.
     pub async fn data(&self, id: ChannelId, data: CryptoVec) -> Result<(), CryptoVec> {
         self.sender
             .send(Msg::Channel(id, ChannelMsg::Data { data }))
@@ -108,6 +155,81 @@ impl Handle {
             })
     }
 
+    /// Sends everything `data` reads to channel `id`, in packets of the
+    /// client's maximum packet size, waiting for the client's window
+    /// before reading more. The payload is never held in memory as a
+    /// whole, and with the `cryptovec-pool` feature, the packets reuse
+    /// the same few buffers. This is the way to send large responses:
+    ///
+    /// ```ignore
+    /// let file = tokio::fs::File::open(path).await?;
+    /// handle.data_stream(channel, file).await?;
+    /// handle.eof(channel).await?;
+    /// ```
+    pub async fn data_stream<R: AsyncRead + Unpin>(
+        &self,
+        id: ChannelId,
+        mut data: R,
+    ) -> Result<(), Error> {
+        let (mut tx, closed) = self.channel_tx(id, None).await?;
+        match tokio::io::copy(&mut data, &mut tx).await {
+            Ok(_) => Ok(()),
+            Err(_) if closed.load(std::sync::atomic::Ordering::Acquire) => {
+                Err(Error::ChannelClosed)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Makes a writer sending [`ChannelMsg::Data`] on channel `id`,
+    /// like [`Channel::make_writer`], for code that only has the
+    /// handle. Writes wait for the client's window, and shutting the
+    /// writer down sends EOF.
+    ///
+    /// The writer doesn't follow [`Channel::set_rate_limit`].
+    pub async fn writer(&self, id: ChannelId) -> Result<impl AsyncWrite, Error> {
+        self.writer_ext(id, None).await
+    }
+
+    /// Like [`Handle::writer`], sending [`ChannelMsg::ExtendedData`]
+    /// of type `ext` if it is set.
+    pub async fn writer_ext(
+        &self,
+        id: ChannelId,
+        ext: Option<u32>,
+    ) -> Result<impl AsyncWrite, Error> {
+        Ok(self.channel_tx(id, ext).await?.0)
+    }
+
+    async fn channel_tx(
+        &self,
+        id: ChannelId,
+        ext: Option<u32>,
+    ) -> Result<(ChannelTx<Msg>, Arc<AtomicBool>), Error> {
+        let (reply_channel, reply) = oneshot::channel();
+        self.sender
+            .send(Msg::ChannelWriter { id, reply_channel })
+            .await
+            .map_err(|_| Error::SendError)?;
+        let parts = reply
+            .await
+            .map_err(|_| Error::Disconnect)?
+            .ok_or(Error::WrongChannel)?;
+        let closed = parts.closed.clone();
+        let tx = ChannelTx::new(
+            self.sender.clone(),
+            id,
+            parts.window_size,
+            parts.max_packet_size,
+            ext,
+            Default::default(),
+            parts.closed,
+            Default::default(),
+            parts.session_error,
+        );
+        Ok((tx, closed))
+    }
+
     /// Send EOF to the session referenced by this handler.
     pub async fn eof(&self, id: ChannelId) -> Result<(), ()> {
         self.sender
@@ -207,16 +329,40 @@ impl Handle {
     /// usable when it's confirmed by the server, as indicated by the
     /// `confirmed` field of the corresponding `Channel`.
     pub async fn channel_open_session(&self) -> Result<Channel<Msg>, Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
+        self.open_session(None).await
+    }
+
+    /// Request a session channel like [`Handle::channel_open_session`],
+    /// with a label that can be read back from [`Channel::label`] and
+    /// the channel listings. Labels let channels be matched across the
+    /// hops of a chain of connections, where their ids differ.
+    ///
+    /// With [`Config::send_channel_labels`], the label is also sent to
+    /// the client, so that a russh client sees it on its side of the
+    /// channel.
+    pub async fn channel_open_session_labeled<L: Into<String>>(
+        &self,
+        label: L,
+    ) -> Result<Channel<Msg>, Error> {
+        self.open_session(Some(label.into())).await
+    }
+
+    async fn open_session(&self, label: Option<String>) -> Result<Channel<Msg>, Error> {
+        let (channel_ref, receiver) = ChannelRef::new(self.channel_buffer_size);
+        if let Some(label) = label {
+            channel_ref.label().set(&label);
+        }
         let window_size_ref = channel_ref.window_size().clone();
+        let closed_ref = channel_ref.closed().clone();
+        let session_error = channel_ref.session_error().clone();
+        let label = channel_ref.label().clone();
 
         self.sender
             .send(Msg::ChannelOpenSession { channel_ref })
             .await
             .map_err(|_| Error::SendError)?;
 
-        self.wait_channel_confirmation(receiver, window_size_ref)
+        self.wait_channel_confirmation(receiver, window_size_ref, closed_ref, session_error, label)
             .await
     }
 
@@ -232,9 +378,11 @@ impl Handle {
         originator_address: B,
         originator_port: u32,
     ) -> Result<Channel<Msg>, Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
+        let (channel_ref, receiver) = ChannelRef::new(self.channel_buffer_size);
         let window_size_ref = channel_ref.window_size().clone();
+        let closed_ref = channel_ref.closed().clone();
+        let session_error = channel_ref.session_error().clone();
+        let label = channel_ref.label().clone();
 
         self.sender
             .send(Msg::ChannelOpenDirectTcpIp {
@@ -246,7 +394,7 @@ impl Handle {
             })
             .await
             .map_err(|_| Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
+        self.wait_channel_confirmation(receiver, window_size_ref, closed_ref, session_error, label)
             .await
     }
 
@@ -257,9 +405,11 @@ impl Handle {
         originator_address: B,
         originator_port: u32,
     ) -> Result<Channel<Msg>, Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
+        let (channel_ref, receiver) = ChannelRef::new(self.channel_buffer_size);
         let window_size_ref = channel_ref.window_size().clone();
+        let closed_ref = channel_ref.closed().clone();
+        let session_error = channel_ref.session_error().clone();
+        let label = channel_ref.label().clone();
 
         self.sender
             .send(Msg::ChannelOpenForwardedTcpIp {
@@ -271,7 +421,7 @@ impl Handle {
             })
             .await
             .map_err(|_| Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
+        self.wait_channel_confirmation(receiver, window_size_ref, closed_ref, session_error, label)
             .await
     }
 
@@ -280,9 +430,11 @@ impl Handle {
         originator_address: A,
         originator_port: u32,
     ) -> Result<Channel<Msg>, Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
+        let (channel_ref, receiver) = ChannelRef::new(self.channel_buffer_size);
         let window_size_ref = channel_ref.window_size().clone();
+        let closed_ref = channel_ref.closed().clone();
+        let session_error = channel_ref.session_error().clone();
+        let label = channel_ref.label().clone();
 
         self.sender
             .send(Msg::ChannelOpenX11 {
@@ -292,14 +444,17 @@ impl Handle {
             })
             .await
             .map_err(|_| Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
+        self.wait_channel_confirmation(receiver, window_size_ref, closed_ref, session_error, label)
             .await
     }
 
     async fn wait_channel_confirmation(
         &self,
-        mut receiver: UnboundedReceiver<ChannelMsg>,
+        mut receiver: Receiver<ChannelMsg>,
         window_size_ref: Arc<Mutex<u32>>,
+        closed_ref: Arc<AtomicBool>,
+        session_error: SessionError,
+        label: ChannelLabel,
     ) -> Result<Channel<Msg>, Error> {
         loop {
             match receiver.recv().await {
@@ -314,8 +469,16 @@ impl Handle {
                         id,
                         sender: self.sender.clone(),
                         receiver,
+                        pending: VecDeque::new(),
                         max_packet_size,
                         window_size: window_size_ref,
+                        rate_limit: Default::default(),
+                        closed: closed_ref,
+                        local_eof_sent: Default::default(),
+                        remote_eof_received: false,
+                        session_error,
+                        label,
+                        timeouts: Default::default(),
                     });
                 }
                 Some(ChannelMsg::OpenFailure(reason)) => {
@@ -332,10 +495,10 @@ impl Handle {
     }
 
     /// If the program was killed by a signal, send the details about the signal to the client.
-    pub async fn exit_signal_request(
+    pub async fn exit_signal_request<A: Into<Sig>>(
         &self,
         id: ChannelId,
-        signal_name: Sig,
+        signal_name: A,
         core_dumped: bool,
         error_message: String,
         lang_tag: String,
@@ -344,7 +507,7 @@ impl Handle {
             .send(Msg::Channel(
                 id,
                 ChannelMsg::ExitSignal {
-                    signal_name,
+                    signal_name: signal_name.into(),
                     core_dumped,
                     error_message,
                     lang_tag,
@@ -354,6 +517,98 @@ impl Handle {
             .map_err(|_| ())
     }
 
+    /// A view of the [`Extensions`] stored by the handler callbacks.
+    pub fn extensions(&self) -> ExtensionsView {
+        ExtensionsView::server(self.sender.clone())
+    }
+
+    /// Lists the channels currently open on this session, ordered by
+    /// id. The list is empty once the session is closed.
+    pub async fn list_channels(&self) -> Vec<crate::ChannelInfo> {
+        let (reply_channel, reply) = oneshot::channel();
+        if self
+            .sender
+            .send(Msg::ListChannels { reply_channel })
+            .await
+            .is_err()
+        {
+            return Vec::new();
+        }
+        reply.await.unwrap_or_default()
+    }
+
+    /// The pacing of this session, if limited by
+    /// [`Config::rate_limit`](super::Config::rate_limit). `None` once
+    /// the session is closed.
+    pub async fn rate_limit_stats(&self) -> Option<RateStats> {
+        let (reply_channel, reply) = oneshot::channel();
+        self.sender
+            .send(Msg::RateLimitStats { reply_channel })
+            .await
+            .ok()?;
+        reply.await.ok().flatten()
+    }
+
+    /// Closes channel `id` without waiting for its pending data, for
+    /// instance to stop a misbehaving tunnel without ending the session.
+    /// SSH has no room for `reason`, which is only logged.
+    ///
+    /// Returns `true` once the client has closed the channel too, or
+    /// `false` if it hasn't done so within ten seconds.
+    pub async fn force_close(&self, id: ChannelId, reason: &str) -> Result<bool, Error> {
+        let (reply_channel, reply) = oneshot::channel();
+        self.sender
+            .send(Msg::ForceClose {
+                id,
+                reason: reason.into(),
+                reply_channel,
+            })
+            .await
+            .map_err(|_| Error::SendError)?;
+        match crate::runtime::timeout(crate::session::FORCE_CLOSE_TIMEOUT, reply).await {
+            Ok(Ok(true)) => Ok(true),
+            Ok(Ok(false)) => Err(Error::WrongChannel),
+            Ok(Err(_)) | Err(_) => Ok(false),
+        }
+    }
+
+    /// Sends `payload`, a message type followed by its contents, as a
+    /// single packet. The packet is queued behind the ones already
+    /// waiting, and held back during key re-exchanges like any other.
+    ///
+    /// Nothing else is checked: the session doesn't know what was
+    /// sent, and will not expect any answer. Empty payloads and key
+    /// exchange messages are refused with [`Error::RawPacketRejected`].
+    #[cfg(feature = "danger-raw-packets")]
+    pub async fn send_raw_packet(&self, payload: &[u8]) -> Result<(), Error> {
+        crate::raw::check_payload(payload)?;
+        self.sender
+            .send(Msg::RawPacket {
+                payload: payload.to_vec(),
+            })
+            .await
+            .map_err(|_| Error::SendError)
+    }
+
+    /// Waits until everything sent through this handle or the channels
+    /// before this call has been written to the socket, for instance
+    /// before a long pause.
+    ///
+    /// The session writes after handling the messages it has received:
+    /// when several are queued, they are taken together and written in
+    /// one batch, and this returns once the batch holding the messages
+    /// sent before it has been written. It also waits for a key
+    /// re-exchange in progress. Data waiting for the peer's window to
+    /// open isn't written yet, and isn't waited for.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let (reply_channel, reply) = oneshot::channel();
+        self.sender
+            .send(Msg::Flush { reply_channel })
+            .await
+            .map_err(|_| Error::SendError)?;
+        reply.await.map_err(|_| Error::Disconnect)
+    }
+
     /// Allows a server to disconnect a client session
     pub async fn disconnect(
         &self,
@@ -381,8 +636,60 @@ impl Session {
         }
     }
 
-    pub(crate) async fn run<H, R>(
-        mut self,
+    /// Whether messages from the handle and the channels can be handled
+    /// now. During a key re-exchange, what they send is held back until
+    /// the new keys are in use, within the write buffer's limits.
+    fn can_handle_messages(&self) -> bool {
+        self.common.encrypted.is_some() && !self.is_write_buffer_full()
+    }
+
+    /// Whether new outgoing messages should wait until the pending
+    /// data has been sent.
+    fn is_write_buffer_full(&self) -> bool {
+        self.common.config.write_buffer_policy == WriteBufferPolicy::Backpressure
+            && self.common.pending_write_len() > self.common.config.write_buffer_high_water_mark
+    }
+
+    fn check_write_buffer(&self) -> Result<(), Error> {
+        if self.common.config.write_buffer_policy == WriteBufferPolicy::Disconnect
+            && self.common.pending_write_len() > self.common.config.write_buffer_high_water_mark
+        {
+            debug!(
+                "write buffer overflow: {} bytes pending",
+                self.common.pending_write_len()
+            );
+            return Err(Error::WriteBufferOverflow);
+        }
+        Ok(())
+    }
+
+    /// Records that the handler callback `callback` ran for longer
+    /// than [`Config::handler_timeout`], and tells `handler`.
+    pub(crate) fn handler_timed_out<H: Handler>(&self, handler: &mut H, callback: &'static str) {
+        error!("handler callback {} timed out", callback);
+        handler.handler_timed_out(callback);
+    }
+
+    pub(crate) async fn run<H, R>(mut self, stream: SshRead<R>, handler: H) -> Result<(), H::Error>
+    where
+        H: Handler + Send + 'static,
+        R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let result = self.run_inner(stream, handler).await;
+        if result.is_err() {
+            // Handler errors can't be described.
+            let description = self
+                .common
+                .error_disconnect
+                .description()
+                .unwrap_or("the session ended with an error");
+            fail_channels(&self.channels, description);
+        }
+        result
+    }
+
+    async fn run_inner<H, R>(
+        &mut self,
         mut stream: SshRead<R>,
         mut handler: H,
     ) -> Result<(), H::Error>
@@ -395,6 +702,7 @@ impl Session {
             .write_all(&self.common.write_buffer.buffer)
             .await
             .map_err(crate::Error::from)?;
+        stream.flush().await.map_err(crate::Error::from)?;
         self.common.write_buffer.buffer.clear();
 
         let (stream_read, mut stream_write) = stream.split();
@@ -404,14 +712,38 @@ impl Session {
         let mut opening_cipher = Box::new(clear::Key) as Box<dyn OpeningKey + Send>;
         std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
 
-        let keepalive_timer =
-            future_or_pending(self.common.config.keepalive_interval, tokio::time::sleep);
+        let mut runtime_changed = self.runtime.subscribe();
+        let keepalive_timer = future_or_pending(
+            self.runtime_config().keepalive_interval,
+            crate::runtime::sleep,
+        );
         pin!(keepalive_timer);
 
-        let inactivity_timer =
-            future_or_pending(self.common.config.inactivity_timeout, tokio::time::sleep);
+        let inactivity_timer = future_or_pending(
+            self.runtime_config().inactivity_timeout,
+            crate::runtime::sleep,
+        );
         pin!(inactivity_timer);
 
+        let session_deadline = future_or_pending(
+            self.common.config.max_session_duration,
+            crate::runtime::sleep,
+        );
+        pin!(session_deadline);
+
+        // Wakes the session up when the data held back by the rate
+        // limits can be sent.
+        let paced = self.common.config.per_channel_rate.is_some();
+        let rate_timer = future_or_pending(None, crate::runtime::sleep);
+        pin!(rate_timer);
+
+        let stream_read = PacketReader::new(
+            stream_read,
+            self.common.config.maximum_inbound_packet_size,
+            self.common.config.read_pipeline_depth,
+            self.common.config.spawner.as_ref(),
+        );
+        let read_barrier = stream_read.barrier();
         let reading = start_reading(stream_read, buffer, opening_cipher);
         pin!(reading);
         let mut is_reading = None;
@@ -422,10 +754,14 @@ impl Session {
             self.common.received_data = false;
             let mut sent_keepalive = false;
             tokio::select! {
-                r = &mut reading => {
+                r = &mut reading, if !channels_full(&self.channels) => {
                     let (stream_read, mut buffer, mut opening_cipher) = match r {
                         Ok((_, stream_read, buffer, opening_cipher)) => (stream_read, buffer, opening_cipher),
-                        Err(e) => return Err(e.into())
+                        Err(e) => {
+                            let e = self.common.error_disconnect.record(e);
+                            self.write_error_disconnect(&mut stream_write).await;
+                            return Err(e.into())
+                        }
                     };
                     if buffer.buffer.len() < 5 {
                         is_reading = Some((stream_read, buffer, opening_cipher));
@@ -457,9 +793,12 @@ impl Session {
                             self.common.received_data = true;
                             std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
                             // TODO it'd be cleaner to just pass cipher to reply()
-                            match reply(&mut self, &mut handler, &mut buffer.seqn, buf).await {
+                            match reply(self, &mut handler, &mut buffer.seqn, buf).await {
                                 Ok(_) => {},
-                                Err(e) => return Err(e),
+                                Err(e) => {
+                                    self.write_error_disconnect(&mut stream_write).await;
+                                    return Err(e)
+                                }
                             }
                             std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
                         }
@@ -467,7 +806,8 @@ impl Session {
                     reading.set(start_reading(stream_read, buffer, opening_cipher));
                 }
                 () = &mut keepalive_timer => {
-                    if self.common.config.keepalive_max != 0 && self.common.alive_timeouts > self.common.config.keepalive_max {
+                    let keepalive_max = self.runtime_config().keepalive_max;
+                    if keepalive_max != 0 && self.common.alive_timeouts > keepalive_max {
                         debug!("Timeout, client not responding to keepalives");
                         return Err(crate::Error::KeepaliveTimeout.into());
                     }
@@ -479,17 +819,40 @@ impl Session {
                     debug!("timeout");
                     return Err(crate::Error::InactivityTimeout.into());
                 }
-                msg = self.receiver.recv(), if !self.is_rekeying() => {
+                () = &mut session_deadline => {
+                    debug!("maximum session duration reached");
+                    let e = self.common.error_disconnect.record(crate::Error::SessionDurationExceeded);
+                    self.write_error_disconnect(&mut stream_write).await;
+                    return Err(e.into());
+                }
+                Ok(()) = runtime_changed.changed() => {
+                    let runtime = self.runtime_config();
+                    debug!("runtime config changed: {:?}", runtime);
+                    keepalive_timer.set(future_or_pending(runtime.keepalive_interval, crate::runtime::sleep));
+                    inactivity_timer.set(future_or_pending(runtime.inactivity_timeout, crate::runtime::sleep));
+                }
+                // The data held back is flushed below.
+                () = &mut rate_timer => {}
+                () = std::future::ready(()), if self.common.has_flushable_data() => {}
+                () = wait_channel_capacity(&self.channels) => {
+                    flush_channels(&mut self.channels);
+                }
+                msg = self.receiver.recv(), if self.can_handle_messages() => {
                     match msg {
                         Some(Msg::Channel(id, ChannelMsg::Data { data })) => {
+                            handler.on_channel_data(Direction::Outbound, id, &data);
                             self.data(id, data);
                         }
                         Some(Msg::Channel(id, ChannelMsg::ExtendedData { ext, data })) => {
+                            handler.on_channel_extended_data(Direction::Outbound, id, ext, &data);
                             self.extended_data(id, ext, data);
                         }
                         Some(Msg::Channel(id, ChannelMsg::Eof)) => {
                             self.eof(id);
                         }
+                        Some(Msg::Channel(id, ChannelMsg::EndOfWrite)) => {
+                            self.end_of_write(id);
+                        }
                         Some(Msg::Channel(id, ChannelMsg::Close)) => {
                             self.close(id);
                         }
@@ -499,6 +862,12 @@ impl Session {
                         Some(Msg::Channel(id, ChannelMsg::Failure)) => {
                             self.channel_failure(id);
                         }
+                        Some(Msg::Channel(id, ChannelMsg::Exec { want_reply, command })) => {
+                            self.exec(id, want_reply, &command);
+                        }
+                        Some(Msg::Channel(id, ChannelMsg::Request { request_type, want_reply, payload })) => {
+                            self.channel_request(id, &request_type, want_reply, &payload);
+                        }
                         Some(Msg::Channel(id, ChannelMsg::XonXoff { client_can_do })) => {
                             self.xon_xoff_request(id, client_can_do);
                         }
@@ -511,8 +880,17 @@ impl Session {
                         Some(Msg::Channel(id, ChannelMsg::WindowAdjusted { new_size })) => {
                             debug!("window adjusted to {:?} for channel {:?}", new_size, id);
                         }
+                        Some(Msg::Channel(_, ChannelMsg::Flush { done })) => {
+                            self.common.flush_waiters.push(done);
+                        }
                         Some(Msg::ChannelOpenSession { channel_ref }) => {
                             let id = self.channel_open_session()?;
+                            if let Some(label) = channel_ref.label().get() {
+                                let announce = self.common.config.send_channel_labels;
+                                if let Some(ref mut enc) = self.common.encrypted {
+                                    enc.label_channel(id, &label, announce);
+                                }
+                            }
                             self.channels.insert(id, channel_ref);
                         }
                         Some(Msg::ChannelOpenDirectTcpIp { host_to_connect, port_to_connect, originator_address, originator_port, channel_ref }) => {
@@ -533,9 +911,31 @@ impl Session {
                         Some(Msg::CancelTcpIpForward { address, port, reply_channel }) => {
                             self.cancel_tcpip_forward(&address, port, reply_channel);
                         }
+                        Some(Msg::ListChannels { reply_channel }) => {
+                            let _ = reply_channel.send(self.channels_info());
+                        }
+                        Some(Msg::RateLimitStats { reply_channel }) => {
+                            let _ = reply_channel.send(self.rate_limit_stats());
+                        }
+                        Some(Msg::ChannelWriter { id, reply_channel }) => {
+                            let _ = reply_channel.send(self.writer_parts(id));
+                        }
+                        Some(Msg::ForceClose { id, reason, reply_channel }) => {
+                            self.force_close(id, &reason, reply_channel);
+                        }
                         Some(Msg::Disconnect {reason, description, language_tag}) => {
                             self.common.disconnect(reason, &description, &language_tag);
                         }
+                        Some(Msg::Flush { reply_channel }) => {
+                            self.common.flush_waiters.push(reply_channel);
+                        }
+                        Some(Msg::ReadExtensions(read)) => {
+                            read.run(&self.extensions);
+                        }
+                        #[cfg(feature = "danger-raw-packets")]
+                        Some(Msg::RawPacket { payload }) => {
+                            self.common.send_raw_packet(&payload);
+                        }
                         Some(_) => {
                             // should be unreachable, since the receiver only gets
                             // messages from methods implemented within russh
@@ -548,11 +948,52 @@ impl Session {
                 }
             }
             self.flush()?;
-            stream_write
-                .write_all(&self.common.write_buffer.buffer)
-                .await
-                .map_err(crate::Error::from)?;
+            read_barrier.set(self.is_rekeying());
+            if !self.common.write_buffer.buffer.is_empty() {
+                if let Some(ref mut rate_limit) = self.common.rate_limit {
+                    rate_limit.wait().await;
+                    rate_limit.consume(self.common.write_buffer.buffer.len());
+                }
+                stream_write
+                    .write_all(&self.common.write_buffer.buffer)
+                    .await
+                    .map_err(crate::Error::from)?;
+                // The stream may be buffered (TLS, BufWriter...), and
+                // the peer could be waiting for these bytes to send more.
+                stream_write.flush().await.map_err(crate::Error::from)?;
+            }
             self.common.write_buffer.buffer.clear();
+            self.common.flushed();
+            self.check_write_buffer()?;
+            // Delayed compression starts right after USERAUTH_SUCCESS has
+            // been written, not when the client sends its next packet.
+            if let Some(ref mut enc) = self.common.encrypted {
+                if let EncryptedState::InitCompression = enc.state {
+                    enc.server_compression.init_compress(&mut enc.compress);
+                    enc.state = EncryptedState::Authenticated;
+                }
+            }
+
+            if std::mem::take(&mut self.match_changed) {
+                let runtime = self.runtime_config();
+                keepalive_timer.set(future_or_pending(
+                    runtime.keepalive_interval,
+                    crate::runtime::sleep,
+                ));
+                inactivity_timer.set(future_or_pending(
+                    runtime.inactivity_timeout,
+                    crate::runtime::sleep,
+                ));
+            }
+
+            if paced {
+                let delay = self
+                    .common
+                    .encrypted
+                    .as_mut()
+                    .and_then(|enc| enc.data_rate_delay());
+                rate_timer.set(future_or_pending(delay, crate::runtime::sleep));
+            }
 
             if self.common.received_data {
                 // Reset the number of failed keepalive attempts. We don't
@@ -565,37 +1006,64 @@ impl Session {
             if self.common.received_data || sent_keepalive {
                 if let (futures::future::Either::Right(ref mut sleep), Some(d)) = (
                     keepalive_timer.as_mut().as_pin_mut(),
-                    self.common.config.keepalive_interval,
+                    self.runtime_config().keepalive_interval,
                 ) {
-                    sleep.as_mut().reset(tokio::time::Instant::now() + d);
+                    sleep.reset(d);
                 }
             }
             if !sent_keepalive {
                 if let (futures::future::Either::Right(ref mut sleep), Some(d)) = (
                     inactivity_timer.as_mut().as_pin_mut(),
-                    self.common.config.inactivity_timeout,
+                    self.runtime_config().inactivity_timeout,
                 ) {
-                    sleep.as_mut().reset(tokio::time::Instant::now() + d);
+                    sleep.reset(d);
                 }
             }
         }
         debug!("disconnected");
-        // Shutdown
-        stream_write.shutdown().await.map_err(crate::Error::from)?;
-        loop {
-            if let Some((stream_read, buffer, opening_cipher)) = is_reading.take() {
-                reading.set(start_reading(stream_read, buffer, opening_cipher));
+        // Shutdown, and drain the reader until the client closes its side.
+        let drain = async {
+            stream_write.shutdown().await.map_err(crate::Error::from)?;
+            loop {
+                if let Some((stream_read, buffer, opening_cipher)) = is_reading.take() {
+                    reading.set(start_reading(stream_read, buffer, opening_cipher));
+                }
+                let (n, r, b, opening_cipher) = (&mut reading).await?;
+                is_reading = Some((r, b, opening_cipher));
+                if n == 0 {
+                    break;
+                }
             }
-            let (n, r, b, opening_cipher) = (&mut reading).await?;
-            is_reading = Some((r, b, opening_cipher));
-            if n == 0 {
-                break;
+            Ok::<(), crate::Error>(())
+        };
+        let shutdown_timer =
+            future_or_pending(self.common.config.shutdown_timeout, crate::runtime::sleep);
+
+        #[allow(clippy::panic)] // false positive in macro
+        {
+            tokio::select! {
+                r = drain => r?,
+                () = shutdown_timer => {
+                    debug!("shutdown timeout, dropping the connection");
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Sends the DISCONNECT matching the protocol error that ended
+    /// the session, if any.
+    async fn write_error_disconnect<W: AsyncWrite + Unpin>(&mut self, stream_write: &mut W) {
+        if self.common.disconnect_after_error() && self.flush().is_ok() {
+            let _ = stream_write
+                .write_all(&self.common.write_buffer.buffer)
+                .await;
+            let _ = stream_write.flush().await;
+            self.common.write_buffer.buffer.clear();
+        }
+    }
+
     /// Get a handle to this session.
     pub fn handle(&self) -> Handle {
         self.sender.clone()
@@ -634,7 +1102,7 @@ impl Session {
     pub fn flush(&mut self) -> Result<(), Error> {
         if let Some(ref mut enc) = self.common.encrypted {
             if enc.flush(
-                &self.common.config.as_ref().limits,
+                &self.common.limits,
                 &mut *self.common.cipher.local_to_remote,
                 &mut self.common.write_buffer,
             )? && enc.rekey.is_none()
@@ -670,6 +1138,44 @@ impl Session {
         }
     }
 
+    /// See [`Handle::force_close`].
+    pub(crate) fn force_close(
+        &mut self,
+        id: ChannelId,
+        reason: &str,
+        reply_channel: oneshot::Sender<bool>,
+    ) {
+        info!("closing channel {:?}: {}", id, reason);
+        match self.common.encrypted {
+            Some(ref mut enc) => enc.force_close(id, reply_channel),
+            None => {
+                let _ = reply_channel.send(false);
+            }
+        }
+    }
+
+    fn writer_parts(&self, id: ChannelId) -> Option<WriterParts> {
+        let enc = self.common.encrypted.as_ref()?;
+        let max_packet_size = enc.channels.get(&id)?.recipient_maximum_packet_size;
+        Some(self.channels.get(&id)?.writer_parts(max_packet_size))
+    }
+
+    /// Lists the channels currently open on this session, ordered by id.
+    pub fn channels_info(&self) -> Vec<crate::ChannelInfo> {
+        if let Some(ref enc) = self.common.encrypted {
+            enc.channels_info()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// The pacing of this session, if limited by
+    /// [`Config::rate_limit`](super::Config::rate_limit). The pacing of
+    /// each channel is in [`Session::channels_info`].
+    pub fn rate_limit_stats(&self) -> Option<RateStats> {
+        self.common.rate_limit.as_ref().map(|rate| rate.stats())
+    }
+
     pub fn has_pending_data(&self, channel: ChannelId) -> bool {
         if let Some(ref enc) = self.common.encrypted {
             enc.has_pending_data(channel)
@@ -683,6 +1189,25 @@ impl Session {
         &self.common.config
     }
 
+    /// The rekey thresholds currently used by this session, initially
+    /// taken from the configuration.
+    pub fn limits(&self) -> &Limits {
+        &self.common.limits
+    }
+
+    /// Change the rekey thresholds for the rest of this session, for
+    /// instance to rekey more often once a bulk transfer is over.
+    ///
+    /// The byte counters are not reset, so if the new limits are
+    /// already exceeded, a key exchange starts on the next flush.
+    /// Returns [`Error::InvalidLimits`] if a byte limit is zero or above
+    /// 1GB, or if the time limit is zero.
+    pub fn set_limits(&mut self, limits: Limits) -> Result<(), Error> {
+        limits.check()?;
+        self.common.limits = limits;
+        Ok(())
+    }
+
     /// Sends a disconnect message.
     pub fn disconnect(&mut self, reason: Disconnect, description: &str, language_tag: &str) {
         self.common.disconnect(reason, description, language_tag);
@@ -765,12 +1290,24 @@ impl Session {
 
     /// Close a channel.
     pub fn close(&mut self, channel: ChannelId) {
-        self.common.byte(channel, msg::CHANNEL_CLOSE);
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.close(channel)
+        }
     }
 
     /// Send EOF to a channel
     pub fn eof(&mut self, channel: ChannelId) {
-        self.common.byte(channel, msg::CHANNEL_EOF);
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.eof(channel)
+        }
+    }
+
+    /// Tells the client that we won't read more data from `channel`,
+    /// see [`Channel::end_of_write`](crate::Channel::end_of_write).
+    pub fn end_of_write(&mut self, channel: ChannelId) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.end_of_write(channel)
+        }
     }
 
     /// Send data to a channel. On session channels, `extended` can be
@@ -806,48 +1343,72 @@ impl Session {
     /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-6.8).
     pub fn xon_xoff_request(&mut self, channel: ChannelId, client_can_do: bool) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                assert!(channel.confirmed);
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"xon-xoff");
-                    enc.write.push(0);
-                    enc.write.push(client_can_do as u8);
-                })
-            }
+            enc.channel_msg(channel, msg::CHANNEL_REQUEST, |w| {
+                w.extend_ssh_string(b"xon-xoff");
+                w.push(0);
+                w.push(client_can_do as u8);
+            });
+        }
+    }
+
+    /// Request that the client execute `command` on a channel opened
+    /// by the server. If `want_reply` is set, the client's answer is
+    /// delivered to the channel as [`ChannelMsg::Success`] or
+    /// [`ChannelMsg::Failure`].
+    pub fn exec(&mut self, channel: ChannelId, want_reply: bool, command: &[u8]) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.channel_msg(channel, msg::CHANNEL_REQUEST, |w| {
+                w.extend_ssh_string(b"exec");
+                w.push(want_reply as u8);
+                w.extend_ssh_string(command);
+            });
         }
     }
 
     /// Ping the client to verify there is still connectivity.
     pub fn keepalive_request(&mut self) {
-        let want_reply = u8::from(true);
         if let Some(ref mut enc) = self.common.encrypted {
-            self.open_global_requests
-                .push_back(GlobalRequestResponse::Keepalive);
-            push_packet!(enc.write, {
-                enc.write.push(msg::GLOBAL_REQUEST);
-                enc.write.extend_ssh_string(b"keepalive@openssh.com");
-                enc.write.push(want_reply);
-            })
+            let mut packet = CryptoVec::new();
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(b"keepalive@openssh.com");
+            packet.push(1);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                Some(GlobalRequestResponse::Keepalive),
+            );
+        }
+    }
+
+    /// Sends a request of type `request_type` on `channel`, with
+    /// `payload` written verbatim after the `want reply` field. If
+    /// `want_reply` is set, the client's answer is delivered to the
+    /// channel as [`ChannelMsg::Success`] or [`ChannelMsg::Failure`].
+    pub fn channel_request(
+        &mut self,
+        channel: ChannelId,
+        request_type: &str,
+        want_reply: bool,
+        payload: &[u8],
+    ) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.channel_msg(channel, msg::CHANNEL_REQUEST, |w| {
+                w.extend_ssh_string(request_type.as_bytes());
+                w.push(want_reply as u8);
+                w.extend(payload);
+            });
         }
     }
 
     /// Send the exit status of a program.
     pub fn exit_status_request(&mut self, channel: ChannelId, exit_status: u32) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                assert!(channel.confirmed);
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"exit-status");
-                    enc.write.push(0);
-                    enc.write.push_u32_be(exit_status)
-                })
-            }
+            enc.channel_msg(channel, msg::CHANNEL_REQUEST, |w| {
+                w.extend_ssh_string(b"exit-status");
+                w.push(0);
+                w.push_u32_be(exit_status)
+            });
         }
     }
 
@@ -861,20 +1422,14 @@ impl Session {
         language_tag: &str,
     ) {
         if let Some(ref mut enc) = self.common.encrypted {
-            if let Some(channel) = enc.channels.get(&channel) {
-                assert!(channel.confirmed);
-                push_packet!(enc.write, {
-                    enc.write.push(msg::CHANNEL_REQUEST);
-
-                    enc.write.push_u32_be(channel.recipient_channel);
-                    enc.write.extend_ssh_string(b"exit-signal");
-                    enc.write.push(0);
-                    enc.write.extend_ssh_string(signal.name().as_bytes());
-                    enc.write.push(core_dumped as u8);
-                    enc.write.extend_ssh_string(error_message.as_bytes());
-                    enc.write.extend_ssh_string(language_tag.as_bytes());
-                })
-            }
+            enc.channel_msg(channel, msg::CHANNEL_REQUEST, |w| {
+                w.extend_ssh_string(b"exit-signal");
+                w.push(0);
+                w.extend_ssh_string(signal.name().as_bytes());
+                w.push(core_dumped as u8);
+                w.extend_ssh_string(error_message.as_bytes());
+                w.extend_ssh_string(language_tag.as_bytes());
+            });
         }
     }
 
@@ -951,6 +1506,7 @@ impl Session {
             }
 
             let sender_channel = enc.new_channel(
+                kind,
                 self.common.config.window_size,
                 self.common.config.maximum_packet_size,
             );
@@ -988,19 +1544,41 @@ impl Session {
         reply_channel: Option<oneshot::Sender<Option<u32>>>,
     ) {
         if let Some(ref mut enc) = self.common.encrypted {
-            let want_reply = reply_channel.is_some();
-            if let Some(reply_channel) = reply_channel {
-                self.open_global_requests.push_back(
-                    crate::session::GlobalRequestResponse::TcpIpForward(reply_channel),
-                );
+            let mut packet = CryptoVec::new();
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(b"tcpip-forward");
+            packet.push(reply_channel.is_some() as u8);
+            packet.extend_ssh_string(address.as_bytes());
+            packet.push_u32_be(port);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                reply_channel.map(GlobalRequestResponse::TcpIpForward),
+            );
+        }
+    }
+
+    /// Like [`tcpip_forward`](Self::tcpip_forward), but asks for the
+    /// client's reply, and returns a future resolving to the forwarded
+    /// port, which the client chooses if `port` is 0.
+    ///
+    /// The request is only sent once the handler returns, so the
+    /// future must be awaited elsewhere, for instance in a spawned task.
+    pub fn tcpip_forward_with_reply(
+        &mut self,
+        address: &str,
+        port: u32,
+    ) -> impl Future<Output = Result<u32, Error>> + Send + 'static {
+        let (reply_channel, reply) = oneshot::channel();
+        self.tcpip_forward(address, port, Some(reply_channel));
+        async move {
+            match reply.await {
+                Ok(Some(bound)) if port == 0 => Ok(bound),
+                Ok(Some(_)) => Ok(port),
+                Ok(None) => Err(Error::RequestDenied),
+                Err(_) => Err(Error::Disconnect),
             }
-            push_packet!(enc.write, {
-                enc.write.push(msg::GLOBAL_REQUEST);
-                enc.write.extend_ssh_string(b"tcpip-forward");
-                enc.write.push(want_reply as u8);
-                enc.write.extend_ssh_string(address.as_bytes());
-                enc.write.push_u32_be(port);
-            });
         }
     }
 
@@ -1012,22 +1590,85 @@ impl Session {
         reply_channel: Option<oneshot::Sender<bool>>,
     ) {
         if let Some(ref mut enc) = self.common.encrypted {
-            let want_reply = reply_channel.is_some();
-            if let Some(reply_channel) = reply_channel {
-                self.open_global_requests.push_back(
-                    crate::session::GlobalRequestResponse::CancelTcpIpForward(reply_channel),
-                );
+            let mut packet = CryptoVec::new();
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(b"cancel-tcpip-forward");
+            packet.push(reply_channel.is_some() as u8);
+            packet.extend_ssh_string(address.as_bytes());
+            packet.push_u32_be(port);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                reply_channel.map(GlobalRequestResponse::CancelTcpIpForward),
+            );
+        }
+    }
+
+    /// Like [`cancel_tcpip_forward`](Self::cancel_tcpip_forward), but
+    /// asks for the client's reply, and returns a future resolving once
+    /// it arrives. As with
+    /// [`tcpip_forward_with_reply`](Self::tcpip_forward_with_reply),
+    /// the future must be awaited outside of the handler.
+    pub fn cancel_tcpip_forward_with_reply(
+        &mut self,
+        address: &str,
+        port: u32,
+    ) -> impl Future<Output = Result<(), Error>> + Send + 'static {
+        let (reply_channel, reply) = oneshot::channel();
+        self.cancel_tcpip_forward(address, port, Some(reply_channel));
+        async move {
+            match reply.await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(Error::RequestDenied),
+                Err(_) => Err(Error::Disconnect),
             }
-            push_packet!(enc.write, {
-                enc.write.push(msg::GLOBAL_REQUEST);
-                enc.write.extend_ssh_string(b"cancel-tcpip-forward");
-                enc.write.push(want_reply as u8);
-                enc.write.extend_ssh_string(address.as_bytes());
-                enc.write.push_u32_be(port);
-            });
         }
     }
 
+    /// Sends a global request named `name`, with `payload` written
+    /// verbatim after the `want reply` field, for requests this crate
+    /// doesn't know about.
+    ///
+    /// If `want_reply` is true, returns a future resolving to the data
+    /// of the client's success reply, or to [`Error::RequestDenied`] if
+    /// the client refused the request. As with the other requests, it
+    /// is only sent once the handler returns, so the future must be
+    /// awaited outside of the handler.
+    pub fn send_global_request(
+        &mut self,
+        name: &str,
+        want_reply: bool,
+        payload: &[u8],
+    ) -> Option<impl Future<Output = Result<Vec<u8>, Error>> + Send + 'static> {
+        let (reply_channel, reply) = if want_reply {
+            let (reply_channel, reply) = oneshot::channel();
+            (Some(reply_channel), Some(reply))
+        } else {
+            (None, None)
+        };
+        if let Some(ref mut enc) = self.common.encrypted {
+            let mut packet = CryptoVec::new();
+            packet.push(msg::GLOBAL_REQUEST);
+            packet.extend_ssh_string(name.as_bytes());
+            packet.push(want_reply as u8);
+            packet.extend(payload);
+            self.global_requests.send(
+                enc,
+                self.common.config.max_pending_global_requests,
+                packet,
+                reply_channel.map(GlobalRequestResponse::Raw),
+            );
+        }
+        reply.map(|reply| async move {
+            match reply.await {
+                Ok(Some(data)) => Ok(data),
+                Ok(None) => Err(Error::RequestDenied),
+                Err(_) => Err(Error::Disconnect),
+            }
+        })
+    }
+
     /// Returns the SSH ID (Protocol Version + Software Version) the client sent when connecting
     ///
     /// This should contain only ASCII characters for implementations conforming to RFC4253, Section 4.2:
@@ -1041,6 +1682,105 @@ impl Session {
         &self.common.remote_sshid
     }
 
+    /// Returns how the client authenticated, or `None` if the client
+    /// is not authenticated yet.
+    ///
+    /// This is set once, when authentication succeeds, and doesn't
+    /// change for the rest of the session.
+    pub fn auth_info(&self) -> Option<&AuthInfo> {
+        self.auth_info.as_ref()
+    }
+
+    /// When authentication succeeded, or `None` if the session is not
+    /// authenticated yet.
+    pub fn established_at(&self) -> Option<std::time::Instant> {
+        self.common.established_at
+    }
+
+    /// How long ago authentication succeeded, see [`Session::established_at`].
+    pub fn age(&self) -> Option<std::time::Duration> {
+        self.common
+            .established_at
+            .map(|t| crate::runtime::now().saturating_duration_since(t))
+    }
+
+    /// Application data attached to this session, for instance
+    /// claims stored in [`Handler::auth_succeeded`] and read by later
+    /// callbacks.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// See [`Session::extensions`].
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// The client's address, if the session was started with
+    /// [`run_stream_with_peer_addr`](super::run_stream_with_peer_addr).
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.peer_addr
+    }
+
+    /// The names of the rules of [`Config::conditional`] applying to
+    /// this session: first those on the address, then, once the client
+    /// names the user it authenticates as, those on the user too.
+    pub fn matched_rules(&self) -> &[String] {
+        &self.matched.rules
+    }
+
+    /// The [`RuntimeConfig`] in effect for this session: the server's,
+    /// changed by the rules of [`Config::conditional`] matching it.
+    pub fn runtime_config(&self) -> Arc<RuntimeConfig> {
+        let runtime = self.runtime.config();
+        if self.matched.rules.is_empty() {
+            runtime
+        } else {
+            Arc::new(self.matched.overrides.apply(&runtime))
+        }
+    }
+
+    /// Evaluates the rules of [`Config::conditional`] again if `user`
+    /// is not the user they were evaluated for.
+    pub(crate) fn match_user(&mut self, user: &str) {
+        let config = &self.common.config;
+        if config.conditional.rules.is_empty() || self.match_user.as_deref() == Some(user) {
+            return;
+        }
+        let matched = config
+            .conditional
+            .evaluate(self.peer_addr.map(|a| a.ip()), Some(user));
+        debug!("rules matching {:?}: {:?}", user, matched.rules);
+        self.forwarding_policy = matched
+            .overrides
+            .forwarding_policy
+            .clone()
+            .unwrap_or_else(|| config.forwarding_policy.clone());
+        self.match_changed = matched != self.matched;
+        self.matched = matched;
+        self.match_user = Some(user.to_string());
+    }
+
+    /// The forwardings allowed in this session, initially
+    /// [`Config::forwarding_policy`], or the policy set by the rules of
+    /// [`Config::conditional`].
+    pub fn forwarding_policy(&self) -> &ForwardingPolicy {
+        &self.forwarding_policy
+    }
+
+    /// See [`Session::forwarding_policy`]. Use
+    /// [`ForwardingPolicy::restrict`] to restrict it for the
+    /// authenticated user, for instance in [`Handler::auth_succeeded`].
+    pub fn forwarding_policy_mut(&mut self) -> &mut ForwardingPolicy {
+        &mut self.forwarding_policy
+    }
+
+    /// Whether the client sent `no-more-sessions@openssh.com`, see
+    /// [`Config::enforce_no_more_sessions`].
+    pub fn no_more_sessions(&self) -> bool {
+        self.no_more_sessions
+    }
+
     pub(crate) fn maybe_send_ext_info(&mut self) {
         if let Some(ref mut enc) = self.common.encrypted {
             // If client sent a ext-info-c message in the kex list, it supports RFC 8308 extension negotiation.
@@ -1062,12 +1802,17 @@ impl Session {
                 return;
             }
 
+            let advertise_ping = self.common.config.advertise_ping;
             push_packet!(enc.write, {
                 enc.write.push(msg::EXT_INFO);
-                enc.write.push_u32_be(1);
+                enc.write.push_u32_be(1 + advertise_ping as u32);
                 enc.write.extend_ssh_string(b"server-sig-algs");
                 enc.write
                     .extend_list(self.common.config.preferred.key.iter());
+                if advertise_ping {
+                    enc.write.extend_ssh_string(b"ping@openssh.com");
+                    enc.write.extend_ssh_string(b"0");
+                }
             });
         }
     }
@@ -13,20 +13,23 @@
 // limitations under the License.
 //
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Debug, Formatter};
 use std::num::Wrapping;
 
 use byteorder::{BigEndian, ByteOrder};
-use log::{debug, trace};
+use log::{debug, error, trace, warn};
 use tokio::sync::oneshot;
 
 use crate::cipher::SealingKey;
 use crate::kex::KexAlgorithm;
-use crate::keys::encoding::Encoding;
+use crate::keys::encoding::{Encoding, Reader};
+use crate::parsing::ChannelOpenConfirmation;
+use crate::rate_limit::TokenBucket;
 use crate::sshbuffer::SSHBuffer;
 use crate::{
-    auth, cipher, mac, msg, negotiation, ChannelId, ChannelParams, CryptoVec, Disconnect, Limits,
+    auth, cipher, mac, msg, negotiation, ChannelId, ChannelParams, CryptoVec, Disconnect, Error,
+    Limits, PendingMessage,
 };
 
 #[derive(Debug)]
@@ -51,12 +54,32 @@ pub(crate) struct Encrypted {
     pub compress: crate::compression::Compress,
     pub decompress: crate::compression::Decompress,
     pub compress_buffer: CryptoVec,
+    /// Offsets in `write` of the global requests waiting for a reply
+    /// that are not sent yet.
+    pub global_request_offsets: VecDeque<usize>,
+    /// Sequence numbers of the sent global requests waiting for a
+    /// reply, oldest first, to match UNIMPLEMENTED replies.
+    pub global_request_seqns: VecDeque<u32>,
+    /// Notified when the peer closes a channel we forcibly closed.
+    pub close_waiters: HashMap<ChannelId, oneshot::Sender<bool>>,
+    /// The pace of the data sent on each new channel.
+    pub per_channel_rate: Option<crate::RateLimit>,
+    /// The channel whose pending data was flushed first last time, so
+    /// that the next flush starts after it.
+    pub flush_cursor: ChannelId,
 }
 
+/// Most bytes of pending data flushed from one channel at a time, so
+/// that a channel with a lot to send can't use up the write buffer of
+/// the session, and every channel makes progress each time the session
+/// loop turns.
+pub(crate) const CHANNEL_FLUSH_QUANTUM: usize = 64 << 10;
+
 pub(crate) struct CommonSession<Config> {
     pub auth_user: String,
     pub remote_sshid: Vec<u8>,
     pub config: Config,
+    pub limits: Limits,
     pub encrypted: Option<Encrypted>,
     pub auth_method: Option<auth::Method>,
     pub(crate) auth_attempts: usize,
@@ -67,20 +90,63 @@ pub(crate) struct CommonSession<Config> {
     pub disconnected: bool,
     pub buffer: CryptoVec,
     pub strict_kex: bool,
+    /// Number of wrongly guessed kex packets skipped during the
+    /// initial key exchange, which don't count in its message order.
+    pub skipped_kex_packets: u32,
     pub alive_timeouts: usize,
     pub received_data: bool,
+    /// Session-wide rate limit, applied when writing to the socket.
+    pub rate_limit: Option<TokenBucket>,
+    pub error_disconnect: ErrorDisconnect,
+    /// Writers waiting for everything they sent before a
+    /// [`ChannelMsg::Flush`](crate::ChannelMsg::Flush) to be written to the socket.
+    pub flush_waiters: Vec<oneshot::Sender<()>>,
+    /// When authentication succeeded.
+    pub established_at: Option<std::time::Instant>,
+}
+
+/// The DISCONNECT to send when the session ends because of a protocol
+/// error. Errors are recorded where they happen, since the session
+/// loop only sees them once converted to the handler's error type.
+#[derive(Debug, Default)]
+pub(crate) struct ErrorDisconnect(Option<(Disconnect, String)>);
+
+impl ErrorDisconnect {
+    /// Records the reason matching `e`, see [`Error::disconnect_reason`],
+    /// unless an earlier error was already recorded. Returns `e`.
+    pub fn record(&mut self, e: Error) -> Error {
+        if self.0.is_none() {
+            if let Some(reason) = e.disconnect_reason() {
+                self.0 = Some((reason, e.to_string()));
+            }
+        }
+        e
+    }
+
+    pub fn check<T>(&mut self, r: Result<T, Error>) -> Result<T, Error> {
+        r.map_err(|e| self.record(e))
+    }
+
+    /// The description of the recorded error.
+    pub fn description(&self) -> Option<&str> {
+        self.0.as_ref().map(|(_, description)| description.as_str())
+    }
+}
+
+impl<C> CommonSession<C> {
+    /// Wakes the writers waiting for a flush, once the write buffer
+    /// has been written.
+    pub fn flushed(&mut self) {
+        for waiter in self.flush_waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum ChannelFlushResult {
-    Incomplete {
-        wrote: usize,
-    },
-    Complete {
-        wrote: usize,
-        pending_eof: bool,
-        pending_close: bool,
-    },
+    Incomplete { wrote: usize },
+    Complete { wrote: usize, pending_close: bool },
 }
 impl ChannelFlushResult {
     pub(crate) fn wrote(&self) -> usize {
@@ -89,11 +155,12 @@ impl ChannelFlushResult {
             ChannelFlushResult::Complete { wrote, .. } => *wrote,
         }
     }
-    pub(crate) fn complete(wrote: usize, channel: &ChannelParams) -> Self {
+    /// Takes the close waiting for the pending data of `channel`,
+    /// which is sent (or delayed again) by the caller.
+    pub(crate) fn complete(wrote: usize, channel: &mut ChannelParams) -> Self {
         ChannelFlushResult::Complete {
             wrote,
-            pending_eof: channel.pending_eof,
-            pending_close: channel.pending_close,
+            pending_close: std::mem::take(&mut channel.pending_close),
         }
     }
 }
@@ -125,17 +192,50 @@ impl<C> CommonSession<C> {
             last_channel_id: Wrapping(1),
             write: CryptoVec::new(),
             write_cursor: 0,
-            last_rekey: std::time::Instant::now(),
+            last_rekey: crate::runtime::now(),
             server_compression: newkeys.names.server_compression,
             client_compression: newkeys.names.client_compression,
             compress: crate::compression::Compress::None,
             compress_buffer: CryptoVec::new(),
             decompress: crate::compression::Decompress::None,
+            global_request_offsets: VecDeque::new(),
+            global_request_seqns: VecDeque::new(),
+            close_waiters: HashMap::new(),
+            per_channel_rate: None,
+            flush_cursor: ChannelId(0),
         });
         self.cipher = newkeys.cipher;
         self.strict_kex = newkeys.names.strict_kex;
     }
 
+    /// Number of bytes waiting to be sent to the peer, including channel
+    /// data held back because the peer's window is exhausted.
+    pub fn pending_write_len(&self) -> usize {
+        let mut len = self.write_buffer.buffer.len();
+        if let Some(ref enc) = self.encrypted {
+            len += enc.write.len();
+            for channel in enc.channels.values() {
+                for (buf, _, from) in channel.pending_data.iter() {
+                    len += buf.len().saturating_sub(*from);
+                }
+                for message in channel.pending_messages.iter() {
+                    if let PendingMessage::Data(buf, _) = message {
+                        len += buf.len();
+                    }
+                }
+            }
+        }
+        len
+    }
+
+    /// Whether channel data held back at the last flush can be sent
+    /// now, so that the session loop turns again to flush it.
+    pub fn has_flushable_data(&self) -> bool {
+        self.encrypted
+            .as_ref()
+            .map_or(false, Encrypted::has_flushable_data)
+    }
+
     /// Send a disconnect message.
     pub fn disconnect(&mut self, reason: Disconnect, description: &str, language_tag: &str) {
         let disconnect = |buf: &mut CryptoVec| {
@@ -151,15 +251,59 @@ impl<C> CommonSession<C> {
             if let Some(ref mut enc) = self.encrypted {
                 disconnect(&mut enc.write)
             } else {
-                disconnect(&mut self.write_buffer.buffer)
+                let mut packet = CryptoVec::new();
+                disconnect(&mut packet);
+                #[allow(clippy::indexing_slicing)] // length checked
+                self.cipher
+                    .local_to_remote
+                    .write(&packet[4..], &mut self.write_buffer)
             }
         }
     }
 
-    /// Send a single byte message onto the channel.
-    pub fn byte(&mut self, channel: ChannelId, msg: u8) {
+    /// Queues `payload` as a single packet, to be sent after the
+    /// packets already queued.
+    #[cfg(feature = "danger-raw-packets")]
+    pub(crate) fn send_raw_packet(&mut self, payload: &[u8]) {
+        if let Some(ref mut enc) = self.encrypted {
+            push_packet!(enc.write, enc.write.extend(payload))
+        }
+    }
+
+    /// Answers a PING, from OpenSSH's `ping@openssh.com` extension,
+    /// with a PONG carrying the same data.
+    pub(crate) fn pong(&mut self, buf: &[u8]) -> Result<(), Error> {
+        let data = buf.reader(1).read_string()?;
+        if let Some(ref mut enc) = self.encrypted {
+            push_packet!(enc.write, {
+                enc.write.push(msg::PONG);
+                enc.write.extend_ssh_string(data);
+            })
+        }
+        Ok(())
+    }
+
+    /// Answers the packet number `seqn`, of a type we don't know, with
+    /// UNIMPLEMENTED (RFC 4253, section 11.4).
+    pub(crate) fn unimplemented(&mut self, seqn: u32) {
         if let Some(ref mut enc) = self.encrypted {
-            enc.byte(channel, msg)
+            push_packet!(enc.write, {
+                enc.write.push(msg::UNIMPLEMENTED);
+                enc.write.push_u32_be(seqn);
+            })
+        }
+    }
+
+    /// Queues the DISCONNECT recorded by [`ErrorDisconnect`], if any.
+    /// Returns whether there was one.
+    pub(crate) fn disconnect_after_error(&mut self) -> bool {
+        match self.error_disconnect.0.take() {
+            Some((reason, description)) if !self.disconnected => {
+                debug!("disconnecting: {:?} ({})", reason, description);
+                self.disconnect(reason, &description, "en");
+                true
+            }
+            _ => false,
         }
     }
 
@@ -172,14 +316,117 @@ impl<C> CommonSession<C> {
 
 impl Encrypted {
     pub fn byte(&mut self, channel: ChannelId, msg: u8) {
-        if let Some(channel) = self.channels.get(&channel) {
+        self.channel_msg(channel, msg, |_| {});
+    }
+
+    /// Writes a message about `channel`: `msg`, the recipient channel
+    /// number, then whatever `payload` writes. The recipient number is
+    /// only known once the channel is confirmed, so until then the
+    /// message is queued with the data written before and after it,
+    /// to be sent in order by [`Self::confirm_channel`]. It is queued
+    /// the same way behind data held back by the window or by a key
+    /// exchange, so that it doesn't overtake that data.
+    ///
+    /// Returns `false` if the channel doesn't exist.
+    pub fn channel_msg<F: FnOnce(&mut CryptoVec)>(
+        &mut self,
+        channel: ChannelId,
+        msg: u8,
+        payload: F,
+    ) -> bool {
+        let rekeying = self.rekey.is_some();
+        let Some(channel) = self.channels.get_mut(&channel) else {
+            return false;
+        };
+        if channel.confirmed
+            && channel.pending_messages.is_empty()
+            && channel.pending_data.is_empty()
+            && !rekeying
+        {
             push_packet!(self.write, {
                 self.write.push(msg);
                 self.write.push_u32_be(channel.recipient_channel);
+                payload(&mut self.write);
+            });
+        } else {
+            let mut buf = CryptoVec::new();
+            payload(&mut buf);
+            channel
+                .pending_messages
+                .push_back(PendingMessage::Message(msg, buf));
+        }
+        true
+    }
+
+    /// Writes a CHANNEL_REQUEST of type `name`, with `payload` after
+    /// the `want reply` field, and counts the reply it asks for.
+    pub fn channel_request<F: FnOnce(&mut CryptoVec)>(
+        &mut self,
+        channel: ChannelId,
+        name: &[u8],
+        want_reply: bool,
+        payload: F,
+    ) -> bool {
+        let sent = self.channel_msg(channel, msg::CHANNEL_REQUEST, |w| {
+            w.extend_ssh_string(name);
+            w.push(want_reply as u8);
+            payload(w);
+        });
+        if want_reply {
+            if let Some(channel) = self.channels.get_mut(&channel) {
+                channel.pending_replies += 1;
+            }
+        }
+        sent
+    }
+
+    /// Tells the peer that we won't read more data from `channel`, in
+    /// an `eow@openssh.com` request.
+    pub fn end_of_write(&mut self, channel: ChannelId) {
+        self.channel_request(channel, b"eow@openssh.com", false, |_| {});
+    }
+
+    /// Labels `channel`, and if `announce` is set, tells the peer in a
+    /// `label@russh.rs` request. Implementations that don't know this
+    /// request ignore it, since it doesn't ask for a reply.
+    pub fn label_channel(&mut self, channel: ChannelId, label: &str, announce: bool) {
+        if let Some(c) = self.channels.get_mut(&channel) {
+            c.label = Some(label.to_owned());
+        }
+        if announce {
+            self.channel_request(channel, b"label@russh.rs", false, |w| {
+                w.extend_ssh_string(label.as_bytes());
             });
         }
     }
 
+    /// Records a CHANNEL_SUCCESS or CHANNEL_FAILURE on `channel`, and
+    /// returns `false` if no request was waiting for it.
+    pub fn channel_reply(&mut self, channel: ChannelId) -> bool {
+        match self.channels.get_mut(&channel) {
+            Some(c) if c.pending_replies > 0 => {
+                c.pending_replies -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the peer's confirmation of a channel we opened, then
+    /// sends what was written to the channel in the meantime, in the
+    /// order it was written, as far as the window allows.
+    ///
+    /// Returns `false` if we didn't open this channel.
+    pub fn confirm_channel(&mut self, confirmation: &ChannelOpenConfirmation) -> bool {
+        let id = ChannelId(confirmation.recipient_channel);
+        let Some(channel) = self.channels.get_mut(&id) else {
+            return false;
+        };
+        channel.confirm(confirmation);
+        self.flush_pending(id);
+        true
+    }
+
     /*
     pub fn authenticated(&mut self) {
         self.server_compression.init_compress(&mut self.compress);
@@ -188,15 +435,14 @@ impl Encrypted {
     */
 
     pub fn eof(&mut self, channel: ChannelId) {
-        if let Some(channel) = self.has_pending_data_mut(channel) {
-            channel.pending_eof = true;
-        } else {
-            self.byte(channel, msg::CHANNEL_EOF);
-        }
+        self.byte(channel, msg::CHANNEL_EOF);
     }
 
     pub fn close(&mut self, channel: ChannelId) {
-        if let Some(channel) = self.has_pending_data_mut(channel) {
+        let rekeying = self.rekey.is_some();
+        if let Some(channel) = self.channels.get_mut(&channel).filter(|c| {
+            !c.confirmed || !c.pending_data.is_empty() || !c.pending_messages.is_empty() || rekeying
+        }) {
             channel.pending_close = true;
         } else {
             self.byte(channel, msg::CHANNEL_CLOSE);
@@ -204,6 +450,49 @@ impl Encrypted {
         }
     }
 
+    /// Sends EOF and CLOSE on `channel` right away, dropping the data
+    /// still waiting for window space. `reply_channel` gets `true` once
+    /// the peer closes the channel too, or `false` right away if the
+    /// channel is not open.
+    pub fn force_close(&mut self, id: ChannelId, reply_channel: oneshot::Sender<bool>) {
+        let Some(channel) = self.channels.get_mut(&id) else {
+            let _ = reply_channel.send(false);
+            return;
+        };
+        channel.pending_data.clear();
+        channel
+            .pending_messages
+            .retain(|m| matches!(m, PendingMessage::Message(..)));
+        self.eof(id);
+        self.close(id);
+        self.close_waiters.insert(id, reply_channel);
+    }
+
+    /// Handles the peer's CHANNEL_CLOSE. The data still waiting for
+    /// window space is dropped, since the peer won't read it, and our
+    /// own CLOSE is sent unless it was sent already.
+    pub fn peer_closed(&mut self, channel: ChannelId) {
+        if let Some(waiter) = self.close_waiters.remove(&channel) {
+            let _ = waiter.send(true);
+        }
+        let Some(channel) = self.channels.remove(&channel) else {
+            return;
+        };
+        if !channel.pending_data.is_empty() {
+            debug!(
+                "dropping {} pending data buffers of closed channel {:?}",
+                channel.pending_data.len(),
+                channel.sender_channel
+            );
+        }
+        if channel.confirmed {
+            push_packet!(self.write, {
+                self.write.push(msg::CHANNEL_CLOSE);
+                self.write.push_u32_be(channel.recipient_channel);
+            });
+        }
+    }
+
     pub fn sender_window_size(&self, channel: ChannelId) -> usize {
         if let Some(channel) = self.channels.get(&channel) {
             channel.sender_window_size as usize
@@ -221,6 +510,7 @@ impl Encrypted {
             );
             // Ignore extra data.
             // https://tools.ietf.org/html/rfc4254#section-5.2
+            channel.bytes_received += data.len() as u64;
             if data.len() as u32 <= channel.sender_window_size {
                 channel.sender_window_size -= data.len() as u32;
             }
@@ -241,17 +531,39 @@ impl Encrypted {
         false
     }
 
-    fn flush_channel(write: &mut CryptoVec, channel: &mut ChannelParams) -> ChannelFlushResult {
+    fn flush_channel(
+        write: &mut CryptoVec,
+        channel: &mut ChannelParams,
+        max: usize,
+    ) -> ChannelFlushResult {
         let mut pending_size = 0;
-        while let Some((buf, a, from)) = channel.pending_data.pop_front() {
-            let size = Self::data_noqueue(write, channel, &buf, a, from);
-            pending_size += size;
-            if from + size < buf.len() {
-                channel.pending_data.push_front((buf, a, from + size));
+        loop {
+            while let Some((buf, a, from)) = channel.pending_data.pop_front() {
+                let size = Self::data_noqueue(write, channel, &buf, a, from, max - pending_size);
+                pending_size += size;
+                if from + size < buf.len() {
+                    channel.pending_data.push_front((buf, a, from + size));
+                    return ChannelFlushResult::Incomplete {
+                        wrote: pending_size,
+                    };
+                }
+            }
+            // What was written after the data follows it, up to the
+            // next data held back by the window.
+            if !channel.confirmed && !channel.pending_messages.is_empty() {
                 return ChannelFlushResult::Incomplete {
                     wrote: pending_size,
                 };
             }
+            match channel.pending_messages.pop_front() {
+                Some(PendingMessage::Data(buf, a)) => channel.pending_data.push_back((buf, a, 0)),
+                Some(PendingMessage::Message(msg, payload)) => push_packet!(write, {
+                    write.push(msg);
+                    write.push_u32_be(channel.recipient_channel);
+                    write.extend(&payload);
+                }),
+                None => break,
+            }
         }
         ChannelFlushResult::complete(pending_size, channel)
     }
@@ -259,13 +571,9 @@ impl Encrypted {
     fn handle_flushed_channel(&mut self, channel: ChannelId, flush_result: ChannelFlushResult) {
         if let ChannelFlushResult::Complete {
             wrote: _,
-            pending_eof,
             pending_close,
         } = flush_result
         {
-            if pending_eof {
-                self.eof(channel);
-            }
             if pending_close {
                 self.close(channel);
             }
@@ -277,7 +585,7 @@ impl Encrypted {
         let mut maybe_flush_result = Option::<ChannelFlushResult>::None;
 
         if let Some(channel) = self.channels.get_mut(&channel) {
-            let flush_result = Self::flush_channel(&mut self.write, channel);
+            let flush_result = Self::flush_channel(&mut self.write, channel, CHANNEL_FLUSH_QUANTUM);
             pending_size += flush_result.wrote();
             maybe_flush_result = Some(flush_result);
         }
@@ -287,16 +595,49 @@ impl Encrypted {
         pending_size
     }
 
+    /// Flushes the pending data of all channels, at most
+    /// [`CHANNEL_FLUSH_QUANTUM`] bytes from each. The channels take
+    /// turns at being flushed first, and a channel waiting for its
+    /// window or its rate limit doesn't hold the others back.
     pub fn flush_all_pending(&mut self) {
-        for channel in self.channels.values_mut() {
-            Self::flush_channel(&mut self.write, channel);
+        let mut channels: Vec<ChannelId> = self
+            .channels
+            .iter()
+            .filter(|(_, c)| {
+                c.confirmed
+                    && (!c.pending_data.is_empty()
+                        || !c.pending_messages.is_empty()
+                        || c.pending_close)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        if channels.is_empty() {
+            return;
+        }
+        channels.sort_unstable();
+        let start = channels.partition_point(|c| *c <= self.flush_cursor);
+        channels.rotate_left(start);
+        #[allow(clippy::indexing_slicing)] // non-empty
+        {
+            self.flush_cursor = channels[0];
+        }
+        for channel in channels {
+            self.flush_pending(channel);
         }
     }
 
-    fn has_pending_data_mut(&mut self, channel: ChannelId) -> Option<&mut ChannelParams> {
-        self.channels
-            .get_mut(&channel)
-            .filter(|c| !c.pending_data.is_empty())
+    /// Whether some pending data can be sent right away.
+    pub fn has_flushable_data(&self) -> bool {
+        self.rekey.is_none()
+            && self.channels.values().any(|channel| {
+                channel.confirmed
+                    && channel
+                        .pending_data
+                        .front()
+                        .map_or(false, |(buf, _, from)| {
+                            Self::sendable(channel, buf.len().saturating_sub(*from)) > 0
+                        })
+            })
     }
 
     pub fn has_pending_data(&self, channel: ChannelId) -> bool {
@@ -307,27 +648,104 @@ impl Encrypted {
         }
     }
 
-    /// Push the largest amount of `&buf0[from..]` that can fit into
-    /// the window, dividing it into packets if it is too large, and
-    /// return the length that was written.
+    /// Time until some of the data held back by the rate limits of the
+    /// channels can be sent, if any is.
+    pub fn data_rate_delay(&mut self) -> Option<std::time::Duration> {
+        if self.rekey.is_some() {
+            return None;
+        }
+        let mut delay: Option<std::time::Duration> = None;
+        for channel in self.channels.values_mut() {
+            if !channel.confirmed {
+                continue;
+            }
+            let Some(ref mut rate) = channel.data_rate else {
+                continue;
+            };
+            let Some((buf, _, from)) = channel.pending_data.front() else {
+                continue;
+            };
+            let len = buf
+                .len()
+                .saturating_sub(*from)
+                .min(channel.recipient_window_size as usize)
+                .min(channel.recipient_maximum_packet_size as usize);
+            if len == 0 {
+                // Waiting for the window, not for the limit.
+                continue;
+            }
+            let d = rate.delay_for(len);
+            delay = Some(delay.map_or(d, |delay| delay.min(d)));
+        }
+        delay
+    }
+
+    pub fn channels_info(&self) -> Vec<crate::ChannelInfo> {
+        let mut info: Vec<_> = self
+            .channels
+            .values()
+            .map(|c| crate::ChannelInfo {
+                id: c.sender_channel,
+                kind: c.kind.clone(),
+                created_at: c.created_at,
+                bytes_sent: c.bytes_sent,
+                bytes_received: c.bytes_received,
+                initial_window_size: c.initial_window_size,
+                window_size: c.recipient_window_size,
+                max_packet_size: c.recipient_maximum_packet_size,
+                has_pending_data: !c.pending_data.is_empty(),
+                label: c.label.clone(),
+                rate: c.data_rate.as_ref().map(TokenBucket::stats),
+            })
+            .collect();
+        info.sort_by_key(|c| c.id);
+        info
+    }
+
+    /// How many of `len` bytes of data fit into the window and the
+    /// rate limit of `channel`.
+    fn sendable(channel: &ChannelParams, len: usize) -> usize {
+        let len = len.min(channel.recipient_window_size as usize);
+        let Some(ref rate) = channel.data_rate else {
+            return len;
+        };
+        // Data over the rate limit waits like data over the window,
+        // until the session loop flushes it again. Small leftovers of
+        // the limit are not used, to avoid sending tiny packets.
+        let packet = len.min(channel.recipient_maximum_packet_size as usize);
+        let available = rate.available();
+        if available < packet.min(rate.burst()) {
+            return 0;
+        }
+        len.min(available)
+    }
+
+    /// Push the largest amount of `&buf0[from..]`, up to `max` bytes,
+    /// that can fit into the window and the rate limit, dividing it
+    /// into packets if it is too large, and return the length that was
+    /// written.
     fn data_noqueue(
         write: &mut CryptoVec,
         channel: &mut ChannelParams,
         buf0: &[u8],
         a: Option<u32>,
         from: usize,
+        max: usize,
     ) -> usize {
         if from >= buf0.len() {
             return 0;
         }
-        let mut buf = if buf0.len() as u32 > from as u32 + channel.recipient_window_size {
-            #[allow(clippy::indexing_slicing)] // length checked
-            &buf0[from..from + channel.recipient_window_size as usize]
-        } else {
-            #[allow(clippy::indexing_slicing)] // length checked
-            &buf0[from..]
-        };
+        let len = Self::sendable(channel, (buf0.len() - from).min(max));
+        if len == 0 {
+            return 0;
+        }
+        if let Some(ref mut rate) = channel.data_rate {
+            rate.consume(len);
+        }
+        #[allow(clippy::indexing_slicing)] // length checked
+        let mut buf = &buf0[from..from + len];
         let buf_len = buf.len();
+        channel.bytes_sent += buf_len as u64;
 
         while !buf.is_empty() {
             // Compute the length we're allowed to send.
@@ -364,12 +782,24 @@ impl Encrypted {
 
     pub fn data(&mut self, channel: ChannelId, buf0: CryptoVec) {
         if let Some(channel) = self.channels.get_mut(&channel) {
-            assert!(channel.confirmed);
-            if !channel.pending_data.is_empty() || self.rekey.is_some() {
+            if !channel.pending_messages.is_empty() {
+                channel
+                    .pending_messages
+                    .push_back(PendingMessage::Data(buf0, None));
+                return;
+            }
+            if !channel.confirmed || !channel.pending_data.is_empty() || self.rekey.is_some() {
                 channel.pending_data.push_back((buf0, None, 0));
                 return;
             }
-            let buf_len = Self::data_noqueue(&mut self.write, channel, &buf0, None, 0);
+            let buf_len = Self::data_noqueue(
+                &mut self.write,
+                channel,
+                &buf0,
+                None,
+                0,
+                CHANNEL_FLUSH_QUANTUM,
+            );
             if buf_len < buf0.len() {
                 channel.pending_data.push_back((buf0, None, buf_len))
             }
@@ -380,12 +810,24 @@ impl Encrypted {
 
     pub fn extended_data(&mut self, channel: ChannelId, ext: u32, buf0: CryptoVec) {
         if let Some(channel) = self.channels.get_mut(&channel) {
-            assert!(channel.confirmed);
-            if !channel.pending_data.is_empty() {
+            if !channel.pending_messages.is_empty() {
+                channel
+                    .pending_messages
+                    .push_back(PendingMessage::Data(buf0, Some(ext)));
+                return;
+            }
+            if !channel.confirmed || !channel.pending_data.is_empty() || self.rekey.is_some() {
                 channel.pending_data.push_back((buf0, Some(ext), 0));
                 return;
             }
-            let buf_len = Self::data_noqueue(&mut self.write, channel, &buf0, Some(ext), 0);
+            let buf_len = Self::data_noqueue(
+                &mut self.write,
+                channel,
+                &buf0,
+                Some(ext),
+                0,
+                CHANNEL_FLUSH_QUANTUM,
+            );
             if buf_len < buf0.len() {
                 channel.pending_data.push_back((buf0, Some(ext), buf_len))
             }
@@ -399,7 +841,10 @@ impl Encrypted {
         write_buffer: &mut SSHBuffer,
     ) -> Result<bool, crate::Error> {
         // If there are pending packets (and we've not started to rekey), flush them.
-        {
+        // During a key exchange, only key exchange messages may be sent
+        // (RFC 4253, section 7.1), so the rest waits until the new keys are in use.
+        if self.rekey.is_none() {
+            self.flush_all_pending();
             while self.write_cursor < self.write.len() {
                 // Read a single packet, encrypt and send it.
                 #[allow(clippy::indexing_slicing)] // length checked
@@ -407,6 +852,10 @@ impl Encrypted {
                 #[allow(clippy::indexing_slicing)]
                 let to_write = &self.write[(self.write_cursor + 4)..(self.write_cursor + 4 + len)];
                 trace!("server_write_encrypted, buf = {:?}", to_write);
+                if self.global_request_offsets.front() == Some(&self.write_cursor) {
+                    self.global_request_offsets.pop_front();
+                    self.global_request_seqns.push_back(write_buffer.seqn.0);
+                }
                 #[allow(clippy::indexing_slicing)]
                 let packet = self
                     .compress
@@ -425,10 +874,15 @@ impl Encrypted {
             return Ok(false);
         }
 
-        let now = std::time::Instant::now();
-        let dur = now.duration_since(self.last_rekey);
+        let dur = crate::runtime::now().saturating_duration_since(self.last_rekey);
         Ok(write_buffer.bytes >= limits.rekey_write_limit || dur >= limits.rekey_time_limit)
     }
+    /// Records that the next packet written is a global request
+    /// waiting for a reply.
+    pub fn global_request_follows(&mut self) {
+        self.global_request_offsets.push_back(self.write.len());
+    }
+
     pub fn new_channel_id(&mut self) -> ChannelId {
         self.last_channel_id += Wrapping(1);
         while self
@@ -439,7 +893,7 @@ impl Encrypted {
         }
         ChannelId(self.last_channel_id.0)
     }
-    pub fn new_channel(&mut self, window_size: u32, maxpacket: u32) -> ChannelId {
+    pub fn new_channel(&mut self, kind: &[u8], window_size: u32, maxpacket: u32) -> ChannelId {
         loop {
             self.last_channel_id += Wrapping(1);
             if let std::collections::hash_map::Entry::Vacant(vacant_entry) =
@@ -453,10 +907,18 @@ impl Encrypted {
                     sender_maximum_packet_size: maxpacket,
                     recipient_maximum_packet_size: 0,
                     confirmed: false,
+                    kind: String::from_utf8_lossy(kind).into_owned(),
+                    created_at: crate::runtime::now(),
+                    initial_window_size: 0,
+                    bytes_sent: 0,
+                    bytes_received: 0,
                     wants_reply: false,
+                    pending_replies: 0,
                     pending_data: std::collections::VecDeque::new(),
-                    pending_eof: false,
+                    pending_messages: std::collections::VecDeque::new(),
                     pending_close: false,
+                    label: None,
+                    data_rate: self.per_channel_rate.map(TokenBucket::new),
                 });
                 return ChannelId(self.last_channel_id.0);
             }
@@ -518,6 +980,9 @@ pub(crate) struct KexInit {
     pub exchange: Exchange,
     pub session_id: Option<CryptoVec>,
     pub sent: bool,
+    /// The kex algorithm of the first kex packet sent right after
+    /// KEXINIT, guessing the outcome of the negotiation.
+    pub guess: Option<(crate::kex::Name, Box<dyn KexAlgorithm + Send>)>,
 }
 
 impl KexInit {
@@ -527,6 +992,7 @@ impl KexInit {
             algo: Some(algo),
             sent: false,
             session_id: Some(session_id.clone()),
+            guess: None,
         };
         kexinit.exchange.client_kex_init.clear();
         kexinit.exchange.server_kex_init.clear();
@@ -541,6 +1007,7 @@ impl KexInit {
             algo: None,
             sent: true,
             session_id: Some(session_id.clone()),
+            guess: None,
         };
         kexinit.exchange.client_kex_init.clear();
         kexinit.exchange.server_kex_init.clear();
@@ -622,8 +1089,181 @@ pub(crate) struct NewKeys {
 pub(crate) enum GlobalRequestResponse {
     /// request was for Keepalive, ignore result
     Keepalive,
+    /// request was for a Keepalive sent with [`crate::client::Handle::keepalive`],
+    /// notifies the sender when any reply arrives
+    KeepaliveReply(oneshot::Sender<()>),
     /// request was for TcpIpForward, sends Some(port) for success or None for failure
     TcpIpForward(oneshot::Sender<Option<u32>>),
+    /// request was for TcpIpForward towards a local address: on success,
+    /// the connections to the forwarded port are piped to `target`
+    LocalTcpIpForward {
+        reply_channel: Option<oneshot::Sender<Option<u32>>>,
+        address: String,
+        port: u32,
+        target: String,
+    },
     /// request was for CancelTcpIpForward, sends true for success or false for failure
     CancelTcpIpForward(oneshot::Sender<bool>),
+    /// request was sent with `send_global_request`, sends the reply's
+    /// data for success or None for failure
+    Raw(oneshot::Sender<Option<Vec<u8>>>),
+}
+
+impl GlobalRequestResponse {
+    /// Answers the waiting operation, if any, after the peer refused
+    /// the request or didn't understand it.
+    pub fn fail(self) {
+        match self {
+            GlobalRequestResponse::Keepalive => {
+                // ignore keepalives
+            }
+            GlobalRequestResponse::KeepaliveReply(return_channel) => {
+                let _ = return_channel.send(());
+            }
+            GlobalRequestResponse::TcpIpForward(return_channel) => {
+                let _ = return_channel.send(None);
+            }
+            GlobalRequestResponse::LocalTcpIpForward { reply_channel, .. } => {
+                if let Some(reply_channel) = reply_channel {
+                    let _ = reply_channel.send(None);
+                }
+            }
+            GlobalRequestResponse::CancelTcpIpForward(return_channel) => {
+                let _ = return_channel.send(false);
+            }
+            GlobalRequestResponse::Raw(return_channel) => {
+                let _ = return_channel.send(None);
+            }
+        }
+    }
+}
+
+/// How long the `force_close` methods of the handles wait for the
+/// peer to close the channel.
+pub(crate) const FORCE_CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The global requests waiting for the peer's reply, in the order
+/// they were sent, since replies come in that order (RFC 4254,
+/// section 4). Past the configured maximum, new requests wait here
+/// until earlier ones are answered.
+#[derive(Default)]
+pub(crate) struct GlobalRequests {
+    open: VecDeque<GlobalRequestResponse>,
+    /// Requests not sent yet, with their packet. Requests that don't
+    /// want a reply wait here too when they follow a deferred one, to
+    /// keep the order of the requests.
+    deferred: VecDeque<(CryptoVec, Option<GlobalRequestResponse>)>,
+}
+
+impl GlobalRequests {
+    /// Sends the global request `packet`, then waits for the reply
+    /// with `response` if it is given, unless `max` replies are
+    /// already awaited, in which case the request is sent later.
+    pub fn send(
+        &mut self,
+        enc: &mut Encrypted,
+        max: usize,
+        packet: CryptoVec,
+        response: Option<GlobalRequestResponse>,
+    ) {
+        if self.deferred.is_empty() && (response.is_none() || self.open.len() < max) {
+            self.write(enc, &packet, response)
+        } else {
+            debug!(
+                "{} global requests awaiting replies, deferring",
+                self.open.len()
+            );
+            self.deferred.push_back((packet, response))
+        }
+    }
+
+    fn write(
+        &mut self,
+        enc: &mut Encrypted,
+        packet: &[u8],
+        response: Option<GlobalRequestResponse>,
+    ) {
+        if let Some(response) = response {
+            self.open.push_back(response);
+            enc.global_request_follows();
+        }
+        push_packet!(enc.write, enc.write.extend(packet));
+    }
+
+    /// Sends the deferred requests that now fit.
+    fn send_deferred(&mut self, encrypted: &mut Option<Encrypted>, max: usize) {
+        let Some(ref mut enc) = encrypted else {
+            return;
+        };
+        while let Some((_, response)) = self.deferred.front() {
+            if response.is_some() && self.open.len() >= max {
+                break;
+            }
+            if let Some((packet, response)) = self.deferred.pop_front() {
+                self.write(enc, &packet, response)
+            }
+        }
+    }
+
+    /// Takes the oldest global request waiting for a reply, once the
+    /// peer has answered it.
+    pub fn pop(
+        &mut self,
+        encrypted: &mut Option<Encrypted>,
+        max: usize,
+    ) -> Option<GlobalRequestResponse> {
+        if let Some(ref mut enc) = encrypted {
+            enc.global_request_seqns.pop_front();
+        }
+        let response = self.open.pop_front();
+        self.send_deferred(encrypted, max);
+        response
+    }
+
+    /// Handles an `SSH_MSG_UNIMPLEMENTED` for the packet number `seqn`:
+    /// if it was a global request waiting for a reply, that request fails.
+    pub fn peer_unimplemented(&mut self, encrypted: &mut Option<Encrypted>, max: usize, seqn: u32) {
+        let position = encrypted.as_mut().and_then(|enc| {
+            let i = enc.global_request_seqns.iter().position(|&s| s == seqn)?;
+            enc.global_request_seqns.remove(i);
+            Some(i)
+        });
+        match position.and_then(|i| self.open.remove(i)) {
+            Some(request) => {
+                warn!("peer does not implement global request #{}", seqn);
+                request.fail();
+                self.send_deferred(encrypted, max);
+            }
+            None => warn!("peer does not implement packet #{}", seqn),
+        }
+    }
+}
+
+/// The port in a successful reply to a `tcpip-forward` request, or 0
+/// if a specific port was requested.
+pub(crate) fn forwarded_port(buf: &[u8]) -> Option<u32> {
+    if buf.len() == 1 {
+        // If a specific port was requested, the reply has no data
+        Some(0)
+    } else {
+        let mut r = buf.reader(1);
+        match r.read_u32() {
+            Ok(port) => Some(port),
+            Err(e) => {
+                error!("Error parsing port for TcpIpForward request: {e:?}");
+                None
+            }
+        }
+    }
+}
+
+/// Awaits `f`, giving up after `timeout` if there is one.
+pub(crate) async fn with_timeout<F: std::future::Future>(
+    timeout: Option<std::time::Duration>,
+    f: F,
+) -> Result<F::Output, crate::runtime::Elapsed> {
+    match timeout {
+        Some(timeout) => crate::runtime::timeout(timeout, f).await,
+        None => Ok(f.await),
+    }
 }
@@ -21,6 +21,27 @@ impl ReadSshIdBuffer {
         &self.buf[..self.sshid_len]
     }
 
+    /// The length of the first line in the buffer, without its line
+    /// ending, and the position of the next line, if it is complete.
+    fn line(&self) -> Option<(usize, usize)> {
+        let data = self.buf.get(..self.total)?;
+        let end = data.iter().position(|&b| b == b'\n')?;
+        if end > 0 && data.get(end - 1) == Some(&b'\r') {
+            Some((end - 1, end + 1))
+        } else {
+            // This is really wrong, but OpenSSH 7.4 uses it.
+            Some((end, end + 1))
+        }
+    }
+
+    /// Drops the line read last, keeping the bytes after it.
+    fn consume_line(&mut self) {
+        self.buf.copy_within(self.bytes_read..self.total, 0);
+        self.total -= self.bytes_read;
+        self.bytes_read = 0;
+        self.sshid_len = 0;
+    }
+
     pub fn new() -> ReadSshIdBuffer {
         let mut buf = CryptoVec::new();
         buf.resize(256);
@@ -116,8 +137,27 @@ impl<R: AsyncRead + Unpin> SshRead<R> {
     #[allow(clippy::unwrap_used)]
     pub async fn read_ssh_id(&mut self) -> Result<&[u8], Error> {
         let ssh_id = self.id.as_mut().unwrap();
+        // Drop the identification string read before, if any.
+        ssh_id.consume_line();
         loop {
-            let mut i = 0;
+            if let Some((len, end)) = ssh_id.line() {
+                ssh_id.bytes_read = end;
+                if ssh_id
+                    .buf
+                    .get(..len)
+                    .map_or(false, |l| l.starts_with(b"SSH-2.0-"))
+                {
+                    // Either the line starts with "SSH-2.0-"
+                    ssh_id.sshid_len = len;
+                    #[allow(clippy::indexing_slicing)] // length checked
+                    return Ok(&ssh_id.buf[..ssh_id.sshid_len]);
+                }
+                // Else, it is a "preliminary" (see
+                // https://tools.ietf.org/html/rfc4253#section-4.2),
+                // and we can discard it and read the next one.
+                ssh_id.consume_line();
+                continue;
+            }
             debug!("read_ssh_id: reading");
 
             #[allow(clippy::indexing_slicing)] // length checked
@@ -132,40 +172,33 @@ impl<R: AsyncRead + Unpin> SshRead<R> {
             if n == 0 {
                 return Err(Error::Disconnect);
             }
+        }
+    }
+
+    /// Whether the peer sent another identification string after the
+    /// one read last, rather than its first packet, which some
+    /// middleboxes do. This reads from the peer until it can tell, and
+    /// drops the identification string read last.
+    #[allow(clippy::unwrap_used)]
+    pub async fn next_is_ssh_id(&mut self) -> Result<bool, Error> {
+        let ssh_id = self.id.as_mut().unwrap();
+        ssh_id.consume_line();
+        loop {
             #[allow(clippy::indexing_slicing)] // length checked
-            loop {
-                if i >= ssh_id.total - 1 {
-                    break;
-                }
-                if ssh_id.buf[i] == b'\r' && ssh_id.buf[i + 1] == b'\n' {
-                    ssh_id.bytes_read = i + 2;
-                    break;
-                } else if ssh_id.buf[i + 1] == b'\n' {
-                    // This is really wrong, but OpenSSH 7.4 uses
-                    // it.
-                    ssh_id.bytes_read = i + 2;
-                    i += 1;
-                    break;
-                } else {
-                    i += 1;
-                }
+            let next = &ssh_id.buf[..ssh_id.total];
+            let n = next.len().min(4);
+            if next.get(..n) != b"SSH-".get(..n) {
+                return Ok(false);
             }
-
-            if ssh_id.bytes_read > 0 {
-                // If we have a full line, handle it.
-                if i >= 8 && ssh_id.buf.get(0..8) == Some(b"SSH-2.0-") {
-                    // Either the line starts with "SSH-2.0-"
-                    ssh_id.sshid_len = i;
-                    #[allow(clippy::indexing_slicing)] // length checked
-                    return Ok(&ssh_id.buf[..ssh_id.sshid_len]);
-                }
-                // Else, it is a "preliminary" (see
-                // https://tools.ietf.org/html/rfc4253#section-4.2),
-                // and we can discard it and read the next one.
-                ssh_id.total = 0;
-                ssh_id.bytes_read = 0;
+            if n == 4 {
+                return Ok(true);
+            }
+            #[allow(clippy::indexing_slicing)] // length checked
+            let n = AsyncReadExt::read(&mut self.r, &mut ssh_id.buf[ssh_id.total..]).await?;
+            if n == 0 {
+                return Err(Error::Disconnect);
             }
-            debug!("bytes_read: {:?}", ssh_id.bytes_read);
+            ssh_id.total += n;
         }
     }
 }
@@ -1,256 +1,7289 @@
 #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
 
+use std::sync::Arc;
+
 use futures::Future;
 
 use super::*;
 
-mod compress {
-    use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+/// A client trusting any server key. Tests checking client callbacks
+/// declare their own `Client`.
+#[derive(Debug, Clone)]
+struct Client;
 
-    use async_trait::async_trait;
-    use log::debug;
+#[async_trait::async_trait]
+impl client::Handler for Client {
+    type Error = Error;
 
-    use super::server::{Server as _, Session};
-    use super::*;
-    use crate::server::Msg;
+    async fn check_server_key(
+        &mut self,
+        _server_public_key: &russh_keys::key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
 
-    #[tokio::test]
-    async fn compress_local_test() {
-        let _ = env_logger::try_init();
+/// A server accepting any public key, and nothing else. Tests checking
+/// server callbacks declare their own `Server`.
+#[derive(Debug, Clone)]
+struct Server;
 
-        let client_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
-        let mut config = server::Config::default();
-        config.preferred = Preferred::COMPRESSED;
-        config.inactivity_timeout = None; // Some(std::time::Duration::from_secs(3));
-        config.auth_rejection_time = std::time::Duration::from_secs(3);
+#[async_trait::async_trait]
+impl server::Handler for Server {
+    type Error = Error;
+
+    async fn auth_publickey(
+        &mut self,
+        _: &str,
+        _: &russh_keys::key::PublicKey,
+    ) -> Result<server::Auth, Self::Error> {
+        Ok(server::Auth::Accept)
+    }
+}
+
+/// Runs `server` on a loopback connection, and connects [`Client`] to
+/// it, see [`connect_with`].
+async fn connect<SH>(
+    config: server::Config,
+    server: SH,
+) -> (client::Handle<Client>, server::RunningSession<SH>)
+where
+    SH: server::Handler + Send + 'static,
+{
+    connect_with(config, server, client::Config::default(), Client).await
+}
+
+/// Runs `server` on a loopback connection, with a new host key unless
+/// `config` has one, and returns a `client` connected to it and
+/// authenticated with a new key, along with the server's session. The
+/// server's session keeps running if that is dropped.
+#[allow(clippy::async_yields_async)] // both ends of a session are futures
+async fn connect_with<SH, CH>(
+    config: server::Config,
+    server: SH,
+    client_config: client::Config,
+    client: CH,
+) -> (client::Handle<CH>, server::RunningSession<SH>)
+where
+    SH: server::Handler + Send + 'static,
+    CH: client::Handler + Send + 'static,
+{
+    let (config, socket) = listen(config).await;
+    let addr = socket.local_addr().unwrap();
+    let server = async move {
+        let (socket, _) = socket.accept().await.unwrap();
+        server::run_stream(config, socket, server)
+            .await
+            .map_err(|_| ())
+            .unwrap()
+    };
+    let client = async move {
+        let mut session = client::connect(Arc::new(client_config), addr, client)
+            .await
+            .map_err(|_| ())
+            .unwrap();
+        let key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+        assert!(session
+            .authenticate_publickey("user", Arc::new(key))
+            .await
+            .unwrap());
+        session
+    };
+    let (server, client) = tokio::join!(server, client);
+    (client, server)
+}
+
+/// Serves one connection with `server` on a loopback port, with a new
+/// host key unless `config` has one, for tests connecting on their own.
+async fn serve<SH>(config: server::Config, server: SH) -> std::net::SocketAddr
+where
+    SH: server::Handler + Send + 'static,
+{
+    let (config, socket) = listen(config).await;
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = socket.accept().await.unwrap();
+        server::run_stream(config, socket, server).await?.await
+    });
+    addr
+}
+
+/// Binds a loopback port for a server with `config`, adding a new host
+/// key unless it has one.
+async fn listen(mut config: server::Config) -> (Arc<server::Config>, tokio::net::TcpListener) {
+    let _ = env_logger::try_init();
+    if config.keys.is_empty() {
         config
             .keys
             .push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
-        let config = Arc::new(config);
-        let mut sh = Server {
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            id: 0,
-        };
+    }
+    let socket = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    (Arc::new(config), socket)
+}
 
-        let socket = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = socket.local_addr().unwrap();
+#[test]
+fn test_limits_check() {
+    use std::time::Duration;
 
-        tokio::spawn(async move {
-            let (socket, _) = socket.accept().await.unwrap();
-            let server = sh.new_client(socket.peer_addr().ok());
-            server::run_stream(config, socket, server).await.unwrap();
-        });
+    assert!(Limits::default().check().is_ok());
+    assert!(Limits::new(1 << 20, 1 << 20, Duration::from_secs(60))
+        .check()
+        .is_ok());
+    assert!(Limits::new(0, 1 << 20, Duration::from_secs(60))
+        .check()
+        .is_err());
+    assert!(Limits::new(1 << 20, 1 << 20, Duration::ZERO)
+        .check()
+        .is_err());
+    let too_large = Limits {
+        rekey_write_limit: (1 << 30) + 1,
+        ..Default::default()
+    };
+    assert!(too_large.check().is_err());
+}
 
-        let mut config = client::Config::default();
-        config.preferred = Preferred::COMPRESSED;
-        let config = Arc::new(config);
+#[test]
+fn test_sig_names() {
+    for sig in all_signals() {
+        assert_eq!(Sig::from(sig.to_string()), sig);
+    }
+    assert_eq!(Sig::from("WINCH"), Sig::Custom("WINCH".to_string()));
+    assert_eq!(Sig::from_name(b"INT"), Sig::INT);
+    // Unknown and even non-UTF-8 names are kept instead of failing.
+    assert_eq!(
+        Sig::from_name(b"\xffX"),
+        Sig::Custom("\u{fffd}X".to_string())
+    );
+}
 
-        dbg!(&addr);
-        let mut session = client::connect(config, addr, Client {}).await.unwrap();
-        let authenticated = session
-            .authenticate_publickey(
-                std::env::var("USER").unwrap_or("user".to_owned()),
-                Arc::new(client_key),
-            )
-            .await
-            .unwrap();
-        assert!(authenticated);
-        let mut channel = session.channel_open_session().await.unwrap();
+#[cfg(unix)]
+#[test]
+fn test_sig_unix() {
+    assert_eq!(Sig::from_unix(libc::SIGTERM), Some(Sig::TERM));
+    assert_eq!(Sig::TERM.to_unix(), Some(libc::SIGTERM));
+    assert_eq!(Sig::USR2.to_unix(), Some(libc::SIGUSR2));
+    assert_eq!(
+        Sig::from_unix(libc::SIGWINCH),
+        Some(Sig::Custom("WINCH".to_string()))
+    );
+    assert_eq!(Sig::from("WINCH").to_unix(), Some(libc::SIGWINCH));
+    assert_eq!(Sig::from("TERM@example.com").to_unix(), None);
+    assert_eq!(Sig::from_unix(-1), None);
+}
 
-        let data = &b"Hello, world!"[..];
-        channel.data(data).await.unwrap();
-        let msg = channel.wait().await.unwrap();
-        match msg {
-            ChannelMsg::Data { data: msg_data } => {
-                assert_eq!(*data, *msg_data)
+#[tokio::test]
+async fn test_shutdown_timeout() {
+    use std::time::Duration;
+
+    let (config, server_socket) = listen(server::Config {
+        inactivity_timeout: None,
+        shutdown_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await;
+    let server_addr = server_socket.local_addr().unwrap();
+    let proxy_socket = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy_socket.local_addr().unwrap();
+
+    // A proxy between the client and the server that, once frozen,
+    // neither reads nor writes but keeps both connections open.
+    let (freeze, frozen) = tokio::sync::oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let (mut a, _) = proxy_socket.accept().await.unwrap();
+        let mut b = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+        tokio::select! {
+            _ = tokio::io::copy_bidirectional(&mut a, &mut b) => {}
+            _ = frozen => {}
+        }
+        futures::future::pending::<()>().await;
+        drop((a, b));
+    });
+
+    let (handle_tx, handle_rx) = tokio::sync::oneshot::channel();
+    let server = tokio::spawn(async move {
+        let (socket, _) = server_socket.accept().await.unwrap();
+        let running = server::run_stream(config, socket, Server {}).await.unwrap();
+        let _ = handle_tx.send(running.handle());
+        running.await
+    });
+
+    let mut session = client::connect(Arc::new(client::Config::default()), proxy_addr, Client {})
+        .await
+        .unwrap();
+    let key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+    assert!(session
+        .authenticate_publickey("user", Arc::new(key))
+        .await
+        .unwrap());
+
+    let handle = handle_rx.await.unwrap();
+    freeze.send(()).unwrap();
+    handle
+        .disconnect(Disconnect::ByApplication, "bye".into(), "".into())
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("the server session did not shut down")
+        .unwrap()
+        .unwrap();
+    drop(session);
+}
+
+#[tokio::test]
+async fn test_handler_timeout() {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    struct Server {
+        timed_out: Option<tokio::sync::oneshot::Sender<&'static str>>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        fn handler_timed_out(&mut self, callback: &'static str) {
+            if let Some(timed_out) = self.timed_out.take() {
+                timed_out.send(callback).unwrap();
             }
-            msg => panic!("Unexpected message {:?}", msg),
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<server::Msg>,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn data(
+            &mut self,
+            _channel: ChannelId,
+            _data: &[u8],
+            _session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
         }
     }
 
-    #[derive(Clone)]
+    let config = server::Config {
+        inactivity_timeout: None,
+        handler_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let (timed_out, timed_out_rx) = tokio::sync::oneshot::channel();
+    let server = Server {
+        timed_out: Some(timed_out),
+    };
+    let (session, server) = connect(config, server).await;
+    let channel = session.channel_open_session().await.unwrap();
+    channel.data(&b"hello"[..]).await.unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("the stalled callback did not time out");
+    assert!(matches!(result, Err(Error::HandlerTimeout("data"))));
+    assert_eq!(timed_out_rx.await.unwrap(), "data");
+}
+
+/// Authentication callbacks, which run without the session, time out
+/// the same way.
+#[tokio::test]
+async fn test_auth_handler_timeout() {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
     struct Server {
-        clients: Arc<Mutex<HashMap<(usize, ChannelId), super::server::Handle>>>,
-        id: usize,
+        timed_out: Option<tokio::sync::oneshot::Sender<&'static str>>,
     }
 
-    impl server::Server for Server {
-        type Handler = Self;
-        fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
-            let s = self.clone();
-            self.id += 1;
-            s
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_password(&mut self, _: &str, _: &str) -> Result<server::Auth, Self::Error> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(server::Auth::Accept)
+        }
+
+        fn handler_timed_out(&mut self, callback: &'static str) {
+            if let Some(timed_out) = self.timed_out.take() {
+                timed_out.send(callback).unwrap();
+            }
         }
     }
 
+    let (config, socket) = listen(server::Config {
+        inactivity_timeout: None,
+        handler_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await;
+    let addr = socket.local_addr().unwrap();
+    let (timed_out, timed_out_rx) = tokio::sync::oneshot::channel();
+    let server = tokio::spawn(async move {
+        let (socket, _) = socket.accept().await.unwrap();
+        let server = Server {
+            timed_out: Some(timed_out),
+        };
+        server::run_stream(config, socket, server)
+            .await
+            .unwrap()
+            .await
+    });
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client)
+        .await
+        .unwrap();
+    let _ = session.authenticate_password("user", "password").await;
+
+    let result = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("the stalled callback did not time out")
+        .unwrap();
+    assert!(matches!(
+        result,
+        Err(Error::HandlerTimeout("auth_password"))
+    ));
+    assert_eq!(timed_out_rx.await.unwrap(), "auth_password");
+}
+
+#[cfg(feature = "danger-raw-packets")]
+#[tokio::test]
+async fn test_raw_packets() {
+    use async_trait::async_trait;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    struct Server {}
+
     #[async_trait]
     impl server::Handler for Server {
-        type Error = super::Error;
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
 
         async fn channel_open_session(
             &mut self,
-            channel: Channel<Msg>,
-            session: &mut Session,
+            _channel: Channel<server::Msg>,
+            session: &mut server::Session,
         ) -> Result<bool, Self::Error> {
-            {
-                let mut clients = self.clients.lock().unwrap();
-                clients.insert((self.id, channel.id()), session.handle());
-            }
+            let handle = session.handle();
+            tokio::spawn(async move { handle.send_raw_packet(&[201, 4, 5]).await });
             Ok(true)
         }
+    }
+
+    fn hook(msg_type: u8, sender: UnboundedSender<Vec<u8>>) -> RawPacketHook {
+        RawPacketHook::new(move |t, payload| {
+            if t == msg_type {
+                sender.send(payload.to_vec()).unwrap();
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    let (server_sender, mut server_received) = unbounded_channel();
+    let config = server::Config {
+        inactivity_timeout: None,
+        raw_packet_hook: Some(hook(200, server_sender)),
+        ..Default::default()
+    };
+    let (client_sender, mut client_received) = unbounded_channel();
+    let client_config = client::Config {
+        raw_packet_hook: Some(hook(201, client_sender)),
+        ..Default::default()
+    };
+    let (session, _server) = connect_with(config, Server {}, client_config, Client).await;
+
+    assert!(matches!(
+        session.send_raw_packet(&[msg::KEXINIT]).await,
+        Err(Error::RawPacketRejected(msg::KEXINIT))
+    ));
+    session.send_raw_packet(&[200, 1, 2, 3]).await.unwrap();
+    assert_eq!(server_received.recv().await.unwrap(), vec![1, 2, 3]);
+
+    // The session still works after the raw packets.
+    let _channel = session.channel_open_session().await.unwrap();
+    assert_eq!(client_received.recv().await.unwrap(), vec![4, 5]);
+}
+
+/// A peer that answers a global request with UNIMPLEMENTED, as some
+/// older implementations do, fails the request instead of leaving it
+/// waiting forever.
+#[cfg(feature = "danger-raw-packets")]
+#[tokio::test]
+async fn test_peer_unimplemented_global_request() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use async_trait::async_trait;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    struct Server {
+        unimplemented: Option<UnboundedReceiver<u32>>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
         async fn auth_publickey(
             &mut self,
             _: &str,
             _: &russh_keys::key::PublicKey,
         ) -> Result<server::Auth, Self::Error> {
-            debug!("auth_publickey");
             Ok(server::Auth::Accept)
         }
-        async fn data(
+
+        async fn auth_succeeded(
             &mut self,
-            channel: ChannelId,
-            data: &[u8],
-            session: &mut Session,
+            session: &mut server::Session,
         ) -> Result<(), Self::Error> {
-            debug!("server data = {:?}", std::str::from_utf8(data));
-            session.data(channel, CryptoVec::from_slice(data));
+            let handle = session.handle();
+            if let Some(mut unimplemented) = self.unimplemented.take() {
+                tokio::spawn(async move {
+                    while let Some(seqn) = unimplemented.recv().await {
+                        let mut payload = vec![msg::UNIMPLEMENTED];
+                        payload.extend_from_slice(&seqn.to_be_bytes());
+                        handle.send_raw_packet(&payload).await.unwrap();
+                    }
+                });
+            }
             Ok(())
         }
-    }
-
-    struct Client {}
-
-    #[async_trait]
-    impl client::Handler for Client {
-        type Error = super::Error;
 
-        async fn check_server_key(
+        async fn channel_open_session(
             &mut self,
-            _server_public_key: &russh_keys::key::PublicKey,
+            _channel: Channel<server::Msg>,
+            _session: &mut server::Session,
         ) -> Result<bool, Self::Error> {
-            // println!("check_server_key: {:?}", server_public_key);
             Ok(true)
         }
     }
+
+    // With strict key exchange, sequence numbers start over after
+    // NEWKEYS, and the hook sees every later packet.
+    let (sender, unimplemented) = unbounded_channel();
+    let count = AtomicU32::new(0);
+    let hook = RawPacketHook::new(move |t, _| {
+        let seqn = count.fetch_add(1, Ordering::SeqCst);
+        if t == msg::GLOBAL_REQUEST {
+            sender.send(seqn).unwrap();
+            true
+        } else {
+            false
+        }
+    });
+    let config = server::Config {
+        inactivity_timeout: None,
+        raw_packet_hook: Some(hook),
+        ..Default::default()
+    };
+    let server = Server {
+        unimplemented: Some(unimplemented),
+    };
+    let (mut session, _server) = connect(config, server).await;
+
+    let forward = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        session.tcpip_forward("127.0.0.1", 0),
+    )
+    .await
+    .expect("the request should fail, not hang");
+    assert!(matches!(forward, Err(Error::RequestDenied)));
+
+    // The session still works afterwards.
+    let _channel = session.channel_open_session().await.unwrap();
 }
 
-mod channels {
+/// Global requests sent before any reply arrives each get their own
+/// reply, replies coming in the order of the requests.
+#[tokio::test]
+async fn test_concurrent_global_requests() {
     use async_trait::async_trait;
-    use server::Session;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use russh_keys::encoding::Encoding;
 
-    use super::*;
-    use crate::CryptoVec;
+    struct Server {}
 
-    async fn test_session<RC, RS, CH, SH, F1, F2>(
-        client_handler: CH,
-        server_handler: SH,
-        run_client: RC,
-        run_server: RS,
-    ) where
-        RC: FnOnce(crate::client::Handle<CH>) -> F1 + Send + Sync + 'static,
-        RS: FnOnce(crate::server::Handle) -> F2 + Send + Sync + 'static,
-        F1: Future<Output = crate::client::Handle<CH>> + Send + Sync + 'static,
-        F2: Future<Output = crate::server::Handle> + Send + Sync + 'static,
-        CH: crate::client::Handler + Send + Sync + 'static,
-        SH: crate::server::Handler + Send + Sync + 'static,
-    {
-        use std::sync::Arc;
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
 
-        use crate::*;
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
 
-        let _ = env_logger::try_init();
+        async fn tcpip_forward(
+            &mut self,
+            address: &str,
+            port: &mut u32,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            // Accepts the even ones, on a port telling which it was.
+            let n: u32 = address.rsplit('.').next().unwrap().parse().unwrap();
+            *port = 1000 + n;
+            Ok(n % 2 == 0)
+        }
+    }
 
-        let client_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
-        let mut config = server::Config::default();
-        config.inactivity_timeout = None;
-        config.auth_rejection_time = std::time::Duration::from_secs(3);
-        config
-            .keys
-            .push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
-        let config = Arc::new(config);
-        let socket = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = socket.local_addr().unwrap();
+    for max_pending in [2, usize::MAX] {
+        let client_config = client::Config {
+            max_pending_global_requests: max_pending,
+            ..Default::default()
+        };
+        let (session, _server) =
+            connect_with(server::Config::default(), Server {}, client_config, Client).await;
 
-        #[derive(Clone)]
-        struct Server {}
+        let forwards = (0..8u32).map(|n| {
+            let mut payload = CryptoVec::new();
+            payload.extend_ssh_string(format!("127.0.0.{}", n).as_bytes());
+            payload.push_u32_be(0);
+            let session = &session;
+            async move {
+                session
+                    .send_global_request("tcpip-forward", true, &payload)
+                    .await
+            }
+        });
+        let replies = futures::future::join_all(forwards).await;
+        for (n, reply) in (0..8u32).zip(replies) {
+            if n % 2 == 0 {
+                assert_eq!(reply.unwrap(), Some((1000 + n).to_be_bytes().to_vec()));
+            } else {
+                assert!(matches!(reply, Err(Error::RequestDenied)));
+            }
+        }
+    }
+}
 
-        let server_join = tokio::spawn(async move {
-            let (socket, _) = socket.accept().await.unwrap();
+/// With `max_pending_global_requests`, later requests wait for the
+/// replies to the earlier ones before being sent.
+#[cfg(feature = "danger-raw-packets")]
+#[tokio::test]
+async fn test_max_pending_global_requests() {
+    use std::time::Duration;
 
-            server::run_stream(config, socket, server_handler)
+    use tokio::sync::mpsc::unbounded_channel;
+
+    // The server's hook swallows the requests, the test answers them.
+    let (sender, mut requests) = unbounded_channel();
+    let hook = RawPacketHook::new(move |t, _| {
+        if t == msg::GLOBAL_REQUEST {
+            sender.send(()).unwrap();
+            true
+        } else {
+            false
+        }
+    });
+    let config = server::Config {
+        raw_packet_hook: Some(hook),
+        ..Default::default()
+    };
+    let client_config = client::Config {
+        max_pending_global_requests: 2,
+        ..Default::default()
+    };
+    let (session, server) = connect_with(config, Server, client_config, Client).await;
+    let server = server.handle();
+    let session = Arc::new(session);
+    let sent = tokio::spawn({
+        let session = session.clone();
+        async move {
+            let requests = (0..5).map(|_| session.send_global_request("x@example.com", true, &[]));
+            futures::future::join_all(requests).await
+        }
+    });
+
+    let idle = Duration::from_millis(200);
+    requests.recv().await.unwrap();
+    requests.recv().await.unwrap();
+    assert!(tokio::time::timeout(idle, requests.recv()).await.is_err());
+    for i in 0..5u8 {
+        server
+            .send_raw_packet(&[msg::REQUEST_SUCCESS, i])
+            .await
+            .unwrap();
+        // Each reply lets one more request through.
+        if i < 3 {
+            requests.recv().await.unwrap();
+        }
+        assert!(tokio::time::timeout(idle, requests.recv()).await.is_err());
+    }
+    let replies = sent.await.unwrap();
+    for (i, reply) in (0..5u8).zip(replies) {
+        assert_eq!(reply.unwrap(), Some(vec![i]));
+    }
+}
+
+/// A server's PING, and messages of unknown types, don't end the
+/// client's session.
+#[cfg(feature = "danger-raw-packets")]
+#[tokio::test]
+async fn test_client_unknown_messages() {
+    let (session, server) = connect(server::Config::default(), Server).await;
+    let server = server.handle();
+
+    server
+        .send_raw_packet(&[msg::PING, 0, 0, 0, 4, b'c', b'h', b'a', b'f'])
+        .await
+        .unwrap();
+    server.send_raw_packet(&[200, 1, 2, 3]).await.unwrap();
+    server
+        .send_raw_packet(&[msg::PONG, 0, 0, 0, 0])
+        .await
+        .unwrap();
+    assert!(session.ping(b"still there").await.is_ok());
+}
+
+/// Mikrotik RouterOS sends SERVICE_ACCEPT again after the first one,
+/// and answers channel requests sent without `want_reply`. A lenient
+/// client ignores these packets, a strict one ends the session.
+#[cfg(feature = "danger-raw-packets")]
+#[tokio::test]
+async fn test_lenient_client() {
+    use async_trait::async_trait;
+    use tokio::sync::oneshot;
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<server::Msg>,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn exec_request(
+            &mut self,
+            channel: ChannelId,
+            _data: &[u8],
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            session.channel_success(channel);
+            session.data(channel, CryptoVec::from_slice(b"done"));
+            Ok(())
+        }
+    }
+
+    async fn connect(lenient: bool) -> (client::Handle<Client>, server::Handle) {
+        let (config, socket) = listen(server::Config {
+            inactivity_timeout: None,
+            ..Default::default()
+        })
+        .await;
+        let addr = socket.local_addr().unwrap();
+        let (sender, server) = oneshot::channel();
+        tokio::spawn(async move {
+            let (socket, _) = socket.accept().await.unwrap();
+            let running = server::run_stream(config, socket, Server {}).await.unwrap();
+            sender
+                .send(running.handle())
+                .unwrap_or_else(|_| unreachable!());
+            running.await
+        });
+
+        let config = Arc::new(client::Config {
+            lenient,
+            ..Default::default()
+        });
+        let session = client::connect(config, addr, Client {}).await.unwrap();
+        (session, server.await.unwrap())
+    }
+
+    async fn authenticate(session: &mut client::Handle<Client>) {
+        let key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+        assert!(session
+            .authenticate_publickey("user", Arc::new(key))
+            .await
+            .unwrap());
+    }
+
+    fn service_accept() -> Vec<u8> {
+        let mut payload = vec![msg::SERVICE_ACCEPT];
+        payload.extend_from_slice(&12u32.to_be_bytes());
+        payload.extend_from_slice(b"ssh-userauth");
+        payload
+    }
+
+    let (mut session, server) = connect(true).await;
+    server.send_raw_packet(&service_accept()).await.unwrap();
+    authenticate(&mut session).await;
+    server.send_raw_packet(&service_accept()).await.unwrap();
+    let mut channel = session.channel_open_session().await.unwrap();
+    channel.set_env(false, "LANG", "C").await.unwrap();
+    for reply in [msg::CHANNEL_SUCCESS, msg::CHANNEL_FAILURE] {
+        let mut payload = vec![reply];
+        payload.extend_from_slice(&channel.id().0.to_be_bytes());
+        server.send_raw_packet(&payload).await.unwrap();
+    }
+    // Only the answer to this request reaches the channel.
+    channel.exec(true, "true").await.unwrap();
+    assert!(matches!(channel.wait().await, Some(ChannelMsg::Success)));
+    assert!(matches!(
+        channel.wait().await,
+        Some(ChannelMsg::Data { ref data }) if &data[..] == b"done"
+    ));
+
+    let (mut session, server) = connect(false).await;
+    authenticate(&mut session).await;
+    server.send_raw_packet(&service_accept()).await.unwrap();
+    assert!(matches!(session.await, Err(Error::Inconsistent)));
+}
+
+/// A repeated USERAUTH_SUCCESS is ignored, even by a strict client,
+/// and doesn't restart the delayed compression.
+#[cfg(all(feature = "danger-raw-packets", feature = "flate2"))]
+#[tokio::test]
+async fn test_repeated_userauth_success() {
+    use std::borrow::Cow;
+
+    use async_trait::async_trait;
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<server::Msg>,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn exec_request(
+            &mut self,
+            channel: ChannelId,
+            _data: &[u8],
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            session.channel_success(channel);
+            session.data(channel, CryptoVec::from_slice(b"done"));
+            Ok(())
+        }
+    }
+
+    let preferred = Preferred {
+        compression: Cow::Borrowed(&[compression::ZLIB_LEGACY]),
+        ..Default::default()
+    };
+    let config = server::Config {
+        preferred: preferred.clone(),
+        inactivity_timeout: None,
+        ..Default::default()
+    };
+    let client_config = client::Config {
+        preferred,
+        lenient: false,
+        ..Default::default()
+    };
+    let (session, server) = connect_with(config, Server {}, client_config, Client).await;
+    let server = server.handle();
+
+    server
+        .send_raw_packet(&[msg::USERAUTH_SUCCESS])
+        .await
+        .unwrap();
+    server
+        .send_raw_packet(&[msg::USERAUTH_SUCCESS])
+        .await
+        .unwrap();
+    let mut channel = session.channel_open_session().await.unwrap();
+    channel.exec(true, "true").await.unwrap();
+    assert!(matches!(channel.wait().await, Some(ChannelMsg::Success)));
+    assert!(matches!(
+        channel.wait().await,
+        Some(ChannelMsg::Data { ref data }) if &data[..] == b"done"
+    ));
+    assert!(!session.is_closed());
+}
+
+#[tokio::test]
+async fn test_tcpip_forward_to_local() {
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+
+    struct Server {
+        channel: Option<oneshot::Sender<Channel<server::Msg>>>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn tcpip_forward(
+            &mut self,
+            address: &str,
+            port: &mut u32,
+            session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            *port = 2222;
+            let handle = session.handle();
+            let address = address.to_string();
+            let sender = self.channel.take();
+            tokio::spawn(async move {
+                let channel = handle
+                    .channel_open_forwarded_tcpip(address, 2222, "10.0.0.1", 51234)
+                    .await
+                    .unwrap();
+                let _ = sender.unwrap().send(channel);
+            });
+            Ok(true)
+        }
+
+        async fn cancel_tcpip_forward(
+            &mut self,
+            _address: &str,
+            _port: u32,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    // The local service the connections are piped to.
+    let local = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = local.accept().await.unwrap();
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        socket.write_all(&buf.to_ascii_uppercase()).await.unwrap();
+        // Stays open until the forward is cancelled.
+        let _ = socket.read(&mut buf).await;
+    });
+
+    let (channel, channel_rx) = oneshot::channel();
+    let config = server::Config {
+        inactivity_timeout: None,
+        ..Default::default()
+    };
+    let server = Server {
+        channel: Some(channel),
+    };
+    let (mut session, _server) = connect(config, server).await;
+    let port = session
+        .tcpip_forward_to(
+            "localhost",
+            0,
+            client::ForwardTarget::Local(local_addr.to_string()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(port, 2222);
+
+    let mut channel = channel_rx.await.unwrap();
+    channel.data(&b"hello"[..]).await.unwrap();
+    let mut stream = channel.make_reader();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"HELLO");
+    drop(stream);
+
+    // Cancelling the forward closes the connections in progress.
+    session
+        .cancel_tcpip_forward("localhost", 2222)
+        .await
+        .unwrap();
+    loop {
+        match tokio::time::timeout(std::time::Duration::from_secs(5), channel.wait())
+            .await
+            .unwrap()
+        {
+            Some(ChannelMsg::Close) | None => break,
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_tcpip_forward_with_reply() {
+    use async_trait::async_trait;
+    use tokio::sync::oneshot;
+
+    type Replies = (Result<u32, Error>, Result<(), Error>);
+
+    struct Client {
+        forwarded: Option<oneshot::Sender<Replies>>,
+    }
+
+    #[async_trait]
+    impl client::Handler for Client {
+        type Error = Error;
+
+        async fn check_server_key(
+            &mut self,
+            _server_public_key: &russh_keys::key::PublicKey,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn channel_open_confirmation(
+            &mut self,
+            _id: ChannelId,
+            _max_packet_size: u32,
+            _window_size: u32,
+            session: &mut client::Session,
+        ) -> Result<(), Self::Error> {
+            if let Some(forwarded) = self.forwarded.take() {
+                let bound = session.tcpip_forward_with_reply("127.0.0.1", 0);
+                let refused = session.cancel_tcpip_forward_with_reply("127.0.0.1", 1234);
+                tokio::spawn(async move {
+                    let _ = forwarded.send((bound.await, refused.await));
+                });
+            }
+            Ok(())
+        }
+    }
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<server::Msg>,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn tcpip_forward(
+            &mut self,
+            _address: &str,
+            port: &mut u32,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            *port = 2222;
+            Ok(true)
+        }
+    }
+
+    let config = server::Config {
+        inactivity_timeout: None,
+        ..Default::default()
+    };
+    let (forwarded, forwarded_rx) = oneshot::channel();
+    let client = Client {
+        forwarded: Some(forwarded),
+    };
+    let (session, _server) =
+        connect_with(config, Server {}, client::Config::default(), client).await;
+    let _channel = session.channel_open_session().await.unwrap();
+
+    let (bound, refused) = forwarded_rx.await.unwrap();
+    assert_eq!(bound.unwrap(), 2222);
+    assert!(matches!(refused, Err(Error::RequestDenied)));
+}
+
+#[tokio::test]
+async fn test_send_global_request() {
+    use async_trait::async_trait;
+    use tokio::sync::oneshot;
+
+    use crate::keys::encoding::Encoding;
+
+    struct Server {
+        refused: Option<oneshot::Sender<Result<Vec<u8>, Error>>>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn auth_succeeded(
+            &mut self,
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            // The client doesn't answer the first one, which mustn't
+            // be mistaken for the reply to the second one.
+            assert!(session
+                .send_global_request("ignored@example.com", false, &[])
+                .is_none());
+            let refused = session
+                .send_global_request("unknown@example.com", true, &[1, 2, 3])
+                .unwrap();
+            if let Some(sender) = self.refused.take() {
+                tokio::spawn(async move {
+                    let _ = sender.send(refused.await);
+                });
+            }
+            Ok(())
+        }
+
+        async fn tcpip_forward(
+            &mut self,
+            _address: &str,
+            port: &mut u32,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            *port = 2222;
+            Ok(true)
+        }
+    }
+
+    let config = server::Config {
+        inactivity_timeout: None,
+        ..Default::default()
+    };
+    let (refused, refused_rx) = oneshot::channel();
+    let server = Server {
+        refused: Some(refused),
+    };
+    let (session, _server) = connect(config, server).await;
+
+    // A request the server knows, built by hand: the reply's data is
+    // the port it chose.
+    let mut payload = Vec::new();
+    payload.extend_ssh_string(b"127.0.0.1");
+    payload.extend_from_slice(&0u32.to_be_bytes());
+    let reply = session
+        .send_global_request("tcpip-forward", true, &payload)
+        .await
+        .unwrap();
+    assert_eq!(reply, Some(2222u32.to_be_bytes().to_vec()));
+
+    assert!(session
+        .send_global_request("unknown@example.com", false, &[])
+        .await
+        .unwrap()
+        .is_none());
+    assert!(matches!(
+        session
+            .send_global_request("unknown@example.com", true, &[])
+            .await,
+        Err(Error::RequestDenied)
+    ));
+
+    assert!(matches!(
+        refused_rx.await.unwrap(),
+        Err(Error::RequestDenied)
+    ));
+}
+
+/// A loopback session on async-std, without any Tokio runtime.
+#[cfg(feature = "runtime-async-std")]
+#[test]
+fn test_async_std_runtime() {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::runtime::Compat;
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<server::Msg>,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn data(
+            &mut self,
+            channel: ChannelId,
+            data: &[u8],
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            session.data(channel, CryptoVec::from_slice(data));
+            Ok(())
+        }
+    }
+
+    let _ = env_logger::try_init();
+
+    async_std::task::block_on(async {
+        assert!(tokio::runtime::Handle::try_current().is_err());
+
+        // Timers are used by the rate limit, handler timeouts and
+        // keepalives.
+        let config = Arc::new(server::Config {
+            keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
+            inactivity_timeout: None,
+            handler_timeout: Some(Duration::from_secs(10)),
+            rate_limit: Some(RateLimit::new(1_000_000, 1_000)),
+            ..Default::default()
+        });
+        let socket = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let (socket, _) = socket.accept().await.unwrap();
+            server::run_stream(config, Compat::new(socket), Server {})
                 .await
-                .map_err(|_| ())
                 .unwrap()
+                .await
         });
 
-        let client_join = tokio::spawn(async move {
-            let config = Arc::new(client::Config::default());
-            let mut session = client::connect(config, addr, client_handler)
-                .await
-                .map_err(|_| ())
-                .unwrap();
-            let authenticated = session
-                .authenticate_publickey(
-                    std::env::var("USER").unwrap_or("user".to_owned()),
-                    Arc::new(client_key),
-                )
-                .await
-                .unwrap();
-            assert!(authenticated);
-            session
-        });
+        let config = Arc::new(client::Config {
+            keepalive_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        let stream = async_std::net::TcpStream::connect(addr).await.unwrap();
+        let mut session = client::connect_stream(config, Compat::new(stream), Client {})
+            .await
+            .unwrap();
+        let key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+        assert!(session
+            .authenticate_publickey("user", Arc::new(key))
+            .await
+            .unwrap());
+
+        let mut channel = session.channel_open_session().await.unwrap();
+        channel.data(&[1u8; 5000][..]).await.unwrap();
+        let mut echoed = 0;
+        while echoed < 5000 {
+            match channel.wait().await {
+                Some(ChannelMsg::Data { data }) => echoed += data.len(),
+                Some(_) => {}
+                None => panic!("channel closed"),
+            }
+        }
+
+        async_std::task::sleep(Duration::from_millis(150)).await;
+        session.keepalive().await.unwrap();
+    });
+}
+
+/// Clients only offer the legacy algorithms an old server needs with
+/// `auto_legacy_compat`.
+#[cfg(feature = "legacy-algorithms")]
+#[tokio::test]
+async fn test_auto_legacy_compat() {
+    use std::borrow::Cow;
+
+    use async_trait::async_trait;
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+    }
+
+    let (config, socket) = listen(server::Config {
+        server_id: SshId::Standard("SSH-2.0-OpenSSH_5.3".to_string()),
+        preferred: Preferred {
+            kex: Cow::Borrowed(&[kex::DH_G14_SHA1]),
+            cipher: Cow::Borrowed(&[cipher::AES_128_CBC]),
+            mac: Cow::Borrowed(&[mac::HMAC_SHA1]),
+            ..Preferred::DEFAULT
+        },
+        ..Default::default()
+    })
+    .await;
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = socket.accept().await.unwrap();
+            tokio::spawn(server::run_stream(config.clone(), socket, Server {}));
+        }
+    });
+
+    let refused = client::connect(Arc::new(client::Config::default()), addr, Client {}).await;
+    assert!(matches!(refused, Err(Error::NoCommonKexAlgo)));
+
+    let config = Arc::new(client::Config {
+        auto_legacy_compat: true,
+        ..Default::default()
+    });
+    client::connect(config, addr, Client {}).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_echo_latency() {
+    use std::borrow::Cow;
+    use std::time::{Duration, Instant};
+
+    struct Server {}
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_none(&mut self, _: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn data(
+            &mut self,
+            channel: ChannelId,
+            data: &[u8],
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            session.data(channel, CryptoVec::from_slice(data));
+            Ok(())
+        }
+    }
+
+    let ciphers = cipher::ALL_CIPHERS.iter().filter(|c| ***c != cipher::CLEAR);
+    for (cipher, depth) in ciphers.flat_map(|c| [(c, None), (c, Some(4))]) {
+        let (config, listener) = listen(server::Config {
+            preferred: Preferred {
+                cipher: Cow::Owned(vec![**cipher]),
+                ..Preferred::DEFAULT
+            },
+            read_pipeline_depth: depth,
+            ..Default::default()
+        })
+        .await;
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            // Nothing reaches the client until the server flushes.
+            let socket = tokio::io::BufWriter::new(socket);
+            server::run_stream(config, socket, Server {}).await?.await
+        });
+
+        let config = Arc::new(client::Config {
+            preferred: Preferred {
+                cipher: Cow::Owned(vec![**cipher]),
+                ..Preferred::DEFAULT
+            },
+            ..Default::default()
+        });
+        let mut session = client::connect(config, addr, Client {}).await.unwrap();
+        assert!(session.authenticate_none("user").await.unwrap());
+        let mut channel = session.channel_open_session().await.unwrap();
+
+        // Keystrokes, each echoed before the next one is typed: a
+        // packet held back until the next one arrives would take 100 ms,
+        // and one held back by Nagle's algorithm and delayed ACKs about
+        // 40 ms. The bound is well below both but leaves room for a
+        // loaded runner.
+        let mut latencies = Vec::new();
+        for byte in b"russh" {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let sent = Instant::now();
+            channel.data(&[*byte][..]).await.unwrap();
+            let echo = tokio::time::timeout(Duration::from_secs(1), async {
+                loop {
+                    if let Some(ChannelMsg::Data { data }) = channel.wait().await {
+                        return data;
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("no echo with {:?}, depth {:?}", cipher, depth));
+            assert_eq!(&echo[..], &[*byte]);
+            latencies.push(sent.elapsed());
+        }
+        latencies.sort();
+        let median = *latencies.get(latencies.len() / 2).unwrap();
+        debug!(
+            "{:?}, depth {:?}: echoes after {:?}",
+            cipher, depth, latencies
+        );
+        assert!(
+            median < Duration::from_millis(30),
+            "median echo after {:?} with {:?}, depth {:?}",
+            median,
+            cipher,
+            depth
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_timings() {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use futures::StreamExt;
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<server::Msg>,
+            _session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(true)
+        }
+    }
+
+    let addr = serve(server::Config::default(), Server {}).await;
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    let mut events = session.events();
+    let timings = session.handshake_timings();
+    assert!(timings.first_kex_complete.is_some());
+    assert!(timings.auth_start.is_none());
+
+    let key = Arc::new(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+    assert!(session.authenticate_publickey("user", key).await.unwrap());
+    session.channel_open_session().await.unwrap();
+
+    let timings = session.handshake_timings();
+    let steps = [
+        timings.tcp_connected,
+        timings.version_exchanged,
+        timings.first_kex_complete,
+        timings.auth_start,
+        timings.auth_success,
+        timings.first_channel_open,
+    ]
+    .map(Option::unwrap);
+    assert!(timings.started <= steps[0]);
+    assert!(steps.windows(2).all(|w| w[0] <= w[1]));
+    assert!(steps[4] - steps[3] >= Duration::from_millis(200));
+    assert!(steps[5] - steps[4] >= Duration::from_millis(100));
+    assert!(timings.elapsed(timings.first_kex_complete).unwrap() < Duration::from_secs(5));
+
+    // Each step was sent as an event, the last one with all of them.
+    let mut last = None;
+    for _ in 0..5 {
+        match events.next().await {
+            Some(client::ClientEvent::Handshake(t)) => last = Some(t),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+    assert_eq!(last, Some(timings));
+}
+
+#[tokio::test]
+async fn test_host_key_policy() {
+    use client::{Handler, HostKeyPolicy, KnownHostsHandler};
+    use russh_keys::PublicKeyBase64;
+
+    struct Client {}
+
+    #[async_trait::async_trait]
+    impl Handler for Client {
+        type Error = crate::Error;
+    }
+
+    let dir = std::env::temp_dir().join(format!("russh-known-hosts-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let key = russh_keys::key::KeyPair::generate_ed25519()
+        .unwrap()
+        .clone_public_key()
+        .unwrap();
+    let handler = |policy, path: &std::path::Path| {
+        KnownHostsHandler::new(Client {}, "localhost", 2222, policy).known_hosts_path(path)
+    };
+
+    // Strict refuses unknown hosts and writes nothing.
+    let path = dir.join("strict");
+    let mut strict = handler(HostKeyPolicy::Strict, &path);
+    assert!(!strict.check_server_key(&key).await.unwrap());
+    assert!(!path.exists());
+
+    // AcceptNew records unknown hosts once.
+    let path = dir.join("accept-new");
+    let mut accept_new = handler(HostKeyPolicy::AcceptNew, &path);
+    assert!(accept_new.check_server_key(&key).await.unwrap());
+    let recorded = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(recorded.lines().filter(|l| !l.is_empty()).count(), 1);
+    assert!(recorded
+        .trim_start()
+        .starts_with("[localhost]:2222 ssh-ed25519 "));
+    assert!(handler(HostKeyPolicy::Strict, &path)
+        .check_server_key(&key)
+        .await
+        .unwrap());
+    assert!(accept_new.check_server_key(&key).await.unwrap());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), recorded);
+
+    // Hashed entries don't mention the host, and are still found.
+    let path = dir.join("hashed");
+    let mut hashed = handler(HostKeyPolicy::AcceptNew, &path).hash_known_hosts(true);
+    assert!(hashed.check_server_key(&key).await.unwrap());
+    let recorded = std::fs::read_to_string(&path).unwrap();
+    assert!(recorded.starts_with("|1|"));
+    assert!(!recorded.contains("localhost"));
+    assert!(handler(HostKeyPolicy::Strict, &path)
+        .check_server_key(&key)
+        .await
+        .unwrap());
+    assert!(hashed.check_server_key(&key).await.unwrap());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), recorded);
+
+    // Ask gets the fingerprint, and only a yes is recorded.
+    let path = dir.join("ask");
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let answer = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let ask = {
+        let answer = answer.clone();
+        HostKeyPolicy::ask(move |unknown| {
+            tx.send(unknown.fingerprint).unwrap();
+            let answer = answer.load(std::sync::atomic::Ordering::SeqCst);
+            async move { answer }
+        })
+    };
+    let mut ask = handler(ask, &path);
+    assert!(!ask.check_server_key(&key).await.unwrap());
+    assert_eq!(
+        rx.try_recv().unwrap(),
+        key.fingerprint(russh_keys::key::HashAlg::Sha256)
+    );
+    assert!(!path.exists());
+    answer.store(true, std::sync::atomic::Ordering::SeqCst);
+    assert!(ask.check_server_key(&key).await.unwrap());
+    assert!(path.exists());
+
+    // A changed key fails with its line, whatever the policy, and the
+    // file is left alone.
+    let path = dir.join("mismatch");
+    let other = russh_keys::key::KeyPair::generate_ed25519()
+        .unwrap()
+        .clone_public_key()
+        .unwrap();
+    let before = format!(
+        "example.com ssh-ed25519 {}\n[localhost]:2222 ssh-ed25519 {}\n",
+        key.public_key_base64(),
+        other.public_key_base64()
+    );
+    std::fs::write(&path, &before).unwrap();
+    for policy in [
+        HostKeyPolicy::Strict,
+        HostKeyPolicy::AcceptNew,
+        HostKeyPolicy::Off,
+        HostKeyPolicy::ask(|_| async { true }),
+    ] {
+        match handler(policy, &path).check_server_key(&key).await {
+            Err(Error::Keys(russh_keys::Error::KeyChanged { line })) => assert_eq!(line, 2),
+            r => panic!("expected a key mismatch, got {:?}", r),
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_check_server_key_blob() {
+    use russh_keys::PublicKeyBase64;
+
+    struct Client {
+        pinned: Vec<u8>,
+    }
+
+    #[async_trait::async_trait]
+    impl client::Handler for Client {
+        type Error = crate::Error;
+
+        async fn check_server_key_blob(
+            &mut self,
+            server_key_blob: &[u8],
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<bool, Self::Error> {
+            Ok(server_key_blob == self.pinned)
+        }
+    }
+
+    struct Server {}
+
+    impl server::Handler for Server {
+        type Error = crate::Error;
+    }
+
+    let host_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+    let blob = host_key.clone_public_key().unwrap().public_key_bytes();
+    let other = russh_keys::key::KeyPair::generate_ed25519()
+        .unwrap()
+        .clone_public_key()
+        .unwrap()
+        .public_key_bytes();
+    let (config, listener) = listen(server::Config {
+        keys: vec![host_key],
+        ..Default::default()
+    })
+    .await;
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            if let Ok(session) = server::run_stream(config.clone(), socket, Server {}).await {
+                tokio::spawn(session);
+            }
+        }
+    });
+
+    let connect = |pinned| async move {
+        client::connect(Arc::new(client::Config::default()), addr, Client { pinned }).await
+    };
+    assert!(connect(blob).await.is_ok());
+    assert!(matches!(connect(other).await, Err(Error::UnknownKey)));
+}
+
+#[tokio::test]
+async fn test_ecdsa_keys() {
+    use std::borrow::Cow;
+    use std::sync::Mutex;
+
+    use russh_keys::key::{self, KeyPair, PublicKey};
+
+    struct Client {
+        host_key: Arc<Mutex<Option<&'static str>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl client::Handler for Client {
+        type Error = crate::Error;
+
+        async fn check_server_key(&mut self, key: &PublicKey) -> Result<bool, Self::Error> {
+            *self.host_key.lock().unwrap() = Some(key.name());
+            Ok(true)
+        }
+    }
+
+    struct Server {
+        authorized: PublicKey,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            key: &PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            if *key == self.authorized {
+                Ok(server::Auth::Accept)
+            } else {
+                Ok(server::Auth::Reject {
+                    proceed_with_methods: None,
+                })
+            }
+        }
+    }
+
+    let curves = [
+        key::ECDSA_SHA2_NISTP256,
+        key::ECDSA_SHA2_NISTP384,
+        key::ECDSA_SHA2_NISTP521,
+    ];
+    let mut keys = vec![KeyPair::generate_ed25519().unwrap()];
+    keys.extend(curves.iter().map(|c| KeyPair::generate_ecdsa(*c).unwrap()));
+    for curve in curves {
+        let user_key = Arc::new(KeyPair::generate_ecdsa(curve).unwrap());
+        let server = Server {
+            authorized: user_key.clone_public_key().unwrap(),
+        };
+        let config = server::Config {
+            keys: keys.clone(),
+            ..Default::default()
+        };
+        let addr = serve(config, server).await;
+
+        // The host key is chosen with `Preferred`.
+        let client_config = Arc::new(client::Config {
+            preferred: Preferred {
+                key: Cow::Owned(vec![curve]),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let host_key = Arc::new(Mutex::new(None));
+        let client = Client {
+            host_key: host_key.clone(),
+        };
+        let mut session = client::connect(client_config, addr, client).await.unwrap();
+        assert_eq!(*host_key.lock().unwrap(), Some(curve.0));
+
+        let other_key = Arc::new(KeyPair::generate_ecdsa(curve).unwrap());
+        assert!(!session
+            .authenticate_publickey("user", other_key)
+            .await
+            .unwrap());
+        assert!(session
+            .authenticate_publickey("user", user_key)
+            .await
+            .unwrap());
+        session
+            .disconnect(Disconnect::ByApplication, "", "")
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_authenticate_publickey_from_file() {
+    use russh_keys::key::{KeyPair, PublicKey};
+
+    struct Server {
+        authorized: PublicKey,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            key: &PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            if *key == self.authorized {
+                Ok(server::Auth::Accept)
+            } else {
+                Ok(server::Auth::Reject {
+                    proceed_with_methods: None,
+                })
+            }
+        }
+    }
+
+    let user_key = KeyPair::generate_ed25519().unwrap();
+    let path = std::env::temp_dir().join(format!("russh-encrypted-key-{}", std::process::id()));
+    let mut pem = Vec::new();
+    russh_keys::encode_pkcs8_pem_encrypted(&user_key, b"passphrase", 16, &mut pem).unwrap();
+    std::fs::write(&path, pem).unwrap();
+
+    let server = Server {
+        authorized: user_key.clone_public_key().unwrap(),
+    };
+    let addr = serve(server::Config::default(), server).await;
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    let result = session
+        .authenticate_publickey_from_file("user", &path, None)
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::Keys(russh_keys::Error::KeyIsEncrypted))
+    ));
+    let result = session
+        .authenticate_publickey_from_file("user", &path, Some("wrong"))
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::Keys(russh_keys::Error::WrongPassphrase))
+    ));
+    assert!(session
+        .authenticate_publickey_from_file("user", &path, Some("passphrase"))
+        .await
+        .unwrap());
+    std::fs::remove_file(&path).unwrap();
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_minimum_key_bits() {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use russh_keys::key::{KeyFamily, KeyPair, PublicKey, SignatureHash};
+
+    struct Client {
+        checked: Arc<Mutex<bool>>,
+    }
+
+    #[async_trait::async_trait]
+    impl client::Handler for Client {
+        type Error = crate::Error;
+
+        async fn check_server_key(&mut self, _: &PublicKey) -> Result<bool, Self::Error> {
+            *self.checked.lock().unwrap() = true;
+            Ok(true)
+        }
+    }
+
+    struct Server {
+        rejected: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            panic!("the handler saw a rejected key");
+        }
+
+        async fn auth_publickey_size_rejected(
+            &mut self,
+            _: &str,
+            _: &PublicKey,
+            reason: &crate::Error,
+        ) -> Result<(), Self::Error> {
+            self.rejected.lock().unwrap().push(reason.to_string());
+            Ok(())
+        }
+    }
+
+    let rsa = Arc::new(KeyPair::generate_rsa(1024, SignatureHash::SHA2_256).unwrap());
+
+    // The server refuses a weak RSA key and a large ECDSA key.
+    let config = server::Config {
+        minimum_key_bits: HashMap::from([(KeyFamily::Rsa, 2048)]),
+        maximum_key_bits: HashMap::from([(KeyFamily::Ecdsa, 384)]),
+        ..Default::default()
+    };
+    let rejected = Arc::new(Mutex::new(Vec::new()));
+    let server = Server {
+        rejected: rejected.clone(),
+    };
+    let addr = serve(config, server).await;
+    let client = Client {
+        checked: Default::default(),
+    };
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, client)
+        .await
+        .unwrap();
+    assert!(!session
+        .authenticate_publickey("user", rsa.clone())
+        .await
+        .unwrap());
+    let ecdsa = KeyPair::generate_ecdsa(russh_keys::key::ECDSA_SHA2_NISTP521).unwrap();
+    assert!(!session
+        .authenticate_publickey("user", Arc::new(ecdsa))
+        .await
+        .unwrap());
+    assert_eq!(
+        *rejected.lock().unwrap(),
+        vec![
+            "ssh-rsa key of 1024 bits is too weak, at least 2048 bits are required",
+            "ecdsa-sha2-nistp521 key of 521 bits is too large, at most 384 bits are accepted",
+        ]
+    );
+
+    // The client refuses a weak host key before checking it.
+    let config = server::Config {
+        keys: vec![(*rsa).clone()],
+        ..Default::default()
+    };
+    let server = Server {
+        rejected: Default::default(),
+    };
+    let addr = serve(config, server).await;
+    let checked = Arc::new(Mutex::new(false));
+    let client = Client {
+        checked: checked.clone(),
+    };
+    let config = client::Config {
+        minimum_key_bits: HashMap::from([(KeyFamily::Rsa, 2048)]),
+        ..Default::default()
+    };
+    let result = client::connect(Arc::new(config), addr, client).await;
+    assert!(matches!(
+        result,
+        Err(Error::KeyTooWeak {
+            bits: 1024,
+            minimum: 2048,
+            ..
+        })
+    ));
+    assert!(!*checked.lock().unwrap());
+}
+
+#[tokio::test]
+async fn test_authenticate_default_identities() {
+    use std::sync::Mutex;
+
+    use russh_keys::key::{KeyPair, PublicKey, SignatureHash};
+
+    struct Server {
+        authorized: PublicKey,
+        offered: Arc<Mutex<Vec<PublicKey>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            key: &PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            self.offered.lock().unwrap().push(key.clone());
+            if *key == self.authorized {
+                Ok(server::Auth::Accept)
+            } else {
+                Ok(server::Auth::Reject {
+                    proceed_with_methods: None,
+                })
+            }
+        }
+    }
+
+    // The ed25519 key needs a passphrase and is skipped, the ECDSA key
+    // is rejected and the RSA key accepted.
+    let home = std::env::temp_dir().join(format!("russh-home-{}", std::process::id()));
+    let ssh_dir = home.join(".ssh");
+    std::fs::create_dir_all(&ssh_dir).unwrap();
+    let ed25519 = KeyPair::generate_ed25519().unwrap();
+    let ecdsa = KeyPair::generate_ecdsa(russh_keys::key::ECDSA_SHA2_NISTP256).unwrap();
+    let rsa = KeyPair::generate_rsa(1024, SignatureHash::SHA2_256).unwrap();
+    let mut pem = Vec::new();
+    russh_keys::encode_pkcs8_pem_encrypted(&ed25519, b"passphrase", 16, &mut pem).unwrap();
+    std::fs::write(ssh_dir.join("id_ed25519"), pem).unwrap();
+    for (name, key) in [("id_ecdsa", &ecdsa), ("id_rsa", &rsa)] {
+        let mut pem = Vec::new();
+        russh_keys::encode_pkcs8_pem(key, &mut pem).unwrap();
+        std::fs::write(ssh_dir.join(name), pem).unwrap();
+    }
+    std::env::set_var("HOME", &home);
+    std::env::remove_var("SSH_AUTH_SOCK");
+
+    let offered = Arc::new(Mutex::new(Vec::new()));
+    let server = Server {
+        authorized: rsa.clone_public_key().unwrap(),
+        offered: offered.clone(),
+    };
+    let addr = serve(server::Config::default(), server).await;
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    let identity = session.authenticate_default_identities_ex("user").await;
+    std::fs::remove_dir_all(&home).unwrap();
+    let identity = identity.unwrap().unwrap();
+    assert_eq!(identity.path, Some(ssh_dir.join("id_rsa")));
+    assert_eq!(
+        identity.fingerprint,
+        rsa.clone_public_key()
+            .unwrap()
+            .fingerprint(russh_keys::key::HashAlg::Sha256)
+    );
+    assert_eq!(
+        *offered.lock().unwrap(),
+        vec![
+            ecdsa.clone_public_key().unwrap(),
+            rsa.clone_public_key().unwrap()
+        ]
+    );
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_channel_labels() {
+    use futures::StreamExt;
+    use russh_keys::key::PublicKey;
+
+    struct Server {
+        channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            if let Some(tx) = self.channel.take() {
+                tx.send(channel).unwrap();
+            }
+            Ok(true)
+        }
+    }
+
+    let config = server::Config {
+        send_channel_labels: true,
+        ..Default::default()
+    };
+    let (channel_tx, channel_rx) = tokio::sync::oneshot::channel();
+    let server = Server {
+        channel: Some(channel_tx),
+    };
+    let client_config = client::Config {
+        send_channel_labels: true,
+        ..Default::default()
+    };
+    let (mut session, server) = connect_with(config, server, client_config, Client).await;
+    let server = server.handle();
+    let mut events = session.events();
+
+    // A label given by the client reaches the server.
+    let channel = session
+        .channel_open_session_labeled("hop1:conn42")
+        .await
+        .unwrap();
+    assert_eq!(channel.label().as_deref(), Some("hop1:conn42"));
+    assert!(format!("{:?}", channel).contains("hop1:conn42"));
+    channel.data(&b"hello"[..]).await.unwrap();
+    let mut server_channel = channel_rx.await.unwrap();
+    // The label was sent before the data.
+    assert!(matches!(
+        server_channel.wait().await,
+        Some(ChannelMsg::Data { .. })
+    ));
+    assert_eq!(server_channel.label().as_deref(), Some("hop1:conn42"));
+    let listed = server.list_channels().await;
+    assert_eq!(listed.len(), 1);
+    assert_eq!(
+        listed.first().unwrap().label.as_deref(),
+        Some("hop1:conn42")
+    );
+
+    // And the other way round.
+    let server_opened = server
+        .channel_open_session_labeled("hop2:conn7")
+        .await
+        .unwrap();
+    assert_eq!(server_opened.label().as_deref(), Some("hop2:conn7"));
+    loop {
+        match events.next().await.unwrap() {
+            client::ClientEvent::ChannelLabeled { label, .. } => {
+                assert_eq!(label, "hop2:conn7");
+                break;
+            }
+            client::ClientEvent::Disconnected(_) => panic!("disconnected"),
+            _ => {}
+        }
+    }
+    let labels: Vec<_> = session
+        .list_channels()
+        .await
+        .into_iter()
+        .map(|c| c.label)
+        .collect();
+    assert_eq!(
+        labels,
+        vec![
+            Some("hop1:conn42".to_owned()),
+            Some("hop2:conn7".to_owned())
+        ]
+    );
+
+    // Channels opened without a label have none.
+    let unlabeled = session.channel_open_session().await.unwrap();
+    assert_eq!(unlabeled.label(), None);
+
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_end_of_write() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use russh_keys::key::PublicKey;
+
+    struct Client {
+        end_of_write: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl client::Handler for Client {
+        type Error = crate::Error;
+
+        async fn check_server_key(&mut self, _: &PublicKey) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn end_of_write(
+            &mut self,
+            _: ChannelId,
+            _: &mut client::Session,
+        ) -> Result<(), Self::Error> {
+            self.end_of_write.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    struct Server {
+        channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+        end_of_write: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            if let Some(tx) = self.channel.take() {
+                tx.send(channel).unwrap();
+            }
+            Ok(true)
+        }
+
+        async fn end_of_write(
+            &mut self,
+            _: ChannelId,
+            _: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            self.end_of_write.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    let (channel_tx, channel_rx) = tokio::sync::oneshot::channel();
+    let server_saw = Arc::new(AtomicBool::new(false));
+    let server = Server {
+        channel: Some(channel_tx),
+        end_of_write: server_saw.clone(),
+    };
+    let client_saw = Arc::new(AtomicBool::new(false));
+    let client = Client {
+        end_of_write: client_saw.clone(),
+    };
+    let (session, _server) = connect_with(
+        server::Config::default(),
+        server,
+        client::Config::default(),
+        client,
+    )
+    .await;
+    let mut channel = session.channel_open_session().await.unwrap();
+    let mut server_channel = channel_rx.await.unwrap();
+
+    // The client stops reading, the server can still be written to.
+    channel.end_of_write().await.unwrap();
+    channel.data(&b"still writing"[..]).await.unwrap();
+    assert!(matches!(
+        server_channel.wait().await,
+        Some(ChannelMsg::EndOfWrite)
+    ));
+    assert!(server_saw.load(Ordering::SeqCst));
+    assert!(matches!(
+        server_channel.wait().await,
+        Some(ChannelMsg::Data { .. })
+    ));
+
+    // And the other way round.
+    server_channel.end_of_write().await.unwrap();
+    assert!(matches!(channel.wait().await, Some(ChannelMsg::EndOfWrite)));
+    assert!(client_saw.load(Ordering::SeqCst));
+
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_on_channel_data() {
+    use std::sync::Mutex;
+
+    use russh_keys::key::PublicKey;
+
+    type Log = Arc<Mutex<Vec<(Direction, Option<u32>, Vec<u8>)>>>;
+
+    struct Client {
+        log: Log,
+    }
+
+    #[async_trait::async_trait]
+    impl client::Handler for Client {
+        type Error = crate::Error;
+
+        async fn check_server_key(&mut self, _: &PublicKey) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        fn on_channel_data(&mut self, direction: Direction, _: ChannelId, data: &[u8]) {
+            self.log
+                .lock()
+                .unwrap()
+                .push((direction, None, data.to_vec()));
+        }
+
+        fn on_channel_extended_data(
+            &mut self,
+            direction: Direction,
+            _: ChannelId,
+            ext: u32,
+            data: &[u8],
+        ) {
+            self.log
+                .lock()
+                .unwrap()
+                .push((direction, Some(ext), data.to_vec()));
+        }
+    }
+
+    struct Server {
+        channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+        log: Log,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            if let Some(tx) = self.channel.take() {
+                tx.send(channel).unwrap();
+            }
+            Ok(true)
+        }
+
+        fn on_channel_data(&mut self, direction: Direction, _: ChannelId, data: &[u8]) {
+            self.log
+                .lock()
+                .unwrap()
+                .push((direction, None, data.to_vec()));
+        }
+
+        fn on_channel_extended_data(
+            &mut self,
+            direction: Direction,
+            _: ChannelId,
+            code: u32,
+            data: &[u8],
+        ) {
+            self.log
+                .lock()
+                .unwrap()
+                .push((direction, Some(code), data.to_vec()));
+        }
+    }
+
+    let (channel_tx, channel_rx) = tokio::sync::oneshot::channel();
+    let server_log = Log::default();
+    let server = Server {
+        channel: Some(channel_tx),
+        log: server_log.clone(),
+    };
+    let client_log = Log::default();
+    let client = Client {
+        log: client_log.clone(),
+    };
+    let (session, _server) = connect_with(
+        server::Config::default(),
+        server,
+        client::Config::default(),
+        client,
+    )
+    .await;
+    let mut channel = session.channel_open_session().await.unwrap();
+    let mut server_channel = channel_rx.await.unwrap();
+
+    channel.data(&b"ping"[..]).await.unwrap();
+    assert!(matches!(
+        server_channel.wait().await,
+        Some(ChannelMsg::Data { .. })
+    ));
+    server_channel.extended_data(1, &b"err"[..]).await.unwrap();
+    server_channel.data(&b"pong"[..]).await.unwrap();
+    assert!(matches!(
+        channel.wait().await,
+        Some(ChannelMsg::ExtendedData { ext: 1, .. })
+    ));
+    assert!(matches!(
+        channel.wait().await,
+        Some(ChannelMsg::Data { .. })
+    ));
+
+    let expected = |first, second| {
+        vec![
+            (first, None, b"ping".to_vec()),
+            (second, Some(1), b"err".to_vec()),
+            (second, None, b"pong".to_vec()),
+        ]
+    };
+    assert_eq!(
+        *client_log.lock().unwrap(),
+        expected(Direction::Outbound, Direction::Inbound)
+    );
+    assert_eq!(
+        *server_log.lock().unwrap(),
+        expected(Direction::Inbound, Direction::Outbound)
+    );
+
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+}
+
+/// Opens a session channel after `no-more-sessions@openssh.com`,
+/// returning whether the opening succeeded and whether the server
+/// handler was told about the request before it.
+async fn no_more_sessions(enforce: bool) -> (bool, bool) {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct Server {
+        told: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            self.told
+                .fetch_and(session.no_more_sessions(), Ordering::SeqCst);
+            Ok(true)
+        }
+
+        async fn no_more_sessions(&mut self, _: &mut server::Session) -> Result<(), Self::Error> {
+            self.told.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    let config = server::Config {
+        enforce_no_more_sessions: enforce,
+        ..Default::default()
+    };
+    let told = Arc::new(AtomicBool::new(false));
+    let server = Server { told: told.clone() };
+    let (session, _server) = connect(config, server).await;
+    session.no_more_sessions().await.unwrap();
+    let opened = match session.channel_open_session().await {
+        Ok(_) => true,
+        Err(Error::ChannelOpenFailure(ChannelOpenFailure::AdministrativelyProhibited)) => false,
+        Err(e) => panic!("unexpected error {:?}", e),
+    };
+    // The global request didn't ask for a reply, so the session must
+    // still be usable.
+    assert!(!session.is_closed());
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+    (opened, told.load(Ordering::SeqCst))
+}
+
+#[tokio::test]
+async fn test_no_more_sessions() {
+    assert_eq!(no_more_sessions(true).await, (false, true));
+}
+
+#[tokio::test]
+async fn test_no_more_sessions_not_enforced() {
+    assert_eq!(no_more_sessions(false).await, (true, true));
+}
+
+#[tokio::test]
+async fn test_session_established_at() {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use russh_keys::key::PublicKey;
+
+    type Seen = Arc<Mutex<Vec<Option<Instant>>>>;
+
+    struct Client {
+        seen: Seen,
+    }
+
+    #[async_trait::async_trait]
+    impl client::Handler for Client {
+        type Error = crate::Error;
+
+        async fn check_server_key(&mut self, _: &PublicKey) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn channel_open_confirmation(
+            &mut self,
+            _: ChannelId,
+            _: u32,
+            _: u32,
+            session: &mut client::Session,
+        ) -> Result<(), Self::Error> {
+            assert!(session.age().is_some());
+            self.seen.lock().unwrap().push(session.established_at());
+            Ok(())
+        }
+    }
+
+    struct Server {
+        seen: Seen,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_none(&mut self, _: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn auth_succeeded(
+            &mut self,
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            self.seen.lock().unwrap().push(session.established_at());
+            Ok(())
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            self.seen.lock().unwrap().push(session.established_at());
+            Ok(true)
+        }
+    }
+
+    let server_seen = Seen::default();
+    let client_seen = Seen::default();
+    let before = Instant::now();
+    let server = Server {
+        seen: server_seen.clone(),
+    };
+    let addr = serve(server::Config::default(), server).await;
+
+    let client = Client {
+        seen: client_seen.clone(),
+    };
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, client)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(session.authenticate_none("user").await.unwrap());
+    let after = Instant::now();
+    session.channel_open_session().await.unwrap();
+
+    // Set once, when authentication succeeds, and visible to the
+    // handler's auth_succeeded already.
+    let server_seen = server_seen.lock().unwrap().clone();
+    assert_eq!(server_seen.len(), 2);
+    let established = server_seen[0].unwrap();
+    assert!(established >= before + Duration::from_millis(50) && established <= after);
+    assert_eq!(server_seen[1], Some(established));
+    let client_seen = client_seen.lock().unwrap().clone();
+    let established = client_seen[0].unwrap();
+    assert!(established >= before + Duration::from_millis(50) && established <= after);
+}
+
+#[tokio::test]
+async fn test_ping() {
+    for advertise_ping in [true, false] {
+        let config = server::Config {
+            advertise_ping,
+            ..Default::default()
+        };
+        let (session, _server) = connect(config, Server).await;
+        if !advertise_ping {
+            assert!(matches!(
+                session.ping(b"ping").await,
+                Err(crate::Error::PingUnsupported)
+            ));
+            continue;
+        }
+
+        // Concurrent pings, with equal and different payloads, are
+        // each answered.
+        let (a, b, c) = tokio::join!(session.ping(b"a"), session.ping(b"b"), session.ping(b"a"));
+        for rtt in [a, b, c] {
+            assert!(rtt.unwrap() < std::time::Duration::from_secs(5));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_max_session_duration() {
+    use std::time::{Duration, Instant};
+
+    use futures::StreamExt;
+
+    struct Server {}
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    let config = server::Config {
+        max_session_duration: Some(Duration::from_secs(1)),
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let (mut session, server) = connect(config, Server {}).await;
+    let mut events = session.events();
+    let channel = session.channel_open_session().await.unwrap();
+
+    // Activity doesn't extend the session.
+    let info = loop {
+        tokio::select! {
+            event = events.next() => match event.unwrap() {
+                client::ClientEvent::Disconnected(info) => break info.unwrap(),
+                _ => {}
+            },
+            () = tokio::time::sleep(Duration::from_millis(100)) => {
+                let _ = channel.data(&b"ping"[..]).await;
+            }
+        }
+    };
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert!(matches!(info.reason_code, Disconnect::ByApplication));
+    assert_eq!(info.message, "Maximum session duration reached");
+    assert!(matches!(server.await, Err(Error::SessionDurationExceeded)));
+}
+
+#[tokio::test]
+async fn test_auth_methods_per_user() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    struct Server {
+        password_attempts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_methods(
+            &mut self,
+            user: &str,
+            methods: MethodSet,
+        ) -> Result<MethodSet, Self::Error> {
+            if user.starts_with("svc-") {
+                Ok(MethodSet::PUBLICKEY)
+            } else {
+                Ok(methods)
+            }
+        }
+
+        async fn auth_password(&mut self, _: &str, _: &str) -> Result<server::Auth, Self::Error> {
+            self.password_attempts.fetch_add(1, Ordering::SeqCst);
+            Ok(server::Auth::Accept)
+        }
+    }
+
+    let config = server::Config {
+        auth_rejection_time: std::time::Duration::from_millis(10),
+        ..Default::default()
+    };
+    let password_attempts = Arc::new(AtomicUsize::new(0));
+    let server = Server {
+        password_attempts: password_attempts.clone(),
+    };
+    let addr = serve(config, server).await;
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    assert!(!session
+        .authenticate_password("svc-backup", "secret")
+        .await
+        .unwrap());
+    assert_eq!(password_attempts.load(Ordering::SeqCst), 0);
+    assert!(session
+        .authenticate_password("alice", "secret")
+        .await
+        .unwrap());
+    assert_eq!(password_attempts.load(Ordering::SeqCst), 1);
+}
+
+/// The rules of `Config::conditional` choose the methods by address and
+/// user, and are recorded in `AuthInfo`.
+#[tokio::test]
+async fn test_conditional_config() {
+    use async_trait::async_trait;
+    use client::AuthResult;
+    use server::{ConditionalConfig, ConfigOverrides, MatchCriteria};
+
+    struct Server {
+        methods: Option<MethodSet>,
+        authenticated: tokio::sync::mpsc::UnboundedSender<(MethodSet, Vec<String>)>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_methods(
+            &mut self,
+            _: &str,
+            methods: MethodSet,
+        ) -> Result<MethodSet, Self::Error> {
+            self.methods = Some(methods);
+            Ok(methods)
+        }
+
+        async fn auth_password(&mut self, _: &str, _: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn auth_succeeded(
+            &mut self,
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            let auth_info = session.auth_info().unwrap();
+            assert_eq!(auth_info.matched_rules, session.matched_rules());
+            assert!(session.peer_addr().unwrap().ip().is_loopback());
+            self.authenticated
+                .send((self.methods.unwrap(), auth_info.matched_rules.clone()))
+                .unwrap();
+            Ok(())
+        }
+    }
+
+    let conditional = ConditionalConfig::new()
+        .rule(
+            "admins",
+            MatchCriteria {
+                users: vec!["admin".into(), "root".into()],
+                ..Default::default()
+            },
+            ConfigOverrides {
+                methods: Some(MethodSet::PUBLICKEY),
+                ..Default::default()
+            },
+        )
+        .rule(
+            "office",
+            MatchCriteria {
+                source_cidrs: vec!["10.0.0.0/8".into()],
+                ..Default::default()
+            },
+            ConfigOverrides {
+                methods: Some(MethodSet::all()),
+                ..Default::default()
+            },
+        )
+        .rule(
+            "loopback",
+            MatchCriteria {
+                source_cidrs: vec!["127.0.0.0/8".into(), "::1".into()],
+                ..Default::default()
+            },
+            ConfigOverrides {
+                methods: Some(MethodSet::PUBLICKEY | MethodSet::PASSWORD),
+                ..Default::default()
+            },
+        );
+    let (config, socket) = listen(server::Config {
+        methods: MethodSet::PUBLICKEY,
+        auth_rejection_time: std::time::Duration::from_millis(10),
+        conditional,
+        ..Default::default()
+    })
+    .await;
+    let addr = socket.local_addr().unwrap();
+    let (authenticated, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (socket, peer_addr) = socket.accept().await.unwrap();
+            let server = Server {
+                methods: None,
+                authenticated: authenticated.clone(),
+            };
+            tokio::spawn(server::run_stream_with_peer_addr(
+                config.clone(),
+                socket,
+                Some(peer_addr),
+                server,
+            ));
+        }
+    });
+
+    // Passwords are allowed from the loopback interface, except to
+    // admins.
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    assert_eq!(
+        session
+            .authenticate_password_ex("admin", "secret")
+            .await
+            .unwrap(),
+        AuthResult::Failure {
+            remaining_methods: vec!["publickey".to_string()],
+            partial_success: false,
+        }
+    );
+    assert!(session
+        .authenticate_password("alice", "secret")
+        .await
+        .unwrap());
+    assert_eq!(
+        rx.recv().await,
+        Some((
+            MethodSet::PUBLICKEY | MethodSet::PASSWORD,
+            vec!["loopback".to_string()]
+        ))
+    );
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    let key = Arc::new(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+    assert!(session.authenticate_publickey("root", key).await.unwrap());
+    assert_eq!(
+        rx.recv().await,
+        Some((
+            MethodSet::PUBLICKEY,
+            vec!["admins".to_string(), "loopback".to_string()]
+        ))
+    );
+}
+
+#[tokio::test]
+async fn test_auth_partial_success() {
+    use async_trait::async_trait;
+
+    struct Server {
+        key_ok: bool,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            self.key_ok = true;
+            Ok(server::Auth::PartialSuccess {
+                proceed_with_methods: MethodSet::PASSWORD,
+            })
+        }
+
+        async fn auth_password(&mut self, _: &str, _: &str) -> Result<server::Auth, Self::Error> {
+            if self.key_ok {
+                Ok(server::Auth::Accept)
+            } else {
+                Ok(server::Auth::Reject {
+                    proceed_with_methods: Some(MethodSet::PUBLICKEY | MethodSet::PASSWORD),
+                })
+            }
+        }
+    }
+
+    let config = server::Config {
+        auth_rejection_time: std::time::Duration::from_millis(10),
+        ..Default::default()
+    };
+    let addr = serve(config, Server { key_ok: false }).await;
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    let client_key = Arc::new(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+    // The password alone isn't enough.
+    assert!(!session
+        .authenticate_password("alice", "secret")
+        .await
+        .unwrap());
+    // The key is a partial success, the password completes it.
+    assert!(!session
+        .authenticate_publickey("alice", client_key)
+        .await
+        .unwrap());
+    assert!(session
+        .authenticate_password("alice", "secret")
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn test_auth_result() {
+    use async_trait::async_trait;
+    use client::AuthResult;
+
+    struct Server {
+        key_ok: bool,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            self.key_ok = true;
+            Ok(server::Auth::PartialSuccess {
+                proceed_with_methods: MethodSet::PASSWORD,
+            })
+        }
+
+        async fn auth_password(&mut self, _: &str, _: &str) -> Result<server::Auth, Self::Error> {
+            if self.key_ok {
+                Ok(server::Auth::Accept)
+            } else {
+                Ok(server::Auth::Reject {
+                    proceed_with_methods: Some(MethodSet::PUBLICKEY | MethodSet::PASSWORD),
+                })
+            }
+        }
+    }
+
+    let config = server::Config {
+        auth_rejection_time: std::time::Duration::from_millis(10),
+        ..Default::default()
+    };
+    let addr = serve(config, Server { key_ok: false }).await;
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    let client_key = Arc::new(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+    match session
+        .authenticate_password_ex("alice", "secret")
+        .await
+        .unwrap()
+    {
+        AuthResult::Failure {
+            mut remaining_methods,
+            partial_success,
+        } => {
+            remaining_methods.sort();
+            assert_eq!(remaining_methods, ["password", "publickey"]);
+            assert!(!partial_success);
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+    assert_eq!(
+        session
+            .authenticate_publickey_ex("alice", client_key)
+            .await
+            .unwrap(),
+        AuthResult::Failure {
+            remaining_methods: vec!["password".to_string()],
+            partial_success: true,
+        }
+    );
+    assert_eq!(
+        session
+            .authenticate_password_ex("alice", "secret")
+            .await
+            .unwrap(),
+        AuthResult::Success
+    );
+}
+
+#[tokio::test]
+async fn test_authenticate_publickey_with_hash_alg() {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use russh_keys::key::{self, SignatureHash};
+
+    struct Server {
+        algorithms: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            key: &key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            self.algorithms.lock().unwrap().push(key.name().to_string());
+            Ok(server::Auth::Accept)
+        }
+    }
+
+    // The server only takes rsa-sha2-256 signatures.
+    let config = server::Config {
+        preferred: Preferred {
+            key: std::borrow::Cow::Borrowed(&[key::ED25519, key::RSA_SHA2_256]),
+            ..Default::default()
+        },
+        auth_rejection_time: std::time::Duration::from_millis(10),
+        ..Default::default()
+    };
+    let algorithms = Arc::new(Mutex::new(Vec::new()));
+    let server = Server {
+        algorithms: algorithms.clone(),
+    };
+    let addr = serve(config, server).await;
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+
+    // Only RSA keys have a choice of algorithm.
+    let ed25519 = Arc::new(key::KeyPair::generate_ed25519().unwrap());
+    assert!(matches!(
+        session
+            .authenticate_publickey_with_hash_alg("alice", ed25519, SignatureHash::SHA2_256)
+            .await,
+        Err(Error::Keys(russh_keys::Error::UnsupportedKeyType { .. }))
+    ));
+
+    // The forced algorithm is used even though server-sig-algs doesn't
+    // list it.
+    let rsa = Arc::new(key::KeyPair::generate_rsa(1024, SignatureHash::SHA2_512).unwrap());
+    assert!(!session
+        .authenticate_publickey_with_hash_alg("alice", rsa.clone(), SignatureHash::SHA2_512)
+        .await
+        .unwrap());
+    assert!(session
+        .authenticate_publickey_with_hash_alg("alice", rsa, SignatureHash::SHA2_256)
+        .await
+        .unwrap());
+    assert_eq!(*algorithms.lock().unwrap(), vec!["rsa-sha2-256"]);
+}
+
+#[tokio::test]
+async fn test_connect_via_proxy() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// An HTTP proxy requiring `user:secret`.
+    async fn http_proxy(mut stream: TcpStream) {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        if !request.contains("\r\nProxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n") {
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+            return;
+        }
+        let target = request.split(' ').nth(1).unwrap();
+        let mut upstream = TcpStream::connect(target).await.unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+    }
+
+    /// A SOCKS5 proxy requiring a password, which can't reach
+    /// `unreachable.invalid`.
+    async fn socks5_proxy(mut stream: TcpStream) {
+        assert_eq!(stream.read_u8().await.unwrap(), 5);
+        let mut methods = vec![0; stream.read_u8().await.unwrap() as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+        assert!(methods.contains(&2));
+        stream.write_all(&[5, 2]).await.unwrap();
+        assert_eq!(stream.read_u8().await.unwrap(), 1);
+        let mut user = vec![0; stream.read_u8().await.unwrap() as usize];
+        stream.read_exact(&mut user).await.unwrap();
+        let mut password = vec![0; stream.read_u8().await.unwrap() as usize];
+        stream.read_exact(&mut password).await.unwrap();
+        stream.write_all(&[1, 0]).await.unwrap();
+
+        // Version, connect, reserved, domain name.
+        assert_eq!(stream.read_u32().await.unwrap(), 0x05010003);
+        let mut host = vec![0; stream.read_u8().await.unwrap() as usize];
+        stream.read_exact(&mut host).await.unwrap();
+        let port = stream.read_u16().await.unwrap();
+        let host = String::from_utf8(host).unwrap();
+        if host == "unreachable.invalid" {
+            stream
+                .write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            return;
+        }
+        let mut upstream = TcpStream::connect((host.as_str(), port)).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+    }
+
+    let (config, socket) = listen(server::Config::default()).await;
+    let port = socket.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = socket.accept().await.unwrap();
+            let config = config.clone();
+            tokio::spawn(server::run_stream(config, socket, Server {}));
+        }
+    });
+
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_port = http.local_addr().unwrap().port();
+    let socks5 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socks5_port = socks5.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Ok((stream, _)) = http.accept() => { tokio::spawn(http_proxy(stream)); }
+                Ok((stream, _)) = socks5.accept() => { tokio::spawn(socks5_proxy(stream)); }
+            }
+        }
+    });
+
+    let auth = client::ProxyAuth {
+        username: "user".to_string(),
+        password: "secret".to_string(),
+    };
+    let connect = |proxy: client::Proxy, host: &'static str| async move {
+        let mut session = client::connect_via_proxy(
+            Arc::new(client::Config::default()),
+            &proxy,
+            host,
+            port,
+            Client {},
+        )
+        .await?;
+        let key = Arc::new(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+        session.authenticate_publickey("user", key).await
+    };
+
+    // The proxies resolve the host name.
+    let http = |auth| client::Proxy::Http {
+        host: "127.0.0.1".to_string(),
+        port: http_port,
+        auth,
+    };
+    assert!(connect(http(Some(auth.clone())), "localhost")
+        .await
+        .unwrap());
+    assert!(matches!(
+        connect(http(None), "localhost").await,
+        Err(Error::Proxy(client::ProxyError::AuthenticationRequired))
+    ));
+
+    let socks5 = client::Proxy::Socks5 {
+        host: "127.0.0.1".to_string(),
+        port: socks5_port,
+        auth: Some(auth),
+    };
+    assert!(connect(socks5.clone(), "localhost").await.unwrap());
+    assert!(matches!(
+        connect(socks5, "unreachable.invalid").await,
+        Err(Error::Proxy(client::ProxyError::Socks5(
+            client::Socks5Error::HostUnreachable
+        )))
+    ));
+}
+
+#[tokio::test]
+async fn test_check_client_id() {
+    use async_trait::async_trait;
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        fn check_client_id(&self, id: &[u8]) -> bool {
+            !id.starts_with(b"SSH-2.0-BadClient_1.")
+        }
+    }
+
+    let (config, socket) = listen(server::Config {
+        ..Default::default()
+    })
+    .await;
+    let addr = socket.local_addr().unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = socket.accept().await.unwrap();
+            let result = server::run_stream(config.clone(), socket, Server {}).await;
+            tx.send(result.err()).unwrap();
+        }
+    });
+
+    let connect = |id: &str| {
+        let config = client::Config {
+            client_id: SshId::Standard(id.to_string()),
+            ..Default::default()
+        };
+        client::connect(Arc::new(config), addr, Client {})
+    };
+    assert!(connect("SSH-2.0-BadClient_1.0").await.is_err());
+    assert!(matches!(
+        rx.recv().await,
+        Some(Some(Error::ClientIdRejected))
+    ));
+    assert!(connect("SSH-2.0-GoodClient_1.0").await.is_ok());
+    assert!(matches!(rx.recv().await, Some(None)));
+}
+
+/// A bogus packet length is refused before anything is allocated, and
+/// the server says why before disconnecting.
+#[tokio::test]
+async fn test_packet_too_large() {
+    use async_trait::async_trait;
+    use byteorder::{BigEndian, ByteOrder};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+    }
+
+    for depth in [None, Some(4)] {
+        let (config, socket) = listen(server::Config {
+            maximum_inbound_packet_size: 64 << 10,
+            read_pipeline_depth: depth,
+            ..Default::default()
+        })
+        .await;
+        let addr = socket.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = socket.accept().await.unwrap();
+            server::run_stream(config, socket, Server {})
+                .await
+                .unwrap()
+                .await
+        });
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"SSH-2.0-Bogus\r\n").await.unwrap();
+        stream
+            .write_all(&[0xff, 0xff, 0xff, 0xf0, 0])
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+
+        match server.await.unwrap() {
+            Err(Error::PacketTooLarge { size, limit }) => {
+                assert_eq!(size, 0xffff_fff0);
+                assert_eq!(limit, 64 << 10);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // The server's identification, its KEXINIT, and a DISCONNECT.
+        let id_end = received.windows(2).position(|w| w == b"\r\n").unwrap() + 2;
+        let mut packets = received.get(id_end..).unwrap();
+        let mut disconnect = None;
+        while let Some(len) = packets.get(..4) {
+            let len = BigEndian::read_u32(len) as usize;
+            let (packet, rest) = packets.split_at(4 + len);
+            if packet.get(5) == Some(&msg::DISCONNECT) {
+                disconnect = packet.get(6..10).map(|r| r.to_vec());
+            }
+            packets = rest;
+        }
+        assert_eq!(
+            disconnect,
+            Some((Disconnect::ProtocolError as u32).to_be_bytes().to_vec())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    use std::time::Instant;
+
+    use async_trait::async_trait;
+
+    struct Server {
+        received: usize,
+        done: tokio::sync::mpsc::UnboundedSender<usize>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn data(
+            &mut self,
+            _: ChannelId,
+            data: &[u8],
+            _: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            self.received += data.len();
+            Ok(())
+        }
+
+        async fn channel_eof(
+            &mut self,
+            _: ChannelId,
+            _: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            self.done.send(std::mem::take(&mut self.received)).unwrap();
+            Ok(())
+        }
+    }
+
+    const RATE: u32 = 512 << 10;
+    const BURST: u32 = 16 << 10;
+    const TOTAL: usize = 1 << 20;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    /// Returns the measured throughput, in bytes per second.
+    async fn transfer(
+        config: client::Config,
+        per_channel: bool,
+        done: &mut tokio::sync::mpsc::UnboundedReceiver<usize>,
+        tx: &tokio::sync::mpsc::UnboundedSender<usize>,
+    ) -> f64 {
+        let server = Server {
+            received: 0,
+            done: tx.clone(),
+        };
+        let (session, _server) =
+            connect_with(server::Config::default(), server, config, Client).await;
+        let channel = session.channel_open_session().await.unwrap();
+        if per_channel {
+            channel.set_rate_limit(RATE, BURST);
+        }
+        let start = Instant::now();
+        channel.data(&vec![0; TOTAL][..]).await.unwrap();
+        channel.eof().await.unwrap();
+        assert_eq!(done.recv().await, Some(TOTAL));
+        TOTAL as f64 / start.elapsed().as_secs_f64()
+    }
+
+    let rate = f64::from(RATE);
+    let throughput = transfer(client::Config::default(), true, &mut rx, &tx).await;
+    assert!(
+        (throughput - rate).abs() < rate / 10.,
+        "channel throughput {} B/s",
+        throughput
+    );
+
+    let config = client::Config {
+        rate_limit: Some(RateLimit::new(RATE, BURST)),
+        ..Default::default()
+    };
+    let throughput = transfer(config, false, &mut rx, &tx).await;
+    assert!(
+        (throughput - rate).abs() < rate / 10.,
+        "session throughput {} B/s",
+        throughput
+    );
+}
+
+/// The server paces the data of each channel, or everything a session
+/// writes, and reports the rates.
+#[tokio::test]
+async fn test_per_channel_and_session_rate() {
+    use std::time::{Duration, Instant};
+
+    use async_trait::async_trait;
+
+    /// Sends `TOTAL` bytes on each channel through a `Handle`, then EOF.
+    struct Server;
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            let handle = session.handle();
+            let id = channel.id();
+            tokio::spawn(async move {
+                for chunk in vec![0; TOTAL].chunks(32 << 10) {
+                    handle.data(id, CryptoVec::from_slice(chunk)).await.unwrap();
+                }
+                handle.eof(id).await.unwrap();
+            });
+            Ok(true)
+        }
+    }
+
+    const RATE: u32 = 128 << 10;
+    const BURST: u32 = 16 << 10;
+    const TOTAL: usize = 128 << 10;
+
+    /// Receives `TOTAL` bytes on each of two channels, and returns the
+    /// time it took, with the handles of the sessions still open.
+    async fn transfer(
+        config: server::Config,
+    ) -> (Duration, server::Handle, client::Handle<Client>) {
+        let (session, server) = connect(config, Server).await;
+        let start = Instant::now();
+        let mut readers = Vec::new();
+        for _ in 0..2 {
+            let mut channel = session.channel_open_session().await.unwrap();
+            readers.push(tokio::spawn(async move {
+                let mut received = 0;
+                while let Some(msg) = channel.wait().await {
+                    match msg {
+                        ChannelMsg::Data { data } => received += data.len(),
+                        ChannelMsg::Eof => break,
+                        _ => {}
+                    }
+                }
+                received
+            }));
+        }
+        for reader in readers {
+            assert_eq!(reader.await.unwrap(), TOTAL);
+        }
+        (start.elapsed(), server.handle(), session)
+    }
+
+    let limit = RateLimit::new(RATE, BURST);
+    // One second for each channel, at the same time. The burst
+    // goes first.
+    let (elapsed, handle, _session) = transfer(server::Config {
+        per_channel_rate: Some(limit),
+        ..Default::default()
+    })
+    .await;
+    assert!(
+        elapsed > Duration::from_millis(800) && elapsed < Duration::from_millis(1500),
+        "per-channel transfer took {:?}",
+        elapsed
+    );
+    let channels = handle.list_channels().await;
+    assert_eq!(channels.len(), 2);
+    for channel in channels {
+        let rate = channel.rate.unwrap();
+        assert_eq!(rate.limit, limit);
+        assert_eq!(rate.bytes_sent, TOTAL as u64);
+        assert!(
+            rate.bytes_per_sec <= u64::from(RATE) * 11 / 10,
+            "{:?}",
+            rate
+        );
+    }
+    assert_eq!(handle.rate_limit_stats().await, None);
+
+    // Two seconds for both channels together.
+    let (elapsed, handle, _session) = transfer(server::Config {
+        rate_limit: Some(limit),
+        ..Default::default()
+    })
+    .await;
+    assert!(
+        elapsed > Duration::from_millis(1700),
+        "per-session transfer took {:?}",
+        elapsed
+    );
+    let rate = handle.rate_limit_stats().await.unwrap();
+    assert_eq!(rate.limit, limit);
+    // The protocol messages count too.
+    assert!(rate.bytes_sent > 2 * TOTAL as u64, "{:?}", rate);
+    assert!(
+        rate.bytes_per_sec <= u64::from(RATE) * 11 / 10,
+        "{:?}",
+        rate
+    );
+    let channels = handle.list_channels().await;
+    assert!(channels.iter().all(|c| c.rate.is_none()));
+}
+
+/// With many channels sending as fast as they can on one session, all
+/// of them make progress at about the same pace.
+#[tokio::test]
+async fn test_channel_flush_fairness() {
+    use std::borrow::Cow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    /// Sends data on each channel until the session is closed, once
+    /// all channels are open.
+    struct Server {
+        all_open: Arc<tokio::sync::Barrier>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            let handle = session.handle();
+            let id = channel.id();
+            let all_open = self.all_open.clone();
+            tokio::spawn(async move {
+                all_open.wait().await;
+                let chunk = vec![0; 64 << 10];
+                while handle.data(id, CryptoVec::from_slice(&chunk)).await.is_ok() {}
+            });
+            Ok(true)
+        }
+    }
+
+    const CHANNELS: usize = 100;
+
+    // Without encryption, to measure the scheduling of the channels
+    // rather than the speed of the ciphers in debug builds.
+    let preferred = Preferred {
+        cipher: Cow::Borrowed(&[cipher::NONE]),
+        ..Preferred::DEFAULT
+    };
+    let config = server::Config {
+        preferred: preferred.clone(),
+        ..Default::default()
+    };
+    let all_open = Arc::new(tokio::sync::Barrier::new(CHANNELS));
+    let client_config = client::Config {
+        preferred,
+        ..Default::default()
+    };
+    let (session, _server) = connect_with(config, Server { all_open }, client_config, Client).await;
+    let received: Arc<Vec<AtomicUsize>> =
+        Arc::new((0..CHANNELS).map(|_| AtomicUsize::new(0)).collect());
+    for i in 0..CHANNELS {
+        let mut channel = session.channel_open_session().await.unwrap();
+        let received = received.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = channel.wait().await {
+                if let ChannelMsg::Data { data } = msg {
+                    received[i].fetch_add(data.len(), Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    let snapshot =
+        || -> Vec<usize> { received.iter().map(|r| r.load(Ordering::Relaxed)).collect() };
+    // The channel opened last starts sending first.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let before = snapshot();
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let after = snapshot();
+    let throughput: Vec<usize> = after.iter().zip(before).map(|(a, b)| a - b).collect();
+    let slowest = *throughput.iter().min().unwrap();
+    let fastest = *throughput.iter().max().unwrap();
+    assert!(
+        slowest > 0 && fastest <= 3 * slowest,
+        "slowest channel received {} bytes, fastest {}",
+        slowest,
+        fastest
+    );
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+}
+
+/// With pipelined decryption on both sides, data goes through intact
+/// across several key exchanges in both directions.
+#[tokio::test]
+async fn test_read_pipeline() {
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Echoes the channel's data.
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn data(
+            &mut self,
+            channel: ChannelId,
+            data: &[u8],
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            session.data(channel, CryptoVec::from_slice(data));
+            Ok(())
+        }
+
+        async fn channel_eof(
+            &mut self,
+            channel: ChannelId,
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            session.eof(channel);
+            session.close(channel);
+            Ok(())
+        }
+    }
+
+    const TOTAL: usize = 2 << 20;
+    // The client starts a key exchange every 256 KiB, the server never.
+    let limits = Limits::new(256 << 10, 256 << 10, std::time::Duration::from_secs(3600));
+
+    let config = server::Config {
+        read_pipeline_depth: Some(4),
+        ..Default::default()
+    };
+    let client_config = client::Config {
+        limits,
+        read_pipeline_depth: Some(4),
+        ..Default::default()
+    };
+    let (session, _server) = connect_with(config, Server {}, client_config, Client).await;
+    let channel = session.channel_open_session().await.unwrap();
+    let (mut read, mut write) = tokio::io::split(channel.into_stream());
+
+    let data: Vec<u8> = (0..TOTAL).map(|i| (i % 251) as u8).collect();
+    let send = async {
+        write.write_all(&data).await.unwrap();
+        write.shutdown().await.unwrap();
+    };
+    let mut echoed = Vec::new();
+    let receive = read.read_to_end(&mut echoed);
+    let ((), received) = tokio::join!(send, receive);
+    assert_eq!(received.unwrap(), TOTAL);
+    assert!(echoed == data, "the echoed data differs");
+
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+}
+
+/// Writes a known byte stream to `stream` for `duration` while checking
+/// the peer's stream, and returns how much was sent and received.
+async fn exchange_for<S>(stream: S, duration: std::time::Duration) -> (u64, u64)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn byte(offset: u64) -> u8 {
+        (offset % 251) as u8
+    }
+
+    let (mut read, mut write) = tokio::io::split(stream);
+    let send = async {
+        let deadline = std::time::Instant::now() + duration;
+        let mut buf = vec![0; 32 << 10];
+        let (mut sent, mut len) = (0, 1);
+        while std::time::Instant::now() < deadline {
+            // Vary the write sizes, so that packets of all sizes straddle
+            // the key exchanges.
+            len = len * 7 % buf.len() + 1;
+            for (i, b) in buf.iter_mut().take(len).enumerate() {
+                *b = byte(sent + i as u64);
+            }
+            write.write_all(&buf[..len]).await.unwrap();
+            sent += len as u64;
+        }
+        write.shutdown().await.unwrap();
+        sent
+    };
+    let receive = async {
+        let mut buf = vec![0; 32 << 10];
+        let mut received = 0;
+        loop {
+            let n = read.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break received;
+            }
+            for (i, b) in buf[..n].iter().enumerate() {
+                let offset = received + i as u64;
+                assert_eq!(*b, byte(offset), "byte {} differs", offset);
+            }
+            received += n as u64;
+        }
+    };
+    tokio::join!(send, receive)
+}
+
+/// Both peers stream data to each other for `duration`. The client
+/// and the server start a key exchange after every MiB they write if
+/// `client_rekeys` and `server_rekeys` are set, respectively.
+async fn rekey_soak(duration: std::time::Duration, client_rekeys: bool, server_rekeys: bool) {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use futures::StreamExt;
+    use tokio::sync::oneshot;
+
+    struct Server {
+        duration: std::time::Duration,
+        done: Arc<Mutex<Option<oneshot::Sender<(u64, u64)>>>>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            let (duration, done) = (self.duration, self.done.clone());
+            tokio::spawn(async move {
+                let counts = exchange_for(channel.into_stream(), duration).await;
+                if let Some(done) = done.lock().unwrap().take() {
+                    let _ = done.send(counts);
+                }
+            });
+            Ok(true)
+        }
+    }
+
+    let limits = |rekeys| {
+        if rekeys {
+            Limits::new(1 << 20, 1 << 20, std::time::Duration::from_secs(3600))
+        } else {
+            Limits::default()
+        }
+    };
+    let config = server::Config {
+        limits: limits(server_rekeys),
+        ..Default::default()
+    };
+    let (done, server_counts) = oneshot::channel();
+    let server = Server {
+        duration,
+        done: Arc::new(Mutex::new(Some(done))),
+    };
+    let client_config = client::Config {
+        limits: limits(client_rekeys),
+        ..Default::default()
+    };
+    let (mut session, _server) = connect_with(config, server, client_config, Client).await;
+    let mut events = session.events();
+    let channel = session.channel_open_session().await.unwrap();
+
+    let run = async {
+        let (sent, received) = exchange_for(channel.into_stream(), duration).await;
+        (sent, received, server_counts.await.unwrap())
+    };
+    let (sent, received, (server_sent, server_received)) =
+        tokio::time::timeout(duration * 2 + std::time::Duration::from_secs(30), run)
+            .await
+            .expect("the transfer stalled");
+    assert_eq!(sent, server_received);
+    assert_eq!(received, server_sent);
+
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+    let mut rekeys = 0;
+    while let Some(event) = events.next().await {
+        if let client::ClientEvent::Rekeyed = event {
+            rekeys += 1;
+        }
+    }
+    // Simultaneous key exchanges by both sides count once.
+    let written = match (client_rekeys, server_rekeys) {
+        (true, true) => sent.max(received),
+        (true, false) => sent,
+        (false, true) => received,
+        (false, false) => 0,
+    };
+    assert!(
+        rekeys >= written >> 21,
+        "only {} key exchanges for {} bytes sent and {} received",
+        rekeys,
+        sent,
+        received
+    );
+}
+
+#[tokio::test]
+async fn test_rekey_under_load() {
+    rekey_soak(std::time::Duration::from_secs(5), true, true).await
+}
+
+/// Key exchanges started by only one of the peers, which the other
+/// answers while it is sending.
+#[tokio::test]
+async fn test_peer_initiated_rekey_under_load() {
+    rekey_soak(std::time::Duration::from_secs(3), false, true).await;
+    rekey_soak(std::time::Duration::from_secs(3), true, false).await;
+}
+
+/// Channel requests written while data is held back by a key exchange
+/// reach the peer after that data, in the order they were written.
+#[tokio::test]
+async fn test_requests_after_data_during_rekey() {
+    use async_trait::async_trait;
+    use futures::StreamExt;
+
+    const CHUNKS: usize = 64;
+    const CHUNK: usize = 4096;
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            let (id, handle) = (channel.id(), session.handle());
+            tokio::spawn(async move {
+                // The first chunks start a key exchange, the next ones
+                // are held back until it is over.
+                for i in 0..CHUNKS {
+                    let chunk = CryptoVec::from_slice(&[i as u8; CHUNK]);
+                    handle.data(id, chunk).await.unwrap();
+                }
+                handle.exit_status_request(id, 3).await.unwrap();
+                handle.eof(id).await.unwrap();
+                handle.close(id).await.unwrap();
+            });
+            Ok(true)
+        }
+    }
+
+    let config = server::Config {
+        limits: Limits::new(8 << 10, 1 << 30, std::time::Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let (mut session, _server) = connect(config, Server {}).await;
+    let mut events = session.events();
+    let mut channel = session.channel_open_session().await.unwrap();
+
+    let mut received = Vec::new();
+    let mut after_data = Vec::new();
+    let read = async {
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => {
+                    assert!(after_data.is_empty(), "data after {:?}", after_data);
+                    received.extend_from_slice(&data);
+                }
+                ChannelMsg::ExitStatus { exit_status } => after_data.push(exit_status),
+                ChannelMsg::Eof => after_data.push(u32::MAX),
+                ChannelMsg::Close => break,
+                _ => {}
+            }
+        }
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(10), read)
+        .await
+        .expect("the channel was not closed");
+    assert_eq!(received.len(), CHUNKS * CHUNK);
+    for (i, chunk) in received.chunks(CHUNK).enumerate() {
+        assert!(
+            chunk.iter().all(|b| *b == i as u8),
+            "chunk {} out of order",
+            i
+        );
+    }
+    assert_eq!(after_data, [3, u32::MAX]);
+
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+    let mut rekeyed = false;
+    while let Some(event) = events.next().await {
+        rekeyed |= matches!(event, client::ClientEvent::Rekeyed);
+    }
+    assert!(rekeyed);
+}
+
+/// The full soak test, run it with
+/// `cargo test -p russh --lib test_rekey_soak -- --ignored`.
+#[tokio::test]
+#[ignore]
+async fn test_rekey_soak() {
+    rekey_soak(std::time::Duration::from_secs(600), true, true).await
+}
+
+/// A client that never opens a channel, like `ssh -N`, stays connected
+/// to a server with an inactivity timeout thanks to its keepalives, until
+/// the server ends the session.
+async fn no_channel_session(duration: std::time::Duration) {
+    let config = server::Config {
+        inactivity_timeout: Some(std::time::Duration::from_secs(2)),
+        ..Default::default()
+    };
+
+    // The compression starts after authentication, and keepalives are
+    // the only packets compressed.
+    #[cfg(feature = "flate2")]
+    let preferred = Preferred {
+        compression: std::borrow::Cow::Borrowed(&[compression::ZLIB_LEGACY]),
+        ..Default::default()
+    };
+    #[cfg(not(feature = "flate2"))]
+    let preferred = Preferred::default();
+    let client_config = client::Config {
+        preferred,
+        keepalive_interval: Some(std::time::Duration::from_millis(500)),
+        ..Default::default()
+    };
+    let (session, server) = connect_with(config, Server, client_config, Client).await;
+    let server = server.handle();
+
+    assert!(tokio::time::timeout(duration, session.wait_closed())
+        .await
+        .is_err());
+    assert!(!session.is_closed());
+
+    server
+        .disconnect(Disconnect::ByApplication, "bye".into(), "".into())
+        .await
+        .unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(5), session.wait_closed())
+        .await
+        .expect("the session didn't end");
+    assert!(session.is_closed());
+}
+
+#[tokio::test]
+async fn test_wait_closed() {
+    no_channel_session(std::time::Duration::from_secs(5)).await
+}
+
+/// The hour-long soak test, run it with
+/// `cargo test -p russh --lib test_no_channel_soak -- --ignored`.
+#[tokio::test]
+#[ignore]
+async fn test_no_channel_soak() {
+    no_channel_session(std::time::Duration::from_secs(3600)).await
+}
+
+/// A client with `disable_rekey` never starts a key exchange, even far
+/// past its limits.
+#[tokio::test]
+async fn test_disable_rekey() {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use futures::StreamExt;
+    use tokio::sync::oneshot;
+
+    struct Server {
+        done: Arc<Mutex<Option<oneshot::Sender<(u64, u64)>>>>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            let done = self.done.clone();
+            tokio::spawn(async move {
+                let counts =
+                    exchange_for(channel.into_stream(), std::time::Duration::from_secs(1)).await;
+                if let Some(done) = done.lock().unwrap().take() {
+                    let _ = done.send(counts);
+                }
+            });
+            Ok(true)
+        }
+    }
+
+    let (done, server_counts) = oneshot::channel();
+    let server = Server {
+        done: Arc::new(Mutex::new(Some(done))),
+    };
+    let client_config = client::Config {
+        limits: Limits::new(64 << 10, 64 << 10, std::time::Duration::from_secs(3600)),
+        disable_rekey: true,
+        ..Default::default()
+    };
+    let (mut session, _server) =
+        connect_with(server::Config::default(), server, client_config, Client).await;
+    let mut events = session.events();
+    let channel = session.channel_open_session().await.unwrap();
+
+    let (sent, received) =
+        exchange_for(channel.into_stream(), std::time::Duration::from_secs(1)).await;
+    let (server_sent, server_received) = server_counts.await.unwrap();
+    assert_eq!(sent, server_received);
+    assert_eq!(received, server_sent);
+    assert!(sent > 64 << 10);
+
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+    while let Some(event) = events.next().await {
+        assert!(!matches!(event, client::ClientEvent::Rekeyed));
+    }
+}
+
+#[tokio::test]
+async fn test_disconnect_reason_on_kex_failure() {
+    use async_trait::async_trait;
+    use russh_keys::encoding::{Encoding, Reader};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+    }
+
+    let (config, socket) = listen(server::Config {
+        ..Default::default()
+    })
+    .await;
+    let addr = socket.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (socket, _) = socket.accept().await.unwrap();
+        server::run_stream(config, socket, Server {})
+            .await
+            .unwrap()
+            .await
+    });
+
+    // A KEXINIT whose only key exchange algorithm is unknown to the server.
+    let mut kexinit = CryptoVec::new();
+    kexinit.push(msg::KEXINIT);
+    kexinit.extend(&[0; 16]);
+    for names in [
+        "unknown-kex@example.com",
+        "ssh-ed25519",
+        "aes256-ctr",
+        "aes256-ctr",
+        "hmac-sha2-256",
+        "hmac-sha2-256",
+        "none",
+        "none",
+        "",
+        "",
+    ] {
+        kexinit.extend_ssh_string(names.as_bytes());
+    }
+    kexinit.push(0);
+    kexinit.push_u32_be(0);
+    let padding = 8 - (kexinit.len() + 5) % 8 + 4;
+    let mut packet = CryptoVec::new();
+    packet.push_u32_be((kexinit.len() + padding + 1) as u32);
+    packet.push(padding as u8);
+    packet.extend(&kexinit);
+    packet.extend(&vec![0; padding]);
+
+    let mut stream = BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+    stream.write_all(b"SSH-2.0-Test\r\n").await.unwrap();
+    stream.write_all(&packet).await.unwrap();
+    let mut id = String::new();
+    stream.read_line(&mut id).await.unwrap();
+    assert!(id.starts_with("SSH-2.0-"));
+
+    // The server's KEXINIT, then its DISCONNECT.
+    let disconnect = loop {
+        let len = stream.read_u32().await.unwrap() as usize;
+        let mut packet = vec![0; len];
+        stream.read_exact(&mut packet).await.unwrap();
+        let padding = *packet.first().unwrap() as usize;
+        let payload = packet.get(1..len - padding).unwrap().to_vec();
+        if payload.first() == Some(&msg::DISCONNECT) {
+            break payload;
+        }
+    };
+    let mut reader = disconnect.reader(1);
+    assert_eq!(
+        reader.read_u32().unwrap(),
+        Disconnect::KeyExchangeFailed as u32
+    );
+    assert_eq!(
+        std::str::from_utf8(reader.read_string().unwrap()).unwrap(),
+        Error::NoCommonKexAlgo.to_string()
+    );
+    assert!(matches!(server.await.unwrap(), Err(Error::NoCommonKexAlgo)));
+}
+
+#[tokio::test]
+async fn test_first_kex_packet_follows() {
+    use std::borrow::Cow;
+
+    let mut kex = Preferred::DEFAULT.kex.to_vec();
+    kex.swap(0, 3);
+    let mut key = Preferred::DEFAULT.key.to_vec();
+    key.swap(0, 3);
+    for (name, preferred) in [
+        ("right guess", Preferred::DEFAULT),
+        (
+            "wrong kex guess",
+            Preferred {
+                kex: Cow::Owned(kex),
+                ..Preferred::DEFAULT
+            },
+        ),
+        (
+            "wrong host key guess",
+            Preferred {
+                key: Cow::Owned(key),
+                ..Preferred::DEFAULT
+            },
+        ),
+    ] {
+        debug!("first_kex_packet_follows with the {}", name);
+        let config = server::Config {
+            inactivity_timeout: None,
+            ..Default::default()
+        };
+        let client_config = client::Config {
+            preferred,
+            first_kex_packet_follows: true,
+            ..Default::default()
+        };
+        let (session, server) = connect_with(config, Server, client_config, Client).await;
+        drop(session);
+        server.await.ok();
+    }
+}
+
+#[tokio::test]
+async fn test_peer_close_with_pending_data() {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::io::AsyncReadExt;
+
+    /// Closes each channel after reading 10 bytes, like `head -c 10`.
+    struct Server {
+        received: HashMap<ChannelId, usize>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn data(
+            &mut self,
+            channel: ChannelId,
+            data: &[u8],
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            let received = self.received.entry(channel).or_default();
+            if *received < 10 {
+                *received += data.len();
+                if *received >= 10 {
+                    session.exit_status_request(channel, 0);
+                    session.eof(channel);
+                    session.close(channel);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let server = Server {
+        received: HashMap::new(),
+    };
+    let (session, _server) = connect(server::Config::default(), server).await;
+
+    for _ in 0..5 {
+        let channel = session.channel_open_session().await.unwrap();
+        let data = tokio::io::repeat(0).take(100 << 20);
+        let result = tokio::time::timeout(Duration::from_secs(10), channel.data(data))
+            .await
+            .expect("data() hung after the channel was closed");
+        assert!(matches!(result, Err(Error::ChannelClosed)));
+    }
+
+    // The session is still usable.
+    let mut channel = session.channel_open_session().await.unwrap();
+    channel.data(&b"short"[..]).await.unwrap();
+    channel.eof().await.unwrap();
+    channel.close().await.unwrap();
+    while channel.wait().await.is_some() {}
+}
+
+#[tokio::test]
+async fn test_file_auth() {
+    use std::net::SocketAddr;
+
+    use async_trait::async_trait;
+    use russh_keys::PublicKeyBase64;
+    use ssh_key::certificate::{Builder, CertType};
+    use ssh_key::{Algorithm, Certificate, PrivateKey};
+    use tokio::sync::mpsc;
+
+    use crate::server::auth::FileAuth;
+
+    struct Server {
+        auth: FileAuth,
+        auth_info: mpsc::UnboundedSender<server::AuthInfo>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            user: &str,
+            public_key: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(self.auth.auth_publickey(user, public_key))
+        }
+
+        async fn auth_openssh_certificate(
+            &mut self,
+            user: &str,
+            certificate: &Certificate,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(self.auth.auth_openssh_certificate(user, certificate))
+        }
+
+        async fn auth_succeeded(
+            &mut self,
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            self.auth.auth_succeeded(session);
+            let _ = self.auth_info.send(session.auth_info().unwrap().clone());
+            Ok(())
+        }
+    }
+
+    async fn login(
+        addr: SocketAddr,
+        user: &str,
+        key: &russh_keys::key::KeyPair,
+        cert: Option<Certificate>,
+    ) -> bool {
+        let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+            .await
+            .unwrap();
+        let key = Arc::new(key.clone());
+        match cert {
+            Some(cert) => session.authenticate_openssh_cert(user, key, cert).await,
+            None => session.authenticate_publickey(user, key).await,
+        }
+        .unwrap()
+    }
+
+    fn openssh_line(key: &russh_keys::key::KeyPair) -> String {
+        let public = key.clone_public_key().unwrap();
+        format!("{} {}", public.name(), public.public_key_base64())
+    }
+
+    fn certify(
+        ca: &PrivateKey,
+        key: &russh_keys::key::KeyPair,
+        principal: &str,
+        source_address: Option<&str>,
+        valid_before: u64,
+    ) -> Certificate {
+        let public = key.clone_public_key().unwrap().public_key_bytes();
+        let public = ssh_key::PublicKey::from_bytes(&public).unwrap();
+        let mut builder = Builder::new_with_random_nonce(
+            &mut rand_core::OsRng,
+            public.key_data().clone(),
+            0,
+            valid_before,
+        )
+        .unwrap();
+        builder.cert_type(CertType::User).unwrap();
+        builder.valid_principal(principal).unwrap();
+        builder.extension("permit-pty", "").unwrap();
+        if let Some(source_address) = source_address {
+            builder
+                .critical_option("source-address", source_address)
+                .unwrap();
+        }
+        builder.sign(ca).unwrap()
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let in_an_hour = now + 3600;
+    let alice_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+    let remote_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+    let cert_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+    let ops_ca = PrivateKey::random(&mut rand_core::OsRng, Algorithm::Ed25519).unwrap();
+    let trusted_ca = PrivateKey::random(&mut rand_core::OsRng, Algorithm::Ed25519).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("russh-file-auth-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("alice.keys"),
+        format!(
+            "# Alice's keys\n\
+             command=\"echo \\\"hello, world\\\"\",permitopen=\"localhost:80\",no-pty {} alice@laptop\n\
+             from=\"10.0.0.0/8,!127.0.0.1\" {} remote\n\
+             cert-authority,principals=\"ops,admins\",restrict,pty {}\n",
+            openssh_line(&alice_key),
+            openssh_line(&remote_key),
+            ops_ca.public_key().to_openssh().unwrap(),
+        ),
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("alice.principals"),
+        "command=\"uptime\" monitoring\n",
+    )
+    .unwrap();
+
+    let (config, socket) = listen(server::Config {
+        auth_rejection_time: std::time::Duration::from_millis(10),
+        ..Default::default()
+    })
+    .await;
+    let addr = socket.local_addr().unwrap();
+    let (tx, mut auth_info) = mpsc::unbounded_channel();
+    let (keys, principals) = (
+        dir.join("%u.keys").to_str().unwrap().to_string(),
+        dir.join("%u.principals").to_str().unwrap().to_string(),
+    );
+    let trusted = trusted_ca.public_key().clone();
+    tokio::spawn(async move {
+        loop {
+            let (socket, peer_addr) = socket.accept().await.unwrap();
+            let server = Server {
+                auth: FileAuth::new([keys.clone()])
+                    .peer_addr(Some(peer_addr))
+                    .trusted_user_ca_keys([trusted.clone()])
+                    .authorized_principals_file(principals.clone()),
+                auth_info: tx.clone(),
+            };
+            let config = config.clone();
+            tokio::spawn(async move { server::run_stream(config, socket, server).await?.await });
+        }
+    });
+
+    // Plain keys, with options.
+    assert!(login(addr, "alice", &alice_key, None).await);
+    let info = auth_info.recv().await.unwrap();
+    assert!(info.certificate.is_none());
+    let options = info.key_options.unwrap();
+    assert_eq!(options.command.as_deref(), Some("echo \"hello, world\""));
+    assert_eq!(options.permit_open, ["localhost:80"]);
+    assert!(options.no_pty);
+    assert!(!options.no_port_forwarding);
+    assert!(!login(addr, "bob", &alice_key, None).await);
+    // Excluded by `from=`.
+    assert!(!login(addr, "alice", &remote_key, None).await);
+
+    // Certificates signed by a `cert-authority` key.
+    let cert = certify(&ops_ca, &cert_key, "ops", None, in_an_hour);
+    assert!(login(addr, "alice", &cert_key, Some(cert)).await);
+    let info = auth_info.recv().await.unwrap();
+    assert_eq!(info.certificate.unwrap().valid_principals(), ["ops"]);
+    let options = info.key_options.unwrap();
+    assert!(options.no_port_forwarding);
+    assert!(!options.no_pty);
+    let cert = certify(&ops_ca, &cert_key, "dev", None, in_an_hour);
+    assert!(!login(addr, "alice", &cert_key, Some(cert)).await);
+
+    // Certificates signed by a trusted CA, with the principals file.
+    let cert = certify(&trusted_ca, &cert_key, "monitoring", None, in_an_hour);
+    assert!(login(addr, "alice", &cert_key, Some(cert)).await);
+    let info = auth_info.recv().await.unwrap();
+    assert_eq!(info.key_options.unwrap().command.as_deref(), Some("uptime"));
+    let cert = certify(&trusted_ca, &cert_key, "alice", None, in_an_hour);
+    assert!(!login(addr, "alice", &cert_key, Some(cert)).await);
+    let cert = certify(
+        &trusted_ca,
+        &cert_key,
+        "monitoring",
+        Some("10.0.0.0/8"),
+        in_an_hour,
+    );
+    assert!(!login(addr, "alice", &cert_key, Some(cert)).await);
+    let cert = certify(
+        &trusted_ca,
+        &cert_key,
+        "monitoring",
+        Some("127.0.0.1/32"),
+        in_an_hour,
+    );
+    assert!(login(addr, "alice", &cert_key, Some(cert)).await);
+    // Expired.
+    let cert = certify(&trusted_ca, &cert_key, "monitoring", None, now - 3600);
+    assert!(!login(addr, "alice", &cert_key, Some(cert)).await);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_acceptor() {
+    use async_trait::async_trait;
+
+    struct Server {
+        authenticated: std::sync::mpsc::Sender<std::thread::ThreadId>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn auth_succeeded(&mut self, _: &mut server::Session) -> Result<(), Self::Error> {
+            self.authenticated
+                .send(std::thread::current().id())
+                .unwrap();
+            Ok(())
+        }
+    }
+
+    let (config, listener) = listen(server::Config {
+        ..Default::default()
+    })
+    .await;
+    let acceptor = server::Acceptor::new(listener, config);
+    let addr = acceptor.local_addr().unwrap();
+
+    let client = tokio::spawn(async move {
+        let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+            .await
+            .unwrap();
+        let key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+        assert!(session
+            .authenticate_publickey("user", Arc::new(key))
+            .await
+            .unwrap());
+        session
+            .disconnect(Disconnect::ByApplication, "", "")
+            .await
+            .unwrap();
+    });
+
+    let pending = acceptor.accept().await.unwrap();
+    assert!(pending.peer_addr().is_some());
+    // The session runs on another thread, with its own runtime.
+    let (tx, authenticated) = std::sync::mpsc::channel();
+    let session = std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let server = Server { authenticated: tx };
+                pending.run(server).await?.await
+            })
+    });
+    client.await.unwrap();
+    assert_eq!(authenticated.recv().unwrap(), session.thread().id());
+    // The server only sees the client go away.
+    let _ = session.join().unwrap();
+}
+
+#[tokio::test]
+async fn test_connection_pool() {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use futures::{FutureExt, StreamExt};
+
+    use crate::client::{ConnectionPool, PoolConfig, PoolEvent, PoolKey};
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            _: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    let (config, listener) = listen(server::Config {
+        ..Default::default()
+    })
+    .await;
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            let session = server::run_stream(config.clone(), socket, Server {})
+                .await
+                .unwrap();
+            tokio::spawn(session);
+        }
+    });
+
+    let client_key = Arc::new(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+    let identity = client_key
+        .clone_public_key()
+        .unwrap()
+        .fingerprint(russh_keys::key::HashAlg::Sha256);
+    let pool = ConnectionPool::new(
+        PoolConfig {
+            max_channels_per_connection: 10,
+            idle_timeout: Duration::from_secs(1),
+            health_check_timeout: Some(Duration::from_secs(5)),
+        },
+        move |key: PoolKey| {
+            let client_key = client_key.clone();
+            async move {
+                let config = Arc::new(client::Config::default());
+                let mut session =
+                    client::connect(config, (key.host.as_str(), key.port), Client {}).await?;
+                assert!(session.authenticate_publickey(key.user, client_key).await?);
+                Ok(session)
+            }
+        },
+    );
+    let mut events = pool.events();
+    let mut drain_events =
+        move || std::iter::from_fn(|| events.next().now_or_never().flatten()).collect::<Vec<_>>();
+    let key = PoolKey::new("127.0.0.1", addr.port(), "user", identity);
+    let connected = PoolEvent::Connected(key.clone());
+    let disconnected = PoolEvent::Disconnected(key.clone());
+
+    // A single handshake for each batch of ten concurrent requests.
+    let channels = futures::future::join_all((0..15).map(|_| pool.channel_open_session(&key)))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(drain_events(), [connected.clone(), connected.clone()]);
+    drop(channels);
+
+    // Idle connections are reused, after a health check.
+    let lease = pool.lease(&key).await.unwrap();
+    assert!(drain_events().is_empty());
+
+    // When a session dies, its borrowers get errors, and it leaves the
+    // pool.
+    lease
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+    while !lease.is_closed() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(lease.channel_open_session().await.is_err());
+    drop(lease);
+    let channel = pool.channel_open_session(&key).await.unwrap();
+    assert_eq!(drain_events(), [disconnected.clone()]);
+    drop(channel);
+
+    // Idle connections are closed after the timeout, and the next
+    // request dials again.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(drain_events(), [disconnected.clone()]);
+    pool.channel_open_session(&key).await.unwrap();
+    assert_eq!(drain_events(), [connected]);
+}
+
+/// Authenticates with an agent holding `keys` against a server
+/// accepting `authorized`, and returning `after_failure` after a wrong
+/// key. Returns the key that authenticated, if any, and the number of
+/// keys the server checked.
+async fn authenticate_agent(
+    keys: Vec<russh_keys::key::KeyPair>,
+    authorized: Option<russh_keys::key::PublicKey>,
+    after_failure: MethodSet,
+) -> (Option<client::KeyIdentity>, usize) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use russh_keys::agent;
+
+    struct Server {
+        authorized: Option<russh_keys::key::PublicKey>,
+        after_failure: MethodSet,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            key: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            if self.authorized.as_ref() == Some(key) {
+                Ok(server::Auth::Accept)
+            } else {
+                Ok(server::Auth::Reject {
+                    proceed_with_methods: Some(self.after_failure),
+                })
+            }
+        }
+    }
+
+    let (agent_stream, agent_server_stream) = tokio::io::duplex(4096);
+    tokio::spawn(agent::server::serve(
+        futures::stream::iter(vec![Ok(agent_server_stream)]),
+        (),
+    ));
+    let mut agent = agent::client::AgentClient::connect(agent_stream);
+    for key in keys.iter() {
+        agent.add_identity(key, &[]).await.unwrap();
+    }
+
+    let config = server::Config {
+        auth_rejection_time: std::time::Duration::from_millis(10),
+        ..Default::default()
+    };
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let server = Server {
+        authorized,
+        after_failure,
+        attempts: attempts.clone(),
+    };
+    let addr = serve(config, server).await;
+
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client {})
+        .await
+        .unwrap();
+    let (_, result) = session.authenticate_agent_ex("alice", agent).await;
+    (result.unwrap(), attempts.load(Ordering::SeqCst))
+}
+
+#[tokio::test]
+async fn test_authenticate_agent() {
+    let keys: Vec<_> = (0..3)
+        .map(|_| russh_keys::key::KeyPair::generate_ed25519().unwrap())
+        .collect();
+    let all = MethodSet::PUBLICKEY | MethodSet::PASSWORD;
+
+    // One of the keys is accepted, whichever order the agent lists them in.
+    let authorized = keys.last().unwrap().clone_public_key().unwrap();
+    let (identity, attempts) =
+        authenticate_agent(keys.clone(), Some(authorized.clone()), all).await;
+    let identity = identity.unwrap();
+    assert_eq!(
+        identity.fingerprint,
+        authorized.fingerprint(russh_keys::key::HashAlg::Sha256)
+    );
+    assert_eq!(identity.public_key, authorized);
+    assert_eq!(identity.path, None);
+    assert!((1..=3).contains(&attempts));
+
+    // None of the keys is accepted.
+    assert_eq!(authenticate_agent(keys.clone(), None, all).await, (None, 3));
+
+    // The server gives up on public keys after the first one.
+    assert_eq!(
+        authenticate_agent(keys, None, MethodSet::PASSWORD).await,
+        (None, 1)
+    );
+}
+
+#[tokio::test]
+async fn test_runtime_config_update() {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    /// Counts the bytes the client receives.
+    struct Counting {
+        stream: tokio::net::TcpStream,
+        received: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for Counting {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let before = buf.filled().len();
+            let r = Pin::new(&mut self.stream).poll_read(cx, buf);
+            self.received
+                .fetch_add(buf.filled().len() - before, Ordering::SeqCst);
+            r
+        }
+    }
+
+    impl AsyncWrite for Counting {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    struct Server {}
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = Error;
+
+        async fn auth_password(&mut self, _: &str, _: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+    }
+
+    let mut config = server::Config {
+        keepalive_interval: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let server_handle = config.server_handle();
+    let addr = serve(config, Server {}).await;
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let stream = Counting {
+        stream: tokio::net::TcpStream::connect(addr).await.unwrap(),
+        received: received.clone(),
+    };
+    let mut session =
+        client::connect_stream(Arc::new(client::Config::default()), stream, Client {})
+            .await
+            .unwrap();
+    assert!(session.authenticate_password("alice", "").await.unwrap());
+
+    // Idle, no keepalive yet.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let idle = received.load(Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(received.load(Ordering::SeqCst), idle);
+
+    server_handle.update_config(|c| server::RuntimeConfig {
+        keepalive_interval: Some(Duration::from_millis(50)),
+        ..c.clone()
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    // About ten keepalives, each more than 32 bytes long.
+    assert!(received.load(Ordering::SeqCst) - idle > 5 * 32);
+    assert!(!session.is_closed());
+}
+
+fn all_signals() -> Vec<Sig> {
+    vec![
+        Sig::ABRT,
+        Sig::ALRM,
+        Sig::FPE,
+        Sig::HUP,
+        Sig::ILL,
+        Sig::INT,
+        Sig::KILL,
+        Sig::PIPE,
+        Sig::QUIT,
+        Sig::SEGV,
+        Sig::TERM,
+        Sig::USR1,
+        Sig::USR2,
+        Sig::Custom("WINCH".to_string()),
+        Sig::Custom("INFO".to_string()),
+        Sig::Custom("RESET@example.com".to_string()),
+    ]
+}
+
+mod compress {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use log::debug;
+
+    use super::server::{Server as _, Session};
+    use super::*;
+    use crate::server::Msg;
+
+    #[tokio::test]
+    async fn compress_local_test() {
+        let _ = env_logger::try_init();
+
+        let client_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+        let mut config = server::Config::default();
+        config.preferred = Preferred::COMPRESSED;
+        config.inactivity_timeout = None; // Some(std::time::Duration::from_secs(3));
+        config.auth_rejection_time = std::time::Duration::from_secs(3);
+        config
+            .keys
+            .push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+        let config = Arc::new(config);
+        let mut sh = Server {
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            id: 0,
+        };
+
+        let socket = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (socket, _) = socket.accept().await.unwrap();
+            let server = sh.new_client(socket.peer_addr().ok());
+            server::run_stream(config, socket, server).await.unwrap();
+        });
+
+        let mut config = client::Config::default();
+        config.preferred = Preferred::COMPRESSED;
+        let config = Arc::new(config);
+
+        dbg!(&addr);
+        let mut session = client::connect(config, addr, Client {}).await.unwrap();
+        let authenticated = session
+            .authenticate_publickey(
+                std::env::var("USER").unwrap_or("user".to_owned()),
+                Arc::new(client_key),
+            )
+            .await
+            .unwrap();
+        assert!(authenticated);
+        let mut channel = session.channel_open_session().await.unwrap();
+
+        let data = &b"Hello, world!"[..];
+        channel.data(data).await.unwrap();
+        let msg = channel.wait().await.unwrap();
+        match msg {
+            ChannelMsg::Data { data: msg_data } => {
+                assert_eq!(*data, *msg_data)
+            }
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+    }
+
+    #[derive(Clone)]
+    struct Server {
+        clients: Arc<Mutex<HashMap<(usize, ChannelId), super::server::Handle>>>,
+        id: usize,
+    }
+
+    impl server::Server for Server {
+        type Handler = Self;
+        fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
+            let s = self.clone();
+            self.id += 1;
+            s
+        }
+    }
+
+    #[async_trait]
+    impl server::Handler for Server {
+        type Error = super::Error;
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<Msg>,
+            session: &mut Session,
+        ) -> Result<bool, Self::Error> {
+            {
+                let mut clients = self.clients.lock().unwrap();
+                clients.insert((self.id, channel.id()), session.handle());
+            }
+            Ok(true)
+        }
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            _: &russh_keys::key::PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            debug!("auth_publickey");
+            Ok(server::Auth::Accept)
+        }
+        async fn data(
+            &mut self,
+            channel: ChannelId,
+            data: &[u8],
+            session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            debug!("server data = {:?}", std::str::from_utf8(data));
+            session.data(channel, CryptoVec::from_slice(data));
+            Ok(())
+        }
+    }
+
+    struct Client {}
+
+    #[async_trait]
+    impl client::Handler for Client {
+        type Error = super::Error;
+
+        async fn check_server_key(
+            &mut self,
+            _server_public_key: &russh_keys::key::PublicKey,
+        ) -> Result<bool, Self::Error> {
+            // println!("check_server_key: {:?}", server_public_key);
+            Ok(true)
+        }
+    }
+}
+
+mod channels {
+    use async_trait::async_trait;
+    use server::Session;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::CryptoVec;
+
+    async fn test_session<RC, RS, CH, SH, F1, F2>(
+        client_handler: CH,
+        server_handler: SH,
+        run_client: RC,
+        run_server: RS,
+    ) where
+        RC: FnOnce(crate::client::Handle<CH>) -> F1 + Send + Sync + 'static,
+        RS: FnOnce(crate::server::Handle) -> F2 + Send + Sync + 'static,
+        F1: Future<Output = crate::client::Handle<CH>> + Send + Sync + 'static,
+        F2: Future<Output = crate::server::Handle> + Send + Sync + 'static,
+        CH: crate::client::Handler + Send + Sync + 'static,
+        SH: crate::server::Handler + Send + Sync + 'static,
+    {
+        use crate::*;
+
+        let _ = env_logger::try_init();
+
+        let client_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+        let mut config = server::Config::default();
+        config.inactivity_timeout = None;
+        config.auth_rejection_time = std::time::Duration::from_secs(3);
+        config
+            .keys
+            .push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+        let config = Arc::new(config);
+        let socket = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        #[derive(Clone)]
+        struct Server {}
+
+        let server_join = tokio::spawn(async move {
+            let (socket, _) = socket.accept().await.unwrap();
+
+            server::run_stream(config, socket, server_handler)
+                .await
+                .map_err(|_| ())
+                .unwrap()
+        });
+
+        let client_join = tokio::spawn(async move {
+            let config = Arc::new(client::Config::default());
+            let mut session = client::connect(config, addr, client_handler)
+                .await
+                .map_err(|_| ())
+                .unwrap();
+            let authenticated = session
+                .authenticate_publickey(
+                    std::env::var("USER").unwrap_or("user".to_owned()),
+                    Arc::new(client_key),
+                )
+                .await
+                .unwrap();
+            assert!(authenticated);
+            session
+        });
+
+        let (server_session, client_session) = tokio::join!(server_join, client_join);
+        let client_handle = tokio::spawn(run_client(client_session.unwrap()));
+        let server_handle = tokio::spawn(run_server(server_session.unwrap().handle()));
+
+        let (server_session, client_session) = tokio::join!(server_handle, client_handle);
+        drop(client_session);
+        drop(server_session);
+    }
+
+    #[tokio::test]
+    async fn test_server_channels() {
+        #[derive(Debug)]
+        struct Client {}
+
+        #[async_trait]
+        impl client::Handler for Client {
+            type Error = crate::Error;
+
+            async fn check_server_key(
+                &mut self,
+                _server_public_key: &russh_keys::key::PublicKey,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn data(
+                &mut self,
+                channel: ChannelId,
+                data: &[u8],
+                session: &mut client::Session,
+            ) -> Result<(), Self::Error> {
+                assert_eq!(data, &b"hello world!"[..]);
+                session.data(channel, CryptoVec::from_slice(&b"hey there!"[..]));
+                Ok(())
+            }
+        }
+
+        struct ServerHandle {
+            did_auth: Option<tokio::sync::oneshot::Sender<()>>,
+        }
+
+        impl ServerHandle {
+            fn get_auth_waiter(&mut self) -> tokio::sync::oneshot::Receiver<()> {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                self.did_auth = Some(tx);
+                rx
+            }
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+            async fn auth_succeeded(&mut self, _session: &mut Session) -> Result<(), Self::Error> {
+                if let Some(a) = self.did_auth.take() {
+                    a.send(()).unwrap();
+                }
+                Ok(())
+            }
+        }
+
+        let mut sh = ServerHandle { did_auth: None };
+        let a = sh.get_auth_waiter();
+        test_session(
+            Client {},
+            sh,
+            |c| async move { c },
+            |s| async move {
+                a.await.unwrap();
+                let mut ch = s.channel_open_session().await.unwrap();
+                ch.data(&b"hello world!"[..]).await.unwrap();
+
+                let msg = ch.wait().await.unwrap();
+                if let ChannelMsg::Data { data } = msg {
+                    assert_eq!(data.as_ref(), &b"hey there!"[..]);
+                } else {
+                    panic!("Unexpected message {:?}", msg);
+                }
+                s
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_auth_info() {
+        struct ServerHandle {
+            auth_info: Option<tokio::sync::oneshot::Sender<server::AuthInfo>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+                if let (Some(tx), Some(info)) = (self.auth_info.take(), session.auth_info()) {
+                    tx.send(info.clone()).unwrap();
+                }
+                Ok(())
+            }
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {
+                auth_info: Some(tx),
+            },
+            |c| async move { c },
+            |s| async move { s },
+        )
+        .await;
+
+        let info = rx.await.unwrap();
+        assert_eq!(info.method, MethodSet::PUBLICKEY);
+        assert!(info.public_key.is_some());
+        assert_eq!(
+            info.fingerprint(),
+            info.public_key
+                .map(|k| k.fingerprint(russh_keys::key::HashAlg::Sha256))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_extensions() {
+        #[derive(Debug, PartialEq)]
+        struct Tenant(&'static str);
+
+        struct ServerHandle {
+            tenant: Option<tokio::sync::oneshot::Sender<(Option<Tenant>, u32)>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+                assert!(session.extensions_mut().insert(Tenant("acme")).is_none());
+                Ok(())
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _: Channel<server::Msg>,
+                session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                *session.extensions_mut().get_or_insert_with(|| 0u32) += 1;
+                let opened = *session.extensions().get::<u32>().unwrap();
+                if let Some(tx) = self.tenant.take() {
+                    tx.send((session.extensions_mut().remove::<Tenant>(), opened))
+                        .unwrap();
+                }
+                Ok(true)
+            }
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle { tenant: Some(tx) },
+            |c| async move {
+                c.channel_open_session().await.unwrap();
+                c
+            },
+            |s| async move { s },
+        )
+        .await;
+
+        assert_eq!(rx.await.unwrap(), (Some(Tenant("acme")), 1));
+    }
+
+    #[tokio::test]
+    async fn test_extensions_view() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Tenant(&'static str);
+
+        struct Client {}
+
+        #[async_trait]
+        impl client::Handler for Client {
+            type Error = crate::Error;
+
+            async fn check_server_key(
+                &mut self,
+                _server_public_key: &russh_keys::key::PublicKey,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn channel_open_confirmation(
+                &mut self,
+                _: ChannelId,
+                _: u32,
+                _: u32,
+                session: &mut client::Session,
+            ) -> Result<(), Self::Error> {
+                session.extensions_mut().insert(Tenant("client"));
+                Ok(())
+            }
+        }
+
+        struct ServerHandle {
+            tenant: Option<tokio::sync::oneshot::Sender<Option<Tenant>>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _: Channel<server::Msg>,
+                session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                let extensions = session.handle().extensions();
+                let tx = self.tenant.take().unwrap();
+                // Read after this callback stored the value.
+                tokio::spawn(async move { tx.send(extensions.get::<Tenant>().await) });
+                session.extensions_mut().insert(Tenant("server"));
+                Ok(true)
+            }
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle { tenant: Some(tx) },
+            |c| async move {
+                let extensions = c.extensions();
+                assert_eq!(extensions.contains::<Tenant>().await, Some(false));
+                c.channel_open_session().await.unwrap();
+                assert_eq!(
+                    extensions.clone().get::<Tenant>().await,
+                    Some(Tenant("client"))
+                );
+                c.disconnect(Disconnect::ByApplication, "", "")
+                    .await
+                    .unwrap();
+                while !c.is_closed() {
+                    tokio::task::yield_now().await;
+                }
+                assert_eq!(extensions.get::<Tenant>().await, None);
+                c
+            },
+            |s| async move { s },
+        )
+        .await;
+
+        assert_eq!(rx.await.unwrap(), Some(Tenant("server")));
+    }
+
+    /// The same handler instance sees every callback of a connection.
+    #[tokio::test]
+    async fn test_handler_state_persists() {
+        struct ServerHandle {
+            received: Vec<u8>,
+            done: Option<tokio::sync::oneshot::Sender<Vec<u8>>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _: Channel<server::Msg>,
+                _: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn data(
+                &mut self,
+                _: ChannelId,
+                data: &[u8],
+                _: &mut Session,
+            ) -> Result<(), Self::Error> {
+                self.received.extend_from_slice(data);
+                Ok(())
+            }
+
+            async fn channel_eof(
+                &mut self,
+                channel: ChannelId,
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                if let Some(tx) = self.done.take() {
+                    tx.send(std::mem::take(&mut self.received)).unwrap();
+                }
+                session.close(channel);
+                Ok(())
+            }
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {
+                received: Vec::new(),
+                done: Some(tx),
+            },
+            |c| async move {
+                let mut ch = c.channel_open_session().await.unwrap();
+                for i in 0..10u8 {
+                    ch.data(&[i][..]).await.unwrap();
+                }
+                ch.eof().await.unwrap();
+                while let Some(msg) = ch.wait().await {
+                    if let ChannelMsg::Close = msg {
+                        break;
+                    }
+                }
+                c
+            },
+            |s| async move { s },
+        )
+        .await;
+
+        assert_eq!(rx.await.unwrap(), (0..10).collect::<Vec<u8>>());
+    }
+
+    /// Requests written right after opening a channel, before it is
+    /// confirmed, reach the right channel.
+    #[tokio::test]
+    async fn test_exec_on_unconfirmed_channels() {
+        use std::collections::HashMap;
+
+        const N: u32 = 1000;
+
+        struct Client {
+            trigger: Option<ChannelId>,
+            sent: HashMap<ChannelId, u32>,
+            received: HashMap<ChannelId, u32>,
+            done: Option<tokio::sync::oneshot::Sender<bool>>,
+        }
+
+        #[async_trait]
+        impl client::Handler for Client {
+            type Error = crate::Error;
+
+            async fn check_server_key(
+                &mut self,
+                _server_public_key: &russh_keys::key::PublicKey,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn channel_open_confirmation(
+                &mut self,
+                id: ChannelId,
+                _: u32,
+                _: u32,
+                session: &mut client::Session,
+            ) -> Result<(), Self::Error> {
+                if self.trigger.is_none() {
+                    self.trigger = Some(id);
+                    for i in 0..N {
+                        let channel = session.channel_open_session()?;
+                        session.exec(channel, false, i.to_string().as_bytes());
+                        self.sent.insert(channel, i);
+                    }
+                }
+                Ok(())
+            }
+
+            async fn exit_status(
+                &mut self,
+                channel: ChannelId,
+                exit_status: u32,
+                _: &mut client::Session,
+            ) -> Result<(), Self::Error> {
+                if self.sent.contains_key(&channel) {
+                    self.received.insert(channel, exit_status);
+                }
+                if self.received.len() == N as usize {
+                    if let Some(tx) = self.done.take() {
+                        tx.send(self.received == self.sent).unwrap();
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        struct ServerHandle {}
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _: Channel<server::Msg>,
+                _: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn exec_request(
+                &mut self,
+                channel: ChannelId,
+                data: &[u8],
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                let status = std::str::from_utf8(data).unwrap().parse().unwrap();
+                session.exit_status_request(channel, status);
+                session.close(channel);
+                Ok(())
+            }
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let (handle_tx, handle_rx) = tokio::sync::oneshot::channel();
+        let (handler_tx, handler_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {
+                trigger: None,
+                sent: HashMap::new(),
+                received: HashMap::new(),
+                done: Some(tx),
+            },
+            ServerHandle {},
+            |c| async move {
+                // Opening this one makes the handler open N more.
+                let _trigger = c.channel_open_session().await.unwrap();
+                // And these are exec'd as soon as they are returned.
+                let c_ref = &c;
+                let results = futures::future::join_all((0..N).map(|i| async move {
+                    let mut ch = c_ref.channel_open_session().await.unwrap();
+                    ch.exec(false, i.to_string()).await.unwrap();
+                    while let Some(msg) = ch.wait().await {
+                        if let ChannelMsg::ExitStatus { exit_status } = msg {
+                            return exit_status == i;
+                        }
+                    }
+                    false
+                }))
+                .await;
+                handle_tx.send(results.into_iter().all(|ok| ok)).unwrap();
+                let _ = handler_tx.send(rx.await.unwrap_or(false));
+                c
+            },
+            |s| async move { s },
+        )
+        .await;
+
+        assert!(handle_rx.await.unwrap(), "wrong exit status on a channel");
+        assert!(
+            handler_rx.await.unwrap(),
+            "wrong exit status on a channel opened from the handler"
+        );
+    }
+
+    /// Data and requests written before the channel is confirmed reach
+    /// the server in the order they were written.
+    #[tokio::test]
+    async fn test_order_on_unconfirmed_channels() {
+        use std::sync::Mutex;
+
+        struct Client {
+            opened: bool,
+        }
+
+        #[async_trait]
+        impl client::Handler for Client {
+            type Error = crate::Error;
+
+            async fn check_server_key(
+                &mut self,
+                _server_public_key: &russh_keys::key::PublicKey,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn channel_open_confirmation(
+                &mut self,
+                _: ChannelId,
+                _: u32,
+                _: u32,
+                session: &mut client::Session,
+            ) -> Result<(), Self::Error> {
+                if !std::mem::replace(&mut self.opened, true) {
+                    let channel = session.channel_open_session()?;
+                    session.data(channel, CryptoVec::from_slice(b"before"));
+                    session.exec(channel, false, b"command");
+                    session.data(channel, CryptoVec::from_slice(b"after"));
+                    session.eof(channel);
+                }
+                Ok(())
+            }
+        }
+
+        struct ServerHandle {
+            log: Arc<Mutex<Vec<String>>>,
+            done: Option<tokio::sync::oneshot::Sender<()>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _: Channel<server::Msg>,
+                _: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn data(
+                &mut self,
+                _: ChannelId,
+                data: &[u8],
+                _: &mut Session,
+            ) -> Result<(), Self::Error> {
+                let data = String::from_utf8_lossy(data).into_owned();
+                self.log.lock().unwrap().push(data);
+                Ok(())
+            }
+
+            async fn exec_request(
+                &mut self,
+                _: ChannelId,
+                data: &[u8],
+                _: &mut Session,
+            ) -> Result<(), Self::Error> {
+                let command = String::from_utf8_lossy(data);
+                self.log.lock().unwrap().push(format!("exec {}", command));
+                Ok(())
+            }
+
+            async fn channel_eof(
+                &mut self,
+                _: ChannelId,
+                _: &mut Session,
+            ) -> Result<(), Self::Error> {
+                self.log.lock().unwrap().push("eof".to_string());
+                if let Some(done) = self.done.take() {
+                    let _ = done.send(());
+                }
+                Ok(())
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client { opened: false },
+            ServerHandle {
+                log: log.clone(),
+                done: Some(done),
+            },
+            |c| async move {
+                // Opening this one makes the handler open the other.
+                let _trigger = c.channel_open_session().await.unwrap();
+                done_rx.await.unwrap();
+                c
+            },
+            |s| async move { s },
+        )
+        .await;
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["before", "exec command", "after", "eof"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_events() {
+        use futures::StreamExt;
+
+        let (listening, listening_rx) = tokio::sync::oneshot::channel();
+        let (event_tx, event_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            Server {},
+            |mut c| async move {
+                // Events are dropped until the stream is taken.
+                let mut events = c.events();
+                listening.send(()).unwrap();
+                loop {
+                    match events.next().await {
+                        Some(client::ClientEvent::ForwardedTcpIp {
+                            connected_address,
+                            connected_port,
+                            originator_address,
+                            originator_port,
+                            ..
+                        }) => {
+                            event_tx
+                                .send((
+                                    connected_address,
+                                    connected_port,
+                                    originator_address,
+                                    originator_port,
+                                ))
+                                .unwrap();
+                            break;
+                        }
+                        Some(_) => {}
+                        None => panic!("no forwarded-tcpip event"),
+                    }
+                }
+                c
+            },
+            |s| async move {
+                listening_rx.await.unwrap();
+                s.channel_open_forwarded_tcpip("localhost", 8022, "10.0.0.1", 51234)
+                    .await
+                    .unwrap();
+                s
+            },
+        )
+        .await;
+
+        assert_eq!(
+            event_rx.await.unwrap(),
+            ("localhost".to_string(), 8022, "10.0.0.1".to_string(), 51234)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_channel_without_events() {
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            Server {},
+            |c| async move {
+                // Nobody takes the events, so the default handler closes
+                // the channel instead of queuing it.
+                done_rx.await.unwrap();
+                c
+            },
+            |s| async move {
+                let mut channel = s
+                    .channel_open_forwarded_tcpip("localhost", 8022, "10.0.0.1", 51234)
+                    .await
+                    .unwrap();
+                // The channel ends when the client closes it.
+                let closed = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                    while channel.wait().await.is_some() {}
+                })
+                .await;
+                closed_tx.send(closed.is_ok()).unwrap();
+                done.send(()).unwrap();
+                s
+            },
+        )
+        .await;
+
+        assert!(closed_rx.await.unwrap(), "the channel wasn't closed");
+    }
+
+    #[tokio::test]
+    async fn test_channel_streams() {
+        #[derive(Debug)]
+        struct Client {}
+
+        #[async_trait]
+        impl client::Handler for Client {
+            type Error = crate::Error;
+
+            async fn check_server_key(
+                &mut self,
+                _server_public_key: &russh_keys::key::PublicKey,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+        }
+
+        struct ServerHandle {
+            channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+        }
+
+        impl ServerHandle {
+            fn get_channel_waiter(
+                &mut self,
+            ) -> tokio::sync::oneshot::Receiver<Channel<server::Msg>> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<Channel<server::Msg>>();
+                self.channel = Some(tx);
+                rx
+            }
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                if let Some(a) = self.channel.take() {
+                    println!("channel open session {:?}", a);
+                    a.send(channel).unwrap();
+                }
+                Ok(true)
+            }
+        }
+
+        let mut sh = ServerHandle { channel: None };
+        let scw = sh.get_channel_waiter();
+
+        test_session(
+            Client {},
+            sh,
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                let mut stream = ch.into_stream();
+                stream.write_all(&b"request"[..]).await.unwrap();
+
+                let mut buf = Vec::new();
+                stream.read_buf(&mut buf).await.unwrap();
+                assert_eq!(&buf, &b"response"[..]);
+
+                stream.write_all(&b"reply"[..]).await.unwrap();
+
+                client
+            },
+            |server| async move {
+                let channel = scw.await.unwrap();
+                let mut stream = channel.into_stream();
+
+                let mut buf = Vec::new();
+                stream.read_buf(&mut buf).await.unwrap();
+                assert_eq!(&buf, &b"request"[..]);
+
+                stream.write_all(&b"response"[..]).await.unwrap();
+
+                buf.clear();
+
+                stream.read_buf(&mut buf).await.unwrap();
+                assert_eq!(&buf, &b"reply"[..]);
+
+                server
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_channel_stream_half_close() {
+        struct ServerHandle {
+            channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                if let Some(a) = self.channel.take() {
+                    a.send(channel).unwrap();
+                }
+                Ok(true)
+            }
+        }
+
+        let (tx, scw) = tokio::sync::oneshot::channel();
+        let sh = ServerHandle { channel: Some(tx) };
+        let (client_tx, client_rx) = tokio::sync::oneshot::channel();
+        let (server_tx, server_rx) = tokio::sync::oneshot::channel();
+
+        test_session(
+            Client {},
+            sh,
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                let mut stream = ch.into_stream();
+                stream.write_all(&b"request"[..]).await.unwrap();
+                // Half-close: we are done sending, but still expect a reply.
+                stream.shutdown().await.unwrap();
+
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                client_tx.send(buf).unwrap();
+
+                client
+            },
+            |server| async move {
+                let channel = scw.await.unwrap();
+                let id = channel.id();
+                let mut stream = channel.into_stream();
+
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                server_tx.send(buf).unwrap();
+
+                // The peer has sent EOF, but our side of the channel is still writable.
+                stream.write_all(&b"response"[..]).await.unwrap();
+                stream.shutdown().await.unwrap();
+                server.close(id).await.unwrap();
+
+                server
+            },
+        )
+        .await;
+
+        assert_eq!(server_rx.await.unwrap(), b"request");
+        assert_eq!(client_rx.await.unwrap(), b"response");
+    }
+
+    /// Our EOF only closes the write half: a large response sent after
+    /// it is read in full.
+    #[tokio::test]
+    async fn test_channel_stream_response_after_eof() {
+        const UPLOAD: usize = 1024 * 1024;
+        const RESPONSE: usize = 10 * 1024 * 1024;
+
+        struct ServerHandle {
+            channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                if let Some(a) = self.channel.take() {
+                    a.send(channel).unwrap();
+                }
+                Ok(true)
+            }
+        }
+
+        let (tx, scw) = tokio::sync::oneshot::channel();
+        let sh = ServerHandle { channel: Some(tx) };
+        let (client_tx, client_rx) = tokio::sync::oneshot::channel();
+        let (server_tx, server_rx) = tokio::sync::oneshot::channel();
+
+        test_session(
+            Client {},
+            sh,
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                let mut writer = ch.make_writer();
+                writer.write_all(&vec![1; UPLOAD]).await.unwrap();
+                writer.shutdown().await.unwrap();
+                // Only sent once, and nothing can be written afterwards.
+                ch.eof().await.unwrap();
+                assert!(ch.local_eof_sent());
+                assert!(writer.write_all(b"more").await.is_err());
+                assert!(!ch.remote_eof_received());
+
+                let mut stream = ch.into_stream();
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                client_tx.send(buf).unwrap();
+
+                client
+            },
+            |server| async move {
+                let mut channel = scw.await.unwrap();
+                let id = channel.id();
+
+                let mut buf = Vec::new();
+                channel.make_reader().read_to_end(&mut buf).await.unwrap();
+                assert!(channel.remote_eof_received());
+                assert!(!channel.local_eof_sent());
+                // Later readers see the EOF too.
+                assert_eq!(channel.make_reader().read(&mut [0; 1]).await.unwrap(), 0);
+                server_tx.send(buf.len()).unwrap();
+
+                let mut stream = channel.into_stream();
+                stream.write_all(&vec![2; RESPONSE]).await.unwrap();
+                stream.shutdown().await.unwrap();
+                server.close(id).await.unwrap();
+
+                server
+            },
+        )
+        .await;
+
+        assert_eq!(server_rx.await.unwrap(), UPLOAD);
+        let response = client_rx.await.unwrap();
+        assert_eq!(response.len(), RESPONSE);
+        assert!(response.iter().all(|&b| b == 2));
+    }
+
+    #[tokio::test]
+    async fn test_channel_stream_flush() {
+        use tokio::sync::mpsc;
+        use tokio::time::timeout;
+
+        const WAIT: std::time::Duration = std::time::Duration::from_secs(10);
+
+        struct ServerHandle {
+            channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+            received: mpsc::UnboundedSender<Vec<u8>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                if let Some(a) = self.channel.take() {
+                    a.send(channel).unwrap();
+                }
+                Ok(true)
+            }
+
+            async fn data(
+                &mut self,
+                _: ChannelId,
+                data: &[u8],
+                _: &mut server::Session,
+            ) -> Result<(), Self::Error> {
+                self.received.send(data.to_vec()).unwrap();
+                Ok(())
+            }
+        }
+
+        let (tx, scw) = tokio::sync::oneshot::channel();
+        let (received_tx, mut received) = mpsc::unbounded_channel();
+        let sh = ServerHandle {
+            channel: Some(tx),
+            received: received_tx,
+        };
+
+        test_session(
+            Client {},
+            sh,
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                let mut stream = ch.into_stream();
+                for request in [&b"request 1"[..], &b"request 2"[..]] {
+                    stream.write_all(request).await.unwrap();
+                    stream.flush().await.unwrap();
+                    // Nothing else is written until the server has seen the request.
+                    let data = timeout(WAIT, received.recv()).await.unwrap().unwrap();
+                    assert_eq!(data, request);
+
+                    let mut buf = [0; 8];
+                    timeout(WAIT, stream.read_exact(&mut buf))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(&buf, b"response");
+                }
+                client
+            },
+            |server| async move {
+                let channel = scw.await.unwrap();
+                let mut stream = channel.into_stream();
+                for _ in 0..2 {
+                    stream.write_all(&b"response"[..]).await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+                server
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_netconf_framing() {
+        use bytes::Bytes;
+        use futures::{SinkExt, StreamExt};
+
+        use crate::framing::{ChunkedFramer, FramingMode};
+
+        struct ServerHandle {
+            channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                if let Some(a) = self.channel.take() {
+                    a.send(channel).unwrap();
+                }
+                Ok(true)
+            }
+        }
+
+        const HELLO: &[u8] = b"<hello xmlns=\"urn:ietf:params:xml:ns:netconf:base:1.0\"><capabilities><capability>urn:ietf:params:netconf:base:1.1</capability></capabilities></hello>";
+        let rpc = Bytes::from(format!(
+            "<rpc message-id=\"1\">{}</rpc>",
+            "x".repeat(100_000)
+        ));
+        let reply = Bytes::from_static(b"<rpc-reply message-id=\"1\"><ok/></rpc-reply>");
+
+        let (tx, scw) = tokio::sync::oneshot::channel();
+        let sh = ServerHandle { channel: Some(tx) };
+
+        let (client_rpc, client_reply) = (rpc.clone(), reply.clone());
+        // Panics in the sessions are not propagated.
+        let (client_done, client_rx) = tokio::sync::oneshot::channel();
+        let (server_done, server_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            sh,
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                ch.request_subsystem(false, "netconf").await.unwrap();
+                let mut framer = ChunkedFramer::new(ch).max_chunk_size(1000);
+                framer.send(Bytes::from_static(HELLO)).await.unwrap();
+                assert_eq!(framer.next().await.unwrap().unwrap(), HELLO);
+                framer.set_mode(FramingMode::Chunked);
+                framer.send(client_rpc).await.unwrap();
+                assert_eq!(framer.next().await.unwrap().unwrap(), client_reply);
+                client_done.send(()).unwrap();
+                client
+            },
+            |server| async move {
+                let channel = scw.await.unwrap();
+                let mut framer = ChunkedFramer::new(channel);
+                framer.send(Bytes::from_static(HELLO)).await.unwrap();
+                assert_eq!(framer.next().await.unwrap().unwrap(), HELLO);
+                framer.set_mode(FramingMode::Chunked);
+                assert_eq!(framer.next().await.unwrap().unwrap(), rpc);
+                framer.send(reply).await.unwrap();
+                server_done.send(()).unwrap();
+                server
+            },
+        )
+        .await;
+
+        assert!(client_rx.await.is_ok());
+        assert!(server_rx.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_channel_stream_session_error() {
+        struct ServerHandle {}
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn data(
+                &mut self,
+                _: ChannelId,
+                _: &[u8],
+                _: &mut server::Session,
+            ) -> Result<(), Self::Error> {
+                // Kills the session, and drops the connection.
+                Err(crate::Error::Inconsistent)
+            }
+        }
+
+        // Panics in the sessions are not propagated.
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {},
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                let mut stream = ch.into_stream();
+                stream.write_all(b"die").await.unwrap();
+
+                let mut buf = [0; 8];
+                let e = stream.read(&mut buf).await.unwrap_err();
+                assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
+                assert!(e.to_string().contains("early eof"), "{}", e);
+                let e = stream.write_all(b"more").await.unwrap_err();
+                assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
+                done.send(()).unwrap();
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        assert!(done_rx.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_request_subsystem_wait() {
+        struct ServerHandle {}
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn subsystem_request(
+                &mut self,
+                channel: ChannelId,
+                name: &str,
+                session: &mut server::Session,
+            ) -> Result<(), Self::Error> {
+                if name == "sftp" {
+                    // The subsystem starts talking before the reply.
+                    session.data(channel, CryptoVec::from_slice(b"version"));
+                    session.channel_success(channel);
+                } else {
+                    session.channel_failure(channel);
+                }
+                Ok(())
+            }
+        }
+
+        // Panics in the sessions are not propagated.
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {},
+            |client| async move {
+                let mut ch = client.channel_open_session().await.unwrap();
+                assert!(!ch.request_subsystem_wait("unknown").await.unwrap());
+                assert!(ch.request_subsystem_wait("sftp").await.unwrap());
+
+                let mut buf = [0; 7];
+                ch.make_reader().read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"version");
+                done.send(()).unwrap();
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        assert!(done_rx.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_channel_idle_timeout() {
+        use std::time::{Duration, Instant};
+
+        struct ServerHandle {}
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            /// "silent" never answers, "tick" sends data every 40ms
+            /// for 200ms, then closes the channel.
+            async fn exec_request(
+                &mut self,
+                channel: ChannelId,
+                data: &[u8],
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                if data == b"tick" {
+                    let handle = session.handle();
+                    tokio::spawn(async move {
+                        for _ in 0..5 {
+                            tokio::time::sleep(Duration::from_millis(40)).await;
+                            let _ = handle.data(channel, CryptoVec::from_slice(b"tick")).await;
+                        }
+                        let _ = handle.close(channel).await;
+                    });
+                }
+                Ok(())
+            }
+        }
+
+        /// Collects the messages until the channel is done.
+        async fn wait_all(ch: &mut Channel<client::Msg>) -> Vec<ChannelMsg> {
+            let mut msgs = Vec::new();
+            while let Some(msg) = ch.wait().await {
+                msgs.push(msg);
+            }
+            msgs
+        }
+
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {},
+            |client| async move {
+                // The timer fires, and again after restarting.
+                let mut ch = client.channel_open_session().await.unwrap();
+                ch.exec(false, "silent").await.unwrap();
+                ch.set_idle_timeout(Duration::from_millis(100));
+                let start = Instant::now();
+                assert!(matches!(ch.wait().await, Some(ChannelMsg::IdleTimeout)));
+                assert!(start.elapsed() >= Duration::from_millis(100));
+                let err = ch.make_reader().read(&mut [0; 8]).await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+                // Traffic restarts the timer, and it stops once the
+                // channel is closed.
+                let mut ch = client.channel_open_session().await.unwrap();
+                ch.set_idle_timeout(Duration::from_millis(100));
+                ch.exec(false, "tick").await.unwrap();
+                let msgs = wait_all(&mut ch).await;
+                assert!(!msgs.iter().any(|m| matches!(m, ChannelMsg::IdleTimeout)));
+                assert_eq!(
+                    msgs.iter()
+                        .filter(|m| matches!(m, ChannelMsg::Data { .. }))
+                        .count(),
+                    5
+                );
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                assert!(ch.wait().await.is_none());
+
+                // The deadline fires once, traffic or not.
+                let mut ch = client.channel_open_session().await.unwrap();
+                ch.deadline(Instant::now() + Duration::from_millis(100));
+                ch.exec(false, "tick").await.unwrap();
+                let msgs = wait_all(&mut ch).await;
+                assert_eq!(
+                    msgs.iter()
+                        .filter(|m| matches!(m, ChannelMsg::IdleTimeout))
+                        .count(),
+                    1
+                );
+                done.send(()).unwrap();
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        assert!(done_rx.await.is_ok());
+    }
+
+    /// With a handler implementing none of the request callbacks, the
+    /// server still answers every request that wants a reply.
+    #[tokio::test]
+    async fn test_default_request_replies() {
+        use std::time::Duration;
+
+        struct ServerHandle {}
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+        }
+
+        /// Waits for the reply to the last request.
+        async fn reply(ch: &mut Channel<client::Msg>) -> bool {
+            loop {
+                match tokio::time::timeout(Duration::from_secs(5), ch.wait())
+                    .await
+                    .expect("no reply")
+                {
+                    Some(ChannelMsg::Success) => return true,
+                    Some(ChannelMsg::Failure) => return false,
+                    Some(_) => {}
+                    None => panic!("channel closed"),
+                }
+            }
+        }
+
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {},
+            |mut client| async move {
+                let mut ch = client.channel_open_session().await.unwrap();
+                ch.request_pty(true, "xterm", 80, 24, 0, 0, &[])
+                    .await
+                    .unwrap();
+                assert!(!reply(&mut ch).await, "pty-req");
+                ch.request_x11(true, false, "MIT-MAGIC-COOKIE-1", "00", 0)
+                    .await
+                    .unwrap();
+                assert!(!reply(&mut ch).await, "x11-req");
+                ch.set_env(true, "LANG", "C").await.unwrap();
+                assert!(!reply(&mut ch).await, "env");
+                ch.agent_forward(true).await.unwrap();
+                assert!(!reply(&mut ch).await, "auth-agent-req@openssh.com");
+                ch.exec(true, "true").await.unwrap();
+                assert!(!reply(&mut ch).await, "exec");
+                ch.request_subsystem(true, "sftp").await.unwrap();
+                assert!(!reply(&mut ch).await, "subsystem");
+                ch.request_shell(true).await.unwrap();
+                assert!(!reply(&mut ch).await, "shell");
+
+                let forward = tokio::time::timeout(
+                    Duration::from_secs(5),
+                    client.tcpip_forward("127.0.0.1", 0),
+                );
+                assert!(matches!(forward.await, Ok(Err(Error::RequestDenied))));
+                let cancel = tokio::time::timeout(
+                    Duration::from_secs(5),
+                    client.cancel_tcpip_forward("127.0.0.1", 1),
+                );
+                assert!(matches!(cancel.await, Ok(Err(Error::RequestDenied))));
+                // An unknown global request.
+                tokio::time::timeout(Duration::from_secs(5), client.keepalive())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                done.send(()).unwrap();
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        assert!(done_rx.await.is_ok());
+    }
+
+    /// The modes built with `TerminalModes` reach the server as set,
+    /// in opcode order.
+    #[tokio::test]
+    async fn test_request_pty_with() {
+        use tokio::sync::oneshot;
+
+        use crate::{TerminalModes, TerminalSize};
+
+        type Request = (String, (u32, u32, u32, u32), Vec<(Pty, u32)>);
+
+        struct ServerHandle {
+            request: Option<oneshot::Sender<Request>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn pty_request(
+                &mut self,
+                channel: ChannelId,
+                term: &str,
+                col_width: u32,
+                row_height: u32,
+                pix_width: u32,
+                pix_height: u32,
+                modes: &[(Pty, u32)],
+                session: &mut server::Session,
+            ) -> Result<(), Self::Error> {
+                if let Some(request) = self.request.take() {
+                    let size = (col_width, row_height, pix_width, pix_height);
+                    let _ = request.send((term.to_string(), size, modes.to_vec()));
+                }
+                session.channel_success(channel);
+                Ok(())
+            }
+        }
+
+        let (request, request_rx) = oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {
+                request: Some(request),
+            },
+            |client| async move {
+                let mut ch = client.channel_open_session().await.unwrap();
+                let modes = TerminalModes::new()
+                    .speeds(38400, 38400)
+                    .echo(false)
+                    .veof(4)
+                    .icanon(true)
+                    .echo(true)
+                    .set(Pty::TTY_OP_END, 1);
+                ch.request_pty_with(true, "xterm", TerminalSize::new(80, 24), &modes)
+                    .await
+                    .unwrap();
+                assert!(matches!(ch.wait().await, Some(ChannelMsg::Success)));
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        let (term, size, modes) = request_rx.await.unwrap();
+        assert_eq!(term, "xterm");
+        assert_eq!(size, (80, 24, 0, 0));
+        assert_eq!(
+            modes,
+            [
+                (Pty::VEOF, 4),
+                (Pty::ICANON, 1),
+                (Pty::ECHO, 1),
+                (Pty::TTY_OP_ISPEED, 38400),
+                (Pty::TTY_OP_OSPEED, 38400),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channel_request_replies_on_server_channels() {
+        #[derive(Debug)]
+        struct Client {
+            refused: tokio::sync::mpsc::UnboundedSender<ChannelId>,
+        }
+
+        #[async_trait]
+        impl client::Handler for Client {
+            type Error = crate::Error;
+
+            async fn check_server_key(
+                &mut self,
+                _server_public_key: &russh_keys::key::PublicKey,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn channel_request(
+                &mut self,
+                channel: ChannelId,
+                request_type: &str,
+                want_reply: bool,
+                data: &[u8],
+                session: &mut client::Session,
+            ) -> Result<(), Self::Error> {
+                assert_eq!(request_type, "exec");
+                assert!(want_reply);
+                if data.ends_with(b"accept") {
+                    session.channel_success(channel);
+                } else {
+                    // Answered later, through the handle.
+                    let _ = self.refused.send(channel);
+                }
+                Ok(())
+            }
+        }
+
+        struct ServerHandle {
+            did_auth: Option<tokio::sync::oneshot::Sender<()>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn auth_succeeded(&mut self, _session: &mut Session) -> Result<(), Self::Error> {
+                if let Some(a) = self.did_auth.take() {
+                    let _ = a.send(());
+                }
+                Ok(())
+            }
+        }
+
+        async fn reply(ch: &mut Channel<server::Msg>) -> Option<bool> {
+            loop {
+                match ch.wait().await? {
+                    ChannelMsg::Success => return Some(true),
+                    ChannelMsg::Failure => return Some(false),
+                    _ => {}
+                }
+            }
+        }
+
+        let (refused, mut refused_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (did_auth, auth_rx) = tokio::sync::oneshot::channel();
+        // Panics in the sessions are not propagated.
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client { refused },
+            ServerHandle {
+                did_auth: Some(did_auth),
+            },
+            |client| async move {
+                let id = refused_rx.recv().await.unwrap();
+                client.channel_failure(id).await.unwrap();
+                client
+            },
+            |server| async move {
+                auth_rx.await.unwrap();
+                let mut ch = server.channel_open_session().await.unwrap();
+                ch.exec(true, "accept").await.unwrap();
+                assert_eq!(reply(&mut ch).await, Some(true));
+                ch.exec(true, "refuse").await.unwrap();
+                assert_eq!(reply(&mut ch).await, Some(false));
+                done.send(()).unwrap();
+                server
+            },
+        )
+        .await;
+        assert!(done_rx.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_channel_request() {
+        use crate::keys::encoding::Encoding;
+
+        #[derive(Debug)]
+        struct Client {}
+
+        #[async_trait]
+        impl client::Handler for Client {
+            type Error = crate::Error;
+
+            async fn check_server_key(
+                &mut self,
+                _server_public_key: &russh_keys::key::PublicKey,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn channel_request(
+                &mut self,
+                channel: ChannelId,
+                request_type: &str,
+                want_reply: bool,
+                data: &[u8],
+                session: &mut client::Session,
+            ) -> Result<(), Self::Error> {
+                assert_eq!(request_type, "custom@example.com");
+                assert!(want_reply);
+                if data == [1, 2, 3] {
+                    session.channel_success(channel);
+                } else {
+                    session.channel_failure(channel);
+                }
+                Ok(())
+            }
+        }
+
+        struct ServerHandle {
+            did_auth: Option<tokio::sync::oneshot::Sender<()>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn auth_succeeded(&mut self, _session: &mut Session) -> Result<(), Self::Error> {
+                if let Some(a) = self.did_auth.take() {
+                    let _ = a.send(());
+                }
+                Ok(())
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn exec_request(
+                &mut self,
+                channel: ChannelId,
+                data: &[u8],
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                assert_eq!(data, b"true");
+                session.channel_success(channel);
+                Ok(())
+            }
+        }
+
+        let (did_auth, auth_rx) = tokio::sync::oneshot::channel();
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {
+                did_auth: Some(did_auth),
+            },
+            |client| async move {
+                let mut ch = client.channel_open_session().await.unwrap();
+                // A request the server knows, built by hand.
+                let mut command = Vec::new();
+                command.extend_ssh_string(b"true");
+                let reply = ch.send_channel_request("exec", true, &command).await;
+                assert_eq!(reply.unwrap(), Some(true));
+                let reply = ch.send_channel_request("custom@example.com", true, &[]);
+                assert_eq!(reply.await.unwrap(), Some(false));
+                let reply = ch.send_channel_request("custom@example.com", false, &[]);
+                assert_eq!(reply.await.unwrap(), None);
+                client
+            },
+            |server| async move {
+                auth_rx.await.unwrap();
+                let mut ch = server.channel_open_session().await.unwrap();
+                let reply = ch.send_channel_request("custom@example.com", true, &[1, 2, 3]);
+                assert_eq!(reply.await.unwrap(), Some(true));
+                let reply = ch.send_channel_request("custom@example.com", true, &[4]);
+                assert_eq!(reply.await.unwrap(), Some(false));
+                done.send(()).unwrap();
+                server
+            },
+        )
+        .await;
+        assert!(done_rx.await.is_ok());
+    }
+
+    /// Each of `shell`, `exec` and `subsystem` reaches its handler
+    /// method, which can answer and write the first output right away.
+    #[tokio::test]
+    async fn test_session_request_callbacks() {
+        use std::time::Duration;
+
+        struct ServerHandle {}
+
+        impl ServerHandle {
+            fn start(&self, channel: ChannelId, output: &str, session: &mut Session) {
+                session.channel_success(channel);
+                session.data(channel, CryptoVec::from_slice(output.as_bytes()));
+                session.eof(channel);
+                session.close(channel);
+            }
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn shell_request(
+                &mut self,
+                channel: ChannelId,
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                self.start(channel, "shell", session);
+                Ok(())
+            }
+
+            async fn exec_request(
+                &mut self,
+                channel: ChannelId,
+                data: &[u8],
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                let output = format!("exec {}", String::from_utf8_lossy(data));
+                self.start(channel, &output, session);
+                Ok(())
+            }
+
+            async fn subsystem_request(
+                &mut self,
+                channel: ChannelId,
+                name: &str,
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                self.start(channel, &format!("subsystem {name}"), session);
+                Ok(())
+            }
+        }
+
+        /// Returns whether the request was accepted, and the output.
+        async fn output(mut ch: Channel<client::Msg>) -> (bool, String) {
+            let mut accepted = false;
+            let mut output = Vec::new();
+            loop {
+                match tokio::time::timeout(Duration::from_secs(5), ch.wait())
+                    .await
+                    .expect("no answer")
+                {
+                    Some(ChannelMsg::Success) => accepted = true,
+                    Some(ChannelMsg::Data { data }) => {
+                        // The request is answered before the output.
+                        assert!(accepted);
+                        output.extend_from_slice(&data);
+                    }
+                    Some(ChannelMsg::Close) | None => break,
+                    Some(_) => {}
+                }
+            }
+            (accepted, String::from_utf8(output).unwrap())
+        }
+
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {},
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                ch.request_shell(true).await.unwrap();
+                assert_eq!(output(ch).await, (true, "shell".to_string()));
+
+                let ch = client.channel_open_session().await.unwrap();
+                ch.exec(true, "ls -l").await.unwrap();
+                assert_eq!(output(ch).await, (true, "exec ls -l".to_string()));
+
+                let ch = client.channel_open_session().await.unwrap();
+                ch.request_subsystem(true, "sftp").await.unwrap();
+                assert_eq!(output(ch).await, (true, "subsystem sftp".to_string()));
+
+                done.send(()).unwrap();
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        assert!(done_rx.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_exec_non_utf8_command() {
+        use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+        // Not valid UTF-8, and quoted as git does.
+        const COMMAND: &[u8] = b"git-upload-pack '\xff\xfe/repo\x80.git'";
+
+        struct ServerHandle {
+            commands: UnboundedSender<Vec<u8>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn exec_request(
+                &mut self,
+                channel: ChannelId,
+                data: &[u8],
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                let _ = self.commands.send(data.to_vec());
+                session.channel_success(channel);
+                Ok(())
+            }
+        }
+
+        assert!(String::from_utf8(COMMAND.to_vec()).is_err());
+        let (commands, mut commands_rx) = unbounded_channel();
+        test_session(
+            Client {},
+            ServerHandle { commands },
+            |client| async move {
+                let mut ch = client.channel_open_session().await.unwrap();
+                ch.exec(true, COMMAND).await.unwrap();
+                assert!(matches!(ch.wait().await, Some(ChannelMsg::Success)));
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        assert_eq!(commands_rx.recv().await.unwrap(), COMMAND);
+    }
+
+    #[tokio::test]
+    async fn test_channels_info() {
+        struct ServerHandle {
+            info: Option<tokio::sync::oneshot::Sender<Vec<ChannelInfo>>>,
+        }
 
-        let (server_session, client_session) = tokio::join!(server_join, client_join);
-        let client_handle = tokio::spawn(run_client(client_session.unwrap()));
-        let server_handle = tokio::spawn(run_server(server_session.unwrap().handle()));
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
 
-        let (server_session, client_session) = tokio::join!(server_handle, client_handle);
-        drop(client_session);
-        drop(server_session);
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+
+            async fn data(
+                &mut self,
+                _channel: ChannelId,
+                _data: &[u8],
+                session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                if let Some(info) = self.info.take() {
+                    let _ = info.send(session.channels_info());
+                }
+                Ok(())
+            }
+        }
+
+        let (info, info_rx) = tokio::sync::oneshot::channel();
+        // Panics in the sessions are not propagated.
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle { info: Some(info) },
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                ch.data(&b"hello"[..]).await.unwrap();
+                done.send(info_rx.await.unwrap()).unwrap();
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        let info = done_rx.await.unwrap();
+        assert_eq!(info.len(), 1);
+        let channel = info.first().unwrap();
+        assert_eq!(channel.kind, "session");
+        assert_eq!(
+            channel.max_packet_size,
+            client::Config::default().maximum_packet_size
+        );
+        assert!(!channel.has_pending_data);
     }
 
     #[tokio::test]
-    async fn test_server_channels() {
-        #[derive(Debug)]
-        struct Client {}
+    async fn test_stream_write_buffer() {
+        struct ServerHandle {
+            received: usize,
+            messages: usize,
+            done: Option<tokio::sync::oneshot::Sender<usize>>,
+        }
 
         #[async_trait]
-        impl client::Handler for Client {
+        impl server::Handler for ServerHandle {
             type Error = crate::Error;
 
-            async fn check_server_key(
+            async fn auth_publickey(
                 &mut self,
-                _server_public_key: &russh_keys::key::PublicKey,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut Session,
             ) -> Result<bool, Self::Error> {
                 Ok(true)
             }
 
             async fn data(
                 &mut self,
-                channel: ChannelId,
+                _channel: ChannelId,
                 data: &[u8],
-                session: &mut client::Session,
+                _session: &mut Session,
             ) -> Result<(), Self::Error> {
-                assert_eq!(data, &b"hello world!"[..]);
-                session.data(channel, CryptoVec::from_slice(&b"hey there!"[..]));
+                self.received += data.len();
+                self.messages += 1;
+                if self.received == 10_000 {
+                    if let Some(done) = self.done.take() {
+                        let _ = done.send(self.messages);
+                    }
+                }
                 Ok(())
             }
         }
 
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {
+                received: 0,
+                messages: 0,
+                done: Some(done),
+            },
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                let mut stream = ch.into_stream();
+                stream.set_write_buffer_size(stream.max_packet_size() as usize);
+                for _ in 0..100 {
+                    stream.write_all(&[0; 100]).await.unwrap();
+                }
+                stream.flush().await.unwrap();
+                // Keep the session alive until the server is done.
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        // The writes were gathered into a single message.
+        assert_eq!(done_rx.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_and_force_close_channels() {
+        struct ServerHandle {}
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                _channel: Channel<server::Msg>,
+                _session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                Ok(true)
+            }
+        }
+
+        // Panics in the sessions are not propagated.
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {},
+            |client| async move {
+                let ch = client.channel_open_session().await.unwrap();
+                ch.data(&b"hello"[..]).await.unwrap();
+                let before = client.list_channels().await;
+                let closed = client.force_close(ch.id(), "test").await;
+                let unknown = client.force_close(ch.id(), "test").await;
+                let after = client.list_channels().await;
+                done.send((ch.id(), before, closed, unknown, after))
+                    .unwrap();
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+        let (id, before, closed, unknown, after) = done_rx.await.unwrap();
+        assert_eq!(before.len(), 1);
+        let channel = before.first().unwrap();
+        assert_eq!(channel.id, id);
+        assert_eq!(channel.kind, "session");
+        assert_eq!(channel.bytes_sent, 5);
+        assert_eq!(channel.bytes_received, 0);
+        assert_eq!(
+            channel.initial_window_size,
+            server::Config::default().window_size
+        );
+        assert!(matches!(closed, Ok(true)));
+        assert!(matches!(unknown, Err(crate::Error::WrongChannel)));
+        assert!(after.is_empty());
+    }
+
+    /// `Handle::data_stream` and the handle's writers send everything
+    /// they are given. The memory they use is checked by the
+    /// `test_handle_data_stream` integration test.
+    #[tokio::test]
+    async fn test_handle_data_stream() {
+        const SIZE: u64 = 4 << 20;
+
         struct ServerHandle {
-            did_auth: Option<tokio::sync::oneshot::Sender<()>>,
+            sent: Option<tokio::sync::oneshot::Sender<Result<(), crate::Error>>>,
         }
 
-        impl ServerHandle {
-            fn get_auth_waiter(&mut self) -> tokio::sync::oneshot::Receiver<()> {
-                let (tx, rx) = tokio::sync::oneshot::channel();
-                self.did_auth = Some(tx);
-                rx
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                let handle = session.handle();
+                let id = channel.id();
+                let sent = self.sent.take().unwrap();
+                tokio::spawn(async move {
+                    assert!(matches!(
+                        handle.writer(ChannelId(id.0 + 1)).await,
+                        Err(crate::Error::WrongChannel)
+                    ));
+                    let mut writer = handle.writer_ext(id, Some(1)).await.unwrap();
+                    writer.write_all(b"starting").await.unwrap();
+                    writer.flush().await.unwrap();
+                    let data = tokio::io::repeat(0x5a).take(SIZE);
+                    let _ = sent.send(handle.data_stream(id, data).await);
+                    handle.eof(id).await.unwrap();
+                    handle.close(id).await.unwrap();
+                });
+                Ok(true)
             }
         }
 
+        let (sent, sent_rx) = tokio::sync::oneshot::channel();
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle { sent: Some(sent) },
+            |client| async move {
+                let mut ch = client.channel_open_session().await.unwrap();
+                let mut stderr: Vec<u8> = Vec::new();
+                let mut received = 0u64;
+                let mut intact = true;
+                while let Some(msg) = ch.wait().await {
+                    match msg {
+                        ChannelMsg::ExtendedData { data, ext: 1 } => {
+                            stderr.extend_from_slice(&data)
+                        }
+                        ChannelMsg::Data { data } => {
+                            received += data.len() as u64;
+                            intact &= data.iter().all(|b| *b == 0x5a);
+                        }
+                        _ => {}
+                    }
+                }
+                done.send((stderr, received, intact)).unwrap();
+                client
+            },
+            |server| async move { server },
+        )
+        .await;
+
+        assert!(matches!(sent_rx.await.unwrap(), Ok(())));
+        let (stderr, received, intact) = done_rx.await.unwrap();
+        assert_eq!(stderr, b"starting");
+        assert_eq!(received, SIZE);
+        assert!(intact);
+    }
+
+    #[tokio::test]
+    async fn test_signal_round_trip() {
+        struct ServerHandle {
+            channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+        }
+
         #[async_trait]
         impl server::Handler for ServerHandle {
             type Error = crate::Error;
@@ -262,68 +7295,224 @@ mod channels {
             ) -> Result<server::Auth, Self::Error> {
                 Ok(server::Auth::Accept)
             }
-            async fn auth_succeeded(&mut self, _session: &mut Session) -> Result<(), Self::Error> {
-                if let Some(a) = self.did_auth.take() {
-                    a.send(()).unwrap();
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                _session: &mut server::Session,
+            ) -> Result<bool, Self::Error> {
+                if let Some(a) = self.channel.take() {
+                    a.send(channel).unwrap();
+                }
+                Ok(true)
+            }
+        }
+
+        let (tx, scw) = tokio::sync::oneshot::channel();
+        let sh = ServerHandle { channel: Some(tx) };
+        let (client_tx, client_rx) = tokio::sync::oneshot::channel();
+        let (server_tx, server_rx) = tokio::sync::oneshot::channel();
+
+        test_session(
+            Client {},
+            sh,
+            |client| async move {
+                let mut ch = client.channel_open_session().await.unwrap();
+                for sig in all_signals() {
+                    ch.signal(sig).await.unwrap();
+                }
+                let mut received = Vec::new();
+                while received.len() < all_signals().len() {
+                    match ch.wait().await {
+                        Some(ChannelMsg::ExitSignal { signal_name, .. }) => {
+                            received.push(signal_name)
+                        }
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                client_tx.send(received).unwrap();
+                client
+            },
+            |server| async move {
+                let mut channel = scw.await.unwrap();
+                let mut received = Vec::new();
+                while received.len() < all_signals().len() {
+                    match channel.wait().await {
+                        Some(ChannelMsg::Signal { signal }) => received.push(signal),
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                for sig in received.iter() {
+                    server
+                        .exit_signal_request(
+                            channel.id(),
+                            sig.clone(),
+                            false,
+                            String::new(),
+                            String::new(),
+                        )
+                        .await
+                        .unwrap();
+                }
+                server_tx.send(received).unwrap();
+                server
+            },
+        )
+        .await;
+
+        assert_eq!(server_rx.await.unwrap(), all_signals());
+        assert_eq!(client_rx.await.unwrap(), all_signals());
+    }
+
+    /// Sends 1 MiB from the server to a client that grants a 32 KiB
+    /// window, with a 64 KiB high-water mark on the server. Returns the
+    /// outcome of the server session and the number of bytes the client
+    /// received.
+    async fn write_buffer_session(policy: WriteBufferPolicy) -> (Result<(), crate::Error>, usize) {
+        struct ServerHandle {
+            channel: Option<tokio::sync::oneshot::Sender<ChannelId>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                _session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                if let Some(a) = self.channel.take() {
+                    a.send(channel.id()).unwrap();
+                }
+                Ok(true)
+            }
+        }
+
+        let config = server::Config {
+            inactivity_timeout: None,
+            write_buffer_high_water_mark: 64 << 10,
+            write_buffer_policy: policy,
+            ..Default::default()
+        };
+        let (tx, channel_id) = tokio::sync::oneshot::channel();
+        let sh = ServerHandle { channel: Some(tx) };
+        let client_config = client::Config {
+            window_size: 32 << 10,
+            ..Default::default()
+        };
+        let (session, server) = connect_with(config, sh, client_config, Client).await;
+        let handle = server.handle();
+        tokio::spawn(async move {
+            let id = channel_id.await.unwrap();
+            for _ in 0..4 {
+                if handle
+                    .data(id, CryptoVec::from(vec![0; 256 << 10]))
+                    .await
+                    .is_err()
+                {
+                    break;
                 }
-                Ok(())
             }
+        });
+        let mut channel = session.channel_open_session().await.unwrap();
+        let mut received = 0;
+        while received < 1 << 20 {
+            match channel.wait().await {
+                Some(ChannelMsg::Data { data }) => received += data.len(),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        if received == 1 << 20 {
+            session
+                .disconnect(Disconnect::ByApplication, "", "")
+                .await
+                .unwrap();
         }
+        (server.await, received)
+    }
 
-        let mut sh = ServerHandle { did_auth: None };
-        let a = sh.get_auth_waiter();
-        test_session(
-            Client {},
-            sh,
-            |c| async move { c },
-            |s| async move {
-                a.await.unwrap();
-                let mut ch = s.channel_open_session().await.unwrap();
-                ch.data(&b"hello world!"[..]).await.unwrap();
+    #[tokio::test]
+    async fn test_write_buffer_backpressure() {
+        let (result, received) = write_buffer_session(WriteBufferPolicy::Backpressure).await;
+        assert!(!matches!(result, Err(crate::Error::WriteBufferOverflow)));
+        assert_eq!(received, 1 << 20);
+    }
 
-                let msg = ch.wait().await.unwrap();
-                if let ChannelMsg::Data { data } = msg {
-                    assert_eq!(data.as_ref(), &b"hey there!"[..]);
-                } else {
-                    panic!("Unexpected message {:?}", msg);
-                }
-                s
-            },
-        )
-        .await;
+    #[tokio::test]
+    async fn test_write_buffer_overflow() {
+        let (result, received) = write_buffer_session(WriteBufferPolicy::Disconnect).await;
+        assert!(matches!(result, Err(crate::Error::WriteBufferOverflow)));
+        assert!(received < 1 << 20);
     }
 
     #[tokio::test]
-    async fn test_channel_streams() {
-        #[derive(Debug)]
-        struct Client {}
+    async fn test_channel_buffer_backpressure() {
+        use std::pin::Pin;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::task::{Context, Poll};
 
-        #[async_trait]
-        impl client::Handler for Client {
-            type Error = crate::Error;
+        use tokio::io::ReadBuf;
 
-            async fn check_server_key(
-                &mut self,
-                _server_public_key: &russh_keys::key::PublicKey,
-            ) -> Result<bool, Self::Error> {
-                Ok(true)
-            }
+        /// Counts the bytes the client reads from the socket.
+        struct CountingStream {
+            inner: tokio::net::TcpStream,
+            read: Arc<AtomicUsize>,
         }
 
-        struct ServerHandle {
-            channel: Option<tokio::sync::oneshot::Sender<Channel<server::Msg>>>,
+        impl tokio::io::AsyncRead for CountingStream {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                let before = buf.filled().len();
+                let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+                self.read
+                    .fetch_add(buf.filled().len() - before, Ordering::SeqCst);
+                r
+            }
         }
 
-        impl ServerHandle {
-            fn get_channel_waiter(
-                &mut self,
-            ) -> tokio::sync::oneshot::Receiver<Channel<server::Msg>> {
-                let (tx, rx) = tokio::sync::oneshot::channel::<Channel<server::Msg>>();
-                self.channel = Some(tx);
-                rx
+        impl tokio::io::AsyncWrite for CountingStream {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<std::io::Result<usize>> {
+                Pin::new(&mut self.inner).poll_write(cx, buf)
+            }
+
+            fn poll_flush(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Pin::new(&mut self.inner).poll_flush(cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Pin::new(&mut self.inner).poll_shutdown(cx)
             }
         }
 
+        struct ServerHandle {
+            channel: Option<tokio::sync::oneshot::Sender<ChannelId>>,
+        }
+
         #[async_trait]
         impl server::Handler for ServerHandle {
             type Error = crate::Error;
@@ -339,54 +7528,72 @@ mod channels {
             async fn channel_open_session(
                 &mut self,
                 channel: Channel<server::Msg>,
-                _session: &mut server::Session,
+                _session: &mut Session,
             ) -> Result<bool, Self::Error> {
                 if let Some(a) = self.channel.take() {
-                    println!("channel open session {:?}", a);
-                    a.send(channel).unwrap();
+                    a.send(channel.id()).unwrap();
                 }
                 Ok(true)
             }
         }
 
-        let mut sh = ServerHandle { channel: None };
-        let scw = sh.get_channel_waiter();
-
-        test_session(
-            Client {},
-            sh,
-            |client| async move {
-                let ch = client.channel_open_session().await.unwrap();
-                let mut stream = ch.into_stream();
-                stream.write_all(&b"request"[..]).await.unwrap();
-
-                let mut buf = Vec::new();
-                stream.read_buf(&mut buf).await.unwrap();
-                assert_eq!(&buf, &b"response"[..]);
-
-                stream.write_all(&b"reply"[..]).await.unwrap();
-
-                client
-            },
-            |server| async move {
-                let channel = scw.await.unwrap();
-                let mut stream = channel.into_stream();
-
-                let mut buf = Vec::new();
-                stream.read_buf(&mut buf).await.unwrap();
-                assert_eq!(&buf, &b"request"[..]);
+        const TOTAL: usize = 4 << 20;
+        let data: Vec<u8> = (0..TOTAL).map(|i| (i % 251) as u8).collect();
 
-                stream.write_all(&b"response"[..]).await.unwrap();
+        let (config, socket) = listen(server::Config {
+            inactivity_timeout: None,
+            ..Default::default()
+        })
+        .await;
+        let addr = socket.local_addr().unwrap();
+        let (tx, channel_id) = tokio::sync::oneshot::channel();
+        let sent = data.clone();
+        tokio::spawn(async move {
+            let (socket, _) = socket.accept().await.unwrap();
+            let sh = ServerHandle { channel: Some(tx) };
+            let running = server::run_stream(config, socket, sh).await.unwrap();
+            let handle = running.handle();
+            let id = channel_id.await.unwrap();
+            for chunk in sent.chunks(256 << 10) {
+                handle.data(id, CryptoVec::from_slice(chunk)).await.unwrap();
+            }
+            running.await
+        });
 
-                buf.clear();
+        let read = Arc::new(AtomicUsize::new(0));
+        let stream = CountingStream {
+            inner: tokio::net::TcpStream::connect(addr).await.unwrap(),
+            read: read.clone(),
+        };
+        let config = Arc::new(client::Config {
+            channel_buffer_size: Some(4),
+            ..Default::default()
+        });
+        let mut session = client::connect_stream(config, stream, Client {})
+            .await
+            .unwrap();
+        let key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+        assert!(session
+            .authenticate_publickey("user", Arc::new(key))
+            .await
+            .unwrap());
+        let mut channel = session.channel_open_session().await.unwrap();
 
-                stream.read_buf(&mut buf).await.unwrap();
-                assert_eq!(&buf, &b"reply"[..]);
+        // A slow consumer: the session stops reading from the socket
+        // once the channel's queue is full, well before the default
+        // 2 MiB window is used up.
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(read.load(Ordering::SeqCst) < 512 << 10);
 
-                server
-            },
-        )
-        .await;
+        let mut received = Vec::new();
+        while received.len() < TOTAL {
+            match channel.wait().await {
+                Some(ChannelMsg::Data { data }) => received.extend_from_slice(&data),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        assert!(received == data);
     }
 
     #[tokio::test]
@@ -470,4 +7677,364 @@ mod channels {
         )
         .await;
     }
+
+    /// `Handle::flush` returns once the data sent before was written,
+    /// on both sides, and fails once the session has ended.
+    #[tokio::test]
+    async fn test_handle_flush() {
+        use tokio::sync::oneshot;
+
+        struct ServerHandle {
+            flushed: Option<oneshot::Sender<Result<(), crate::Error>>>,
+            received: Option<oneshot::Sender<Vec<u8>>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn channel_open_session(
+                &mut self,
+                channel: Channel<server::Msg>,
+                session: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                let handle = session.handle();
+                let flushed = self.flushed.take().unwrap();
+                tokio::spawn(async move {
+                    let data = CryptoVec::from_slice(b"from the server");
+                    handle.data(channel.id(), data).await.unwrap();
+                    let _ = flushed.send(handle.flush().await);
+                });
+                Ok(true)
+            }
+
+            async fn data(
+                &mut self,
+                _: ChannelId,
+                data: &[u8],
+                _: &mut Session,
+            ) -> Result<(), Self::Error> {
+                if let Some(received) = self.received.take() {
+                    let _ = received.send(data.to_vec());
+                }
+                Ok(())
+            }
+        }
+
+        let (flushed, flushed_rx) = oneshot::channel();
+        let (received, received_rx) = oneshot::channel();
+        test_session(
+            Client {},
+            ServerHandle {
+                flushed: Some(flushed),
+                received: Some(received),
+            },
+            |client| async move {
+                let mut ch = client.channel_open_session().await.unwrap();
+                assert!(flushed_rx.await.unwrap().is_ok());
+                match ch.wait().await {
+                    Some(ChannelMsg::Data { data }) => assert_eq!(&data[..], b"from the server"),
+                    msg => panic!("unexpected message {:?}", msg),
+                }
+
+                let data = CryptoVec::from_slice(b"from the client");
+                client.data(ch.id(), data).await.unwrap();
+                client.flush().await.unwrap();
+                assert_eq!(received_rx.await.unwrap(), b"from the client");
+
+                client
+                    .disconnect(Disconnect::ByApplication, "", "")
+                    .await
+                    .unwrap();
+                while !client.is_closed() {
+                    tokio::task::yield_now().await;
+                }
+                assert!(client.flush().await.is_err());
+                client
+            },
+            |s| async move { s },
+        )
+        .await;
+    }
+
+    /// The session refuses the forwardings its policy doesn't allow
+    /// before the handler sees them, and reports them.
+    #[tokio::test]
+    async fn test_forwarding_policy() {
+        use std::sync::Mutex;
+
+        use crate::server::{DeniedForwarding, ForwardPattern, ForwardingPolicy};
+
+        #[derive(Default)]
+        struct Events {
+            opened: Vec<(String, u32)>,
+            listened: Vec<(String, u32)>,
+            denied: Vec<DeniedForwarding>,
+        }
+
+        struct ServerHandle {
+            events: Arc<Mutex<Events>>,
+        }
+
+        #[async_trait]
+        impl server::Handler for ServerHandle {
+            type Error = crate::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _: &str,
+                _: &russh_keys::key::PublicKey,
+            ) -> Result<server::Auth, Self::Error> {
+                Ok(server::Auth::Accept)
+            }
+
+            async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+                *session.forwarding_policy_mut() = ForwardingPolicy::new()
+                    .permit_open(ForwardPattern::open("*:22"))
+                    .permit_listen(ForwardPattern::listen("localhost:*"));
+                // Further restricted for this user.
+                session.forwarding_policy_mut().restrict(
+                    ForwardingPolicy::new().permit_open(
+                        ["localhost:22", "[::1]:22"]
+                            .iter()
+                            .flat_map(|p| ForwardPattern::open(p)),
+                    ),
+                );
+                Ok(())
+            }
+
+            async fn channel_open_direct_tcpip(
+                &mut self,
+                _: Channel<server::Msg>,
+                host_to_connect: &str,
+                port_to_connect: u32,
+                _: &str,
+                _: u32,
+                _: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                let mut events = self.events.lock().unwrap();
+                events
+                    .opened
+                    .push((host_to_connect.into(), port_to_connect));
+                Ok(true)
+            }
+
+            async fn tcpip_forward(
+                &mut self,
+                address: &str,
+                port: &mut u32,
+                _: &mut Session,
+            ) -> Result<bool, Self::Error> {
+                let mut events = self.events.lock().unwrap();
+                events.listened.push((address.into(), *port));
+                Ok(true)
+            }
+
+            async fn forwarding_denied(
+                &mut self,
+                denied: &DeniedForwarding,
+                _: &mut Session,
+            ) -> Result<(), Self::Error> {
+                self.events.lock().unwrap().denied.push(denied.clone());
+                Ok(())
+            }
+        }
+
+        let events = Arc::new(Mutex::new(Events::default()));
+        test_session(
+            Client {},
+            ServerHandle {
+                events: events.clone(),
+            },
+            |mut c| async move {
+                for (host, port) in [("localhost", 22), ("::1", 22)] {
+                    assert!(c
+                        .channel_open_direct_tcpip(host, port, "1.2.3.4", 5)
+                        .await
+                        .is_ok());
+                }
+                for (host, port) in [("localhost", 23), ("127.0.0.1", 22), ("[::1]", 22)] {
+                    match c.channel_open_direct_tcpip(host, port, "1.2.3.4", 5).await {
+                        Err(Error::ChannelOpenFailure(
+                            ChannelOpenFailure::AdministrativelyProhibited,
+                        )) => {}
+                        r => panic!("unexpected result {:?}", r.map(|ch| ch.id())),
+                    }
+                }
+                assert!(c.tcpip_forward("LocalHost", 8080).await.is_ok());
+                assert!(matches!(
+                    c.tcpip_forward("0.0.0.0", 8080).await,
+                    Err(Error::RequestDenied)
+                ));
+                c
+            },
+            |s| async move { s },
+        )
+        .await;
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events.opened,
+            [("localhost".to_string(), 22), ("::1".to_string(), 22)]
+        );
+        assert_eq!(events.listened, [("LocalHost".to_string(), 8080)]);
+        assert_eq!(events.denied.len(), 4);
+        assert_eq!(
+            events.denied[0],
+            DeniedForwarding::Open {
+                host_to_connect: "localhost".into(),
+                port_to_connect: 23,
+                originator_address: "1.2.3.4".into(),
+                originator_port: 5,
+            }
+        );
+        assert_eq!(
+            events.denied[3],
+            DeniedForwarding::Listen {
+                address: "0.0.0.0".into(),
+                port: 8080,
+            }
+        );
+    }
+}
+
+/// Connects through a middlebox sending its own identification string
+/// before the server's, and returns the identification strings of the
+/// [`client::ClientEvent::MultipleServerIds`] event, if the connection
+/// succeeded.
+async fn double_banner(use_last_server_id: bool) -> Option<Vec<Vec<u8>>> {
+    use futures::StreamExt;
+    use russh_keys::key::KeyPair;
+    use tokio::io::AsyncWriteExt;
+
+    struct Server {}
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_none(&mut self, _: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+    }
+
+    let _ = env_logger::try_init();
+
+    let config = Arc::new(server::Config {
+        keys: vec![KeyPair::generate_ed25519().unwrap()],
+        ..Default::default()
+    });
+    let (server_stream, mut to_server) = tokio::io::duplex(1 << 16);
+    let (client_stream, mut to_client) = tokio::io::duplex(1 << 16);
+    tokio::spawn(server::run_stream(config, server_stream, Server {}));
+    tokio::spawn(async move {
+        to_client.write_all(b"SSH-2.0-Firewall_1.0\r\n").await?;
+        tokio::io::copy_bidirectional(&mut to_client, &mut to_server).await
+    });
+
+    let config = client::Config {
+        use_last_server_id,
+        ..Default::default()
+    };
+    let mut session = client::connect_stream(Arc::new(config), client_stream, Client {})
+        .await
+        .ok()?;
+    let mut events = session.events();
+    assert!(session.authenticate_none("user").await.unwrap());
+    session
+        .disconnect(Disconnect::ByApplication, "", "")
+        .await
+        .unwrap();
+    while let Some(event) = events.next().await {
+        if let client::ClientEvent::MultipleServerIds(ids) = event {
+            return Some(ids);
+        }
+    }
+    panic!("no MultipleServerIds event");
+}
+
+#[tokio::test]
+async fn test_use_last_server_id() {
+    let ids = double_banner(true).await.unwrap();
+    assert_eq!(ids.len(), 2);
+    assert_eq!(ids[0], b"SSH-2.0-Firewall_1.0");
+    assert!(ids[1].starts_with(b"SSH-2.0-russh_"));
+}
+
+#[tokio::test]
+async fn test_double_banner_fails_by_default() {
+    assert!(double_banner(false).await.is_none());
+}
+
+/// The timing of sessions follows Tokio's clock, so that tests can
+/// pause it and move it on instead of waiting.
+#[tokio::test(start_paused = true)]
+async fn test_paused_clock() {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use russh_keys::key::KeyPair;
+
+    struct Server {
+        age: Arc<Mutex<Option<Duration>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl server::Handler for Server {
+        type Error = crate::Error;
+
+        async fn auth_none(&mut self, _: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<server::Msg>,
+            session: &mut server::Session,
+        ) -> Result<bool, Self::Error> {
+            *self.age.lock().unwrap() = session.age();
+            Ok(true)
+        }
+    }
+
+    let _ = env_logger::try_init();
+
+    let config = Arc::new(server::Config {
+        keys: vec![KeyPair::generate_ed25519().unwrap()],
+        inactivity_timeout: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    });
+    let age = Arc::new(Mutex::new(None));
+    let (server_stream, client_stream) = tokio::io::duplex(1 << 16);
+    let server = Server { age: age.clone() };
+    let server = tokio::spawn(async move {
+        server::run_stream(config, server_stream, server)
+            .await?
+            .await
+    });
+    let mut session = client::connect_stream(Default::default(), client_stream, Client {})
+        .await
+        .unwrap();
+    assert!(session.authenticate_none("user").await.unwrap());
+
+    tokio::time::sleep(Duration::from_secs(3000)).await;
+    session.channel_open_session().await.unwrap();
+    let age = age.lock().unwrap().unwrap();
+    assert!(age >= Duration::from_secs(3000), "{:?}", age);
+
+    // An hour without any message from the client.
+    let idle = tokio::time::Instant::now();
+    assert!(matches!(
+        server.await.unwrap(),
+        Err(Error::InactivityTimeout)
+    ));
+    assert_eq!(idle.elapsed().as_secs(), 3600);
 }
@@ -0,0 +1,121 @@
+//! `Handle::data_stream` sends a payload much larger than the memory
+//! it uses. This is the only test of this binary, so that the limit it
+//! sets and the peak memory it measures are its own.
+#![cfg(unix)]
+
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use russh::keys::key;
+use russh::server::{self, Auth, Msg, Session};
+use russh::{client, Channel, ChannelMsg, Preferred};
+use tokio::io::AsyncReadExt;
+
+const SIZE: u64 = 1 << 30;
+
+/// Sends 1 GiB, which takes most of a minute in a debug build. Run it
+/// with `cargo test -p russh --test test_handle_data_stream -- --ignored`.
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_stream_memory() -> Result<(), anyhow::Error> {
+    env_logger::init();
+
+    // Lower than a single packet: locking the buffers fails, which
+    // must not matter.
+    let mut limit = unsafe { std::mem::zeroed::<libc::rlimit>() };
+    assert_eq!(
+        unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) },
+        0
+    );
+    limit.rlim_cur = 16 << 10;
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &limit) }, 0);
+
+    // The ciphers are not what this test measures.
+    let preferred = Preferred {
+        cipher: Cow::Borrowed(&[russh::cipher::NONE]),
+        ..Preferred::DEFAULT
+    };
+    let config = Arc::new(server::Config {
+        keys: vec![key::KeyPair::generate_ed25519().unwrap()],
+        preferred: preferred.clone(),
+        ..Default::default()
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (sent, sent_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        server::run_stream(config, socket, Server { sent: Some(sent) })
+            .await?
+            .await
+    });
+
+    let config = Arc::new(client::Config {
+        preferred,
+        ..Default::default()
+    });
+    let mut session = client::connect(config, addr, Client).await?;
+    assert!(session.authenticate_none("user").await?);
+    let mut channel = session.channel_open_session().await?;
+    let mut received = 0u64;
+    while let Some(msg) = channel.wait().await {
+        if let ChannelMsg::Data { data } = msg {
+            assert!(data.iter().all(|b| *b == 0x5a));
+            received += data.len() as u64;
+        }
+    }
+    sent_rx.await??;
+    assert_eq!(received, SIZE);
+
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) }, 0);
+    // In kilobytes on Linux, in bytes on macOS.
+    let max_rss = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64
+    } else {
+        usage.ru_maxrss as u64 * 1024
+    };
+    assert!(max_rss < SIZE / 8, "peak RSS of {} bytes", max_rss);
+    Ok(())
+}
+
+struct Server {
+    sent: Option<tokio::sync::oneshot::Sender<Result<(), russh::Error>>>,
+}
+
+#[async_trait::async_trait]
+impl server::Handler for Server {
+    type Error = russh::Error;
+
+    async fn auth_none(&mut self, _: &str) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let handle = session.handle();
+        let id = channel.id();
+        let sent = self.sent.take().unwrap();
+        tokio::spawn(async move {
+            let data = tokio::io::repeat(0x5a).take(SIZE);
+            let _ = sent.send(handle.data_stream(id, data).await);
+            let _ = handle.eof(id).await;
+            let _ = handle.close(id).await;
+        });
+        Ok(true)
+    }
+}
+
+struct Client;
+
+#[async_trait::async_trait]
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _: &key::PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
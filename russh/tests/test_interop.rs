@@ -0,0 +1,1302 @@
+//! Interoperability matrix against other SSH implementations, running
+//! in docker containers built from `tests/interop`.
+//!
+//! The russh client is run against each target's server, and each
+//! target's client against a russh server, with every kex, cipher and
+//! mac algorithm both sides implement, ECDSA host keys, then password
+//! and public key authentication (with an Ed25519 key and an ECDSA key
+//! on each curve), exec with an exit status, the sftp subsystem, local
+//! and remote forwarding, rekeying during bulk transfers, and
+//! `ping@openssh.com`, `eow@openssh.com` and
+//! `no-more-sessions@openssh.com` where the target implements them.
+//!
+//! Failing cases are retried, and every result is written as a JSON
+//! line to `RUSSH_INTEROP_RESULTS` (by default `interop-results.jsonl`
+//! in the cargo target directory), so that the run can gate releases.
+//!
+//! These need docker and only run when `RUSSH_INTEROP_TESTS` is set.
+//! `RUSSH_INTEROP_TARGETS` restricts the run to a comma-separated list
+//! of target names, and `RUSSH_INTEROP_RETRIES` sets the number of
+//! attempts per case (3 by default).
+
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io::Write as _;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use russh::client;
+use russh::keys::key;
+use russh::server::{self, Auth, Msg, Server as _, Session};
+use russh::{cipher, kex, mac, Channel, ChannelId, ChannelMsg, Limits, Preferred, SshId};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+
+fn enabled() -> bool {
+    std::env::var_os("RUSSH_INTEROP_TESTS").is_some()
+}
+
+const CASE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Size of the transfers in the rekey cases, 16 times the rekey limit.
+const BULK_SIZE: usize = 16 << 20;
+
+/// The ECDSA algorithms, each with a client key generated by `keygen`
+/// in `/keys/id_<algorithm>`.
+const ECDSA: &[key::Name] = &[
+    key::ECDSA_SHA2_NISTP256,
+    key::ECDSA_SHA2_NISTP384,
+    key::ECDSA_SHA2_NISTP521,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    OpenSsh,
+    Dropbear,
+}
+
+struct Target {
+    name: &'static str,
+    dockerfile: &'static str,
+    base: &'static str,
+    kind: Kind,
+}
+
+const TARGETS: &[Target] = &[
+    Target {
+        name: "openssh-8.2",
+        dockerfile: "openssh.Dockerfile",
+        base: "ubuntu:20.04",
+        kind: Kind::OpenSsh,
+    },
+    Target {
+        name: "openssh-8.4",
+        dockerfile: "openssh.Dockerfile",
+        base: "debian:bullseye",
+        kind: Kind::OpenSsh,
+    },
+    Target {
+        name: "openssh-8.9",
+        dockerfile: "openssh.Dockerfile",
+        base: "ubuntu:22.04",
+        kind: Kind::OpenSsh,
+    },
+    Target {
+        name: "openssh-9.2",
+        dockerfile: "openssh.Dockerfile",
+        base: "debian:bookworm",
+        kind: Kind::OpenSsh,
+    },
+    Target {
+        name: "openssh-10.0",
+        dockerfile: "openssh.Dockerfile",
+        base: "debian:trixie",
+        kind: Kind::OpenSsh,
+    },
+    Target {
+        name: "dropbear-2022.83",
+        dockerfile: "dropbear.Dockerfile",
+        base: "alpine:3.19",
+        kind: Kind::Dropbear,
+    },
+];
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_interop_matrix() -> Result<(), anyhow::Error> {
+    if !enabled() {
+        return Ok(());
+    }
+    let _ = env_logger::try_init();
+
+    let selected = std::env::var("RUSSH_INTEROP_TARGETS").ok();
+    let selected: Option<Vec<&str>> = selected.as_deref().map(|s| s.split(',').collect());
+    let retries = match std::env::var("RUSSH_INTEROP_RETRIES") {
+        Ok(n) => n.parse().context("RUSSH_INTEROP_RETRIES")?,
+        Err(_) => 3,
+    };
+    let mut report = Report::new(retries)?;
+
+    for target in TARGETS {
+        if let Some(ref selected) = selected {
+            if !selected.contains(&target.name) {
+                continue;
+            }
+        }
+        let image = build_image(target).await?;
+        let keys = TempDir::new(target.name)?;
+        docker([
+            "run",
+            "--rm",
+            "-v",
+            keys.mount().as_str(),
+            &image,
+            "/interop/keygen",
+        ])
+        .await?;
+        let key = Arc::new(russh::keys::load_secret_key(keys.0.join("id"), None)?);
+
+        report.target = target.name;
+        russh_client_cases(&mut report, &image, &keys, &key).await?;
+        russh_server_cases(&mut report, target, &image, &keys, &key).await?;
+    }
+
+    report.summary()
+}
+
+/// Runs the russh client against the target's server.
+async fn russh_client_cases(
+    report: &mut Report,
+    image: &str,
+    keys: &TempDir,
+    key: &Arc<key::KeyPair>,
+) -> Result<(), anyhow::Error> {
+    report.direction = "russh-client";
+    let container = Container::start_server(image, keys).await?;
+    let addr = container.addr;
+
+    // The server's lists aren't known in advance: algorithms it doesn't
+    // implement fail negotiation and are skipped.
+    for kex in russh_kex() {
+        let preferred = Preferred {
+            kex: Cow::Owned(vec![
+                kex,
+                kex::EXTENSION_SUPPORT_AS_CLIENT,
+                kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+            ]),
+            ..Preferred::DEFAULT
+        };
+        report
+            .case(format!("kex {}", kex.as_ref()), || {
+                algorithm_case(addr, key, preferred.clone())
+            })
+            .await;
+    }
+    for (cipher, mac) in cipher_mac_pairs(&russh_ciphers(), &russh_macs()) {
+        let preferred = Preferred {
+            cipher: Cow::Owned(vec![cipher]),
+            mac: Cow::Owned(mac.into_iter().collect()),
+            ..Preferred::DEFAULT
+        };
+        report
+            .case(cipher_mac_case_name(cipher, mac), || {
+                algorithm_case(addr, key, preferred.clone())
+            })
+            .await;
+    }
+
+    for algorithm in ECDSA.iter().copied() {
+        let preferred = Preferred {
+            key: Cow::Owned(vec![algorithm]),
+            ..Preferred::DEFAULT
+        };
+        report
+            .case(format!("host key {}", algorithm.as_ref()), || {
+                algorithm_case(addr, key, preferred.clone())
+            })
+            .await;
+    }
+
+    report
+        .case("auth password".into(), || async move {
+            let (mut session, _) = connect(addr, client::Config::default()).await?;
+            expect(session.authenticate_password("russh", "russh").await?)
+        })
+        .await;
+    report
+        .case("auth publickey".into(), || async move {
+            let (mut session, _) = connect(addr, client::Config::default()).await?;
+            expect(session.authenticate_publickey("russh", key.clone()).await?)
+        })
+        .await;
+    for algorithm in ECDSA {
+        let path = keys.0.join(format!("id_{}", algorithm.as_ref()));
+        report
+            .case(format!("auth publickey {}", algorithm.as_ref()), || {
+                let path = path.clone();
+                async move {
+                    let key = Arc::new(russh::keys::load_secret_key(path, None)?);
+                    let (mut session, _) = connect(addr, client::Config::default()).await?;
+                    expect(session.authenticate_publickey("russh", key).await?)
+                }
+            })
+            .await;
+    }
+    report
+        .case("exec exit status".into(), || async move {
+            let (session, _) = authenticated(addr, key, client::Config::default()).await?;
+            let (_, status) = exec(&session, "exit 42", None).await?;
+            expect_eq(status, Some(42))
+        })
+        .await;
+    report
+        .case("labeled channel".into(), || async move {
+            // The server ignores the label request, which asks for no reply.
+            let config = client::Config {
+                send_channel_labels: true,
+                ..Default::default()
+            };
+            let (session, _) = authenticated(addr, key, config).await?;
+            let mut channel = session.channel_open_session_labeled("hop1").await?;
+            channel.exec(true, "exit 7").await?;
+            let mut status = None;
+            while let Some(msg) = channel.wait().await {
+                match msg {
+                    ChannelMsg::ExitStatus { exit_status } => status = Some(exit_status),
+                    ChannelMsg::Failure => bail!("exec refused"),
+                    _ => {}
+                }
+            }
+            expect_eq(
+                (channel.label(), status),
+                (Some("hop1".to_string()), Some(7)),
+            )
+        })
+        .await;
+    report
+        .case("sftp subsystem".into(), || sftp_case(addr, key))
+        .await;
+    report
+        .case("local forward".into(), || local_forward_case(addr, key))
+        .await;
+    report
+        .case("remote forward".into(), || remote_forward_case(addr, key))
+        .await;
+    report
+        .case("rekey download".into(), || async move {
+            let (session, _) = authenticated(addr, key, rekey_config()).await?;
+            let command = format!("head -c {} /dev/zero", BULK_SIZE);
+            let (output, status) = exec(&session, &command, None).await?;
+            expect_eq((output.len(), status), (BULK_SIZE, Some(0)))
+        })
+        .await;
+    report
+        .case("rekey upload".into(), || async move {
+            let (session, _) = authenticated(addr, key, rekey_config()).await?;
+            let input = (0..BULK_SIZE).map(|i| i as u8).collect();
+            let (output, status) = exec(&session, "wc -c", Some(input)).await?;
+            let count = String::from_utf8_lossy(&output).trim().to_string();
+            expect_eq((count, status), (BULK_SIZE.to_string(), Some(0)))
+        })
+        .await;
+    report
+        .case("ping".into(), || async move {
+            let (session, _) = authenticated(addr, key, client::Config::default()).await?;
+            match session.ping(b"russh").await {
+                Ok(_) => Ok(Outcome::Pass),
+                Err(russh::Error::PingUnsupported) => {
+                    Ok(Outcome::Skip("not implemented by the server".into()))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await;
+    report
+        .case("no more sessions".into(), || async move {
+            let (session, _) = authenticated(addr, key, client::Config::default()).await?;
+            exec(&session, "true", None).await?;
+            session.no_more_sessions().await?;
+            // OpenSSH disconnects rather than refusing the channel.
+            match session.channel_open_session().await {
+                Ok(_) => Ok(Outcome::Skip("not implemented by the server".into())),
+                Err(_) => Ok(Outcome::Pass),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// Runs the target's client against a russh server.
+async fn russh_server_cases(
+    report: &mut Report,
+    target: &Target,
+    image: &str,
+    keys: &TempDir,
+    key: &Arc<key::KeyPair>,
+) -> Result<(), anyhow::Error> {
+    report.direction = "russh-server";
+    let mut authorized = vec![key.clone_public_key()?];
+    for algorithm in ECDSA {
+        let path = keys.0.join(format!("id_{}", algorithm.as_ref()));
+        authorized.push(russh::keys::load_secret_key(path, None)?.clone_public_key()?);
+    }
+    let port = start_russh_server(authorized.clone(), None).await?;
+    let container = Container::start_client(image, keys).await?;
+    let client = SshClient {
+        kind: target.kind,
+        container: &container,
+        port,
+    };
+    let supported = container.algorithms().await?;
+    let offered = |kind: &str, name: &str| {
+        supported
+            .iter()
+            .any(|(k, names)| k == kind && names.iter().any(|n| n == name))
+    };
+
+    if target.kind == Kind::OpenSsh {
+        for kex in russh_kex()
+            .into_iter()
+            .filter(|k| offered("kex", k.as_ref()))
+        {
+            let option = format!("-o KexAlgorithms={}", kex.as_ref());
+            report
+                .case(format!("kex {}", kex.as_ref()), || {
+                    client.expect_status(&option, ClientAuth::PublicKey, "true", 0)
+                })
+                .await;
+        }
+    }
+    let ciphers: Vec<_> = russh_ciphers()
+        .into_iter()
+        .filter(|c| offered("cipher", c.as_ref()))
+        .collect();
+    let macs: Vec<_> = russh_macs()
+        .into_iter()
+        .filter(|m| offered("mac", m.as_ref()))
+        .collect();
+    for (cipher, mac) in cipher_mac_pairs(&ciphers, &macs) {
+        let mut options = format!("-c {}", cipher.as_ref());
+        if let Some(mac) = mac {
+            let _ = write!(options, " -m {}", mac.as_ref());
+        }
+        report
+            .case(cipher_mac_case_name(cipher, mac), || {
+                client.expect_status(&options, ClientAuth::PublicKey, "true", 0)
+            })
+            .await;
+    }
+
+    report
+        .case("auth password".into(), || {
+            client.expect_status("", ClientAuth::Password, "true", 0)
+        })
+        .await;
+    report
+        .case("auth publickey".into(), || {
+            client.expect_status("", ClientAuth::PublicKey, "true", 0)
+        })
+        .await;
+    if target.kind == Kind::OpenSsh {
+        for algorithm in ECDSA.iter().copied() {
+            let option = format!("-o HostKeyAlgorithms={}", algorithm.as_ref());
+            report
+                .case(format!("host key {}", algorithm.as_ref()), || {
+                    client.expect_status(&option, ClientAuth::PublicKey, "true", 0)
+                })
+                .await;
+            report
+                .case(format!("auth publickey {}", algorithm.as_ref()), || {
+                    client.expect_status("", ClientAuth::Identity(algorithm), "true", 0)
+                })
+                .await;
+        }
+    }
+    report
+        .case("exec exit status".into(), || {
+            client.expect_status("", ClientAuth::PublicKey, "exit 42", 42)
+        })
+        .await;
+    if target.kind == Kind::OpenSsh {
+        // OpenSSH 9.8 and later send chaff pings while keys are typed
+        // in an interactive session.
+        report
+            .case("obscured keystrokes".into(), || async move {
+                let option = "-o ObscureKeystrokeTiming=yes";
+                let probe = format!("ssh -F /dev/null -G {} host >/dev/null", option);
+                if client.container.exec(&probe).await?.0 != Some(0) {
+                    return Ok(Outcome::Skip("not implemented by the client".into()));
+                }
+                let keys = "for k in a b c d e f g h q; do printf $k; sleep 0.2; done";
+                client
+                    .expect_piped(
+                        keys,
+                        &format!("-tt {}", option),
+                        ClientAuth::PublicKey,
+                        "echo",
+                        0,
+                    )
+                    .await
+            })
+            .await;
+
+        // OpenSSH only sends its own extensions to OpenSSH peers.
+        let server_id = SshId::Standard("SSH-2.0-OpenSSH_9.9 russh".into());
+        let port = start_russh_server(authorized, Some(server_id)).await?;
+        let client = SshClient { port, ..client };
+        report
+            .case("end of write".into(), || {
+                client.expect_closed_output("", ClientAuth::PublicKey, "flood", 0)
+            })
+            .await;
+        report
+            .case("no more sessions".into(), || {
+                client.expect_status("", ClientAuth::PublicKey, "sessions", 0)
+            })
+            .await;
+    }
+    Ok(())
+}
+
+fn russh_kex() -> Vec<kex::Name> {
+    kex::ALL_KEX_ALGORITHMS
+        .iter()
+        .map(|n| **n)
+        .filter(|n| *n != kex::NONE)
+        .collect()
+}
+
+fn russh_ciphers() -> Vec<cipher::Name> {
+    cipher::ALL_CIPHERS
+        .iter()
+        .map(|n| **n)
+        .filter(|n| *n != cipher::NONE && *n != cipher::CLEAR)
+        .collect()
+}
+
+fn russh_macs() -> Vec<mac::Name> {
+    mac::ALL_MAC_ALGORITHMS
+        .iter()
+        .map(|n| **n)
+        .filter(|n| *n != mac::NONE)
+        .collect()
+}
+
+/// Each cipher with each mac, except for the AEAD ciphers that don't
+/// use a separate mac and are only paired with `None`.
+fn cipher_mac_pairs(
+    ciphers: &[cipher::Name],
+    macs: &[mac::Name],
+) -> Vec<(cipher::Name, Option<mac::Name>)> {
+    let mut pairs = Vec::new();
+    for cipher in ciphers.iter().copied() {
+        if cipher == cipher::AES_256_GCM || cipher == cipher::CHACHA20_POLY1305 {
+            pairs.push((cipher, None));
+        } else {
+            pairs.extend(macs.iter().map(|mac| (cipher, Some(*mac))));
+        }
+    }
+    pairs
+}
+
+fn cipher_mac_case_name(cipher: cipher::Name, mac: Option<mac::Name>) -> String {
+    match mac {
+        Some(mac) => format!("cipher {} mac {}", cipher.as_ref(), mac.as_ref()),
+        None => format!("cipher {}", cipher.as_ref()),
+    }
+}
+
+/// Rekeys every megabyte in each direction.
+fn rekey_config() -> client::Config {
+    client::Config {
+        limits: Limits::new(1 << 20, 1 << 20, Duration::from_secs(3600)),
+        ..Default::default()
+    }
+}
+
+fn expect(ok: bool) -> Result<Outcome, anyhow::Error> {
+    if ok {
+        Ok(Outcome::Pass)
+    } else {
+        bail!("rejected")
+    }
+}
+
+fn expect_eq<T: PartialEq + std::fmt::Debug>(
+    got: T,
+    expected: T,
+) -> Result<Outcome, anyhow::Error> {
+    if got == expected {
+        Ok(Outcome::Pass)
+    } else {
+        bail!("expected {:?}, got {:?}", expected, got)
+    }
+}
+
+async fn algorithm_case(
+    addr: SocketAddr,
+    key: &Arc<key::KeyPair>,
+    preferred: Preferred,
+) -> Result<Outcome, anyhow::Error> {
+    let config = client::Config {
+        preferred,
+        ..Default::default()
+    };
+    let session = match authenticated(addr, key, config).await {
+        Ok((session, _)) => session,
+        Err(e) => {
+            return match e.downcast_ref::<russh::Error>() {
+                Some(
+                    russh::Error::NoCommonKexAlgo
+                    | russh::Error::NoCommonKeyAlgo
+                    | russh::Error::NoCommonCipher
+                    | russh::Error::NoCommonMac,
+                ) => Ok(Outcome::Skip("not implemented by the server".into())),
+                _ => Err(e),
+            }
+        }
+    };
+    let (output, status) = exec(&session, "echo interop", None).await?;
+    expect_eq((output, status), (b"interop\n".to_vec(), Some(0)))
+}
+
+async fn sftp_case(addr: SocketAddr, key: &Arc<key::KeyPair>) -> Result<Outcome, anyhow::Error> {
+    let (session, _) = authenticated(addr, key, client::Config::default()).await?;
+    let mut channel = session.channel_open_session().await?;
+    channel.request_subsystem(true, "sftp").await?;
+    // SSH_FXP_INIT, version 3.
+    channel.data(&[0, 0, 0, 5, 1, 0, 0, 0, 3][..]).await?;
+    let mut reply = Vec::new();
+    while reply.len() < 9 {
+        match channel.wait().await {
+            Some(ChannelMsg::Data { data }) => reply.extend_from_slice(&data),
+            Some(ChannelMsg::Failure) => bail!("subsystem refused"),
+            Some(_) => {}
+            None => bail!("channel closed after {:?}", reply),
+        }
+    }
+    // SSH_FXP_VERSION.
+    expect_eq(reply.get(4), Some(&2))
+}
+
+async fn local_forward_case(
+    addr: SocketAddr,
+    key: &Arc<key::KeyPair>,
+) -> Result<Outcome, anyhow::Error> {
+    let (session, _) = authenticated(addr, key, client::Config::default()).await?;
+    // The target's own SSH server is reachable from inside the container.
+    let mut channel = session
+        .channel_open_direct_tcpip("127.0.0.1", 22, "127.0.0.1", 12345)
+        .await?;
+    let mut banner = Vec::new();
+    while !banner.contains(&b'\n') {
+        match channel.wait().await {
+            Some(ChannelMsg::Data { data }) => banner.extend_from_slice(&data),
+            Some(_) => {}
+            None => bail!("channel closed after {:?}", banner),
+        }
+    }
+    if banner.starts_with(b"SSH-2.0-") {
+        Ok(Outcome::Pass)
+    } else {
+        bail!("unexpected banner {:?}", String::from_utf8_lossy(&banner))
+    }
+}
+
+async fn remote_forward_case(
+    addr: SocketAddr,
+    key: &Arc<key::KeyPair>,
+) -> Result<Outcome, anyhow::Error> {
+    let (mut session, mut forwarded) = authenticated(addr, key, client::Config::default()).await?;
+    let port = session.tcpip_forward("127.0.0.1", 0).await?;
+    if port == 0 {
+        bail!("the server didn't report the allocated port");
+    }
+    let channel = session.channel_open_session().await?;
+    let command = format!("echo interop | nc -N 127.0.0.1 {}", port);
+    channel.exec(true, command).await?;
+
+    let mut forwarded = forwarded
+        .recv()
+        .await
+        .ok_or_else(|| anyhow!("no forwarded connection"))?;
+    let mut received = Vec::new();
+    forwarded.make_reader().read_to_end(&mut received).await?;
+    expect_eq(received, b"interop\n".to_vec())
+}
+
+struct Client {
+    forwarded: mpsc::UnboundedSender<Channel<client::Msg>>,
+}
+
+#[async_trait::async_trait]
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _: &key::PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<client::Msg>,
+        _: &str,
+        _: u32,
+        _: &str,
+        _: u32,
+        _: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        let _ = self.forwarded.send(channel);
+        Ok(())
+    }
+}
+
+type Forwarded = mpsc::UnboundedReceiver<Channel<client::Msg>>;
+
+async fn connect(
+    addr: SocketAddr,
+    config: client::Config,
+) -> Result<(client::Handle<Client>, Forwarded), anyhow::Error> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let client = Client { forwarded: sender };
+    let session = client::connect(Arc::new(config), addr, client).await?;
+    Ok((session, receiver))
+}
+
+async fn authenticated(
+    addr: SocketAddr,
+    key: &Arc<key::KeyPair>,
+    config: client::Config,
+) -> Result<(client::Handle<Client>, Forwarded), anyhow::Error> {
+    let (mut session, forwarded) = connect(addr, config).await?;
+    if !session.authenticate_publickey("russh", key.clone()).await? {
+        bail!("public key rejected");
+    }
+    Ok((session, forwarded))
+}
+
+/// Runs `command`, sending it `input` followed by EOF, and returns its
+/// standard output and exit status.
+async fn exec(
+    session: &client::Handle<Client>,
+    command: &str,
+    input: Option<Vec<u8>>,
+) -> Result<(Vec<u8>, Option<u32>), anyhow::Error> {
+    let mut channel = session.channel_open_session().await?;
+    channel.exec(true, command).await?;
+    if let Some(input) = input {
+        channel.data(&input[..]).await?;
+        channel.eof().await?;
+    }
+    let mut output = Vec::new();
+    let mut status = None;
+    while let Some(msg) = channel.wait().await {
+        match msg {
+            ChannelMsg::Data { data } => output.extend_from_slice(&data),
+            ChannelMsg::ExitStatus { exit_status } => status = Some(exit_status),
+            ChannelMsg::Failure => bail!("exec refused"),
+            _ => {}
+        }
+    }
+    Ok((output, status))
+}
+
+async fn start_russh_server(
+    authorized: Vec<key::PublicKey>,
+    server_id: Option<SshId>,
+) -> Result<u16, anyhow::Error> {
+    // The containers reach the server through the docker host gateway.
+    let port = TcpListener::bind(("0.0.0.0", 0))?.local_addr()?.port();
+    let mut kex = russh_kex();
+    kex.extend([
+        kex::EXTENSION_SUPPORT_AS_SERVER,
+        kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+    ]);
+    let mut keys = vec![key::KeyPair::generate_ed25519().ok_or_else(|| anyhow!("keygen"))?];
+    for algorithm in ECDSA.iter().copied() {
+        keys.push(key::KeyPair::generate_ecdsa(algorithm).ok_or_else(|| anyhow!("keygen"))?);
+    }
+    let mut config = server::Config {
+        keys,
+        preferred: Preferred {
+            kex: Cow::Owned(kex),
+            cipher: Cow::Owned(russh_ciphers()),
+            mac: Cow::Owned(russh_macs()),
+            ..Preferred::DEFAULT
+        },
+        ..Default::default()
+    };
+    if let Some(server_id) = server_id {
+        config.server_id = server_id;
+    }
+    let config = Arc::new(config);
+    let mut server = Server { authorized };
+    tokio::spawn(async move { server.run_on_address(config, ("0.0.0.0", port)).await });
+    while tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .is_err()
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    Ok(port)
+}
+
+/// Accepts the harness keys and the `russh` password, and runs `exit
+/// <status>` and `true` commands, `echo`, which echoes its input until
+/// a `q`, `flood`, which writes until the client sends
+/// `eow@openssh.com`, and `sessions`, which exits with 0 if the client
+/// sent `no-more-sessions@openssh.com`.
+#[derive(Clone)]
+struct Server {
+    authorized: Vec<key::PublicKey>,
+}
+
+impl server::Server for Server {
+    type Handler = Self;
+
+    fn new_client(&mut self, _: Option<SocketAddr>) -> Self::Handler {
+        self.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl server::Handler for Server {
+    type Error = anyhow::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        if user == "russh" && password == "russh" {
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        key: &key::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if user == "russh" && self.authorized.contains(key) {
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _: Channel<Msg>,
+        _: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        command: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_success(channel);
+        if command == b"echo" {
+            return Ok(());
+        }
+        if command == b"flood" {
+            // Stops when `end_of_write` closes the channel.
+            let handle = session.handle();
+            tokio::spawn(async move {
+                while handle.data(channel, vec![b'x'; 4096].into()).await.is_ok() {}
+            });
+            return Ok(());
+        }
+        let status = match command {
+            b"sessions" => u32::from(!session.no_more_sessions()),
+            _ => std::str::from_utf8(command)
+                .ok()
+                .and_then(|c| c.strip_prefix("exit "))
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0),
+        };
+        session.exit_status_request(channel, status);
+        session.eof(channel);
+        session.close(channel);
+        Ok(())
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.data(channel, data.to_vec().into());
+        if data.contains(&b'q') {
+            session.exit_status_request(channel, 0);
+            session.eof(channel);
+            session.close(channel);
+        }
+        Ok(())
+    }
+
+    async fn end_of_write(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.exit_status_request(channel, 0);
+        session.eof(channel);
+        session.close(channel);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ClientAuth {
+    Password,
+    PublicKey,
+    /// The ECDSA key of this algorithm, OpenSSH only.
+    Identity(key::Name),
+}
+
+/// The target's client, run in a container against the russh server.
+#[derive(Clone, Copy)]
+struct SshClient<'a> {
+    kind: Kind,
+    container: &'a Container,
+    port: u16,
+}
+
+impl SshClient<'_> {
+    /// Runs `command` on the russh server, with extra client `options`,
+    /// and checks its exit status.
+    async fn expect_status(
+        self,
+        options: &str,
+        auth: ClientAuth,
+        command: &str,
+        status: i32,
+    ) -> Result<Outcome, anyhow::Error> {
+        self.expect_piped("true", options, auth, command, status)
+            .await
+    }
+
+    /// Like `expect_status`, with the client's output closed after its
+    /// first byte.
+    async fn expect_closed_output(
+        self,
+        options: &str,
+        auth: ClientAuth,
+        command: &str,
+        status: i32,
+    ) -> Result<Outcome, anyhow::Error> {
+        let Some(ssh) = self.command(options, auth, command) else {
+            return Ok(Outcome::Skip("no Dropbear client key".into()));
+        };
+        let script = format!(
+            "({}; echo $? >/tmp/status) | head -c 1 >/dev/null; exit $(cat /tmp/status)",
+            ssh
+        );
+        self.expect_script(&script, status).await
+    }
+
+    /// Like `expect_status`, with the output of the `input` script
+    /// piped to the client.
+    async fn expect_piped(
+        self,
+        input: &str,
+        options: &str,
+        auth: ClientAuth,
+        command: &str,
+        status: i32,
+    ) -> Result<Outcome, anyhow::Error> {
+        let Some(ssh) = self.command(options, auth, command) else {
+            return Ok(Outcome::Skip("no Dropbear client key".into()));
+        };
+        self.expect_script(&format!("({}) | {}", input, ssh), status)
+            .await
+    }
+
+    /// The client command line running `command`, or `None` if `auth`
+    /// isn't available with this client.
+    fn command(self, options: &str, auth: ClientAuth, command: &str) -> Option<String> {
+        let auth = match (self.kind, auth) {
+            (Kind::OpenSsh, ClientAuth::PublicKey) => {
+                "ssh -o BatchMode=yes -o PreferredAuthentications=publickey \
+                 -o IdentitiesOnly=yes -i /tmp/id"
+                    .to_string()
+            }
+            (Kind::OpenSsh, ClientAuth::Identity(algorithm)) => format!(
+                "ssh -o BatchMode=yes -o PreferredAuthentications=publickey \
+                 -o IdentitiesOnly=yes -i /tmp/id_{}",
+                algorithm.as_ref()
+            ),
+            (Kind::OpenSsh, ClientAuth::Password) => {
+                "sshpass -p russh ssh -o PreferredAuthentications=password \
+                 -o PubkeyAuthentication=no"
+                    .to_string()
+            }
+            (Kind::Dropbear, ClientAuth::PublicKey) => "dbclient -i /tmp/id.dropbear".to_string(),
+            (Kind::Dropbear, ClientAuth::Identity(_)) => return None,
+            (Kind::Dropbear, ClientAuth::Password) => {
+                "DROPBEAR_PASSWORD=russh dbclient".to_string()
+            }
+        };
+        let common = match self.kind {
+            Kind::OpenSsh => {
+                "-F /dev/null -o StrictHostKeyChecking=no \
+                 -o UserKnownHostsFile=/dev/null -o LogLevel=ERROR"
+            }
+            Kind::Dropbear => "-y -y",
+        };
+        Some(format!(
+            "{} {} {} -p {} russh@host.docker.internal '{}'",
+            auth, common, options, self.port, command
+        ))
+    }
+
+    async fn expect_script(self, script: &str, status: i32) -> Result<Outcome, anyhow::Error> {
+        let (got, stderr) = self.container.exec(script).await?;
+        if got == Some(status) {
+            Ok(Outcome::Pass)
+        } else {
+            bail!("exit status {:?}: {}", got, stderr.trim())
+        }
+    }
+}
+
+async fn build_image(target: &Target) -> Result<String, anyhow::Error> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/interop");
+    let image = format!("russh-interop-{}", target.name);
+    let dockerfile = dir.join(target.dockerfile);
+    docker([
+        "build".as_ref(),
+        "-q".as_ref(),
+        "-t".as_ref(),
+        image.as_ref(),
+        "-f".as_ref(),
+        dockerfile.as_os_str(),
+        "--build-arg".as_ref(),
+        format!("BASE={}", target.base).as_ref(),
+        dir.as_os_str(),
+    ])
+    .await?;
+    Ok(image)
+}
+
+/// Runs docker, returning its standard output.
+async fn docker<I, S>(args: I) -> Result<String, anyhow::Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let mut command = Command::new("docker");
+    command.args(args);
+    let output = tokio::task::spawn_blocking(move || command.output()).await??;
+    if !output.status.success() {
+        bail!(
+            "docker failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+struct Container {
+    id: String,
+    /// The published SSH port, for server containers.
+    addr: SocketAddr,
+}
+
+impl Container {
+    async fn start_server(image: &str, keys: &TempDir) -> Result<Self, anyhow::Error> {
+        let id = docker([
+            "run",
+            "-d",
+            "-p",
+            "127.0.0.1::22",
+            "-v",
+            keys.mount().as_str(),
+            image,
+        ])
+        .await?;
+        let port = docker(["port", id.as_str(), "22/tcp"]).await;
+        let mut container = Container {
+            id,
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        };
+        container.addr = port?
+            .lines()
+            .next()
+            .ok_or_else(|| anyhow!("port not published"))?
+            .parse()?;
+        container.wait_for_banner().await?;
+        Ok(container)
+    }
+
+    async fn start_client(image: &str, keys: &TempDir) -> Result<Self, anyhow::Error> {
+        let id = docker([
+            "run",
+            "-d",
+            "--add-host",
+            "host.docker.internal:host-gateway",
+            "-v",
+            keys.mount().as_str(),
+            image,
+            "sleep",
+            "infinity",
+        ])
+        .await?;
+        let container = Container {
+            id,
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        };
+        // Clients refuse keys that other users can read.
+        container
+            .exec("cp /keys/id* /tmp/ && chmod 600 /tmp/id*")
+            .await?;
+        Ok(container)
+    }
+
+    /// The docker proxy accepts connections before the server is up,
+    /// so wait for the server's identification string.
+    async fn wait_for_banner(&self) -> Result<(), anyhow::Error> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let mut banner = [0; 4];
+            let attempt = tokio::time::timeout(Duration::from_secs(1), async {
+                let mut stream = tokio::net::TcpStream::connect(self.addr).await?;
+                stream.read_exact(&mut banner).await
+            })
+            .await;
+            if let Ok(Ok(_)) = attempt {
+                if &banner == b"SSH-" {
+                    return Ok(());
+                }
+            }
+            if Instant::now() > deadline {
+                bail!("the server in {} didn't start", self.id);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Runs a shell script in the container, returning its exit status
+    /// and standard error.
+    async fn exec(&self, script: &str) -> Result<(Option<i32>, String), anyhow::Error> {
+        let mut command = Command::new("docker");
+        command.args(["exec", self.id.as_str(), "sh", "-c", script]);
+        let output = tokio::task::spawn_blocking(move || command.output()).await??;
+        Ok((
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
+
+    /// The algorithms implemented by the container's client, as
+    /// (kind, names) pairs.
+    async fn algorithms(&self) -> Result<Vec<(String, Vec<String>)>, anyhow::Error> {
+        let output = docker(["exec", self.id.as_str(), "/interop/algorithms"]).await?;
+        Ok(output
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(kind, names)| {
+                let names = names
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|n| !n.is_empty())
+                    .map(String::from)
+                    .collect();
+                (kind.to_string(), names)
+            })
+            .collect())
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let _ = Command::new("docker")
+            .args(["rm", "-f", self.id.as_str()])
+            .output();
+    }
+}
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> std::io::Result<Self> {
+        let path =
+            std::env::temp_dir().join(format!("russh-interop-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    fn mount(&self) -> String {
+        format!("{}:/keys", self.0.display())
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+enum Outcome {
+    Pass,
+    Skip(String),
+}
+
+struct CaseResult {
+    target: &'static str,
+    direction: &'static str,
+    case: String,
+    /// `Err` with the last error if every attempt failed.
+    outcome: Result<Outcome, String>,
+    attempts: usize,
+    duration: Duration,
+}
+
+impl CaseResult {
+    fn json(&self) -> String {
+        let (result, detail) = match self.outcome {
+            Ok(Outcome::Pass) if self.attempts > 1 => ("flaky", ""),
+            Ok(Outcome::Pass) => ("pass", ""),
+            Ok(Outcome::Skip(ref reason)) => ("skip", reason.as_str()),
+            Err(ref e) => ("fail", e.as_str()),
+        };
+        format!(
+            "{{\"target\":{},\"direction\":{},\"case\":{},\"result\":{},\"attempts\":{},\"duration_ms\":{},\"detail\":{}}}",
+            json_string(self.target),
+            json_string(self.direction),
+            json_string(&self.case),
+            json_string(result),
+            self.attempts,
+            self.duration.as_millis(),
+            json_string(detail),
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+struct Report {
+    target: &'static str,
+    direction: &'static str,
+    retries: usize,
+    results: Vec<CaseResult>,
+    file: std::fs::File,
+    path: PathBuf,
+}
+
+impl Report {
+    fn new(retries: usize) -> Result<Self, anyhow::Error> {
+        let path = match std::env::var_os("RUSSH_INTEROP_RESULTS") {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(env!("CARGO_TARGET_TMPDIR")).join("interop-results.jsonl"),
+        };
+        let file =
+            std::fs::File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+        Ok(Report {
+            target: "",
+            direction: "",
+            retries: retries.max(1),
+            results: Vec::new(),
+            file,
+            path,
+        })
+    }
+
+    /// Runs a case until it passes or is skipped, at most `retries`
+    /// times, and records the result.
+    async fn case<F, Fut>(&mut self, case: String, f: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Outcome, anyhow::Error>>,
+    {
+        let start = Instant::now();
+        let mut attempts = 0;
+        let outcome = loop {
+            attempts += 1;
+            let error = match tokio::time::timeout(CASE_TIMEOUT, f()).await {
+                Ok(Ok(outcome)) => break Ok(outcome),
+                Ok(Err(e)) => format!("{:#}", e),
+                Err(_) => "timed out".to_string(),
+            };
+            log::warn!(
+                "{} {} {}: attempt {} failed: {}",
+                self.target,
+                self.direction,
+                case,
+                attempts,
+                error
+            );
+            if attempts >= self.retries {
+                break Err(error);
+            }
+        };
+        let result = CaseResult {
+            target: self.target,
+            direction: self.direction,
+            case,
+            outcome,
+            attempts,
+            duration: start.elapsed(),
+        };
+        let _ = writeln!(self.file, "{}", result.json());
+        self.results.push(result);
+    }
+
+    /// Prints a summary per target and direction, and fails if any
+    /// case failed.
+    fn summary(&self) -> Result<(), anyhow::Error> {
+        let mut groups: Vec<(&str, &str)> = Vec::new();
+        for r in self.results.iter() {
+            if !groups.contains(&(r.target, r.direction)) {
+                groups.push((r.target, r.direction));
+            }
+        }
+        println!(
+            "{:<20} {:<14} {:>5} {:>5} {:>5} {:>5}",
+            "target", "direction", "pass", "flaky", "skip", "fail"
+        );
+        for (target, direction) in groups {
+            let mut counts = [0; 4];
+            for r in self.results.iter() {
+                if (r.target, r.direction) != (target, direction) {
+                    continue;
+                }
+                let i = match r.outcome {
+                    Ok(Outcome::Pass) if r.attempts > 1 => 1,
+                    Ok(Outcome::Pass) => 0,
+                    Ok(Outcome::Skip(_)) => 2,
+                    Err(_) => 3,
+                };
+                if let Some(count) = counts.get_mut(i) {
+                    *count += 1;
+                }
+            }
+            let [pass, flaky, skip, fail] = counts;
+            println!(
+                "{:<20} {:<14} {:>5} {:>5} {:>5} {:>5}",
+                target, direction, pass, flaky, skip, fail
+            );
+        }
+        println!("results written to {}", self.path.display());
+
+        let failures: Vec<_> = self
+            .results
+            .iter()
+            .filter_map(|r| match r.outcome {
+                Err(ref e) => Some(format!("{} {} {}: {}", r.target, r.direction, r.case, e)),
+                Ok(_) => None,
+            })
+            .collect();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            bail!("{} cases failed:\n{}", failures.len(), failures.join("\n"))
+        }
+    }
+}
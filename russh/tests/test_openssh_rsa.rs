@@ -0,0 +1,262 @@
+//! Interoperability tests against the OpenSSH client.
+//!
+//! These need `ssh`, `ssh-keygen`, `ssh-agent` and `ssh-add` (OpenSSH 8.2+)
+//! and only run when `RUSSH_OPENSSH_TESTS` is set.
+
+use std::ffi::OsStr;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use russh::keys::key;
+use russh::server::{self, Auth, Msg, Server as _, Session};
+use russh::{Channel, ChannelId};
+
+fn enabled() -> bool {
+    std::env::var_os("RUSSH_OPENSSH_TESTS").is_some()
+}
+
+#[tokio::test]
+async fn test_openssh_rsa_sha2_512_key_file() -> Result<(), anyhow::Error> {
+    if !enabled() {
+        return Ok(());
+    }
+    let _ = env_logger::try_init();
+
+    let dir = TempDir::new("key-file")?;
+    let key_path = dir.generate_rsa_key()?;
+    let (addr, events) = start_server().await;
+
+    let status = tokio::task::spawn_blocking(move || {
+        let key_path = key_path.as_os_str();
+        ssh_command(
+            addr,
+            [
+                "-o".as_ref(),
+                "IdentityAgent=none".as_ref(),
+                "-o".as_ref(),
+                "IdentitiesOnly=yes".as_ref(),
+                "-i".as_ref(),
+                key_path,
+            ],
+        )
+        .status()
+    })
+    .await??;
+    assert!(status.success());
+
+    assert_single_probe(&events, "rsa-sha2-512");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_openssh_rsa_sha2_512_agent() -> Result<(), anyhow::Error> {
+    if !enabled() {
+        return Ok(());
+    }
+    let _ = env_logger::try_init();
+
+    let dir = TempDir::new("agent")?;
+    let key_path = dir.generate_rsa_key()?;
+    let agent = Agent::start(&dir.0)?;
+    agent.add(&key_path)?;
+    let (addr, events) = start_server().await;
+
+    let socket = agent.socket.clone();
+    let status = tokio::task::spawn_blocking(move || {
+        let agent = format!("IdentityAgent={}", socket.display());
+        ssh_command(addr, ["-o", &agent]).status()
+    })
+    .await??;
+    assert!(status.success());
+
+    assert_single_probe(&events, "rsa-sha2-512");
+    Ok(())
+}
+
+fn ssh_command<A: AsRef<OsStr>>(addr: SocketAddr, args: impl IntoIterator<Item = A>) -> Command {
+    let mut cmd = Command::new("ssh");
+    cmd.args([
+        "-F",
+        "/dev/null",
+        "-o",
+        "BatchMode=yes",
+        "-o",
+        "LogLevel=ERROR",
+        "-o",
+        "StrictHostKeyChecking=no",
+        "-o",
+        "UserKnownHostsFile=/dev/null",
+        "-o",
+        "PubkeyAcceptedAlgorithms=rsa-sha2-512",
+        "-o",
+        "PreferredAuthentications=publickey",
+    ])
+    .args(args)
+    .arg("-p")
+    .arg(addr.port().to_string())
+    .arg("user@127.0.0.1")
+    .arg("true")
+    .stdin(Stdio::null());
+    cmd
+}
+
+/// Checks that the client probed exactly once with `algo`, and then
+/// signed exactly once with the same algorithm.
+fn assert_single_probe(events: &Events, algo: &str) {
+    let events = events.lock().unwrap();
+    assert_eq!(
+        events.as_slice(),
+        &[(false, algo.to_string()), (true, algo.to_string())]
+    );
+}
+
+/// (is_signed, algorithm name) for each public key auth request.
+type Events = Arc<Mutex<Vec<(bool, String)>>>;
+
+async fn start_server() -> (SocketAddr, Events) {
+    let addr = TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let events = Events::default();
+
+    let config = Arc::new(server::Config {
+        keys: vec![key::KeyPair::generate_ed25519().unwrap()],
+        ..Default::default()
+    });
+    let mut server = Server {
+        events: events.clone(),
+    };
+    tokio::spawn(async move { server.run_on_address(config, addr).await });
+
+    while TcpStream::connect(addr).is_err() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    (addr, events)
+}
+
+#[derive(Clone)]
+struct Server {
+    events: Events,
+}
+
+impl server::Server for Server {
+    type Handler = Self;
+
+    fn new_client(&mut self, _: Option<SocketAddr>) -> Self::Handler {
+        self.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl server::Handler for Server {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey_offered(
+        &mut self,
+        _: &str,
+        key: &key::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        self.events
+            .lock()
+            .unwrap()
+            .push((false, key.name().to_string()));
+        Ok(Auth::Accept)
+    }
+
+    async fn auth_publickey(&mut self, _: &str, key: &key::PublicKey) -> Result<Auth, Self::Error> {
+        self.events
+            .lock()
+            .unwrap()
+            .push((true, key.name().to_string()));
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _: Channel<Msg>,
+        _: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        _: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_success(channel);
+        session.exit_status_request(channel, 0);
+        session.eof(channel);
+        session.close(channel);
+        Ok(())
+    }
+}
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> std::io::Result<Self> {
+        let path =
+            std::env::temp_dir().join(format!("russh-openssh-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    fn generate_rsa_key(&self) -> std::io::Result<PathBuf> {
+        let path = self.0.join("id_rsa");
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "rsa", "-b", "2048", "-N", "", "-f"])
+            .arg(&path)
+            .status()?;
+        assert!(status.success());
+        Ok(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+struct Agent {
+    child: Child,
+    socket: PathBuf,
+}
+
+impl Agent {
+    fn start(dir: &Path) -> std::io::Result<Self> {
+        let socket = dir.join("agent.sock");
+        let child = Command::new("ssh-agent")
+            .arg("-D")
+            .arg("-a")
+            .arg(&socket)
+            .stdout(Stdio::null())
+            .spawn()?;
+        while !socket.exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        Ok(Self { child, socket })
+    }
+
+    fn add(&self, key: &Path) -> std::io::Result<()> {
+        let status = Command::new("ssh-add")
+            .arg("-q")
+            .arg(key)
+            .env("SSH_AUTH_SOCK", &self.socket)
+            .status()?;
+        assert!(status.success());
+        Ok(())
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,125 @@
+//! Keyboard-interactive authentication through PAM.
+//!
+//! These need the services in `tests/pam.d` to be installed in
+//! `/etc/pam.d` (typically inside a container) and only run when
+//! `RUSSH_PAM_TESTS` is set.
+#![cfg(all(unix, feature = "pam"))]
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+
+use russh::client::{self, KeyboardInteractiveAuthResponse};
+use russh::keys::key;
+use russh::server::auth::pam::PamAuthenticator;
+use russh::server::{self, Auth, Response, Server as _};
+use russh::MethodSet;
+
+fn enabled() -> bool {
+    std::env::var_os("RUSSH_PAM_TESTS").is_some()
+}
+
+#[tokio::test]
+async fn test_pam_permit() -> Result<(), anyhow::Error> {
+    if !enabled() {
+        return Ok(());
+    }
+    let _ = env_logger::try_init();
+    let addr = start_server("russh-test-permit").await;
+    assert!(matches!(
+        authenticate(addr).await?,
+        KeyboardInteractiveAuthResponse::Success
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pam_deny() -> Result<(), anyhow::Error> {
+    if !enabled() {
+        return Ok(());
+    }
+    let _ = env_logger::try_init();
+    let addr = start_server("russh-test-deny").await;
+    assert!(matches!(
+        authenticate(addr).await?,
+        KeyboardInteractiveAuthResponse::Failure
+    ));
+    Ok(())
+}
+
+/// Runs keyboard-interactive to completion, answering every prompt
+/// with an empty string.
+async fn authenticate(addr: SocketAddr) -> Result<KeyboardInteractiveAuthResponse, anyhow::Error> {
+    let mut session = client::connect(Arc::new(client::Config::default()), addr, Client).await?;
+    let mut response = session
+        .authenticate_keyboard_interactive_start("user", Some("pam".to_string()))
+        .await?;
+    while let KeyboardInteractiveAuthResponse::InfoRequest { prompts, .. } = response {
+        response = session
+            .authenticate_keyboard_interactive_respond(vec![String::new(); prompts.len()])
+            .await?;
+    }
+    Ok(response)
+}
+
+async fn start_server(service: &'static str) -> SocketAddr {
+    let addr = TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = Arc::new(server::Config {
+        methods: MethodSet::KEYBOARD_INTERACTIVE,
+        auth_rejection_time: std::time::Duration::from_millis(10),
+        keys: vec![key::KeyPair::generate_ed25519().unwrap()],
+        ..Default::default()
+    });
+    let mut server = Server { service };
+    tokio::spawn(async move { server.run_on_address(config, addr).await });
+
+    while TcpStream::connect(addr).is_err() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    addr
+}
+
+struct Server {
+    service: &'static str,
+}
+
+impl server::Server for Server {
+    type Handler = Handler;
+
+    fn new_client(&mut self, _: Option<SocketAddr>) -> Self::Handler {
+        Handler {
+            pam: PamAuthenticator::new(self.service).remote_host("127.0.0.1"),
+        }
+    }
+}
+
+struct Handler {
+    pam: PamAuthenticator,
+}
+
+#[async_trait::async_trait]
+impl server::Handler for Handler {
+    type Error = anyhow::Error;
+
+    async fn auth_keyboard_interactive(
+        &mut self,
+        user: &str,
+        _: &str,
+        response: Option<Response<'async_trait>>,
+    ) -> Result<Auth, Self::Error> {
+        Ok(self.pam.keyboard_interactive(user, response).await)
+    }
+}
+
+struct Client;
+
+#[async_trait::async_trait]
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _: &key::PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}